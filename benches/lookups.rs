@@ -0,0 +1,148 @@
+//! What a lookup costs when it doesn't have to leave the process
+//!
+//! Most of a busy client's lookups are answered from a cache or a fixed
+//! table, so that's where the time (and the allocations) per lookup
+//! matter.  Run with `cargo bench`, and add `--features testserver` to
+//! include lookups through trust-dns.
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use futures::executor::block_on;
+use reqwest_resolve::cache::CachingResolver;
+use reqwest_resolve::static_hosts::StaticResolver;
+use reqwest_resolve::static_resolver;
+use reqwest_resolve::MyResolve;
+use reqwest_resolve::ResolveAdapter;
+use std::hint::black_box;
+
+static HOSTS: StaticResolver = static_resolver! {
+    "one.test" => ["192.0.2.1"],
+    "four.test" => ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"],
+    "eight.test" => [
+        "192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4",
+        "2001:db8::1", "2001:db8::2", "2001:db8::3", "2001:db8::4",
+    ],
+};
+
+/// Cache hits for answers short enough to be kept inline, and for one that
+/// isn't
+fn cached_addrs(c: &mut Criterion) {
+    let caching = CachingResolver::new(HOSTS);
+    let mut group = c.benchmark_group("cached_addrs");
+    for name in ["one.test", "four.test", "eight.test"] {
+        let name: hyper::client::connect::dns::Name = name.parse().unwrap();
+        // Fill the cache.
+        let _ = block_on(caching.resolve(name.clone())).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name.as_str()),
+            &name,
+            |b, name| {
+                b.iter(|| {
+                    let addrs = block_on(caching.resolve(name.clone()));
+                    black_box(addrs.unwrap().count())
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Answers that are ready as soon as they're asked for, which don't box a
+/// future, next to the same answer through reqwest's `Resolve`, which does
+fn ready_answers(c: &mut Criterion) {
+    let caching = CachingResolver::new(HOSTS);
+    let adapter = ResolveAdapter::new(HOSTS);
+    let name: hyper::client::connect::dns::Name = "one.test".parse().unwrap();
+    // Fill the cache.
+    let _ = block_on(caching.resolve(name.clone())).unwrap();
+
+    let mut group = c.benchmark_group("ready_answers");
+    group.bench_function("static", |b| {
+        b.iter(|| black_box(block_on(HOSTS.resolve(name.clone())).is_ok()))
+    });
+    group.bench_function("cache_hit", |b| {
+        b.iter(|| black_box(block_on(caching.resolve(name.clone())).is_ok()))
+    });
+    group.bench_function("static_through_reqwest_resolve", |b| {
+        b.iter(|| {
+            let lookup = reqwest::dns::Resolve::resolve(&adapter, name.clone());
+            black_box(block_on(lookup).is_ok())
+        })
+    });
+    group.finish();
+}
+
+/// Cache hits for a name as it's usually written, which is used as it is,
+/// and for one that has to be lowercased first
+fn cached_names(c: &mut Criterion) {
+    let caching = CachingResolver::new(HOSTS);
+    let mut group = c.benchmark_group("cached_names");
+    for name in ["one.test", "One.Test."] {
+        let name: hyper::client::connect::dns::Name = name.parse().unwrap();
+        // Fill the cache.
+        let _ = block_on(caching.resolve(name.clone())).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name.as_str()),
+            &name,
+            |b, name| {
+                b.iter(|| {
+                    black_box(block_on(caching.resolve(name.clone())).is_ok())
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Lookups answered from trust-dns's own cache, which reuse the name
+/// parsed the first time
+#[cfg(feature = "testserver")]
+fn trust_dns_hits(c: &mut Criterion) {
+    use reqwest_resolve::testserver::TestServer;
+    use reqwest_resolve::testserver::TestZone;
+    use reqwest_resolve::MyCustomDnsResolver;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let name: hyper::client::connect::dns::Name = "api.test".parse().unwrap();
+    let (_server, resolver) = runtime.block_on(async {
+        let mut zone = TestZone::new();
+        zone.add_addr("api.test", "192.0.2.1".parse().unwrap());
+        let server = TestServer::start(zone).await.unwrap();
+        let resolver = MyCustomDnsResolver::new(
+            TokioAsyncResolver::tokio(
+                server.resolver_config(),
+                ResolverOpts::default(),
+            )
+            .unwrap(),
+        );
+        // Fill trust-dns's cache.
+        let _ = resolver.resolve(name.clone()).await.unwrap();
+        (server, resolver)
+    });
+
+    c.bench_function("trust_dns_hits", |b| {
+        b.iter(|| {
+            let lookup = runtime.block_on(resolver.resolve(name.clone()));
+            black_box(lookup.is_ok())
+        })
+    });
+}
+
+#[cfg(not(feature = "testserver"))]
+criterion_group!(benches, cached_addrs, ready_answers, cached_names);
+#[cfg(feature = "testserver")]
+criterion_group!(
+    benches,
+    cached_addrs,
+    ready_answers,
+    cached_names,
+    trust_dns_hits
+);
+criterion_main!(benches);
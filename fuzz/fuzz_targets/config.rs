@@ -0,0 +1,21 @@
+//! Configuration files: resolv.conf, DNS stamps, and the crate's own
+//! configuration structures
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reqwest_resolve::cache::CacheConfig;
+use reqwest_resolve::container::parse_resolv_conf;
+use reqwest_resolve::split_dns::SplitDnsConfig;
+use reqwest_resolve::stamps::DnsStamp;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<CacheConfig>(data);
+    let _ = serde_json::from_slice::<SplitDnsConfig>(data);
+
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = parse_resolv_conf(None, text);
+    let _ = DnsStamp::parse(text);
+});
@@ -0,0 +1,24 @@
+//! Host overrides from the environment, and captured answers for fixtures
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reqwest_resolve::env_hosts::HostOverrides;
+use reqwest_resolve::fixtures::Fixture;
+
+fuzz_target!(|text: &str| {
+    if let Ok(overrides) = HostOverrides::parse((), text) {
+        for entry in text.split(',') {
+            let name = entry.split('=').next().unwrap_or_default();
+            let _ = overrides.lookup(name.trim());
+        }
+    }
+
+    if let Ok(fixture) = Fixture::parse(text) {
+        let _ = fixture.to_static_resolver();
+        for line in text.lines() {
+            let name = line.split_whitespace().next().unwrap_or_default();
+            let _ = fixture.lookup(name);
+        }
+    }
+});
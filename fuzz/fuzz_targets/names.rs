@@ -0,0 +1,20 @@
+//! Hostnames, as they come from URLs, on their way to a resolver
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reqwest_resolve::normalize::{Canonicalize, NameNormalizer, TrailingDot};
+
+fuzz_target!(|name: &str| {
+    for normalizer in [
+        Canonicalize::new(),
+        Canonicalize::new().with_trailing_dot(TrailingDot::Strip),
+        Canonicalize::new().with_trailing_dot(TrailingDot::Add),
+        Canonicalize::new().without_case_folding(),
+    ] {
+        let normalized = normalizer.normalize(name);
+        // Normalizing a name that's already normal leaves it alone.
+        assert_eq!(normalizer.normalize(&normalized), normalized);
+        let _ = normalized.parse::<hyper::client::connect::dns::Name>();
+    }
+});
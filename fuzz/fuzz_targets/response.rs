@@ -0,0 +1,35 @@
+//! Responses from upstream servers, as seen by the query filters that
+//! inspect and rewrite them
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reqwest_resolve::answer_names::AnswerNameValidation;
+use reqwest_resolve::cname_trace::CnameTraceFilter;
+use reqwest_resolve::transport::{QueryFilter, Upstream};
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::proto::op::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut response) = Message::from_vec(data) else {
+        return;
+    };
+    // The query being answered, as it was sent
+    let mut request = Message::new();
+    request.set_id(response.id());
+    request.add_queries(response.queries().to_vec());
+
+    let upstream = Upstream {
+        addr: "192.0.2.53:53".parse().unwrap(),
+        protocol: Protocol::Udp,
+    };
+    let filters: [&dyn QueryFilter; 2] =
+        [&CnameTraceFilter::new(), &AnswerNameValidation::new()];
+    for filter in filters {
+        filter.on_request(&upstream, &mut request);
+        if filter.on_response(&upstream, &request, &mut response).is_err() {
+            return;
+        }
+    }
+    let _ = response.to_vec();
+});
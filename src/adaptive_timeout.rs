@@ -0,0 +1,137 @@
+//! Timing queries out based on how long each upstream usually takes
+//!
+//! `ResolverOpts::timeout` is one number for every upstream and every
+//! network.  Five seconds (the default) is far longer than a healthy
+//! upstream on a LAN ever takes, so when one stops answering each query
+//! waits that long before trying the next; a second can be too short on a
+//! slow satellite link.  [`AdaptiveTimeoutTransport`] gives each upstream its
+//! own timeout, derived from how quickly it has been answering.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::latency::Latencies;
+use crate::logging::debug;
+use futures::future::FutureExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::Time;
+use trust_dns_resolver::proto::TokioTime;
+
+/// How an [`AdaptiveTimeoutTransport`] picks each upstream's timeout: the
+/// `percentile` (between 0 and 1) of its recent latencies, times `factor`,
+/// kept between `min` and `max`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveTimeout {
+    pub percentile: f64,
+    pub factor: f64,
+    pub min: Duration,
+    /// the timeout until an upstream has answered enough queries to go by,
+    /// and the longest it can get
+    pub max: Duration,
+}
+
+impl Default for AdaptiveTimeout {
+    /// three times the 99th percentile, between 100ms and trust-dns's
+    /// default timeout of 5s
+    fn default() -> AdaptiveTimeout {
+        AdaptiveTimeout {
+            percentile: 0.99,
+            factor: 3.0,
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Gives up on queries to each upstream after a timeout derived from its
+/// recent latencies (see [`AdaptiveTimeout`])
+///
+/// A query that times out fails with a timeout, as it would have after
+/// `ResolverOpts::timeout`, and the resolver moves on to the next upstream.
+/// The timeout counts as one of the upstream's latencies, so an upstream
+/// that has gotten slower (rather than stopped answering) sees its timeout
+/// grow again after a few of them, up to `max`.  `ResolverOpts::timeout`
+/// still applies on top of this, so it should be at least `max`.
+///
+/// ```
+/// # use reqwest_resolve::adaptive_timeout::{AdaptiveTimeout, AdaptiveTimeoutTransport};
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::dns_transport::StandardTransport;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let options = ResolverOpts::default();
+/// let transport = AdaptiveTimeoutTransport::new(
+///     StandardTransport::new(options),
+///     AdaptiveTimeout::default(),
+/// );
+/// let resolver =
+///     transport_resolver(ResolverConfig::cloudflare(), options, transport)
+///         .unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct AdaptiveTimeoutTransport<T> {
+    inner: T,
+    policy: AdaptiveTimeout,
+    latencies: Arc<Latencies>,
+}
+
+impl<T: DnsTransport> AdaptiveTimeoutTransport<T> {
+    pub fn new(
+        inner: T,
+        policy: AdaptiveTimeout,
+    ) -> AdaptiveTimeoutTransport<T> {
+        AdaptiveTimeoutTransport { inner, policy, latencies: Arc::default() }
+    }
+
+    /// Returns the timeout the next query to `upstream` gets.
+    pub fn timeout(&self, upstream: SocketAddr) -> Duration {
+        let AdaptiveTimeout { percentile, factor, min, max } = self.policy;
+        self.latencies
+            .percentile(upstream, percentile)
+            .map_or(max, |latency| latency.mul_f64(factor).clamp(min, max))
+    }
+}
+
+impl<T: DnsTransport> DnsTransport for AdaptiveTimeoutTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let upstream = server.socket_addr;
+        let timeout = self.timeout(upstream);
+        let response = self.inner.send(server, query);
+        let latencies = Arc::clone(&self.latencies);
+
+        async move {
+            let started = Instant::now();
+            match TokioTime::timeout(timeout, response).await {
+                Ok(result) => {
+                    if result.is_ok() {
+                        latencies.record(upstream, started.elapsed());
+                    }
+                    result
+                }
+                Err(_) => {
+                    debug!(
+                        "query hit adaptive timeout",
+                        upstream = upstream,
+                        timeout = timeout,
+                    );
+                    latencies.record(upstream, timeout);
+                    Err(ResolveError::from(ResolveErrorKind::Timeout))
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,519 @@
+//! A small HTTP server for looking at the resolver stack from outside
+//!
+//! A long-running service can answer "what's in the DNS cache?" or "is the
+//! resolver falling back?" with a debugger, or with code it didn't think to
+//! write until it needed it.  [`AdminEndpoints`] collects the counters,
+//! cache, and health checks of whichever layers the stack has, and
+//! [`AdminEndpoints::spawn`] serves them over HTTP:
+//!
+//! - `/metrics`: the counters, in the Prometheus text format
+//! - `/cache`: a cache's entries, one name per line, with its TTL and
+//!   addresses
+//! - `/health`: "ok", or (with status 503) each failing check and why
+//! - `/audit`: the lookups an audit log remembers, oldest first
+//!
+//! There's no authentication, so listen on a loopback or otherwise private
+//! address.
+
+use crate::anomaly::AnomalyCounters;
+use crate::anomaly::AnomalyCounts;
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::cache::CacheEntry;
+use crate::fallback::FallbackCounters;
+use crate::fallback::FallbackCounts;
+use crate::logging::debug;
+use crate::tasks::running_tasks;
+use crate::tasks::TaskSet;
+use hyper::service::make_service_fn;
+use hyper::service::service_fn;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Response;
+use hyper::StatusCode;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// What [`AdminEndpoints::metric`] reports
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind {
+    /// only ever goes up (its name should end in "_total")
+    Counter,
+    /// can go up and down
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+struct Metric {
+    name: String,
+    help: String,
+    kind: MetricKind,
+    value: Box<dyn Fn() -> u64 + Send + Sync>,
+}
+
+struct HealthCheck {
+    name: String,
+    check: Box<dyn Fn() -> Result<(), String> + Send + Sync>,
+}
+
+type CacheSource = Box<dyn Fn() -> Vec<CacheEntry> + Send + Sync>;
+
+/// The metrics, cache, and health checks to serve
+///
+/// The number of this crate's background tasks still running (see
+/// [`crate::tasks`]) is always included in the metrics.
+///
+/// ```no_run
+/// # use reqwest_resolve::admin::AdminEndpoints;
+/// # use reqwest_resolve::anomaly::AnomalyResolver;
+/// # use reqwest_resolve::cache::CachingResolver;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// # let resolver = || TokioAsyncResolver::tokio(
+/// #     ResolverConfig::default(),
+/// #     ResolverOpts::default(),
+/// # )
+/// # .unwrap();
+/// let caching = Arc::new(CachingResolver::new(resolver()));
+/// let _cached_client =
+///     reqwest::ClientBuilder::new().dns_resolver(caching.clone());
+///
+/// let anomalies = AnomalyResolver::new(
+///     reqwest_resolve::MyCustomDnsResolver::new(resolver()),
+/// );
+/// let counters = anomalies.counters();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(anomalies)));
+///
+/// let admin = AdminEndpoints::new()
+///     .anomaly_counters(counters)
+///     .cache(move || caching.entries())
+///     .spawn("127.0.0.1:9153".parse()?)?;
+/// println!("admin server on {}", admin.addr());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct AdminEndpoints {
+    metrics: Vec<Metric>,
+    cache: Option<CacheSource>,
+    audit: Option<AuditLog>,
+    health: Vec<HealthCheck>,
+}
+
+impl AdminEndpoints {
+    /// Serves no metrics besides the task count, no cache, and no health
+    /// checks (so `/health` is always "ok").
+    pub fn new() -> AdminEndpoints {
+        AdminEndpoints::default()
+    }
+
+    /// Adds a metric called `name` whose value is whatever `value` returns
+    /// when the metrics are scraped.
+    pub fn metric<F>(
+        mut self,
+        name: &str,
+        help: &str,
+        kind: MetricKind,
+        value: F,
+    ) -> AdminEndpoints
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.metrics.push(Metric {
+            name: name.to_owned(),
+            help: help.to_owned(),
+            kind,
+            value: Box::new(value),
+        });
+        self
+    }
+
+    /// Adds a health check called `name`, which fails `/health` whenever it
+    /// returns an error.
+    pub fn health_check<F>(mut self, name: &str, check: F) -> AdminEndpoints
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.health.push(HealthCheck {
+            name: name.to_owned(),
+            check: Box::new(check),
+        });
+        self
+    }
+
+    /// Serves whatever `entries` returns (like `CachingResolver::entries`)
+    /// at `/cache`, replacing any cache set before.
+    pub fn cache<F>(mut self, entries: F) -> AdminEndpoints
+    where
+        F: Fn() -> Vec<CacheEntry> + Send + Sync + 'static,
+    {
+        self.cache = Some(Box::new(entries));
+        self
+    }
+
+    /// Serves the lookups in `log` at `/audit`, one per line: when it
+    /// started (in seconds since the Unix epoch), the name, how long it
+    /// took, and the addresses and backends or the error.
+    pub fn audit(mut self, log: AuditLog) -> AdminEndpoints {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Adds the counters of an `anomaly::AnomalyResolver` to the metrics.
+    pub fn anomaly_counters(self, counters: AnomalyCounters) -> AdminEndpoints {
+        let read = |field: fn(&AnomalyCounts) -> u64| {
+            let counters = counters.clone();
+            move || field(&counters.snapshot())
+        };
+        self.metric(
+            "reqwest_resolve_anomaly_lookups_total",
+            "lookups made",
+            MetricKind::Counter,
+            read(|counts| counts.lookups),
+        )
+        .metric(
+            "reqwest_resolve_anomaly_empty_answers_total",
+            "lookups that returned no addresses",
+            MetricKind::Counter,
+            read(|counts| counts.empty_answers),
+        )
+        .metric(
+            "reqwest_resolve_anomaly_unroutable_answers_total",
+            "lookups whose addresses were all unroutable",
+            MetricKind::Counter,
+            read(|counts| counts.unroutable_answers),
+        )
+        .metric(
+            "reqwest_resolve_anomaly_nxdomain_after_success_total",
+            "NXDOMAIN answers for names that had resolved before",
+            MetricKind::Counter,
+            read(|counts| counts.nxdomain_after_success),
+        )
+        .metric(
+            "reqwest_resolve_anomaly_recent_nxdomain_after_success",
+            "NXDOMAIN answers for names that had resolved before, within \
+             the resolver's window",
+            MetricKind::Gauge,
+            read(|counts| counts.recent_nxdomain_after_success),
+        )
+    }
+
+    /// Adds the counters of a `fallback::SystemFallback` to the metrics,
+    /// and a health check that fails while it's using the system resolver.
+    pub fn fallback_counters(
+        self,
+        counters: FallbackCounters,
+    ) -> AdminEndpoints {
+        let read = |field: fn(&FallbackCounts) -> u64| {
+            let counters = counters.clone();
+            move || field(&counters.snapshot())
+        };
+        let endpoints = self
+            .metric(
+                "reqwest_resolve_fallback_panics_total",
+                "lookups in which the backend panicked",
+                MetricKind::Counter,
+                read(|counts| counts.panics),
+            )
+            .metric(
+                "reqwest_resolve_fallback_backend_failures_total",
+                "lookups that failed because of the backend",
+                MetricKind::Counter,
+                read(|counts| counts.backend_failures),
+            )
+            .metric(
+                "reqwest_resolve_fallback_system_lookups_total",
+                "lookups sent to the system resolver",
+                MetricKind::Counter,
+                read(|counts| counts.system_lookups),
+            );
+        endpoints.health_check("fallback", move || {
+            if counters.snapshot().falling_back {
+                Err(String::from("using the system resolver"))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Returns the status and body of the response to a GET for `path`.
+    pub fn render(&self, path: &str) -> (StatusCode, String) {
+        match path {
+            "/metrics" => (StatusCode::OK, self.render_metrics()),
+            "/cache" => match &self.cache {
+                Some(entries) => (StatusCode::OK, render_cache(&entries())),
+                None => (
+                    StatusCode::NOT_FOUND,
+                    String::from("no cache configured\n"),
+                ),
+            },
+            "/health" => self.render_health(),
+            "/audit" => match &self.audit {
+                Some(log) => (StatusCode::OK, render_audit(&log.entries())),
+                None => (
+                    StatusCode::NOT_FOUND,
+                    String::from("no audit log configured\n"),
+                ),
+            },
+            _ => (StatusCode::NOT_FOUND, String::from("not found\n")),
+        }
+    }
+
+    fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let tasks = running_tasks().len() as u64;
+        write_metric(
+            &mut out,
+            "reqwest_resolve_running_tasks",
+            "background tasks running",
+            MetricKind::Gauge,
+            tasks,
+        );
+        for metric in &self.metrics {
+            write_metric(
+                &mut out,
+                &metric.name,
+                &metric.help,
+                metric.kind,
+                (metric.value)(),
+            );
+        }
+        out
+    }
+
+    fn render_health(&self) -> (StatusCode, String) {
+        let mut out = String::new();
+        for check in &self.health {
+            if let Err(reason) = (check.check)() {
+                let _ = writeln!(out, "{}: {}", check.name, reason);
+            }
+        }
+        if out.is_empty() {
+            (StatusCode::OK, String::from("ok\n"))
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, out)
+        }
+    }
+
+    /// Starts serving on `addr` in a task called "admin server" (see
+    /// [`crate::tasks`]), which stops when the returned server is dropped.
+    ///
+    /// This has to be called within a Tokio runtime.
+    pub fn spawn(self, addr: SocketAddr) -> hyper::Result<AdminServer> {
+        let endpoints = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let endpoints = Arc::clone(&endpoints);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let endpoints = Arc::clone(&endpoints);
+                    async move { Ok::<_, Infallible>(respond(&endpoints, request)) }
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&addr)?.serve(make_service);
+        let addr = server.local_addr();
+        let tasks = TaskSet::default();
+        tasks.spawn("admin server", async move {
+            if let Err(error) = server.await {
+                debug!("admin server failed", error = error);
+            }
+        });
+        debug!("admin server listening", addr = addr);
+        Ok(AdminServer { addr, _tasks: tasks })
+    }
+}
+
+fn respond(
+    endpoints: &AdminEndpoints,
+    request: Request<Body>,
+) -> Response<Body> {
+    let (status, body) = if request.method() == Method::GET {
+        endpoints.render(request.uri().path())
+    } else {
+        (StatusCode::METHOD_NOT_ALLOWED, String::from("GET only\n"))
+    };
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: MetricKind,
+    value: u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind.as_str());
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render_cache(entries: &[CacheEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let _ = write!(out, "{} {}", entry.name, entry.ttl);
+        for addr in &entry.addrs {
+            let _ = write!(out, " {}", addr);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn render_audit(entries: &[AuditEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let started = entry
+            .started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let _ = write!(
+            out,
+            "{}.{:03} {} {}ms",
+            started.as_secs(),
+            started.subsec_millis(),
+            entry.name,
+            entry.latency.as_millis()
+        );
+        match &entry.result {
+            Ok(addrs) => {
+                for addr in addrs {
+                    let _ = write!(out, " {}", addr);
+                }
+                if !entry.backends.is_empty() {
+                    let _ = write!(out, " ({})", entry.backends.join(", "));
+                }
+            }
+            Err(error) => {
+                let _ = write!(out, " error: {}", error);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// A running admin server, which stops when this is dropped
+pub struct AdminServer {
+    addr: SocketAddr,
+    _tasks: TaskSet,
+}
+
+impl AdminServer {
+    /// Returns the address the server is listening on (useful when it was
+    /// started on port 0).
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdminEndpoints;
+    use super::MetricKind;
+    use crate::audit::AuditResolver;
+    use crate::cache::CacheEntry;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use hyper::StatusCode;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "api.test" => ["192.0.2.1"],
+    };
+
+    #[test]
+    fn metrics_cache_and_health() {
+        let endpoints = AdminEndpoints::new()
+            .metric("lookups_total", "lookups made", MetricKind::Counter, || 7)
+            .cache(|| {
+                vec![CacheEntry {
+                    name: "api.test".to_owned(),
+                    addrs: vec![
+                        "192.0.2.1".parse().unwrap(),
+                        "2001:db8::1".parse().unwrap(),
+                    ],
+                    ttl: 30,
+                }]
+            })
+            .health_check("always", || Ok(()))
+            .health_check("upstream", || Err(String::from("unreachable")));
+
+        let (status, metrics) = endpoints.render("/metrics");
+        assert_eq!(status, StatusCode::OK);
+        assert!(metrics.starts_with(
+            "# HELP reqwest_resolve_running_tasks background tasks running\n\
+             # TYPE reqwest_resolve_running_tasks gauge\n"
+        ));
+        assert!(metrics.ends_with(
+            "# HELP lookups_total lookups made\n\
+             # TYPE lookups_total counter\n\
+             lookups_total 7\n"
+        ));
+
+        assert_eq!(
+            endpoints.render("/cache"),
+            (
+                StatusCode::OK,
+                String::from("api.test 30 192.0.2.1 2001:db8::1\n")
+            )
+        );
+        assert_eq!(
+            endpoints.render("/health"),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                String::from("upstream: unreachable\n")
+            )
+        );
+        assert_eq!(endpoints.render("/nope").0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn unconfigured() {
+        let endpoints = AdminEndpoints::new();
+        assert_eq!(
+            endpoints.render("/health"),
+            (StatusCode::OK, String::from("ok\n"))
+        );
+        assert_eq!(endpoints.render("/cache").0, StatusCode::NOT_FOUND);
+        assert_eq!(endpoints.render("/audit").0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn audit() {
+        let resolver = AuditResolver::new(HOSTS);
+        let endpoints = AdminEndpoints::new().audit(resolver.log());
+        resolver.resolve_to_vec("api.test").await.unwrap();
+        assert!(resolver.resolve_to_vec("missing.test").await.is_err());
+
+        let (status, body) = endpoints.render("/audit");
+        assert_eq!(status, StatusCode::OK);
+        let lines: Vec<Vec<&str>> =
+            body.lines().map(|line| line.splitn(4, ' ').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0][1], "api.test");
+        assert!(lines[0][2].ends_with("ms"));
+        assert_eq!(lines[0][3], "192.0.2.1:0 (static)");
+        assert_eq!(lines[1][1], "missing.test");
+        assert!(lines[1][3].starts_with("error: "), "{}", lines[1][3]);
+    }
+}
@@ -0,0 +1,299 @@
+//! Counting suspicious lookup results
+//!
+//! Upstream DNS incidents don't always look like failures.  A broken or
+//! hijacked resolver may answer with no addresses at all, with sinkhole
+//! addresses like 0.0.0.0 that can't be connected to, or with NXDOMAIN for
+//! names that resolved fine a minute ago.  Each of those turns into a
+//! confusing connection error somewhere else, and none of them shows up in
+//! lookup error rates.  [`AnomalyResolver`] counts them, so they can be
+//! graphed and alerted on directly.
+
+use crate::special_use::normalize;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::IpList;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::error::ResolveErrorKind;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::proto::op::ResponseCode;
+
+/// How many names [`AnomalyResolver`] remembers as having resolved
+pub const DEFAULT_TRACKED_NAMES: usize = 10_000;
+
+/// How far back [`AnomalyCounts::recent_nxdomain_after_success`] looks
+pub const DEFAULT_ANOMALY_WINDOW: Duration = Duration::from_secs(60);
+
+/// A snapshot of the counters kept by an [`AnomalyResolver`]
+///
+/// Everything except `recent_nxdomain_after_success` counts from when the
+/// resolver was created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AnomalyCounts {
+    /// lookups made through the resolver
+    pub lookups: u64,
+    /// lookups that succeeded but returned no addresses
+    pub empty_answers: u64,
+    /// lookups whose addresses were all unroutable (see
+    /// [`is_unroutable`])
+    pub unroutable_answers: u64,
+    /// lookups that got NXDOMAIN for a name that had resolved before
+    pub nxdomain_after_success: u64,
+    /// how many of those happened within the resolver's window, which is
+    /// the number to alert on for a sudden spike
+    pub recent_nxdomain_after_success: u64,
+}
+
+/// Returns whether `ip` is an address no working upstream should hand out
+/// for a real host: unspecified, loopback, link-local, broadcast,
+/// multicast, or reserved for documentation.
+///
+/// Private addresses (10.0.0.0/8 and so on) aren't included, since plenty
+/// of internal names legitimately resolve to them.
+pub fn is_unroutable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                || ip.to_ipv4_mapped().is_some_and(|ip| {
+                    is_unroutable(&IpAddr::V4(ip))
+                })
+        }
+    }
+}
+
+/// The counters themselves, shared between the resolver and its handles
+struct Counters {
+    lookups: AtomicU64,
+    empty_answers: AtomicU64,
+    unroutable_answers: AtomicU64,
+    nxdomain_after_success: AtomicU64,
+    /// names that have resolved successfully
+    resolved: Mutex<BTreeSet<String>>,
+    /// when each recent NXDOMAIN-after-success happened, oldest first
+    recent: Mutex<VecDeque<Instant>>,
+    max_names: usize,
+    window: Duration,
+}
+
+impl Counters {
+    fn record_success(&self, name: &str, ips: &[IpAddr]) {
+        if ips.is_empty() {
+            self.empty_answers.fetch_add(1, Ordering::Relaxed);
+        } else if ips.iter().all(is_unroutable) {
+            self.unroutable_answers.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let mut resolved = self.resolved.lock().unwrap();
+            if resolved.len() < self.max_names {
+                resolved.insert(normalize(name).into_owned());
+            }
+        }
+    }
+
+    fn record_failure(&self, name: &str, error: &(dyn StdError + 'static)) {
+        if !is_nxdomain(error)
+            || !self.resolved.lock().unwrap().contains(&*normalize(name))
+        {
+            return;
+        }
+
+        self.nxdomain_after_success.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(now);
+        prune(&mut recent, now, self.window);
+    }
+}
+
+fn prune(recent: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while recent.front().is_some_and(|when| now - *when > window) {
+        recent.pop_front();
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+pub(crate) fn is_nxdomain(error: &(dyn StdError + 'static)) -> bool {
+    match error.downcast_ref::<trust_dns_resolver::error::ResolveError>() {
+        Some(error) => matches!(
+            error.kind(),
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NXDomain,
+                ..
+            }
+        ),
+        None => false,
+    }
+}
+
+/// Without trust-dns, nothing reports a name as nonexistent.
+#[cfg(not(feature = "trust-dns"))]
+pub(crate) fn is_nxdomain(_error: &(dyn StdError + 'static)) -> bool {
+    false
+}
+
+/// Reads the counters of an [`AnomalyResolver`]
+#[derive(Clone)]
+pub struct AnomalyCounters {
+    counters: Arc<Counters>,
+}
+
+impl AnomalyCounters {
+    pub fn snapshot(&self) -> AnomalyCounts {
+        let counters = &self.counters;
+        let recent = {
+            let mut recent = counters.recent.lock().unwrap();
+            prune(&mut recent, Instant::now(), counters.window);
+            recent.len() as u64
+        };
+        AnomalyCounts {
+            lookups: counters.lookups.load(Ordering::Relaxed),
+            empty_answers: counters.empty_answers.load(Ordering::Relaxed),
+            unroutable_answers: counters
+                .unroutable_answers
+                .load(Ordering::Relaxed),
+            nxdomain_after_success: counters
+                .nxdomain_after_success
+                .load(Ordering::Relaxed),
+            recent_nxdomain_after_success: recent,
+        }
+    }
+}
+
+/// Counts suspicious results from the inner resolver
+///
+/// Results are passed through unchanged.  To tell when an NXDOMAIN is for a
+/// name that used to resolve, this remembers up to
+/// [`DEFAULT_TRACKED_NAMES`] names that have resolved to routable
+/// addresses; names that resolve after that aren't tracked.
+///
+/// ```
+/// # use reqwest_resolve::anomaly::AnomalyResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = AnomalyResolver::new(MyCustomDnsResolver::new(resolver));
+/// let counters = my_resolver.counters();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// // Later, from a metrics exporter:
+/// let counts = counters.snapshot();
+/// assert_eq!(counts.empty_answers, 0);
+/// ```
+pub struct AnomalyResolver<R> {
+    inner: R,
+    counters: Arc<Counters>,
+}
+
+impl<R> AnomalyResolver<R> {
+    pub fn new(inner: R) -> AnomalyResolver<R> {
+        AnomalyResolver::with_window(inner, DEFAULT_ANOMALY_WINDOW)
+    }
+
+    /// Reports NXDOMAIN-after-success events within `window` as recent.
+    pub fn with_window(inner: R, window: Duration) -> AnomalyResolver<R> {
+        AnomalyResolver {
+            inner,
+            counters: Arc::new(Counters {
+                lookups: AtomicU64::new(0),
+                empty_answers: AtomicU64::new(0),
+                unroutable_answers: AtomicU64::new(0),
+                nxdomain_after_success: AtomicU64::new(0),
+                resolved: Mutex::new(BTreeSet::new()),
+                recent: Mutex::new(VecDeque::new()),
+                max_names: DEFAULT_TRACKED_NAMES,
+                window,
+            }),
+        }
+    }
+
+    /// Returns a handle for reading the counters, which keeps working after
+    /// the resolver has been handed to reqwest.
+    pub fn counters(&self) -> AnomalyCounters {
+        AnomalyCounters { counters: Arc::clone(&self.counters) }
+    }
+}
+
+impl<R: MyResolve> MyResolve for AnomalyResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            self.counters.lookups.fetch_add(1, Ordering::Relaxed);
+            match self.inner.resolve(name.clone()).await {
+                Ok(addrs) => {
+                    let addrs: AddrList = addrs.collect();
+                    let ips: IpList =
+                        addrs.iter().map(|addr| addr.ip()).collect();
+                    self.counters.record_success(name.as_str(), &ips);
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                }
+                Err(error) => {
+                    self.counters.record_failure(name.as_str(), &*error);
+                    Err(error)
+                }
+            }
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            self.counters.lookups.fetch_add(1, Ordering::Relaxed);
+            match self.inner.resolve_detailed(name.clone()).await {
+                Ok(addrs) => {
+                    let ips: IpList = addrs
+                        .iter()
+                        .map(|resolved| resolved.addr.ip())
+                        .collect();
+                    self.counters.record_success(name.as_str(), &ips);
+                    Ok(addrs)
+                }
+                Err(error) => {
+                    self.counters.record_failure(name.as_str(), &*error);
+                    Err(error)
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,139 @@
+//! Capping how many addresses a lookup returns
+//!
+//! Some hosts publish dozens of A and AAAA records.  hyper tries the
+//! addresses it's given one after another, so when the first few are
+//! unreachable, a request against such a host can spend a connect timeout
+//! on each of them before getting anywhere.  [`AnswerLimit`] passes on only
+//! a few of them, chosen by a [`Selection`].
+
+use crate::deterministic::random;
+use crate::logging::debug;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Ranks an address for [`Selection::Best`]: lower is better
+pub type Ranking = Arc<dyn Fn(&SocketAddr) -> i64 + Send + Sync>;
+
+/// Which addresses [`AnswerLimit`] keeps when there are too many
+#[derive(Clone)]
+pub enum Selection {
+    /// the first ones, in the order the inner resolver returned them
+    First,
+    /// randomly chosen ones (which spreads load across all of the host's
+    /// addresses), kept in the order the inner resolver returned them
+    Random,
+    /// the ones with the lowest rank, best first, with ties kept in the order
+    /// the inner resolver returned them
+    Best(Ranking),
+}
+
+/// Returns at most a fixed number of addresses from each lookup
+///
+/// Lookups with no more addresses than that are passed on as they are.
+/// Keep in mind that cutting the list short can leave out a whole address
+/// family, which matters on hosts that can only reach one of them.
+/// [`Selection::Best`] with a ranking that puts the reachable family first
+/// avoids that.
+///
+/// ```
+/// # use reqwest_resolve::answer_limit::{AnswerLimit, Selection};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "cdn.example.com" => ["2001:db8::1", "192.0.2.1", "192.0.2.2"],
+/// };
+/// // Keep two addresses, preferring IPv4.
+/// let ipv4_first = Arc::new(|addr: &std::net::SocketAddr| {
+///     i64::from(addr.is_ipv6())
+/// });
+/// let my_resolver = AnswerLimit::new(HOSTS, 2, Selection::Best(ipv4_first));
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct AnswerLimit<R> {
+    inner: R,
+    max: usize,
+    selection: Selection,
+}
+
+impl<R> AnswerLimit<R> {
+    /// Returns at most `max` addresses (at least 1) per lookup.
+    pub fn new(inner: R, max: usize, selection: Selection) -> AnswerLimit<R> {
+        AnswerLimit { inner, max: max.max(1), selection }
+    }
+
+    /// Returns the `max` items of `items` that `self.selection` picks.
+    fn select<T>(
+        &self,
+        name: &str,
+        mut items: Vec<T>,
+        addr: impl Fn(&T) -> SocketAddr,
+    ) -> Vec<T> {
+        if items.len() <= self.max {
+            return items;
+        }
+        debug!(
+            "limiting addresses",
+            name = name,
+            found = items.len(),
+            kept = self.max,
+        );
+
+        match &self.selection {
+            Selection::First => items.truncate(self.max),
+            Selection::Random => {
+                // Pick which to keep by shuffling indexes (only as far as
+                // needed), and then keep those in their original order.
+                let mut indexes: Vec<usize> = (0..items.len()).collect();
+                for i in 0..self.max {
+                    let j = i + random::<usize>() % (indexes.len() - i);
+                    indexes.swap(i, j);
+                }
+                let mut keep = vec![false; items.len()];
+                for i in &indexes[..self.max] {
+                    keep[*i] = true;
+                }
+                let mut keep = keep.into_iter();
+                items.retain(|_| keep.next().unwrap());
+            }
+            Selection::Best(rank) => {
+                // `sort_by_cached_key` is stable, so ties stay in order.
+                items.sort_by_cached_key(|item| rank(&addr(item)));
+                items.truncate(self.max);
+            }
+        }
+        items
+    }
+}
+
+impl<R: MyResolve> MyResolve for AnswerLimit<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs = self.inner.resolve(name.clone()).await?.collect();
+            let addrs = self.select(name.as_str(), addrs, |addr| *addr);
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let addrs = self.inner.resolve_detailed(name.clone()).await?;
+            Ok(self.select(name.as_str(), addrs, |resolved| resolved.addr))
+        }
+        .boxed()
+    }
+}
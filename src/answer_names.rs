@@ -0,0 +1,326 @@
+//! Checking that answers are about the name that was asked about
+//!
+//! A response to a query for "www.example.com" should only contain addresses
+//! for "www.example.com", or for the names it's an alias of (through a chain
+//! of CNAME records starting at "www.example.com").  A malicious or broken
+//! server can include records for other names too, and a resolver that's
+//! careless about which records it uses could end up handing those to the
+//! HTTP layer.  trust-dns is careful about this already, so
+//! [`AnswerNameValidation`] is defense in depth: it removes such records from
+//! every response before trust-dns sees them.
+
+use crate::logging::debug;
+use crate::transport::QueryFilter;
+use crate::transport::Upstream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::Record;
+use trust_dns_resolver::Name;
+
+/// A [`QueryFilter`] that drops records that aren't about the queried name
+///
+/// For a response to a query for some name, this keeps:
+///
+/// * in the answer section, the CNAME records that form a chain starting at
+///   that name (at most one per name), and the other records whose name is
+///   in that chain;
+/// * in the authority section, the SOA and NS records of the zone each name
+///   in the chain is in (like the SOA record of the zone that says a name
+///   doesn't exist), taking the zone to be the closest enclosing domain the
+///   section has an SOA or NS record for; and
+/// * in the additional section, records whose name is in the chain, or is
+///   the target of one of the NS records kept and within that zone (the
+///   "glue" addresses of its name servers).
+///
+/// Everything else is dropped.  In particular, records for the root and for
+/// top-level domains are only kept for queries about those domains
+/// themselves: otherwise an NS record for "." or "com." would make the
+/// server's word good for every name under it.  Responses with no question,
+/// or more than one, are left alone, since there's nothing to check them
+/// against.
+///
+/// ```
+/// # use reqwest_resolve::answer_names::AnswerNameValidation;
+/// # use reqwest_resolve::transport::{filtered_resolver, FilteredDnsResolver};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let validation = Arc::new(AnswerNameValidation::new());
+/// let resolver = filtered_resolver(
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+///     vec![validation.clone()],
+/// )
+/// .unwrap();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(FilteredDnsResolver::new(resolver)));
+///
+/// // Later, from a metrics exporter:
+/// assert_eq!(validation.dropped_records(), 0);
+/// ```
+#[derive(Debug, Default)]
+pub struct AnswerNameValidation {
+    dropped: AtomicU64,
+}
+
+impl AnswerNameValidation {
+    pub fn new() -> AnswerNameValidation {
+        AnswerNameValidation::default()
+    }
+
+    /// Returns how many records have been dropped so far.
+    pub fn dropped_records(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl QueryFilter for AnswerNameValidation {
+    fn on_response(
+        &self,
+        upstream: &Upstream,
+        _request: &Message,
+        response: &mut Message,
+    ) -> Result<(), ResolveError> {
+        let [query] = response.queries() else {
+            return Ok(());
+        };
+        let queried = query.name().clone();
+
+        let answers = response.take_answers();
+        let (chain, in_chain) = follow_chain(&queried, &answers);
+        let mut dropped = Vec::new();
+        for (record, in_chain) in answers.into_iter().zip(in_chain) {
+            let keep = match record.data() {
+                Some(RData::CNAME(_)) => in_chain,
+                _ => chain.contains(record.name()),
+            };
+            if keep {
+                response.add_answer(record);
+            } else {
+                dropped.push(record);
+            }
+        }
+
+        let authority = response.take_name_servers();
+        let zones = zone_cuts(&chain, &authority);
+        let (kept, rejected): (Vec<Record>, Vec<Record>) =
+            authority.into_iter().partition(|record| {
+                is_zone_record(record) && zones.contains(record.name())
+            });
+        let glue: Vec<Name> = kept
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::NS(target)) if record.name().zone_of(target) => {
+                    Some(target.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        response.insert_name_servers(kept);
+        dropped.extend(rejected);
+
+        let (kept, rejected): (Vec<Record>, Vec<Record>) =
+            response.take_additionals().into_iter().partition(|record| {
+                chain.contains(record.name()) || glue.contains(record.name())
+            });
+        response.insert_additionals(kept);
+        dropped.extend(rejected);
+
+        if !dropped.is_empty() {
+            self.dropped.fetch_add(dropped.len() as u64, Ordering::Relaxed);
+            for record in dropped {
+                debug!(
+                    "dropped record not about the queried name",
+                    upstream = upstream.addr,
+                    name = queried,
+                    record = record,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether `record` is one that describes a zone: its SOA record or
+/// one of its NS records.
+fn is_zone_record(record: &Record) -> bool {
+    matches!(record.data(), Some(RData::SOA(_)) | Some(RData::NS(_)))
+}
+
+/// Returns the zone each name in `chain` is in, as far as `authority` says:
+/// the owner of the SOA or NS record there that's the closest enclosing
+/// domain of the name
+///
+/// The root and top-level domains only count for names that are those
+/// domains themselves.
+fn zone_cuts(chain: &[Name], authority: &[Record]) -> Vec<Name> {
+    let mut zones = Vec::new();
+    for name in chain {
+        let closest = authority
+            .iter()
+            .filter(|record| is_zone_record(record))
+            .map(Record::name)
+            .filter(|owner| owner.zone_of(name))
+            .filter(|owner| owner.num_labels() >= 2 || *owner == name)
+            .max_by_key(|owner| owner.num_labels());
+        if let Some(zone) = closest {
+            if !zones.contains(zone) {
+                zones.push(zone.clone());
+            }
+        }
+    }
+    zones
+}
+
+/// Follows the chain of CNAME records in `answers` starting at `name`,
+/// returning the names in the chain and which of the answers are the CNAME
+/// records that make it up
+fn follow_chain(name: &Name, answers: &[Record]) -> (Vec<Name>, Vec<bool>) {
+    let mut chain = vec![name.clone()];
+    let mut in_chain = vec![false; answers.len()];
+    while let Some((i, target)) =
+        answers.iter().enumerate().find_map(|(i, record)| match record.data() {
+            Some(RData::CNAME(target))
+                if record.name() == chain.last().unwrap() =>
+            {
+                Some((i, target))
+            }
+            _ => None,
+        })
+    {
+        in_chain[i] = true;
+        // A chain that loops back on itself ends where it starts repeating.
+        if chain.contains(target) {
+            break;
+        }
+        chain.push(target.clone());
+    }
+    (chain, in_chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnswerNameValidation;
+    use crate::transport::QueryFilter;
+    use crate::transport::Upstream;
+    use trust_dns_resolver::config::Protocol;
+    use trust_dns_resolver::proto::op::Message;
+    use trust_dns_resolver::proto::op::Query;
+    use trust_dns_resolver::proto::rr::rdata::SOA;
+    use trust_dns_resolver::proto::rr::RData;
+    use trust_dns_resolver::proto::rr::Record;
+    use trust_dns_resolver::proto::rr::RecordType;
+    use trust_dns_resolver::Name;
+
+    fn name(name: &str) -> Name {
+        name.parse().unwrap()
+    }
+
+    fn a(owner: &str, ip: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::A(ip.parse().unwrap()))
+    }
+
+    fn ns(owner: &str, target: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::NS(name(target)))
+    }
+
+    fn soa(owner: &str) -> Record {
+        let soa = SOA::new(name(owner), name(owner), 1, 3600, 600, 86400, 300);
+        Record::from_rdata(name(owner), 300, RData::SOA(soa))
+    }
+
+    fn response(queried: &str) -> Message {
+        let mut response = Message::new();
+        response.add_query(Query::query(name(queried), RecordType::A));
+        response
+    }
+
+    fn validate(response: &mut Message) -> u64 {
+        let upstream = Upstream {
+            addr: "192.0.2.53:53".parse().unwrap(),
+            protocol: Protocol::Udp,
+        };
+        let validation = AnswerNameValidation::new();
+        validation.on_response(&upstream, &Message::new(), response).unwrap();
+        validation.dropped_records()
+    }
+
+    fn names(records: &[Record]) -> Vec<String> {
+        records.iter().map(|record| record.name().to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_the_answer_and_its_zone() {
+        let mut message = response("www.example.com.");
+        message.add_answer(a("www.example.com.", "192.0.2.1"));
+        message.add_name_server(ns("example.com.", "ns1.example.com."));
+        message.add_additional(a("ns1.example.com.", "192.0.2.53"));
+        assert_eq!(validate(&mut message), 0);
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(message.name_servers().len(), 1);
+        assert_eq!(names(message.additionals()), ["ns1.example.com."]);
+    }
+
+    #[test]
+    fn drops_root_authority_and_what_it_would_vouch_for() {
+        let mut message = response("www.example.com.");
+        message.add_answer(a("www.example.com.", "192.0.2.1"));
+        message.add_name_server(ns(".", "www.bank.com."));
+        message.add_additional(a("www.bank.com.", "203.0.113.66"));
+        assert_eq!(validate(&mut message), 2);
+        assert_eq!(message.answers().len(), 1);
+        assert!(message.name_servers().is_empty());
+        assert!(message.additionals().is_empty());
+    }
+
+    #[test]
+    fn drops_tld_authority() {
+        let mut message = response("www.example.com.");
+        message.add_name_server(soa("com."));
+        message.add_name_server(ns("com.", "www.bank.com."));
+        message.add_additional(a("www.bank.com.", "203.0.113.66"));
+        assert_eq!(validate(&mut message), 3);
+        assert!(message.name_servers().is_empty());
+        assert!(message.additionals().is_empty());
+    }
+
+    #[test]
+    fn keeps_only_the_closest_zone() {
+        let mut message = response("www.example.com.");
+        message.add_name_server(soa("example.com."));
+        message.add_name_server(ns("com.", "a.gtld-servers.net."));
+        assert_eq!(validate(&mut message), 1);
+        assert_eq!(names(message.name_servers()), ["example.com."]);
+    }
+
+    #[test]
+    fn drops_glue_outside_the_zone_and_for_names_not_listed() {
+        let mut message = response("www.example.com.");
+        message.add_name_server(ns("example.com.", "ns1.example.com."));
+        message.add_name_server(ns("example.com.", "ns.provider.net."));
+        message.add_additional(a("ns1.example.com.", "192.0.2.53"));
+        message.add_additional(a("ns.provider.net.", "198.51.100.53"));
+        message.add_additional(a("mail.example.com.", "192.0.2.25"));
+        assert_eq!(validate(&mut message), 2);
+        assert_eq!(names(message.additionals()), ["ns1.example.com."]);
+    }
+
+    #[test]
+    fn drops_authority_records_other_than_soa_and_ns() {
+        let mut message = response("www.example.com.");
+        message.add_name_server(soa("example.com."));
+        message.add_name_server(a("example.com.", "192.0.2.1"));
+        assert_eq!(validate(&mut message), 1);
+        assert_eq!(message.name_servers().len(), 1);
+    }
+
+    #[test]
+    fn root_authority_counts_for_the_root_itself() {
+        let mut message = response(".");
+        message.add_name_server(soa("."));
+        assert_eq!(validate(&mut message), 0);
+        assert_eq!(message.name_servers().len(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+//! Defending against off-path spoofing of DNS responses
+//!
+//! An attacker who can't see our queries can still try to race the real
+//! server's response with forged ones.  Over UDP, the only things we have
+//! for telling the two apart are what the forger has to guess: the query ID,
+//! the source port, and whatever the response has to echo back from the
+//! query.  trust-dns already picks a random ID and source port for each UDP
+//! query, and its UDP transport ignores datagrams that don't come from the
+//! server's address or don't carry the query's ID.  [`AntiSpoofing`] adds
+//! the rest:
+//!
+//! * Every response must echo the question exactly.  (trust-dns doesn't check
+//!   this at all.)
+//! * With "0x20" case randomization (draft-vixie-dnsext-dns0x20), the letters
+//!   in each UDP query's name are randomly upper- or lowercased, and the
+//!   response has to echo the same case.  For a name with n letters, that's
+//!   up to n more bits for a forger to guess.
+//!
+//! Some servers and middleboxes don't preserve the case of the question, and
+//! every response from those will be rejected with case randomization on.
+//! If that happens, turn it off with
+//! [`AntiSpoofing::without_case_randomization`].
+
+use crate::deterministic::random;
+use crate::transport::QueryFilter;
+use crate::transport::Upstream;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::MessageType;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::Name;
+
+/// A [`QueryFilter`] that randomizes the case of UDP queries and validates
+/// that responses echo their question
+///
+/// ```
+/// # use reqwest_resolve::anti_spoofing::AntiSpoofing;
+/// # use reqwest_resolve::transport::{filtered_resolver, FilteredDnsResolver};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let resolver = filtered_resolver(
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+///     vec![Arc::new(AntiSpoofing::new())],
+/// )
+/// .unwrap();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(FilteredDnsResolver::new(resolver)));
+/// ```
+pub struct AntiSpoofing {
+    case_randomization: bool,
+}
+
+impl AntiSpoofing {
+    /// Validates responses and randomizes the case of UDP queries.
+    pub fn new() -> AntiSpoofing {
+        AntiSpoofing { case_randomization: true }
+    }
+
+    /// Validates responses, but leaves the case of queries alone.
+    pub fn without_case_randomization() -> AntiSpoofing {
+        AntiSpoofing { case_randomization: false }
+    }
+}
+
+impl Default for AntiSpoofing {
+    fn default() -> AntiSpoofing {
+        AntiSpoofing::new()
+    }
+}
+
+impl QueryFilter for AntiSpoofing {
+    fn on_request(&self, upstream: &Upstream, request: &mut Message) {
+        // Over TCP and the encrypted transports, a forger would have to be
+        // on-path, and then the case wouldn't be secret anyway.
+        if !self.case_randomization || upstream.protocol != Protocol::Udp {
+            return;
+        }
+
+        for query in request.queries_mut() {
+            let randomized = randomize_case(query.name());
+            query.set_name(randomized);
+        }
+    }
+
+    fn on_response(
+        &self,
+        upstream: &Upstream,
+        request: &Message,
+        response: &mut Message,
+    ) -> Result<(), ResolveError> {
+        let reject = |message: &str| {
+            Err(ResolveError::from(format!(
+                "rejected response from {} ({:?}): {}",
+                upstream.addr, upstream.protocol, message
+            )))
+        };
+
+        if response.message_type() != MessageType::Response
+            || response.op_code() != request.op_code()
+        {
+            return reject("not a response to a query");
+        }
+
+        // Servers commonly leave out the question when they reject a query
+        // outright.  Such a response can't put anything into the cache, so
+        // there's no point rejecting it.
+        let rejected_query = matches!(
+            response.response_code(),
+            ResponseCode::FormErr
+                | ResponseCode::NotImp
+                | ResponseCode::Refused
+                | ResponseCode::ServFail
+        );
+        if response.queries().is_empty() && rejected_query {
+            return Ok(());
+        }
+
+        let exact_case =
+            self.case_randomization && upstream.protocol == Protocol::Udp;
+        let matches = response.queries().len() == request.queries().len()
+            && response.queries().iter().zip(request.queries()).all(
+                |(answered, asked)| {
+                    answered.query_type() == asked.query_type()
+                        && answered.query_class() == asked.query_class()
+                        && if exact_case {
+                            answered.name().eq_case(asked.name())
+                        } else {
+                            answered.name() == asked.name()
+                        }
+                },
+            );
+        if !matches {
+            return reject("question does not match the query");
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns `name` with each ASCII letter randomly upper- or lowercased.
+fn randomize_case(name: &Name) -> Name {
+    let labels = name.iter().map(|label| {
+        label
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_alphabetic() && random::<bool>() {
+                    byte ^ 0x20
+                } else {
+                    *byte
+                }
+            })
+            .collect::<Vec<u8>>()
+    });
+
+    match Name::from_labels(labels) {
+        Ok(mut randomized) => {
+            randomized.set_fqdn(name.is_fqdn());
+            randomized
+        }
+        Err(_) => name.clone(),
+    }
+}
@@ -0,0 +1,188 @@
+//! Writing resolvers as plain `async fn`s
+//!
+//! Implementing `MyResolve` means naming the future being returned, and
+//! writing `async move { ... }.boxed().into()` around the lookup.  For a
+//! resolver that only needs to look a name up and hand back its addresses,
+//! [`AsyncResolve`] is simpler: it's one `async fn`, written with
+//! [`macro@async_trait`] (re-exported here so that implementations don't
+//! need to depend on it themselves).  The adapters here go between it and
+//! the other two traits:
+//!
+//! * [`FromAsync`] is a `MyResolve`, and a reqwest `Resolve`, that looks
+//!   names up through an `AsyncResolve`.
+//! * [`IntoAsync`] is an `AsyncResolve` that looks names up through a
+//!   `MyResolve`, for code written against `AsyncResolve` that needs to use
+//!   a stack from this crate.
+//! * [`FromReqwest`] is a `MyResolve` that looks names up through a reqwest
+//!   `Resolve`, so a resolver written for reqwest can go under this crate's
+//!   layers (or, through `IntoAsync`, be used as an `AsyncResolve`).
+//!
+//! There's no blanket implementation of `AsyncResolve` for `MyResolve`
+//! types, because a type implementing both would make every call to
+//! `resolve` ambiguous wherever both traits are in scope.
+
+use crate::global::overridable;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub use async_trait::async_trait;
+
+/// A resolver written as an `async fn` (see the [module
+/// documentation](self))
+///
+/// The ports in the addresses returned are handled the same way as
+/// `MyResolve::resolve`'s: reqwest ignores them, and 0 is fine.
+///
+/// ```
+/// # use reqwest_resolve::async_resolve::{async_trait, AsyncResolve, FromAsync};
+/// # use std::error::Error as StdError;
+/// # use std::net::SocketAddr;
+/// # use std::sync::Arc;
+/// struct Localhost;
+///
+/// #[async_trait]
+/// impl AsyncResolve for Localhost {
+///     async fn resolve(
+///         &self,
+///         name: &str,
+///     ) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>> {
+///         match name {
+///             "localhost" => Ok(vec!["127.0.0.1:0".parse().unwrap()]),
+///             _ => Err(format!("{} is not localhost", name).into()),
+///         }
+///     }
+/// }
+///
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(FromAsync::new(Localhost)));
+/// ```
+#[async_trait]
+pub trait AsyncResolve: Send + Sync {
+    async fn resolve(
+        &self,
+        name: &str,
+    ) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>>;
+}
+
+/// Looks names up through an [`AsyncResolve`], as either a `MyResolve` (to
+/// put layers around it) or a reqwest `Resolve` (to give it straight to a
+/// client)
+pub struct FromAsync<R: ?Sized> {
+    inner: Arc<R>,
+}
+
+impl<R> FromAsync<R> {
+    pub fn new(inner: R) -> FromAsync<R> {
+        FromAsync { inner: Arc::new(inner) }
+    }
+}
+
+impl<R: ?Sized> FromAsync<R> {
+    /// Adapts a resolver that's already behind an `Arc`, which may be a
+    /// trait object.
+    pub fn from_arc(inner: Arc<R>) -> FromAsync<R> {
+        FromAsync { inner }
+    }
+}
+
+impl<R: AsyncResolve + ?Sized> MyResolve for FromAsync<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs = self.inner.resolve(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+}
+
+impl<R: AsyncResolve + ?Sized + 'static> reqwest::dns::Resolve
+    for FromAsync<R>
+{
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let adapter = FromAsync::from_arc(Arc::clone(&self.inner));
+        async move {
+            overridable(name, |name| MyResolve::resolve(&adapter, name)).await
+        }
+        .boxed()
+    }
+}
+
+/// Looks names up through a `MyResolve`, as an [`AsyncResolve`]
+///
+/// Names that aren't valid hostnames fail with
+/// [`ResolveError::InvalidName`](crate::error::ResolveError::InvalidName)
+/// without being looked up.
+pub struct IntoAsync<R> {
+    inner: R,
+}
+
+impl<R> IntoAsync<R> {
+    pub fn new(inner: R) -> IntoAsync<R> {
+        IntoAsync { inner }
+    }
+}
+
+#[async_trait]
+impl<R: MyResolve> AsyncResolve for IntoAsync<R> {
+    async fn resolve(
+        &self,
+        name: &str,
+    ) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>> {
+        self.inner.resolve_to_vec(name).await
+    }
+}
+
+/// Looks names up through a reqwest `Resolve`, as a `MyResolve`
+///
+/// ```
+/// # use reqwest_resolve::async_resolve::FromReqwest;
+/// # use reqwest_resolve::audit::AuditResolver;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use reqwest_resolve::system::SystemResolver;
+/// # fn existing_resolver() -> Arc<dyn reqwest::dns::Resolve> {
+/// #     Arc::new(ResolveAdapter::new(SystemResolver))
+/// # }
+/// let resolver: Arc<dyn reqwest::dns::Resolve> = existing_resolver();
+/// let my_resolver = AuditResolver::new(FromReqwest::from_arc(resolver));
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct FromReqwest<R: ?Sized> {
+    inner: Arc<R>,
+}
+
+impl<R> FromReqwest<R> {
+    pub fn new(inner: R) -> FromReqwest<R> {
+        FromReqwest { inner: Arc::new(inner) }
+    }
+}
+
+impl<R: ?Sized> FromReqwest<R> {
+    /// Adapts a resolver that's already behind an `Arc`, which is how
+    /// reqwest takes them.
+    pub fn from_arc(inner: Arc<R>) -> FromReqwest<R> {
+        FromReqwest { inner }
+    }
+}
+
+impl<R: reqwest::dns::Resolve + ?Sized> MyResolve for FromReqwest<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        self.inner.resolve(name).into()
+    }
+}
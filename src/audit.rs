@@ -0,0 +1,248 @@
+//! Remembering what the resolver did recently
+//!
+//! When requests start failing, the question is usually what the resolver
+//! was doing just before: which names it looked up, what it got back, how
+//! long that took, and where the answers came from (down to each query sent
+//! upstream, with a `cname_trace::CnameTraceFilter`).  Logs have that only if
+//! debug logging was turned on in advance.  [`AuditResolver`] keeps the last
+//! few lookups in memory instead, and [`AuditLog`] reads them back, so a
+//! crash handler or debug endpoint (like `admin::AdminEndpoints::audit`,
+//! with the "admin" feature) can show exactly what happened.
+
+use crate::cname_trace::traced;
+use crate::cname_trace::QueryHop;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// How many lookups [`AuditResolver::new`] remembers
+pub const DEFAULT_AUDIT_CAPACITY: usize = 1000;
+
+/// One lookup made through an [`AuditResolver`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AuditEntry {
+    /// the name looked up
+    pub name: String,
+    /// when the lookup started
+    pub started: SystemTime,
+    /// how long it took
+    pub latency: Duration,
+    /// the addresses found, or what went wrong
+    pub result: Result<Vec<SocketAddr>, String>,
+    /// where the addresses came from (see `ResolvedAddr::backend`), each
+    /// backend once.  This is empty if the lookup failed.
+    pub backends: Vec<Cow<'static, str>>,
+    /// the queries the lookup sent upstream, if they went through a
+    /// `cname_trace::CnameTraceFilter`
+    pub hops: Vec<QueryHop>,
+}
+
+struct Entries {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl Entries {
+    fn record(&self, entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+}
+
+/// Reads the lookups remembered by an [`AuditResolver`]
+#[derive(Clone)]
+pub struct AuditLog {
+    entries: Arc<Entries>,
+}
+
+impl AuditLog {
+    /// Returns every lookup remembered, oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the lookups remembered that started within the last
+    /// `period`, oldest first.
+    pub fn recent(&self, period: Duration) -> Vec<AuditEntry> {
+        let since = SystemTime::now().checked_sub(period);
+        let entries = self.entries.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| since.map_or(true, |since| entry.started >= since))
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets every lookup remembered so far.
+    pub fn clear(&self) {
+        self.entries.entries.lock().unwrap().clear();
+    }
+}
+
+/// Remembers the most recent lookups made through the inner resolver
+///
+/// Results are passed through unchanged.  Once the log is full, each new
+/// lookup pushes out the oldest one.  Lookups are recorded when they finish,
+/// so the log is in the order lookups finished, and lookups that are
+/// cancelled (say, because reqwest gave up on the request) aren't recorded.
+///
+/// ```
+/// # use reqwest_resolve::audit::AuditResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = AuditResolver::new(MyCustomDnsResolver::new(resolver));
+/// let log = my_resolver.log();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// // Later, from a debug endpoint:
+/// for entry in log.recent(Duration::from_secs(30)) {
+///     println!("{} {:?} {:?}", entry.name, entry.latency, entry.result);
+/// }
+/// ```
+pub struct AuditResolver<R> {
+    inner: R,
+    entries: Arc<Entries>,
+}
+
+impl<R> AuditResolver<R> {
+    /// Remembers the last [`DEFAULT_AUDIT_CAPACITY`] lookups.
+    pub fn new(inner: R) -> AuditResolver<R> {
+        AuditResolver::with_capacity(inner, DEFAULT_AUDIT_CAPACITY)
+    }
+
+    /// Remembers the last `capacity` lookups.
+    pub fn with_capacity(inner: R, capacity: usize) -> AuditResolver<R> {
+        AuditResolver {
+            inner,
+            entries: Arc::new(Entries {
+                capacity,
+                entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            }),
+        }
+    }
+
+    /// Returns a handle for reading the log, which keeps working after the
+    /// resolver has been handed to reqwest.
+    pub fn log(&self) -> AuditLog {
+        AuditLog { entries: Arc::clone(&self.entries) }
+    }
+}
+
+impl<R: MyResolve> MyResolve for AuditResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        // Lookups from reqwest come in through here, and are recorded with
+        // their backends all the same.
+        let lookup = self.resolve_detailed(name);
+        async move {
+            let addrs = lookup.await?.into_iter().map(|resolved| resolved.addr);
+            Ok(Box::new(addrs) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let started = SystemTime::now();
+            let start = Instant::now();
+            let (result, hops) =
+                traced(self.inner.resolve_detailed(name.clone())).await;
+            let latency = start.elapsed();
+            let (recorded, backends) = match &result {
+                Ok(addrs) => {
+                    let mut backends: Vec<Cow<'static, str>> = Vec::new();
+                    for addr in addrs {
+                        if !backends.contains(&addr.backend) {
+                            backends.push(addr.backend.clone());
+                        }
+                    }
+                    (Ok(addrs.iter().map(|addr| addr.addr).collect()), backends)
+                }
+                Err(error) => (Err(error.to_string()), Vec::new()),
+            };
+            self.entries.record(AuditEntry {
+                name: name.as_str().to_owned(),
+                started,
+                latency,
+                result: recorded,
+                backends,
+                hops,
+            });
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuditResolver;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use std::time::Duration;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "a.example.com" => ["192.0.2.1"],
+        "b.example.com" => ["192.0.2.2"],
+    };
+
+    #[tokio::test]
+    async fn records_lookups() {
+        let resolver = AuditResolver::with_capacity(HOSTS, 2);
+        let log = resolver.log();
+
+        resolver.resolve_to_vec("a.example.com").await.unwrap();
+        resolver.resolve_to_vec("missing.example.com").await.unwrap_err();
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.example.com");
+        assert_eq!(entries[0].result, Ok(vec!["192.0.2.1:0".parse().unwrap()]));
+        assert_eq!(entries[0].backends, ["static"]);
+        assert!(entries[1].result.is_err());
+        assert!(entries[1].backends.is_empty());
+
+        // The oldest lookup makes room for the newest.
+        resolver.resolve_to_vec("b.example.com").await.unwrap();
+        let names: Vec<_> =
+            log.entries().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["missing.example.com", "b.example.com"]);
+
+        assert_eq!(log.recent(Duration::from_secs(60)).len(), 2);
+        assert_eq!(log.recent(Duration::MAX).len(), 2);
+        log.clear();
+        assert!(log.entries().is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+//! Using resolver stacks from synchronous code
+//!
+//! Everything in this crate is async, and most of it needs a Tokio runtime:
+//! to run lookups, and for the background tasks that keep caches fresh or
+//! watch configuration.  Programs built on `reqwest::blocking` don't have
+//! one of their own, so [`BlockingResolver`] brings its own.  It runs one
+//! worker thread for the stack's background tasks and its lookups, and can
+//! both answer lookups for synchronous code directly (see
+//! [`BlockingResolver::resolve`]) and stand in as the resolver for blocking
+//! clients (see [`BlockingResolver::client_builder`]), so that a program's
+//! synchronous parts share the same cache, policies, and upstreams as
+//! everything else.
+//!
+//! Blocking clients run their requests on a runtime of their own, but the
+//! lookups they make through a `BlockingResolver` are sent to its runtime,
+//! which is where the stack's tasks have to live.
+
+use crate::error::ResolveError;
+use crate::global::task_override;
+use crate::panics::isolate;
+use crate::MyResolve;
+use futures::future::FutureExt;
+use std::error::Error as StdError;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// Runs a resolver stack on a runtime of its own, for synchronous callers
+///
+/// ```
+/// # use reqwest_resolve::blocking::BlockingResolver;
+/// # use reqwest_resolve::cache::{CachingResolver, TrustDnsBackend};
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// // A stack that spawns tasks when it's built has to be built on the
+/// // runtime it will run on.
+/// let resolver = BlockingResolver::build(|| {
+///     let backend = TrustDnsBackend::new(
+///         ResolverConfig::default(),
+///         ResolverOpts::default(),
+///     )
+///     .unwrap();
+///     CachingResolver::new(backend).with_background_refresh(2)
+/// })
+/// .unwrap();
+///
+/// let _client = resolver.client_builder().build().unwrap();
+/// ```
+///
+/// Like a `handle::ResolverHandle`, a `BlockingResolver` is cheap to clone,
+/// and the clones share the stack and the runtime, which shuts down (along
+/// with any background tasks) when the last clone is dropped.
+pub struct BlockingResolver<R: ?Sized = dyn MyResolve> {
+    runtime: Arc<OwnedRuntime>,
+    resolver: Arc<R>,
+}
+
+/// The runtime, shut down without waiting when it's dropped
+///
+/// The last clone of a resolver may be dropped on a blocking client's
+/// runtime, where waiting isn't allowed.
+struct OwnedRuntime(Option<Runtime>);
+
+impl OwnedRuntime {
+    fn get(&self) -> &Runtime {
+        self.0.as_ref().expect("runtime used after shutdown")
+    }
+}
+
+impl Drop for OwnedRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl<R> BlockingResolver<R> {
+    /// Runs `resolver` on a new runtime.  Use [`BlockingResolver::build`]
+    /// instead if building the stack spawns any tasks.
+    pub fn new(resolver: R) -> io::Result<BlockingResolver<R>> {
+        BlockingResolver::build(|| resolver)
+    }
+
+    /// Builds a stack with `build`, on a new runtime, and runs it there.
+    pub fn build(build: impl FnOnce() -> R) -> io::Result<BlockingResolver<R>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("reqwest-resolve")
+            .enable_all()
+            .build()?;
+        let resolver = {
+            let _entered = runtime.enter();
+            build()
+        };
+        Ok(BlockingResolver {
+            runtime: Arc::new(OwnedRuntime(Some(runtime))),
+            resolver: Arc::new(resolver),
+        })
+    }
+}
+
+impl<R: MyResolve + 'static> BlockingResolver<R> {
+    /// Returns a resolver that can run any stack, for storing where
+    /// different parts of a program may be given different stacks.
+    pub fn erase(self) -> BlockingResolver {
+        BlockingResolver { runtime: self.runtime, resolver: self.resolver }
+    }
+}
+
+impl<R: ?Sized> BlockingResolver<R> {
+    /// Returns the stack, for whatever it offers besides lookups.
+    pub fn inner(&self) -> &R {
+        &self.resolver
+    }
+}
+
+impl<R: MyResolve + ?Sized + 'static> BlockingResolver<R> {
+    /// Resolves `name`, waiting for the answer
+    ///
+    /// The ports in the addresses returned are 0, as with
+    /// `MyResolve::resolve_to_vec`.
+    ///
+    /// ```
+    /// # use reqwest_resolve::blocking::BlockingResolver;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "example.com" => ["192.0.2.1"],
+    /// };
+    /// let resolver = BlockingResolver::new(HOSTS).unwrap();
+    /// let addrs = resolver.resolve("example.com").unwrap();
+    /// assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Like any other way of blocking on a future, this panics if it's called
+    /// from async code.
+    pub fn resolve(
+        &self,
+        name: &str,
+    ) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>> {
+        self.runtime.get().block_on(self.resolver.resolve_to_vec(name))
+    }
+
+    /// Resolves `name` for connecting to `port`, waiting for the answer (see
+    /// `MyResolve::resolve_with_port`)
+    ///
+    /// # Panics
+    ///
+    /// This panics if it's called from async code.
+    pub fn resolve_with_port(
+        &self,
+        name: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>> {
+        let Ok(parsed) = hyper::client::connect::dns::Name::from_str(name)
+        else {
+            let error = ResolveError::InvalidName { name: name.to_owned() };
+            return Err(error.into());
+        };
+        let lookup = self.resolver.resolve_with_port(parsed, port);
+        self.runtime.get().block_on(async { Ok(lookup.await?.collect()) })
+    }
+
+    /// Returns a blocking client builder that resolves names through this
+    /// resolver.
+    pub fn client_builder(&self) -> reqwest::blocking::ClientBuilder {
+        reqwest::ClientBuilder::new()
+            .dns_resolver(Arc::new(self.clone()))
+            .into()
+    }
+}
+
+impl<R: ?Sized> Clone for BlockingResolver<R> {
+    fn clone(&self) -> BlockingResolver<R> {
+        BlockingResolver {
+            runtime: Arc::clone(&self.runtime),
+            resolver: Arc::clone(&self.resolver),
+        }
+    }
+}
+
+impl<R: MyResolve + ?Sized + 'static> reqwest::dns::Resolve
+    for BlockingResolver<R>
+{
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = Arc::clone(&self.resolver);
+        // Holding on to the runtime keeps it from shutting down while the
+        // lookup runs.
+        let runtime = Arc::clone(&self.runtime);
+        async move {
+            let host = name.as_str().to_owned();
+            // The override (see `global::with_task_override`) belongs to the
+            // caller's task, not the one the lookup runs on.
+            let task_override = task_override();
+            let lookup = runtime.get().spawn(async move {
+                let host = name.as_str().to_owned();
+                match task_override {
+                    Some(resolver) => {
+                        isolate(&host, || resolver.resolve(name)).await
+                    }
+                    None => isolate(&host, || resolver.resolve(name)).await,
+                }
+            });
+            match lookup.await {
+                Ok(result) => result,
+                Err(error) => Err(ResolveError::Internal {
+                    name: host,
+                    message: error.to_string(),
+                }
+                .into()),
+            }
+        }
+        .boxed()
+    }
+}
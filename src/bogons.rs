@@ -0,0 +1,258 @@
+//! Removing addresses that can't belong to a real host
+//!
+//! Some answers contain addresses that nothing on the Internet should be
+//! reached at: 0.0.0.0 (which some blocklisting resolvers hand out), the
+//! broadcast address, the prefixes reserved for documentation and
+//! benchmarking, and the rest of the well-known bogon ranges.  A connection
+//! to one of those fails in a confusing way, or worse, reaches something on
+//! the local machine or network that the request was never meant for.
+//! [`BogonFilter`] removes them from answers before reqwest sees them, and
+//! counts how often it does.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// IPv4 bogons that are never a real host's address, as (network, prefix
+/// length)
+const BOGONS_V4: &[(Ipv4Addr, u8)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8),   // "this network"
+    (Ipv4Addr::new(127, 0, 0, 0), 8), // loopback
+    (Ipv4Addr::new(169, 254, 0, 0), 16), // link-local
+    (Ipv4Addr::new(192, 0, 0, 0), 24), // IETF protocol assignments
+    (Ipv4Addr::new(192, 0, 2, 0), 24), // documentation (TEST-NET-1)
+    (Ipv4Addr::new(198, 18, 0, 0), 15), // benchmarking
+    (Ipv4Addr::new(198, 51, 100, 0), 24), // documentation (TEST-NET-2)
+    (Ipv4Addr::new(203, 0, 113, 0), 24), // documentation (TEST-NET-3)
+    (Ipv4Addr::new(224, 0, 0, 0), 4), // multicast
+    (Ipv4Addr::new(240, 0, 0, 0), 4), // reserved, and broadcast
+];
+
+/// IPv4 ranges for private networks, which are only bogons for names that
+/// should be on the Internet
+const PRIVATE_V4: &[(Ipv4Addr, u8)] = &[
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(100, 64, 0, 0), 10), // carrier-grade NAT
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+];
+
+/// IPv6 bogons that are never a real host's address
+const BOGONS_V6: &[(Ipv6Addr, u8)] = &[
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 96), // unspecified, loopback,
+    // and the deprecated IPv4-compatible addresses
+    (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0), 64), // discard-only
+    (Ipv6Addr::new(0x2001, 0x10, 0, 0, 0, 0, 0, 0), 28), // ORCHID
+    (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32), // documentation
+    (Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0), 20), // documentation
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10), // link-local
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10), // site-local
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8), // multicast
+];
+
+/// IPv6 unique local addresses, the equivalent of the private IPv4 ranges
+const PRIVATE_V6: &[(Ipv6Addr, u8)] =
+    &[(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7)];
+
+/// Returns whether `ip` is in one of the well-known bogon ranges, counting
+/// the private ranges (10.0.0.0/8, fc00::/7, and so on) only if `private`
+/// is true
+///
+/// IPv4-mapped IPv6 addresses are judged by the IPv4 address they map.
+///
+/// ```
+/// # use reqwest_resolve::bogons::is_bogon;
+/// assert!(is_bogon("0.0.0.0".parse().unwrap(), false));
+/// assert!(is_bogon("255.255.255.255".parse().unwrap(), false));
+/// assert!(is_bogon("2001:db8::1".parse().unwrap(), false));
+/// assert!(!is_bogon("10.1.2.3".parse().unwrap(), false));
+/// assert!(is_bogon("10.1.2.3".parse().unwrap(), true));
+/// assert!(!is_bogon("1.1.1.1".parse().unwrap(), true));
+/// ```
+pub fn is_bogon(ip: IpAddr, private: bool) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip);
+            let within = |(network, prefix): &(Ipv4Addr, u8)| {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix));
+                let mask = mask.unwrap_or(0);
+                bits & mask == u32::from(*network)
+            };
+            BOGONS_V4.iter().any(within)
+                || (private && PRIVATE_V4.iter().any(within))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_bogon(IpAddr::V4(mapped), private);
+            }
+            let bits = u128::from(ip);
+            let within = |(network, prefix): &(Ipv6Addr, u8)| {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix));
+                let mask = mask.unwrap_or(0);
+                bits & mask == u128::from(*network)
+            };
+            BOGONS_V6.iter().any(within)
+                || (private && PRIVATE_V6.iter().any(within))
+        }
+    }
+}
+
+/// A snapshot of the counters kept by a [`BogonFilter`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct BogonCounts {
+    /// lookups that had at least one address removed
+    pub filtered_lookups: u64,
+    /// addresses removed, in all
+    pub removed_addresses: u64,
+    /// lookups that failed because every address was removed
+    pub emptied_lookups: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    filtered_lookups: AtomicU64,
+    removed_addresses: AtomicU64,
+    emptied_lookups: AtomicU64,
+}
+
+/// Reads the counters of a [`BogonFilter`]
+#[derive(Clone)]
+pub struct BogonCounters {
+    counters: Arc<Counters>,
+}
+
+impl BogonCounters {
+    pub fn snapshot(&self) -> BogonCounts {
+        let counters = &self.counters;
+        BogonCounts {
+            filtered_lookups: counters.filtered_lookups.load(Ordering::Relaxed),
+            removed_addresses: counters
+                .removed_addresses
+                .load(Ordering::Relaxed),
+            emptied_lookups: counters.emptied_lookups.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Removes bogon addresses (see [`is_bogon`]) from the inner resolver's
+/// answers
+///
+/// A lookup whose addresses were all removed fails with
+/// [`ResolveError::AddressesFiltered`].  Put this around the part of the
+/// stack that talks to DNS, rather than around tables that may map names
+/// to loopback or private addresses on purpose.
+///
+/// ```
+/// # use reqwest_resolve::bogons::BogonFilter;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "blocked.example.com" => ["0.0.0.0"],
+///     "www.example.com" => ["93.184.216.34"],
+/// };
+/// let my_resolver = BogonFilter::new(HOSTS);
+/// let counters = my_resolver.counters();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// // Later, from a metrics exporter:
+/// assert_eq!(counters.snapshot().removed_addresses, 0);
+/// ```
+pub struct BogonFilter<R> {
+    inner: R,
+    private: bool,
+    counters: Arc<Counters>,
+}
+
+impl<R> BogonFilter<R> {
+    /// Removes bogons, but not private addresses.
+    pub fn new(inner: R) -> BogonFilter<R> {
+        BogonFilter { inner, private: false, counters: Arc::default() }
+    }
+
+    /// Removes private addresses too.  This is for resolvers that are only
+    /// used for names on the Internet, like the ones in webhook URLs.
+    pub fn with_private(mut self) -> BogonFilter<R> {
+        self.private = true;
+        self
+    }
+
+    /// Returns a handle for reading the counters, which keeps working after
+    /// the resolver has been handed to reqwest.
+    pub fn counters(&self) -> BogonCounters {
+        BogonCounters { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Removes the items of `items` whose address is a bogon, failing if
+    /// that leaves none.
+    fn filter<T>(
+        &self,
+        name: &str,
+        items: impl IntoIterator<Item = T>,
+        ip: impl Fn(&T) -> IpAddr,
+    ) -> Result<Vec<T>, ResolveError> {
+        let (bogons, kept): (Vec<T>, Vec<T>) = items
+            .into_iter()
+            .partition(|item| is_bogon(ip(item), self.private));
+        if bogons.is_empty() {
+            return Ok(kept);
+        }
+
+        let counters = &self.counters;
+        counters.filtered_lookups.fetch_add(1, Ordering::Relaxed);
+        counters
+            .removed_addresses
+            .fetch_add(bogons.len() as u64, Ordering::Relaxed);
+        let bogons: Vec<IpAddr> = bogons.iter().map(ip).collect();
+        debug!("removed bogon addresses", name = name, addrs = bogons);
+        if kept.is_empty() {
+            counters.emptied_lookups.fetch_add(1, Ordering::Relaxed);
+            return Err(ResolveError::AddressesFiltered {
+                name: name.to_owned(),
+            });
+        }
+        Ok(kept)
+    }
+}
+
+impl<R: MyResolve> MyResolve for BogonFilter<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs: AddrList =
+                self.inner.resolve(name.clone()).await?.collect();
+            let addrs = self.filter(name.as_str(), addrs, |addr| addr.ip())?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let addrs = self.inner.resolve_detailed(name.clone()).await?;
+            let addrs = self
+                .filter(name.as_str(), addrs, |resolved| resolved.addr.ip())?;
+            Ok(addrs)
+        }
+        .boxed()
+    }
+}
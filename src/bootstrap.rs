@@ -0,0 +1,225 @@
+//! Finding encrypted-DNS servers without depending on the system resolver
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::NameServerConfigGroup;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Which encrypted transport to use to reach an [`EncryptedUpstream`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum EncryptedProtocol {
+    /// DNS-over-TLS (RFC 7858)
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484)
+    #[cfg(feature = "dns-over-https-rustls")]
+    Https,
+}
+
+impl EncryptedProtocol {
+    /// Returns the port this protocol uses when none is configured.
+    pub fn default_port(self) -> u16 {
+        match self {
+            EncryptedProtocol::Tls => 853,
+            #[cfg(feature = "dns-over-https-rustls")]
+            EncryptedProtocol::Https => 443,
+        }
+    }
+
+    pub(crate) fn protocol(self) -> Protocol {
+        match self {
+            EncryptedProtocol::Tls => Protocol::Tls,
+            #[cfg(feature = "dns-over-https-rustls")]
+            EncryptedProtocol::Https => Protocol::Https,
+        }
+    }
+}
+
+/// An encrypted DNS server identified by hostname (e.g., "dns.quad9.net")
+///
+/// trust-dns only knows how to talk to name servers by IP address.  For
+/// encrypted transports, the hostname matters too: it's the name we validate
+/// the server's certificate against.  That leaves a chicken-and-egg problem:
+/// something has to turn this hostname into addresses before we can do any
+/// encrypted lookups at all.  That's the job of [`Bootstrap`].
+///
+/// Some servers are reached by a different name than the one on their
+/// certificate, or by a literal IP address (like an anycast resolver whose
+/// certificate is shared by many addresses).  For those, `tls_name` is the
+/// name sent in the TLS handshake (as SNI) and checked against the
+/// certificate instead, and `hostname` is only used to find the addresses.
+/// A `hostname` that's an IP address is used as it is, without a lookup.
+///
+/// ```
+/// # use reqwest_resolve::bootstrap::{Bootstrap, EncryptedUpstream};
+/// let upstream =
+///     EncryptedUpstream::tls("9.9.9.9").with_tls_name("dns.quad9.net");
+/// let bootstrap = Bootstrap::Static(Default::default());
+/// let servers = futures::executor::block_on(
+///     bootstrap.resolve_upstreams(&[upstream]),
+/// )
+/// .unwrap();
+/// assert_eq!(servers[0].tls_dns_name.as_deref(), Some("dns.quad9.net"));
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct EncryptedUpstream {
+    pub hostname: String,
+    pub port: u16,
+    pub protocol: EncryptedProtocol,
+    /// the name to present and validate, if not `hostname`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tls_name: Option<String>,
+}
+
+impl EncryptedUpstream {
+    /// Describes a DNS-over-TLS server on the default port.
+    pub fn tls(hostname: &str) -> EncryptedUpstream {
+        EncryptedUpstream::new(hostname, EncryptedProtocol::Tls)
+    }
+
+    /// Describes a DNS-over-HTTPS server on the default port.
+    #[cfg(feature = "dns-over-https-rustls")]
+    pub fn https(hostname: &str) -> EncryptedUpstream {
+        EncryptedUpstream::new(hostname, EncryptedProtocol::Https)
+    }
+
+    fn new(hostname: &str, protocol: EncryptedProtocol) -> EncryptedUpstream {
+        EncryptedUpstream {
+            hostname: hostname.to_owned(),
+            port: protocol.default_port(),
+            protocol,
+            tls_name: None,
+        }
+    }
+
+    /// Presents and validates `tls_name` rather than the hostname.
+    pub fn with_tls_name(mut self, tls_name: &str) -> EncryptedUpstream {
+        self.tls_name = Some(tls_name.to_owned());
+        self
+    }
+
+    /// Returns the name the server's certificate is checked against.
+    pub fn tls_name(&self) -> &str {
+        self.tls_name.as_deref().unwrap_or(&self.hostname)
+    }
+
+    fn name_server(&self, ip: IpAddr) -> NameServerConfig {
+        let mut config = NameServerConfig::new(
+            SocketAddr::new(ip, self.port),
+            self.protocol.protocol(),
+        );
+        config.tls_dns_name = Some(self.tls_name().to_owned());
+        config.trust_nx_responses = false;
+        config
+    }
+}
+
+/// Says how to find the addresses of encrypted upstreams
+///
+/// This is only ever used for the upstreams' own hostnames.  Lookups on behalf
+/// of the application all go over the encrypted transport.
+///
+/// ```
+/// # use reqwest_resolve::bootstrap::{Bootstrap, EncryptedUpstream};
+/// let bootstrap = Bootstrap::Static(
+///     [(
+///         String::from("cloudflare-dns.com"),
+///         vec!["1.1.1.1".parse().unwrap(), "1.0.0.1".parse().unwrap()],
+///     )]
+///     .into_iter()
+///     .collect(),
+/// );
+/// let upstream = EncryptedUpstream::tls("cloudflare-dns.com");
+/// let servers = futures::executor::block_on(
+///     bootstrap.resolve_upstreams(&[upstream]),
+/// )
+/// .unwrap();
+/// assert_eq!(servers.len(), 2);
+/// ```
+pub enum Bootstrap {
+    /// Use fixed addresses, keyed by upstream hostname.  This never touches
+    /// the network, so it works even when no plain resolver is reachable.
+    Static(BTreeMap<String, Vec<IpAddr>>),
+    /// Ask this (presumably plaintext) resolver.  This is useful when the
+    /// system resolver is unreliable but some other known server isn't.
+    Resolver(Arc<TokioAsyncResolver>),
+}
+
+impl Bootstrap {
+    /// Resolves each upstream's hostname and returns name server entries for
+    /// every address found, in the order the upstreams were given.
+    ///
+    /// This fails if any upstream's hostname can't be resolved, rather than
+    /// quietly building a resolver with fewer servers than were configured.
+    pub async fn resolve_upstreams(
+        &self,
+        upstreams: &[EncryptedUpstream],
+    ) -> Result<NameServerConfigGroup, ResolveError> {
+        let mut group = NameServerConfigGroup::new();
+        for upstream in upstreams {
+            let ips = if let Ok(ip) = upstream.hostname.parse::<IpAddr>() {
+                // A certificate can't be checked against an address.
+                if upstream.tls_name.is_none() {
+                    return Err(ResolveError::from(format!(
+                        "bootstrap: encrypted upstream {} is an IP address, \
+                         so it needs a TLS name",
+                        upstream.hostname
+                    )));
+                }
+                vec![ip]
+            } else {
+                self.lookup(&upstream.hostname).await?
+            };
+
+            if ips.is_empty() {
+                return Err(ResolveError::from(format!(
+                    "bootstrap: no addresses for encrypted upstream {:?}",
+                    upstream.hostname
+                )));
+            }
+
+            group.extend(ips.into_iter().map(|ip| upstream.name_server(ip)));
+        }
+
+        Ok(group)
+    }
+
+    /// Returns `hostname`'s addresses, which may be none.
+    pub(crate) async fn lookup(
+        &self,
+        hostname: &str,
+    ) -> Result<Vec<IpAddr>, ResolveError> {
+        match self {
+            Bootstrap::Static(hosts) => {
+                Ok(hosts.get(hostname).cloned().unwrap_or_default())
+            }
+            Bootstrap::Resolver(resolver) => {
+                Ok(resolver.lookup_ip(hostname).await?.into_iter().collect())
+            }
+        }
+    }
+
+    /// Resolves the upstreams' hostnames and returns a resolver that sends all
+    /// queries to them.
+    ///
+    /// The upstreams' addresses are fixed when this is called.  If they move,
+    /// a new resolver has to be built.
+    pub async fn build_resolver(
+        &self,
+        upstreams: &[EncryptedUpstream],
+        options: ResolverOpts,
+    ) -> Result<TokioAsyncResolver, ResolveError> {
+        let group = self.resolve_upstreams(upstreams).await?;
+        let config = ResolverConfig::from_parts(None, Vec::new(), group);
+        TokioAsyncResolver::tokio(config, options)
+    }
+}
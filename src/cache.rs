@@ -0,0 +1,1769 @@
+//! An address cache that can be inspected, exported, and pre-seeded
+//!
+//! trust-dns keeps its own cache, but it's entirely internal: there's no way
+//! to see what's in it or to carry it over to another process.
+//! [`CachingResolver`] keeps a cache of its own in front of the
+//! `TokioAsyncResolver`, with the TTLs trust-dns reports.  It can just as
+//! well cache the answers of any other [`CacheBackend`], like a
+//! `MyResolve` stack, which is how builds without trust-dns use it.  With
+//! the "serde"
+//! feature, [`CachingResolver::export_cache`] and
+//! [`CachingResolver::import_cache`] move its contents to and from JSON.
+//! That's handy for seeing what a running instance has resolved, and for
+//! warming up a new instance (say, during a blue/green deploy) so that its
+//! first requests don't all wait on DNS.
+//!
+//! When an application learns that an endpoint has moved before its TTL
+//! is up, [`CachingResolver::invalidate`] (or `invalidate_suffix`, or
+//! `invalidate_all`) makes the next lookup go upstream.
+//!
+//! [`CacheConfig`] declares a cache's initial entries, along with the rest
+//! of its setup, so that a service can start with answers for the names it
+//! depends on even if DNS is down at the time.
+//!
+//! [`CachingResolver::pin`] keeps the most important names in the cache
+//! for good, refreshing them in the background, so that requests to them
+//! don't wait on DNS.  With trust-dns, a [`TrustDnsBackend`] makes those
+//! refreshes find fresh answers rather than trust-dns's cached ones.
+//!
+//! A cache is bounded either by how many names it holds or, with
+//! [`CachingResolver::with_max_bytes`], by an estimate of how much memory
+//! its entries take up.
+//!
+//! Entries that were cached at the same time, like a seed or an imported
+//! snapshot, would otherwise all expire (and be refreshed) at the same time
+//! too.  [`CachingResolver::with_ttl_jitter`] spreads them out.
+//!
+//! [`CachingResolver::with_background_refresh`] refreshes the entries in
+//! use shortly before they expire, a bounded number at a time and the
+//! busiest first, instead of leaving it to whichever requests miss.
+//!
+//! [`CachingResolver::refresh_counters`] says how often refreshing an
+//! expired entry actually turned up different addresses.  When that's rare,
+//! longer TTLs (or pinning) cost little; when it's common, they'd keep
+//! clients on stale addresses.
+//!
+//! The table of entries is shared by every lookup and by the background
+//! refreshers.  Its interleavings are checked with
+//! [loom](https://docs.rs/loom): build with `RUSTFLAGS="--cfg
+//! reqwest_resolve_loom"` and run `cargo test --release --lib
+//! cache::loom_models`.
+
+use crate::deterministic::is_deterministic;
+use crate::deterministic::stable_order;
+use crate::error::ResolveError;
+use crate::global::overridable;
+use crate::logging::debug;
+use crate::logging::trace;
+#[cfg(feature = "trust-dns")]
+use crate::names;
+use crate::resolved::ResolvedAddr;
+use crate::special_use::normalize;
+use crate::tasks::TaskSet;
+use crate::DetailedResolving;
+use crate::IpList;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use reqwest::dns::Addrs;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[cfg(not(reqwest_resolve_loom))]
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::config::ResolverConfig;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::config::ResolverOpts;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::TokioAsyncResolver;
+
+#[cfg(reqwest_resolve_loom)]
+use loom::sync::Mutex;
+
+/// How many names [`CachingResolver::new`] caches at most
+pub const DEFAULT_MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// How long answers from a backend that doesn't report TTLs are cached
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The addresses a [`CacheBackend`] found, and when they expire
+pub type CacheLookup<'a> = BoxFuture<
+    'a,
+    Result<(Vec<IpAddr>, Instant), Box<dyn StdError + Send + Sync>>,
+>;
+
+/// Where a [`CachingResolver`] looks up the names it doesn't have
+///
+/// This is implemented for trust-dns's `TokioAsyncResolver` (with the
+/// "trust-dns" feature), whose answers are cached for as long as their TTLs
+/// say, and for any [`MyResolve`], whose answers are cached for the
+/// shortest TTL that `MyResolve::resolve_detailed` reports, or for
+/// [`DEFAULT_CACHE_TTL`] if it reports none (as the static and system
+/// resolvers don't).
+pub trait CacheBackend: Send + Sync + 'static {
+    /// Looks up `name`, returning its addresses and when they expire.
+    fn lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a>;
+
+    /// Looks up `name` for a refresh of its entry, going past whatever the
+    /// backend has cached itself if it can, so that the answer is a fresh
+    /// one.  By default, this is `lookup`.
+    fn refresh_lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a> {
+        self.lookup(name)
+    }
+
+    /// Forgets whatever the backend has cached itself, so that its next
+    /// answers are fresh ones.  By default, this does nothing.
+    fn clear_cache(&self) {}
+
+    /// Describes `ip`, found by this backend and good until `expires`, for
+    /// `MyResolve::resolve_detailed`, with `cached` saying whether it was
+    /// answered from the cache.
+    fn describe(
+        &self,
+        ip: IpAddr,
+        expires: Instant,
+        cached: bool,
+    ) -> ResolvedAddr {
+        ResolvedAddr {
+            ttl: Some(expires.saturating_duration_since(Instant::now())),
+            cached,
+            ..ResolvedAddr::unknown(SocketAddr::new(ip, 0))
+        }
+    }
+}
+
+/// Looks up `name`'s addresses with trust-dns.
+#[cfg(feature = "trust-dns")]
+fn trust_dns_lookup<'a>(
+    resolver: &'a TokioAsyncResolver,
+    name: &'a str,
+) -> CacheLookup<'a> {
+    async move {
+        let lookup = match names::parsed(name) {
+            Some(parsed) => resolver.lookup_ip(parsed).await?,
+            None => resolver.lookup_ip(name).await?,
+        };
+        Ok((lookup.iter().collect(), lookup.valid_until()))
+    }
+    .boxed()
+}
+
+/// A `TokioAsyncResolver` on its own can't look a name up past its own
+/// cache, so refreshes through it find the answer it already has until that
+/// expires.  [`TrustDnsBackend`] refreshes with fresh answers.
+#[cfg(feature = "trust-dns")]
+impl CacheBackend for TokioAsyncResolver {
+    fn lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a> {
+        trust_dns_lookup(self, name)
+    }
+
+    /// trust-dns's cache can't forget a single name, so this empties it.
+    fn clear_cache(&self) {
+        TokioAsyncResolver::clear_cache(self);
+    }
+
+    fn describe(
+        &self,
+        ip: IpAddr,
+        expires: Instant,
+        cached: bool,
+    ) -> ResolvedAddr {
+        ResolvedAddr::from_dns(ip, expires, cached)
+    }
+}
+
+/// trust-dns, set up to refresh a [`CachingResolver`]'s entries with fresh
+/// answers
+///
+/// This is two `TokioAsyncResolver`s with the same configuration: one for
+/// lookups, with trust-dns's own cache, and one without a cache, just for
+/// refreshes (of pinned names, and with
+/// [`CachingResolver::with_background_refresh`]).  A refresh through the
+/// first would find the answer that's about to expire, and emptying its
+/// cache first would throw away answers that other lookups, or other
+/// refreshes running at the same time, are still using.
+///
+/// ```
+/// # use reqwest_resolve::cache::{CachingResolver, TrustDnsBackend};
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let backend =
+///     TrustDnsBackend::new(ResolverConfig::default(), ResolverOpts::default())
+///         .unwrap();
+/// let caching = CachingResolver::new(backend);
+/// # let _ = caching;
+/// ```
+#[cfg(feature = "trust-dns")]
+pub struct TrustDnsBackend {
+    resolver: TokioAsyncResolver,
+    uncached: TokioAsyncResolver,
+}
+
+#[cfg(feature = "trust-dns")]
+impl TrustDnsBackend {
+    pub fn new(
+        config: ResolverConfig,
+        options: ResolverOpts,
+    ) -> Result<TrustDnsBackend, trust_dns_resolver::error::ResolveError> {
+        let resolver = TokioAsyncResolver::tokio(config.clone(), options)?;
+        let mut uncached_options = options;
+        uncached_options.cache_size = 0;
+        let uncached = TokioAsyncResolver::tokio(config, uncached_options)?;
+        Ok(TrustDnsBackend { resolver, uncached })
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+impl CacheBackend for TrustDnsBackend {
+    fn lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a> {
+        trust_dns_lookup(&self.resolver, name)
+    }
+
+    fn refresh_lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a> {
+        trust_dns_lookup(&self.uncached, name)
+    }
+
+    fn clear_cache(&self) {
+        self.resolver.clear_cache();
+    }
+
+    fn describe(
+        &self,
+        ip: IpAddr,
+        expires: Instant,
+        cached: bool,
+    ) -> ResolvedAddr {
+        ResolvedAddr::from_dns(ip, expires, cached)
+    }
+}
+
+impl<R: MyResolve + 'static> CacheBackend for R {
+    fn lookup<'a>(&'a self, name: &'a str) -> CacheLookup<'a> {
+        async move {
+            let Ok(parsed) = name.parse::<hyper::client::connect::dns::Name>()
+            else {
+                let error = ResolveError::InvalidName { name: name.to_owned() };
+                return Err(error.into());
+            };
+            let found = self.resolve_detailed(parsed).await?;
+            let ttl = found
+                .iter()
+                .filter_map(|resolved| resolved.ttl)
+                .min()
+                .unwrap_or(DEFAULT_CACHE_TTL);
+            let mut addrs = Vec::new();
+            for resolved in found {
+                if !addrs.contains(&resolved.addr.ip()) {
+                    addrs.push(resolved.addr.ip());
+                }
+            }
+            Ok((addrs, Instant::now() + ttl))
+        }
+        .boxed()
+    }
+}
+
+/// One cached name, as exported by [`CachingResolver::entries`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct CacheEntry {
+    /// the name, lowercased and without a trailing dot
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+    /// how many more seconds the entry is valid for
+    pub ttl: u64,
+}
+
+/// How a [`CachingResolver`] is set up, as it might appear in a service's
+/// configuration
+///
+/// `seed` is for the names a service can't do without: it starts out with
+/// answers for them that are known to be good, so that it can come up even
+/// if DNS is unavailable for a moment while it does.  Those answers are
+/// used until their TTLs run out, just like cached ones, so keep them
+/// short, or pin the same names: the first refresh of a pinned name
+/// replaces the seeded entry as soon as DNS answers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct CacheConfig {
+    /// how many names to cache at most
+    #[cfg_attr(feature = "serde", serde(default = "default_max_entries"))]
+    pub max_entries: usize,
+    /// if set, how many bytes the entries may take up, in which case
+    /// `max_entries` is ignored (see [`CachingResolver::with_max_bytes`])
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_bytes: Option<usize>,
+    /// entries to start with, with TTLs counting from when the resolver is
+    /// built
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub seed: Vec<CacheEntry>,
+    /// how much to shorten TTLs by, at most, as a percentage (see
+    /// [`CachingResolver::with_ttl_jitter`])
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ttl_jitter_percent: u8,
+    /// if more than 0, how many background refreshes to run at a time (see
+    /// [`CachingResolver::with_background_refresh`])
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub refresh_workers: usize,
+    /// names to pin (see [`CachingResolver::pin`])
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pinned: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            max_entries: DEFAULT_MAX_CACHE_ENTRIES,
+            max_bytes: None,
+            ttl_jitter_percent: 0,
+            refresh_workers: 0,
+            seed: Vec::new(),
+            pinned: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_max_entries() -> usize {
+    DEFAULT_MAX_CACHE_ENTRIES
+}
+
+/// How long after it expires a pinned entry is still used, while it's
+/// being refreshed
+const PINNED_GRACE: Duration = Duration::from_secs(30);
+
+/// How long before they expire pinned entries are refreshed, and other
+/// entries are with [`CachingResolver::with_background_refresh`]
+const REFRESH_AHEAD: Duration = Duration::from_secs(5);
+
+/// How often [`CachingResolver::with_background_refresh`] looks for entries
+/// to refresh
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before trying again when refreshing a pinned entry
+/// fails
+const PINNED_RETRY: Duration = Duration::from_secs(5);
+
+/// What an entry costs beyond its name and addresses, roughly: its share
+/// of the map's nodes
+const ENTRY_OVERHEAD: usize = 48;
+
+/// How full a cache can get
+#[derive(Clone, Copy, Debug)]
+enum CacheLimit {
+    Entries(usize),
+    Bytes(usize),
+}
+
+/// How a cache decides what to keep, and for how long
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    limit: CacheLimit,
+    /// the most a TTL is shortened by, in percent
+    ttl_jitter: u8,
+    /// mixed into each name's jitter, so that different caches (and
+    /// processes) don't all shorten the same name by the same amount
+    salt: u64,
+}
+
+impl Settings {
+    /// Returns when an entry for `key` (a normalized name) that's valid
+    /// until `expires` should expire, after jitter.
+    fn jittered(&self, key: &str, expires: Instant) -> Instant {
+        if self.ttl_jitter == 0 {
+            return expires;
+        }
+        // The jitter only has to differ from name to name, so a hash of the
+        // name will do.  Inside `deterministic::with_seed`, it's the same
+        // every run.
+        let salt = if is_deterministic() { 0 } else { self.salt };
+        let mut hasher = DefaultHasher::new();
+        (salt, key).hash(&mut hasher);
+        let fraction = (hasher.finish() % 10_000) as f64 / 10_000.0;
+        let ttl = expires.saturating_duration_since(Instant::now());
+        let cut = ttl.mul_f64(fraction * f64::from(self.ttl_jitter) / 100.0);
+        expires - cut
+    }
+}
+
+struct Cached {
+    addrs: IpList,
+    expires: Instant,
+    /// the estimated size of the entry, in bytes
+    size: usize,
+    /// how many lookups the entry has answered
+    hits: u64,
+}
+
+/// Returns roughly how much memory an entry for `name` with `addrs` takes.
+fn entry_size(name: &str, addrs: &IpList) -> usize {
+    let spilled = if addrs.spilled() {
+        addrs.capacity() * std::mem::size_of::<IpAddr>()
+    } else {
+        0
+    };
+    std::mem::size_of::<(String, Cached)>()
+        + name.len()
+        + spilled
+        + ENTRY_OVERHEAD
+}
+
+#[derive(Default)]
+struct Table {
+    cached: BTreeMap<String, Cached>,
+    /// names (normalized) whose entries are never evicted, and are
+    /// refreshed in the background, each with the ID of its refresher.  A
+    /// name that's unpinned and pinned again gets a new refresher, and the
+    /// old one stops when it sees that the ID has changed.
+    pinned: BTreeMap<String, u64>,
+    /// the ID for the next pinned name's refresher
+    next_pin: u64,
+    /// how many times entries have been invalidated.  A lookup that was
+    /// already in flight when this changed may have gotten the answer
+    /// being invalidated, so what it found isn't cached.
+    generation: u64,
+    /// the total size of the entries in `cached`
+    bytes: usize,
+}
+
+impl Table {
+    fn remove(&mut self, name: &str) -> Option<Cached> {
+        let removed = self.cached.remove(name)?;
+        self.bytes -= removed.size;
+        Some(removed)
+    }
+
+    /// Removes the entries `keep` returns false for, returning how many.
+    fn retain<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&str, &Cached) -> bool,
+    {
+        let before = self.cached.len();
+        let mut freed = 0;
+        self.cached.retain(|name, cached| {
+            let kept = keep(name, cached);
+            if !kept {
+                freed += cached.size;
+            }
+            kept
+        });
+        self.bytes -= freed;
+        before - self.cached.len()
+    }
+
+    /// Removes `name`'s entry, as an invalidation (see `generation`),
+    /// returning whether there was one.
+    fn invalidate(&mut self, name: &str) -> bool {
+        self.generation += 1;
+        self.remove(name).is_some()
+    }
+
+    /// Removes the entries `keep` returns false for, as an invalidation,
+    /// returning how many.
+    fn invalidate_where<F>(&mut self, keep: F) -> usize
+    where
+        F: FnMut(&str, &Cached) -> bool,
+    {
+        self.generation += 1;
+        self.retain(keep)
+    }
+
+    /// Removes the expired entries that aren't pinned.
+    fn drop_expired(&mut self, now: Instant) {
+        let Table { cached, pinned, bytes, .. } = self;
+        cached.retain(|name, entry| {
+            let kept = entry.expires > now || pinned.contains_key(name);
+            if !kept {
+                *bytes -= entry.size;
+            }
+            kept
+        });
+    }
+
+    /// Returns whether an entry of `size` bytes for `name` can go in, in
+    /// place of any entry already there, without going over `limit`.
+    fn fits_replacing(
+        &self,
+        limit: CacheLimit,
+        name: &str,
+        size: usize,
+    ) -> bool {
+        let old = self.cached.get(name);
+        match limit {
+            CacheLimit::Entries(max) => {
+                self.cached.len() - usize::from(old.is_some()) < max
+            }
+            CacheLimit::Bytes(max) => {
+                self.bytes - old.map_or(0, |old| old.size) + size <= max
+            }
+        }
+    }
+}
+
+type Entries = Arc<Mutex<Table>>;
+
+/// A snapshot of the counters returned by
+/// [`CachingResolver::refresh_counters`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct RefreshCounts {
+    /// upstream lookups of names that already had an entry (which had
+    /// expired)
+    pub refreshes: u64,
+    /// refreshes that found a different set of addresses than the entry
+    /// had (the same addresses in a different order don't count)
+    pub changed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    refreshes: AtomicU64,
+    changed: AtomicU64,
+}
+
+/// Reads the refresh counters of a [`CachingResolver`]
+#[derive(Clone)]
+pub struct RefreshCounters {
+    counters: Arc<Counters>,
+}
+
+impl RefreshCounters {
+    pub fn snapshot(&self) -> RefreshCounts {
+        RefreshCounts {
+            refreshes: self.counters.refreshes.load(Ordering::Relaxed),
+            changed: self.counters.changed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Resolves names with a `TokioAsyncResolver` (or another
+/// [`CacheBackend`]), caching the results
+///
+/// Each result is cached for as long as trust-dns says it's valid (which
+/// takes into account `ResolverOpts::positive_min_ttl` and
+/// `positive_max_ttl`), or, with another backend, as long as it says (see
+/// [`CacheBackend`]).  Failed lookups aren't cached here, though
+/// trust-dns may cache them itself.  Once the cache is full, expired
+/// entries are dropped to make room, and if there aren't any, new results
+/// aren't cached.  (A cache bounded by [`CachingResolver::with_max_bytes`]
+/// makes room differently.)
+///
+/// ```
+/// # use reqwest_resolve::cache::{CacheEntry, CachingResolver};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let caching = CachingResolver::new(resolver);
+/// caching.insert_entries(vec![CacheEntry {
+///     name: "api.example.com".to_owned(),
+///     addrs: vec!["192.0.2.10".parse().unwrap()],
+///     ttl: 300,
+/// }]);
+/// assert_eq!(caching.entries().len(), 1);
+/// let _client = reqwest::ClientBuilder::new().dns_resolver(Arc::new(caching));
+/// ```
+pub struct CachingResolver {
+    resolver: Arc<dyn CacheBackend>,
+    entries: Entries,
+    settings: Settings,
+    counters: Arc<Counters>,
+    tasks: TaskSet,
+}
+
+impl CachingResolver {
+    /// Caches up to [`DEFAULT_MAX_CACHE_ENTRIES`] names.
+    pub fn new(resolver: impl CacheBackend) -> CachingResolver {
+        CachingResolver::with_max_entries(resolver, DEFAULT_MAX_CACHE_ENTRIES)
+    }
+
+    pub fn with_max_entries(
+        resolver: impl CacheBackend,
+        max_entries: usize,
+    ) -> CachingResolver {
+        CachingResolver::with_limit(resolver, CacheLimit::Entries(max_entries))
+    }
+
+    /// Caches as many names as fit in `max_bytes`
+    ///
+    /// Each entry's size is an estimate of the memory it takes up: its name
+    /// and addresses, plus a fixed overhead for the bookkeeping around them
+    /// (see [`CachingResolver::estimated_size`]).  When a new result
+    /// doesn't fit, expired entries are dropped to make room, and then, if
+    /// that wasn't enough, the ones closest to expiring.  Pinned entries
+    /// are never dropped, and always cached, even if they don't fit.
+    ///
+    /// ```
+    /// # use reqwest_resolve::cache::CachingResolver;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # use trust_dns_resolver::TokioAsyncResolver;
+    /// let resolver = TokioAsyncResolver::tokio(
+    ///     ResolverConfig::default(),
+    ///     ResolverOpts::default(),
+    /// )
+    /// .unwrap();
+    /// let caching = CachingResolver::with_max_bytes(resolver, 16 << 20);
+    /// assert_eq!(caching.estimated_size(), 0);
+    /// ```
+    pub fn with_max_bytes(
+        resolver: impl CacheBackend,
+        max_bytes: usize,
+    ) -> CachingResolver {
+        CachingResolver::with_limit(resolver, CacheLimit::Bytes(max_bytes))
+    }
+
+    fn with_limit(
+        resolver: impl CacheBackend,
+        limit: CacheLimit,
+    ) -> CachingResolver {
+        CachingResolver {
+            resolver: Arc::new(resolver),
+            entries: Arc::default(),
+            settings: Settings {
+                limit,
+                ttl_jitter: 0,
+                salt: RandomState::new().build_hasher().finish(),
+            },
+            counters: Arc::default(),
+            tasks: TaskSet::default(),
+        }
+    }
+
+    /// Shortens each entry's TTL by up to `percent` percent (at most 100)
+    ///
+    /// Each name is shortened by a different, pseudo-random amount, so that
+    /// entries cached at the same moment don't all expire at the same
+    /// moment, and pinned names aren't all refreshed at once.  TTLs are
+    /// only ever shortened, never stretched past what the backend reported.
+    /// This applies to entries cached from then on, including ones added
+    /// with [`CachingResolver::insert_entries`].
+    ///
+    /// ```
+    /// # use reqwest_resolve::cache::{CacheEntry, CachingResolver};
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # use trust_dns_resolver::TokioAsyncResolver;
+    /// let resolver = TokioAsyncResolver::tokio(
+    ///     ResolverConfig::default(),
+    ///     ResolverOpts::default(),
+    /// )
+    /// .unwrap();
+    /// let caching = CachingResolver::new(resolver).with_ttl_jitter(10);
+    /// caching.insert_entries(vec![CacheEntry {
+    ///     name: "api.example.com".to_owned(),
+    ///     addrs: vec!["192.0.2.10".parse().unwrap()],
+    ///     ttl: 1000,
+    /// }]);
+    /// let ttl = caching.entries()[0].ttl;
+    /// assert!(ttl >= 899 && ttl <= 1000);
+    /// ```
+    pub fn with_ttl_jitter(mut self, percent: u8) -> CachingResolver {
+        self.settings.ttl_jitter = percent.min(100);
+        self
+    }
+
+    /// Refreshes entries that have been used shortly before they expire, in
+    /// the background, at most `workers` at a time
+    ///
+    /// Without this, an entry is only refreshed once it has expired, by the
+    /// first request to miss on it, and when lots of entries expire at
+    /// once, that's a burst of upstream queries, all with requests waiting
+    /// on them.  With it, a task called "cache refresher" (see
+    /// [`crate::tasks`]) looks for entries about to expire every second, and
+    /// refreshes the ones that have answered lookups since they were cached,
+    /// most-used first, so the names that matter most are the ones that
+    /// stay fresh if there are too many to get to in time.  Entries nobody
+    /// has used expire as usual, and pinned names keep their own refresher
+    /// (see [`CachingResolver::pin`]).  Refreshes go past the backend's own
+    /// cache when it can (see [`CacheBackend::refresh_lookup`]), as a
+    /// [`TrustDnsBackend`] can, since that cache would otherwise hand back
+    /// the answer that's about to expire.
+    ///
+    /// This must be called from within a Tokio runtime, which the refresher
+    /// runs on.
+    pub fn with_background_refresh(self, workers: usize) -> CachingResolver {
+        self.tasks.spawn(
+            "cache refresher",
+            refresh_due(
+                Arc::clone(&self.resolver),
+                Arc::clone(&self.entries),
+                self.settings,
+                Arc::clone(&self.counters),
+                workers.max(1),
+            ),
+        );
+        self
+    }
+
+    /// Builds a cache as `config` describes, seeded and with its names
+    /// pinned
+    ///
+    /// If any names are pinned, or `refresh_workers` is set, this must be
+    /// called from within a Tokio runtime (see [`CachingResolver::pin`]).
+    ///
+    /// ```
+    /// # use reqwest_resolve::cache::{CacheConfig, CacheEntry, CachingResolver};
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # use trust_dns_resolver::TokioAsyncResolver;
+    /// let config = CacheConfig {
+    ///     seed: vec![CacheEntry {
+    ///         name: "db.internal.example.com".to_owned(),
+    ///         addrs: vec!["10.0.0.5".parse().unwrap()],
+    ///         ttl: 60,
+    ///     }],
+    ///     ..CacheConfig::default()
+    /// };
+    /// let resolver = TokioAsyncResolver::tokio(
+    ///     ResolverConfig::default(),
+    ///     ResolverOpts::default(),
+    /// )
+    /// .unwrap();
+    /// let caching = CachingResolver::from_config(resolver, &config);
+    /// assert_eq!(caching.entries()[0].name, "db.internal.example.com");
+    /// ```
+    pub fn from_config(
+        resolver: impl CacheBackend,
+        config: &CacheConfig,
+    ) -> CachingResolver {
+        let limit = match config.max_bytes {
+            Some(max_bytes) => CacheLimit::Bytes(max_bytes),
+            None => CacheLimit::Entries(config.max_entries),
+        };
+        let mut caching = CachingResolver::with_limit(resolver, limit)
+            .with_ttl_jitter(config.ttl_jitter_percent);
+        if config.refresh_workers > 0 {
+            caching = caching.with_background_refresh(config.refresh_workers);
+        }
+        caching.insert_entries(config.seed.iter().cloned());
+        for name in &config.pinned {
+            caching.pin(name);
+        }
+        caching
+    }
+
+    /// Returns a handle for reading how often refreshes changed a name's
+    /// addresses, which keeps working after the resolver has been handed
+    /// to reqwest.
+    pub fn refresh_counters(&self) -> RefreshCounters {
+        RefreshCounters { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Returns an estimate of how many bytes the entries take up, expired
+    /// ones included, the same way [`CachingResolver::with_max_bytes`]
+    /// counts them.
+    pub fn estimated_size(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+
+    /// Returns a resolver that answers from this cache and never goes
+    /// upstream, for putting the cache somewhere other than right in front
+    /// of DNS (see `precedence::PrecedenceResolver`)
+    ///
+    /// Names without an entry that's still being used fail with
+    /// [`ResolveError::NotFound`].  Hits count toward the entries' use as
+    /// they would through the resolver itself.
+    pub fn cache_only(&self) -> CacheOnly {
+        CacheOnly {
+            entries: Arc::clone(&self.entries),
+            resolver: Arc::clone(&self.resolver),
+        }
+    }
+
+    /// Returns the entries that haven't expired, sorted by name.
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .cached
+            .iter()
+            .filter(|(_, cached)| cached.expires > now)
+            .map(|(name, cached)| CacheEntry {
+                name: name.clone(),
+                addrs: cached.addrs.to_vec(),
+                ttl: cached.expires.duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Adds `entries` to the cache, replacing any entries for the same
+    /// names.  Entries with no addresses or no TTL left are skipped.
+    pub fn insert_entries<I>(&self, entries: I)
+    where
+        I: IntoIterator<Item = CacheEntry>,
+    {
+        let now = Instant::now();
+        for entry in entries {
+            if entry.addrs.is_empty() || entry.ttl == 0 {
+                continue;
+            }
+            let key = normalize(&entry.name);
+            let expires = now + Duration::from_secs(entry.ttl);
+            let expires = self.settings.jittered(&key, expires);
+            insert(
+                &self.entries,
+                self.settings.limit,
+                &entry.name,
+                IpList::from_vec(entry.addrs),
+                expires,
+                None,
+            );
+        }
+    }
+
+    /// Removes every entry from the cache.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().invalidate_where(|_, _| false);
+    }
+
+    /// Keeps `name` in the cache for good, refreshing it in the background
+    ///
+    /// A pinned entry is never dropped to make room for others (and is
+    /// cached even when the cache is full), and is looked up again shortly
+    /// before it expires, in a task called "cache pin refresher" (see
+    /// [`crate::tasks`]), past the backend's own cache if it can (see
+    /// [`TrustDnsBackend`]).  If a refresh fails, requests keep using the
+    /// old entry for up to 30 seconds after it expires while the refresher
+    /// tries again.  Lookups of a pinned name
+    /// only wait on DNS if it has never been resolved, or the refreshes
+    /// have been failing for that long.  The first lookup happens right
+    /// away.
+    ///
+    /// This must be called from within a Tokio runtime, which the refresher
+    /// runs on.  Pinning a name again does nothing.
+    pub fn pin(&self, name: &str) {
+        let key = normalize(name).into_owned();
+        let Some(pin) = pin(&self.entries, &key) else {
+            return;
+        };
+        debug!("pinned cache entry", name = key);
+        self.tasks.spawn(
+            "cache pin refresher",
+            refresh_pinned(
+                Arc::clone(&self.resolver),
+                Arc::clone(&self.entries),
+                self.settings,
+                Arc::clone(&self.counters),
+                key,
+                pin,
+            ),
+        );
+    }
+
+    /// Stops keeping `name` in the cache for good.  Its entry stays until
+    /// it expires, like any other.
+    pub fn unpin(&self, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.pinned.remove(&*normalize(name));
+    }
+
+    /// Returns the pinned names, sorted.
+    pub fn pinned(&self) -> Vec<String> {
+        self.entries.lock().unwrap().pinned.keys().cloned().collect()
+    }
+
+    /// Forgets what's cached for `name`, so that the next lookup goes
+    /// upstream, returning whether there was an entry
+    ///
+    /// This is for when the application learns some other way that a name's
+    /// addresses have changed, like from a failover controller.  trust-dns
+    /// can't forget a single name, so this empties its cache too (which
+    /// only holds what it has looked up for this cache anyway): otherwise,
+    /// the next lookup could get the old answer from there.  Lookups that
+    /// were already in flight don't cache what they find, in case it's the
+    /// old answer, which goes for other names' lookups too.
+    pub fn invalidate(&self, name: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.invalidate(&normalize(name));
+        drop(entries);
+        self.resolver.clear_cache();
+        debug!("invalidated cache entry", name = name, removed = removed);
+        removed
+    }
+
+    /// Forgets what's cached for `suffix` and every name under it (so
+    /// "example.com" covers "api.example.com" too, but not
+    /// "badexample.com"), returning how many entries were removed
+    pub fn invalidate_suffix(&self, suffix: &str) -> usize {
+        let suffix = normalize(suffix);
+        let under = format!(".{}", suffix);
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.invalidate_where(|name, _| {
+            name != &*suffix && !name.ends_with(&under)
+        });
+        drop(entries);
+        self.resolver.clear_cache();
+        debug!("invalidated cache entries", suffix = suffix, removed = removed);
+        removed
+    }
+
+    /// Forgets everything cached, here and in the backend (see
+    /// [`CacheBackend::clear_cache`]), returning how many entries were
+    /// removed.
+    pub fn invalidate_all(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.invalidate_where(|_, _| false);
+        drop(entries);
+        self.resolver.clear_cache();
+        removed
+    }
+
+    /// Returns the unexpired entries as a JSON array of [`CacheEntry`]s.
+    #[cfg(feature = "serde")]
+    pub fn export_cache(&self) -> String {
+        // Serializing these types can't fail.
+        serde_json::to_string_pretty(&self.entries()).unwrap()
+    }
+
+    /// Adds the entries in `json` (as produced by
+    /// [`CachingResolver::export_cache`]), returning how many there were.
+    ///
+    /// The TTLs count from the time of the import, so a snapshot that's been
+    /// sitting around for a while keeps its entries longer than it should.
+    /// Export shortly before importing.
+    #[cfg(feature = "serde")]
+    pub fn import_cache(&self, json: &str) -> Result<usize, serde_json::Error> {
+        let entries: Vec<CacheEntry> = serde_json::from_str(json)?;
+        let count = entries.len();
+        self.insert_entries(entries);
+        Ok(count)
+    }
+}
+
+/// Caches `addrs` for `name`, returning the addresses of the entry they
+/// replace, if there was one
+///
+/// If there isn't room for `addrs` even after evicting everything that can
+/// be evicted, nothing is evicted, and an unexpired entry already cached for
+/// `name` is kept (and its addresses are still returned).
+///
+/// `started` is the table's generation when the lookup that found `addrs`
+/// started, if they came from one.  If anything has been invalidated since
+/// then, nothing is cached, and nothing is replaced.
+fn insert(
+    entries: &Entries,
+    limit: CacheLimit,
+    name: &str,
+    addrs: IpList,
+    expires: Instant,
+    started: Option<u64>,
+) -> Option<IpList> {
+    let name = normalize(name).into_owned();
+    let size = entry_size(&name, &addrs);
+    let mut entries = entries.lock().unwrap();
+    if started.is_some_and(|started| started != entries.generation) {
+        debug!("cache invalidated during lookup; not caching", name = name);
+        return None;
+    }
+    let old = entries.cached.get(&name).map(|old| old.addrs.clone());
+    if !entries.fits_replacing(limit, &name, size)
+        && !entries.pinned.contains_key(&name)
+    {
+        entries.drop_expired(Instant::now());
+
+        if let CacheLimit::Bytes(max) = limit {
+            // If the pinned entries alone leave no room, evicting the rest
+            // wouldn't help.
+            let pinned: usize = entries
+                .cached
+                .iter()
+                .filter(|(other, _)| {
+                    **other != name && entries.pinned.contains_key(*other)
+                })
+                .map(|(_, entry)| entry.size)
+                .sum();
+            if pinned + size <= max {
+                // Then make room by dropping the entries closest to
+                // expiring.
+                let mut evictable: Vec<(Instant, String)> = entries
+                    .cached
+                    .iter()
+                    .filter(|(other, _)| {
+                        **other != name && !entries.pinned.contains_key(*other)
+                    })
+                    .map(|(other, entry)| (entry.expires, other.clone()))
+                    .collect();
+                evictable.sort();
+                let mut evictable = evictable.into_iter();
+                while !entries.fits_replacing(limit, &name, size) {
+                    let Some((_, evicted)) = evictable.next() else { break };
+                    trace!("evicting cache entry", name = evicted);
+                    entries.remove(&evicted);
+                }
+            }
+        }
+
+        if !entries.fits_replacing(limit, &name, size) {
+            debug!("cache is full; not caching", name = name);
+            return old;
+        }
+    }
+    entries.remove(&name);
+    entries.bytes += size;
+    entries.cached.insert(name, Cached { addrs, expires, size, hits: 0 });
+    old
+}
+
+/// Pins `key` (a normalized name), returning the ID for its refresher, or
+/// nothing if it's already pinned
+fn pin(entries: &Entries, key: &str) -> Option<u64> {
+    let mut entries = entries.lock().unwrap();
+    if entries.pinned.contains_key(key) {
+        return None;
+    }
+    let pin = entries.next_pin;
+    entries.next_pin += 1;
+    entries.pinned.insert(key.to_owned(), pin);
+    Some(pin)
+}
+
+/// Returns whether `key` is still pinned with refresher `pin`.
+fn still_pinned(entries: &Entries, key: &str, pin: u64) -> bool {
+    entries.lock().unwrap().pinned.get(key) == Some(&pin)
+}
+
+/// Looks up `key` (a normalized name) whenever its entry is about to
+/// expire, until it's unpinned (or unpinned and pinned again, with a new
+/// refresher)
+async fn refresh_pinned(
+    resolver: Arc<dyn CacheBackend>,
+    entries: Entries,
+    settings: Settings,
+    counters: Arc<Counters>,
+    key: String,
+    pin: u64,
+) {
+    while still_pinned(&entries, &key, pin) {
+        let result =
+            refresh(&*resolver, &entries, settings, &counters, &key).await;
+        let delay = match result {
+            Ok((_, expires)) => {
+                trace!("refreshed pinned cache entry", name = key);
+                expires
+                    .saturating_duration_since(Instant::now())
+                    .saturating_sub(REFRESH_AHEAD)
+            }
+            Err(error) => {
+                debug!(
+                    "refreshing pinned cache entry failed",
+                    name = key,
+                    error = error,
+                );
+                PINNED_RETRY
+            }
+        };
+        // An answer with no TTL left would otherwise be looked up again
+        // immediately, over and over.
+        tokio::time::sleep(delay.max(Duration::from_secs(1))).await;
+    }
+    debug!("unpinned cache entry", name = key);
+}
+
+/// Refreshes used entries that are about to expire, forever
+async fn refresh_due(
+    resolver: Arc<dyn CacheBackend>,
+    entries: Entries,
+    settings: Settings,
+    counters: Arc<Counters>,
+    workers: usize,
+) {
+    loop {
+        let due = take_due(&entries, Instant::now() + REFRESH_AHEAD);
+        if !due.is_empty() {
+            debug!("refreshing cache entries", count = due.len());
+            let (resolver, entries, counters) =
+                (&*resolver, &entries, &counters);
+            futures::stream::iter(due)
+                .for_each_concurrent(workers, |(_, key)| async move {
+                    let result =
+                        refresh(resolver, entries, settings, counters, &key)
+                            .await;
+                    if let Err(error) = result {
+                        debug!(
+                            "refreshing cache entry failed",
+                            name = key,
+                            error = error,
+                        );
+                    }
+                })
+                .await;
+        }
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+/// Looks up `key` (a normalized name) upstream again, ahead of (or after)
+/// its entry's expiry
+///
+/// A backend with a cache of its own, like trust-dns, would otherwise answer
+/// from it until the TTL it reported is up, so that the refresh found the
+/// same answer with the same expiry and the entry never got any fresher.
+/// So this uses `CacheBackend::refresh_lookup`, which goes past that cache
+/// (without emptying it) if the backend can.
+async fn refresh(
+    resolver: &dyn CacheBackend,
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    key: &str,
+) -> Result<(IpList, Instant), Box<dyn StdError + Send + Sync>> {
+    let started = entries.lock().unwrap().generation;
+    let found = resolver.refresh_lookup(key).await?;
+    Ok(cache_found(entries, settings, counters, key, started, found))
+}
+
+/// Returns the unpinned entries that expire by `soon` and have been used
+/// since they were last refreshed, with how many times, most-used first
+fn take_due(entries: &Entries, soon: Instant) -> Vec<(u64, String)> {
+    let mut entries = entries.lock().unwrap();
+    let Table { cached, pinned, .. } = &mut *entries;
+    let mut due: Vec<(u64, String)> = cached
+        .iter_mut()
+        .filter(|(name, entry)| {
+            entry.hits > 0
+                && entry.expires <= soon
+                && !pinned.contains_key(*name)
+        })
+        .map(|(name, entry)| {
+            // Whether or not the refresh works, the entry has to be used
+            // again before it's tried again.
+            (std::mem::take(&mut entry.hits), name.clone())
+        })
+        .collect();
+    due.sort_by(|a, b| b.cmp(a));
+    due
+}
+
+async fn do_resolve_cached(
+    resolver: &dyn CacheBackend,
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    let (addrs, _, _) =
+        lookup_cached(resolver, entries, settings, counters, &name).await?;
+    Ok(to_addrs(addrs))
+}
+
+async fn do_resolve_cached_detailed(
+    resolver: &dyn CacheBackend,
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Vec<ResolvedAddr>, Box<dyn StdError + Send + Sync>> {
+    let (addrs, expires, cached) =
+        lookup_cached(resolver, entries, settings, counters, &name).await?;
+    Ok(addrs
+        .into_iter()
+        .map(|ip| resolver.describe(ip, expires, cached))
+        .collect())
+}
+
+fn to_addrs(addrs: IpList) -> Addrs {
+    Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)))
+}
+
+/// Returns `name`'s addresses, when they expire, and whether they came from
+/// the cache
+async fn lookup_cached(
+    resolver: &dyn CacheBackend,
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    name: &hyper::client::connect::dns::Name,
+) -> Result<(IpList, Instant, bool), Box<dyn StdError + Send + Sync>> {
+    let key = normalize(name.as_str());
+    if let Some((addrs, expires)) = cached(entries, &key) {
+        return Ok((addrs, expires, true));
+    }
+    let (addrs, expires) = lookup_uncached(
+        resolver,
+        entries,
+        settings,
+        counters,
+        &key,
+        name.as_str(),
+    )
+    .await?;
+    Ok((addrs, expires, false))
+}
+
+/// Returns the cached addresses for `key` (a normalized name) and when they
+/// expire, if there are any that haven't
+fn cached(entries: &Entries, key: &str) -> Option<(IpList, Instant)> {
+    let mut entries = entries.lock().unwrap();
+    let Table { cached, pinned, .. } = &mut *entries;
+    let now = Instant::now();
+    match cached.get_mut(key) {
+        Some(cached) if cached.expires > now => {
+            trace!("cache hit", name = key);
+            cached.hits += 1;
+            let mut addrs = cached.addrs.clone();
+            stable_order(&mut addrs);
+            Some((addrs, cached.expires))
+        }
+        Some(cached)
+            if pinned.contains_key(key)
+                && cached.expires + PINNED_GRACE > now =>
+        {
+            trace!("pinned cache entry expired; still using it", name = key);
+            let mut addrs = cached.addrs.clone();
+            stable_order(&mut addrs);
+            Some((addrs, cached.expires))
+        }
+        Some(_) => {
+            // The entry stays until it's refreshed, so that the refresh can
+            // be compared with it.
+            trace!("cache entry expired", name = key);
+            None
+        }
+        None => {
+            trace!("cache miss", name = key);
+            None
+        }
+    }
+}
+
+/// Looks up `name` upstream and caches the result under `key`, counting it
+/// as a refresh if it replaced an entry
+async fn lookup_uncached(
+    resolver: &dyn CacheBackend,
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    key: &str,
+    name: &str,
+) -> Result<(IpList, Instant), Box<dyn StdError + Send + Sync>> {
+    let started = entries.lock().unwrap().generation;
+    let found = resolver.lookup(name).await?;
+    Ok(cache_found(entries, settings, counters, key, started, found))
+}
+
+/// Caches the addresses a lookup `found` under `key`, counting it as a
+/// refresh if it replaced an entry, and returns them in the order they're
+/// handed out with when they expire
+///
+/// `started` is the table's generation when the lookup started.
+fn cache_found(
+    entries: &Entries,
+    settings: Settings,
+    counters: &Counters,
+    key: &str,
+    started: u64,
+    (addrs, valid_until): (Vec<IpAddr>, Instant),
+) -> (IpList, Instant) {
+    let mut addrs: IpList = addrs.into_iter().collect();
+    let expires = settings.jittered(key, valid_until);
+    let old = insert(
+        entries,
+        settings.limit,
+        key,
+        addrs.clone(),
+        expires,
+        Some(started),
+    );
+    if let Some(mut old) = old {
+        counters.refreshes.fetch_add(1, Ordering::Relaxed);
+        old.sort();
+        old.dedup();
+        let mut new = addrs.clone();
+        new.sort();
+        new.dedup();
+        if old != new {
+            counters.changed.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "refresh changed addresses",
+                name = key,
+                old = old,
+                new = new,
+            );
+        }
+    }
+    stable_order(&mut addrs);
+    (addrs, expires)
+}
+
+impl reqwest::dns::Resolve for CachingResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        let entries = self.entries.clone();
+        let settings = self.settings;
+        let counters = self.counters.clone();
+        async move {
+            overridable(name, |name| {
+                do_resolve_cached(
+                    &*resolver, &entries, settings, &counters, name,
+                )
+            })
+            .await
+        }
+        .boxed()
+    }
+}
+
+impl MyResolve for CachingResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        // Cache hits are answered without boxing a future.
+        let key = normalize(name.as_str());
+        if let Some((addrs, _)) = cached(&self.entries, &key) {
+            return MyResolving::ready(Ok(to_addrs(addrs)));
+        }
+        let key = key.into_owned();
+        async move {
+            let (addrs, _) = lookup_uncached(
+                &*self.resolver,
+                &self.entries,
+                self.settings,
+                &self.counters,
+                &key,
+                name.as_str(),
+            )
+            .await?;
+            Ok(to_addrs(addrs))
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        do_resolve_cached_detailed(
+            &*self.resolver,
+            &self.entries,
+            self.settings,
+            &self.counters,
+            name,
+        )
+        .boxed()
+    }
+}
+
+/// Answers from a [`CachingResolver`]'s entries alone (see
+/// [`CachingResolver::cache_only`])
+#[derive(Clone)]
+pub struct CacheOnly {
+    entries: Entries,
+    /// for describing the addresses it found
+    resolver: Arc<dyn CacheBackend>,
+}
+
+impl MyResolve for CacheOnly {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let result = match cached(&self.entries, &normalize(name.as_str())) {
+            Some((addrs, _)) => Ok(to_addrs(addrs)),
+            None => {
+                let name = name.as_str().to_owned();
+                Err(ResolveError::NotFound { name }.into())
+            }
+        };
+        MyResolving::ready(result)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let result = match cached(&self.entries, &normalize(name.as_str())) {
+            Some((addrs, expires)) => Ok(addrs
+                .into_iter()
+                .map(|ip| self.resolver.describe(ip, expires, true))
+                .collect()),
+            None => {
+                let name = name.as_str().to_owned();
+                Err(ResolveError::NotFound { name }.into())
+            }
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+#[cfg(all(test, reqwest_resolve_loom))]
+mod loom_models {
+    use super::*;
+    use loom::thread;
+
+    fn addrs(last: u8) -> IpList {
+        IpList::from_elem(IpAddr::from([192, 0, 2, last]), 1)
+    }
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(300)
+    }
+
+    /// An answer found by a lookup that was in flight when its name was
+    /// invalidated may be the answer being invalidated, so it mustn't be
+    /// cached.
+    #[test]
+    fn invalidate_during_lookup() {
+        loom::model(|| {
+            let entries: Entries = Arc::default();
+            let limit = CacheLimit::Entries(10);
+            let lookup = thread::spawn({
+                let entries = Arc::clone(&entries);
+                move || {
+                    let started = entries.lock().unwrap().generation;
+                    // The upstream lookup happens here.
+                    let name = "a.example";
+                    insert(
+                        &entries,
+                        limit,
+                        name,
+                        addrs(1),
+                        later(),
+                        Some(started),
+                    );
+                    started
+                }
+            });
+            entries.lock().unwrap().invalidate("a.example");
+            let started = lookup.join().unwrap();
+
+            let table = entries.lock().unwrap();
+            let cached = table.cached.contains_key("a.example");
+            assert_eq!(cached, started == table.generation);
+        });
+    }
+
+    /// However lookups, evictions, and invalidations interleave, the size
+    /// of the table is what its entries add up to, within its limit.
+    #[test]
+    fn byte_accounting() {
+        loom::model(|| {
+            let entries: Entries = Arc::default();
+            let limit = CacheLimit::Bytes(entry_size("a.example", &addrs(1)));
+            let lookups: Vec<_> = ["a.example", "b.example"]
+                .into_iter()
+                .zip(1..)
+                .map(|(name, last)| {
+                    let entries = Arc::clone(&entries);
+                    thread::spawn(move || {
+                        insert(
+                            &entries,
+                            limit,
+                            name,
+                            addrs(last),
+                            later(),
+                            None,
+                        );
+                    })
+                })
+                .collect();
+            entries.lock().unwrap().invalidate_where(|_, _| false);
+            for lookup in lookups {
+                lookup.join().unwrap();
+            }
+
+            let table = entries.lock().unwrap();
+            let total: usize = table.cached.values().map(|c| c.size).sum();
+            assert_eq!(table.bytes, total);
+            assert!(table.fits_replacing(limit, "", 0));
+        });
+    }
+
+    /// Unpinning and pinning a name again while its refresher is checking
+    /// whether to carry on leaves exactly one refresher running.
+    #[test]
+    fn repin_during_refresh() {
+        loom::model(|| {
+            let entries: Entries = Arc::default();
+            let first = pin(&entries, "a.example").unwrap();
+            let refresher = thread::spawn({
+                let entries = Arc::clone(&entries);
+                move || still_pinned(&entries, "a.example", first)
+            });
+            entries.lock().unwrap().pinned.remove("a.example");
+            let second = pin(&entries, "a.example").unwrap();
+            refresher.join().unwrap();
+
+            // This is what each refresher sees the next time it checks.
+            assert!(!still_pinned(&entries, "a.example", first));
+            assert!(still_pinned(&entries, "a.example", second));
+        });
+    }
+
+    /// A hit that races with the background refresher collecting due
+    /// entries is either taken by it or left for next time, never lost.
+    #[test]
+    fn hits_during_refresh() {
+        loom::model(|| {
+            let entries: Entries = Arc::default();
+            let expires = Instant::now() + REFRESH_AHEAD / 2;
+            insert(
+                &entries,
+                CacheLimit::Entries(10),
+                "a.example",
+                addrs(1),
+                expires,
+                None,
+            );
+            let lookup = thread::spawn({
+                let entries = Arc::clone(&entries);
+                move || assert!(cached(&entries, "a.example").is_some())
+            });
+            let due = take_due(&entries, Instant::now() + REFRESH_AHEAD);
+            lookup.join().unwrap();
+
+            let taken: u64 = due.iter().map(|(hits, _)| hits).sum();
+            let left = entries.lock().unwrap().cached["a.example"].hits;
+            assert_eq!(taken + left, 1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "testserver")]
+    use crate::testserver::TestServer;
+    #[cfg(feature = "testserver")]
+    use crate::testserver::TestZone;
+    use std::sync::atomic::AtomicUsize;
+    #[cfg(feature = "testserver")]
+    use trust_dns_resolver::config::ResolverOpts;
+    #[cfg(feature = "testserver")]
+    use trust_dns_resolver::proto::rr::RData;
+    #[cfg(feature = "testserver")]
+    use trust_dns_resolver::proto::rr::Record;
+
+    /// Answers every name with 192.0.2.1, counting the lookups
+    #[derive(Default)]
+    struct Counting {
+        lookups: AtomicUsize,
+    }
+
+    impl MyResolve for Counting {
+        fn resolve(
+            &self,
+            _name: hyper::client::connect::dns::Name,
+        ) -> MyResolving<'_> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let addr = SocketAddr::from(([192, 0, 2, 1], 0));
+            MyResolving::ready(Ok(Box::new(std::iter::once(addr)) as Addrs))
+        }
+    }
+
+    /// A `MyResolve` backend's answers are cached, for the default TTL
+    /// since it doesn't report any.
+    #[tokio::test]
+    async fn caches_any_resolver() {
+        let counting = Arc::new(Counting::default());
+        let caching = CachingResolver::new(Arc::clone(&counting));
+        let lookups = || counting.lookups.load(Ordering::Relaxed);
+
+        let addrs = caching.resolve_to_vec("api.test").await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 0))]);
+        let detailed = caching
+            .resolve_detailed("API.test".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(detailed[0].cached);
+        assert_eq!(lookups(), 1);
+        let entries = caching.entries();
+        assert_eq!(entries[0].name, "api.test");
+        assert!(entries[0].ttl <= DEFAULT_CACHE_TTL.as_secs());
+        assert!(entries[0].ttl + 5 > DEFAULT_CACHE_TTL.as_secs());
+
+        assert!(caching.invalidate("api.test"));
+        caching.resolve_to_vec("api.test").await.unwrap();
+        assert_eq!(lookups(), 2);
+    }
+
+    /// An exported cache imports into another one, whose lookups are then
+    /// answered without going to its backend.
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn export_import_roundtrip() {
+        let exporting = CachingResolver::new(Counting::default());
+        exporting.resolve_to_vec("api.test").await.unwrap();
+        exporting.insert_entries([CacheEntry {
+            name: "db.test".to_owned(),
+            addrs: vec![
+                "192.0.2.2".parse().unwrap(),
+                "2001:db8::2".parse().unwrap(),
+            ],
+            ttl: 300,
+        }]);
+        let json = exporting.export_cache();
+
+        let counting = Arc::new(Counting::default());
+        let importing = CachingResolver::new(Arc::clone(&counting));
+        assert_eq!(importing.import_cache(&json).unwrap(), 2);
+        let mut exported = exporting.entries();
+        let mut imported = importing.entries();
+        exported.sort_by(|a, b| a.name.cmp(&b.name));
+        imported.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(exported.len(), imported.len());
+        for (exported, imported) in exported.iter().zip(&imported) {
+            assert_eq!(exported.name, imported.name);
+            assert_eq!(exported.addrs, imported.addrs);
+            assert!(imported.ttl <= exported.ttl);
+            assert!(imported.ttl + 5 > exported.ttl);
+        }
+
+        let addrs = importing.resolve_to_vec("db.test").await.unwrap();
+        assert_eq!(addrs.len(), 2);
+        importing.resolve_to_vec("api.test").await.unwrap();
+        assert_eq!(counting.lookups.load(Ordering::Relaxed), 0);
+
+        assert!(importing.import_cache("{\"not\": \"entries\"}").is_err());
+        assert_eq!(importing.entries().len(), 2);
+    }
+
+    /// A refreshed entry that's grown past the whole byte budget doesn't go
+    /// in, and the entry it would have replaced stays, along with the
+    /// entries that evicting wouldn't have made enough room anyway.
+    #[test]
+    fn bytes_limit_replacement_too_big() {
+        let addrs = |count: u8| -> IpList {
+            (1..=count).map(|last| IpAddr::from([192, 0, 2, last])).collect()
+        };
+        let later = Instant::now() + Duration::from_secs(300);
+        let max =
+            entry_size("a.test", &addrs(1)) + entry_size("b.test", &addrs(1));
+        let limit = CacheLimit::Bytes(max);
+        let entries: Entries = Arc::default();
+        insert(&entries, limit, "a.test", addrs(1), later, None);
+        insert(&entries, limit, "b.test", addrs(1), later, None);
+        assert_eq!(entries.lock().unwrap().bytes, max);
+
+        let bigger = addrs(64);
+        assert!(entry_size("a.test", &bigger) > max);
+        let old = insert(&entries, limit, "a.test", bigger, later, None);
+        assert_eq!(old, Some(addrs(1)));
+        let table = entries.lock().unwrap();
+        assert_eq!(table.cached["a.test"].addrs, addrs(1));
+        assert!(table.cached.contains_key("b.test"));
+        assert_eq!(table.bytes, max);
+        drop(table);
+
+        // A replacement that does fit once the old entry is credited goes
+        // in without evicting anything.
+        let old = insert(&entries, limit, "a.test", addrs(2), later, None);
+        assert_eq!(old, Some(addrs(1)));
+        let table = entries.lock().unwrap();
+        assert_eq!(table.cached["a.test"].addrs, addrs(2));
+        assert!(table.cached.contains_key("b.test"));
+    }
+
+    #[cfg(feature = "testserver")]
+    fn zone(ttl: u32) -> TestZone {
+        let mut zone = TestZone::new();
+        zone.add_record(Record::from_rdata(
+            "api.test.".parse().unwrap(),
+            ttl,
+            RData::A("192.0.2.10".parse().unwrap()),
+        ));
+        zone
+    }
+
+    #[cfg(feature = "testserver")]
+    async fn caching(server: &TestServer) -> CachingResolver {
+        let backend = TrustDnsBackend::new(
+            server.resolver_config(),
+            ResolverOpts::default(),
+        )
+        .unwrap();
+        CachingResolver::new(backend)
+    }
+
+    /// A refresh gets a new answer from upstream, rather than trust-dns's
+    /// cached copy of the old one, so the entry's expiry moves forward.
+    #[cfg(feature = "testserver")]
+    #[tokio::test]
+    async fn refresh_moves_expiry_forward() {
+        let server = TestServer::start(zone(5)).await.unwrap();
+        let caching = caching(&server).await;
+        caching.resolve_to_vec("api.test").await.unwrap();
+        assert!(caching.entries()[0].ttl <= 5);
+
+        server.update(|zone| *zone = self::zone(300));
+        refresh(
+            &*caching.resolver,
+            &caching.entries,
+            caching.settings,
+            &caching.counters,
+            "api.test",
+        )
+        .await
+        .unwrap();
+        assert!(caching.entries()[0].ttl > 200);
+        assert_eq!(server.queries().len(), 2);
+        assert_eq!(caching.refresh_counters().snapshot().refreshes, 1);
+    }
+
+    /// A refresh leaves what trust-dns has cached for other names alone.
+    #[cfg(feature = "testserver")]
+    #[tokio::test]
+    async fn refresh_keeps_backend_cache() {
+        let mut zone = zone(300);
+        zone.add_addr("db.test", "192.0.2.20".parse().unwrap());
+        let server = TestServer::start(zone).await.unwrap();
+        let caching = caching(&server).await;
+        caching.resolve_to_vec("api.test").await.unwrap();
+        caching.resolve_to_vec("db.test").await.unwrap();
+        assert_eq!(server.queries().len(), 2);
+
+        refresh(
+            &*caching.resolver,
+            &caching.entries,
+            caching.settings,
+            &caching.counters,
+            "api.test",
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.queries().len(), 3);
+        caching.resolver.lookup("db.test").await.unwrap();
+        caching.resolver.lookup("api.test").await.unwrap();
+        assert_eq!(server.queries().len(), 3);
+    }
+
+    /// A pinned entry is refreshed before it expires, not after.
+    #[cfg(feature = "testserver")]
+    #[tokio::test]
+    async fn pinned_entry_refreshed_ahead_of_expiry() {
+        let ttl = REFRESH_AHEAD.as_secs() as u32 + 1;
+        let server = TestServer::start(zone(ttl)).await.unwrap();
+        let caching = caching(&server).await;
+        caching.pin("api.test");
+        while caching.entries().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        server.update(|zone| *zone = self::zone(300));
+        let started = Instant::now();
+        while caching.entries()[0].ttl < 200 {
+            assert!(started.elapsed() < Duration::from_secs(u64::from(ttl)));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.queries().len(), 2);
+    }
+}
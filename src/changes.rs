@@ -0,0 +1,232 @@
+//! Noticing when a name's addresses change
+//!
+//! When a host moves, connections already pooled to its old addresses keep
+//! working until the old host goes away, and then requests on them start
+//! failing.  [`AddressChangeResolver`] tells the application as soon as a
+//! lookup returns a different set of addresses than the last one did, so it
+//! can open connections to the new addresses ahead of time, or drop the
+//! pooled ones that are about to break.
+//! [`AddressChangeResolver::invalidating_pools`] is the second of those: it
+//! calls a hook only when addresses go away, which is when idle connections
+//! to them should be dropped.
+
+use crate::logging::debug;
+use crate::special_use::normalize;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// How many names an [`AddressChangeResolver`] remembers addresses for.
+/// Names first resolved after that many others aren't tracked.
+const MAX_TRACKED_NAMES: usize = 10_000;
+
+/// What an [`AddressChangeResolver`] passes to its callback
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AddressChange {
+    /// the name, lowercased and without a trailing dot
+    pub name: String,
+    /// the addresses from the previous lookup, sorted
+    pub old: Vec<IpAddr>,
+    /// the addresses from this one, sorted
+    pub new: Vec<IpAddr>,
+}
+
+impl AddressChange {
+    /// Returns the addresses that weren't there before.
+    pub fn added(&self) -> impl Iterator<Item = &IpAddr> {
+        self.new.iter().filter(|ip| !self.old.contains(ip))
+    }
+
+    /// Returns the addresses that are gone.
+    pub fn removed(&self) -> impl Iterator<Item = &IpAddr> {
+        self.old.iter().filter(|ip| !self.new.contains(ip))
+    }
+}
+
+type Callback = Box<dyn Fn(&AddressChange) + Send + Sync>;
+
+/// Calls a callback whenever a name resolves to a different set of addresses
+/// than it did the last time
+///
+/// Only the set matters: the same addresses in a different order aren't a
+/// change.  Failed lookups are ignored, and the first successful lookup of a
+/// name just records its addresses.  The callback is called on the task
+/// doing the lookup, before the lookup returns, so it should be quick; hand
+/// anything slow (like actually opening connections) off to another task.
+///
+/// Tracking is only as fresh as the lookups: with a cache in front of this,
+/// a change shows up when the cached entry expires and the name is looked up
+/// again.
+///
+/// ```
+/// # use reqwest_resolve::changes::AddressChangeResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = AddressChangeResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     |change| {
+///         for ip in change.added() {
+///             eprintln!("{} is now also at {}", change.name, ip);
+///         }
+///         for ip in change.removed() {
+///             eprintln!("{} is no longer at {}", change.name, ip);
+///         }
+///     },
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct AddressChangeResolver<R> {
+    inner: R,
+    callback: Callback,
+    last: Mutex<BTreeMap<String, BTreeSet<IpAddr>>>,
+}
+
+impl<R> AddressChangeResolver<R> {
+    pub fn new<F>(inner: R, callback: F) -> AddressChangeResolver<R>
+    where
+        F: Fn(&AddressChange) + Send + Sync + 'static,
+    {
+        AddressChangeResolver {
+            inner,
+            callback: Box::new(callback),
+            last: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Calls `invalidate` with a name whenever addresses it used to resolve
+    /// to are gone
+    ///
+    /// This is where to clear the idle connections to that host, so that
+    /// requests don't keep going to addresses that may have been
+    /// decommissioned.  New addresses alone don't call the hook, since
+    /// connections to the old ones still work.  reqwest can't drop the idle
+    /// connections for just one host, so this usually means replacing the
+    /// client (and its pool) with a new one, or marking it to be replaced
+    /// before its next request.
+    ///
+    /// ```
+    /// # use reqwest_resolve::changes::AddressChangeResolver;
+    /// # use reqwest_resolve::handle::ResolverHandle;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "api.example.com" => ["192.0.2.10"],
+    /// };
+    /// let stale = Arc::new(AtomicBool::new(false));
+    /// let invalidate = {
+    ///     let stale = Arc::clone(&stale);
+    ///     move |_name: &str| stale.store(true, Ordering::Relaxed)
+    /// };
+    /// let my_resolver =
+    ///     AddressChangeResolver::invalidating_pools(HOSTS, invalidate);
+    /// let handle = ResolverHandle::new(my_resolver);
+    /// let mut client = handle.client_builder().build().unwrap();
+    ///
+    /// // Before each request:
+    /// if stale.swap(false, Ordering::Relaxed) {
+    ///     client = handle.client_builder().build().unwrap();
+    /// }
+    /// # drop(client);
+    /// ```
+    pub fn invalidating_pools<F>(
+        inner: R,
+        invalidate: F,
+    ) -> AddressChangeResolver<R>
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        AddressChangeResolver::new(inner, move |change| {
+            if change.removed().next().is_some() {
+                debug!(
+                    "invalidating pooled connections",
+                    name = change.name,
+                    removed = change.removed().collect::<Vec<_>>(),
+                );
+                invalidate(&change.name);
+            }
+        })
+    }
+
+    /// Records the addresses from a successful lookup, returning the change
+    /// to report if they're different from last time.
+    fn record(
+        &self,
+        name: &str,
+        addrs: &[SocketAddr],
+    ) -> Option<AddressChange> {
+        let new: BTreeSet<IpAddr> =
+            addrs.iter().map(|addr| addr.ip()).collect();
+        let name = normalize(name);
+        let mut last = self.last.lock().unwrap();
+        match last.get_mut(&*name) {
+            Some(old) if *old != new => {
+                let change = AddressChange {
+                    name: name.into_owned(),
+                    old: old.iter().copied().collect(),
+                    new: new.iter().copied().collect(),
+                };
+                *old = new;
+                Some(change)
+            }
+            Some(_) => None,
+            None => {
+                if last.len() < MAX_TRACKED_NAMES {
+                    last.insert(name.into_owned(), new);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<R: MyResolve> MyResolve for AddressChangeResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs: AddrList =
+                self.inner.resolve(name.clone()).await?.collect();
+            if let Some(change) = self.record(name.as_str(), &addrs) {
+                (self.callback)(&change);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let resolved = self.inner.resolve_detailed(name.clone()).await?;
+            let addrs: AddrList =
+                resolved.iter().map(|resolved| resolved.addr).collect();
+            if let Some(change) = self.record(name.as_str(), &addrs) {
+                (self.callback)(&change);
+            }
+            Ok(resolved)
+        }
+        .boxed()
+    }
+}
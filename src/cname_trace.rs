@@ -0,0 +1,233 @@
+//! Following a lookup through its CNAME chain
+//!
+//! A name that's slow to resolve is often slow because of what it's an
+//! alias for: a chain of CNAMEs through a CDN or two, each of which may
+//! need its own query, to its own upstream.  None of that shows up in a
+//! lookup's telemetry, only the total time, so finding the slow hop has
+//! meant a packet capture.  [`CnameTraceFilter`] is a `QueryFilter` that
+//! times every query it sees and follows the CNAME chain in each answer.
+//! Each one is a debug event, and the lookups made inside [`traced`] collect
+//! them as [`QueryHop`]s.  `otel::OtelResolver` (with the "opentelemetry"
+//! feature) and `audit::AuditResolver` do that on their own, adding the
+//! chain and the hops to their spans and log entries.
+//!
+//! Like [`labels`](crate::labels), this works through a task-local, so only
+//! queries sent from the task running the lookup are collected.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+tokio::task_local! {
+    static CURRENT_TRACE: Arc<Mutex<Vec<QueryHop>>>;
+}
+
+/// One query sent upstream, as seen by a [`CnameTraceFilter`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct QueryHop {
+    /// the name asked about, lowercased and without a trailing dot
+    pub name: String,
+    /// the type of record asked for, like "A" or "AAAA"
+    pub record_type: String,
+    pub upstream: SocketAddr,
+    /// how long the upstream took to answer
+    pub latency: Duration,
+    /// the CNAME chain in the answer, starting with the target of `name`'s
+    /// CNAME, in the same form as `name`.  This is empty if `name` isn't an
+    /// alias.
+    pub cnames: Vec<String>,
+}
+
+/// Runs `future`, returning what it produced along with the queries it
+/// sent through a [`CnameTraceFilter`], in the order they were answered
+///
+/// An enclosing `traced` sees these queries too.
+///
+/// ```
+/// # use reqwest_resolve::cname_trace::{chain, traced};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "example.com" => ["192.0.2.1"],
+/// };
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let (addrs, hops) = traced(HOSTS.resolve_to_vec("example.com")).await;
+/// assert_eq!(addrs.unwrap().len(), 1);
+/// // A static table doesn't send any queries.
+/// assert!(chain(&hops).is_empty());
+/// # });
+/// ```
+pub async fn traced<F: Future>(future: F) -> (F::Output, Vec<QueryHop>) {
+    let hops = Arc::new(Mutex::new(Vec::new()));
+    let output = CURRENT_TRACE.scope(Arc::clone(&hops), future).await;
+    let hops = std::mem::take(&mut *hops.lock().unwrap());
+    let _ = CURRENT_TRACE.try_with(|outer| {
+        outer.lock().unwrap().extend(hops.iter().cloned());
+    });
+    (output, hops)
+}
+
+/// Returns the names `hops` went through, in order: the first name asked
+/// about, and then each CNAME target, once each
+pub fn chain(hops: &[QueryHop]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for hop in hops {
+        for name in std::iter::once(&hop.name).chain(&hop.cnames) {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    names
+}
+
+/// Adds `hop` to the trace in scope (see [`traced`]), if there is one.
+#[cfg(feature = "trust-dns")]
+fn record(hop: QueryHop) {
+    let _ = CURRENT_TRACE.try_with(|hops| hops.lock().unwrap().push(hop));
+}
+
+#[cfg(feature = "trust-dns")]
+pub use filter::CnameTraceFilter;
+
+#[cfg(feature = "trust-dns")]
+mod filter {
+    use super::record;
+    use super::QueryHop;
+    use crate::logging::debug;
+    use crate::transport::QueryFilter;
+    use crate::transport::Upstream;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use std::time::Instant;
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::proto::op::Message;
+    use trust_dns_resolver::proto::rr::RData;
+    use trust_dns_resolver::Name;
+
+    /// How many CNAMEs in a row are followed in one answer
+    const MAX_CHAIN: usize = 16;
+
+    /// Above this many queries waiting for an answer, the ones that have
+    /// waited longer than [`GIVEN_UP_AFTER`] are forgotten
+    const MAX_IN_FLIGHT: usize = 1024;
+
+    /// Long enough that trust-dns has given up on the query
+    const GIVEN_UP_AFTER: Duration = Duration::from_secs(60);
+
+    /// A [`QueryFilter`] that times each query and reports the CNAME chain
+    /// in its answer (see the [module documentation](super))
+    ///
+    /// Responses are passed through unchanged.
+    ///
+    /// ```
+    /// # use reqwest_resolve::cname_trace::CnameTraceFilter;
+    /// # use reqwest_resolve::transport::{filtered_resolver, FilteredDnsResolver};
+    /// # use reqwest_resolve::audit::AuditResolver;
+    /// # use reqwest_resolve::ResolveAdapter;
+    /// # use std::sync::Arc;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// let resolver = filtered_resolver(
+    ///     ResolverConfig::default(),
+    ///     ResolverOpts::default(),
+    ///     vec![Arc::new(CnameTraceFilter::new())],
+    /// )
+    /// .unwrap();
+    /// // Each entry in `log` now says which queries its lookup sent.
+    /// let my_resolver =
+    ///     AuditResolver::new(FilteredDnsResolver::new(resolver));
+    /// let log = my_resolver.log();
+    /// let _client = reqwest::ClientBuilder::new()
+    ///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+    /// ```
+    #[derive(Default)]
+    pub struct CnameTraceFilter {
+        /// when each query still waiting for an answer was sent, by
+        /// upstream and query ID
+        sent: Mutex<BTreeMap<(SocketAddr, u16), Instant>>,
+    }
+
+    impl CnameTraceFilter {
+        pub fn new() -> CnameTraceFilter {
+            CnameTraceFilter::default()
+        }
+    }
+
+    /// Returns `name` lowercased and without its trailing dot.
+    fn plain(name: &Name) -> String {
+        let name = name.to_lowercase().to_string();
+        name.strip_suffix('.').map(str::to_owned).unwrap_or(name)
+    }
+
+    impl QueryFilter for CnameTraceFilter {
+        fn on_request(&self, upstream: &Upstream, request: &mut Message) {
+            let now = Instant::now();
+            let mut sent = self.sent.lock().unwrap();
+            if sent.len() >= MAX_IN_FLIGHT {
+                sent.retain(|_, at| now.duration_since(*at) < GIVEN_UP_AFTER);
+            }
+            sent.insert((upstream.addr, request.id()), now);
+        }
+
+        fn on_response(
+            &self,
+            upstream: &Upstream,
+            request: &Message,
+            response: &mut Message,
+        ) -> Result<(), ResolveError> {
+            let sent = self
+                .sent
+                .lock()
+                .unwrap()
+                .remove(&(upstream.addr, request.id()));
+            let Some(query) = request.queries().first() else {
+                return Ok(());
+            };
+            let latency = sent.map(|at| at.elapsed()).unwrap_or_default();
+
+            let mut cnames = Vec::new();
+            let mut current = query.name().clone();
+            while cnames.len() < MAX_CHAIN {
+                let target = response.answers().iter().find_map(|record| {
+                    match record.data() {
+                        Some(RData::CNAME(target))
+                            if *record.name() == current =>
+                        {
+                            Some(target.clone())
+                        }
+                        _ => None,
+                    }
+                });
+                let Some(target) = target else { break };
+                cnames.push(plain(&target));
+                current = target;
+            }
+
+            let hop = QueryHop {
+                name: plain(query.name()),
+                record_type: query.query_type().to_string(),
+                upstream: upstream.addr,
+                latency,
+                cnames,
+            };
+            debug!(
+                "upstream answered",
+                name = hop.name,
+                record_type = hop.record_type,
+                upstream = hop.upstream,
+                latency = hop.latency,
+                cnames = hop.cnames,
+            );
+            record(hop);
+            Ok(())
+        }
+    }
+}
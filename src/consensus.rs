@@ -0,0 +1,282 @@
+//! Only trusting addresses that several resolvers agree on
+//!
+//! A resolver on a hijacked or censored path answers with whatever
+//! addresses the attacker or censor picked, and nothing in the answer
+//! itself says so.  Asking several independent resolvers (over different
+//! paths, run by different operators) and comparing their answers does:
+//! [`ConsensusResolver`] asks all of them for every name, only returns the
+//! addresses that enough of them returned, and counts the lookups where
+//! they disagreed, and which resolvers were the odd ones out.
+//!
+//! Names served by CDNs often resolve to different addresses depending on
+//! who's asking, so resolvers in different places can disagree about them
+//! without anything being wrong.  For those, the quorum may leave only some
+//! of the addresses (or none, failing the lookup), and the counters are
+//! better read as trends than as alarms.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::AddrList;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// The counters shared between a resolver and its handles
+struct Counters {
+    lookups: AtomicU64,
+    disagreements: AtomicU64,
+    no_consensus: AtomicU64,
+    dissents: Vec<AtomicU64>,
+}
+
+/// Reads the counters of a [`ConsensusResolver`]
+#[derive(Clone)]
+pub struct ConsensusCounters {
+    counters: Arc<Counters>,
+}
+
+impl ConsensusCounters {
+    pub fn snapshot(&self) -> ConsensusCounts {
+        let counters = &self.counters;
+        ConsensusCounts {
+            lookups: counters.lookups.load(Ordering::Relaxed),
+            disagreements: counters.disagreements.load(Ordering::Relaxed),
+            no_consensus: counters.no_consensus.load(Ordering::Relaxed),
+            dissents: counters
+                .dissents
+                .iter()
+                .map(|dissents| dissents.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// What a [`ConsensusResolver`] has seen since it was created
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ConsensusCounts {
+    pub lookups: u64,
+    /// lookups where the resolvers didn't all give the same answer
+    pub disagreements: u64,
+    /// lookups that failed because no answer had a quorum
+    pub no_consensus: u64,
+    /// for each resolver, in the order they were given, the lookups where
+    /// it gave a different answer from the quorum's
+    pub dissents: Vec<u64>,
+}
+
+/// Asks every one of several resolvers, and only returns addresses that at
+/// least `quorum` of them returned
+///
+/// The resolvers are asked at the same time, and the lookup finishes when
+/// they all have, so it takes as long as the slowest of them.  A resolver
+/// that fails counts as returning no addresses, so the lookup succeeds as
+/// long as some address has a quorum, however many others failed.  If none
+/// does, the lookup fails with the first of the resolvers' errors if at
+/// least `quorum` of them failed, and with [`ResolveError::NoConsensus`]
+/// otherwise.  The addresses returned are in the order the resolvers
+/// returned them in, as given by the first resolver to return each one.
+///
+/// Each resolver whose answer (the addresses it returned, or its failure)
+/// differs from the quorum's is counted as dissenting, and a lookup with
+/// any dissent, or no quorum, is counted as a disagreement and logged.
+///
+/// ```
+/// # use reqwest_resolve::consensus::ConsensusResolver;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// # use std::sync::Arc;
+/// static FIRST: StaticResolver = static_resolver! {
+///     "example.com" => ["192.0.2.1"],
+/// };
+/// static SECOND: StaticResolver = static_resolver! {
+///     "example.com" => ["192.0.2.1"],
+/// };
+/// static HIJACKED: StaticResolver = static_resolver! {
+///     "example.com" => ["198.51.100.66"],
+/// };
+/// let my_resolver = ConsensusResolver::new(
+///     vec![Arc::new(FIRST), Arc::new(SECOND), Arc::new(HIJACKED)],
+///     2,
+/// )
+/// .unwrap();
+/// let counters = my_resolver.counters();
+/// let addrs =
+///     futures::executor::block_on(my_resolver.resolve_to_vec("example.com"))
+///         .unwrap();
+/// assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+/// assert_eq!(counters.snapshot().dissents, [0, 0, 1]);
+/// ```
+pub struct ConsensusResolver {
+    resolvers: Vec<Arc<dyn MyResolve>>,
+    quorum: usize,
+    counters: Arc<Counters>,
+}
+
+impl ConsensusResolver {
+    /// Fails with [`ResolveError::InvalidConfig`] if `quorum` is 0 or more
+    /// than the number of resolvers.
+    pub fn new(
+        resolvers: Vec<Arc<dyn MyResolve>>,
+        quorum: usize,
+    ) -> Result<ConsensusResolver, ResolveError> {
+        if quorum == 0 || quorum > resolvers.len() {
+            return Err(ResolveError::InvalidConfig(format!(
+                "a quorum of {} out of {} resolvers",
+                quorum,
+                resolvers.len()
+            )));
+        }
+        let counters = Arc::new(Counters {
+            lookups: AtomicU64::new(0),
+            disagreements: AtomicU64::new(0),
+            no_consensus: AtomicU64::new(0),
+            dissents: resolvers.iter().map(|_| AtomicU64::new(0)).collect(),
+        });
+        Ok(ConsensusResolver { resolvers, quorum, counters })
+    }
+
+    /// Returns a handle for reading the counters, which keeps working after
+    /// the resolver has been handed to reqwest.
+    pub fn counters(&self) -> ConsensusCounters {
+        ConsensusCounters { counters: Arc::clone(&self.counters) }
+    }
+}
+
+/// Returns whether `addrs` has the same addresses as `agreed`, in any order.
+fn same_addrs(addrs: &[SocketAddr], agreed: &[SocketAddr]) -> bool {
+    addrs.iter().all(|addr| agreed.contains(addr))
+        && agreed.iter().all(|addr| addrs.contains(addr))
+}
+
+impl MyResolve for ConsensusResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let lookups = self.resolvers.iter().map(|resolver| {
+                let lookup = resolver.resolve(name.clone());
+                async move { Ok(lookup.await?.collect::<AddrList>()) }
+            });
+            let results: Vec<Result<AddrList, _>> =
+                futures::future::join_all(lookups).await;
+            let counters = &self.counters;
+            counters.lookups.fetch_add(1, Ordering::Relaxed);
+
+            let mut tally: Vec<(SocketAddr, usize)> = Vec::new();
+            for addrs in
+                results.iter().filter_map(|result| result.as_ref().ok())
+            {
+                for (i, addr) in addrs.iter().enumerate() {
+                    if addrs[..i].contains(addr) {
+                        continue;
+                    }
+                    match tally.iter_mut().find(|(seen, _)| seen == addr) {
+                        Some((_, count)) => *count += 1,
+                        None => tally.push((*addr, 1)),
+                    }
+                }
+            }
+            let agreed: AddrList = tally
+                .iter()
+                .filter(|(_, count)| *count >= self.quorum)
+                .map(|(addr, _)| *addr)
+                .collect();
+            let failures =
+                results.iter().filter(|result| result.is_err()).count();
+            // Failing is only the quorum's answer if nothing else was.
+            let failed = agreed.is_empty() && failures >= self.quorum;
+
+            let dissenting: Vec<usize> = results
+                .iter()
+                .enumerate()
+                .filter(|(_, result)| match result {
+                    Ok(addrs) => failed || !same_addrs(addrs, &agreed),
+                    Err(_) => !failed,
+                })
+                .map(|(i, _)| i)
+                .collect();
+            let consensus = failed || !agreed.is_empty();
+            if !consensus || !dissenting.is_empty() {
+                counters.disagreements.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "resolvers disagree",
+                    name = name.as_str(),
+                    consensus = consensus,
+                    dissenting = dissenting,
+                );
+            }
+            if consensus {
+                for i in dissenting {
+                    counters.dissents[i].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            if failed {
+                let error = results.into_iter().find_map(Result::err);
+                return Err(error.expect("no failures"));
+            }
+            if agreed.is_empty() {
+                counters.no_consensus.fetch_add(1, Ordering::Relaxed);
+                return Err(ResolveError::NoConsensus {
+                    name: name.as_str().to_owned(),
+                    quorum: self.quorum,
+                }
+                .into());
+            }
+            Ok(Box::new(agreed.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConsensusResolver;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use std::sync::Arc;
+
+    static AGREES: StaticResolver = static_resolver! {
+        "example.com" => ["192.0.2.1"],
+    };
+    static FAILS: StaticResolver = static_resolver! {
+        "example.net" => ["192.0.2.2"],
+    };
+
+    #[tokio::test]
+    async fn failures_and_agreement() {
+        // Two of five resolvers fail, which is enough for a quorum of two,
+        // but the other three agree, so their answer wins.
+        let resolvers: Vec<Arc<dyn MyResolve>> = vec![
+            Arc::new(FAILS),
+            Arc::new(AGREES),
+            Arc::new(FAILS),
+            Arc::new(AGREES),
+            Arc::new(AGREES),
+        ];
+        let resolver = ConsensusResolver::new(resolvers, 2).unwrap();
+        let counters = resolver.counters();
+
+        let addrs = resolver.resolve_to_vec("example.com").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+        let counts = counters.snapshot();
+        assert_eq!(counts.dissents, [1, 0, 1, 0, 0]);
+        assert_eq!(counts.no_consensus, 0);
+
+        // With no address agreed on, the failures are the answer.
+        let error = resolver.resolve_to_vec("example.org").await.unwrap_err();
+        assert!(error.to_string().contains("example.org"), "{}", error);
+        let counts = counters.snapshot();
+        assert_eq!(counts.dissents, [1, 0, 1, 0, 0]);
+        assert_eq!(counts.no_consensus, 0);
+    }
+}
@@ -0,0 +1,150 @@
+//! Running inside Docker and Podman containers
+//!
+//! Containers on user-defined networks get their resolv.conf pointed at a DNS
+//! server provided by the container runtime: Docker's embedded server at
+//! 127.0.0.11, or Podman's aardvark-dns on the network's gateway address.
+//! That server is the only one that knows the names of other containers, so
+//! it mustn't be bypassed, and it has a few quirks worth configuring around.
+//! [`system_conf`] is a drop-in replacement for trust-dns's
+//! `read_system_conf()` that does this.
+
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The address of Docker's embedded DNS server, inside every container on a
+/// user-defined network
+pub const DOCKER_EMBEDDED_DNS: IpAddr =
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 11));
+
+/// The search domain Podman's aardvark-dns serves container names under
+const PODMAN_DNS_DOMAIN: &str = "dns.podman";
+
+/// The longest we'll cache answers from an embedded server
+///
+/// Docker hands out container addresses with a TTL of 600 seconds, but a
+/// container that restarts usually comes back with a different address.
+/// Caching for the full TTL would keep sending requests to the old one.
+pub const EMBEDDED_DNS_MAX_TTL: Duration = Duration::from_secs(10);
+
+/// Which container runtime we seem to be running under
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+}
+
+impl ContainerRuntime {
+    /// Looks for the marker files that Docker and Podman create at the root
+    /// of every container's filesystem.
+    pub fn detect() -> Option<ContainerRuntime> {
+        if Path::new("/run/.containerenv").exists() {
+            Some(ContainerRuntime::Podman)
+        } else if Path::new("/.dockerenv").exists() {
+            Some(ContainerRuntime::Docker)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the address of the runtime's embedded DNS server, if `config`
+/// (as parsed from resolv.conf) points at one.
+///
+/// Docker's server is recognized by its fixed address alone, since it's
+/// never anything else.  Podman's address varies by network, so we only
+/// believe it's there if we're in a Podman container and the "dns.podman"
+/// search domain is configured.
+pub fn embedded_server(
+    runtime: Option<ContainerRuntime>,
+    config: &ResolverConfig,
+) -> Option<IpAddr> {
+    let servers = config.name_servers();
+    if servers.iter().any(|ns| ns.socket_addr.ip() == DOCKER_EMBEDDED_DNS) {
+        return Some(DOCKER_EMBEDDED_DNS);
+    }
+
+    let podman_domain = config.search().iter().any(|domain| {
+        domain.to_ascii().trim_end_matches('.') == PODMAN_DNS_DOMAIN
+    });
+    if runtime == Some(ContainerRuntime::Podman) && podman_domain {
+        return servers.first().map(|ns| ns.socket_addr.ip());
+    }
+
+    None
+}
+
+/// Parses resolv.conf contents like trust-dns's `parse_resolv_conf()`, then
+/// adjusts the result if it points at a container runtime's embedded server
+///
+/// The adjustments are:
+///
+/// * Only the embedded server is used.  Runtimes sometimes list the host's
+///   servers too, but those can't resolve container names.
+/// * Cached answers are capped at [`EMBEDDED_DNS_MAX_TTL`].
+/// * Failed queries aren't retried over TCP.  The embedded server's TCP
+///   listener forwards to the same place as its UDP one, so a query that
+///   failed over UDP would just fail again, more slowly.  TCP is still used
+///   when a response is truncated.
+///
+/// ```
+/// # use reqwest_resolve::container::{parse_resolv_conf, DOCKER_EMBEDDED_DNS};
+/// let resolv_conf = "nameserver 127.0.0.11\noptions ndots:0\n";
+/// let (config, options) = parse_resolv_conf(None, resolv_conf).unwrap();
+/// assert!(config
+///     .name_servers()
+///     .iter()
+///     .all(|ns| ns.socket_addr.ip() == DOCKER_EMBEDDED_DNS));
+/// assert_eq!(options.ndots, 0);
+/// assert!(!options.try_tcp_on_error);
+/// ```
+pub fn parse_resolv_conf(
+    runtime: Option<ContainerRuntime>,
+    resolv_conf: &str,
+) -> io::Result<(ResolverConfig, ResolverOpts)> {
+    let (config, mut options) =
+        trust_dns_resolver::system_conf::parse_resolv_conf(resolv_conf)?;
+    let Some(server) = embedded_server(runtime, &config) else {
+        return Ok((config, options));
+    };
+
+    let mut embedded = ResolverConfig::from_parts(
+        config.domain().cloned(),
+        config.search().to_vec(),
+        Vec::new(),
+    );
+    for name_server in config.name_servers() {
+        if name_server.socket_addr.ip() == server {
+            embedded.add_name_server(name_server.clone());
+        }
+    }
+
+    options.positive_max_ttl = Some(
+        options
+            .positive_max_ttl
+            .map_or(EMBEDDED_DNS_MAX_TTL, |ttl| ttl.min(EMBEDDED_DNS_MAX_TTL)),
+    );
+    options.try_tcp_on_error = false;
+    Ok((embedded, options))
+}
+
+/// Reads /etc/resolv.conf and configures it with [`parse_resolv_conf`]
+pub fn system_conf() -> io::Result<(ResolverConfig, ResolverOpts)> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    parse_resolv_conf(ContainerRuntime::detect(), &resolv_conf)
+}
+
+/// Like `TokioAsyncResolver::tokio_from_system_conf()`, but using
+/// [`system_conf`]
+pub fn tokio_resolver() -> Result<TokioAsyncResolver, ResolveError> {
+    let (config, options) = system_conf()?;
+    TokioAsyncResolver::tokio(config, options)
+}
@@ -0,0 +1,173 @@
+//! Telling a resolver what a lookup is for
+//!
+//! reqwest only gives its resolver the name from the URL.  Some decisions
+//! about a name can't be made well from the name alone: which HTTPS or SRV
+//! records apply depends on the scheme and port being connected to, and a
+//! policy layer may want to treat a request differently because of
+//! something only the caller knows.  A [`ResolveContext`] carries all of
+//! that into `MyResolve::resolve_with_context`, for callers that use a
+//! resolver directly (and connectors, like `happy_eyeballs`'s, that have
+//! the whole URL).
+//!
+//! Most layers don't care what a lookup is for, and pass it on with
+//! `resolve`.  So that layers further in can still see the context,
+//! `resolve_with_context` puts it in scope for the lookup, and
+//! [`current_context`] returns it, the way the deadline from
+//! `deadline::with_deadline` reaches a `deadline::DeadlineResolver`.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+
+tokio::task_local! {
+    static CURRENT_CONTEXT: ResolveContext;
+}
+
+/// What a lookup is for, as far as the caller knows
+///
+/// ```
+/// # use reqwest_resolve::context::ResolveContext;
+/// let url = reqwest::Url::parse("https://api.example.com/v1/items").unwrap();
+/// let context = ResolveContext::for_url(&url).with_hint("tier", "batch");
+/// assert_eq!(context.scheme(), Some("https"));
+/// assert_eq!(context.port(), Some(443));
+/// assert_eq!(context.hint("tier"), Some("batch"));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResolveContext {
+    port: Option<u16>,
+    scheme: Option<String>,
+    hints: BTreeMap<String, String>,
+}
+
+impl ResolveContext {
+    /// Says nothing about the lookup, which makes `resolve_with_context`
+    /// the same as `resolve`.
+    pub fn new() -> ResolveContext {
+        ResolveContext::default()
+    }
+
+    /// Says the lookup is for connecting to `url`: its scheme, and its port
+    /// (or the scheme's usual one).
+    pub fn for_url(url: &reqwest::Url) -> ResolveContext {
+        let context = ResolveContext::new().with_scheme(url.scheme());
+        match url.port_or_known_default() {
+            Some(port) => context.with_port(port),
+            None => context,
+        }
+    }
+
+    /// Says the lookup is for connecting to `port`.
+    pub fn with_port(mut self, port: u16) -> ResolveContext {
+        self.port = Some(port);
+        self
+    }
+
+    /// Says the lookup is for a URL with the `scheme` (like "https"),
+    /// which is kept in lowercase.
+    pub fn with_scheme(mut self, scheme: &str) -> ResolveContext {
+        self.scheme = Some(scheme.to_ascii_lowercase());
+        self
+    }
+
+    /// Adds a hint for resolvers that look for it, replacing any hint
+    /// already given with the same key.
+    pub fn with_hint(mut self, key: &str, value: &str) -> ResolveContext {
+        self.hints.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    pub fn hint(&self, key: &str) -> Option<&str> {
+        self.hints.get(key).map(String::as_str)
+    }
+
+    pub fn hints(&self) -> &BTreeMap<String, String> {
+        &self.hints
+    }
+}
+
+/// Runs `lookup` with `context` in scope, both while it's created and while
+/// it runs, so that a resolver sees the context whether it reads it in
+/// `resolve` or in the future that returns.
+pub(crate) async fn scoped<F, Fut>(
+    context: &ResolveContext,
+    lookup: F,
+) -> Fut::Output
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    let future = CURRENT_CONTEXT.sync_scope(context.clone(), lookup);
+    CURRENT_CONTEXT.scope(context.clone(), future).await
+}
+
+/// Returns the context of the lookup being made, if it was made through
+/// `MyResolve::resolve_with_context`.
+pub fn current_context() -> Option<ResolveContext> {
+    CURRENT_CONTEXT.try_with(ResolveContext::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::current_context;
+    use super::ResolveContext;
+    use crate::audit::AuditResolver;
+    use crate::MyResolve;
+    use crate::MyResolving;
+    use futures::future::FutureExt;
+    use reqwest::dns::Addrs;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    /// Records the context in scope when a lookup is made and when it runs
+    #[derive(Default)]
+    struct Recorder {
+        seen: Mutex<Vec<Option<ResolveContext>>>,
+    }
+
+    impl MyResolve for Recorder {
+        fn resolve(
+            &self,
+            _name: hyper::client::connect::dns::Name,
+        ) -> MyResolving<'_> {
+            self.seen.lock().unwrap().push(current_context());
+            async move {
+                self.seen.lock().unwrap().push(current_context());
+                let addr: SocketAddr = "192.0.2.1:0".parse().unwrap();
+                Ok(Box::new(std::iter::once(addr)) as Addrs)
+            }
+            .boxed()
+            .into()
+        }
+    }
+
+    #[tokio::test]
+    async fn context_reaches_inner_layers() {
+        let recorder = Arc::new(Recorder::default());
+        let resolver = AuditResolver::new(Arc::clone(&recorder));
+        let url = reqwest::Url::parse("https://example.com:8443/").unwrap();
+        let context = ResolveContext::for_url(&url).with_hint("tier", "batch");
+
+        let addrs: Vec<SocketAddr> = resolver
+            .resolve_with_context("example.com".parse().unwrap(), &context)
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, ["192.0.2.1:8443".parse().unwrap()]);
+        let seen = recorder.seen.lock().unwrap().clone();
+        assert_eq!(seen, [Some(context.clone()), Some(context)]);
+
+        // Outside of resolve_with_context, there's no context.
+        resolver.resolve_to_vec("example.com").await.unwrap();
+        let seen = recorder.seen.lock().unwrap().clone();
+        assert_eq!(seen[2..], [None, None]);
+    }
+}
@@ -0,0 +1,156 @@
+//! DNS Cookies (RFC 7873)
+//!
+//! With cookies, each query carries a random "client cookie", and a server
+//! that supports them answers with that same client cookie plus a "server
+//! cookie" of its own, which the client sends back on later queries.  That
+//! gives clients a second secret (besides the query ID and port) that a
+//! forged response has to get right, and it lets servers tell real clients
+//! from spoofed sources, which is why some of them rate-limit queries that
+//! don't carry a valid server cookie.
+
+use crate::deterministic::random;
+use crate::transport::QueryFilter;
+use crate::transport::Upstream;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::op::Edns;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::rdata::opt::EdnsCode;
+use trust_dns_resolver::proto::rr::rdata::opt::EdnsOption;
+
+/// The EDNS option code for COOKIE
+const EDNS_COOKIE: u16 = 10;
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_MIN: usize = 8;
+const SERVER_COOKIE_MAX: usize = 32;
+
+/// A [`QueryFilter`] that adds DNS Cookies to queries and checks them in
+/// responses
+///
+/// Each upstream server gets its own client cookie, so servers can't use it
+/// to correlate this client's queries with each other.  Responses that carry
+/// a cookie option with the wrong client cookie are rejected as spoofed.
+/// Responses with no cookie at all are accepted, since plenty of servers
+/// don't support cookies.  When a server answers BADCOOKIE (because the
+/// server cookie we sent is stale), we keep the fresh server cookie from its
+/// response for later queries, but the lookup in progress fails over to the
+/// next server (trust-dns doesn't retry against the same one).
+///
+/// Cookies need EDNS, so queries that weren't going to use EDNS get it
+/// added.
+///
+/// ```
+/// # use reqwest_resolve::cookies::DnsCookies;
+/// # use reqwest_resolve::transport::{filtered_resolver, FilteredDnsResolver};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let resolver = filtered_resolver(
+///     ResolverConfig::quad9(),
+///     ResolverOpts::default(),
+///     vec![Arc::new(DnsCookies::new())],
+/// )
+/// .unwrap();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(FilteredDnsResolver::new(resolver)));
+/// ```
+#[derive(Default)]
+pub struct DnsCookies {
+    servers: Mutex<BTreeMap<SocketAddr, ServerCookies>>,
+}
+
+struct ServerCookies {
+    client: [u8; CLIENT_COOKIE_LEN],
+    /// the server cookie from the server's latest response, if it has sent
+    /// one
+    server: Option<Vec<u8>>,
+}
+
+impl DnsCookies {
+    pub fn new() -> DnsCookies {
+        DnsCookies::default()
+    }
+
+    /// Returns the cookie option to send to `addr`: our client cookie,
+    /// followed by the server's cookie if we have one.
+    fn option_for(&self, addr: SocketAddr) -> Vec<u8> {
+        let mut servers = self.servers.lock().unwrap();
+        let cookies = servers.entry(addr).or_insert_with(|| ServerCookies {
+            client: random(),
+            server: None,
+        });
+        let mut data = cookies.client.to_vec();
+        if let Some(server) = &cookies.server {
+            data.extend_from_slice(server);
+        }
+        data
+    }
+}
+
+impl QueryFilter for DnsCookies {
+    fn on_request(&self, upstream: &Upstream, request: &mut Message) {
+        let data = self.option_for(upstream.addr);
+        request
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .options_mut()
+            .insert(EdnsOption::Unknown(EDNS_COOKIE, data));
+    }
+
+    fn on_response(
+        &self,
+        upstream: &Upstream,
+        _request: &Message,
+        response: &mut Message,
+    ) -> Result<(), ResolveError> {
+        let reject = |message: &str| {
+            Err(ResolveError::from(format!(
+                "rejected response from {}: {}",
+                upstream.addr, message
+            )))
+        };
+
+        let option = response
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(EdnsCode::Cookie));
+        let data = match option {
+            Some(EdnsOption::Unknown(_, data)) => data.as_slice(),
+            Some(_) | None => {
+                if response.response_code() == ResponseCode::BADCOOKIE {
+                    return reject("BADCOOKIE without a cookie");
+                }
+                return Ok(());
+            }
+        };
+
+        let server_len = data.len().saturating_sub(CLIENT_COOKIE_LEN);
+        if data.len() < CLIENT_COOKIE_LEN + SERVER_COOKIE_MIN
+            || server_len > SERVER_COOKIE_MAX
+        {
+            return reject("malformed DNS cookie");
+        }
+
+        let (client, server) = data.split_at(CLIENT_COOKIE_LEN);
+        {
+            let mut servers = self.servers.lock().unwrap();
+            let Some(cookies) = servers.get_mut(&upstream.addr) else {
+                return reject("DNS cookie from a server we never sent one");
+            };
+            if cookies.client[..] != *client {
+                return reject("wrong client cookie");
+            }
+            cookies.server = Some(server.to_vec());
+        }
+
+        if response.response_code() == ResponseCode::BADCOOKIE {
+            // We've stored the new server cookie, so the next query will use
+            // it.
+            return reject("server cookie was stale (BADCOOKIE)");
+        }
+
+        Ok(())
+    }
+}
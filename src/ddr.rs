@@ -0,0 +1,649 @@
+//! Discovering a resolver's encrypted equivalents (RFC 9462)
+//!
+//! Plenty of networks hand out a plain DNS server (over DHCP, say) that
+//! also speaks DNS-over-TLS or DNS-over-HTTPS, and says so: asked for the
+//! SVCB records of `_dns.resolver.arpa`, it answers with its "designated
+//! resolvers", the encrypted servers that act for it.  [`upgrade`] asks
+//! each configured server and, where they have designated resolvers that
+//! check out, sends queries to those instead, so the same resolvers get
+//! used without anyone on the network path seeing the queries.
+//!
+//! An answer to that query could come from anyone on the path, so RFC 9462
+//! only trusts a designated resolver whose certificate is valid for its own
+//! name and also lists the address of the plain server that designated it
+//! ("Verified Discovery").  The certificate is checked that way when the
+//! resolver is discovered and on every connection after.  Designated
+//! resolvers that can't be verified, including all of those for servers at
+//! private addresses (which public certificate authorities don't issue
+//! certificates for), aren't used.  Neither are DNS-over-QUIC ones, which
+//! this crate doesn't speak.
+
+use crate::bootstrap::EncryptedProtocol;
+use crate::logging::debug;
+use crate::upstream_tls::UpstreamTls;
+use rustls::ClientConfig;
+use rustls::ServerName;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::NameServerConfigGroup;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamKey;
+use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamValue;
+use trust_dns_resolver::proto::rr::rdata::svcb::SVCB;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The name whose SVCB records list a resolver's designated resolvers
+pub const DDR_NAME: &str = "_dns.resolver.arpa.";
+
+/// How long to wait for a designated resolver's TLS handshake when
+/// verifying it
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The "dohpath" SvcParamKey (RFC 9461), which trust-dns doesn't know
+const DOHPATH: u16 = 7;
+
+/// An encrypted resolver that a plain one designated, and that's been
+/// verified
+#[derive(Clone)]
+pub struct DesignatedResolver {
+    /// the plain resolver that designated this one
+    pub designated_by: SocketAddr,
+    /// this resolver's name, which its certificate is valid for
+    pub name: String,
+    pub protocol: EncryptedProtocol,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    /// for DNS-over-HTTPS, the URI template for queries (like
+    /// "/dns-query{?dns}")
+    pub doh_path: Option<String>,
+    /// the SVCB record's priority (lower is preferred)
+    pub priority: u16,
+    tls_config: Arc<ClientConfig>,
+}
+
+impl DesignatedResolver {
+    /// Returns whether trust-dns itself can send queries to this resolver.
+    /// It can't for DNS-over-HTTPS at any path but "/dns-query", though
+    /// `doh::DohTransport::with_path` can.
+    pub fn usable_by_trust_dns(&self) -> bool {
+        match self.protocol {
+            EncryptedProtocol::Tls => true,
+            #[cfg(feature = "dns-over-https-rustls")]
+            EncryptedProtocol::Https => {
+                self.doh_path.as_deref().map(doh_path_base)
+                    == Some("/dns-query")
+            }
+        }
+    }
+
+    /// Returns name server entries for each of this resolver's addresses,
+    /// which check its certificate the way it was checked when it was
+    /// discovered
+    ///
+    /// trust-dns keeps the TLS configuration for the whole group, not each
+    /// entry, so merging these into another group loses it.
+    pub fn name_servers(&self) -> NameServerConfigGroup {
+        let group: NameServerConfigGroup = self
+            .addrs
+            .iter()
+            .map(|ip| {
+                let mut config = NameServerConfig::new(
+                    SocketAddr::new(*ip, self.port),
+                    self.protocol.protocol(),
+                );
+                config.tls_dns_name = Some(self.name.clone());
+                config.trust_nx_responses = false;
+                config
+            })
+            .collect::<Vec<_>>()
+            .into();
+        group.with_client_config(Arc::clone(&self.tls_config))
+    }
+}
+
+/// Asks the plain resolver at `server` for its designated resolvers, and
+/// returns the ones that can be verified, most preferred first
+///
+/// Certificates are checked against `tls`'s root certificates (and pins,
+/// if it has any).  A resolver with no designated resolvers produces an
+/// empty list rather than an error.
+pub async fn discover(
+    server: SocketAddr,
+    tls: &UpstreamTls,
+) -> Result<Vec<DesignatedResolver>, ResolveError> {
+    let plain = TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(
+            None,
+            Vec::new(),
+            vec![
+                NameServerConfig::new(server, Protocol::Udp),
+                NameServerConfig::new(server, Protocol::Tcp),
+            ],
+        ),
+        ResolverOpts::default(),
+    )?;
+    let lookup = match plain.lookup(DDR_NAME, RecordType::SVCB).await {
+        Ok(lookup) => lookup,
+        Err(error) => match error.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok(Vec::new()),
+            _ => return Err(error),
+        },
+    };
+    let mut records: Vec<SVCB> = lookup
+        .iter()
+        .filter_map(|rdata| match rdata {
+            RData::SVCB(svcb) => Some(svcb.clone()),
+            _ => None,
+        })
+        .collect();
+    records.sort_by_key(|svcb| svcb.svc_priority());
+
+    let tls_config = tls.clone().with_required_ip(server.ip()).build();
+    let mut designated = Vec::new();
+    for svcb in &records {
+        for candidate in candidates(server, svcb, &tls_config) {
+            let mut candidate = candidate;
+            if candidate.addrs.is_empty() {
+                candidate.addrs = plain
+                    .lookup_ip(candidate.name.as_str())
+                    .await
+                    .map(|lookup| lookup.iter().collect())
+                    .unwrap_or_default();
+            }
+            match verify(&candidate).await {
+                Ok(addrs) => {
+                    debug!(
+                        "verified designated resolver",
+                        server = server,
+                        name = candidate.name,
+                        protocol = candidate.protocol,
+                    );
+                    candidate.addrs = addrs;
+                    designated.push(candidate);
+                }
+                Err(error) => {
+                    debug!(
+                        "couldn't verify designated resolver",
+                        server = server,
+                        name = candidate.name,
+                        error = error,
+                    );
+                }
+            }
+        }
+    }
+    Ok(designated)
+}
+
+/// Returns `config` with each plain server that has verified designated
+/// resolvers replaced by those resolvers (the ones trust-dns can use)
+///
+/// Servers with none, and servers that can't be asked, are kept as they
+/// are, as is everything else about the configuration.  trust-dns uses one
+/// TLS configuration for every server, so each designated resolver's
+/// certificate has to list the address of any one of the servers that were
+/// replaced, not necessarily the one that designated it.  A configuration
+/// that already has encrypted servers is returned unchanged, since they
+/// wouldn't pass that check.
+///
+/// ```no_run
+/// # use reqwest_resolve::ddr::upgrade;
+/// # use reqwest_resolve::upstream_tls::UpstreamTls;
+/// # use trust_dns_resolver::system_conf::read_system_conf;
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let (config, options) = read_system_conf()?;
+/// let config = upgrade(&config, &UpstreamTls::new()).await;
+/// let _resolver = TokioAsyncResolver::tokio(config, options)?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn upgrade(
+    config: &ResolverConfig,
+    tls: &UpstreamTls,
+) -> ResolverConfig {
+    if config
+        .name_servers()
+        .iter()
+        .any(|name_server| name_server.protocol.is_encrypted())
+    {
+        return config.clone();
+    }
+
+    let mut name_servers: Vec<NameServerConfig> = Vec::new();
+    let mut upgraded: Vec<SocketAddr> = Vec::new();
+    let mut plain: Vec<SocketAddr> = Vec::new();
+    for name_server in config.name_servers() {
+        let server = name_server.socket_addr;
+        if upgraded.contains(&server) {
+            continue;
+        }
+        if !plain.contains(&server) {
+            let designated =
+                discover(server, tls).await.unwrap_or_else(|error| {
+                    debug!(
+                        "DDR discovery failed",
+                        server = server,
+                        error = error
+                    );
+                    Vec::new()
+                });
+            let before = name_servers.len();
+            for resolver in &designated {
+                if resolver.usable_by_trust_dns() {
+                    name_servers.extend(resolver.name_servers().into_inner());
+                }
+            }
+            if name_servers.len() > before {
+                upgraded.push(server);
+                continue;
+            }
+            plain.push(server);
+        }
+        name_servers.push(name_server.clone());
+    }
+    if upgraded.is_empty() {
+        return config.clone();
+    }
+
+    let tls_config = upgraded
+        .iter()
+        .fold(tls.clone(), |tls, server| tls.with_required_ip(server.ip()))
+        .build();
+    let mut upgraded_config = ResolverConfig::from_parts(
+        config.domain().cloned(),
+        config.search().to_vec(),
+        name_servers,
+    );
+    upgraded_config.set_tls_client_config(tls_config);
+    upgraded_config
+}
+
+/// Returns the designated resolvers `svcb` describes, unverified.
+fn candidates(
+    server: SocketAddr,
+    svcb: &SVCB,
+    tls_config: &Arc<ClientConfig>,
+) -> Vec<DesignatedResolver> {
+    // AliasMode records aren't defined for DDR, and a target of "." would
+    // mean `_dns.resolver.arpa` itself, which no certificate can be valid
+    // for.
+    let name = svcb.target_name().to_ascii();
+    let name = name.trim_end_matches('.');
+    if svcb.svc_priority() == 0 || name.is_empty() {
+        return Vec::new();
+    }
+
+    let mut alpns: &[String] = &[];
+    let mut port = None;
+    let mut addrs = Vec::new();
+    let mut doh_path = None;
+    for (key, value) in svcb.svc_params() {
+        match (key, value) {
+            (_, SvcParamValue::Alpn(alpn)) => alpns = &alpn.0,
+            (_, SvcParamValue::Port(value)) => port = Some(*value),
+            (_, SvcParamValue::Ipv4Hint(hint)) => {
+                addrs.extend(hint.0.iter().map(|ip| IpAddr::V4(*ip)))
+            }
+            (_, SvcParamValue::Ipv6Hint(hint)) => {
+                addrs.extend(hint.0.iter().map(|ip| IpAddr::V6(*ip)))
+            }
+            (SvcParamKey::Unknown(DOHPATH), SvcParamValue::Unknown(value)) => {
+                doh_path = String::from_utf8(value.0.clone()).ok()
+            }
+            _ => (),
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for alpn in alpns {
+        let protocol = match alpn.as_str() {
+            "dot" => EncryptedProtocol::Tls,
+            // DNS-over-HTTPS needs a path to send queries to.
+            #[cfg(feature = "dns-over-https-rustls")]
+            "h2" if doh_path.is_some() => EncryptedProtocol::Https,
+            _ => continue,
+        };
+        if candidates.iter().any(|candidate: &DesignatedResolver| {
+            candidate.protocol == protocol
+        }) {
+            continue;
+        }
+        candidates.push(DesignatedResolver {
+            designated_by: server,
+            name: name.to_owned(),
+            protocol,
+            addrs: addrs.clone(),
+            port: port.unwrap_or(protocol.default_port()),
+            doh_path: doh_path
+                .clone()
+                .filter(|_| protocol != EncryptedProtocol::Tls),
+            priority: svcb.svc_priority(),
+            tls_config: Arc::clone(tls_config),
+        });
+    }
+    candidates
+}
+
+/// Connects to each of `resolver`'s addresses to check the certificate
+/// there, and returns the addresses where it checked out
+///
+/// Each address is a separate server as far as TLS is concerned, and any of
+/// them may end up getting queries, so one good certificate doesn't vouch
+/// for the rest.  This fails if none of them check out.
+async fn verify(
+    resolver: &DesignatedResolver,
+) -> Result<Vec<IpAddr>, std::io::Error> {
+    use std::io::Error;
+    use std::io::ErrorKind;
+
+    if resolver.addrs.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "no addresses"));
+    }
+    let results = futures::future::join_all(
+        resolver.addrs.iter().map(|ip| verify_one(resolver, *ip)),
+    )
+    .await;
+    let mut verified = Vec::new();
+    let mut last_error = None;
+    for (ip, result) in resolver.addrs.iter().zip(results) {
+        match result {
+            Ok(()) => verified.push(*ip),
+            Err(error) => {
+                debug!(
+                    "designated resolver address failed verification",
+                    name = resolver.name,
+                    ip = ip,
+                    error = error,
+                );
+                last_error = Some(error);
+            }
+        }
+    }
+    match last_error {
+        Some(error) if verified.is_empty() => Err(error),
+        _ => Ok(verified),
+    }
+}
+
+/// Connects to `resolver` at `ip` to check its certificate.
+async fn verify_one(
+    resolver: &DesignatedResolver,
+    ip: IpAddr,
+) -> Result<(), std::io::Error> {
+    use std::io::Error;
+    use std::io::ErrorKind;
+
+    let server_name = ServerName::try_from(resolver.name.as_str())
+        .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+    let mut tls_config = Arc::clone(&resolver.tls_config);
+    if resolver.doh_path.is_some() {
+        Arc::make_mut(&mut tls_config).alpn_protocols = vec![b"h2".to_vec()];
+    }
+    let handshake = async {
+        let tcp =
+            TcpStream::connect(SocketAddr::new(ip, resolver.port)).await?;
+        TlsConnector::from(tls_config).connect(server_name, tcp).await
+    };
+    tokio::time::timeout(VERIFY_TIMEOUT, handshake)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake"))??;
+    Ok(())
+}
+
+/// Returns the path of a "dohpath" URI template, without its variables.
+#[cfg(feature = "dns-over-https-rustls")]
+fn doh_path_base(template: &str) -> &str {
+    template.split('{').next().unwrap_or(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::candidates;
+    use super::verify;
+    use super::DesignatedResolver;
+    use crate::bootstrap::EncryptedProtocol;
+    use crate::upstream_tls::UpstreamTls;
+    use rcgen::BasicConstraints;
+    use rcgen::CertificateParams;
+    use rcgen::IsCa;
+    use rcgen::SanType;
+    use rustls::Certificate;
+    use rustls::PrivateKey;
+    use rustls::RootCertStore;
+    use rustls::ServerConfig;
+    use std::net::IpAddr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use trust_dns_resolver::proto::rr::rdata::svcb::Alpn;
+    use trust_dns_resolver::proto::rr::rdata::svcb::IpHint;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamKey;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamValue;
+    #[cfg(feature = "dns-over-https-rustls")]
+    use trust_dns_resolver::proto::rr::rdata::svcb::Unknown;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SVCB;
+    use trust_dns_resolver::proto::rr::Name;
+
+    const PLAIN: &str = "192.0.2.53:53";
+    const NAME: &str = "dns.example.com";
+
+    fn svcb(priority: u16, params: Vec<SvcParamValue>) -> SVCB {
+        let params = params
+            .into_iter()
+            .map(|value| {
+                let key = match &value {
+                    SvcParamValue::Alpn(_) => SvcParamKey::Alpn,
+                    SvcParamValue::Port(_) => SvcParamKey::Port,
+                    SvcParamValue::Ipv4Hint(_) => SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv6Hint(_) => SvcParamKey::Ipv6Hint,
+                    _ => SvcParamKey::Unknown(super::DOHPATH),
+                };
+                (key, value)
+            })
+            .collect();
+        SVCB::new(priority, Name::from_ascii(NAME).unwrap(), params)
+    }
+
+    fn alpn(alpns: &[&str]) -> SvcParamValue {
+        SvcParamValue::Alpn(Alpn(
+            alpns.iter().map(|alpn| String::from(*alpn)).collect(),
+        ))
+    }
+
+    fn candidates_for(svcb: &SVCB) -> Vec<DesignatedResolver> {
+        let tls_config = UpstreamTls::new().build();
+        candidates(PLAIN.parse().unwrap(), svcb, &tls_config)
+    }
+
+    #[test]
+    fn candidates_from_service_mode_record() {
+        let record = svcb(
+            1,
+            vec![
+                alpn(&["dot"]),
+                SvcParamValue::Port(8853),
+                SvcParamValue::Ipv4Hint(IpHint(vec!["192.0.2.1"
+                    .parse()
+                    .unwrap()])),
+                SvcParamValue::Ipv6Hint(IpHint(vec!["2001:db8::1"
+                    .parse()
+                    .unwrap()])),
+            ],
+        );
+        let found = candidates_for(&record);
+        assert_eq!(found.len(), 1);
+        let candidate = &found[0];
+        assert_eq!(candidate.designated_by, PLAIN.parse().unwrap());
+        assert_eq!(candidate.name, NAME);
+        assert_eq!(candidate.protocol, EncryptedProtocol::Tls);
+        assert_eq!(candidate.port, 8853);
+        assert_eq!(candidate.priority, 1);
+        assert_eq!(candidate.doh_path, None);
+        let addrs: Vec<IpAddr> =
+            vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        assert_eq!(candidate.addrs, addrs);
+    }
+
+    #[test]
+    fn candidates_default_port_and_no_hints() {
+        let found = candidates_for(&svcb(2, vec![alpn(&["dot", "dot"])]));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].port, EncryptedProtocol::Tls.default_port());
+        assert!(found[0].addrs.is_empty());
+    }
+
+    #[test]
+    fn candidates_ignore_alias_mode_and_root_target() {
+        assert!(candidates_for(&svcb(0, vec![alpn(&["dot"])])).is_empty());
+        let tls_config = UpstreamTls::new().build();
+        let root = SVCB::new(
+            1,
+            Name::root(),
+            vec![(SvcParamKey::Alpn, alpn(&["dot"]))],
+        );
+        assert!(
+            candidates(PLAIN.parse().unwrap(), &root, &tls_config).is_empty()
+        );
+    }
+
+    #[test]
+    fn candidates_ignore_unknown_protocols() {
+        assert!(candidates_for(&svcb(1, vec![alpn(&["doq", "h3"])])).is_empty());
+        // DNS-over-HTTPS needs a "dohpath".
+        let found = candidates_for(&svcb(1, vec![alpn(&["h2", "dot"])]));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].protocol, EncryptedProtocol::Tls);
+    }
+
+    #[cfg(feature = "dns-over-https-rustls")]
+    #[test]
+    fn candidates_for_https() {
+        let dohpath =
+            SvcParamValue::Unknown(Unknown(b"/dns-query{?dns}".to_vec()));
+        let found =
+            candidates_for(&svcb(1, vec![alpn(&["h2", "dot"]), dohpath]));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].protocol, EncryptedProtocol::Https);
+        assert_eq!(found[0].doh_path.as_deref(), Some("/dns-query{?dns}"));
+        assert!(found[0].usable_by_trust_dns());
+        assert_eq!(found[1].protocol, EncryptedProtocol::Tls);
+        assert_eq!(found[1].doh_path, None);
+    }
+
+    /// A certificate authority for the tests' designated resolvers
+    struct Authority {
+        cert: rcgen::Certificate,
+        roots: RootCertStore,
+    }
+
+    impl Authority {
+        fn new() -> Authority {
+            let mut params = CertificateParams::new(Vec::new());
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let mut roots = RootCertStore::empty();
+            roots.add(&Certificate(cert.serialize_der().unwrap())).unwrap();
+            Authority { cert, roots }
+        }
+
+        /// Returns a TLS server configuration with a certificate for
+        /// `NAME` that lists `ips`.
+        fn server(&self, ips: &[&str]) -> Arc<ServerConfig> {
+            let mut params = CertificateParams::new(vec![String::from(NAME)]);
+            params.subject_alt_names.extend(
+                ips.iter().map(|ip| SanType::IpAddress(ip.parse().unwrap())),
+            );
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let der = cert.serialize_der_with_signer(&self.cert).unwrap();
+            let config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![Certificate(der)],
+                    PrivateKey(cert.serialize_private_key_der()),
+                )
+                .unwrap();
+            Arc::new(config)
+        }
+    }
+
+    /// Accepts TLS connections on `listener` with `config` until the test
+    /// ends.
+    fn serve(listener: TcpListener, config: Arc<ServerConfig>) {
+        let acceptor = TlsAcceptor::from(config);
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let _ = acceptor.accept(tcp).await;
+            }
+        });
+    }
+
+    fn designated(
+        authority: &Authority,
+        addrs: Vec<IpAddr>,
+        port: u16,
+    ) -> DesignatedResolver {
+        let plain: SocketAddr = PLAIN.parse().unwrap();
+        let tls_config = UpstreamTls::with_roots(authority.roots.clone())
+            .with_required_ip(plain.ip())
+            .build();
+        DesignatedResolver {
+            designated_by: plain,
+            name: String::from(NAME),
+            protocol: EncryptedProtocol::Tls,
+            addrs,
+            port,
+            doh_path: None,
+            priority: 1,
+            tls_config,
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_checks_every_address() {
+        let authority = Authority::new();
+        let good = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = good.local_addr().unwrap().port();
+        let bad = TcpListener::bind(("127.0.0.2", port)).await.unwrap();
+        serve(good, authority.server(&["192.0.2.53"]));
+        // This one's certificate doesn't list the plain server's address.
+        serve(bad, authority.server(&["192.0.2.54"]));
+
+        let good_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let bad_ip: IpAddr = "127.0.0.2".parse().unwrap();
+        let resolver = designated(&authority, vec![bad_ip, good_ip], port);
+        assert_eq!(verify(&resolver).await.unwrap(), [good_ip]);
+
+        let resolver = designated(&authority, vec![bad_ip], port);
+        verify(&resolver).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn verify_checks_the_roots() {
+        let authority = Authority::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        serve(listener, Authority::new().server(&["192.0.2.53"]));
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        verify(&designated(&authority, vec![ip], port)).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn verify_needs_addresses() {
+        let authority = Authority::new();
+        verify(&designated(&authority, Vec::new(), 853)).await.unwrap_err();
+    }
+}
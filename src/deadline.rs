@@ -0,0 +1,169 @@
+//! Keeping lookups within a request's time budget
+//!
+//! A request with a five-second timeout that spends four of them waiting on
+//! DNS has little chance of finishing, and when it fails, it fails as a
+//! request timeout that says nothing about DNS.  [`with_deadline`] and
+//! [`with_timeout`] put a deadline in scope for the lookups a future makes,
+//! and [`DeadlineResolver`] fails any lookup that would run past it (or past
+//! its share of the time left) with [`ResolveError::DeadlineExceeded`].
+//!
+//! Like the other task-local settings in this crate, the deadline only
+//! applies to lookups made on the task running the future.  A connection that
+//! hyper finishes in the background resolves without one.
+
+use crate::error::ResolveError;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::time::Duration;
+use std::time::Instant;
+
+tokio::task_local! {
+    static CURRENT_DEADLINE: Instant;
+}
+
+/// Runs `future` with `deadline` in scope for the lookups it makes
+///
+/// An enclosing deadline that's earlier still applies.
+///
+/// ```
+/// # use reqwest_resolve::deadline::with_deadline;
+/// # use std::time::{Duration, Instant};
+/// # async fn example(client: reqwest::Client) -> reqwest::Result<()> {
+/// let deadline = Instant::now() + Duration::from_secs(5);
+/// let request = client
+///     .get("https://example.com/")
+///     .timeout(Duration::from_secs(5))
+///     .send();
+/// let response = with_deadline(deadline, request).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn with_deadline<F: Future>(
+    deadline: Instant,
+    future: F,
+) -> F::Output {
+    let deadline = match current_deadline() {
+        Some(outer) => outer.min(deadline),
+        None => deadline,
+    };
+    CURRENT_DEADLINE.scope(deadline, future).await
+}
+
+/// Runs `future` with a deadline `timeout` from now (see [`with_deadline`])
+pub async fn with_timeout<F: Future>(
+    timeout: Duration,
+    future: F,
+) -> F::Output {
+    // A timeout too long to represent is no deadline at all.
+    match Instant::now().checked_add(timeout) {
+        Some(deadline) => with_deadline(deadline, future).await,
+        None => future.await,
+    }
+}
+
+/// Returns the deadline in scope, if any.
+pub fn current_deadline() -> Option<Instant> {
+    CURRENT_DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Fails lookups that don't finish before the deadline in scope
+///
+/// With [`DeadlineResolver::with_share`], lookups only get part of the time
+/// left, leaving the rest for connecting and the request itself.  Lookups
+/// with no deadline in scope aren't limited.
+///
+/// ```
+/// # use reqwest_resolve::deadline::DeadlineResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// // DNS gets at most a quarter of whatever time the request has left.
+/// let my_resolver =
+///     DeadlineResolver::with_share(MyCustomDnsResolver::new(resolver), 0.25);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct DeadlineResolver<R> {
+    inner: R,
+    share: f64,
+}
+
+impl<R> DeadlineResolver<R> {
+    /// Lets lookups use all of the time left.
+    pub fn new(inner: R) -> DeadlineResolver<R> {
+        DeadlineResolver::with_share(inner, 1.0)
+    }
+
+    /// Lets lookups use `share` (from 0 to 1) of the time left when they
+    /// start.  Values outside that range are clamped to it.
+    pub fn with_share(inner: R, share: f64) -> DeadlineResolver<R> {
+        let share = if share.is_nan() { 1.0 } else { share.clamp(0.0, 1.0) };
+        DeadlineResolver { inner, share }
+    }
+}
+
+impl<R: MyResolve> MyResolve for DeadlineResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        // Look for a deadline when the lookup is first polled rather than
+        // when it's created, so that a lookup created outside `with_deadline`
+        // but awaited inside it still gets one.
+        async move {
+            let host = name.as_str().to_owned();
+            self.limited(host, self.inner.resolve(name)).await
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let host = name.as_str().to_owned();
+            self.limited(host, self.inner.resolve_detailed(name)).await
+        }
+        .boxed()
+    }
+}
+
+impl<R> DeadlineResolver<R> {
+    /// Runs `lookup` (of `name`) with this layer's share of the time left
+    /// before the task's deadline, if it has one.
+    async fn limited<T, F>(
+        &self,
+        name: String,
+        lookup: F,
+    ) -> Result<T, Box<dyn StdError + Send + Sync>>
+    where
+        F: Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>,
+    {
+        let Some(deadline) = current_deadline() else {
+            return lookup.await;
+        };
+
+        let left = deadline.saturating_duration_since(Instant::now());
+        let budget = left.mul_f64(self.share);
+        let error = ResolveError::DeadlineExceeded { name };
+        if budget.is_zero() {
+            return Err(error.into());
+        }
+        match tokio::time::timeout(budget, lookup).await {
+            Ok(result) => result,
+            Err(_) => Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Sharing one lookup between callers asking for the same name
+//!
+//! A client that opens many connections to the same host at once (a burst
+//! of requests after a deploy, or a pool warming up) asks for that host's
+//! addresses once per connection, and until an answer is cached, every one
+//! of those lookups goes upstream.  [`DedupResolver`] lets the first lookup
+//! of a name go through and has the others that arrive while it's in
+//! flight wait for its answer instead.
+//!
+//! A lookup removes its own entry from the table of lookups in flight when
+//! it finishes, which can race with another caller joining it.  Those
+//! interleavings are checked with [loom](https://docs.rs/loom): build with
+//! `RUSTFLAGS="--cfg reqwest_resolve_loom"` and run `cargo test --release
+//! --lib dedup::loom_models`.
+
+use crate::error::ResolveError;
+use crate::logging::trace;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::future::Shared;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+#[cfg(not(reqwest_resolve_loom))]
+use std::sync::Mutex;
+
+#[cfg(reqwest_resolve_loom)]
+use loom::sync::Mutex;
+
+/// The result of a lookup, in a form every waiter can have a copy of
+type SharedResult =
+    Result<Vec<crate::resolved::ResolvedAddr>, Arc<dyn StdError + Send + Sync>>;
+
+type InFlight =
+    Arc<Mutex<BTreeMap<String, Shared<BoxFuture<'static, SharedResult>>>>>;
+
+/// Shares each lookup with the lookups of the same name that start while
+/// it's in flight
+///
+/// Names are matched case-insensitively, ignoring any trailing dot, and the
+/// inner resolver sees the name as the first caller wrote it.  Nothing is
+/// kept once a lookup finishes, so this doesn't cache anything: put it
+/// beneath a cache to keep a burst of misses from all going upstream.  Every
+/// caller gets the same answer, including the same error, which is a copy of
+/// the inner resolver's if it's one of this crate's `ResolveError`s (or,
+/// with the "trust-dns" feature, one of trust-dns's) and otherwise carries
+/// its message.
+///
+/// Lookups go through the inner resolver's `resolve_detailed`, so both
+/// `resolve` and `resolve_detailed` share them.
+///
+/// ```
+/// # use reqwest_resolve::dedup::DedupResolver;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// let my_resolver = DedupResolver::new(HOSTS);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct DedupResolver<R> {
+    inner: Arc<R>,
+    in_flight: InFlight,
+}
+
+impl<R> DedupResolver<R> {
+    pub fn new(inner: R) -> DedupResolver<R> {
+        DedupResolver { inner: Arc::new(inner), in_flight: Arc::default() }
+    }
+
+    /// Returns how many names have a lookup in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+impl<R: MyResolve + 'static> DedupResolver<R> {
+    /// Returns the lookup in flight for `name`, starting one if there isn't
+    /// one.
+    fn lookup(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> Shared<BoxFuture<'static, SharedResult>> {
+        let key = normalize(name.as_str()).into_owned();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(lookup) = in_flight.get(&key) {
+            trace!("joining lookup in flight", name = key);
+            return lookup.clone();
+        }
+
+        let inner = Arc::clone(&self.inner);
+        let done = Arc::clone(&self.in_flight);
+        let removed = key.clone();
+        let lookup = async move {
+            let result = inner.resolve_detailed(name).await.map_err(Arc::from);
+            // Lookups that start after this one has finished get their own.
+            done.lock().unwrap().remove(&removed);
+            result
+        }
+        .boxed()
+        .shared();
+        in_flight.insert(key, lookup.clone());
+        lookup
+    }
+}
+
+impl<R: MyResolve + 'static> MyResolve for DedupResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let lookup = self.lookup(name);
+        async move {
+            match lookup.await {
+                Ok(resolved) => {
+                    let addrs = resolved.into_iter().map(|r| r.addr);
+                    Ok(Box::new(addrs) as Addrs)
+                }
+                Err(error) => Err(unshared(&error)),
+            }
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let lookup = self.lookup(name);
+        async move { lookup.await.map_err(|error| unshared(&error)) }.boxed()
+    }
+}
+
+/// Returns a caller's own copy of a shared lookup's error.
+fn unshared(
+    error: &Arc<dyn StdError + Send + Sync>,
+) -> Box<dyn StdError + Send + Sync> {
+    if let Some(error) = error.downcast_ref::<ResolveError>() {
+        return Box::new(error.clone());
+    }
+    #[cfg(feature = "trust-dns")]
+    if let Some(error) =
+        error.downcast_ref::<trust_dns_resolver::error::ResolveError>()
+    {
+        return Box::new(error.clone());
+    }
+    Box::new(SharedError(Arc::clone(error)))
+}
+
+/// An error from a shared lookup that can't be copied
+struct SharedError(Arc<dyn StdError + Send + Sync>);
+
+impl fmt::Debug for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl StdError for SharedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+#[cfg(all(test, reqwest_resolve_loom))]
+mod loom_models {
+    use super::*;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use loom::thread;
+    use std::net::SocketAddr;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "a.example" => ["192.0.2.1"],
+    };
+
+    /// A caller that joins a lookup just as it finishes gets its answer,
+    /// and whichever lookup it ends up with, nothing is left in flight.
+    #[test]
+    fn join_while_finishing() {
+        loom::model(|| {
+            let dedup = Arc::new(DedupResolver::new(HOSTS));
+            let joiner = thread::spawn({
+                let dedup = Arc::clone(&dedup);
+                move || {
+                    loom::future::block_on(dedup.resolve_to_vec("a.example"))
+                }
+            });
+            let first =
+                loom::future::block_on(dedup.resolve_to_vec("A.example."));
+            let joined = joiner.join().unwrap();
+
+            let expected = [SocketAddr::from(([192, 0, 2, 1], 0))];
+            assert_eq!(first.unwrap(), expected);
+            assert_eq!(joined.unwrap(), expected);
+            assert_eq!(dedup.in_flight(), 0);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DedupResolver;
+    use crate::error::ResolveError;
+    use crate::MyResolve;
+    use crate::MyResolving;
+    use futures::future::FutureExt;
+    use reqwest::dns::Addrs;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    /// Answers every name once it's told to, counting the lookups, and
+    /// fails for names starting with "missing"
+    #[derive(Default)]
+    struct Gated {
+        lookups: AtomicUsize,
+        open: Notify,
+    }
+
+    impl MyResolve for Gated {
+        fn resolve(
+            &self,
+            name: hyper::client::connect::dns::Name,
+        ) -> MyResolving<'_> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            async move {
+                self.open.notified().await;
+                if name.as_str().starts_with("missing") {
+                    let name = name.as_str().to_owned();
+                    return Err(ResolveError::NotFound { name }.into());
+                }
+                let addr = SocketAddr::from(([192, 0, 2, 1], 0));
+                Ok(Box::new(std::iter::once(addr)) as Addrs)
+            }
+            .boxed()
+            .into()
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_lookups_in_flight() {
+        let gated = Arc::new(Gated::default());
+        let dedup = DedupResolver::new(Arc::clone(&gated));
+
+        let first = dedup.resolve_to_vec("api.test");
+        let second = dedup.resolve_detailed("API.test.".parse().unwrap());
+        let other = dedup.resolve_to_vec("db.test");
+        let lookups = async {
+            tokio::task::yield_now().await;
+            assert_eq!(dedup.in_flight(), 2);
+            gated.open.notify_waiters();
+        };
+        let (first, second, other, ()) =
+            futures::join!(first, second, other, lookups);
+        assert_eq!(first.unwrap(), [SocketAddr::from(([192, 0, 2, 1], 0))]);
+        assert_eq!(second.unwrap()[0].backend, "unknown");
+        assert!(other.is_ok());
+        assert_eq!(gated.lookups.load(Ordering::SeqCst), 2);
+        assert_eq!(dedup.in_flight(), 0);
+
+        // Errors are shared too, as copies of the original.
+        let first = dedup.resolve_to_vec("missing.test");
+        let second = dedup.resolve_to_vec("missing.test");
+        let open = async {
+            tokio::task::yield_now().await;
+            gated.open.notify_waiters();
+        };
+        let (first, second, ()) = futures::join!(first, second, open);
+        for error in [first.unwrap_err(), second.unwrap_err()] {
+            assert_eq!(
+                error.downcast_ref::<ResolveError>(),
+                Some(&ResolveError::NotFound { name: "missing.test".into() })
+            );
+        }
+        assert_eq!(gated.lookups.load(Ordering::SeqCst), 3);
+
+        // A lookup that starts after the last one finished gets its own.
+        let again = dedup.resolve_to_vec("api.test");
+        let open = async {
+            tokio::task::yield_now().await;
+            gated.open.notify_waiters();
+        };
+        let (again, ()) = futures::join!(again, open);
+        assert!(again.is_ok());
+        assert_eq!(gated.lookups.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,149 @@
+//! Making resolution reproducible for tests
+//!
+//! Several things this crate does are random or depend on timing on purpose:
+//! LLMNR and NetBIOS query IDs, 0x20 case randomization, and DNS cookies come
+//! from a random number generator; LLMNR takes whichever multicast group
+//! answers first; and addresses and endpoints come back in whatever order
+//! the server sent them.  That's what you want in production and not what
+//! you want in a snapshot test.  Inside [`with_seed`], random values come
+//! from a generator seeded with the given seed, races are replaced by
+//! waiting for every contestant and picking in a fixed order, and answers
+//! with no inherent order are sorted.
+//!
+//! What trust-dns does internally (like the IDs of its own queries, or which
+//! name server it tries first) isn't affected.  Pointing the resolver at a
+//! single server, such as a `testserver::TestServer`, takes care of most of
+//! that.
+
+use std::cell::Cell;
+use std::future::Future;
+
+tokio::task_local! {
+    static SEED_STATE: Cell<u64>;
+}
+
+/// Runs `future` with every random choice drawn from a generator seeded with
+/// `seed`, and with races and orderings made deterministic
+///
+/// The same seed, with the same sequence of lookups, makes the same choices.
+/// Each scope has its own generator, so concurrent tests don't disturb each
+/// other.  The random values are predictable, so never use this outside
+/// tests: query IDs and 0x20 case randomization are defenses against
+/// spoofing only because an attacker can't guess them.
+///
+/// ```
+/// # use reqwest_resolve::deterministic::{is_deterministic, with_seed};
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// assert!(!is_deterministic());
+/// with_seed(42, async {
+///     assert!(is_deterministic());
+///     // ... lookups made here are reproducible ...
+/// })
+/// .await;
+/// # });
+/// ```
+pub async fn with_seed<F: Future>(seed: u64, future: F) -> F::Output {
+    SEED_STATE.scope(Cell::new(seed), future).await
+}
+
+/// Returns whether the current task is inside [`with_seed`].
+pub fn is_deterministic() -> bool {
+    SEED_STATE.try_with(|_| ()).is_ok()
+}
+
+/// Sorts `items` if the current task is inside [`with_seed`], and otherwise
+/// leaves them alone.
+pub(crate) fn stable_order<T: Ord>(items: &mut [T]) {
+    if is_deterministic() {
+        items.sort();
+    }
+}
+
+#[cfg(any(
+    feature = "answer-limit",
+    feature = "anti-spoofing",
+    feature = "dns-cookies",
+    feature = "llmnr",
+    feature = "netbios",
+    feature = "slow-dns",
+    feature = "udp-ports",
+    feature = "zone-transfer"
+))]
+pub(crate) use seeded::random;
+
+/// The parts that need `rand`, which only some features depend on
+#[cfg(any(
+    feature = "answer-limit",
+    feature = "anti-spoofing",
+    feature = "dns-cookies",
+    feature = "llmnr",
+    feature = "netbios",
+    feature = "slow-dns",
+    feature = "udp-ports",
+    feature = "zone-transfer"
+))]
+mod seeded {
+    use super::is_deterministic;
+    use super::SEED_STATE;
+    use rand::distributions::Distribution;
+    use rand::distributions::Standard;
+    use std::cell::Cell;
+
+    /// Returns a random value: from the seeded generator inside
+    /// [`with_seed`](super::with_seed), and from `rand::random()` everywhere
+    /// else.
+    pub(crate) fn random<T>() -> T
+    where
+        Standard: Distribution<T>,
+    {
+        if is_deterministic() {
+            Standard.sample(&mut SeededRng)
+        } else {
+            rand::random()
+        }
+    }
+
+    /// Adapts the task's seeded generator to `rand`'s interface
+    struct SeededRng;
+
+    impl rand::RngCore for SeededRng {
+        fn next_u32(&mut self) -> u32 {
+            (self.next_u64() >> 32) as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            // This is only used inside `with_seed`, so there's always a
+            // generator.
+            SEED_STATE.try_with(splitmix64).unwrap_or_default()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                let bytes = self.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    /// SplitMix64, which is small, fast, and plenty good for making test runs
+    /// repeatable
+    fn splitmix64(state: &Cell<u64>) -> u64 {
+        let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(next);
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,140 @@
+//! Driving tower load balancers from DNS
+//!
+//! `tower::balance` spreads requests across a changing set of services, which
+//! it learns about from a `tower::discover::Discover`: a stream of services
+//! being inserted and removed.  [`WatchingResolver::discover`] turns a
+//! watched name into one of those, with a service per address, so that a
+//! balancer follows the name's addresses as they change.
+
+use crate::watch::WatchingResolver;
+use crate::MyResolve;
+use futures::stream::Stream;
+use reqwest::dns::Addrs;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use tower::discover::Change;
+
+type Updates = Pin<Box<dyn Stream<Item = Addrs> + Send>>;
+
+/// A `tower::discover::Discover` of one service per address of a watched
+/// name, returned by [`WatchingResolver::discover`]
+///
+/// Each address is a key (with the port given to `discover`), inserted as
+/// soon as a lookup finds it and removed as soon as a lookup no longer does.
+/// The stream never fails, and ends when the [`WatchingResolver`] is
+/// dropped.
+pub struct DnsDiscover<S> {
+    updates: Updates,
+    port: u16,
+    make_service: Box<dyn Fn(SocketAddr) -> S + Send>,
+    /// the addresses that have been inserted and not removed since
+    current: BTreeSet<SocketAddr>,
+    /// changes found in the last update that haven't been yielded yet
+    pending: VecDeque<Change<SocketAddr, S>>,
+}
+
+impl<R: MyResolve + 'static> WatchingResolver<R> {
+    /// Returns a `tower::discover::Discover` with a service for each of
+    /// `name`'s addresses (with `port`), made with `make_service`
+    ///
+    /// ```
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// # use reqwest_resolve::watch::WatchingResolver;
+    /// # use std::net::SocketAddr;
+    /// # use std::time::Duration;
+    /// # use tower::discover::{Change, Discover};
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "backend.example.com" => ["192.0.2.1"],
+    /// };
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .enable_all()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let watcher = WatchingResolver::new(HOSTS, Duration::from_secs(30));
+    /// // A real service would connect to `addr`.
+    /// let mut discover = watcher.discover(
+    ///     "backend.example.com".parse().unwrap(),
+    ///     8080,
+    ///     |addr: SocketAddr| addr,
+    /// );
+    /// let change = futures::future::poll_fn(|cx| {
+    ///     std::pin::Pin::new(&mut discover).poll_discover(cx)
+    /// })
+    /// .await;
+    /// let expected: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+    /// assert!(matches!(
+    ///     change,
+    ///     Some(Ok(Change::Insert(addr, _))) if addr == expected
+    /// ));
+    /// # });
+    /// ```
+    pub fn discover<S, F>(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+        make_service: F,
+    ) -> DnsDiscover<S>
+    where
+        F: Fn(SocketAddr) -> S + Send + 'static,
+    {
+        DnsDiscover {
+            updates: Box::pin(self.watch_resolve(name)),
+            port,
+            make_service: Box::new(make_service),
+            current: BTreeSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<S> DnsDiscover<S> {
+    /// Queues the changes that take `current` to `addrs`: removals first,
+    /// so a balancer never has more services than there are addresses.
+    fn update(&mut self, addrs: Addrs) {
+        let port = self.port;
+        let new: BTreeSet<SocketAddr> = addrs
+            .map(|mut addr| {
+                addr.set_port(port);
+                addr
+            })
+            .collect();
+        for addr in self.current.difference(&new) {
+            self.pending.push_back(Change::Remove(*addr));
+        }
+        for addr in new.difference(&self.current) {
+            let service = (self.make_service)(*addr);
+            self.pending.push_back(Change::Insert(*addr, service));
+        }
+        self.current = new;
+    }
+}
+
+// None of the fields are pinned.
+impl<S> Unpin for DnsDiscover<S> {}
+
+impl<S> Stream for DnsDiscover<S> {
+    type Item = Result<Change<SocketAddr, S>, Infallible>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(change)));
+            }
+            match self.updates.as_mut().poll_next(cx) {
+                Poll::Ready(Some(addrs)) => self.update(addrs),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! DNS-based Service Discovery (RFC 6763)
+//!
+//! This implements browsing over unicast DNS: the PTR, SRV, and TXT records
+//! are looked up with an ordinary `TokioAsyncResolver`.  That works for any
+//! domain that publishes DNS-SD records (many LANs do this under their local
+//! search domain), but not for "local.", which is only served by multicast DNS
+//! and which trust-dns 0.22 can't query.
+
+use crate::deterministic::is_deterministic;
+use crate::deterministic::stable_order;
+use crate::svcb::rewritten_target;
+use crate::svcb::TargetRewrite;
+use futures::future::join_all;
+use reqwest::Url;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::Name;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// One instance of a service found by [`browse`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ServiceInstance {
+    /// the instance's name as a user would see it (e.g., "Office Printer")
+    pub instance: String,
+    /// the instance's full DNS name
+    pub fullname: Name,
+    /// the host providing the service, from its SRV record
+    pub target: String,
+    /// the port the service is on, from its SRV record
+    pub port: u16,
+    /// the key/value pairs from the instance's TXT record.  Keys are
+    /// lowercased; keys that appear without "=" have no value.
+    pub txt: BTreeMap<String, Option<String>>,
+    /// addresses for `target`, with `port` filled in
+    pub addrs: Vec<SocketAddr>,
+}
+
+impl ServiceInstance {
+    /// Returns a URL for this instance using the given scheme (normally
+    /// "http" or "https"), including the "path" from the TXT record if there
+    /// is one (as DNS-SD's "_http._tcp" convention specifies).
+    ///
+    /// Requests to this URL will go through the client's resolver like any
+    /// other.  To make sure they reach exactly the addresses that were found
+    /// here, configure the client with
+    /// `ClientBuilder::resolve_to_addrs(instance.host(), &instance.addrs)`.
+    pub fn url(&self, scheme: &str) -> Option<Url> {
+        let path = match self.txt.get("path") {
+            Some(Some(path)) if path.starts_with('/') => path.as_str(),
+            _ => "/",
+        };
+        Url::parse(&format!(
+            "{}://{}:{}{}",
+            scheme,
+            self.host(),
+            self.port,
+            path
+        ))
+        .ok()
+    }
+
+    /// Returns `target` without the trailing dot, as it would appear in a URL.
+    pub fn host(&self) -> &str {
+        self.target.trim_end_matches('.')
+    }
+}
+
+/// Finds the instances of `service` (e.g., "_http._tcp") in `domain`
+///
+/// Instances whose SRV record can't be found are skipped: PTR records for
+/// services that have gone away commonly outlive the services themselves.  If
+/// an instance's target has no addresses, it's still returned (with no
+/// `addrs`), since the caller may be able to reach it some other way.
+///
+/// ```no_run
+/// # async fn example() {
+/// # use reqwest_resolve::dns_sd;
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
+/// let instances = dns_sd::browse(&resolver, "_http._tcp", "example.com")
+///     .await
+///     .unwrap();
+/// for instance in instances {
+///     let client = reqwest::ClientBuilder::new()
+///         .resolve_to_addrs(instance.host(), &instance.addrs)
+///         .build()
+///         .unwrap();
+///     let _response = client.get(instance.url("http").unwrap()).send().await;
+/// }
+/// # }
+/// ```
+pub async fn browse(
+    resolver: &TokioAsyncResolver,
+    service: &str,
+    domain: &str,
+) -> Result<Vec<ServiceInstance>, ResolveError> {
+    browse_instances(resolver, service, domain, None).await
+}
+
+/// Like [`browse`], but looks up the addresses of the name `rewrite` maps
+/// each instance's target to
+pub async fn browse_with_rewrite(
+    resolver: &TokioAsyncResolver,
+    service: &str,
+    domain: &str,
+    rewrite: &dyn TargetRewrite,
+) -> Result<Vec<ServiceInstance>, ResolveError> {
+    browse_instances(resolver, service, domain, Some(rewrite)).await
+}
+
+async fn browse_instances(
+    resolver: &TokioAsyncResolver,
+    service: &str,
+    domain: &str,
+    rewrite: Option<&dyn TargetRewrite>,
+) -> Result<Vec<ServiceInstance>, ResolveError> {
+    let browse_name = format!("{}.{}.", service, domain.trim_end_matches('.'));
+    let instances = lookup_ptr(resolver, &browse_name).await?;
+    let resolved = join_all(
+        instances
+            .into_iter()
+            .map(|name| resolve_instance(resolver, name, rewrite)),
+    )
+    .await;
+    Ok(resolved.into_iter().filter_map(Result::ok).collect())
+}
+
+/// Lists the service types (e.g., "_http._tcp") advertised in `domain`, using
+/// the "_services._dns-sd._udp" meta-query (RFC 6763 section 9)
+pub async fn service_types(
+    resolver: &TokioAsyncResolver,
+    domain: &str,
+) -> Result<Vec<String>, ResolveError> {
+    let domain = domain.trim_end_matches('.');
+    let names =
+        lookup_ptr(resolver, &format!("_services._dns-sd._udp.{}.", domain))
+            .await?;
+    Ok(names
+        .iter()
+        .filter(|name| name.num_labels() >= 2)
+        .map(|name| {
+            name.iter()
+                .take(2)
+                .map(|label| String::from_utf8_lossy(label).into_owned())
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect())
+}
+
+/// Looks up the SRV, TXT, and address records for one service instance,
+/// identified by its full name (as returned in the PTR records for a service)
+pub async fn resolve(
+    resolver: &TokioAsyncResolver,
+    fullname: Name,
+) -> Result<ServiceInstance, ResolveError> {
+    resolve_instance(resolver, fullname, None).await
+}
+
+/// Like [`resolve`], but looks up the addresses of the name `rewrite` maps
+/// the instance's target to
+///
+/// The instance's `target` is the rewritten name, since that's the host
+/// that was found and that URLs for it should name.
+pub async fn resolve_with_rewrite(
+    resolver: &TokioAsyncResolver,
+    fullname: Name,
+    rewrite: &dyn TargetRewrite,
+) -> Result<ServiceInstance, ResolveError> {
+    resolve_instance(resolver, fullname, Some(rewrite)).await
+}
+
+async fn resolve_instance(
+    resolver: &TokioAsyncResolver,
+    fullname: Name,
+    rewrite: Option<&dyn TargetRewrite>,
+) -> Result<ServiceInstance, ResolveError> {
+    let (srv, txt) = futures::join!(
+        resolver.srv_lookup(fullname.clone()),
+        resolver.txt_lookup(fullname.clone())
+    );
+
+    let srv = srv?;
+    let srv = srv
+        .iter()
+        .min_by(|a, b| {
+            // Among records with the same priority, the first one wins,
+            // unless that has to be reproducible.
+            let order = a.priority().cmp(&b.priority());
+            if is_deterministic() {
+                order.then_with(|| a.target().cmp(b.target()))
+            } else {
+                order
+            }
+        })
+        .ok_or_else(|| ResolveError::from("SRV lookup returned no records"))?;
+
+    // TXT records are required by RFC 6763, but plenty of publishers leave
+    // them out, and none of what's in them is essential.
+    let mut txt_map = BTreeMap::new();
+    if let Ok(txt) = txt {
+        for record in txt.iter() {
+            for entry in record.txt_data() {
+                let entry = String::from_utf8_lossy(entry);
+                let (key, value) = match entry.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_owned())),
+                    None => (&*entry, None),
+                };
+                // Empty keys are meaningless, and only the first occurrence
+                // of a key counts (RFC 6763 section 6.4).
+                if !key.is_empty() {
+                    txt_map.entry(key.to_ascii_lowercase()).or_insert(value);
+                }
+            }
+        }
+    }
+
+    let port = srv.port();
+    let target = rewritten_target(
+        rewrite,
+        &fullname.to_ascii(),
+        &srv.target().to_ascii(),
+    );
+    let mut addrs = match resolver.lookup_ip(target.as_str()).await {
+        Ok(ips) => ips.iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+        Err(_) => Vec::new(),
+    };
+    stable_order(&mut addrs);
+
+    let instance = fullname
+        .iter()
+        .next()
+        .map(|label| String::from_utf8_lossy(label).into_owned())
+        .unwrap_or_default();
+
+    Ok(ServiceInstance {
+        instance,
+        fullname,
+        target,
+        port,
+        txt: txt_map,
+        addrs,
+    })
+}
+
+async fn lookup_ptr(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<Name>, ResolveError> {
+    match resolver.lookup(name, RecordType::PTR).await {
+        Ok(lookup) => Ok(lookup
+            .iter()
+            .filter_map(|rdata| match rdata {
+                RData::PTR(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()),
+        Err(error) => match error.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(Vec::new()),
+            _ => Err(error),
+        },
+    }
+}
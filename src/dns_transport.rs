@@ -0,0 +1,459 @@
+//! Sending queries upstream some other way
+//!
+//! trust-dns knows how to reach a name server over UDP, TCP, TLS, and HTTPS,
+//! and nothing else.  Reaching one through an existing tunnel, a message
+//! queue, or a test double means replacing the whole resolver.  The
+//! [`DnsTransport`] trait is the one piece that's different in those cases:
+//! given a query and the server it's for, produce the response.
+//! [`transport_resolver`] builds a resolver that sends every query through
+//! one, and keeps everything else (caching, retries, search domains, CNAME
+//! handling) as trust-dns does it.
+//!
+//! [`StandardTransport`] is the usual UDP, TCP, DNS-over-TLS, and
+//! DNS-over-HTTPS, for transports that only want to handle some servers
+//! themselves.  With the `testserver` feature,
+//! [`InMemoryTransport`](crate::testserver::InMemoryTransport) answers from
+//! a [`TestZone`](crate::testserver::TestZone) without any sockets at all.
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::global::overridable;
+use crate::logging::debug;
+use crate::tasks::NamedTokioHandle;
+use crate::tasks::NamedTokioRuntime;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::name_server::ConnectionProvider;
+use trust_dns_resolver::name_server::GenericConnectionProvider;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::xfer::DnsRequest;
+use trust_dns_resolver::proto::xfer::DnsRequestOptions;
+use trust_dns_resolver::proto::xfer::DnsResponse;
+use trust_dns_resolver::proto::DnsHandle;
+use trust_dns_resolver::proto::Time;
+use trust_dns_resolver::proto::TokioTime;
+use trust_dns_resolver::AsyncResolver;
+use trust_dns_resolver::TokioConnection;
+
+/// What a [`DnsTransport`] returns for each query
+pub type TransportFuture = BoxFuture<'static, Result<Message, ResolveError>>;
+
+/// Gets queries to upstream servers and their responses back
+///
+/// `server` is one of the name servers from the resolver's configuration,
+/// and says where the query should go and (in `protocol`) how trust-dns
+/// would have sent it.  A transport is free to ignore either.  The query is
+/// complete, with its ID and any EDNS options already set, and the response
+/// should have the same ID.
+///
+/// The resolver gives up on a query after `ResolverOpts::timeout`, so a
+/// transport doesn't need its own timeout.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{DnsTransport, TransportFuture};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::NameServerConfig;
+/// # use trust_dns_resolver::error::ResolveError;
+/// # use trust_dns_resolver::proto::op::Message;
+/// # struct Tunnel;
+/// # impl Tunnel {
+/// #     async fn exchange(&self, _: Vec<u8>) -> std::io::Result<Vec<u8>> {
+/// #         unimplemented!()
+/// #     }
+/// # }
+/// /// Sends queries through an already-established tunnel
+/// struct TunnelTransport {
+///     tunnel: Arc<Tunnel>,
+/// }
+///
+/// impl DnsTransport for TunnelTransport {
+///     fn send(
+///         &self,
+///         _server: &NameServerConfig,
+///         query: Message,
+///     ) -> TransportFuture {
+///         let tunnel = Arc::clone(&self.tunnel);
+///         Box::pin(async move {
+///             let bytes = tunnel.exchange(query.to_vec()?).await?;
+///             Ok::<_, ResolveError>(Message::from_vec(&bytes)?)
+///         })
+///     }
+/// }
+/// ```
+pub trait DnsTransport: Send + Sync + 'static {
+    /// Sends `query` to `server` and returns its response.
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture;
+}
+
+impl<T: DnsTransport + ?Sized> DnsTransport for Arc<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        (**self).send(server, query)
+    }
+}
+
+impl<T: DnsTransport + ?Sized> DnsTransport for Box<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        (**self).send(server, query)
+    }
+}
+
+/// Sends queries the way trust-dns normally does, using each server's
+/// `protocol`
+///
+/// Connections are opened the first time a server is used and reused after
+/// that, until one fails.  The tasks that drive them are listed in
+/// [`tasks::running_tasks`](crate::tasks::running_tasks).
+pub struct StandardTransport {
+    provider: GenericConnectionProvider<NamedTokioRuntime>,
+    options: ResolverOpts,
+    connections: Arc<Mutex<Vec<(NameServerConfig, TokioConnection)>>>,
+}
+
+impl StandardTransport {
+    /// Makes connections with `options`, which should be the same options
+    /// the resolver was built with.
+    pub fn new(options: ResolverOpts) -> StandardTransport {
+        StandardTransport {
+            provider: GenericConnectionProvider::new(NamedTokioHandle),
+            options,
+            connections: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl DnsTransport for StandardTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let connections = Arc::clone(&self.connections);
+        let cached = connections
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(config, _)| config == server)
+            .map(|(_, connection)| connection.clone());
+        let connecting = match cached {
+            Some(connection) => futures::future::ok(connection).left_future(),
+            None => self
+                .provider
+                .new_connection(server, &self.options)
+                .right_future(),
+        };
+        let server = server.clone();
+
+        async move {
+            let mut connection = connecting.await?;
+            {
+                let mut connections = connections.lock().unwrap();
+                if !connections.iter().any(|(config, _)| *config == server) {
+                    connections.push((server.clone(), connection.clone()));
+                }
+            }
+
+            // trust-dns's UDP and TCP connections pick their own ID for
+            // each query, so put back the one the caller used.
+            let id = query.id();
+            let request = DnsRequest::new(query, DnsRequestOptions::default());
+            let result = match connection.send(request).next().await {
+                Some(result) => result,
+                None => Err(ResolveError::from(ResolveErrorKind::Message(
+                    "connection closed without a response",
+                ))),
+            };
+            if result.is_err() {
+                // The next query to this server gets a new connection.
+                connections
+                    .lock()
+                    .unwrap()
+                    .retain(|(config, _)| *config != server);
+            }
+            let mut response = result?.into_inner();
+            response.set_id(id);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// An `AsyncResolver` whose queries go through a [`DnsTransport`]
+pub type TransportAsyncResolver =
+    AsyncResolver<TransportConnection, TransportConnectionProvider>;
+
+/// Builds a resolver like `TokioAsyncResolver::tokio()` does, but sending
+/// every query through `transport`.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::dns_transport::StandardTransport;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let options = ResolverOpts::default();
+/// let resolver = transport_resolver(
+///     ResolverConfig::default(),
+///     options.clone(),
+///     StandardTransport::new(options),
+/// )
+/// .unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub fn transport_resolver<T: DnsTransport>(
+    config: ResolverConfig,
+    options: ResolverOpts,
+    transport: T,
+) -> Result<TransportAsyncResolver, ResolveError> {
+    AsyncResolver::new_with_conn(
+        config,
+        options,
+        TransportConnectionProvider::new(transport),
+    )
+}
+
+/// Makes connections that send their queries through a [`DnsTransport`]
+#[derive(Clone)]
+pub struct TransportConnectionProvider {
+    transport: Arc<dyn DnsTransport>,
+}
+
+impl TransportConnectionProvider {
+    pub fn new<T: DnsTransport>(transport: T) -> TransportConnectionProvider {
+        TransportConnectionProvider { transport: Arc::new(transport) }
+    }
+}
+
+impl ConnectionProvider for TransportConnectionProvider {
+    type Conn = TransportConnection;
+    type FutureConn =
+        futures::future::Ready<Result<TransportConnection, ResolveError>>;
+    type Time = TokioTime;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Self::FutureConn {
+        futures::future::ready(Ok(TransportConnection {
+            transport: Arc::clone(&self.transport),
+            server: Arc::new(config.clone()),
+            timeout: options.timeout,
+        }))
+    }
+}
+
+/// A connection to one upstream server, made by
+/// [`TransportConnectionProvider`]
+#[derive(Clone)]
+pub struct TransportConnection {
+    transport: Arc<dyn DnsTransport>,
+    server: Arc<NameServerConfig>,
+    timeout: Duration,
+}
+
+impl DnsHandle for TransportConnection {
+    type Response =
+        Pin<Box<dyn Stream<Item = Result<DnsResponse, ResolveError>> + Send>>;
+    type Error = ResolveError;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(
+        &mut self,
+        request: R,
+    ) -> Self::Response {
+        let (message, _) = request.into().into_parts();
+        let id = message.id();
+        let response = self.transport.send(&self.server, message);
+        let timeout = self.timeout;
+        let server = Arc::clone(&self.server);
+
+        futures::stream::once(async move {
+            let result = match TokioTime::timeout(timeout, response).await {
+                Ok(Ok(response)) if response.id() != id => {
+                    Err(ResolveError::from(ResolveErrorKind::Message(
+                        "response ID doesn't match the query",
+                    )))
+                }
+                Ok(result) => result,
+                Err(_) => Err(ResolveError::from(ResolveErrorKind::Timeout)),
+            };
+            if let Err(error) = &result {
+                debug!(
+                    "transport failed",
+                    upstream = server.socket_addr,
+                    protocol = server.protocol,
+                    error = error,
+                );
+            }
+            result.map(DnsResponse::from)
+        })
+        .boxed()
+    }
+}
+
+/// Resolves names with a [`TransportAsyncResolver`]
+///
+/// This is the same as `CustomDnsResolver` and `MyCustomDnsResolver`, for
+/// resolvers built with [`transport_resolver`].
+pub struct TransportDnsResolver {
+    resolver: Arc<TransportAsyncResolver>,
+}
+
+impl TransportDnsResolver {
+    pub fn new(resolver: TransportAsyncResolver) -> TransportDnsResolver {
+        TransportDnsResolver { resolver: Arc::new(resolver) }
+    }
+}
+
+impl reqwest::dns::Resolve for TransportDnsResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        async move {
+            overridable(name, |name| do_resolve(&resolver, name)).await
+        }
+        .boxed()
+    }
+}
+
+impl MyResolve for TransportDnsResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve(&self.resolver, name).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        do_resolve_detailed(&self.resolver, name).boxed()
+    }
+}
+
+#[cfg(all(test, feature = "testserver"))]
+mod tests {
+    use super::transport_resolver;
+    use super::DnsTransport;
+    use super::TransportDnsResolver;
+    use super::TransportFuture;
+    use crate::testserver::InMemoryTransport;
+    use crate::testserver::TestZone;
+    use crate::MyResolve;
+    use futures::future::FutureExt;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use trust_dns_resolver::config::LookupIpStrategy;
+    use trust_dns_resolver::config::NameServerConfig;
+    use trust_dns_resolver::config::Protocol;
+    use trust_dns_resolver::config::ResolverConfig;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::proto::op::Message;
+
+    /// Passes queries on to an `InMemoryTransport`, recording the server and
+    /// ID of each one and the ID of its response, and changing the response's
+    /// ID when told to
+    #[derive(Clone)]
+    struct Recording {
+        inner: InMemoryTransport,
+        sent: Arc<Mutex<Vec<(NameServerConfig, u16, u16)>>>,
+        mismatch: Arc<AtomicBool>,
+    }
+
+    impl DnsTransport for Recording {
+        fn send(
+            &self,
+            server: &NameServerConfig,
+            query: Message,
+        ) -> TransportFuture {
+            let server = server.clone();
+            let id = query.id();
+            let response = self.inner.send(&server, query);
+            let sent = Arc::clone(&self.sent);
+            let mismatch = self.mismatch.load(Ordering::SeqCst);
+            async move {
+                let mut response = response.await?;
+                if mismatch {
+                    response.set_id(id.wrapping_add(1));
+                }
+                sent.lock().unwrap().push((server, id, response.id()));
+                Ok(response)
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        let mut zone = TestZone::new();
+        zone.add_addr("api.test", "192.0.2.1".parse().unwrap());
+        let transport = Recording {
+            inner: InMemoryTransport::new(zone),
+            sent: Arc::default(),
+            mismatch: Arc::default(),
+        };
+        let mut config = ResolverConfig::new();
+        let server = "192.0.2.53:53".parse().unwrap();
+        config.add_name_server(NameServerConfig::new(server, Protocol::Udp));
+        let mut options = ResolverOpts::default();
+        options.cache_size = 0;
+        options.ip_strategy = LookupIpStrategy::Ipv4Only;
+        options.timeout = Duration::from_millis(100);
+        options.attempts = 1;
+        let resolver = TransportDnsResolver::new(
+            transport_resolver(config, options, transport.clone()).unwrap(),
+        );
+
+        let addrs = resolver.resolve_to_vec("api.test.").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+        {
+            let sent = transport.sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let (config, query_id, response_id) = &sent[0];
+            assert_eq!(config.socket_addr, server);
+            assert_eq!(config.protocol, Protocol::Udp);
+            assert_eq!(query_id, response_id);
+        }
+
+        // A response to some other query is rejected, not taken as the
+        // answer.
+        transport.mismatch.store(true, Ordering::SeqCst);
+        assert!(resolver.resolve_to_vec("api.test.").await.is_err());
+        let sent = transport.sent.lock().unwrap();
+        assert!(sent.len() > 1);
+        assert!(sent[1..].iter().all(|(_, query, response)| query != response));
+    }
+}
@@ -0,0 +1,764 @@
+//! Sending queries to DNSCrypt resolvers
+//!
+//! DNSCrypt (version 2) encrypts and authenticates queries to a resolver
+//! like DNS-over-TLS does, but without TLS or certificate authorities: each
+//! server's provider publishes short-lived certificates, signed with a key
+//! that's distributed out of band (usually in a DNS Stamp), and queries are
+//! encrypted to the key in the most recent one.  Some deployments use it
+//! everywhere, so [`DnscryptTransport`] speaks it to their resolvers, as a
+//! [`DnsTransport`] (see [`crate::dns_transport`]).  It can also send them
+//! through relays, as Anonymized DNSCrypt describes, so that no one server
+//! sees both who's asking and what they asked.
+//!
+//! Only the XChaCha20-Poly1305 construction is used; servers that publish
+//! only XSalsa20-Poly1305 certificates (which the specification allows, but
+//! virtually none do) can't be used.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::stamps::decode;
+use crate::stamps::parse_addr;
+use crate::stamps::stamp_error;
+use crate::stamps::Reader;
+use chacha20::cipher::consts::U10;
+use crypto_secretbox::aead::Aead;
+use crypto_secretbox::aead::KeyInit;
+use crypto_secretbox::XChaCha20Poly1305;
+use futures::future::FutureExt;
+use ring::agreement;
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+use ring::signature;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::NameServerConfigGroup;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::Query;
+use trust_dns_resolver::proto::rr::Name;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+
+/// The port DNSCrypt servers (and relays) use when a stamp doesn't give one
+pub const DEFAULT_DNSCRYPT_PORT: u16 = 443;
+
+/// How long to wait for a response through one relay before trying the next
+pub const RELAY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+/// What every packet sent through a relay starts with
+const ANON_MAGIC: &[u8; 12] = b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\0\0";
+/// The certificate version (es-version) for XChaCha20-Poly1305
+const XCHACHA20_POLY1305: u16 = 2;
+/// What every response starts with
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+/// Queries sent over UDP are padded to at least this long, so that
+/// responses to them can't be much bigger.
+const MIN_UDP_QUERY: usize = 256;
+/// The size of the largest UDP response expected
+const MAX_UDP_RESPONSE: usize = 4096;
+const TAG_LEN: usize = 16;
+const HALF_NONCE_LEN: usize = 12;
+
+/// A DNSCrypt server and the provider whose key signs its certificates
+///
+/// With relays, queries (and the requests for the server's certificates)
+/// go through a relay instead of straight to the server, so the server
+/// never sees the client's address, and the relay can't read the queries.
+/// Each query goes to the relay that last worked, or if that fails or takes
+/// longer than [`RELAY_TIMEOUT`], the next one.  The relays should be run by
+/// someone other than the server's operator, or there's no point.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnscryptServer {
+    pub addr: SocketAddr,
+    /// the name the server's certificates are published under (like
+    /// "2.dnscrypt-cert.example.com")
+    pub provider_name: String,
+    /// the provider's Ed25519 public key
+    pub provider_key: [u8; 32],
+    /// relays to reach the server through, in order of preference (if
+    /// empty, queries go straight to the server)
+    pub relays: Vec<SocketAddr>,
+}
+
+impl DnscryptServer {
+    pub fn new(
+        addr: SocketAddr,
+        provider_name: &str,
+        provider_key: [u8; 32],
+    ) -> DnscryptServer {
+        DnscryptServer {
+            addr,
+            provider_name: provider_name.to_owned(),
+            provider_key,
+            relays: Vec::new(),
+        }
+    }
+
+    /// Reaches the server through the relay at `relay`, after any relays
+    /// added before.
+    pub fn with_relay(mut self, relay: SocketAddr) -> DnscryptServer {
+        self.relays.push(relay);
+        self
+    }
+
+    /// Reads a DNSCrypt server's DNS Stamp (see [`crate::stamps`]), failing
+    /// with [`ResolveError::InvalidConfig`] if it's malformed or for some
+    /// other protocol.
+    pub fn from_stamp(stamp: &str) -> Result<DnscryptServer, ResolveError> {
+        let invalid = |message: &str| stamp_error(stamp, message);
+        let (kind, bytes) = decode(stamp)?;
+        if kind != 0x01 {
+            return Err(invalid("not a DNSCrypt stamp"));
+        }
+        let mut reader = Reader(&bytes);
+        reader.props().ok_or_else(|| invalid("truncated"))?;
+        let addr = reader.string().ok_or_else(|| invalid("truncated"))?;
+        let (ip, port) = parse_addr(addr)
+            .and_then(|(ip, port)| Some((ip?, port)))
+            .ok_or_else(|| invalid(&format!("bad address {:?}", addr)))?;
+        let provider_key = reader
+            .value()
+            .ok_or_else(|| invalid("truncated"))?
+            .try_into()
+            .map_err(|_| invalid("bad provider key"))?;
+        let provider_name =
+            reader.string().ok_or_else(|| invalid("truncated"))?;
+        Ok(DnscryptServer::new(
+            SocketAddr::new(ip, port.unwrap_or(DEFAULT_DNSCRYPT_PORT)),
+            provider_name.trim_end_matches('.'),
+            provider_key,
+        ))
+    }
+}
+
+/// Each server's current certificate
+type Certificates = Mutex<Vec<(SocketAddr, Certificate)>>;
+
+/// Reads an anonymized DNSCrypt relay's DNS Stamp, returning its address,
+/// for [`DnscryptServer::with_relay`].
+pub fn relay_from_stamp(stamp: &str) -> Result<SocketAddr, ResolveError> {
+    let invalid = |message: &str| stamp_error(stamp, message);
+    let (kind, bytes) = decode(stamp)?;
+    if kind != 0x81 {
+        return Err(invalid("not a DNSCrypt relay stamp"));
+    }
+    let addr = Reader(&bytes).string().ok_or_else(|| invalid("truncated"))?;
+    let (ip, port) = parse_addr(addr)
+        .and_then(|(ip, port)| Some((ip?, port)))
+        .ok_or_else(|| invalid(&format!("bad address {:?}", addr)))?;
+    Ok(SocketAddr::new(ip, port.unwrap_or(DEFAULT_DNSCRYPT_PORT)))
+}
+
+/// A resolver's certificate, after its signature has been checked
+#[derive(Clone)]
+struct Certificate {
+    resolver_key: [u8; 32],
+    client_magic: [u8; 8],
+    serial: u32,
+    not_after: u32,
+}
+
+/// Sends queries to DNSCrypt servers
+///
+/// Each server's certificates are fetched (with a plain DNS query for their
+/// provider name) the first time it's used, and again once the one in use
+/// has expired or stops working.  Each query is encrypted with a new key, so
+/// the server can't tell which queries came from the same client.  Queries
+/// go over UDP, and over TCP when a response is truncated.
+///
+/// Queries for name servers that aren't DNSCrypt servers given here fail, so
+/// the resolver should be built with [`DnscryptTransport::resolver_config`].
+///
+/// ```no_run
+/// # use reqwest_resolve::dns_transport::{
+/// #     transport_resolver, TransportDnsResolver,
+/// # };
+/// # use reqwest_resolve::dnscrypt::{DnscryptServer, DnscryptTransport};
+/// # use trust_dns_resolver::config::ResolverOpts;
+/// let server = DnscryptServer::from_stamp(concat!(
+///     "sdns://AQMAAAAAAAAAETk0LjE0MC4xNC4xNDo1NDQzINErR_JS3PLCu_iZEIbq95",
+///     "zkSV2LFsigxDIuUso_OQhzIjIuZG5zY3J5cHQuZGVmYXVsdC5uczEuYWRndWFyZC5j",
+///     "b20",
+/// ))
+/// .unwrap();
+/// let transport = DnscryptTransport::new(vec![server]);
+/// let config = transport.resolver_config();
+/// let resolver =
+///     transport_resolver(config, ResolverOpts::default(), transport)
+///         .unwrap();
+/// let _my_resolver = TransportDnsResolver::new(resolver);
+/// ```
+pub struct DnscryptTransport {
+    servers: Vec<DnscryptServer>,
+    certificates: Arc<Certificates>,
+    /// for each server with relays, the relay that last worked
+    preferred_relays: Arc<Mutex<Vec<(SocketAddr, usize)>>>,
+    rng: SystemRandom,
+}
+
+impl DnscryptTransport {
+    pub fn new(servers: Vec<DnscryptServer>) -> DnscryptTransport {
+        DnscryptTransport {
+            servers,
+            certificates: Arc::new(Mutex::new(Vec::new())),
+            preferred_relays: Arc::new(Mutex::new(Vec::new())),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Returns a resolver configuration with a name server for each of the
+    /// servers given.
+    pub fn resolver_config(&self) -> ResolverConfig {
+        let name_servers: Vec<NameServerConfig> = self
+            .servers
+            .iter()
+            .map(|server| NameServerConfig::new(server.addr, Protocol::Udp))
+            .collect();
+        ResolverConfig::from_parts(
+            None,
+            Vec::new(),
+            NameServerConfigGroup::from(name_servers),
+        )
+    }
+}
+
+impl DnsTransport for DnscryptTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let server = self
+            .servers
+            .iter()
+            .find(|dnscrypt| dnscrypt.addr == server.socket_addr)
+            .cloned()
+            .ok_or_else(|| {
+                trust_dns_resolver::error::ResolveError::from(format!(
+                    "{} is not a DNSCrypt server",
+                    server.socket_addr
+                ))
+            });
+        let certificates = Arc::clone(&self.certificates);
+        let preferred = Arc::clone(&self.preferred_relays);
+        let rng = self.rng.clone();
+
+        async move {
+            let server = server?;
+            let id = query.id();
+            let query = query.to_vec()?;
+            let response = if server.relays.is_empty() {
+                let route = Route::direct(server.addr);
+                attempt(&server, &route, &certificates, &query, &rng).await?
+            } else {
+                relayed(&server, &preferred, &certificates, &query, &rng)
+                    .await?
+            };
+            let mut response = Message::from_vec(&response)?;
+            response.set_id(id);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// Where to send a server's packets, and what goes in front of them
+struct Route {
+    addr: SocketAddr,
+    header: Vec<u8>,
+}
+
+impl Route {
+    fn direct(server: SocketAddr) -> Route {
+        Route { addr: server, header: Vec::new() }
+    }
+
+    /// Sends packets for `server` through the relay at `relay`, which reads
+    /// where to send them from the header.
+    fn relayed(relay: SocketAddr, server: SocketAddr) -> Route {
+        let ip = match server.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let mut header = ANON_MAGIC.to_vec();
+        header.extend_from_slice(&ip.octets());
+        header.extend_from_slice(&server.port().to_be_bytes());
+        Route { addr: relay, header }
+    }
+}
+
+/// Sends `query` to `server` through each of its relays in turn, starting
+/// with the one that last worked, until one gets a response.
+async fn relayed(
+    server: &DnscryptServer,
+    preferred: &Mutex<Vec<(SocketAddr, usize)>>,
+    certificates: &Certificates,
+    query: &[u8],
+    rng: &SystemRandom,
+) -> Result<Vec<u8>, trust_dns_resolver::error::ResolveError> {
+    let first = preferred
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(addr, _)| *addr == server.addr)
+        .map_or(0, |(_, index)| *index);
+    let mut last_error = None;
+    for n in 0..server.relays.len() {
+        let index = (first + n) % server.relays.len();
+        let relay = server.relays[index];
+        let route = Route::relayed(relay, server.addr);
+        let result = tokio::time::timeout(
+            RELAY_TIMEOUT,
+            attempt(server, &route, certificates, query, rng),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(trust_dns_resolver::error::ResolveError::from(format!(
+                "DNSCrypt relay {} timed out",
+                relay
+            )))
+        });
+        match result {
+            Ok(response) => {
+                let mut preferred = preferred.lock().unwrap();
+                preferred.retain(|(addr, _)| *addr != server.addr);
+                preferred.push((server.addr, index));
+                return Ok(response);
+            }
+            Err(error) => {
+                debug!(
+                    "DNSCrypt relay failed",
+                    server = server.addr,
+                    relay = relay,
+                    error = error,
+                );
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error.expect("server with no relays"))
+}
+
+/// Sends `query` to `server` by `route`, fetching the server's certificate
+/// first if there isn't a current one.
+async fn attempt(
+    server: &DnscryptServer,
+    route: &Route,
+    certificates: &Certificates,
+    query: &[u8],
+    rng: &SystemRandom,
+) -> Result<Vec<u8>, trust_dns_resolver::error::ResolveError> {
+    let cached = certificates
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(addr, certificate)| {
+            *addr == server.addr && certificate.not_after > now()
+        })
+        .map(|(_, certificate)| certificate.clone());
+    let certificate = match cached {
+        Some(certificate) => certificate,
+        None => {
+            let certificate = fetch_certificate(server, route, rng).await?;
+            let mut certificates = certificates.lock().unwrap();
+            certificates.retain(|(addr, _)| *addr != server.addr);
+            certificates.push((server.addr, certificate.clone()));
+            certificate
+        }
+    };
+
+    let result = exchange(server, route, &certificate, query, rng).await;
+    if result.is_err() {
+        // Maybe the server has moved on to a new certificate.
+        certificates.lock().unwrap().retain(|(addr, _)| *addr != server.addr);
+    }
+    Ok(result?)
+}
+
+/// Sends `query` to `server` encrypted for `certificate`, over UDP and then
+/// over TCP if the response is truncated, and returns the decrypted
+/// response.
+async fn exchange(
+    server: &DnscryptServer,
+    route: &Route,
+    certificate: &Certificate,
+    query: &[u8],
+    rng: &SystemRandom,
+) -> Result<Vec<u8>, io::Error> {
+    let session = Session::new(certificate, rng)?;
+    let packet = session.seal(query, MIN_UDP_QUERY);
+    let response =
+        udp_exchange(route, &packet, |packet| session.open(packet)).await?;
+    // The TC flag is in the third byte of the header.
+    if response.get(2).is_some_and(|flags| flags & 0x02 == 0) {
+        return Ok(response);
+    }
+    debug!(
+        "DNSCrypt response truncated; retrying over TCP",
+        server = server.addr
+    );
+    let session = Session::new(certificate, rng)?;
+    let packet = session.seal(query, 0);
+    let response = tcp_exchange(route, &packet).await?;
+    session.open(&response).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "bad DNSCrypt response")
+    })
+}
+
+/// Fetches `server`'s certificates by `route` and returns the newest one
+/// that's valid.
+async fn fetch_certificate(
+    server: &DnscryptServer,
+    route: &Route,
+    rng: &SystemRandom,
+) -> Result<Certificate, trust_dns_resolver::error::ResolveError> {
+    let mut id = [0; 2];
+    rng.fill(&mut id).map_err(|_| random_failed())?;
+    let id = u16::from_be_bytes(id);
+    let name = Name::from_ascii(&server.provider_name)?;
+    let mut query = Message::new();
+    query
+        .set_id(id)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, RecordType::TXT));
+    let query = query.to_vec()?;
+    let accept = |packet: &[u8]| {
+        let response = Message::from_vec(packet).ok()?;
+        (response.id() == id).then_some(response)
+    };
+    let mut response = udp_exchange(route, &query, accept).await?;
+    if response.truncated() {
+        let packet = tcp_exchange(route, &query).await?;
+        response = Message::from_vec(&packet)?;
+    }
+
+    let now = now();
+    let certificate = response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::TXT(txt)) => Some(txt.txt_data().concat()),
+            _ => None,
+        })
+        .filter_map(|txt| parse_certificate(&txt, &server.provider_key, now))
+        .max_by_key(|certificate| certificate.serial)
+        .ok_or_else(|| {
+            trust_dns_resolver::error::ResolveError::from(format!(
+                "DNSCrypt server {} has no valid certificate for {}",
+                server.addr, server.provider_name
+            ))
+        })?;
+    debug!(
+        "fetched DNSCrypt certificate",
+        server = server.addr,
+        serial = certificate.serial,
+    );
+    Ok(certificate)
+}
+
+/// Parses a certificate and checks its signature, version, and dates.
+fn parse_certificate(
+    cert: &[u8],
+    provider_key: &[u8; 32],
+    now: u32,
+) -> Option<Certificate> {
+    let be16 =
+        |at: usize| u16::from_be_bytes(cert[at..at + 2].try_into().unwrap());
+    let be32 =
+        |at: usize| u32::from_be_bytes(cert[at..at + 4].try_into().unwrap());
+
+    if cert.len() < 124 || &cert[..4] != CERT_MAGIC {
+        return None;
+    }
+    if be16(4) != XCHACHA20_POLY1305 {
+        return None;
+    }
+    // The signature covers everything after it, extensions included.
+    signature::UnparsedPublicKey::new(&signature::ED25519, provider_key)
+        .verify(&cert[72..], &cert[8..72])
+        .ok()?;
+    let not_before = be32(116);
+    let not_after = be32(120);
+    if now < not_before || now >= not_after {
+        return None;
+    }
+    Some(Certificate {
+        resolver_key: cert[72..104].try_into().unwrap(),
+        client_magic: cert[104..112].try_into().unwrap(),
+        serial: be32(112),
+        not_after,
+    })
+}
+
+/// One query's keys
+struct Session {
+    client_magic: [u8; 8],
+    public_key: [u8; 32],
+    shared_key: [u8; 32],
+    nonce: [u8; HALF_NONCE_LEN],
+}
+
+impl Session {
+    /// Makes a new key pair and works out the key shared with the server.
+    fn new(
+        certificate: &Certificate,
+        rng: &SystemRandom,
+    ) -> Result<Session, io::Error> {
+        let private_key =
+            agreement::EphemeralPrivateKey::generate(&agreement::X25519, rng)
+                .map_err(|_| random_failed())?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| random_failed())?
+            .as_ref()
+            .try_into()
+            .unwrap();
+        let resolver_key = agreement::UnparsedPublicKey::new(
+            &agreement::X25519,
+            certificate.resolver_key,
+        );
+        let shared_key = agreement::agree_ephemeral(
+            private_key,
+            &resolver_key,
+            io::Error::new(io::ErrorKind::InvalidData, "bad resolver key"),
+            |secret| Ok(shared_key(secret.try_into().unwrap())),
+        )?;
+        let mut nonce = [0; HALF_NONCE_LEN];
+        rng.fill(&mut nonce).map_err(|_| random_failed())?;
+        Ok(Session {
+            client_magic: certificate.client_magic,
+            public_key,
+            shared_key,
+            nonce,
+        })
+    }
+
+    /// Returns `query` padded to a multiple of 64 bytes and at least
+    /// `min_len`, encrypted, and ready to send.
+    fn seal(&self, query: &[u8], min_len: usize) -> Vec<u8> {
+        let padded_len = ((query.len() + 1).div_ceil(64) * 64).max(min_len);
+        let mut message = query.to_vec();
+        message.push(0x80);
+        message.resize(padded_len, 0);
+
+        let mut nonce = [0; 24];
+        nonce[..HALF_NONCE_LEN].copy_from_slice(&self.nonce);
+        let mut packet = Vec::with_capacity(52 + TAG_LEN + padded_len);
+        packet.extend_from_slice(&self.client_magic);
+        packet.extend_from_slice(&self.public_key);
+        packet.extend_from_slice(&self.nonce);
+        packet.extend_from_slice(&box_seal(&self.shared_key, &nonce, message));
+        packet
+    }
+
+    /// Returns the response in `packet`, or `None` if it isn't a response
+    /// to this session's query.
+    fn open(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let rest = packet.strip_prefix(RESOLVER_MAGIC)?;
+        if rest.len() < 24 + TAG_LEN || rest[..HALF_NONCE_LEN] != self.nonce {
+            return None;
+        }
+        let nonce: [u8; 24] = rest[..24].try_into().unwrap();
+        let mut message = box_open(&self.shared_key, &nonce, &rest[24..])?;
+        let end = message.iter().rposition(|byte| *byte != 0)?;
+        if message[end] != 0x80 {
+            return None;
+        }
+        message.truncate(end);
+        Some(message)
+    }
+}
+
+/// Sends `packet` by `route` and returns the first response that `accept`
+/// takes (others are assumed to be spoofed or late, and are ignored).
+async fn udp_exchange<T>(
+    route: &Route,
+    packet: &[u8],
+    accept: impl Fn(&[u8]) -> Option<T>,
+) -> Result<T, io::Error> {
+    let local: IpAddr = match route.addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.connect(route.addr).await?;
+    socket.send(&[&route.header[..], packet].concat()).await?;
+    let mut buffer = vec![0; MAX_UDP_RESPONSE];
+    loop {
+        let len = socket.recv(&mut buffer).await?;
+        if let Some(response) = accept(&buffer[..len]) {
+            return Ok(response);
+        }
+    }
+}
+
+/// Sends `packet` by `route` over TCP and returns the response.
+async fn tcp_exchange(
+    route: &Route,
+    packet: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let mut stream = TcpStream::connect(route.addr).await?;
+    let len = u16::try_from(route.header.len() + packet.len())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(&route.header);
+    framed.extend_from_slice(packet);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; usize::from(len)];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| u32::try_from(since.as_secs()).unwrap_or(u32::MAX))
+}
+
+fn random_failed() -> io::Error {
+    io::Error::other("couldn't generate random numbers")
+}
+
+// DNSCrypt encrypts with XChaCha20-Poly1305 as NaCl's secretbox does it,
+// which isn't the same as the IETF's AEAD construction, and derives the key
+// it shares with the server from the X25519 secret with HChaCha20.
+
+/// Returns the key shared with the server, from the X25519 `secret`.
+fn shared_key(secret: &[u8; 32]) -> [u8; 32] {
+    hchacha20(secret, &[0; 16])
+}
+
+fn hchacha20(key: &[u8; 32], input: &[u8; 16]) -> [u8; 32] {
+    chacha20::hchacha::<U10>(key.into(), input.into()).into()
+}
+
+/// Encrypts `message`, returning the tag and then the ciphertext.
+fn box_seal(key: &[u8; 32], nonce: &[u8; 24], message: Vec<u8>) -> Vec<u8> {
+    XChaCha20Poly1305::new(key.into())
+        .encrypt(nonce.into(), message.as_slice())
+        .expect("secretbox messages have no length limit to exceed")
+}
+
+/// Checks and decrypts a tag and ciphertext from [`box_seal`].
+fn box_open(
+    key: &[u8; 32],
+    nonce: &[u8; 24],
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    XChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), sealed).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::box_open;
+    use super::box_seal;
+    use super::hchacha20;
+
+    fn hex(encoded: &str) -> Vec<u8> {
+        let digits: Vec<u8> = encoded
+            .bytes()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+        digits
+            .chunks(2)
+            .map(|pair| {
+                u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    const KEY: &str =
+        "1b27556473e985d462cd51197a9a46c76009549eac6474f206c4ee0844f68389";
+    const NONCE: &str = "69696ee955b62b73cd62bda875fc73d68219e0036b7a0b37";
+
+    /// libsodium's crypto_secretbox_xchacha20poly1305 output for these
+    const PLAINTEXT: &str = "
+        be075fc53c81f2d5cf141316ebeb0c7b5228c52a4c62cbd44b66849b64244ffc
+        e5ecbaaf33bd751a1ac728d45e6c61296cdc3c01233561f41db66cce314adb31
+        0e3be8250c46f06dceea3a7fa1348057e2f6556ad6b1318a024a838f21af1fde
+        048977eb48f59ffd4924ca1c60902e52f0a089bc76897040e082f93776384864
+        5e0705";
+    const SEALED: &str = "
+        0c61fcffbc3fc8d3aa7464b91ab35374bf8af3198585e55d9cb07edcd1e5a695
+        26547fbd0f2c642e9ee96e19462031f1032f1cd862bb952900103c06ac16344d
+        7f9c9df0feaaf5a733dea7ea2df70a619936fcc5501de75c5d112e8abd7573c4
+        61ada29ec016d131aa557804320011ff6d94092581ceea1bad3cf0d651938802
+        ca867cd52bbe50c2da1161cb09514407609920";
+
+    fn key() -> [u8; 32] {
+        hex(KEY).try_into().unwrap()
+    }
+
+    fn nonce() -> [u8; 24] {
+        hex(NONCE).try_into().unwrap()
+    }
+
+    #[test]
+    fn seal_matches_libsodium() {
+        assert_eq!(box_seal(&key(), &nonce(), hex(PLAINTEXT)), hex(SEALED));
+    }
+
+    #[test]
+    fn open_matches_libsodium() {
+        let opened = box_open(&key(), &nonce(), &hex(SEALED)).unwrap();
+        assert_eq!(opened, hex(PLAINTEXT));
+    }
+
+    #[test]
+    fn seal_and_open() {
+        for len in [0, 1, 63, 64, 65, 256, 1000] {
+            let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = box_seal(&key(), &nonce(), message.clone());
+            assert_eq!(sealed.len(), 16 + len);
+            assert_eq!(box_open(&key(), &nonce(), &sealed), Some(message));
+        }
+    }
+
+    #[test]
+    fn open_rejects_tampering() {
+        let sealed = hex(SEALED);
+        for i in [0, 15, 16, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert_eq!(box_open(&key(), &nonce(), &tampered), None);
+        }
+        let mut other_nonce = nonce();
+        other_nonce[23] ^= 1;
+        assert_eq!(box_open(&key(), &other_nonce, &sealed), None);
+        assert_eq!(box_open(&key(), &nonce(), &sealed[..15]), None);
+    }
+
+    /// The HChaCha20 test vector from draft-irtf-cfrg-xchacha, section 2.2.1
+    #[test]
+    fn hchacha20_vector() {
+        let key: [u8; 32] = hex(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .try_into()
+        .unwrap();
+        let input: [u8; 16] =
+            hex("000000090000004a0000000031415927").try_into().unwrap();
+        let expected = hex(
+            "82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc",
+        );
+        assert_eq!(hchacha20(&key, &input).to_vec(), expected);
+    }
+}
@@ -0,0 +1,492 @@
+//! Logging upstream queries and responses as dnstap
+//!
+//! dnstap is the format DNS servers (BIND, Unbound, Knot, CoreDNS, and
+//! others) use to log the messages they send and receive: protocol buffers,
+//! one per message, carried in Frame Streams over a Unix socket to a
+//! collector like `dnstap` or `fstrm_capture`.  [`DnstapTransport`] logs
+//! this crate's upstream traffic the same way, so a pipeline that already
+//! watches the DNS servers can watch the clients too.
+//!
+//! Each query is logged as a `STUB_QUERY` message and each response as a
+//! `STUB_RESPONSE`, with the server's address and port and the protocol
+//! used.  The messages are logged as trust-dns built and parsed them, which
+//! can differ from what went over the wire in small ways (like the order of
+//! EDNS options).  The local address isn't known, so it's left out.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::logging::debug;
+use crate::tasks::TaskSet;
+use futures::channel::mpsc;
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::proto::op::Message;
+
+/// The Frame Streams content type for dnstap
+pub const DNSTAP_CONTENT_TYPE: &str = "protobuf:dnstap.Dnstap";
+
+/// How many frames can wait for the collector before new ones are dropped
+const FRAME_BUFFER: usize = 1024;
+
+/// How long to wait before reconnecting to a collector that went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Frame Streams control frame types and fields
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_READY: u32 = 0x04;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// dnstap.proto values
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+const MESSAGE_TYPE_STUB_QUERY: u64 = 9;
+const MESSAGE_TYPE_STUB_RESPONSE: u64 = 10;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+const SOCKET_PROTOCOL_UDP: u64 = 1;
+const SOCKET_PROTOCOL_TCP: u64 = 2;
+const SOCKET_PROTOCOL_DOT: u64 = 3;
+const SOCKET_PROTOCOL_DOH: u64 = 4;
+const SOCKET_PROTOCOL_DOQ: u64 = 7;
+
+/// Logs every query sent through its inner transport, and every response,
+/// to a dnstap collector
+///
+/// The collector is connected to the first time a query is sent, and
+/// reconnected to if it goes away.  Logging never holds up a query: frames
+/// wait in a buffer while the collector is slow or unreachable, and once the
+/// buffer is full, new ones are dropped.  Frames are written by a task
+/// called "dnstap writer" (see [`crate::tasks`]), which stops when the
+/// transport is dropped.
+///
+/// ```no_run
+/// # use reqwest_resolve::dns_transport::{
+/// #     transport_resolver, StandardTransport, TransportDnsResolver,
+/// # };
+/// # use reqwest_resolve::dnstap::DnstapTransport;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let options = ResolverOpts::default();
+/// let transport = DnstapTransport::new(
+///     StandardTransport::new(options),
+///     "/var/run/dnstap.sock",
+/// )
+/// .with_identity("api-gateway");
+/// let resolver =
+///     transport_resolver(ResolverConfig::default(), options, transport)
+///         .unwrap();
+/// let _my_resolver = TransportDnsResolver::new(resolver);
+/// ```
+pub struct DnstapTransport<T> {
+    inner: T,
+    path: PathBuf,
+    identity: Option<String>,
+    /// where frames go, once the writer has been started
+    frames: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    tasks: TaskSet,
+}
+
+impl<T> DnstapTransport<T> {
+    /// Logs to the collector listening on the Unix socket at `path`.
+    pub fn new(inner: T, path: impl AsRef<Path>) -> DnstapTransport<T> {
+        DnstapTransport {
+            inner,
+            path: path.as_ref().to_owned(),
+            identity: None,
+            frames: Mutex::new(None),
+            tasks: TaskSet::default(),
+        }
+    }
+
+    /// Identifies this client in every message (usually its hostname or the
+    /// service's name).
+    pub fn with_identity(mut self, identity: &str) -> DnstapTransport<T> {
+        self.identity = Some(identity.to_owned());
+        self
+    }
+
+    /// Queues `frame` for the collector, starting the writer if this is the
+    /// first one.
+    fn log(&self, frame: Vec<u8>) {
+        let mut frames = self.frames.lock().unwrap();
+        let sender = frames.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(FRAME_BUFFER);
+            self.tasks.spawn(
+                "dnstap writer",
+                write_frames(self.path.clone(), receiver),
+            );
+            sender
+        });
+        if sender.try_send(frame).is_err() {
+            debug!("dnstap buffer full; dropping frame");
+        }
+    }
+}
+
+impl<T: DnsTransport> DnsTransport for DnstapTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let server = server.clone();
+        let query_time = SystemTime::now();
+        let query_bytes = query.to_vec().unwrap_or_default();
+        self.log(frame(
+            self.identity.as_deref(),
+            MESSAGE_TYPE_STUB_QUERY,
+            &server,
+            query_time,
+            &query_bytes,
+            None,
+        ));
+
+        let response = self.inner.send(&server, query);
+        let identity = self.identity.clone();
+        // The frame for the response is made here but queued back on this
+        // transport, which the future can't borrow, so hand it a sender.
+        let frames = self.frames.lock().unwrap().clone();
+        async move {
+            let response = response.await?;
+            if let (Some(mut frames), Ok(bytes)) = (frames, response.to_vec()) {
+                let _ = frames.try_send(frame(
+                    identity.as_deref(),
+                    MESSAGE_TYPE_STUB_RESPONSE,
+                    &server,
+                    query_time,
+                    &query_bytes,
+                    Some((SystemTime::now(), &bytes)),
+                ));
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// Connects to the collector and writes frames to it until the transport is
+/// dropped, reconnecting whenever the collector goes away.
+async fn write_frames(path: PathBuf, mut frames: mpsc::Receiver<Vec<u8>>) {
+    loop {
+        let mut stream = match connect(&path).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                debug!(
+                    "dnstap collector unavailable",
+                    path = path,
+                    error = error
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        debug!("connected to dnstap collector", path = path);
+        loop {
+            let Some(frame) = frames.next().await else {
+                return;
+            };
+            let len = frame.len() as u32;
+            let written = async {
+                stream.write_u32(len).await?;
+                stream.write_all(&frame).await
+            };
+            if let Err(error) = written.await {
+                debug!(
+                    "dnstap collector went away",
+                    path = path,
+                    error = error
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Connects to the collector at `path` and does the Frame Streams
+/// handshake for a bidirectional stream.
+async fn connect(path: &Path) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream.write_all(&control_frame(CONTROL_READY)).await?;
+    if read_control_frame(&mut stream).await? != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "dnstap collector didn't accept the stream",
+        ));
+    }
+    stream.write_all(&control_frame(CONTROL_START)).await?;
+    Ok(stream)
+}
+
+/// Returns a control frame of type `control` offering dnstap.
+fn control_frame(control: u32) -> Vec<u8> {
+    let content_type = DNSTAP_CONTENT_TYPE.as_bytes();
+    let mut body = Vec::new();
+    body.extend_from_slice(&control.to_be_bytes());
+    body.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+    body.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+    body.extend_from_slice(content_type);
+
+    // A zero-length data frame is the escape that says this is a control
+    // frame.
+    let mut frame = Vec::with_capacity(8 + body.len());
+    frame.extend_from_slice(&0u32.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Reads a control frame and returns its type.
+async fn read_control_frame(stream: &mut UnixStream) -> io::Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame from the dnstap collector",
+        ));
+    }
+    let len = stream.read_u32().await?;
+    if !(4..=512).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad control frame from the dnstap collector",
+        ));
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(u32::from_be_bytes([body[0], body[1], body[2], body[3]]))
+}
+
+/// Encodes a `Dnstap` protocol buffer holding one `Message`.
+fn frame(
+    identity: Option<&str>,
+    message_type: u64,
+    server: &NameServerConfig,
+    query_time: SystemTime,
+    query: &[u8],
+    response: Option<(SystemTime, &[u8])>,
+) -> Vec<u8> {
+    let family = match server.socket_addr {
+        SocketAddr::V4(_) => SOCKET_FAMILY_INET,
+        SocketAddr::V6(_) => SOCKET_FAMILY_INET6,
+    };
+    let protocol = socket_protocol(server.protocol);
+    let server_ip = match server.socket_addr {
+        SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+        SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+    };
+
+    let mut message = Vec::new();
+    put_varint_field(&mut message, 1, message_type);
+    put_varint_field(&mut message, 2, family);
+    if let Some(protocol) = protocol {
+        put_varint_field(&mut message, 3, protocol);
+    }
+    put_bytes_field(&mut message, 5, &server_ip);
+    put_varint_field(&mut message, 7, u64::from(server.socket_addr.port()));
+    let (sec, nsec) = since_epoch(query_time);
+    put_varint_field(&mut message, 8, sec);
+    put_fixed32_field(&mut message, 9, nsec);
+    put_bytes_field(&mut message, 10, query);
+    if let Some((response_time, response)) = response {
+        let (sec, nsec) = since_epoch(response_time);
+        put_varint_field(&mut message, 12, sec);
+        put_fixed32_field(&mut message, 13, nsec);
+        put_bytes_field(&mut message, 14, response);
+    }
+
+    let mut dnstap = Vec::new();
+    if let Some(identity) = identity {
+        put_bytes_field(&mut dnstap, 1, identity.as_bytes());
+    }
+    put_bytes_field(
+        &mut dnstap,
+        2,
+        concat!("reqwest-resolve ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    put_bytes_field(&mut dnstap, 14, &message);
+    put_varint_field(&mut dnstap, 15, DNSTAP_TYPE_MESSAGE);
+    dnstap
+}
+
+/// Returns the dnstap `SocketProtocol` for `protocol`, or `None` if dnstap
+/// has none.
+fn socket_protocol(protocol: Protocol) -> Option<u64> {
+    match protocol {
+        Protocol::Udp => Some(SOCKET_PROTOCOL_UDP),
+        Protocol::Tcp => Some(SOCKET_PROTOCOL_TCP),
+        // The other variants only exist when something in the build turns
+        // on the trust-dns features for them, which may not be this crate's
+        // features, so they're told apart by name.
+        other => match other.to_string().as_str() {
+            "tls" => Some(SOCKET_PROTOCOL_DOT),
+            "https" => Some(SOCKET_PROTOCOL_DOH),
+            "quic" => Some(SOCKET_PROTOCOL_DOQ),
+            // mDNS is multicast UDP.
+            "mdns" => Some(SOCKET_PROTOCOL_UDP),
+            // Leave the field out rather than guess.
+            _ => None,
+        },
+    }
+}
+
+fn since_epoch(time: SystemTime) -> (u64, u32) {
+    let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(out, field << 3);
+    put_varint(out, value);
+}
+
+fn put_fixed32_field(out: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(out, (field << 3) | 5);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(out, (field << 3) | 2);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frame;
+    use super::socket_protocol;
+    use super::DNSTAP_TYPE_MESSAGE;
+    use super::MESSAGE_TYPE_STUB_RESPONSE;
+    use super::SOCKET_FAMILY_INET6;
+    use super::SOCKET_PROTOCOL_TCP;
+    use super::SOCKET_PROTOCOL_UDP;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use trust_dns_resolver::config::NameServerConfig;
+    use trust_dns_resolver::config::Protocol;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Varint(u64),
+        Fixed32(u32),
+        Bytes(Vec<u8>),
+    }
+
+    fn varint(buf: &[u8], offset: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buf[*offset];
+            *offset += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    /// Decodes the fields of one protocol buffer message, in order.
+    fn fields(buf: &[u8]) -> Vec<(u64, Value)> {
+        let mut offset = 0;
+        let mut fields = Vec::new();
+        while offset < buf.len() {
+            let key = varint(buf, &mut offset);
+            let value = match key & 7 {
+                0 => Value::Varint(varint(buf, &mut offset)),
+                2 => {
+                    let len = varint(buf, &mut offset) as usize;
+                    offset += len;
+                    Value::Bytes(buf[offset - len..offset].to_vec())
+                }
+                5 => {
+                    let bytes = buf[offset..offset + 4].try_into().unwrap();
+                    offset += 4;
+                    Value::Fixed32(u32::from_le_bytes(bytes))
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    #[test]
+    fn encodes_frames() {
+        let server = NameServerConfig::new(
+            "[2001:db8::53]:853".parse().unwrap(),
+            Protocol::Tcp,
+        );
+        let query_time =
+            SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 250);
+        let response_time = query_time + Duration::from_millis(3);
+        let encoded = frame(
+            Some("host1"),
+            MESSAGE_TYPE_STUB_RESPONSE,
+            &server,
+            query_time,
+            b"query",
+            Some((response_time, b"response")),
+        );
+
+        let dnstap = fields(&encoded);
+        assert_eq!(dnstap[0], (1, Value::Bytes(b"host1".to_vec())));
+        assert_eq!(dnstap[1].0, 2);
+        assert_eq!(dnstap[3], (15, Value::Varint(DNSTAP_TYPE_MESSAGE)));
+        let Value::Bytes(message) = &dnstap[2].1 else {
+            panic!("message isn't embedded: {:?}", dnstap[2]);
+        };
+        assert_eq!(dnstap[2].0, 14);
+
+        let server_ip: std::net::Ipv6Addr = "2001:db8::53".parse().unwrap();
+        assert_eq!(
+            fields(message),
+            [
+                (1, Value::Varint(MESSAGE_TYPE_STUB_RESPONSE)),
+                (2, Value::Varint(SOCKET_FAMILY_INET6)),
+                (3, Value::Varint(SOCKET_PROTOCOL_TCP)),
+                (5, Value::Bytes(server_ip.octets().to_vec())),
+                (7, Value::Varint(853)),
+                (8, Value::Varint(1_700_000_000)),
+                (9, Value::Fixed32(250)),
+                (10, Value::Bytes(b"query".to_vec())),
+                (12, Value::Varint(1_700_000_000)),
+                (13, Value::Fixed32(3_000_250)),
+                (14, Value::Bytes(b"response".to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn socket_protocols() {
+        assert_eq!(socket_protocol(Protocol::Udp), Some(SOCKET_PROTOCOL_UDP));
+        assert_eq!(socket_protocol(Protocol::Tcp), Some(SOCKET_PROTOCOL_TCP));
+        #[cfg(feature = "dns-over-rustls")]
+        assert_eq!(
+            socket_protocol(Protocol::Tls),
+            Some(super::SOCKET_PROTOCOL_DOT)
+        );
+        #[cfg(feature = "dns-over-https-rustls")]
+        assert_eq!(
+            socket_protocol(Protocol::Https),
+            Some(super::SOCKET_PROTOCOL_DOH)
+        );
+    }
+}
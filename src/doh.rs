@@ -0,0 +1,425 @@
+//! DNS-over-HTTPS with extra request headers
+//!
+//! Hosted filtering services and private resolvers often put their
+//! DNS-over-HTTPS endpoints behind authentication: an API key or bearer
+//! token in a header, or an account ID in the path.  trust-dns's own
+//! DNS-over-HTTPS client sends a fixed request to "/dns-query" with no way
+//! to add anything, so [`DohTransport`] sends the queries itself instead,
+//! as a [`DnsTransport`] (see [`crate::dns_transport`]).  It speaks HTTP/2,
+//! like trust-dns, and POSTs each query as RFC 8484 describes.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::logging::debug;
+use crate::tasks::TaskSet;
+use crate::upstream_tls::UpstreamTls;
+use futures::future::FutureExt;
+use hyper::client::conn::http2::SendRequest;
+use hyper::header::HeaderMap;
+use hyper::header::HeaderName;
+use hyper::header::HeaderValue;
+use hyper::header::ACCEPT;
+use hyper::header::CONTENT_TYPE;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::StatusCode;
+use rustls::ClientConfig;
+use rustls::ServerName;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::op::Message;
+
+/// The path trust-dns (and most servers) use for DNS-over-HTTPS
+pub const DEFAULT_DOH_PATH: &str = "/dns-query";
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+type HeaderFn =
+    Box<dyn Fn(&NameServerConfig) -> HeaderMap + Send + Sync + 'static>;
+
+/// Connections, shared with the futures that make and use them
+struct Connections {
+    open: Mutex<Vec<(NameServerConfig, SendRequest<Body>)>>,
+    tasks: TaskSet,
+}
+
+/// Sends queries to DNS-over-HTTPS servers, adding headers to each request
+///
+/// Every server is sent queries this way, whatever its `protocol` says, so
+/// a resolver using this should only be configured with DNS-over-HTTPS
+/// servers.  Each server's `tls_dns_name` is the name its certificate is
+/// checked against and the authority requests are sent to.
+///
+/// Headers can be fixed, with [`DohTransport::with_header`], or made for
+/// each request, with [`DohTransport::with_headers_from`] (for tokens that
+/// expire).  Connections are opened the first time a server is used and
+/// reused until one fails or the server closes it; the tasks that drive
+/// them are called "doh connection", and those for their streams "doh
+/// stream" (see [`crate::tasks`]), and they stop when the transport is
+/// dropped.
+///
+/// ```no_run
+/// # use hyper::header::{HeaderName, HeaderValue};
+/// # use reqwest_resolve::dns_transport::{
+/// #     transport_resolver, TransportDnsResolver,
+/// # };
+/// # use reqwest_resolve::doh::DohTransport;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfigGroup, ResolverConfig, ResolverOpts,
+/// # };
+/// let config = ResolverConfig::from_parts(
+///     None,
+///     Vec::new(),
+///     NameServerConfigGroup::from_ips_https(
+///         &["192.0.2.53".parse().unwrap()],
+///         443,
+///         String::from("dns.filter.example"),
+///         true,
+///     ),
+/// );
+/// let transport = DohTransport::new()
+///     .with_path("/dns-query/8c5f2a")
+///     .with_header(
+///         HeaderName::from_static("x-api-key"),
+///         HeaderValue::from_static("secret"),
+///     );
+/// let resolver =
+///     transport_resolver(config, ResolverOpts::default(), transport)
+///         .unwrap();
+/// let _my_resolver = TransportDnsResolver::new(resolver);
+/// ```
+pub struct DohTransport {
+    tls_config: Arc<ClientConfig>,
+    path: String,
+    headers: HeaderMap,
+    make_headers: Option<HeaderFn>,
+    connections: Arc<Connections>,
+}
+
+impl Default for DohTransport {
+    fn default() -> DohTransport {
+        DohTransport::new()
+    }
+}
+
+impl DohTransport {
+    /// Sends queries to [`DEFAULT_DOH_PATH`] with no extra headers, trusting
+    /// the same root certificates trust-dns does by default.
+    pub fn new() -> DohTransport {
+        DohTransport::with_tls_config(UpstreamTls::new().build())
+    }
+
+    /// Like [`DohTransport::new`], but connects with `tls_config`, as from
+    /// [`UpstreamTls`] for other root certificates, pins, or a client
+    /// certificate.
+    pub fn with_tls_config(tls_config: Arc<ClientConfig>) -> DohTransport {
+        // The servers have to be told we speak HTTP/2.
+        let mut tls_config = tls_config;
+        if tls_config.alpn_protocols.is_empty() {
+            Arc::make_mut(&mut tls_config).alpn_protocols =
+                vec![b"h2".to_vec()];
+        }
+        DohTransport {
+            tls_config,
+            path: String::from(DEFAULT_DOH_PATH),
+            headers: HeaderMap::new(),
+            make_headers: None,
+            connections: Arc::new(Connections {
+                open: Mutex::new(Vec::new()),
+                tasks: TaskSet::default(),
+            }),
+        }
+    }
+
+    /// Sends queries to `path` on every server instead of
+    /// [`DEFAULT_DOH_PATH`].
+    pub fn with_path(mut self, path: &str) -> DohTransport {
+        self.path = path.to_owned();
+        self
+    }
+
+    /// Adds `name: value` to every request, after any headers added before
+    /// with the same name.
+    pub fn with_header(
+        mut self,
+        name: HeaderName,
+        value: HeaderValue,
+    ) -> DohTransport {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Adds the headers `make` returns for each request to the server it's
+    /// given, after the fixed ones, replacing any set before with this
+    ///
+    /// This is called for every query, so it should be quick: something
+    /// that fetches tokens should do that in the background and have this
+    /// return the latest one.
+    pub fn with_headers_from<F>(mut self, make: F) -> DohTransport
+    where
+        F: Fn(&NameServerConfig) -> HeaderMap + Send + Sync + 'static,
+    {
+        self.make_headers = Some(Box::new(make));
+        self
+    }
+
+    /// Builds the request for sending `query` to `server`.
+    fn request(
+        &self,
+        server: &NameServerConfig,
+        name: &str,
+        query: Vec<u8>,
+    ) -> Result<Request<Body>, ResolveError> {
+        let uri = match server.socket_addr.port() {
+            443 => format!("https://{}{}", name, self.path),
+            port => format!("https://{}:{}{}", name, port, self.path),
+        };
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(query))
+            .map_err(|error| {
+                ResolveError::from(format!("DNS-over-HTTPS request: {}", error))
+            })?;
+        let headers = request.headers_mut();
+        for (name, value) in &self.headers {
+            headers.append(name, value.clone());
+        }
+        if let Some(make_headers) = &self.make_headers {
+            for (name, value) in &make_headers(server) {
+                headers.append(name, value.clone());
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl DnsTransport for DohTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let server = server.clone();
+        let id = query.id();
+        let prepared = server
+            .tls_dns_name
+            .clone()
+            .ok_or_else(|| {
+                ResolveError::from(format!(
+                    "DNS-over-HTTPS server {} has no name",
+                    server.socket_addr
+                ))
+            })
+            .and_then(|name| {
+                let request = self.request(&server, &name, query.to_vec()?)?;
+                Ok((name, request))
+            });
+        let tls_config = Arc::clone(&self.tls_config);
+        let connections = Arc::clone(&self.connections);
+
+        async move {
+            let (name, request) = prepared?;
+            let cached = connections
+                .open
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(config, _)| *config == server)
+                .map(|(_, sender)| sender.clone());
+            // The server may have closed the connection since it was last
+            // used (after it sat idle, say), so it gets a new one.
+            let cached = match cached {
+                Some(mut sender) => match sender.ready().await {
+                    Ok(()) => Some(sender),
+                    Err(error) => {
+                        debug!(
+                            "DNS-over-HTTPS connection closed",
+                            name = name,
+                            error = error
+                        );
+                        connections
+                            .open
+                            .lock()
+                            .unwrap()
+                            .retain(|(config, _)| *config != server);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let mut sender = match cached {
+                Some(sender) => sender,
+                None => {
+                    let sender =
+                        connect(&connections, &server, &name, tls_config)
+                            .await?;
+                    connections
+                        .open
+                        .lock()
+                        .unwrap()
+                        .push((server.clone(), sender.clone()));
+                    sender
+                }
+            };
+
+            let result = async {
+                let response = sender.send_request(request).await?;
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await?;
+                Ok::<_, hyper::Error>((status, body))
+            }
+            .await;
+            let (status, body) = match result {
+                Ok(response) => response,
+                Err(error) => {
+                    // The next query to this server gets a new connection.
+                    connections
+                        .open
+                        .lock()
+                        .unwrap()
+                        .retain(|(config, _)| *config != server);
+                    return Err(ResolveError::from(format!(
+                        "DNS-over-HTTPS request to {}: {}",
+                        name, error
+                    )));
+                }
+            };
+            if status != StatusCode::OK {
+                return Err(ResolveError::from(format!(
+                    "DNS-over-HTTPS server {} responded with {}",
+                    name, status
+                )));
+            }
+
+            let mut response = Message::from_vec(&body)?;
+            response.set_id(id);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// Opens an HTTP/2 connection to `server`, checking its certificate against
+/// `name`.
+async fn connect(
+    connections: &Arc<Connections>,
+    server: &NameServerConfig,
+    name: &str,
+    tls_config: Arc<ClientConfig>,
+) -> Result<SendRequest<Body>, ResolveError> {
+    let server_name = ServerName::try_from(name).map_err(|error| {
+        ResolveError::from(format!(
+            "DNS-over-HTTPS server name {:?}: {}",
+            name, error
+        ))
+    })?;
+    let tcp = TcpStream::connect(server.socket_addr).await?;
+    let tls = TlsConnector::from(tls_config).connect(server_name, tcp).await?;
+    let (sender, connection) =
+        hyper::client::conn::http2::Builder::new(StreamExecutor {
+            connections: Arc::downgrade(connections),
+        })
+        .handshake(tls)
+        .await
+        .map_err(|error| {
+            ResolveError::from(format!(
+                "DNS-over-HTTPS connection to {}: {}",
+                name, error
+            ))
+        })?;
+    debug!("connected to DNS-over-HTTPS server", name = name);
+    let name = name.to_owned();
+    connections.tasks.spawn("doh connection", async move {
+        if let Err(error) = connection.await {
+            debug!(
+                "DNS-over-HTTPS connection failed",
+                name = name,
+                error = error
+            );
+        }
+    });
+    Ok(sender)
+}
+
+/// Spawns the tasks hyper uses for each HTTP/2 stream, as the transport's
+/// own
+///
+/// The connection's task holds on to this, so it only has a weak reference
+/// to the tasks: otherwise, they'd keep each other from ever being dropped.
+#[derive(Clone)]
+struct StreamExecutor {
+    connections: Weak<Connections>,
+}
+
+impl<F> hyper::rt::Executor<F> for StreamExecutor
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, future: F) {
+        // Once the transport is gone, so are its connections, and there's
+        // nothing for the stream to do.
+        if let Some(connections) = self.connections.upgrade() {
+            connections.tasks.spawn("doh stream", future);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DohTransport;
+    use hyper::header::HeaderMap;
+    use hyper::header::HeaderName;
+    use hyper::header::HeaderValue;
+    use trust_dns_resolver::config::NameServerConfig;
+    use trust_dns_resolver::config::Protocol;
+
+    #[test]
+    fn request_uri() {
+        let transport = DohTransport::new().with_path("/dns-query/abc");
+        let server = |addr: &str| {
+            NameServerConfig::new(addr.parse().unwrap(), Protocol::Https)
+        };
+
+        let request = transport
+            .request(&server("192.0.2.53:443"), "dns.test", Vec::new())
+            .unwrap();
+        assert_eq!(request.uri(), "https://dns.test/dns-query/abc");
+        let request = transport
+            .request(&server("192.0.2.53:8443"), "dns.test", Vec::new())
+            .unwrap();
+        assert_eq!(request.uri(), "https://dns.test:8443/dns-query/abc");
+    }
+
+    #[test]
+    fn request_headers() {
+        let tag = HeaderName::from_static("x-tag");
+        let fixed = HeaderValue::from_static("fixed");
+        let transport = DohTransport::new()
+            .with_header(tag.clone(), fixed)
+            .with_headers_from(|_| {
+                let mut headers = HeaderMap::new();
+                headers.append("x-tag", HeaderValue::from_static("a"));
+                headers.append("x-tag", HeaderValue::from_static("b"));
+                headers
+            });
+        let server = NameServerConfig::new(
+            "192.0.2.53:443".parse().unwrap(),
+            Protocol::Https,
+        );
+
+        let request =
+            transport.request(&server, "dns.test", Vec::new()).unwrap();
+        let values: Vec<_> = request.headers().get_all(tag).iter().collect();
+        assert_eq!(values, ["fixed", "a", "b"]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Finding an organization's DNS-over-HTTPS servers from its domain
+//!
+//! Some organizations publish the DNS-over-HTTPS servers their clients
+//! should use at a well-known URI on their own domain, so that clients only
+//! need to be configured with the domain: a GET of
+//! `https://example.com/.well-known/doh-servers-associated/` returns a JSON
+//! object listing their URI templates, as in
+//!
+//! ```text
+//! {"associated-resolvers": ["https://dns.example.com/dns-query{?dns}"]}
+//! ```
+//!
+//! (This is the format draft-ietf-doh-resolver-associated-doh proposed.)
+//! [`discover`] fetches and parses that list, and [`upgrade`] replaces a
+//! configuration's plain servers with the servers it lists.
+//!
+//! The list is trusted because it's fetched over HTTPS from the domain
+//! itself, with its certificate checked against the same roots as the
+//! servers'.  Finding the domain's address, and the servers', is the only
+//! thing the plain servers are used for.
+
+use crate::bootstrap::Bootstrap;
+use crate::bootstrap::EncryptedUpstream;
+use crate::doh::DohTransport;
+use crate::logging::debug;
+use crate::tasks::TaskSet;
+use crate::upstream_tls::UpstreamTls;
+use hyper::body::HttpBody;
+use hyper::header::ACCEPT;
+use hyper::header::HOST;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::StatusCode;
+use hyper::Uri;
+use rustls::ClientConfig;
+use rustls::ServerName;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The path the list of servers is fetched from
+pub const WELL_KNOWN_PATH: &str = "/.well-known/doh-servers-associated/";
+
+/// How long fetching the list may take, from connecting to reading the
+/// last of it
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest list that's read
+const MAX_DOCUMENT: usize = 64 * 1024;
+
+/// The document at [`WELL_KNOWN_PATH`]
+#[derive(serde::Deserialize)]
+struct Document {
+    #[serde(rename = "associated-resolvers")]
+    associated_resolvers: Vec<String>,
+}
+
+/// A DNS-over-HTTPS server, as described by its URI template (RFC 8484)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DohTemplate {
+    /// the template as it was given (like
+    /// "https://dns.example.com/dns-query{?dns}")
+    pub template: String,
+    /// the server's name, which its certificate is checked against
+    pub hostname: String,
+    pub port: u16,
+    /// where queries are POSTed: the template's path and query, without its
+    /// variables
+    pub path: String,
+}
+
+impl DohTemplate {
+    /// Parses an "https" URI template, failing for any other scheme and for
+    /// servers identified by IP address (which their certificates can't be
+    /// checked against).
+    ///
+    /// ```
+    /// # use reqwest_resolve::doh_discovery::DohTemplate;
+    /// let template =
+    ///     DohTemplate::parse("https://dns.example.com:8443/q{?dns}").unwrap();
+    /// assert_eq!(template.hostname, "dns.example.com");
+    /// assert_eq!(template.port, 8443);
+    /// assert_eq!(template.path, "/q");
+    /// assert!(DohTemplate::parse("https://192.0.2.53/dns-query").is_err());
+    /// ```
+    pub fn parse(template: &str) -> Result<DohTemplate, ResolveError> {
+        let invalid = |why: &str| {
+            ResolveError::from(format!(
+                "DNS-over-HTTPS template {:?}: {}",
+                template, why
+            ))
+        };
+        let base = template.split('{').next().unwrap_or(template);
+        let uri: Uri = base.parse().map_err(|_| invalid("not a URI"))?;
+        if uri.scheme_str() != Some("https") {
+            return Err(invalid("not an https URI"));
+        }
+        let hostname = uri.host().unwrap_or_default();
+        if hostname.is_empty() {
+            return Err(invalid("no host"));
+        }
+        if hostname.starts_with('[') || hostname.parse::<IpAddr>().is_ok() {
+            return Err(invalid("the host is an IP address"));
+        }
+        let path = uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .filter(|path| !path.is_empty())
+            .unwrap_or("/");
+        Ok(DohTemplate {
+            template: template.to_owned(),
+            hostname: hostname.to_owned(),
+            port: uri.port_u16().unwrap_or(443),
+            path: path.to_owned(),
+        })
+    }
+
+    /// Returns whether trust-dns itself can send queries to this server.
+    /// It can't at any path but "/dns-query", though the transport from
+    /// [`DohTemplate::transport`] can.
+    pub fn usable_by_trust_dns(&self) -> bool {
+        self.path == "/dns-query"
+    }
+
+    /// Returns the server as an upstream, for `bootstrap::Bootstrap`.
+    pub fn upstream(&self) -> EncryptedUpstream {
+        EncryptedUpstream {
+            port: self.port,
+            ..EncryptedUpstream::https(&self.hostname)
+        }
+    }
+
+    /// Returns a transport that sends queries to this server's path,
+    /// connecting with `tls_config`.
+    pub fn transport(&self, tls_config: Arc<ClientConfig>) -> DohTransport {
+        DohTransport::with_tls_config(tls_config).with_path(&self.path)
+    }
+}
+
+/// Fetches the list of DNS-over-HTTPS servers `domain` publishes, finding
+/// its address with `bootstrap`
+///
+/// The domain's certificate is checked against `tls`'s root certificates
+/// (and pins, if it has any).  Templates in the list that can't be parsed
+/// are skipped, and a domain that publishes a list with none that can is
+/// an empty list rather than an error.
+pub async fn discover(
+    domain: &str,
+    bootstrap: &Bootstrap,
+    tls: &UpstreamTls,
+) -> Result<Vec<DohTemplate>, ResolveError> {
+    let domain = domain.trim_end_matches('.');
+    let mut tls_config = tls.clone().build();
+    Arc::make_mut(&mut tls_config).alpn_protocols = vec![b"http/1.1".to_vec()];
+    let fetch = fetch(domain, bootstrap, tls_config);
+    let body =
+        tokio::time::timeout(FETCH_TIMEOUT, fetch).await.map_err(|_| {
+            ResolveError::from(format!(
+                "fetching DNS-over-HTTPS servers from {}: timed out",
+                domain
+            ))
+        })??;
+    let document: Document =
+        serde_json::from_slice(&body).map_err(|error| {
+            ResolveError::from(format!(
+                "DNS-over-HTTPS servers from {}: {}",
+                domain, error
+            ))
+        })?;
+
+    let mut templates = Vec::new();
+    for template in &document.associated_resolvers {
+        match DohTemplate::parse(template) {
+            Ok(template) => {
+                debug!(
+                    "discovered DNS-over-HTTPS server",
+                    domain = domain,
+                    template = template.template,
+                );
+                templates.push(template);
+            }
+            Err(error) => {
+                debug!(
+                    "skipping DNS-over-HTTPS template",
+                    domain = domain,
+                    error = error,
+                );
+            }
+        }
+    }
+    Ok(templates)
+}
+
+/// Returns `config` with its servers replaced by the DNS-over-HTTPS servers
+/// `domain` publishes (the ones trust-dns can use)
+///
+/// The configured servers are used to find the domain's address and the
+/// servers'.  If the list can't be fetched, or none of its servers can be
+/// used or found, `config` is returned as it is, as it is if it already has
+/// encrypted servers.  Everything else about the configuration is kept.
+///
+/// Servers at paths other than "/dns-query" are skipped (see
+/// [`DohTemplate::usable_by_trust_dns`]), since a configuration can't say
+/// where to send queries.  To use those, call [`discover`] and send queries
+/// with [`DohTemplate::transport`] instead.
+///
+/// ```no_run
+/// # use reqwest_resolve::doh_discovery::upgrade;
+/// # use reqwest_resolve::upstream_tls::UpstreamTls;
+/// # use trust_dns_resolver::system_conf::read_system_conf;
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let (config, options) = read_system_conf()?;
+/// let config = upgrade(&config, "corp.example", &UpstreamTls::new()).await;
+/// let _resolver = TokioAsyncResolver::tokio(config, options)?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn upgrade(
+    config: &ResolverConfig,
+    domain: &str,
+    tls: &UpstreamTls,
+) -> ResolverConfig {
+    if config
+        .name_servers()
+        .iter()
+        .any(|name_server| name_server.protocol.is_encrypted())
+    {
+        return config.clone();
+    }
+
+    let bootstrap = match TokioAsyncResolver::tokio(
+        config.clone(),
+        ResolverOpts::default(),
+    ) {
+        Ok(resolver) => Bootstrap::Resolver(Arc::new(resolver)),
+        Err(error) => {
+            debug!("DoH discovery failed", domain = domain, error = error);
+            return config.clone();
+        }
+    };
+    let templates = match discover(domain, &bootstrap, tls).await {
+        Ok(templates) => templates,
+        Err(error) => {
+            debug!("DoH discovery failed", domain = domain, error = error);
+            return config.clone();
+        }
+    };
+
+    let mut name_servers = Vec::new();
+    for template in &templates {
+        if !template.usable_by_trust_dns() {
+            debug!(
+                "skipping DNS-over-HTTPS server trust-dns can't use",
+                domain = domain,
+                template = template.template,
+            );
+            continue;
+        }
+        let upstream = template.upstream();
+        match bootstrap.resolve_upstreams(&[upstream]).await {
+            Ok(group) => name_servers.extend(group.into_inner()),
+            Err(error) => {
+                debug!(
+                    "couldn't find DNS-over-HTTPS server",
+                    domain = domain,
+                    template = template.template,
+                    error = error,
+                );
+            }
+        }
+    }
+    if name_servers.is_empty() {
+        debug!("no usable DNS-over-HTTPS servers", domain = domain);
+        return config.clone();
+    }
+
+    let mut upgraded = ResolverConfig::from_parts(
+        config.domain().cloned(),
+        config.search().to_vec(),
+        name_servers,
+    );
+    upgraded.set_tls_client_config(tls.clone().build());
+    upgraded
+}
+
+/// GETs [`WELL_KNOWN_PATH`] from `domain`, from the first of its addresses
+/// that can be connected to, returning the body.
+async fn fetch(
+    domain: &str,
+    bootstrap: &Bootstrap,
+    tls_config: Arc<ClientConfig>,
+) -> Result<Vec<u8>, ResolveError> {
+    let server_name = ServerName::try_from(domain).map_err(|error| {
+        ResolveError::from(format!("domain {:?}: {}", domain, error))
+    })?;
+    let ips = bootstrap.lookup(domain).await?;
+    let mut last_error = None;
+    let mut connected = None;
+    for ip in ips {
+        match TcpStream::connect(SocketAddr::new(ip, 443)).await {
+            Ok(tcp) => {
+                connected = Some(tcp);
+                break;
+            }
+            Err(error) => last_error = Some(error),
+        }
+    }
+    let tcp = match (connected, last_error) {
+        (Some(tcp), _) => tcp,
+        (None, Some(error)) => return Err(error.into()),
+        (None, None) => {
+            return Err(ResolveError::from(format!(
+                "no addresses for {:?}",
+                domain
+            )))
+        }
+    };
+    let tls = TlsConnector::from(tls_config).connect(server_name, tcp).await?;
+
+    let http_error = |error: hyper::Error| {
+        ResolveError::from(format!(
+            "fetching DNS-over-HTTPS servers from {}: {}",
+            domain, error
+        ))
+    };
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(tls).await.map_err(http_error)?;
+    // The connection is only needed for this one request, so its task stops
+    // when this returns (or times out).
+    let tasks = TaskSet::default();
+    tasks.spawn("doh discovery connection", async move {
+        let _ = connection.await;
+    });
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(WELL_KNOWN_PATH)
+        .header(HOST, domain)
+        .header(ACCEPT, "application/json")
+        .body(Body::empty())
+        .map_err(|error| ResolveError::from(error.to_string()))?;
+    let response = sender.send_request(request).await.map_err(http_error)?;
+    if response.status() != StatusCode::OK {
+        return Err(ResolveError::from(format!(
+            "fetching DNS-over-HTTPS servers from {}: {}",
+            domain,
+            response.status()
+        )));
+    }
+
+    let mut body = response.into_body();
+    let mut document = Vec::new();
+    while let Some(chunk) = body.data().await {
+        document.extend_from_slice(&chunk.map_err(http_error)?);
+        if document.len() > MAX_DOCUMENT {
+            return Err(ResolveError::from(format!(
+                "DNS-over-HTTPS servers from {}: longer than {} bytes",
+                domain, MAX_DOCUMENT
+            )));
+        }
+    }
+    Ok(document)
+}
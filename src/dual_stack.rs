@@ -0,0 +1,215 @@
+//! Preferring one address family, without waiting long for it
+//!
+//! `ResolverOpts::ip_strategy` can put IPv6 addresses first
+//! (`Ipv6thenIpv4`), but a lookup still waits for both the A and the AAAA
+//! lookups, so a slow (or unanswered) AAAA lookup holds up every
+//! connection, even on networks where IPv4 is all that works anyway.
+//! Browsers wait for the family they prefer only so long before going
+//! ahead with the other one (RFC 8305 calls this the "Resolution Delay").
+//!
+//! A [`DualStackResolver`] looks up both families at the same time, and
+//! once one of them is in, gives the other a configured delay to arrive.
+//! Its addresses are ordered with the preferred family first, and since
+//! reqwest connects to the first family in the list and only falls back
+//! to the other after a while, that decides which one it tries first.
+
+use crate::deterministic::stable_order;
+use crate::logging::debug;
+use crate::names;
+use crate::IpList;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::Either;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::Time;
+use trust_dns_resolver::proto::TokioTime;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Which family goes first, and how long the other one is waited for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct DualStackPreference {
+    /// whether IPv6 addresses go before IPv4 ones (rather than after)
+    pub prefer_ipv6: bool,
+    /// how long, once one family's addresses are in, the other's are waited
+    /// for before the lookup finishes without them
+    pub fallback_delay: Duration,
+}
+
+impl Default for DualStackPreference {
+    /// IPv6 first, with the 50ms delay RFC 8305 recommends
+    fn default() -> DualStackPreference {
+        DualStackPreference {
+            prefer_ipv6: true,
+            fallback_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Looks up both address families at the same time, and orders them as a
+/// [`DualStackPreference`] says
+///
+/// Once either family's lookup has found addresses, the other one gets the
+/// fallback delay to finish too.  If it doesn't, the lookup finishes with
+/// the first family's addresses alone, so a preferred family that's slow
+/// to resolve is left out (until a later lookup finds it in the cache), and
+/// so is a fallback family that's slow when the preferred one was quick.
+/// A family whose lookup fails is left out (without cutting the other one
+/// short), and if both fail, so does the lookup, with the preferred
+/// family's error.
+///
+/// As with `CustomDnsResolver::resolve_stream`, names are looked up exactly
+/// as given, with no search domain or hosts file.
+///
+/// ```no_run
+/// # use reqwest_resolve::dual_stack::{DualStackPreference, DualStackResolver};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// // IPv6 first, unless its addresses are more than 100ms behind IPv4's
+/// let preference = DualStackPreference {
+///     prefer_ipv6: true,
+///     fallback_delay: Duration::from_millis(100),
+/// };
+/// let my_resolver = DualStackResolver::new(resolver, preference);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct DualStackResolver {
+    resolver: TokioAsyncResolver,
+    preference: DualStackPreference,
+}
+
+impl DualStackResolver {
+    pub fn new(
+        resolver: TokioAsyncResolver,
+        preference: DualStackPreference,
+    ) -> DualStackResolver {
+        DualStackResolver { resolver, preference }
+    }
+
+    pub fn preference(&self) -> DualStackPreference {
+        self.preference
+    }
+}
+
+/// Looks up `name`'s addresses in one family.
+async fn lookup_family(
+    resolver: TokioAsyncResolver,
+    name: String,
+    ipv6: bool,
+) -> Result<IpList, ResolveError> {
+    let parsed = names::parsed(&name);
+    let mut ips: IpList = if ipv6 {
+        let lookup = match parsed {
+            Some(parsed) => resolver.ipv6_lookup(parsed).await?,
+            None => resolver.ipv6_lookup(name).await?,
+        };
+        lookup.iter().map(|ip| IpAddr::V6(*ip)).collect()
+    } else {
+        let lookup = match parsed {
+            Some(parsed) => resolver.ipv4_lookup(parsed).await?,
+            None => resolver.ipv4_lookup(name).await?,
+        };
+        lookup.iter().map(|ip| IpAddr::V4(*ip)).collect()
+    };
+    stable_order(&mut ips);
+    Ok(ips)
+}
+
+/// Waits for `lookup` for at most `delay`, returning nothing if it takes
+/// longer.
+async fn within<F>(delay: Duration, lookup: F) -> Option<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+{
+    TokioTime::timeout(delay, lookup).await.ok()
+}
+
+impl MyResolve for DualStackResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        if let Ok(ip) = name.as_str().parse::<IpAddr>() {
+            let addrs = std::iter::once(SocketAddr::new(ip, 0));
+            return MyResolving::ready(Ok(Box::new(addrs)));
+        }
+        async move {
+            let host = name.as_str();
+            let prefer_ipv6 = self.preference.prefer_ipv6;
+            let preferred = lookup_family(
+                self.resolver.clone(),
+                host.to_owned(),
+                prefer_ipv6,
+            );
+            let fallback = lookup_family(
+                self.resolver.clone(),
+                host.to_owned(),
+                !prefer_ipv6,
+            );
+
+            // Whichever finishes first with addresses gives the other
+            // `fallback_delay` more.  One that fails doesn't cut the other
+            // short.
+            let delay = self.preference.fallback_delay;
+            let (preferred, fallback) = match futures::future::select(
+                preferred.boxed(),
+                fallback.boxed(),
+            )
+            .await
+            {
+                Either::Left((Ok(preferred), fallback)) => {
+                    (Some(Ok(preferred)), within(delay, fallback).await)
+                }
+                Either::Left((Err(error), fallback)) => {
+                    (Some(Err(error)), Some(fallback.await))
+                }
+                Either::Right((Ok(fallback), preferred)) => {
+                    (within(delay, preferred).await, Some(Ok(fallback)))
+                }
+                Either::Right((Err(error), preferred)) => {
+                    (Some(preferred.await), Some(Err(error)))
+                }
+            };
+
+            let mut ips = IpList::new();
+            let mut errors = Vec::new();
+            for (family, result) in
+                [("preferred", preferred), ("fallback", fallback)]
+            {
+                match result {
+                    Some(Ok(found)) => ips.extend(found),
+                    Some(Err(error)) => errors.push(error),
+                    None => debug!(
+                        "address family too slow",
+                        name = host,
+                        family = family,
+                        delay = delay,
+                    ),
+                }
+            }
+            if ips.is_empty() {
+                if let Some(error) = errors.into_iter().next() {
+                    return Err(error.into());
+                }
+            }
+            let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, 0));
+            Ok(Box::new(addrs) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+}
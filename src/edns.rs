@@ -0,0 +1,80 @@
+//! Controlling EDNS(0) on upstream queries
+//!
+//! EDNS lets a client advertise that it can take UDP responses bigger than
+//! the classic 512 bytes.  Bigger responses mean fewer retries over TCP, but
+//! they also mean IP fragmentation, and some firewalls and NAT boxes drop
+//! fragmented or oversized DNS responses (or any response carrying an OPT
+//! record at all).  The symptom is lookups that time out on some networks
+//! and not others.  trust-dns only offers `ResolverOpts::edns0` (on or off)
+//! and always advertises the same size; [`EdnsMode`] makes both tunable.
+
+use crate::transport::QueryFilter;
+use crate::transport::Upstream;
+use trust_dns_resolver::proto::op::Edns;
+use trust_dns_resolver::proto::op::Message;
+
+/// The smallest payload size EDNS allows (RFC 6891)
+pub const MIN_EDNS_PAYLOAD: u16 = 512;
+
+/// The largest payload size worth advertising
+///
+/// trust-dns reads UDP responses into a buffer of this size, so a larger
+/// response would be cut off and then discarded as malformed.
+pub const MAX_EDNS_PAYLOAD: u16 = 2048;
+
+/// The payload size recommended by DNS Flag Day 2020, which avoids
+/// fragmentation on nearly every network
+pub const DEFAULT_EDNS_PAYLOAD: u16 = 1232;
+
+/// A [`QueryFilter`] that sets how queries use EDNS
+///
+/// Use this as the last filter: filters after it could add EDNS back (as
+/// `DnsCookies` does).  Disabling EDNS also disables anything that depends
+/// on it, including DNSSEC validation.
+///
+/// ```
+/// # use reqwest_resolve::edns::EdnsMode;
+/// # use reqwest_resolve::transport::filtered_resolver;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let _resolver = filtered_resolver(
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+///     vec![Arc::new(EdnsMode::Advertise(1400))],
+/// )
+/// .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum EdnsMode {
+    /// Use EDNS on every query, advertising this UDP payload size.  Sizes
+    /// outside [`MIN_EDNS_PAYLOAD`] to [`MAX_EDNS_PAYLOAD`] are clamped into
+    /// that range.
+    Advertise(u16),
+    /// Never use EDNS.
+    Disabled,
+}
+
+impl Default for EdnsMode {
+    fn default() -> EdnsMode {
+        EdnsMode::Advertise(DEFAULT_EDNS_PAYLOAD)
+    }
+}
+
+impl QueryFilter for EdnsMode {
+    fn on_request(&self, _upstream: &Upstream, request: &mut Message) {
+        match self {
+            EdnsMode::Advertise(size) => {
+                let size = (*size).clamp(MIN_EDNS_PAYLOAD, MAX_EDNS_PAYLOAD);
+                request
+                    .extensions_mut()
+                    .get_or_insert_with(Edns::new)
+                    .set_max_payload(size);
+            }
+            EdnsMode::Disabled => {
+                *request.extensions_mut() = None;
+            }
+        }
+    }
+}
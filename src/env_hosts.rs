@@ -0,0 +1,278 @@
+//! Overriding hosts from the environment
+//!
+//! CI jobs and container entrypoints often need to point a few names
+//! somewhere else (a service at a test instance, say) without a config file
+//! or a rebuild.  [`HostOverrides::from_env`] reads the overrides from
+//! [`HOSTS_ENV_VAR`] when the program starts and answers for those names in
+//! front of another resolver:
+//!
+//! ```text
+//! REQWEST_RESOLVE_HOSTS="api.example.com=10.0.0.5:443,db.example.com=10.0.0.6"
+//! ```
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+/// The environment variable [`HostOverrides::from_env`] reads
+pub const HOSTS_ENV_VAR: &str = "REQWEST_RESOLVE_HOSTS";
+
+/// Answers for some names with fixed addresses, and passes lookups for the
+/// rest to the inner resolver
+///
+/// Overrides are written as a comma-separated list of `name=address`
+/// entries.  Addresses may be written with or without a port, as for
+/// [`static_resolver!`](crate::static_resolver) (and reqwest still connects
+/// to the port in the URL).  A name given more than once gets all of its
+/// addresses, in order.  Names are matched case-insensitively, ignoring any
+/// trailing dot.
+///
+/// ```
+/// # use reqwest_resolve::env_hosts::HostOverrides;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static DNS: StaticResolver = static_resolver! {
+///     "www.example.com" => ["192.0.2.80"],
+/// };
+/// let resolver = HostOverrides::parse(
+///     DNS,
+///     "api.example.com=10.0.0.5:443,db.example.com=10.0.0.6",
+/// )
+/// .unwrap();
+/// assert_eq!(resolver.lookup("API.example.com").len(), 1);
+/// assert!(resolver.lookup("www.example.com").is_empty());
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(resolver)));
+/// ```
+pub struct HostOverrides<R> {
+    inner: R,
+    hosts: BTreeMap<String, Vec<SocketAddr>>,
+}
+
+impl<R> HostOverrides<R> {
+    /// Parses `overrides`, failing with [`ResolveError::InvalidConfig`] if
+    /// any entry is malformed.  Empty entries are skipped.
+    pub fn parse(
+        inner: R,
+        overrides: &str,
+    ) -> Result<HostOverrides<R>, ResolveError> {
+        let mut hosts: BTreeMap<String, Vec<SocketAddr>> = BTreeMap::new();
+        for entry in overrides.split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let invalid = |message: &str| {
+                ResolveError::InvalidConfig(format!(
+                    "host override {:?}: {}",
+                    entry, message
+                ))
+            };
+            let (name, addr) =
+                entry.split_once('=').ok_or_else(|| invalid("missing '='"))?;
+            let name = name.trim().trim_end_matches('.');
+            if name.is_empty() {
+                return Err(invalid("missing name"));
+            }
+            let addr = parse_addr(addr.trim())
+                .ok_or_else(|| invalid("invalid address"))?;
+            hosts.entry(name.to_ascii_lowercase()).or_default().push(addr);
+        }
+        Ok(HostOverrides { inner, hosts })
+    }
+
+    /// Reads overrides from [`HOSTS_ENV_VAR`] (see [`HostOverrides::parse`]).
+    /// If it isn't set, there aren't any, and every lookup goes to `inner`.
+    pub fn from_env(inner: R) -> Result<HostOverrides<R>, ResolveError> {
+        let overrides = std::env::var(HOSTS_ENV_VAR).unwrap_or_default();
+        let resolver = HostOverrides::parse(inner, &overrides).map_err(
+            |error| match error {
+                ResolveError::InvalidConfig(message) => {
+                    ResolveError::InvalidConfig(format!(
+                        "{}: {}",
+                        HOSTS_ENV_VAR, message
+                    ))
+                }
+                error => error,
+            },
+        )?;
+        if !resolver.hosts.is_empty() {
+            debug!(
+                "overriding hosts from the environment",
+                names = resolver.hosts.len()
+            );
+        }
+        Ok(resolver)
+    }
+
+    /// Returns the addresses `name` is overridden with, which are empty if
+    /// it isn't.
+    pub fn lookup(&self, name: &str) -> &[SocketAddr] {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.get(&name).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl<R: MyResolve> MyResolve for HostOverrides<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        if addrs.is_empty() {
+            return self.inner.resolve(name);
+        }
+        let addrs: Vec<SocketAddr> = addrs.to_vec();
+        MyResolving::ready(Ok(Box::new(addrs.into_iter()) as Addrs))
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        if addrs.is_empty() {
+            return self.inner.resolve_detailed(name);
+        }
+        let addrs = addrs
+            .iter()
+            .map(|addr| ResolvedAddr::from_table(*addr, "env"))
+            .collect();
+        futures::future::ready(Ok(addrs)).boxed()
+    }
+}
+
+/// Parses an IP address with an optional port (0 if there isn't one), with
+/// IPv6 addresses in brackets if there's a port.
+fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = addr.parse() {
+        return Some(addr);
+    }
+    let ip = addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+    let ip: IpAddr = ip.unwrap_or(addr).parse().ok()?;
+    Some(SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostOverrides;
+    use crate::error::ResolveError;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use std::net::SocketAddr;
+
+    static DNS: StaticResolver = static_resolver! {
+        "www.example.com" => ["192.0.2.80"],
+    };
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn malformed() {
+        for overrides in [
+            "api.example.com",
+            "api.example.com 10.0.0.5",
+            "=10.0.0.5",
+            " . = 10.0.0.5",
+            "api.example.com=",
+            "api.example.com=api.internal",
+            "api.example.com=10.0.0.256",
+            "api.example.com=10.0.0.5:65536",
+            "api.example.com=10.0.0.5=10.0.0.6",
+            "api.example.com=[10.0.0.5",
+            "api.example.com=2001:db8::1:443:",
+            "api.example.com=[2001:db8::1]443",
+            "db.example.com=10.0.0.6,api.example.com",
+        ] {
+            match HostOverrides::parse(DNS, overrides) {
+                Err(ResolveError::InvalidConfig(message)) => {
+                    assert!(message.starts_with("host override"), "{}", message)
+                }
+                Err(error) => {
+                    panic!("{:?}: unexpected error {}", overrides, error)
+                }
+                Ok(_) => panic!("{:?}: parsed", overrides),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_entries() {
+        let resolver =
+            HostOverrides::parse(DNS, " , api.example.com=10.0.0.5,, ")
+                .unwrap();
+        assert_eq!(resolver.lookup("api.example.com"), addrs(&["10.0.0.5:0"]));
+        assert!(HostOverrides::parse(DNS, "").unwrap().hosts.is_empty());
+    }
+
+    /// A name given more than once gets all of its addresses, in order,
+    /// however it's written.
+    #[test]
+    fn duplicate_names() {
+        let resolver = HostOverrides::parse(
+            DNS,
+            "api.example.com=10.0.0.5, db.example.com=10.0.0.9, \
+             API.Example.com.=10.0.0.6:8443, api.example.com=10.0.0.5",
+        )
+        .unwrap();
+        assert_eq!(
+            resolver.lookup("api.example.com."),
+            addrs(&["10.0.0.5:0", "10.0.0.6:8443", "10.0.0.5:0"])
+        );
+        assert_eq!(resolver.lookup("db.example.com"), addrs(&["10.0.0.9:0"]));
+    }
+
+    #[test]
+    fn ipv6() {
+        let resolver = HostOverrides::parse(
+            DNS,
+            "v6.example.com=2001:db8::1, v6.example.com=[2001:db8::2]:8443, \
+             v6.example.com=[2001:db8::3], v6.example.com=::ffff:192.0.2.1",
+        )
+        .unwrap();
+        assert_eq!(
+            resolver.lookup("v6.example.com"),
+            addrs(&[
+                "[2001:db8::1]:0",
+                "[2001:db8::2]:8443",
+                "[2001:db8::3]:0",
+                "[::ffff:192.0.2.1]:0",
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves() {
+        let resolver = HostOverrides::parse(
+            DNS,
+            "api.example.com=10.0.0.5,www.example.com=10.0.0.80",
+        )
+        .unwrap();
+        let found = resolver.resolve_to_vec("API.example.com").await.unwrap();
+        assert_eq!(found, addrs(&["10.0.0.5:0"]));
+        let found = resolver.resolve_to_vec("www.example.com").await.unwrap();
+        assert_eq!(found, addrs(&["10.0.0.80:0"]));
+
+        let detailed = resolver
+            .resolve_detailed("api.example.com".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(detailed[0].backend, "env");
+
+        let resolver = HostOverrides::parse(DNS, "").unwrap();
+        let found = resolver.resolve_to_vec("www.example.com").await.unwrap();
+        assert_eq!(found, addrs(&["192.0.2.80:0"]));
+    }
+}
@@ -0,0 +1,173 @@
+//! Errors produced by this crate's resolvers
+
+use std::fmt;
+
+/// Describes why one of this crate's resolvers didn't produce addresses
+///
+/// The `Resolve` and `MyResolve` traits return boxed errors, so this is what
+/// you'll find if you downcast one of those that came from this crate (as
+/// opposed to one passed through from trust-dns).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ResolveError {
+    /// Every address found for the name was removed by a filter
+    AddressesFiltered { name: String },
+    /// The tenant's policy doesn't allow it to resolve the name
+    BlockedForTenant { name: String, tenant: String },
+    /// The addresses of the encrypted upstreams couldn't be found
+    BootstrapFailed(String),
+    /// The lookup checking that a new resolver works failed
+    CanaryFailed { name: String, message: String },
+    /// The lookup didn't finish before the deadline in scope
+    DeadlineExceeded { name: String },
+    /// The name should be resolved by a proxy, not locally
+    DeferredToProxy { name: String },
+    /// A global resolver was installed when there already was one
+    GlobalAlreadyInstalled,
+    /// A resolver panicked during the lookup
+    Internal { name: String, message: String },
+    /// The resolver's configuration was rejected
+    InvalidConfig(String),
+    /// A test fixture couldn't be parsed
+    InvalidFixture { line: usize, message: String },
+    /// A name isn't valid, or was rewritten into something that isn't
+    InvalidName { name: String },
+    /// Addresses (or a document listing them) weren't signed by a trusted
+    /// key, or the signature has expired
+    InvalidSignature(String),
+    /// The lookup came back around to a layer with a name that layer was
+    /// already resolving.  `chain` lists the names along the way, ending
+    /// with the one that closed the loop.
+    LookupLoop { name: String, chain: Vec<String> },
+    /// No address was returned by enough of the resolvers asked
+    NoConsensus { name: String, quorum: usize },
+    /// No addresses were found for the name
+    NotFound { name: String },
+    /// The lookup used up its budget of upstream queries
+    QueryBudgetExceeded { name: String, limit: usize },
+    /// Lookups have been switched off (see `maintenance::MaintenanceSwitch`)
+    ResolutionDisabled { name: String },
+    /// The name has a single label, and single-label names are rejected
+    /// (see `single_label::SingleLabelPolicy`)
+    SingleLabelName { name: String },
+    /// The name is under a special-use domain that's never sent to DNS
+    SpecialUse { name: String, domain: String },
+    /// The lookup passed through more layers than it was allowed to
+    TooManyRewrites { name: String, max_depth: usize },
+    /// The lookup needed a tenant, but none was in scope or the one in scope
+    /// isn't configured
+    UnknownTenant { name: String, tenant: Option<String> },
+    /// A zone couldn't be transferred (see
+    /// `zone_transfer::ZoneTransferResolver`)
+    ZoneTransferFailed { zone: String, message: String },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::AddressesFiltered { name } => {
+                write!(f, "every address found for {:?} was filtered out", name)
+            }
+            ResolveError::BlockedForTenant { name, tenant } => write!(
+                f,
+                "refusing to resolve {:?}: blocked by the policy for tenant \
+                 {:?}",
+                name, tenant
+            ),
+            ResolveError::BootstrapFailed(message) => {
+                write!(f, "bootstrapping encrypted upstreams: {}", message)
+            }
+            ResolveError::CanaryFailed { name, message } => {
+                write!(f, "canary lookup of {:?} failed: {}", name, message)
+            }
+            ResolveError::DeadlineExceeded { name } => {
+                write!(f, "deadline exceeded resolving {:?}", name)
+            }
+            ResolveError::DeferredToProxy { name } => write!(
+                f,
+                "refusing to resolve {:?} locally: it should be resolved \
+                 by the proxy",
+                name
+            ),
+            ResolveError::GlobalAlreadyInstalled => {
+                write!(f, "a global resolver is already installed")
+            }
+            ResolveError::Internal { name, message } => write!(
+                f,
+                "internal error resolving {:?}: resolver panicked: {}",
+                name, message
+            ),
+            ResolveError::InvalidConfig(message) => {
+                write!(f, "invalid resolver configuration: {}", message)
+            }
+            ResolveError::InvalidFixture { line, message } => {
+                write!(f, "invalid fixture: line {}: {}", line, message)
+            }
+            ResolveError::InvalidName { name } => {
+                write!(f, "invalid name: {:?}", name)
+            }
+            ResolveError::InvalidSignature(message) => {
+                write!(f, "invalid signature: {}", message)
+            }
+            ResolveError::LookupLoop { name, chain } => write!(
+                f,
+                "lookup of {:?} loops back on itself: {}",
+                name,
+                chain.join(" -> ")
+            ),
+            ResolveError::NoConsensus { name, quorum } => write!(
+                f,
+                "no address for {:?} was returned by at least {} resolvers",
+                name, quorum
+            ),
+            ResolveError::NotFound { name } => {
+                write!(f, "no addresses found for {:?}", name)
+            }
+            ResolveError::QueryBudgetExceeded { name, limit } => write!(
+                f,
+                "gave up resolving {:?}: it took more than {} upstream \
+                 queries",
+                name, limit
+            ),
+            ResolveError::ResolutionDisabled { name } => write!(
+                f,
+                "refusing to resolve {:?}: resolution is switched off for \
+                 maintenance",
+                name
+            ),
+            ResolveError::SingleLabelName { name } => write!(
+                f,
+                "refusing to resolve {:?}: single-label names are rejected",
+                name
+            ),
+            ResolveError::SpecialUse { name, domain } => write!(
+                f,
+                "refusing to resolve {:?}: names under special-use domain \
+                 {:?} are not looked up in DNS",
+                name, domain
+            ),
+            ResolveError::TooManyRewrites { name, max_depth } => write!(
+                f,
+                "gave up resolving {:?}: it passed through more than {} \
+                 layers",
+                name, max_depth
+            ),
+            ResolveError::UnknownTenant { name, tenant: None } => write!(
+                f,
+                "refusing to resolve {:?}: no tenant is in scope",
+                name
+            ),
+            ResolveError::UnknownTenant { name, tenant: Some(tenant) } => {
+                write!(
+                    f,
+                    "refusing to resolve {:?}: unknown tenant {:?}",
+                    name, tenant
+                )
+            }
+            ResolveError::ZoneTransferFailed { zone, message } => {
+                write!(f, "transferring zone {:?}: {}", zone, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
@@ -0,0 +1,295 @@
+//! Choosing which transports a query tries, and in what order
+//!
+//! trust-dns decides for itself how to reach a server: over UDP first, then
+//! over TCP if the answer was truncated (and the configuration lists the
+//! server for TCP too; see [`crate::tcp`]), and over TLS or HTTPS only if
+//! that's how the server is listed.  There's no way to say "try UDP, then
+//! TCP, then DNS-over-TLS", or "only ever use DNS-over-TLS, and fail rather
+//! than fall back to cleartext".
+//!
+//! An [`EscalatingTransport`] sends each query through the steps of an
+//! [`EscalationOrder`] in turn, moving on to the next step when one fails,
+//! times out, or comes back truncated.  A strict order
+//! ([`EscalationOrder::strict`]) can only contain encrypted steps, so its
+//! queries are never sent in cleartext, whatever the configuration says
+//! about the server.
+//!
+//! The transport ignores the protocol each server is listed with, so list
+//! each server once: a server listed for both UDP and TCP would get each
+//! query twice, once for each entry, when the first one fails.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use futures::future::FutureExt;
+use std::sync::Arc;
+use std::time::Duration;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::error::ResolveError as DnsError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::Time;
+use trust_dns_resolver::proto::TokioTime;
+
+/// How long each step gets, by default, before the next one is tried
+pub const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// One way of reaching a server
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TransportStep {
+    Udp,
+    Tcp,
+    /// DNS-over-TLS (RFC 7858)
+    #[cfg(feature = "dns-over-rustls")]
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484)
+    #[cfg(feature = "dns-over-https-rustls")]
+    Https,
+}
+
+impl TransportStep {
+    /// Returns whether queries sent this way are encrypted.
+    pub fn is_encrypted(self) -> bool {
+        self.protocol().is_encrypted()
+    }
+
+    fn protocol(self) -> Protocol {
+        match self {
+            TransportStep::Udp => Protocol::Udp,
+            TransportStep::Tcp => Protocol::Tcp,
+            #[cfg(feature = "dns-over-rustls")]
+            TransportStep::Tls => Protocol::Tls,
+            #[cfg(feature = "dns-over-https-rustls")]
+            TransportStep::Https => Protocol::Https,
+        }
+    }
+}
+
+/// Returns the port `protocol` uses when none is configured.
+fn default_port(protocol: Protocol) -> u16 {
+    match protocol {
+        #[cfg(feature = "dns-over-rustls")]
+        Protocol::Tls => 853,
+        #[cfg(feature = "dns-over-https-rustls")]
+        Protocol::Https => 443,
+        _ => 53,
+    }
+}
+
+/// The transports an [`EscalatingTransport`] tries, in order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EscalationOrder {
+    steps: Vec<TransportStep>,
+    strict: bool,
+}
+
+impl EscalationOrder {
+    /// Tries each of `steps` in turn, failing with
+    /// [`ResolveError::InvalidConfig`] if there aren't any.
+    pub fn new(
+        steps: &[TransportStep],
+    ) -> Result<EscalationOrder, ResolveError> {
+        if steps.is_empty() {
+            return Err(ResolveError::InvalidConfig(String::from(
+                "an escalation order needs at least one step",
+            )));
+        }
+        Ok(EscalationOrder { steps: steps.to_vec(), strict: false })
+    }
+
+    /// Tries each of `steps` in turn, and never anything unencrypted,
+    /// failing with [`ResolveError::InvalidConfig`] if any of them is.
+    ///
+    /// With the "dns-over-rustls" feature, `&[TransportStep::Tls]` is
+    /// DNS-over-TLS only, with no cleartext fallback.
+    ///
+    /// ```
+    /// # use reqwest_resolve::escalation::{EscalationOrder, TransportStep};
+    /// assert!(EscalationOrder::strict(&[TransportStep::Tcp]).is_err());
+    /// ```
+    pub fn strict(
+        steps: &[TransportStep],
+    ) -> Result<EscalationOrder, ResolveError> {
+        if let Some(step) = steps.iter().find(|step| !step.is_encrypted()) {
+            return Err(ResolveError::InvalidConfig(format!(
+                "a strict escalation order can't include {:?}, which is \
+                 unencrypted",
+                step
+            )));
+        }
+        let order = EscalationOrder::new(steps)?;
+        Ok(EscalationOrder { strict: true, ..order })
+    }
+
+    pub fn steps(&self) -> &[TransportStep] {
+        &self.steps
+    }
+
+    /// Returns whether every step is encrypted.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+}
+
+/// UDP, then TCP, which is what trust-dns does for a server listed for both
+impl Default for EscalationOrder {
+    fn default() -> EscalationOrder {
+        EscalationOrder {
+            steps: vec![TransportStep::Udp, TransportStep::Tcp],
+            strict: false,
+        }
+    }
+}
+
+/// Sends each query through the steps of an [`EscalationOrder`] in turn,
+/// until one gets a complete response
+///
+/// Each step sends the query to the same address as the server it's for,
+/// through the inner transport, as though the server had been listed for
+/// that step's protocol.  A server listed on its protocol's usual port
+/// (53, 853, or 443) is reached on the usual port for each step's, and one
+/// on some other port is reached on that port for every step.  TLS and
+/// HTTPS steps need the server's `tls_dns_name`, and are skipped for
+/// servers without one.
+///
+/// A step that fails, or doesn't answer within the step timeout (see
+/// [`EscalatingTransport::with_step_timeout`]), is followed by the next
+/// one.  A truncated response is too, unless it came from the last step, in
+/// which case it's returned as it is.  The resolver's own timeout
+/// (`ResolverOpts::timeout`) covers all of the steps, so it should be long
+/// enough for as many of them as should be tried.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, StandardTransport};
+/// # use reqwest_resolve::dns_transport::TransportDnsResolver;
+/// # use reqwest_resolve::escalation::{EscalatingTransport, EscalationOrder, TransportStep};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+/// # };
+/// // TCP right after UDP, even where UDP is dropped instead of truncated
+/// let mut config = ResolverConfig::new();
+/// config.add_name_server(NameServerConfig::new(
+///     "192.0.2.53:53".parse().unwrap(),
+///     Protocol::Udp,
+/// ));
+///
+/// let options = ResolverOpts::default();
+/// let order =
+///     EscalationOrder::new(&[TransportStep::Udp, TransportStep::Tcp])
+///         .unwrap();
+/// let transport =
+///     EscalatingTransport::new(StandardTransport::new(options), order)
+///         .with_step_timeout(Duration::from_secs(1));
+/// let resolver = transport_resolver(config, options, transport).unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct EscalatingTransport<T> {
+    inner: Arc<T>,
+    order: EscalationOrder,
+    step_timeout: Duration,
+}
+
+impl<T: DnsTransport> EscalatingTransport<T> {
+    /// Gives each step [`DEFAULT_STEP_TIMEOUT`].
+    pub fn new(inner: T, order: EscalationOrder) -> EscalatingTransport<T> {
+        EscalatingTransport {
+            inner: Arc::new(inner),
+            order,
+            step_timeout: DEFAULT_STEP_TIMEOUT,
+        }
+    }
+
+    /// Gives each step `timeout` to answer before the next one is tried.
+    pub fn with_step_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> EscalatingTransport<T> {
+        self.step_timeout = timeout;
+        self
+    }
+}
+
+/// Returns `server` as it would be listed for `step`, or nothing if it can't
+/// be reached that way.
+fn for_step(
+    server: &NameServerConfig,
+    step: TransportStep,
+) -> Option<NameServerConfig> {
+    let protocol = step.protocol();
+    if protocol.is_encrypted() && server.tls_dns_name.is_none() {
+        return None;
+    }
+    let mut config = server.clone();
+    if server.socket_addr.port() == default_port(server.protocol) {
+        config.socket_addr.set_port(default_port(protocol));
+    }
+    config.protocol = protocol;
+    Some(config)
+}
+
+impl<T: DnsTransport> DnsTransport for EscalatingTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let steps: Vec<(TransportStep, NameServerConfig)> = self
+            .order
+            .steps
+            .iter()
+            .filter_map(|step| Some((*step, for_step(server, *step)?)))
+            .collect();
+        let Some(last) = steps.len().checked_sub(1) else {
+            debug!(
+                "no escalation step can reach upstream",
+                upstream = server.socket_addr,
+                strict = self.order.strict,
+            );
+            let error = ResolveErrorKind::Message(
+                "no escalation step can reach this server (it has no TLS \
+                 name)",
+            );
+            return futures::future::err(error.into()).boxed();
+        };
+        let inner = Arc::clone(&self.inner);
+        let step_timeout = self.step_timeout;
+
+        async move {
+            let mut last_error = None;
+            for (i, (step, config)) in steps.into_iter().enumerate() {
+                // Each step is only sent once the one before it has failed.
+                let send = inner.send(&config, query.clone());
+                let error: DnsError =
+                    match TokioTime::timeout(step_timeout, send).await {
+                        Ok(Ok(response))
+                            if response.truncated() && i < last =>
+                        {
+                            ResolveErrorKind::Message("truncated response")
+                                .into()
+                        }
+                        Ok(Ok(response)) => return Ok(response),
+                        Ok(Err(error)) => error,
+                        Err(_) => ResolveErrorKind::Timeout.into(),
+                    };
+                debug!(
+                    "transport step failed",
+                    upstream = config.socket_addr,
+                    step = step,
+                    error = error,
+                );
+                last_error = Some(error);
+            }
+            Err(last_error.expect("escalation order has no steps"))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,82 @@
+//! Sending one query over a socket and reading back its response
+//!
+//! The transports that need control over their sockets (which port, which
+//! interface, which network namespace) can't use trust-dns's connections, so
+//! they make their own sockets and exchange messages over them with these.
+
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::config::NameServerConfig;
+
+/// The largest UDP response accepted
+pub(crate) const MAX_UDP_RESPONSE: usize = 4096;
+
+/// Returns the address to bind to for queries to `server`: its `bind_addr`,
+/// or else the unspecified address in its address family.
+pub(crate) fn local_addr(server: &NameServerConfig) -> SocketAddr {
+    server.bind_addr.unwrap_or_else(|| {
+        let ip = match server.socket_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        SocketAddr::new(ip, 0)
+    })
+}
+
+/// Returns the ID of the DNS message in `bytes`.
+pub(crate) fn id_of(bytes: &[u8]) -> Option<u16> {
+    match bytes {
+        [high, low, ..] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+/// Sends `query` (whose ID is `id`) to `server` from `socket`, and returns
+/// the response.
+pub(crate) async fn exchange_udp(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    id: u16,
+    query: &[u8],
+) -> io::Result<Vec<u8>> {
+    socket.connect(server).await?;
+    socket.send(query).await?;
+
+    // Skip anything that isn't the response to this query (like a late
+    // response to an earlier one that used the same port).
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        if id_of(&buf[..len]) == Some(id) {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+/// Connects `socket` to `server`, sends `query`, and returns the response.
+#[cfg(any(feature = "bind-device", feature = "netns"))]
+pub(crate) async fn exchange_tcp(
+    socket: tokio::net::TcpSocket,
+    server: SocketAddr,
+    query: &[u8],
+) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = socket.connect(server).await?;
+    let len = u16::try_from(query.len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "query too large")
+    })?;
+    stream.write_u16(len).await?;
+    stream.write_all(query).await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
@@ -0,0 +1,332 @@
+//! Falling back to the system resolver when the backend is broken
+//!
+//! A bug in trust-dns (or in a layer of this crate) that makes every lookup
+//! panic or fail shouldn't take down every outbound request the process
+//! makes.  [`SystemFallback`] watches its inner resolver for panics and for
+//! errors that mean the backend itself is in trouble, and when it sees them,
+//! resolves names with the operating system's resolver (`getaddrinfo`, on
+//! Unix) for a while instead.  That's slower, since each lookup ties up a
+//! thread from tokio's blocking pool, and it ignores everything the stack
+//! was configured to do, but requests keep working until someone gets to
+//! fix the stack.
+
+use crate::logging::debug;
+use crate::logging::warning;
+use crate::panics::panic_message;
+use crate::system::lookup_system;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use crate::ResolvedAddr;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// How many backend failures in a row [`SystemFallback::new`] puts up with
+/// before falling back
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+/// How long [`SystemFallback::new`] uses the system resolver once it's
+/// fallen back, before trying the backend again
+pub const DEFAULT_FALLBACK_PERIOD: Duration = Duration::from_secs(60);
+
+type LookupResult<T> = Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// Whether the backend is being trusted with lookups right now
+struct Health {
+    /// backend failures since the last lookup that didn't fail that way
+    failures: u32,
+    /// when to go back to the backend, while falling back
+    falling_back_until: Option<Instant>,
+}
+
+/// The counters and state shared between a resolver and its handles
+struct Counters {
+    panics: AtomicU64,
+    backend_failures: AtomicU64,
+    system_lookups: AtomicU64,
+    health: Mutex<Health>,
+}
+
+impl Counters {
+    /// Returns whether lookups should go to the system resolver for now.
+    fn falling_back(&self) -> bool {
+        let mut health = self.health.lock().unwrap();
+        match health.falling_back_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                debug!("trying the resolver backend again");
+                health.falling_back_until = None;
+                health.failures = 0;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Reads the counters of a [`SystemFallback`]
+#[derive(Clone)]
+pub struct FallbackCounters {
+    counters: Arc<Counters>,
+}
+
+impl FallbackCounters {
+    pub fn snapshot(&self) -> FallbackCounts {
+        let counters = &self.counters;
+        FallbackCounts {
+            panics: counters.panics.load(Ordering::Relaxed),
+            backend_failures: counters.backend_failures.load(Ordering::Relaxed),
+            system_lookups: counters.system_lookups.load(Ordering::Relaxed),
+            falling_back: counters
+                .health
+                .lock()
+                .unwrap()
+                .falling_back_until
+                .is_some_and(|until| Instant::now() < until),
+        }
+    }
+}
+
+/// What a [`SystemFallback`] has seen since it was created
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct FallbackCounts {
+    /// lookups in which the backend panicked
+    pub panics: u64,
+    /// lookups that failed because of the backend rather than the name
+    pub backend_failures: u64,
+    /// lookups sent to the system resolver
+    pub system_lookups: u64,
+    /// whether lookups are going to the system resolver right now
+    pub falling_back: bool,
+}
+
+/// Resolves names with the system resolver when the inner resolver panics
+/// or keeps failing
+///
+/// A panic in the inner resolver is caught, and that lookup and every
+/// lookup for the next `period` go to the system resolver.  So do the
+/// lookups for `period` after `threshold` backend failures in a row:
+/// failures that say nothing about the name, like trust-dns having no
+/// working connections to any server or failing to handle a message.
+/// Timeouts and names that don't exist aren't backend failures, since the
+/// system resolver would most likely say the same.  After `period`, lookups
+/// go back to the inner resolver.
+///
+/// Each switch to the system resolver is logged as a warning, and
+/// [`SystemFallback::counters`] counts them for metrics.
+///
+/// ```
+/// # use reqwest_resolve::fallback::SystemFallback;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = SystemFallback::new(MyCustomDnsResolver::new(resolver));
+/// let counters = my_resolver.counters();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// // Later, from a metrics exporter:
+/// assert_eq!(counters.snapshot().panics, 0);
+/// ```
+pub struct SystemFallback<R> {
+    inner: R,
+    threshold: u32,
+    period: Duration,
+    counters: Arc<Counters>,
+}
+
+impl<R> SystemFallback<R> {
+    /// Falls back after [`DEFAULT_FAILURE_THRESHOLD`] failures in a row,
+    /// for [`DEFAULT_FALLBACK_PERIOD`].
+    pub fn new(inner: R) -> SystemFallback<R> {
+        SystemFallback::with_policy(
+            inner,
+            DEFAULT_FAILURE_THRESHOLD,
+            DEFAULT_FALLBACK_PERIOD,
+        )
+    }
+
+    /// Falls back after `threshold` failures in a row (at least 1), for
+    /// `period`.
+    pub fn with_policy(
+        inner: R,
+        threshold: u32,
+        period: Duration,
+    ) -> SystemFallback<R> {
+        SystemFallback {
+            inner,
+            threshold: threshold.max(1),
+            period,
+            counters: Arc::new(Counters {
+                panics: AtomicU64::new(0),
+                backend_failures: AtomicU64::new(0),
+                system_lookups: AtomicU64::new(0),
+                health: Mutex::new(Health {
+                    failures: 0,
+                    falling_back_until: None,
+                }),
+            }),
+        }
+    }
+
+    /// Returns a handle for reading the counters, which keeps working after
+    /// the resolver has been handed to reqwest.
+    pub fn counters(&self) -> FallbackCounters {
+        FallbackCounters { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Runs the inner resolver's `lookup` unless it's being avoided,
+    /// returning `None` if the system resolver should be used instead.
+    async fn try_backend<T, F, Fut>(
+        &self,
+        name: &str,
+        lookup: F,
+    ) -> Option<LookupResult<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = LookupResult<T>>,
+    {
+        if self.counters.falling_back() {
+            return None;
+        }
+
+        // Start the lookup inside `catch_unwind` too: a layer can just as
+        // well panic when building its future as when polling it.
+        let result = AssertUnwindSafe(async move { lookup().await })
+            .catch_unwind()
+            .await;
+        let counters = &self.counters;
+        let mut health = counters.health.lock().unwrap();
+        match result {
+            Ok(Err(error)) if is_backend_failure(&*error) => {
+                counters.backend_failures.fetch_add(1, Ordering::Relaxed);
+                health.failures += 1;
+                if health.failures < self.threshold {
+                    return Some(Err(error));
+                }
+                warning!(
+                    "resolver backend keeps failing; using the system \
+                     resolver",
+                    name = name,
+                    failures = health.failures,
+                    error = error,
+                    period = self.period,
+                );
+                health.falling_back_until =
+                    Instant::now().checked_add(self.period);
+                None
+            }
+            Ok(result) => {
+                health.failures = 0;
+                Some(result)
+            }
+            Err(panic) => {
+                counters.panics.fetch_add(1, Ordering::Relaxed);
+                warning!(
+                    "resolver backend panicked; using the system resolver",
+                    name = name,
+                    panic = panic_message(&*panic),
+                    period = self.period,
+                );
+                health.falling_back_until =
+                    Instant::now().checked_add(self.period);
+                None
+            }
+        }
+    }
+
+    async fn resolve_system(
+        &self,
+        name: &str,
+    ) -> LookupResult<Vec<SocketAddr>> {
+        self.counters.system_lookups.fetch_add(1, Ordering::Relaxed);
+        lookup_system(name).await
+    }
+}
+
+impl<R: MyResolve> MyResolve for SystemFallback<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let backend = self.try_backend(name.as_str(), || {
+                self.inner.resolve(name.clone())
+            });
+            match backend.await {
+                Some(result) => result,
+                None => {
+                    let addrs = self.resolve_system(name.as_str()).await?;
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                }
+            }
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let backend = self.try_backend(name.as_str(), || {
+                self.inner.resolve_detailed(name.clone())
+            });
+            match backend.await {
+                Some(result) => result,
+                None => {
+                    let addrs = self.resolve_system(name.as_str()).await?;
+                    Ok(addrs
+                        .into_iter()
+                        .map(|addr| ResolvedAddr {
+                            backend: Cow::Borrowed("system"),
+                            ..ResolvedAddr::unknown(addr)
+                        })
+                        .collect())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Returns whether `error` is the backend's fault rather than the name's.
+#[cfg(feature = "trust-dns")]
+fn is_backend_failure(error: &(dyn StdError + 'static)) -> bool {
+    match error.downcast_ref::<trust_dns_resolver::error::ResolveError>() {
+        Some(error) => matches!(
+            error.kind(),
+            ResolveErrorKind::NoConnections
+                | ResolveErrorKind::Msg(_)
+                | ResolveErrorKind::Message(_)
+                | ResolveErrorKind::Proto(_)
+        ),
+        None => false,
+    }
+}
+
+/// Without trust-dns, nothing reports a failure as the backend's.
+#[cfg(not(feature = "trust-dns"))]
+fn is_backend_failure(_error: &(dyn StdError + 'static)) -> bool {
+    false
+}
@@ -0,0 +1,384 @@
+//! Turning captured DNS answers into test fixtures
+//!
+//! The easiest way to get realistic answers for a test is to capture real
+//! ones: run `dig` against the name, or copy the relevant lines out of a zone
+//! file.  [`Fixture::parse`] reads either format and keeps the addresses, so
+//! a test can resolve against exactly what production DNS returned on the
+//! day it was captured.  A fixture can be used directly as a resolver, or
+//! written out with [`Fixture::to_static_resolver`] as a
+//! [`static_resolver!`](crate::static_resolver) invocation to paste into a
+//! test.
+
+use crate::error::ResolveError;
+use crate::resolved::ResolvedAddr;
+use crate::special_use::normalize;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+/// How many CNAMEs in a row [`Fixture::parse`] will follow
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Names and the addresses they resolve to, usually parsed from `dig` output
+/// or a zone file
+///
+/// The input is a series of resource records in zone-file syntax, which is
+/// also what `dig` prints (without `+short`).  Comments, blank lines, and
+/// `$TTL` directives are skipped, `$ORIGIN` and "@" work as they do in zone
+/// files, and a record that starts with whitespace belongs to the previous
+/// record's name.  Only A, AAAA, and CNAME records are used.  A name with a
+/// CNAME gets the addresses at the end of its chain, if the input has them.
+///
+/// ```
+/// # use reqwest_resolve::fixtures::Fixture;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// let fixture = Fixture::parse(
+///     r#"
+/// ;; ANSWER SECTION:
+/// www.example.com.    300   IN  CNAME  edge.example.net.
+/// edge.example.net.   60    IN  A      192.0.2.10
+/// edge.example.net.   60    IN  AAAA   2001:db8::10
+/// "#,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(fixture.lookup("WWW.example.com").len(), 2);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(fixture)));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Fixture {
+    hosts: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl Fixture {
+    pub fn new() -> Fixture {
+        Fixture::default()
+    }
+
+    /// Parses `dig` output or zone-file records.
+    pub fn parse(text: &str) -> Result<Fixture, ResolveError> {
+        let mut fixture = Fixture::new();
+        fixture.add_records(text)?;
+        Ok(fixture)
+    }
+
+    /// Parses more records (see [`Fixture::parse`]) and adds their addresses
+    /// to the ones already here.
+    ///
+    /// CNAMEs can point at names added by earlier calls.
+    pub fn add_records(&mut self, text: &str) -> Result<(), ResolveError> {
+        let mut parser = Parser::default();
+        for (number, line) in text.lines().enumerate() {
+            parser.line(number + 1, line)?;
+        }
+        parser.finish()?;
+
+        for (name, addr) in parser.addrs {
+            self.add(name, addr);
+        }
+        for name in parser.cnames.keys() {
+            let mut target = name;
+            let mut hops = 0;
+            while let Some(next) = parser.cnames.get(target) {
+                target = next;
+                hops += 1;
+                if hops > MAX_CNAME_CHAIN {
+                    break;
+                }
+            }
+            if let Some(addrs) = self.hosts.get(target).cloned() {
+                for addr in addrs {
+                    self.add(name.clone(), addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds addresses for `name`, after any it already has.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) {
+        for addr in addrs {
+            self.add(normalize(name).into_owned(), addr);
+        }
+    }
+
+    fn add(&mut self, name: String, addr: IpAddr) {
+        let addrs = self.hosts.entry(name).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// Returns the addresses for `name`, which are empty if it isn't in the
+    /// fixture.  Names are matched case-insensitively, ignoring any trailing
+    /// dot.
+    pub fn lookup(&self, name: &str) -> &[IpAddr] {
+        self.hosts.get(&*normalize(name)).map_or(&[], |addrs| addrs.as_slice())
+    }
+
+    /// Iterates over the names in the fixture and their addresses, sorted by
+    /// name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[IpAddr])> + '_ {
+        self.hosts.iter().map(|(name, addrs)| (name.as_str(), addrs.as_slice()))
+    }
+
+    /// Returns a [`static_resolver!`](crate::static_resolver) invocation with
+    /// the same names and addresses.
+    ///
+    /// ```
+    /// # use reqwest_resolve::fixtures::Fixture;
+    /// let fixture = Fixture::parse("api.test. 60 IN A 10.0.0.1").unwrap();
+    /// assert_eq!(
+    ///     fixture.to_static_resolver(),
+    ///     "static_resolver! {\n    \"api.test\" => [\"10.0.0.1\"],\n}\n",
+    /// );
+    /// ```
+    pub fn to_static_resolver(&self) -> String {
+        let mut source = String::from("static_resolver! {\n");
+        for (name, addrs) in &self.hosts {
+            let addrs: Vec<_> = addrs
+                .iter()
+                .map(|addr| format!("{:?}", static_addr(addr)))
+                .collect();
+            writeln!(source, "    {:?} => [{}],", name, addrs.join(", "))
+                .unwrap();
+        }
+        source.push_str("}\n");
+        source
+    }
+}
+
+/// Formats `addr` in a form `parse_socket_addr` accepts, which excludes IPv6
+/// addresses with an embedded IPv4 address ("::ffff:10.0.0.1").
+fn static_addr(addr: &IpAddr) -> String {
+    let formatted = addr.to_string();
+    match addr {
+        IpAddr::V6(v6) if formatted.contains('.') => v6
+            .segments()
+            .iter()
+            .map(|segment| format!("{:x}", segment))
+            .collect::<Vec<_>>()
+            .join(":"),
+        _ => formatted,
+    }
+}
+
+/// State for parsing one batch of records
+#[derive(Default)]
+struct Parser {
+    origin: Option<String>,
+    last_owner: Option<String>,
+    /// a record split across lines with parentheses, and the line it started
+    pending: Option<(usize, String)>,
+    addrs: Vec<(String, IpAddr)>,
+    cnames: BTreeMap<String, String>,
+}
+
+impl Parser {
+    fn line(&mut self, number: usize, line: &str) -> Result<(), ResolveError> {
+        let line = strip_comment(line);
+        if let Some((start, mut record)) = self.pending.take() {
+            record.push(' ');
+            record.push_str(line);
+            if line.contains(')') {
+                return self.record(start, &record);
+            }
+            self.pending = Some((start, record));
+            return Ok(());
+        }
+
+        if line.contains('(') && !line.contains(')') {
+            self.pending = Some((number, line.to_owned()));
+            return Ok(());
+        }
+        self.record(number, line)
+    }
+
+    fn finish(&self) -> Result<(), ResolveError> {
+        match &self.pending {
+            Some((start, _)) => Err(invalid(*start, "unclosed '(' in record")),
+            None => Ok(()),
+        }
+    }
+
+    fn record(
+        &mut self,
+        number: usize,
+        line: &str,
+    ) -> Result<(), ResolveError> {
+        let line = line.replace(['(', ')'], " ");
+        let mut tokens = line.split_whitespace().peekable();
+        let Some(&first) = tokens.peek() else {
+            return Ok(());
+        };
+
+        if first.starts_with('$') {
+            tokens.next();
+            return match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let Some(origin) = tokens.next() else {
+                        return Err(invalid(number, "$ORIGIN needs a name"));
+                    };
+                    self.origin = Some(self.absolute(number, origin)?);
+                    Ok(())
+                }
+                "$TTL" => Ok(()),
+                _ => Err(invalid(
+                    number,
+                    format!("unsupported directive {:?}", first),
+                )),
+            };
+        }
+
+        // A record that starts with whitespace has no owner of its own.
+        let owner = if line.starts_with(char::is_whitespace) {
+            match &self.last_owner {
+                Some(owner) => owner.clone(),
+                None => {
+                    return Err(invalid(
+                        number,
+                        "record has no name and there's no previous one",
+                    ))
+                }
+            }
+        } else {
+            tokens.next();
+            self.absolute(number, first)?
+        };
+        self.last_owner = Some(owner.clone());
+
+        // The TTL and class are both optional, in either order.
+        let rtype = loop {
+            let Some(token) = tokens.next() else {
+                return Err(invalid(number, "record has no type"));
+            };
+            let is_ttl = token.starts_with(|c: char| c.is_ascii_digit());
+            let is_class = ["IN", "CH", "HS", "CS"]
+                .iter()
+                .any(|class| token.eq_ignore_ascii_case(class));
+            if !is_ttl && !is_class {
+                break token.to_ascii_uppercase();
+            }
+        };
+
+        let rdata = tokens.next();
+        match (rtype.as_str(), rdata) {
+            ("A", Some(rdata)) => {
+                let addr = rdata.parse::<Ipv4Addr>().map_err(|_| {
+                    invalid(number, format!("invalid IPv4 address {:?}", rdata))
+                })?;
+                self.addrs.push((owner, IpAddr::V4(addr)));
+            }
+            ("AAAA", Some(rdata)) => {
+                let addr = rdata.parse::<Ipv6Addr>().map_err(|_| {
+                    invalid(number, format!("invalid IPv6 address {:?}", rdata))
+                })?;
+                self.addrs.push((owner, IpAddr::V6(addr)));
+            }
+            ("CNAME", Some(rdata)) => {
+                let target = self.absolute(number, rdata)?;
+                self.cnames.insert(owner, target);
+            }
+            ("A" | "AAAA" | "CNAME", None) => {
+                return Err(invalid(
+                    number,
+                    format!("{} record has no data", rtype),
+                ))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Returns the normalized form of `name`, which is relative to the
+    /// current origin unless it ends with a dot.
+    fn absolute(
+        &self,
+        number: usize,
+        name: &str,
+    ) -> Result<String, ResolveError> {
+        if name == "@" {
+            return match &self.origin {
+                Some(origin) => Ok(origin.clone()),
+                None => Err(invalid(number, "\"@\" used without an $ORIGIN")),
+            };
+        }
+        match &self.origin {
+            Some(origin) if !name.ends_with('.') && !origin.is_empty() => {
+                Ok(normalize(&format!("{}.{}", name, origin)).into_owned())
+            }
+            _ => Ok(normalize(name).into_owned()),
+        }
+    }
+}
+
+/// Removes a ';' comment, ignoring any ';' in a quoted string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn invalid(line: usize, message: impl Into<String>) -> ResolveError {
+    ResolveError::InvalidFixture { line, message: message.into() }
+}
+
+impl MyResolve for Fixture {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            let addrs: AddrList =
+                addrs.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        };
+        MyResolving::ready(result)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            Ok(addrs
+                .iter()
+                .map(|ip| {
+                    ResolvedAddr::from_table(SocketAddr::new(*ip, 0), "fixture")
+                })
+                .collect())
+        };
+        futures::future::ready(result).boxed()
+    }
+}
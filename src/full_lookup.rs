@@ -0,0 +1,103 @@
+//! Getting at everything trust-dns found for a lookup
+//!
+//! `resolve` boils a lookup down to a list of addresses, which is all
+//! reqwest wants.  Some callers want the rest of what came back: the
+//! records themselves (with their names, so a CNAME chain can be seen),
+//! their TTLs, and when the answer stops being valid.  Without a way to get
+//! it from the resolver the client uses, they'd have to run a second
+//! resolver and look every name up twice, with no guarantee that the two
+//! agree.  [`CustomDnsResolver::resolve_full`] (and the same on
+//! `MyCustomDnsResolver`) returns the addresses `resolve` would, alongside
+//! trust-dns's own [`Lookup`].
+
+use crate::lookup_ip;
+use crate::ordered_ips;
+use crate::CustomDnsResolver;
+use crate::IpList;
+use crate::MyCustomDnsResolver;
+use reqwest::dns::Addrs;
+use std::net::SocketAddr;
+use std::time::Instant;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::lookup_ip::LookupIp;
+
+/// The addresses from a lookup, and the full answer they came from
+///
+/// ```no_run
+/// # use reqwest_resolve::MyCustomDnsResolver;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # async fn f() -> Result<(), trust_dns_resolver::error::ResolveError> {
+/// let resolver = MyCustomDnsResolver::new(
+///     TokioAsyncResolver::tokio(
+///         ResolverConfig::default(),
+///         ResolverOpts::default(),
+///     )
+///     .unwrap(),
+/// );
+/// let full = resolver.resolve_full("www.example.com".parse().unwrap()).await?;
+/// for record in full.lookup().record_iter() {
+///     println!("{} {} {:?}", record.name(), record.ttl(), record.data());
+/// }
+/// let _addrs = full.addrs();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FullLookup {
+    ips: IpList,
+    lookup: LookupIp,
+}
+
+impl FullLookup {
+    fn new(lookup: LookupIp) -> FullLookup {
+        FullLookup { ips: ordered_ips(&lookup), lookup }
+    }
+
+    /// Returns the addresses, as `resolve` would have returned them (in the
+    /// same order, with the ports all 0).
+    pub fn addrs(&self) -> Addrs {
+        let ips = self.ips.clone();
+        Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)))
+    }
+
+    /// Returns trust-dns's answer, with every record it found (including
+    /// any CNAMEs on the way to the addresses).
+    pub fn lookup(&self) -> &Lookup {
+        self.lookup.as_lookup()
+    }
+
+    /// Returns when the answer stops being valid, which is when the record
+    /// with the shortest TTL expires.
+    pub fn valid_until(&self) -> Instant {
+        self.lookup.valid_until()
+    }
+
+    pub fn into_lookup(self) -> Lookup {
+        self.lookup.into()
+    }
+}
+
+impl CustomDnsResolver {
+    /// Looks up `name` as `resolve` does, returning the whole answer
+    ///
+    /// The error is trust-dns's, which says more than the boxed one
+    /// `resolve` returns.
+    pub async fn resolve_full(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> Result<FullLookup, ResolveError> {
+        Ok(FullLookup::new(lookup_ip(&self.resolver, &name).await?))
+    }
+}
+
+impl MyCustomDnsResolver {
+    /// The same as [`CustomDnsResolver::resolve_full`]
+    pub async fn resolve_full(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> Result<FullLookup, ResolveError> {
+        Ok(FullLookup::new(lookup_ip(&self.resolver, &name).await?))
+    }
+}
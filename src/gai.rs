@@ -0,0 +1,647 @@
+//! Ordering addresses the way an administrator's gai.conf says
+//!
+//! glibc's `getaddrinfo` sorts the addresses it returns with the rules of
+//! RFC 6724, and `/etc/gai.conf` lets administrators change the tables
+//! those rules use: which prefixes are preferred over which (the
+//! "precedence" table), which source and destination prefixes belong
+//! together (the "label" table), and how IPv4 addresses are scoped.  Some
+//! enterprises rely on that, to prefer IPv4 on networks with poor IPv6
+//! transit or to keep traffic for internal prefixes on internal paths.
+//! trust-dns doesn't sort addresses this way, so programs that switch to it
+//! from the system resolver quietly stop honoring the file.
+//!
+//! [`GaiConfig`] reads the file (or takes the same syntax from anywhere
+//! else), and [`GaiSorter`] sorts each lookup's addresses with it, as
+//! glibc would.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where glibc reads its configuration from
+pub const GAI_CONF_PATH: &str = "/etc/gai.conf";
+
+/// A prefix (in IPv6 form, with IPv4 addresses mapped) and the value a
+/// table gives the addresses in it
+type Entry = (Ipv6Addr, u8, u32);
+
+/// RFC 6724's default policy table, as (prefix, length, precedence, label)
+const DEFAULT_POLICY: &[(Ipv6Addr, u8, u32, u32)] = &[
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 128, 50, 0),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 40, 1),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96, 35, 4),
+    (Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16, 30, 2),
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 32, 5, 5),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7, 3, 13),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 96, 1, 3),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10, 1, 11),
+    (Ipv6Addr::new(0x3ffe, 0, 0, 0, 0, 0, 0, 0), 16, 1, 12),
+];
+
+/// glibc's default scopes for IPv4 addresses (link-local and loopback are
+/// link-local, and everything else is global)
+const DEFAULT_SCOPEV4: &[Entry] = &[
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xa9fe, 0), 112, 2),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x7f00, 0), 104, 2),
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96, 14),
+];
+
+/// The scope of global addresses
+const SCOPE_GLOBAL: u32 = 14;
+
+/// The tables RFC 6724's address selection rules use
+///
+/// A configuration starts out with RFC 6724's tables.  Parsing a file
+/// replaces each table that it has any lines for, entirely, the way glibc
+/// does: a file with a single "precedence" line has a precedence table
+/// with only that line in it.  (glibc's own built-in tables are the older
+/// ones from RFC 3484, so a file that means to adjust them should list
+/// them all.)  Addresses are looked up in each table by their longest
+/// matching prefix, with IPv4 addresses as IPv4-mapped IPv6 ones.
+///
+/// ```
+/// # use reqwest_resolve::gai::GaiConfig;
+/// // gai.conf's own example for preferring IPv4: glibc's built-in table,
+/// // with IPv4 moved above everything else.
+/// let config = GaiConfig::parse(
+///     "precedence ::1/128       50
+///      precedence ::/0          40
+///      precedence 2002::/16     30
+///      precedence ::/96         20
+///      precedence ::ffff:0:0/96 100",
+/// )
+/// .unwrap();
+/// assert_eq!(config.precedence("192.0.2.1".parse().unwrap()), 100);
+/// assert_eq!(config.precedence("2001:db8::1".parse().unwrap()), 40);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GaiConfig {
+    precedence: Vec<Entry>,
+    label: Vec<Entry>,
+    scopev4: Vec<Entry>,
+}
+
+impl Default for GaiConfig {
+    fn default() -> GaiConfig {
+        let table = |value: fn(&(Ipv6Addr, u8, u32, u32)) -> u32| {
+            sorted(
+                DEFAULT_POLICY
+                    .iter()
+                    .map(|entry| (entry.0, entry.1, value(entry)))
+                    .collect(),
+            )
+        };
+        GaiConfig {
+            precedence: table(|entry| entry.2),
+            label: table(|entry| entry.3),
+            scopev4: sorted(DEFAULT_SCOPEV4.to_vec()),
+        }
+    }
+}
+
+/// Puts `table`'s most specific prefixes first, so the first match is the
+/// longest.
+fn sorted(mut table: Vec<Entry>) -> Vec<Entry> {
+    table.sort_by_key(|(_, len, _)| Reverse(*len));
+    table
+}
+
+/// Returns `ip` as an IPv6 address, mapping IPv4 addresses.
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn mask(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
+
+/// Returns the value of the first entry of `table` whose prefix holds `ip`.
+fn lookup(table: &[Entry], ip: Ipv6Addr) -> Option<u32> {
+    let bits = u128::from(ip);
+    table
+        .iter()
+        .find(|(prefix, len, _)| bits & mask(*len) == u128::from(*prefix))
+        .map(|(_, _, value)| *value)
+}
+
+/// Parses a prefix like "2001:db8::/32" or (for IPv4 scopes)
+/// "169.254.0.0/16", with the host bits cleared.
+fn parse_prefix(prefix: &str, ipv4: bool) -> Option<(Ipv6Addr, u8)> {
+    let (addr, len) = match prefix.split_once('/') {
+        Some((addr, len)) => (addr, Some(len.parse::<u8>().ok()?)),
+        None => (prefix, None),
+    };
+    let (addr, len) = match addr.parse::<IpAddr>().ok()? {
+        IpAddr::V4(addr) if ipv4 => {
+            (addr.to_ipv6_mapped(), 96 + len.unwrap_or(32).min(32))
+        }
+        IpAddr::V4(_) => return None,
+        IpAddr::V6(addr) => (addr, len.unwrap_or(128).min(128)),
+    };
+    if ipv4 && (len < 96 || addr.to_ipv4_mapped().is_none()) {
+        return None;
+    }
+    Some((Ipv6Addr::from(u128::from(addr) & mask(len)), len))
+}
+
+impl GaiConfig {
+    /// Parses `contents`, in the syntax of `gai.conf(5)`, failing with
+    /// [`ResolveError::InvalidConfig`] at the first line that isn't valid
+    ///
+    /// "label", "precedence", and "scopev4" lines are used.  "reload" lines
+    /// are accepted and ignored, since the configuration is only read when
+    /// it's parsed.
+    pub fn parse(contents: &str) -> Result<GaiConfig, ResolveError> {
+        let mut precedence = Vec::new();
+        let mut label = Vec::new();
+        let mut scopev4 = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let invalid = |message: &str| {
+                ResolveError::InvalidConfig(format!(
+                    "gai.conf line {}: {}",
+                    number + 1,
+                    message
+                ))
+            };
+            let table = match fields.first() {
+                None => continue,
+                Some(&"reload") => continue,
+                Some(&"precedence") => &mut precedence,
+                Some(&"label") => &mut label,
+                Some(&"scopev4") => &mut scopev4,
+                Some(keyword) => {
+                    return Err(invalid(&format!("unknown {:?}", keyword)));
+                }
+            };
+            let [_, prefix, value] = fields[..] else {
+                return Err(invalid("expected a prefix and a value"));
+            };
+            let ipv4 = fields[0] == "scopev4";
+            let (prefix, len) = parse_prefix(prefix, ipv4)
+                .ok_or_else(|| invalid("invalid prefix"))?;
+            let value = value.parse().map_err(|_| invalid("invalid value"))?;
+            table.push((prefix, len, value));
+        }
+
+        let mut config = GaiConfig::default();
+        for (table, parsed) in [
+            (&mut config.precedence, precedence),
+            (&mut config.label, label),
+            (&mut config.scopev4, scopev4),
+        ] {
+            if !parsed.is_empty() {
+                *table = sorted(parsed);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads and parses the file at `path`.  A file that doesn't exist
+    /// means the default tables, as it does for glibc.
+    pub fn from_file(
+        path: impl AsRef<Path>,
+    ) -> Result<GaiConfig, ResolveError> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => GaiConfig::parse(&contents),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Ok(GaiConfig::default())
+            }
+            Err(error) => Err(ResolveError::InvalidConfig(format!(
+                "reading {}: {}",
+                path.display(),
+                error
+            ))),
+        }
+    }
+
+    /// Reads the system's configuration, from [`GAI_CONF_PATH`].
+    pub fn system() -> Result<GaiConfig, ResolveError> {
+        GaiConfig::from_file(GAI_CONF_PATH)
+    }
+
+    pub fn precedence(&self, ip: IpAddr) -> u32 {
+        lookup(&self.precedence, to_v6(ip)).unwrap_or(0)
+    }
+
+    pub fn label(&self, ip: IpAddr) -> u32 {
+        lookup(&self.label, to_v6(ip)).unwrap_or(0)
+    }
+
+    /// Returns `ip`'s scope, as RFC 6724 (section 3.1) numbers them: 2 for
+    /// link-local, 5 for site-local, and 14 for global, for example.
+    pub fn scope(&self, ip: IpAddr) -> u32 {
+        let ip = to_v6(ip);
+        if ip.to_ipv4_mapped().is_some() {
+            return lookup(&self.scopev4, ip).unwrap_or(SCOPE_GLOBAL);
+        }
+        let first = ip.segments()[0];
+        if ip.is_multicast() {
+            u32::from(first & 0x000f)
+        } else if ip.is_loopback() || (first & 0xffc0) == 0xfe80 {
+            2
+        } else if (first & 0xffc0) == 0xfec0 {
+            5
+        } else {
+            SCOPE_GLOBAL
+        }
+    }
+
+    /// Sorts `addrs` by RFC 6724's destination address selection rules,
+    /// with the source address the system would use for each one
+    ///
+    /// Sources are found the way glibc finds them, by connecting a UDP
+    /// socket to each address (which sends nothing), and addresses there's
+    /// no route to go last.  Rules 3 (deprecated addresses), 4 (home
+    /// addresses) and 7 (native transport) need more than that says, and
+    /// aren't applied.
+    pub fn sort(&self, addrs: &mut [SocketAddr]) {
+        self.sort_with_sources(addrs, source_for);
+    }
+
+    /// Sorts `addrs` as [`GaiConfig::sort`] does, with the source address
+    /// `source` returns for each one (or `None` if it can't be reached),
+    /// for callers that already know which source they'll connect from.
+    ///
+    /// ```
+    /// # use reqwest_resolve::gai::GaiConfig;
+    /// # use std::net::{IpAddr, SocketAddr};
+    /// let mut addrs: Vec<SocketAddr> = vec![
+    ///     "192.0.2.1:443".parse().unwrap(),
+    ///     "[2001:db8::1]:443".parse().unwrap(),
+    /// ];
+    /// // A host with both families; by default, IPv6 goes first.
+    /// let source = |addr: &SocketAddr| -> Option<IpAddr> {
+    ///     Some(if addr.is_ipv4() { "192.0.2.100" } else { "2001:db8::100" }
+    ///         .parse()
+    ///         .unwrap())
+    /// };
+    /// GaiConfig::default().sort_with_sources(&mut addrs, source);
+    /// assert!(addrs[0].is_ipv6());
+    ///
+    /// let prefer_ipv4 =
+    ///     GaiConfig::parse("precedence ::ffff:0:0/96 100").unwrap();
+    /// prefer_ipv4.sort_with_sources(&mut addrs, source);
+    /// assert!(addrs[0].is_ipv4());
+    /// ```
+    pub fn sort_with_sources<F>(&self, addrs: &mut [SocketAddr], source: F)
+    where
+        F: Fn(&SocketAddr) -> Option<IpAddr>,
+    {
+        let mut candidates: Vec<Candidate> = addrs
+            .iter()
+            .map(|addr| Candidate::new(self, *addr, source(addr)))
+            .collect();
+        // `sort_by` is stable, which is rule 10.
+        candidates.sort_by(Candidate::compare);
+        for (addr, candidate) in addrs.iter_mut().zip(candidates) {
+            *addr = candidate.addr;
+        }
+    }
+}
+
+/// Returns the source address the system would use to reach `addr`.
+fn source_for(addr: &SocketAddr) -> Option<IpAddr> {
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).ok()?;
+    let mut addr = *addr;
+    if addr.port() == 0 {
+        // Any port will do, since nothing is sent, but it can't be 0.
+        addr.set_port(9);
+    }
+    socket.connect(addr).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// A destination, with what the rules compare about it
+struct Candidate {
+    addr: SocketAddr,
+    /// the source address's scope and label, if there's a source
+    source: Option<(u32, u32)>,
+    scope: u32,
+    label: u32,
+    precedence: u32,
+    /// how many leading bits the destination shares with its source (for
+    /// IPv6 destinations only, and at most 64)
+    common_prefix: Option<u32>,
+}
+
+impl Candidate {
+    fn new(
+        config: &GaiConfig,
+        addr: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> Candidate {
+        let ip = addr.ip();
+        let common_prefix = match (to_v6(ip), source.map(to_v6)) {
+            (dst, Some(src))
+                if dst.to_ipv4_mapped().is_none()
+                    && src.to_ipv4_mapped().is_none() =>
+            {
+                let shared =
+                    (u128::from(dst) ^ u128::from(src)).leading_zeros();
+                Some(shared.min(64))
+            }
+            _ => None,
+        };
+        Candidate {
+            addr,
+            source: source.map(|src| (config.scope(src), config.label(src))),
+            scope: config.scope(ip),
+            label: config.label(ip),
+            precedence: config.precedence(ip),
+            common_prefix,
+        }
+    }
+
+    /// Orders two destinations, the preferred one first.
+    fn compare(a: &Candidate, b: &Candidate) -> Ordering {
+        let by_source = match (a.source, b.source) {
+            (Some(a_source), Some(b_source)) => {
+                // Rule 2: prefer matching scope.
+                let a_scope = a.scope == a_source.0;
+                let b_scope = b.scope == b_source.0;
+                // Rule 5: prefer matching label.
+                let a_label = a.label == a_source.1;
+                let b_label = b.label == b_source.1;
+                b_scope.cmp(&a_scope).then(b_label.cmp(&a_label))
+            }
+            // Neither can be reached, but the rules that only look at the
+            // destinations still order them.
+            (None, None) => Ordering::Equal,
+            // Rule 1: avoid unusable destinations.
+            _ => return b.source.is_some().cmp(&a.source.is_some()),
+        };
+        by_source
+            // Rule 6: prefer higher precedence.
+            .then(b.precedence.cmp(&a.precedence))
+            // Rule 8: prefer smaller scope.
+            .then(a.scope.cmp(&b.scope))
+            // Rule 9: use the longest matching prefix.  The RFC only
+            // compares IPv6 destinations this way, but those go ahead of
+            // the ones without a prefix here, so that the order stays total
+            // when a custom table ties IPv4 with IPv6.
+            .then(b.common_prefix.cmp(&a.common_prefix))
+    }
+}
+
+/// Sorts the inner resolver's addresses by a [`GaiConfig`]
+///
+/// Sorting finds the source address for each destination with a few
+/// system calls (see [`GaiConfig::sort`]), but doesn't wait on the
+/// network.  The order only matters to clients that try addresses in the
+/// order they're given: reqwest does, within each address family, but it
+/// decides which family goes first by the first address alone, so the
+/// precedence table decides that too.
+///
+/// ```no_run
+/// # use reqwest_resolve::gai::{GaiConfig, GaiSorter};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "www.example.com" => ["2001:db8::80", "192.0.2.80"],
+/// };
+/// let config = GaiConfig::system().unwrap();
+/// let my_resolver = GaiSorter::new(HOSTS, config);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct GaiSorter<R> {
+    inner: R,
+    config: Arc<GaiConfig>,
+}
+
+impl<R> GaiSorter<R> {
+    pub fn new(inner: R, config: GaiConfig) -> GaiSorter<R> {
+        GaiSorter { inner, config: Arc::new(config) }
+    }
+
+    pub fn config(&self) -> &GaiConfig {
+        &self.config
+    }
+}
+
+impl<R: MyResolve> MyResolve for GaiSorter<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let mut addrs: Vec<SocketAddr> =
+                self.inner.resolve(name.clone()).await?.collect();
+            self.config.sort(&mut addrs);
+            debug!("sorted addresses", name = name.as_str(), addrs = addrs);
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let mut resolved = self.inner.resolve_detailed(name).await?;
+            let mut addrs: Vec<SocketAddr> =
+                resolved.iter().map(|resolved| resolved.addr).collect();
+            self.config.sort(&mut addrs);
+            // Addresses are unique within a lookup, except by accident, so
+            // putting each one back where its address went is enough.
+            resolved.sort_by_key(|resolved| {
+                addrs.iter().position(|addr| *addr == resolved.addr)
+            });
+            Ok(resolved)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GaiConfig;
+    use std::net::IpAddr;
+    use std::net::SocketAddr;
+
+    /// Sorts `destinations` with the default tables, each one reached from
+    /// the source it's paired with in `sources` (or unreachable)
+    fn sorted(destinations: &[&str], sources: &[(&str, &str)]) -> Vec<IpAddr> {
+        sorted_with(&GaiConfig::default(), destinations, sources)
+    }
+
+    /// Sorts `destinations` as [`sorted`] does, with `config`'s tables
+    fn sorted_with(
+        config: &GaiConfig,
+        destinations: &[&str],
+        sources: &[(&str, &str)],
+    ) -> Vec<IpAddr> {
+        let mut addrs: Vec<SocketAddr> = destinations
+            .iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 443))
+            .collect();
+        let source = |addr: &SocketAddr| {
+            sources.iter().find_map(|(destination, source)| {
+                (destination.parse::<IpAddr>().unwrap() == addr.ip())
+                    .then(|| source.parse().unwrap())
+            })
+        };
+        config.sort_with_sources(&mut addrs, source);
+        addrs.iter().map(SocketAddr::ip).collect()
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    /// The destination address selection examples from RFC 6724, section
+    /// 10.2, except the ones for rules this crate doesn't apply (home and
+    /// deprecated addresses).  The sources are the ones the RFC's results
+    /// use for each destination.
+    #[test]
+    fn rfc6724_examples() {
+        // Prefer matching scope.
+        assert_eq!(
+            sorted(
+                &["198.51.100.121", "2001:db8:1::1"],
+                &[
+                    ("2001:db8:1::1", "2001:db8:1::2"),
+                    ("198.51.100.121", "169.254.13.78"),
+                ],
+            ),
+            ips(&["2001:db8:1::1", "198.51.100.121"]),
+        );
+        assert_eq!(
+            sorted(
+                &["2001:db8:1::1", "198.51.100.121"],
+                &[
+                    ("2001:db8:1::1", "fe80::1"),
+                    ("198.51.100.121", "198.51.100.117"),
+                ],
+            ),
+            ips(&["198.51.100.121", "2001:db8:1::1"]),
+        );
+        // Prefer higher precedence.
+        assert_eq!(
+            sorted(
+                &["10.1.2.3", "2001:db8:1::1"],
+                &[("2001:db8:1::1", "2001:db8:1::2"), ("10.1.2.3", "10.1.2.4")],
+            ),
+            ips(&["2001:db8:1::1", "10.1.2.3"]),
+        );
+        // Prefer smaller scope.
+        assert_eq!(
+            sorted(
+                &["2001:db8:1::1", "fe80::1"],
+                &[("2001:db8:1::1", "2001:db8:1::2"), ("fe80::1", "fe80::2")],
+            ),
+            ips(&["fe80::1", "2001:db8:1::1"]),
+        );
+        // Prefer matching label.
+        assert_eq!(
+            sorted(
+                &["2001:db8:1::1", "2002:c633:6401::1"],
+                &[
+                    ("2002:c633:6401::1", "2002:c633:6401::2"),
+                    ("2001:db8:1::1", "2002:c633:6401::2"),
+                ],
+            ),
+            ips(&["2002:c633:6401::1", "2001:db8:1::1"]),
+        );
+        // Prefer higher precedence.
+        assert_eq!(
+            sorted(
+                &["2002:c633:6401::1", "2001:db8:1::1"],
+                &[
+                    ("2001:db8:1::1", "2001:db8:1::2"),
+                    ("2002:c633:6401::1", "2002:c633:6401::2"),
+                ],
+            ),
+            ips(&["2001:db8:1::1", "2002:c633:6401::1"]),
+        );
+        // Prefer smaller scope.
+        assert_eq!(
+            sorted(
+                &["2001:db8:1::1", "2002:c633:6401::1", "fe80::2"],
+                &[
+                    ("fe80::2", "fe80::1"),
+                    ("2001:db8:1::1", "2001:db8:1::2"),
+                    ("2002:c633:6401::1", "2002:c633:6401::2"),
+                ],
+            ),
+            ips(&["fe80::2", "2001:db8:1::1", "2002:c633:6401::1"]),
+        );
+    }
+
+    /// Unreachable destinations go last, and are still ordered by the rules
+    /// that don't need a source.
+    #[test]
+    fn unreachable() {
+        assert_eq!(
+            sorted(
+                &["192.0.2.1", "2001:db8::1", "198.51.100.1"],
+                &[("198.51.100.1", "198.51.100.2")],
+            ),
+            ips(&["198.51.100.1", "2001:db8::1", "192.0.2.1"]),
+        );
+        assert_eq!(
+            sorted(&["2001:db8::1", "fe80::1", "192.0.2.1"], &[]),
+            ips(&["fe80::1", "2001:db8::1", "192.0.2.1"]),
+        );
+    }
+
+    /// With a table that gives IPv4 and IPv6 the same precedence, IPv6
+    /// destinations sharing a longer prefix with their source still go
+    /// first, wherever the IPv4 ones started out.
+    #[test]
+    fn mixed_family_tie() {
+        let config = GaiConfig::parse(
+            "precedence ::/0 40\nprecedence ::ffff:0:0/96 40\n",
+        )
+        .unwrap();
+        let sources = [
+            ("2001:db8::1", "2001:db8::2"),
+            ("2001:db8:ffff::1", "2001:db8::2"),
+            ("192.0.2.1", "192.0.2.2"),
+        ];
+        let expected = ips(&["2001:db8::1", "2001:db8:ffff::1", "192.0.2.1"]);
+        assert_eq!(
+            sorted_with(
+                &config,
+                &["2001:db8:ffff::1", "192.0.2.1", "2001:db8::1"],
+                &sources,
+            ),
+            expected,
+        );
+        assert_eq!(
+            sorted_with(
+                &config,
+                &["192.0.2.1", "2001:db8::1", "2001:db8:ffff::1"],
+                &sources,
+            ),
+            expected,
+        );
+    }
+}
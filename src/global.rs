@@ -0,0 +1,265 @@
+//! A resolver stack shared by the whole process
+//!
+//! A program usually wants every reqwest client it makes (including the ones
+//! made inside other libraries) to resolve names the same way: same servers,
+//! same cache, same policies.  Passing one stack around to all of them isn't
+//! always possible, so the program can [`install_global`] it once at
+//! startup, and anything that builds a client can pick it up with
+//! [`global`].  Handles are `Arc`s, so taking one is cheap, and every client
+//! using one shares the stack's cache.
+//!
+//! The global resolver can only be installed once.  Tests that need a
+//! different one use [`override_global`], which swaps it for as long as the
+//! returned guard lives, or [`with_task_override`], which answers every
+//! lookup made on one task with a resolver of their own (including the
+//! lookups of clients that were built with some other resolver, as long as
+//! they go through this crate).
+
+use crate::error::ResolveError;
+use crate::panics::isolate;
+use crate::MyResolve;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+
+static GLOBAL: RwLock<Option<Arc<dyn MyResolve>>> = RwLock::new(None);
+
+/// Held by whichever test is overriding the global resolver
+static OVERRIDE: Mutex<()> = Mutex::new(());
+
+tokio::task_local! {
+    static TASK_OVERRIDE: Arc<dyn MyResolve>;
+}
+
+/// Installs `resolver` as the process's global resolver
+///
+/// This fails with [`ResolveError::GlobalAlreadyInstalled`] if one has
+/// already been installed.
+///
+/// ```
+/// # use reqwest_resolve::global::{global, install_global};
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// install_global(MyCustomDnsResolver::new(resolver)).unwrap();
+///
+/// // Anywhere else in the program:
+/// let mut builder = reqwest::ClientBuilder::new();
+/// if let Some(stack) = global() {
+///     builder = builder.dns_resolver(Arc::new(ResolveAdapter::from_arc(stack)));
+/// }
+/// ```
+pub fn install_global<R: MyResolve + 'static>(
+    resolver: R,
+) -> Result<(), ResolveError> {
+    install_global_arc(Arc::new(resolver))
+}
+
+/// Like [`install_global`], for a stack that's already shared
+pub fn install_global_arc(
+    resolver: Arc<dyn MyResolve>,
+) -> Result<(), ResolveError> {
+    let mut global = GLOBAL.write().unwrap();
+    if global.is_some() {
+        return Err(ResolveError::GlobalAlreadyInstalled);
+    }
+    *global = Some(resolver);
+    Ok(())
+}
+
+/// Returns a handle to the global resolver, if one has been installed, or
+/// to the task's override (see [`with_task_override`]) if there is one.
+pub fn global() -> Option<Arc<dyn MyResolve>> {
+    task_override().or_else(|| GLOBAL.read().unwrap().clone())
+}
+
+/// Replaces the global resolver with `resolver` until the returned guard is
+/// dropped, when the one before (or none) is put back
+///
+/// This is meant for tests.  Overrides wait for each other, so tests that
+/// run in parallel can each override the global resolver without seeing one
+/// another's.  Handles taken with [`global`] before the override keep using
+/// the resolver they got, so clients have to be built after overriding.
+///
+/// ```
+/// # use reqwest_resolve::global::{global, override_global};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["127.0.0.1"],
+/// };
+///
+/// let _guard = override_global(HOSTS);
+/// assert!(global().is_some());
+/// ```
+pub fn override_global<R: MyResolve + 'static>(resolver: R) -> GlobalOverride {
+    // A test that panicked while holding the lock already put the previous
+    // resolver back when its guard was dropped.
+    let lock = OVERRIDE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let previous = GLOBAL.write().unwrap().replace(Arc::new(resolver));
+    GlobalOverride { previous, _lock: lock }
+}
+
+/// Puts the global resolver back the way it was before [`override_global`]
+/// when dropped
+#[must_use = "the override ends as soon as this is dropped"]
+pub struct GlobalOverride {
+    previous: Option<Arc<dyn MyResolve>>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Drop for GlobalOverride {
+    fn drop(&mut self) {
+        let mut global =
+            GLOBAL.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *global = self.previous.take();
+    }
+}
+
+/// Runs `future` with `resolver` answering the lookups made on its task
+///
+/// This is meant for tests of code that builds its own reqwest clients,
+/// somewhere a test can't reach to give them a different resolver.  While
+/// `future` is being polled, every `reqwest::dns::Resolve` in this crate
+/// (`ResolveAdapter`, `handle::ResolverHandle`, `CustomDnsResolver`,
+/// `cache::CachingResolver`, and the rest), this crate's hyper connectors,
+/// and [`global`] send every lookup to `resolver` instead of the stack they
+/// were given, whenever that stack was built.  Unlike [`override_global`],
+/// the override only applies to the task, so tests running in parallel on
+/// different tasks can each have their own, without waiting for each other.
+///
+/// Clients that don't resolve through this crate (like one built with
+/// reqwest's own resolver) aren't affected.  As with the other task-local
+/// settings in this crate, a connection that hyper finishes in the
+/// background resolves without the override.
+///
+/// ```
+/// # use reqwest_resolve::global::with_task_override;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static PRODUCTION: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// static MOCK: StaticResolver = static_resolver! {
+///     "api.example.com" => ["127.0.0.1"],
+/// };
+/// // Deep inside some library:
+/// let adapter = Arc::new(ResolveAdapter::new(PRODUCTION));
+/// let lookup = async move {
+///     let name = "api.example.com".parse().unwrap();
+///     let addrs = reqwest::dns::Resolve::resolve(&*adapter, name);
+///     addrs.await.unwrap().collect::<Vec<_>>()
+/// };
+///
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let addrs = with_task_override(MOCK, lookup).await;
+/// assert_eq!(addrs, ["127.0.0.1:0".parse().unwrap()]);
+/// # });
+/// ```
+pub async fn with_task_override<R, F>(resolver: R, future: F) -> F::Output
+where
+    R: MyResolve + 'static,
+    F: Future,
+{
+    TASK_OVERRIDE.scope(Arc::new(resolver), future).await
+}
+
+/// Returns the task's override (see [`with_task_override`]), if any.
+pub fn task_override() -> Option<Arc<dyn MyResolve>> {
+    TASK_OVERRIDE.try_with(Arc::clone).ok()
+}
+
+/// Answers a lookup made through one of this crate's `reqwest::dns::Resolve`
+/// impls: with the task's override, if there is one, and otherwise with
+/// `lookup`.  Either way, a panic fails the lookup rather than the task.
+pub(crate) async fn overridable<F, Fut>(
+    name: hyper::client::connect::dns::Name,
+    lookup: F,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>>
+where
+    F: FnOnce(hyper::client::connect::dns::Name) -> Fut,
+    Fut: Future<Output = Result<Addrs, Box<dyn StdError + Send + Sync>>>,
+{
+    let host = name.as_str().to_owned();
+    match task_override() {
+        Some(resolver) => isolate(&host, || resolver.resolve(name)).await,
+        None => isolate(&host, || lookup(name)).await,
+    }
+}
+
+#[cfg(all(test, any(feature = "blocking", feature = "trust-dns")))]
+mod tests {
+    use super::with_task_override;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use std::net::SocketAddr;
+
+    static MOCK: StaticResolver = static_resolver! {
+        "api.example.com" => ["127.0.0.1"],
+    };
+
+    async fn lookup(resolver: &dyn reqwest::dns::Resolve) -> Vec<SocketAddr> {
+        let name = "api.example.com".parse().unwrap();
+        let addrs = with_task_override(MOCK, resolver.resolve(name)).await;
+        addrs.unwrap().collect()
+    }
+
+    /// The resolvers that implement reqwest's trait directly (rather than
+    /// through `ResolveAdapter`) answer with the task's override too.
+    #[cfg(feature = "trust-dns")]
+    #[tokio::test]
+    async fn direct_resolvers_use_the_override() {
+        use crate::cache::CachingResolver;
+        use crate::CustomDnsResolver;
+        use trust_dns_resolver::config::ResolverConfig;
+        use trust_dns_resolver::config::ResolverOpts;
+        use trust_dns_resolver::TokioAsyncResolver;
+
+        // Nothing's listening here, so only the override can answer.
+        let unreachable = || {
+            let mut config = ResolverConfig::new();
+            config.add_name_server(
+                trust_dns_resolver::config::NameServerConfig::new(
+                    "127.0.0.1:9".parse().unwrap(),
+                    trust_dns_resolver::config::Protocol::Udp,
+                ),
+            );
+            TokioAsyncResolver::tokio(config, ResolverOpts::default()).unwrap()
+        };
+        let expected: Vec<SocketAddr> = vec!["127.0.0.1:0".parse().unwrap()];
+        let custom = CustomDnsResolver::new(unreachable());
+        assert_eq!(lookup(&custom).await, expected);
+        let caching = CachingResolver::new(unreachable());
+        assert_eq!(lookup(&caching).await, expected);
+        assert!(caching.entries().is_empty());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn blocking_resolver_uses_the_override() {
+        use crate::blocking::BlockingResolver;
+
+        static REAL: StaticResolver = static_resolver! {
+            "api.example.com" => ["192.0.2.10"],
+        };
+        let blocking = BlockingResolver::new(REAL).unwrap();
+        let runtime =
+            tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let addrs = runtime.block_on(lookup(&blocking));
+        assert_eq!(addrs, ["127.0.0.1:0".parse().unwrap()]);
+    }
+}
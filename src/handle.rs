@@ -0,0 +1,151 @@
+//! Sharing one resolver between many clients
+//!
+//! A resolver stack owns state that every client using it should share: a
+//! cache, connections to upstream servers, background tasks.  A
+//! [`ResolverHandle`] is a cheap-to-clone reference to such a stack (the
+//! "core"), which can be handed to as many reqwest clients and other
+//! subsystems as need it, without each of them wrapping the stack in an
+//! `Arc` of its own.  The core is dropped, and its background tasks stopped,
+//! when the last handle is.
+
+use crate::context::ResolveContext;
+use crate::global::overridable;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::sync::Arc;
+
+/// A cheap-to-clone handle to a shared resolver core
+///
+/// A handle is both a `MyResolve` and a reqwest `Resolve`, so it can be used
+/// as the innermost resolver of another stack or given straight to a client
+/// (see [`ResolverHandle::client_builder`]).  With the "hyper-util"
+/// feature, it's also a resolver for hyper 1.x connectors (see `hyper1`).
+/// [`ResolverHandle::core`] gets at the core itself, for whatever it offers
+/// besides lookups.
+///
+/// `ResolverHandle<R>` knows the type of its core.  A plain
+/// `ResolverHandle` can hold any core (see [`ResolverHandle::erase`]), which
+/// is what to store when different parts of a program may be given different
+/// stacks.
+///
+/// ```
+/// # use reqwest_resolve::audit::AuditResolver;
+/// # use reqwest_resolve::handle::ResolverHandle;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// let handle = ResolverHandle::new(AuditResolver::new(HOSTS));
+/// let _api_client = handle.client_builder().build().unwrap();
+/// let _webhook_client = handle.client_builder().build().unwrap();
+///
+/// // Both clients' lookups are recorded in the same log.
+/// let log = handle.core().log();
+/// assert!(log.entries().is_empty());
+///
+/// // A subsystem that doesn't care what the core is can take a plain handle.
+/// let _plain: ResolverHandle = handle.clone().erase();
+/// ```
+pub struct ResolverHandle<R: ?Sized = dyn MyResolve> {
+    core: Arc<R>,
+}
+
+impl<R> ResolverHandle<R> {
+    pub fn new(core: R) -> ResolverHandle<R> {
+        ResolverHandle { core: Arc::new(core) }
+    }
+}
+
+impl<R: MyResolve + 'static> ResolverHandle<R> {
+    /// Returns a handle to the same core that doesn't know its type.
+    pub fn erase(self) -> ResolverHandle {
+        ResolverHandle { core: self.core }
+    }
+}
+
+impl<R: ?Sized> ResolverHandle<R> {
+    /// Makes a handle for a core that's already behind an `Arc`, like the
+    /// one [`global::global`](crate::global::global) returns.
+    pub fn from_arc(core: Arc<R>) -> ResolverHandle<R> {
+        ResolverHandle { core }
+    }
+
+    pub fn core(&self) -> &R {
+        &self.core
+    }
+
+    /// Returns whether `self` and `other` are handles to the same core.
+    pub fn same_core(&self, other: &ResolverHandle<R>) -> bool {
+        Arc::ptr_eq(&self.core, &other.core)
+    }
+}
+
+impl<R: MyResolve + ?Sized + 'static> ResolverHandle<R> {
+    /// Returns a `ClientBuilder` that resolves names with this handle's
+    /// core.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        reqwest::ClientBuilder::new().dns_resolver(Arc::new(self.clone()))
+    }
+}
+
+// Deriving `Clone` would require the core to be `Clone` too.
+impl<R: ?Sized> Clone for ResolverHandle<R> {
+    fn clone(&self) -> ResolverHandle<R> {
+        ResolverHandle { core: Arc::clone(&self.core) }
+    }
+}
+
+impl<R: ?Sized> From<Arc<R>> for ResolverHandle<R> {
+    fn from(core: Arc<R>) -> ResolverHandle<R> {
+        ResolverHandle::from_arc(core)
+    }
+}
+
+impl<R: MyResolve + ?Sized> MyResolve for ResolverHandle<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        self.core.resolve(name)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        self.core.resolve_with_port(name, port)
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &ResolveContext,
+    ) -> MyResolving<'_> {
+        self.core.resolve_with_context(name, context)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        self.core.resolve_detailed(name)
+    }
+}
+
+/// This works just like `ResolveAdapter`.
+impl<R: MyResolve + ?Sized + 'static> reqwest::dns::Resolve
+    for ResolverHandle<R>
+{
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let core = self.core.clone();
+        async move { overridable(name, |name| core.resolve(name)).await }
+            .boxed()
+    }
+}
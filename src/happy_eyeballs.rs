@@ -0,0 +1,298 @@
+//! Racing connections to a name's IPv4 and IPv6 addresses
+//!
+//! A name with both IPv4 and IPv6 addresses is only as reachable as the
+//! first address tried, if connections are made one at a time: a host on a
+//! network with broken IPv6 waits out a connection timeout for every IPv6
+//! address before it gets to an IPv4 one.  Happy Eyeballs (RFC 8305) is
+//! what clients do instead: they interleave the two families, start a
+//! connection to the first address, start one to the next if the first
+//! hasn't connected within a short delay (or as soon as it fails), and so
+//! on, and keep whichever connects first.
+//!
+//! [`connect`] does that for a list of addresses, like the ones a
+//! `MyResolve` returns, and [`HappyEyeballsConnector`] is a hyper connector
+//! that resolves names through a resolver stack and connects to them that
+//! way.
+//!
+//! reqwest (as of the version this crate builds on) can't be given a
+//! connector of its own: its connector takes the addresses from the
+//! resolver it's given and races the two families itself, less eagerly
+//! (one attempt per family, the second starting after 300ms).  So the
+//! connector is for programs that build their own hyper clients, and
+//! [`connect`] for ones that make their own connections.
+
+use crate::context::ResolveContext;
+use crate::error::ResolveError;
+use crate::global::task_override;
+use crate::handle::ResolverHandle;
+use crate::logging::debug;
+use crate::panics::isolate;
+use crate::MyResolve;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::stream::FuturesUnordered;
+use futures::stream::StreamExt;
+use hyper::Uri;
+use std::error::Error as StdError;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long, by default, each connection attempt gets before the next one
+/// starts (RFC 8305's recommended "Connection Attempt Delay")
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The shortest delay between attempts that's used, whatever's configured
+/// (RFC 8305 says it shouldn't be less than 10ms)
+const MIN_ATTEMPT_DELAY: Duration = Duration::from_millis(10);
+
+/// How connections are raced
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HappyEyeballs {
+    attempt_delay: Duration,
+    prefer_ipv4: bool,
+}
+
+impl HappyEyeballs {
+    /// Tries IPv6 addresses first, and starts a new attempt every
+    /// [`DEFAULT_ATTEMPT_DELAY`].
+    pub fn new() -> HappyEyeballs {
+        HappyEyeballs::default()
+    }
+
+    /// Starts a new attempt every `delay` (or 10ms, if that's longer)
+    /// while none of the ones already started has connected.
+    pub fn with_attempt_delay(mut self, delay: Duration) -> HappyEyeballs {
+        self.attempt_delay = delay.max(MIN_ATTEMPT_DELAY);
+        self
+    }
+
+    /// Tries IPv4 addresses first, for networks where IPv6 is known to be
+    /// the less reliable of the two.
+    pub fn preferring_ipv4(mut self) -> HappyEyeballs {
+        self.prefer_ipv4 = true;
+        self
+    }
+
+    pub fn attempt_delay(&self) -> Duration {
+        self.attempt_delay
+    }
+
+    /// Returns `addrs` in the order they're tried: alternating between the
+    /// two families, starting with the preferred one, and otherwise in the
+    /// order they were given (RFC 8305, section 4).
+    pub fn order(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (mut first, mut second): (Vec<SocketAddr>, Vec<_>) =
+            addrs.iter().partition(|addr| addr.is_ipv6());
+        if self.prefer_ipv4 || first.is_empty() {
+            std::mem::swap(&mut first, &mut second);
+        }
+        let mut ordered = Vec::with_capacity(addrs.len());
+        let mut first = first.into_iter();
+        let mut second = second.into_iter();
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (one, other) => ordered.extend(one.into_iter().chain(other)),
+            }
+        }
+        ordered
+    }
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> HappyEyeballs {
+        HappyEyeballs {
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            prefer_ipv4: false,
+        }
+    }
+}
+
+/// Connects to whichever of `addrs` connects first, racing them as
+/// `config` says
+///
+/// Attempts are started in the order [`HappyEyeballs::order`] gives, each
+/// one when the attempt delay has passed since the one before it started,
+/// or as soon as that one has failed.  Once one connects, the others are
+/// abandoned.  If none of them connects, this fails with the last
+/// attempt's error.
+///
+/// ```no_run
+/// # use reqwest_resolve::happy_eyeballs::{connect, HappyEyeballs};
+/// # use reqwest_resolve::MyResolve;
+/// # async fn example(
+/// #     my_resolver: impl MyResolve,
+/// # ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let lookup = my_resolver.resolve_with_port("example.com".parse()?, 443);
+/// let addrs: Vec<_> = lookup.await?.collect();
+/// let _stream = connect(&addrs, &HappyEyeballs::new()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect(
+    addrs: &[SocketAddr],
+    config: &HappyEyeballs,
+) -> io::Result<TcpStream> {
+    let mut remaining = config.order(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = remaining.next() {
+            debug!("starting connection attempt", addr = addr);
+            attempts
+                .push(async move { (addr, TcpStream::connect(addr).await) });
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        // Wait for an attempt to finish, or (if there's another address
+        // to try) for the delay that starts the next one to pass.
+        let finished = if remaining.peek().is_some() {
+            let delay = config.attempt_delay;
+            match tokio::time::timeout(delay, attempts.next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+        match finished {
+            Some((addr, Ok(stream))) => {
+                debug!("connected", addr = addr);
+                return Ok(stream);
+            }
+            Some((addr, Err(error))) => {
+                debug!("connection attempt failed", addr = addr, error = error);
+                last_error = Some(error);
+            }
+            None => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no addresses to connect to",
+        )
+    }))
+}
+
+/// A hyper connector that looks names up through a resolver stack, and
+/// races connections to their addresses
+///
+/// URIs are connected to on their port, or 443 for "https" URIs and 80 for
+/// others without one, unless the resolver returns addresses with ports of
+/// their own.  Names are looked up with `MyResolve::resolve_with_context`,
+/// with the URI's scheme and port, and hosts that are IP addresses aren't
+/// looked up at all.
+/// Lookups have the same protection from panics (and the same task-local
+/// override) that `ResolveAdapter` gives (see
+/// [`error::ResolveError::Internal`](crate::error::ResolveError::Internal)).
+///
+/// This makes plain TCP connections, so it's for "http" URIs as it is, and
+/// for "https" ones wrapped in a TLS connector (like `hyper-rustls`'s,
+/// which can wrap any connector).
+///
+/// ```
+/// # use reqwest_resolve::happy_eyeballs::{HappyEyeballs, HappyEyeballsConnector};
+/// # use reqwest_resolve::handle::ResolverHandle;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.internal" => ["2001:db8::10", "192.0.2.10"],
+/// };
+/// let handle = ResolverHandle::new(HOSTS);
+/// let connector = HappyEyeballsConnector::new(handle, HappyEyeballs::new());
+/// let _client = hyper::Client::builder().build::<_, hyper::Body>(connector);
+/// ```
+pub struct HappyEyeballsConnector<R: ?Sized = dyn MyResolve> {
+    handle: ResolverHandle<R>,
+    config: HappyEyeballs,
+}
+
+impl<R: ?Sized> HappyEyeballsConnector<R> {
+    pub fn new(
+        handle: ResolverHandle<R>,
+        config: HappyEyeballs,
+    ) -> HappyEyeballsConnector<R> {
+        HappyEyeballsConnector { handle, config }
+    }
+}
+
+impl<R: ?Sized> Clone for HappyEyeballsConnector<R> {
+    fn clone(&self) -> HappyEyeballsConnector<R> {
+        HappyEyeballsConnector {
+            handle: self.handle.clone(),
+            config: self.config,
+        }
+    }
+}
+
+impl<R: MyResolve + ?Sized + 'static> hyper::service::Service<Uri>
+    for HappyEyeballsConnector<R>
+{
+    type Response = TcpStream;
+    type Error = Box<dyn StdError + Send + Sync>;
+    type Future = BoxFuture<'static, Result<TcpStream, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        async move {
+            let host = uri.host().unwrap_or_default();
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                let name = uri.to_string();
+                return Err(ResolveError::InvalidName { name }.into());
+            }
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+
+            let addrs = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![SocketAddr::new(ip, port)],
+                Err(_) => {
+                    let Ok(name) =
+                        hyper::client::connect::dns::Name::from_str(host)
+                    else {
+                        let name = host.to_owned();
+                        return Err(ResolveError::InvalidName { name }.into());
+                    };
+                    let mut context = ResolveContext::new().with_port(port);
+                    if let Some(scheme) = uri.scheme_str() {
+                        context = context.with_scheme(scheme);
+                    }
+                    let addrs = match task_override() {
+                        Some(resolver) => {
+                            let lookup = || {
+                                resolver.resolve_with_context(name, &context)
+                            };
+                            isolate(host, lookup).await?
+                        }
+                        None => {
+                            let handle = &connector.handle;
+                            let lookup =
+                                || handle.resolve_with_context(name, &context);
+                            isolate(host, lookup).await?
+                        }
+                    };
+                    addrs.collect()
+                }
+            };
+            Ok(connect(&addrs, &connector.config).await?)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,247 @@
+//! Asking a second upstream when the first is slow
+//!
+//! Most queries are answered quickly, but every so often an upstream drops
+//! one or takes much longer than usual, and the lookup waits for it.
+//! Asking every upstream every time would hide that, at the cost of doubling
+//! the traffic to them.  [`HedgedTransport`] only asks a second upstream
+//! when the first hasn't answered by the time most queries would have been
+//! (by default, its 95th percentile), so only the slowest few percent of
+//! queries are sent twice.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::latency::Latencies;
+use crate::logging::debug;
+use futures::future::Either;
+use futures::future::FutureExt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::Time;
+use trust_dns_resolver::proto::TokioTime;
+
+/// How long a [`HedgedTransport`] waits for the first upstream before asking
+/// a second one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HedgeThreshold {
+    /// always the same time
+    Fixed(Duration),
+    /// the given percentile (between 0 and 1) of the first upstream's recent
+    /// latencies, kept between `min` and `max`
+    ///
+    /// Until an upstream has answered enough queries to say, this is `max`,
+    /// which also wins if it's less than `min`.
+    Percentile { percentile: f64, min: Duration, max: Duration },
+}
+
+impl Default for HedgeThreshold {
+    /// the 95th percentile, between 10ms and 1s
+    fn default() -> HedgeThreshold {
+        HedgeThreshold::Percentile {
+            percentile: 0.95,
+            min: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A snapshot of the counters kept by a [`HedgedTransport`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct HedgeCounts {
+    /// queries sent through the transport
+    pub queries: u64,
+    /// queries that were also sent to a second upstream
+    pub hedged: u64,
+    /// hedged queries that the second upstream answered first
+    pub hedges_won: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    queries: AtomicU64,
+    hedged: AtomicU64,
+    hedges_won: AtomicU64,
+}
+
+/// Reads the counters of a [`HedgedTransport`]
+#[derive(Clone)]
+pub struct HedgeCounters {
+    counters: Arc<Counters>,
+}
+
+impl HedgeCounters {
+    pub fn snapshot(&self) -> HedgeCounts {
+        let counters = &self.counters;
+        HedgeCounts {
+            queries: counters.queries.load(Ordering::Relaxed),
+            hedged: counters.hedged.load(Ordering::Relaxed),
+            hedges_won: counters.hedges_won.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sends a query to a second upstream as well when the first hasn't
+/// answered within a [`HedgeThreshold`], and returns whichever response
+/// comes back first
+///
+/// The second upstream is the next name server in the configuration with a
+/// different address, wrapping around, so this does nothing with only one
+/// upstream.  If one of the two fails, the other still gets to answer.
+///
+/// trust-dns already has its own, blunter, version of this: it sends each
+/// query to `ResolverOpts::num_concurrent_reqs` upstreams at once (two, by
+/// default).  Set that to 1 for the resolver using this transport, or every
+/// query goes out at least twice anyway.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::dns_transport::StandardTransport;
+/// # use reqwest_resolve::hedge::{HedgedTransport, HedgeThreshold};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let config = ResolverConfig::cloudflare();
+/// let mut options = ResolverOpts::default();
+/// options.num_concurrent_reqs = 1;
+/// let transport = HedgedTransport::new(
+///     StandardTransport::new(options),
+///     &config,
+///     HedgeThreshold::default(),
+/// );
+/// let counters = transport.counters();
+/// let resolver = transport_resolver(config, options, transport).unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// assert_eq!(counters.snapshot().hedged, 0);
+/// ```
+pub struct HedgedTransport<T> {
+    inner: Arc<T>,
+    servers: Vec<NameServerConfig>,
+    threshold: HedgeThreshold,
+    latencies: Arc<Latencies>,
+    counters: Arc<Counters>,
+}
+
+impl<T: DnsTransport> HedgedTransport<T> {
+    /// Hedges between the name servers in `config`, which should be the
+    /// configuration the resolver was built with.
+    pub fn new(
+        inner: T,
+        config: &ResolverConfig,
+        threshold: HedgeThreshold,
+    ) -> HedgedTransport<T> {
+        HedgedTransport {
+            inner: Arc::new(inner),
+            servers: config.name_servers().to_vec(),
+            threshold,
+            latencies: Arc::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn counters(&self) -> HedgeCounters {
+        HedgeCounters { counters: Arc::clone(&self.counters) }
+    }
+
+    /// Returns how long to wait for `server` before asking another one.
+    fn threshold(&self, server: &NameServerConfig) -> Duration {
+        match self.threshold {
+            HedgeThreshold::Fixed(threshold) => threshold,
+            HedgeThreshold::Percentile { percentile, min, max } => self
+                .latencies
+                .percentile(server.socket_addr, percentile)
+                .map_or(max, |latency| latency.max(min).min(max)),
+        }
+    }
+
+    /// Returns the upstream to ask if `server` is slow: the next one in the
+    /// configuration with a different address.
+    fn second(&self, server: &NameServerConfig) -> Option<NameServerConfig> {
+        let addr = server.socket_addr;
+        let start = self.servers.iter().position(|s| s.socket_addr == addr)?;
+        let (before, after) = self.servers.split_at(start);
+        after.iter().chain(before).find(|s| s.socket_addr != addr).cloned()
+    }
+}
+
+/// Sends `query` to `server` with `inner`, recording how long a successful
+/// response took.
+fn timed<T: DnsTransport>(
+    inner: &T,
+    server: &NameServerConfig,
+    query: Message,
+    latencies: &Arc<Latencies>,
+) -> TransportFuture {
+    let started = Instant::now();
+    let response = inner.send(server, query);
+    let latencies = Arc::clone(latencies);
+    let upstream = server.socket_addr;
+    async move {
+        let result = response.await;
+        if result.is_ok() {
+            latencies.record(upstream, started.elapsed());
+        }
+        result
+    }
+    .boxed()
+}
+
+impl<T: DnsTransport> DnsTransport for HedgedTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        self.counters.queries.fetch_add(1, Ordering::Relaxed);
+        let threshold = self.threshold(server);
+        let Some(second) = self.second(server) else {
+            return timed(&*self.inner, server, query, &self.latencies);
+        };
+        let first = timed(&*self.inner, server, query.clone(), &self.latencies);
+        let upstream = server.socket_addr;
+        let inner = Arc::clone(&self.inner);
+        let latencies = Arc::clone(&self.latencies);
+        let counters = Arc::clone(&self.counters);
+
+        async move {
+            let waiting = Box::pin(TokioTime::delay_for(threshold));
+            let first = match futures::future::select(first, waiting).await {
+                Either::Left((result, _)) => return result,
+                Either::Right(((), first)) => first,
+            };
+
+            debug!(
+                "upstream is slow, asking another",
+                upstream = upstream,
+                threshold = threshold,
+                hedge = second.socket_addr,
+            );
+            counters.hedged.fetch_add(1, Ordering::Relaxed);
+            let hedge = timed(&*inner, &second, query, &latencies);
+            match futures::future::select(first, hedge).await {
+                Either::Left((Ok(response), _)) => Ok(response),
+                Either::Left((Err(_), hedge)) => {
+                    let result = hedge.await;
+                    if result.is_ok() {
+                        counters.hedges_won.fetch_add(1, Ordering::Relaxed);
+                    }
+                    result
+                }
+                Either::Right((Ok(response), _)) => {
+                    counters.hedges_won.fetch_add(1, Ordering::Relaxed);
+                    Ok(response)
+                }
+                Either::Right((Err(_), first)) => first.await,
+            }
+        }
+        .boxed()
+    }
+}
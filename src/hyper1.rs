@@ -0,0 +1,76 @@
+//! Using resolver stacks with hyper 1.x
+//!
+//! reqwest (as of the version this crate builds on) is on hyper 0.14, but
+//! programs that use hyper 1.x directly connect through `hyper-util`'s
+//! legacy client, which takes its resolver as a tower `Service` from
+//! `hyper_util::client::legacy::connect::dns::Name` to an iterator of
+//! addresses.  A [`ResolverHandle`] is one of those, so the same stack
+//! can serve both kinds of client:
+//!
+//! ```
+//! # use hyper_util::client::legacy::connect::HttpConnector;
+//! # use reqwest_resolve::audit::AuditResolver;
+//! # use reqwest_resolve::handle::ResolverHandle;
+//! # use reqwest_resolve::static_hosts::StaticResolver;
+//! # use reqwest_resolve::static_resolver;
+//! static HOSTS: StaticResolver = static_resolver! {
+//!     "api.example.com" => ["192.0.2.10"],
+//! };
+//! let handle = ResolverHandle::new(AuditResolver::new(HOSTS));
+//! let _connector = HttpConnector::new_with_resolver(handle.clone());
+//! let _client = handle.client_builder().build().unwrap();
+//! ```
+//!
+//! Lookups go through `MyResolve::resolve`, with the same protection from
+//! panics (and the same task-local override, from
+//! `global::with_task_override`) that `ResolveAdapter` gives (see
+//! [`error::ResolveError::Internal`](crate::error::ResolveError::Internal)).
+
+use crate::error::ResolveError;
+use crate::global::task_override;
+use crate::handle::ResolverHandle;
+use crate::panics::isolate;
+use crate::MyResolve;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::str::FromStr;
+use std::task::Context;
+use std::task::Poll;
+
+impl<R: MyResolve + ?Sized + 'static>
+    tower_service::Service<hyper_util::client::legacy::connect::dns::Name>
+    for ResolverHandle<R>
+{
+    type Response = Addrs;
+    type Error = Box<dyn StdError + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Addrs, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(
+        &mut self,
+        name: hyper_util::client::legacy::connect::dns::Name,
+    ) -> Self::Future {
+        let handle = self.clone();
+        async move {
+            let host = name.as_str().to_owned();
+            // The two versions of `Name` accept the same names.
+            let Ok(name) = hyper::client::connect::dns::Name::from_str(&host)
+            else {
+                return Err(ResolveError::InvalidName { name: host }.into());
+            };
+            if let Some(resolver) = task_override() {
+                return isolate(&host, || resolver.resolve(name)).await;
+            }
+            isolate(&host, || handle.resolve(name)).await
+        }
+        .boxed()
+    }
+}
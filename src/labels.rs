@@ -0,0 +1,59 @@
+//! Labelling lookups with what they're for
+//!
+//! An application usually shares one reqwest client, and so one resolver,
+//! between all of its features, which leaves nothing in the resolver's
+//! telemetry to say which feature a lookup was for.  [`with_labels`] attaches
+//! key/value labels to every lookup made while a future runs.  The labels are
+//! added as attributes to the spans and metrics from `otel::OtelResolver`
+//! (with the "opentelemetry" feature) and as a field on lookup log events
+//! (with the "log" or "tracing" feature).
+//!
+//! Like [`tenant::with_tenant`](crate::tenant::with_tenant), this works
+//! through a task-local, so labels only apply to lookups made on the task
+//! that's running the future.  reqwest usually resolves names on the task
+//! that's waiting for the response, but a connection that hyper finishes in
+//! the background (because the request went out on another one first)
+//! resolves without the labels.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+
+tokio::task_local! {
+    static CURRENT_LABELS: BTreeMap<String, String>;
+}
+
+/// Runs `future` with `labels` attached to the lookups it makes
+///
+/// Labels from an enclosing `with_labels` still apply, except where `labels`
+/// has the same key.
+///
+/// ```
+/// # use reqwest_resolve::labels::with_labels;
+/// # async fn example(client: reqwest::Client) -> reqwest::Result<()> {
+/// let response = with_labels(
+///     [("feature", "webhooks")],
+///     client.get("https://example.com/").send(),
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn with_labels<F, I, K, V>(labels: I, future: F) -> F::Output
+where
+    F: Future,
+    I: IntoIterator<Item = (K, V)>,
+    K: Into<String>,
+    V: Into<String>,
+{
+    let mut merged = current_labels();
+    merged.extend(
+        labels.into_iter().map(|(key, value)| (key.into(), value.into())),
+    );
+    CURRENT_LABELS.scope(merged, future).await
+}
+
+/// Returns the labels in scope (see [`with_labels`]), which is empty outside
+/// of any.
+pub fn current_labels() -> BTreeMap<String, String> {
+    CURRENT_LABELS.try_with(|labels| labels.clone()).unwrap_or_default()
+}
@@ -0,0 +1,80 @@
+//! Keeping track of how quickly (and whether) each upstream answers
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many of each upstream's most recent queries are kept
+const WINDOW: usize = 256;
+
+/// How many answers an upstream needs before its percentiles (or failures
+/// before its success rate) mean anything
+pub(crate) const MIN_SAMPLES: usize = 20;
+
+/// The outcomes of each upstream's most recent queries: how long it took to
+/// answer, or `None` if it didn't
+#[derive(Default)]
+pub(crate) struct Latencies {
+    by_upstream: Mutex<BTreeMap<SocketAddr, VecDeque<Option<Duration>>>>,
+}
+
+impl Latencies {
+    /// Records that `upstream` answered a query in `latency`.
+    pub(crate) fn record(&self, upstream: SocketAddr, latency: Duration) {
+        self.push(upstream, Some(latency));
+    }
+
+    /// Records that a query to `upstream` failed.
+    pub(crate) fn record_failure(&self, upstream: SocketAddr) {
+        self.push(upstream, None);
+    }
+
+    fn push(&self, upstream: SocketAddr, outcome: Option<Duration>) {
+        let mut by_upstream = self.by_upstream.lock().unwrap();
+        let window = by_upstream.entry(upstream).or_default();
+        if window.len() == WINDOW {
+            window.pop_front();
+        }
+        window.push_back(outcome);
+    }
+
+    /// Returns how many of `upstream`'s queries are being remembered.
+    pub(crate) fn samples(&self, upstream: SocketAddr) -> usize {
+        let by_upstream = self.by_upstream.lock().unwrap();
+        by_upstream.get(&upstream).map_or(0, |window| window.len())
+    }
+
+    /// Returns the fraction of `upstream`'s recent queries that it answered,
+    /// if there have been at least [`MIN_SAMPLES`] of them.
+    pub(crate) fn success_rate(&self, upstream: SocketAddr) -> Option<f64> {
+        let by_upstream = self.by_upstream.lock().unwrap();
+        let window = by_upstream.get(&upstream)?;
+        if window.len() < MIN_SAMPLES {
+            return None;
+        }
+        let successes = window.iter().filter(|o| o.is_some()).count();
+        Some(successes as f64 / window.len() as f64)
+    }
+
+    /// Returns the `percentile`th (between 0 and 1) of `upstream`'s recent
+    /// latencies, if it has answered at least [`MIN_SAMPLES`] queries.
+    pub(crate) fn percentile(
+        &self,
+        upstream: SocketAddr,
+        percentile: f64,
+    ) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = {
+            let by_upstream = self.by_upstream.lock().unwrap();
+            by_upstream.get(&upstream)?.iter().flatten().copied().collect()
+        };
+        if sorted.len() < MIN_SAMPLES {
+            return None;
+        }
+        sorted.sort_unstable();
+        let rank = (percentile.clamp(0.0, 1.0) * sorted.len() as f64).ceil();
+        let index = (rank as usize).clamp(1, sorted.len()) - 1;
+        Some(sorted[index])
+    }
+}
@@ -1,43 +1,223 @@
 // Demo some lifetime questions around reqwest `Resolve` trait
 
+#[cfg(feature = "trust-dns")]
+use crate::logging::debug;
+#[cfg(feature = "trust-dns")]
+use crate::logging::trace;
+use futures::future::BoxFuture;
 use futures::future::FutureExt;
 use reqwest::dns::Addrs;
+use smallvec::SmallVec;
 use std::error::Error as StdError;
 use std::future::Future;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+#[cfg(feature = "trust-dns")]
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::lookup_ip::LookupIp;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::name_server::ConnectionProvider;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::proto::DnsHandle;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::AsyncResolver;
+#[cfg(feature = "trust-dns")]
 use trust_dns_resolver::TokioAsyncResolver;
 
+#[cfg(feature = "trust-dns")]
+pub mod adaptive_timeout;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod anomaly;
+#[cfg(feature = "answer-limit")]
+pub mod answer_limit;
+#[cfg(feature = "trust-dns")]
+pub mod answer_names;
+#[cfg(feature = "anti-spoofing")]
+pub mod anti_spoofing;
+#[cfg(feature = "async-trait")]
+pub mod async_resolve;
+pub mod audit;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod bogons;
+#[cfg(feature = "dns-over-rustls")]
+pub mod bootstrap;
+pub mod cache;
+pub mod changes;
+pub mod cname_trace;
+pub mod consensus;
+#[cfg(all(feature = "trust-dns", unix))]
+pub mod container;
+pub mod context;
+#[cfg(feature = "dns-cookies")]
+pub mod cookies;
+#[cfg(feature = "dns-over-rustls")]
+pub mod ddr;
+#[cfg(feature = "deadline")]
+pub mod deadline;
+pub mod dedup;
+pub mod deterministic;
+#[cfg(feature = "tower")]
+pub mod discover;
+#[cfg(feature = "trust-dns")]
+pub mod dns_sd;
+#[cfg(feature = "trust-dns")]
+pub mod dns_transport;
+#[cfg(feature = "dnscrypt")]
+pub mod dnscrypt;
+#[cfg(all(feature = "dnstap", unix))]
+pub mod dnstap;
+#[cfg(feature = "dns-over-https-rustls")]
+pub mod doh;
+#[cfg(feature = "doh-discovery")]
+pub mod doh_discovery;
+#[cfg(feature = "trust-dns")]
+pub mod dual_stack;
+#[cfg(feature = "trust-dns")]
+pub mod edns;
+pub mod env_hosts;
+pub mod error;
+#[cfg(feature = "trust-dns")]
+pub mod escalation;
+#[cfg(any(feature = "bind-device", feature = "netns", feature = "udp-ports"))]
+mod exchange;
+pub mod fallback;
+pub mod fixtures;
+#[cfg(feature = "trust-dns")]
+pub mod full_lookup;
+pub mod gai;
+pub mod global;
+pub mod handle;
+#[cfg(feature = "happy-eyeballs")]
+pub mod happy_eyeballs;
+#[cfg(feature = "trust-dns")]
+pub mod hedge;
+#[cfg(feature = "hyper-util")]
+pub mod hyper1;
+pub mod labels;
+#[cfg(feature = "trust-dns")]
+mod latency;
+#[cfg(feature = "trust-dns")]
+pub mod links;
+#[cfg(feature = "llmnr")]
+pub mod llmnr;
+mod logging;
+#[cfg(feature = "trust-dns")]
+pub mod lookup_policy;
+pub mod loops;
+pub mod maintenance;
+#[cfg(feature = "middleware")]
+pub mod middleware;
+#[cfg(feature = "dns-over-rustls")]
+pub mod mtls;
+#[cfg(feature = "trust-dns")]
+mod names;
+#[cfg(feature = "netbios")]
+pub mod netbios;
+#[cfg(all(feature = "netns", target_os = "linux"))]
+pub mod netns;
+pub mod normalize;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+mod panics;
+#[cfg(feature = "trust-dns")]
+pub mod pool;
+#[cfg(feature = "udp-ports")]
+pub mod ports;
+#[cfg(feature = "trust-dns")]
+pub mod precedence;
+pub mod proxy;
+#[cfg(feature = "trust-dns")]
+pub mod query_budget;
+#[cfg(feature = "trust-dns")]
+pub mod ranking;
+pub mod resolved;
+pub mod routing;
+pub mod scope;
+#[cfg(feature = "signed-answers")]
+pub mod signed;
+pub mod single_label;
+#[cfg(feature = "slow-dns")]
+pub mod slow;
+#[cfg(feature = "trust-dns")]
+pub mod source;
+pub mod special_use;
+pub mod spike;
+#[cfg(feature = "trust-dns")]
+pub mod split_dns;
+pub mod stack;
+#[cfg(feature = "dns-over-rustls")]
+pub mod stamps;
+pub mod standby;
+#[cfg(feature = "trust-dns")]
+pub mod startup;
+pub mod static_hosts;
+pub mod streak;
+#[cfg(feature = "trust-dns")]
+pub mod streaming;
+#[cfg(feature = "trust-dns")]
+pub mod svcb;
+pub mod system;
+pub mod tasks;
+#[cfg(feature = "trust-dns")]
+pub mod tcp;
+#[cfg(feature = "trust-dns")]
+pub mod tenant;
+#[cfg(feature = "testserver")]
+pub mod testserver;
+#[cfg(feature = "trust-dns")]
+pub mod transport;
+#[cfg(all(feature = "unix-socket", unix))]
+pub mod unix_socket;
+#[cfg(feature = "dns-over-rustls")]
+pub mod upstream_tls;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "trust-dns")]
+pub mod watchdog;
+#[cfg(feature = "zone-transfer")]
+pub mod zone_transfer;
+
+pub use error::ResolveError;
+pub use resolved::DetailedResolving;
+pub use resolved::ResolvedAddr;
+
 /// Suppose that we want to provide reqwest with a custom DNS resolver.  We can
 /// do this by providing an object that impls its `Resolve` trait.  Here's an
 /// example that uses a `trust_dns_resolver::TokioAsyncResolver` under the hood.
 /// Here's how you might use it:
 ///
 /// ```
+/// # use reqwest_resolve::CustomDnsResolver;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+/// # };
+/// # use trust_dns_resolver::TokioAsyncResolver;
 /// // Create a reqwest client with a custom DNS resolver that always uses
 /// // 1.1.1.1 (just as an example of wanting a custom resolver).
 /// let raw_resolver = {
 ///     let dns_addr = "1.1.1.1:53".parse().unwrap();
 ///     let mut resolver_config = ResolverConfig::new();
-///     resolver_config.add_name_server(NameServerConfig {
-///         socket_addr: dns_addr,
-///         protocol: Protocol::Udp,
-///         tls_dns_name: None,
-///         trust_nx_responses: false,
-///         bind_addr: None,
-///     });
+///     let mut name_server = NameServerConfig::new(dns_addr, Protocol::Udp);
+///     name_server.trust_nx_responses = false;
+///     resolver_config.add_name_server(name_server);
 ///
 ///     TokioAsyncResolver::tokio(
 ///         resolver_config,
 ///         ResolverOpts::default()
-///     ).unwrap();
+///     ).unwrap()
 /// };
 ///
 /// let my_resolver = Arc::new(CustomDnsResolver::new(raw_resolver));
 /// let _client =
 ///     reqwest::ClientBuilder::new().dns_resolver(my_resolver).build();
 /// ```
+#[cfg(feature = "trust-dns")]
 pub struct CustomDnsResolver {
     // Note that we have to store an `Arc` here because the definition of the
     // `Resolve` trait seems to require that the returned Future outlive the
@@ -45,12 +225,14 @@ pub struct CustomDnsResolver {
     resolver: Arc<TokioAsyncResolver>,
 }
 
+#[cfg(feature = "trust-dns")]
 impl CustomDnsResolver {
     pub fn new(resolver: TokioAsyncResolver) -> CustomDnsResolver {
         CustomDnsResolver { resolver: Arc::new(resolver) }
     }
 }
 
+#[cfg(feature = "trust-dns")]
 impl reqwest::dns::Resolve for CustomDnsResolver {
     fn resolve(
         &self,
@@ -59,7 +241,10 @@ impl reqwest::dns::Resolve for CustomDnsResolver {
         // Compare to the impl of MyResolve below.  Here, we have to clone the
         // resolver and use an extra async block that we can move the Arc into.
         let resolver = self.resolver.clone();
-        async move { do_resolve(&resolver, name).await }.boxed()
+        async move {
+            global::overridable(name, |name| do_resolve(&resolver, name)).await
+        }
+        .boxed()
     }
 }
 
@@ -68,13 +253,50 @@ impl reqwest::dns::Resolve for CustomDnsResolver {
 // has the same lifetime as the Resolver itself.
 
 /// Nearly the same as `Resolving`, but the Future has lifetime 'a.
-pub type MyResolving<'a> = Pin<
-    Box<
-        dyn Future<Output = Result<Addrs, Box<dyn StdError + Send + Sync>>>
-            + Send
-            + 'a,
-    >,
->;
+///
+/// Unlike `Resolving`, an answer that's known straight away (from a fixed
+/// table, say, or a name that's refused without a lookup) doesn't need a
+/// boxed future: see [`MyResolving::ready`].  Anything that has to wait is
+/// boxed, which is what converting from a `BoxFuture` does, so resolvers can
+/// still end with `.boxed().into()`.
+pub struct MyResolving<'a>(ResolvingState<'a>);
+
+type ResolveResult = Result<Addrs, Box<dyn StdError + Send + Sync>>;
+
+enum ResolvingState<'a> {
+    /// The answer, until it's been returned
+    Ready(Option<ResolveResult>),
+    Pending(BoxFuture<'a, ResolveResult>),
+}
+
+impl<'a> MyResolving<'a> {
+    /// Returns a future that completes immediately with `result`.
+    pub fn ready(result: ResolveResult) -> MyResolving<'a> {
+        MyResolving(ResolvingState::Ready(Some(result)))
+    }
+}
+
+impl<'a> From<BoxFuture<'a, ResolveResult>> for MyResolving<'a> {
+    fn from(future: BoxFuture<'a, ResolveResult>) -> MyResolving<'a> {
+        MyResolving(ResolvingState::Pending(future))
+    }
+}
+
+impl Future for MyResolving<'_> {
+    type Output = ResolveResult;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<ResolveResult> {
+        match &mut self.get_mut().0 {
+            ResolvingState::Ready(result) => std::task::Poll::Ready(
+                result.take().expect("MyResolving polled after completion"),
+            ),
+            ResolvingState::Pending(future) => future.as_mut().poll(cx),
+        }
+    }
+}
 
 /// Same as `Resolve`, but using `MyResolving<'a>` in place of `Resolving`.
 pub trait MyResolve: Send + Sync {
@@ -82,22 +304,298 @@ pub trait MyResolve: Send + Sync {
         &'a self,
         name: hyper::client::connect::dns::Name,
     ) -> MyResolving<'a>;
+
+    /// Resolves `name` for connecting to `port`
+    ///
+    /// reqwest ignores the port in the addresses it gets back and connects to
+    /// the one from the URL, which is why `resolve` leaves them all 0.
+    /// Anything else using a resolver directly needs real ports, and this
+    /// fills in `port` for every address that doesn't already have one.
+    /// Addresses that do, like the ones `svcb::SvcbResolver` returns for
+    /// endpoints that publish their own port, keep it.
+    ///
+    /// Resolvers whose lookups depend on the port can override this.  The
+    /// layers in this crate look names up through their inner resolver's
+    /// `resolve`, so only the outermost resolver sees the port.
+    ///
+    /// ```
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::{static_resolver, MyResolve};
+    /// # use std::net::SocketAddr;
+    /// # use std::str::FromStr;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "example.com" => ["192.0.2.1"],
+    /// };
+    ///
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let name =
+    ///     hyper::client::connect::dns::Name::from_str("example.com").unwrap();
+    /// let addrs: Vec<SocketAddr> =
+    ///     HOSTS.resolve_with_port(name, 8080).await.unwrap().collect();
+    /// assert_eq!(addrs, ["192.0.2.1:8080".parse().unwrap()]);
+    /// # });
+    /// ```
+    fn resolve_with_port<'a>(
+        &'a self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'a> {
+        let lookup = self.resolve(name);
+        async move { Ok(with_port(lookup.await?, port)) }.boxed().into()
+    }
+
+    /// Resolves `name` for the lookup `context` describes
+    ///
+    /// By default, this is `resolve_with_port` with the context's port, if
+    /// it has one, and `resolve` otherwise, with the context in scope (see
+    /// [`context::current_context`]).  Layers that don't care what a lookup
+    /// is for can leave this alone, and resolvers further in still see the
+    /// context: `svcb::SvcbResolver`, for one, only looks for HTTPS records
+    /// when the scheme is "https".
+    ///
+    /// ```
+    /// # use reqwest_resolve::context::ResolveContext;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::{static_resolver, MyResolve};
+    /// # use std::net::SocketAddr;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "example.com" => ["192.0.2.1"],
+    /// };
+    ///
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let url = reqwest::Url::parse("https://example.com/").unwrap();
+    /// let context = ResolveContext::for_url(&url);
+    /// let name = url.host_str().unwrap().parse().unwrap();
+    /// let addrs: Vec<SocketAddr> =
+    ///     HOSTS.resolve_with_context(name, &context).await.unwrap().collect();
+    /// assert_eq!(addrs, ["192.0.2.1:443".parse().unwrap()]);
+    /// # });
+    /// ```
+    fn resolve_with_context<'a>(
+        &'a self,
+        name: hyper::client::connect::dns::Name,
+        context: &context::ResolveContext,
+    ) -> MyResolving<'a> {
+        let context = context.clone();
+        async move {
+            context::scoped(&context, || match context.port() {
+                Some(port) => self.resolve_with_port(name, port),
+                None => self.resolve(name),
+            })
+            .await
+        }
+        .boxed()
+        .into()
+    }
+
+    /// Resolves `name`, describing where each address came from
+    ///
+    /// By default, this calls `resolve` and describes every address with
+    /// [`ResolvedAddr::unknown`].  The resolvers that produce addresses (the
+    /// ones that talk to DNS, the cache, and the fixed tables) say more, and
+    /// so do the layers that only choose which of those to use, like
+    /// `routing::RoutingResolver`.  Other layers use the default.
+    fn resolve_detailed<'a>(
+        &'a self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'a> {
+        let lookup = self.resolve(name);
+        async move { Ok(lookup.await?.map(ResolvedAddr::unknown).collect()) }
+            .boxed()
+    }
+
+    /// Resolves `name` into a plain `Vec`, for callers that aren't reqwest
+    ///
+    /// This saves building a hyper `Name` and collecting the boxed iterator
+    /// that `resolve` returns.  The error is the same as `resolve`'s (so one
+    /// from trust-dns can still be downcast to find out what went wrong),
+    /// or [`error::ResolveError::InvalidName`] if `name` isn't a valid name.
+    /// As with `resolve`, the ports are all 0.
+    ///
+    /// ```
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::{static_resolver, MyResolve};
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "example.com" => ["192.0.2.1"],
+    /// };
+    ///
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let addrs = HOSTS.resolve_to_vec("example.com").await.unwrap();
+    /// assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+    /// # });
+    /// ```
+    fn resolve_to_vec<'a>(
+        &'a self,
+        name: &str,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>>>
+    {
+        let Ok(parsed) = name.parse::<hyper::client::connect::dns::Name>()
+        else {
+            let error =
+                error::ResolveError::InvalidName { name: name.to_owned() };
+            return futures::future::ready(Err(error.into())).boxed();
+        };
+        let lookup = self.resolve(parsed);
+        async move { Ok(lookup.await?.collect()) }.boxed()
+    }
+}
+
+/// Lookups that return more addresses than this are rare, so lists up to this
+/// long are kept inline, and returning one allocates only the `Addrs` box
+const INLINE_ADDRS: usize = 4;
+
+/// Socket addresses on their way into an `Addrs`
+pub(crate) type AddrList = SmallVec<[SocketAddr; INLINE_ADDRS]>;
+
+/// IP addresses on their way into an `Addrs`
+pub(crate) type IpList = SmallVec<[IpAddr; INLINE_ADDRS]>;
+
+/// Sets the port of every address in `addrs` that doesn't have one.
+pub(crate) fn with_port(addrs: Addrs, port: u16) -> Addrs {
+    Box::new(addrs.map(move |mut addr| {
+        if addr.port() == 0 {
+            addr.set_port(port);
+        }
+        addr
+    }))
+}
+
+// These let a resolver stack be shared (`Arc`), stored as a trait object
+// (`Box<dyn MyResolve>`), or borrowed by a layer that doesn't own it (`&T`),
+// all without a newtype.
+
+impl<T: MyResolve + ?Sized> MyResolve for Arc<T> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        (**self).resolve(name)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_port(name, port)
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &context::ResolveContext,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_context(name, context)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        (**self).resolve_detailed(name)
+    }
+}
+
+impl<T: MyResolve + ?Sized> MyResolve for Box<T> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        (**self).resolve(name)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_port(name, port)
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &context::ResolveContext,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_context(name, context)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        (**self).resolve_detailed(name)
+    }
+}
+
+impl<T: MyResolve + ?Sized> MyResolve for &T {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        (**self).resolve(name)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_port(name, port)
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &context::ResolveContext,
+    ) -> MyResolving<'_> {
+        (**self).resolve_with_context(name, context)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        (**self).resolve_detailed(name)
+    }
 }
 
 /// This wrapper doesn't need an Arc.
+#[cfg(feature = "trust-dns")]
 pub struct MyCustomDnsResolver {
     resolver: TokioAsyncResolver,
 }
 
+#[cfg(feature = "trust-dns")]
 impl MyCustomDnsResolver {
     pub fn new(resolver: TokioAsyncResolver) -> MyCustomDnsResolver {
         MyCustomDnsResolver { resolver }
     }
 }
 
+#[cfg(feature = "trust-dns")]
 impl MyResolve for MyCustomDnsResolver {
-    fn resolve(&self, name: hyper::client::connect::dns::Name) -> MyResolving {
-        do_resolve(&self.resolver, name).boxed()
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve(&self.resolver, name).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        do_resolve_detailed(&self.resolver, name).boxed()
     }
 }
 
@@ -123,13 +621,169 @@ impl MyResolve for MyCustomDnsResolver {
 //110 |         do_resolve(&self.resolver, name).boxed()
 //    |         ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^ returning this value requires that `'1` must outlive `'static`
 
-async fn do_resolve(
-    resolver: &TokioAsyncResolver,
-    name: hyper::client::connect::dns::Name
-) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
-    let list = resolver.lookup_ip(name.as_str()).await?;
+/// Adapts any `MyResolve` into a reqwest `Resolve`.
+///
+/// This does the same `Arc` dance that `CustomDnsResolver` does, but once, for
+/// the whole resolver.  That means the resolvers themselves (and any layers
+/// wrapped around them) can all be written against `MyResolve` without
+/// worrying about the returned Future being `'static`.
+///
+/// A panic anywhere in the stack fails only the lookup it happened in, with
+/// [`error::ResolveError::Internal`], rather than unwinding through reqwest.
+///
+/// ```
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = ResolveAdapter::new(MyCustomDnsResolver::new(resolver));
+/// let _client =
+///     reqwest::ClientBuilder::new().dns_resolver(Arc::new(my_resolver));
+/// ```
+pub struct ResolveAdapter<R: ?Sized> {
+    resolver: Arc<R>,
+}
+
+impl<R> ResolveAdapter<R> {
+    pub fn new(resolver: R) -> ResolveAdapter<R> {
+        ResolveAdapter { resolver: Arc::new(resolver) }
+    }
+}
+
+impl<R: ?Sized> ResolveAdapter<R> {
+    /// Adapts a resolver that's already behind an `Arc`, which may be a
+    /// trait object.  This is how to give several clients the same stack:
+    ///
+    /// ```
+    /// # use reqwest_resolve::{MyCustomDnsResolver, MyResolve, ResolveAdapter};
+    /// # use std::sync::Arc;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # use trust_dns_resolver::TokioAsyncResolver;
+    /// # let resolver = TokioAsyncResolver::tokio(
+    /// #     ResolverConfig::default(),
+    /// #     ResolverOpts::default(),
+    /// # )
+    /// # .unwrap();
+    /// let stack: Arc<dyn MyResolve> = Arc::new(MyCustomDnsResolver::new(resolver));
+    /// let _first = reqwest::ClientBuilder::new()
+    ///     .dns_resolver(Arc::new(ResolveAdapter::from_arc(stack.clone())));
+    /// let _second = reqwest::ClientBuilder::new()
+    ///     .dns_resolver(Arc::new(ResolveAdapter::from_arc(stack)));
+    /// ```
+    pub fn from_arc(resolver: Arc<R>) -> ResolveAdapter<R> {
+        ResolveAdapter { resolver }
+    }
+}
+
+impl<R: MyResolve + ?Sized + 'static> reqwest::dns::Resolve
+    for ResolveAdapter<R>
+{
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        async move {
+            global::overridable(name, |name| resolver.resolve(name)).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+async fn do_resolve<C, P>(
+    resolver: &AsyncResolver<C, P>,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>>
+where
+    C: DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    let (list, _) = lookup_ips(resolver, &name).await?;
     Ok(Box::new(list.into_iter().map(|s| {
         // The port number is not used here.
         SocketAddr::from((s, 0))
     })) as Addrs)
 }
+
+/// Like `do_resolve`, but describing where each address came from
+#[cfg(feature = "trust-dns")]
+async fn do_resolve_detailed<C, P>(
+    resolver: &AsyncResolver<C, P>,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Vec<ResolvedAddr>, Box<dyn StdError + Send + Sync>>
+where
+    C: DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    let (list, valid_until) = lookup_ips(resolver, &name).await?;
+    Ok(list
+        .into_iter()
+        .map(|ip| ResolvedAddr::from_dns(ip, valid_until, false))
+        .collect())
+}
+
+/// Looks up `name`'s addresses, returning them with when they expire
+#[cfg(feature = "trust-dns")]
+async fn lookup_ips<C, P>(
+    resolver: &AsyncResolver<C, P>,
+    name: &hyper::client::connect::dns::Name,
+) -> Result<(IpList, Instant), trust_dns_resolver::error::ResolveError>
+where
+    C: DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    let lookup = lookup_ip(resolver, name).await?;
+    let valid_until = lookup.valid_until();
+    Ok((ordered_ips(&lookup), valid_until))
+}
+
+/// Looks `name` up with trust-dns's `lookup_ip`, logging how it went
+#[cfg(feature = "trust-dns")]
+pub(crate) async fn lookup_ip<C, P>(
+    resolver: &AsyncResolver<C, P>,
+    name: &hyper::client::connect::dns::Name,
+) -> Result<LookupIp, trust_dns_resolver::error::ResolveError>
+where
+    C: DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    trace!("looking up name", name = name.as_str());
+    let result = match names::parsed(name.as_str()) {
+        Some(parsed) => resolver.lookup_ip(parsed).await,
+        None => resolver.lookup_ip(name.as_str()).await,
+    };
+    let lookup = match result {
+        Ok(lookup) => lookup,
+        Err(error) => {
+            debug!(
+                "lookup failed",
+                name = name.as_str(),
+                error = error,
+                labels = labels::current_labels(),
+            );
+            return Err(error);
+        }
+    };
+    let list = ordered_ips(&lookup);
+    debug!(
+        "lookup succeeded",
+        name = name.as_str(),
+        addrs = list,
+        labels = labels::current_labels(),
+    );
+    Ok(lookup)
+}
+
+/// Returns `lookup`'s addresses in the order `resolve` returns them.
+#[cfg(feature = "trust-dns")]
+pub(crate) fn ordered_ips(lookup: &LookupIp) -> IpList {
+    let mut list: IpList = lookup.iter().collect();
+    deterministic::stable_order(&mut list);
+    list
+}
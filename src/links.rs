@@ -0,0 +1,368 @@
+//! Resolving on hosts with more than one network interface
+//!
+//! A laptop on Wi-Fi with a VPN up, or a server with a management network,
+//! learns a different set of DNS servers and search domains for each of its
+//! interfaces (from DHCP, router advertisements, or the VPN client).
+//! systemd-resolved keeps those settings per link and decides for each name
+//! which links to ask.  [`LinkResolver`] does the same for a reqwest
+//! client: each [`Link`] has its own servers and domains, names under a
+//! link's domains go to that link, and everything else goes to the links
+//! that can take any name, as the [`LinkPolicy`] says.
+
+use crate::deterministic::is_deterministic;
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::routing::CompiledRules;
+use crate::routing::RoutingRules;
+use crate::scope::scope_of_servers;
+use crate::scope::with_scope;
+use crate::source::with_source_addr;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use futures::stream::FuturesUnordered;
+use futures::stream::StreamExt;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::Name;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// One network interface's DNS settings
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Link {
+    /// the interface's name (like "wlan0"), used in logs
+    pub name: String,
+    /// the interface's DNS servers, tried over UDP and then TCP
+    pub servers: Vec<SocketAddr>,
+    /// domains to try single-label names under, which are also routed to
+    /// this link like `route_domains` (systemd-resolved's "Domains=")
+    pub search_domains: Vec<String>,
+    /// names under these domains go to this link, but aren't used for
+    /// searching (systemd-resolved's "Domains=~")
+    pub route_domains: Vec<String>,
+    /// whether names that aren't under any link's domains can go to this
+    /// link (systemd-resolved's "DefaultRoute=")
+    pub default_route: bool,
+    /// the interface's address, to send this link's queries from (see
+    /// [`crate::source`])
+    pub source: Option<IpAddr>,
+    /// the interface's index, attached to link-local IPv6 answers as their
+    /// scope ID (see [`crate::scope`]); if this is `None`, the scope ID of
+    /// the link's link-local servers is used, if they have one
+    pub scope_id: Option<u32>,
+}
+
+impl Link {
+    /// Returns a link with `servers` that takes any name, and no domains.
+    pub fn new(name: &str, servers: Vec<SocketAddr>) -> Link {
+        Link {
+            name: name.to_owned(),
+            servers,
+            search_domains: Vec::new(),
+            route_domains: Vec::new(),
+            default_route: true,
+            source: None,
+            scope_id: None,
+        }
+    }
+
+    /// Builds a resolver that sends queries only to this link's servers.
+    fn resolver(
+        &self,
+        options: ResolverOpts,
+    ) -> Result<TokioAsyncResolver, ResolveError> {
+        let mut search = Vec::with_capacity(self.search_domains.len());
+        for domain in &self.search_domains {
+            let name = Name::from_utf8(domain).map_err(|error| {
+                ResolveError::InvalidConfig(format!(
+                    "link {:?}: search domain {:?}: {}",
+                    self.name, domain, error
+                ))
+            })?;
+            search.push(name);
+        }
+
+        let mut config = ResolverConfig::from_parts(None, search, Vec::new());
+        for server in &self.servers {
+            config
+                .add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+            config
+                .add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+        }
+        if let Some(source) = self.source {
+            config = with_source_addr(&config, source);
+        }
+        TokioAsyncResolver::tokio(config, options).map_err(|error| {
+            ResolveError::InvalidConfig(format!(
+                "link {:?}: {}",
+                self.name, error
+            ))
+        })
+    }
+}
+
+/// Which links a [`LinkResolver`] asks about names that aren't under any
+/// link's domains
+///
+/// Names under a link's domains always go to the links with the most
+/// specific matching domain.  When more than one link has that domain,
+/// they're all asked as with [`LinkPolicy::FirstAnswer`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LinkPolicy {
+    /// Ask every link with `default_route` at once and take the first
+    /// successful answer, as systemd-resolved does.  Inside
+    /// `deterministic::with_seed`, every link is waited for and the first
+    /// one (in the order they were given) that succeeded wins.
+    #[default]
+    FirstAnswer,
+    /// Ask the links with `default_route` one at a time, in the order they
+    /// were given, until one succeeds.  This suits a primary interface with
+    /// backups behind it.
+    InOrder,
+}
+
+type LinkResult<T> = Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// A link, ready to resolve names
+struct ActiveLink {
+    name: String,
+    resolver: TokioAsyncResolver,
+    scope_id: Option<u32>,
+}
+
+/// Resolves each name using the links responsible for it
+///
+/// ```
+/// # use reqwest_resolve::links::{Link, LinkPolicy, LinkResolver};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::ResolverOpts;
+/// let mut wifi = Link::new("wlan0", vec!["192.168.1.1:53".parse().unwrap()]);
+/// wifi.search_domains = vec!["lan".to_owned()];
+///
+/// // Only names under corp.example.com go over the VPN.
+/// let mut vpn = Link::new("wg0", vec!["10.8.0.1:53".parse().unwrap()]);
+/// vpn.route_domains = vec!["corp.example.com".to_owned()];
+/// vpn.default_route = false;
+///
+/// let my_resolver = LinkResolver::new(
+///     vec![wifi, vpn],
+///     LinkPolicy::FirstAnswer,
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct LinkResolver {
+    links: Vec<Arc<ActiveLink>>,
+    /// from each link's domains to the links responsible for them
+    rules: CompiledRules<Vec<usize>>,
+    /// the links with `default_route`
+    default_links: Vec<usize>,
+    policy: LinkPolicy,
+}
+
+impl LinkResolver {
+    /// Builds a resolver for each of `links` with `options`.
+    pub fn new(
+        links: Vec<Link>,
+        policy: LinkPolicy,
+        options: ResolverOpts,
+    ) -> Result<LinkResolver, ResolveError> {
+        // Links can share a domain, so group them by domain before making
+        // rules: each rule needs every link that has it.
+        let mut domains: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, link) in links.iter().enumerate() {
+            for domain in link.search_domains.iter().chain(&link.route_domains)
+            {
+                let domain = normalize(domain).into_owned();
+                let indexes = domains.entry(domain).or_default();
+                if !indexes.contains(&index) {
+                    indexes.push(index);
+                }
+            }
+        }
+        let mut rules = RoutingRules::new();
+        for (domain, indexes) in domains {
+            rules = rules
+                .rule(&format!("*.{}", domain), indexes.clone())
+                .rule(&domain, indexes);
+        }
+
+        let default_links = links
+            .iter()
+            .enumerate()
+            .filter(|(_, link)| link.default_route)
+            .map(|(index, _)| index)
+            .collect();
+        let mut active = Vec::with_capacity(links.len());
+        for link in &links {
+            active.push(Arc::new(ActiveLink {
+                name: link.name.clone(),
+                resolver: link.resolver(options)?,
+                scope_id: link
+                    .scope_id
+                    .or_else(|| scope_of_servers(&link.servers)),
+            }));
+        }
+
+        Ok(LinkResolver {
+            links: active,
+            rules: rules.compile()?,
+            default_links,
+            policy,
+        })
+    }
+
+    /// Returns the links to ask about `name`, and how.
+    fn links_for(&self, name: &str) -> (Vec<Arc<ActiveLink>>, LinkPolicy) {
+        let (indexes, policy) = match self.rules.lookup(name) {
+            Some(indexes) => (indexes, LinkPolicy::FirstAnswer),
+            None => (&self.default_links, self.policy),
+        };
+        let links =
+            indexes.iter().map(|i| Arc::clone(&self.links[*i])).collect();
+        (links, policy)
+    }
+}
+
+impl MyResolve for LinkResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let (links, policy) = self.links_for(name.as_str());
+        resolve_on(links, policy, name, |link, name| async move {
+            let addrs = do_resolve(&link.resolver, name).await?;
+            Ok(match link.scope_id {
+                Some(scope_id) => {
+                    Box::new(addrs.map(move |addr| with_scope(addr, scope_id)))
+                }
+                None => addrs,
+            })
+        })
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let (links, policy) = self.links_for(name.as_str());
+        resolve_on(links, policy, name, |link, name| async move {
+            let mut addrs = do_resolve_detailed(&link.resolver, name).await?;
+            if let Some(scope_id) = link.scope_id {
+                for addr in &mut addrs {
+                    addr.addr = with_scope(addr.addr, scope_id);
+                }
+            }
+            Ok(addrs)
+        })
+        .boxed()
+    }
+}
+
+/// Looks `name` up with `lookup` on `links` as `policy` says, returning the
+/// first success or, if every link fails, the first error.
+async fn resolve_on<T, F, Fut>(
+    links: Vec<Arc<ActiveLink>>,
+    policy: LinkPolicy,
+    name: hyper::client::connect::dns::Name,
+    lookup: F,
+) -> LinkResult<T>
+where
+    F: Fn(Arc<ActiveLink>, hyper::client::connect::dns::Name) -> Fut,
+    Fut: Future<Output = LinkResult<T>>,
+{
+    if links.is_empty() {
+        debug!("no link for name", name = name.as_str());
+        return Err(
+            ResolveError::NotFound { name: name.as_str().to_owned() }.into()
+        );
+    }
+
+    let attempt = |link: Arc<ActiveLink>| {
+        let name = name.clone();
+        let link_name = link.name.clone();
+        let lookup = lookup(link, name);
+        async move { (link_name, lookup.await) }
+    };
+
+    let mut first_error = None;
+    match policy {
+        LinkPolicy::FirstAnswer if !is_deterministic() => {
+            let mut pending: FuturesUnordered<_> =
+                links.into_iter().map(attempt).collect();
+            while let Some((link, result)) = pending.next().await {
+                match result {
+                    Ok(answer) => {
+                        debug!(
+                            "link answered",
+                            name = name.as_str(),
+                            link = link
+                        );
+                        return Ok(answer);
+                    }
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+        }
+        LinkPolicy::FirstAnswer => {
+            let results =
+                futures::future::join_all(links.into_iter().map(attempt)).await;
+            for (link, result) in results {
+                match result {
+                    Ok(answer) => {
+                        debug!(
+                            "link answered",
+                            name = name.as_str(),
+                            link = link
+                        );
+                        return Ok(answer);
+                    }
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+        }
+        LinkPolicy::InOrder => {
+            for link in links {
+                let (link, result) = attempt(link).await;
+                match result {
+                    Ok(answer) => {
+                        debug!(
+                            "link answered",
+                            name = name.as_str(),
+                            link = link
+                        );
+                        return Ok(answer);
+                    }
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+        }
+    }
+    Err(first_error.unwrap())
+}
@@ -0,0 +1,313 @@
+//! Link-Local Multicast Name Resolution (RFC 4795)
+//!
+//! Windows machines (and plenty of printers, NAS boxes, and other small
+//! devices) answer LLMNR queries for their own single-label names, and
+//! Windows clients fall back to LLMNR when DNS can't resolve a single-label
+//! name.  [`LlmnrFallback`] gives reqwest clients the same behavior.
+
+use crate::deterministic::is_deterministic;
+use crate::deterministic::random;
+use crate::deterministic::stable_order;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::scope::with_scope;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::Either;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::MessageType;
+use trust_dns_resolver::proto::op::OpCode;
+use trust_dns_resolver::proto::op::Query;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::Name;
+
+/// The UDP port LLMNR uses
+pub const LLMNR_PORT: u16 = 5355;
+/// The IPv4 multicast group LLMNR queries are sent to
+pub const LLMNR_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+/// The IPv6 multicast group LLMNR queries are sent to
+pub const LLMNR_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 3);
+/// How long to wait for responses when no timeout is configured
+/// (LLMNR_TIMEOUT in RFC 4795)
+pub const DEFAULT_LLMNR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resolves names by asking the local link over LLMNR
+///
+/// Queries for both A and AAAA records go out to the IPv4 and IPv6 LLMNR
+/// multicast groups.  Responses are collected until every query has been
+/// answered or the timeout expires, whichever comes first.
+pub struct LlmnrResolver {
+    timeout: Duration,
+}
+
+impl LlmnrResolver {
+    pub fn new(timeout: Duration) -> LlmnrResolver {
+        LlmnrResolver { timeout }
+    }
+}
+
+impl Default for LlmnrResolver {
+    fn default() -> LlmnrResolver {
+        LlmnrResolver::new(DEFAULT_LLMNR_TIMEOUT)
+    }
+}
+
+impl MyResolve for LlmnrResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve_llmnr(self.timeout, name).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let addrs = do_resolve_llmnr(self.timeout, name).await?;
+            Ok(addrs
+                .map(|addr| ResolvedAddr {
+                    backend: Cow::Borrowed("llmnr"),
+                    ..ResolvedAddr::unknown(addr)
+                })
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+async fn do_resolve_llmnr(
+    timeout: Duration,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    let qname = Name::from_ascii(name.as_str())?;
+    let deadline = Instant::now() + timeout;
+    let v4 = query_group(
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::from((LLMNR_GROUP_V4, LLMNR_PORT)),
+        &qname,
+        deadline,
+    )
+    .boxed();
+    let v6 = query_group(
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        SocketAddr::from((LLMNR_GROUP_V6, LLMNR_PORT)),
+        &qname,
+        deadline,
+    )
+    .boxed();
+
+    // Whichever group answers first wins.  We only wait for the other one if
+    // the first came back empty (which includes failing to send at all, as
+    // happens on hosts without IPv6).  When the result has to be
+    // reproducible, we wait for both and prefer IPv4.
+    let mut ips = if is_deterministic() {
+        let (v4, v6) = futures::future::join(v4, v6).await;
+        if v4.is_empty() {
+            v6
+        } else {
+            v4
+        }
+    } else {
+        match futures::future::select(v4, v6).await {
+            Either::Left((ips, other)) | Either::Right((ips, other)) => {
+                if ips.is_empty() {
+                    other.await
+                } else {
+                    ips
+                }
+            }
+        }
+    };
+    stable_order(&mut ips);
+
+    if ips.is_empty() {
+        return Err(
+            ResolveError::NotFound { name: name.as_str().to_owned() }.into()
+        );
+    }
+
+    Ok(Box::new(ips.into_iter()))
+}
+
+/// Sends A and AAAA queries for `name` to one multicast group and collects
+/// the addresses in the responses
+///
+/// Link-local IPv6 addresses get the scope ID of the interface the response
+/// arrived on, which is the link they're on.
+///
+/// Any I/O error just ends the collection early: LLMNR is best-effort, and
+/// the caller only cares whether any addresses turned up.
+async fn query_group(
+    bind: SocketAddr,
+    group: SocketAddr,
+    name: &Name,
+    deadline: Instant,
+) -> Vec<SocketAddr> {
+    let mut ips = Vec::new();
+    let Ok(socket) = UdpSocket::bind(bind).await else {
+        return ips;
+    };
+
+    let mut pending = Vec::new();
+    for record_type in [RecordType::A, RecordType::AAAA] {
+        let id = random::<u16>();
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(name.clone(), record_type));
+        let Ok(bytes) = message.to_vec() else {
+            return ips;
+        };
+        if socket.send_to(&bytes, group).await.is_err() {
+            return ips;
+        }
+        pending.push(id);
+    }
+
+    let mut buf = [0u8; 9000];
+    while !pending.is_empty() {
+        let received =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await;
+        let Ok(Ok((len, from))) = received else {
+            break;
+        };
+
+        let Ok(response) = Message::from_vec(&buf[..len]) else {
+            continue;
+        };
+        if response.message_type() != MessageType::Response
+            || !pending.contains(&response.id())
+            || response.query().map(|q| q.name()) != Some(name)
+        {
+            continue;
+        }
+
+        pending.retain(|id| *id != response.id());
+        if response.response_code() != ResponseCode::NoError {
+            continue;
+        }
+
+        for record in response.answers() {
+            let ip = match record.data() {
+                Some(RData::A(ip)) => IpAddr::from(*ip),
+                Some(RData::AAAA(ip)) => IpAddr::from(*ip),
+                _ => continue,
+            };
+            let mut addr = SocketAddr::new(ip, 0);
+            if let SocketAddr::V6(from) = from {
+                addr = with_scope(addr, from.scope_id());
+            }
+            if !ips.contains(&addr) {
+                ips.push(addr);
+            }
+        }
+    }
+
+    ips
+}
+
+/// Resolves single-label names over LLMNR when the inner resolver can't
+///
+/// Names with more than one label always go only to the inner resolver, as
+/// do single-label names that it resolves successfully.  The LLMNR attempt
+/// only happens after the inner resolver has failed, so it adds no latency
+/// to names DNS knows about.
+///
+/// ```
+/// # use reqwest_resolve::llmnr::{LlmnrFallback, LlmnrResolver};
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = LlmnrFallback::new(
+///     MyCustomDnsResolver::new(resolver),
+///     LlmnrResolver::default(),
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct LlmnrFallback<R> {
+    inner: R,
+    llmnr: LlmnrResolver,
+}
+
+impl<R> LlmnrFallback<R> {
+    pub fn new(inner: R, llmnr: LlmnrResolver) -> LlmnrFallback<R> {
+        LlmnrFallback { inner, llmnr }
+    }
+}
+
+impl<R: MyResolve> MyResolve for LlmnrFallback<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        if name.as_str().trim_end_matches('.').contains('.') {
+            return self.inner.resolve(name);
+        }
+
+        async move {
+            match self.inner.resolve(name.clone()).await {
+                Ok(addrs) => Ok(addrs),
+                Err(error) => {
+                    debug!(
+                        "falling back to LLMNR",
+                        name = name.as_str(),
+                        error = error,
+                    );
+                    self.llmnr.resolve(name).await.map_err(|_| error)
+                }
+            }
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        if name.as_str().trim_end_matches('.').contains('.') {
+            return self.inner.resolve_detailed(name);
+        }
+
+        async move {
+            match self.inner.resolve_detailed(name.clone()).await {
+                Ok(addrs) => Ok(addrs),
+                Err(error) => {
+                    debug!(
+                        "falling back to LLMNR",
+                        name = name.as_str(),
+                        error = error,
+                    );
+                    self.llmnr.resolve_detailed(name).await.map_err(|_| error)
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,67 @@
+//! Debug logging through `log` or `tracing`
+//!
+//! With the "log" or "tracing" feature, the resolvers in this crate report
+//! what they're doing (which backend handled a name, whether the cache had
+//! it, why a response was rejected) as debug- and trace-level events, and
+//! warn about the few things that mean something is broken.  Without either
+//! feature, the macros here compile to nothing.  Turn on only one: with
+//! both, every event is emitted twice (or more, if `tracing` is also
+//! forwarding to `log`).
+//!
+//! Events carry structured fields.  With `tracing`, they're real fields;
+//! with `log`, they're appended to the message as `key=value` pairs.
+
+/// Emits a debug-level event: a message, followed by `key = value` fields
+/// whose values implement `Debug`
+macro_rules! debug {
+    ($($args:tt)+) => {
+        $crate::logging::event!(DEBUG, Debug, $($args)+)
+    };
+}
+
+/// Emits a warn-level event, with the same syntax as [`debug!`], for the
+/// few things an operator should hear about without turning on debug logs
+macro_rules! warning {
+    ($($args:tt)+) => {
+        $crate::logging::event!(WARN, Warn, $($args)+)
+    };
+}
+
+/// Emits a trace-level event, with the same syntax as [`debug!`]
+macro_rules! trace {
+    ($($args:tt)+) => {
+        $crate::logging::event!(TRACE, Trace, $($args)+)
+    };
+}
+
+macro_rules! event {
+    (
+        $tracing_level:ident,
+        $log_level:ident,
+        $message:literal $(, $key:ident = $value:expr)* $(,)?
+    ) => {{
+        #[cfg(feature = "tracing")]
+        tracing::event!(
+            tracing::Level::$tracing_level,
+            $($key = ?$value,)*
+            $message
+        );
+        #[cfg(feature = "log")]
+        log::log!(
+            log::Level::$log_level,
+            concat!($message $(, " ", stringify!($key), "={:?}")*)
+            $(, $value)*
+        );
+        // Without either feature, this keeps variables that are only
+        // logged from looking unused, without evaluating anything.
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        if false {
+            $(let _ = &$value;)*
+        }
+    }};
+}
+
+pub(crate) use debug;
+pub(crate) use event;
+pub(crate) use trace;
+pub(crate) use warning;
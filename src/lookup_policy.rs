@@ -0,0 +1,202 @@
+//! Choosing which records a lookup asks for, one call at a time
+//!
+//! A resolver's `ResolverOpts::ip_strategy` decides, for every lookup it
+//! makes, whether to ask for A records, AAAA records, or both.  Programs
+//! that share one resolver across very different callers often want
+//! something else in different places: IPv4 only for a peer known not to
+//! have working IPv6, or SRV or HTTPS records first for a service that
+//! publishes them.  `lookup` (on [`CustomDnsResolver`] and
+//! [`MyCustomDnsResolver`], or the free function [`lookup`]) takes a
+//! [`RecordTypePolicy`] for each call, and leaves the resolver's own
+//! lookups as they were.
+
+use crate::deterministic::stable_order;
+use crate::do_resolve;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::svcb::do_resolve_svcb;
+use crate::CustomDnsResolver;
+use crate::IpList;
+use crate::MyCustomDnsResolver;
+use futures::future::join_all;
+use std::cmp::Reverse;
+use std::error::Error as StdError;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+type LookupResult = Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>>;
+
+/// Which records a [`lookup`] asks for
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum RecordTypePolicy {
+    /// only A records
+    A,
+    /// only AAAA records
+    Aaaa,
+    /// A and AAAA records, both at once, whatever the resolver's IP
+    /// strategy says.  This fails only if both lookups do.
+    Both,
+    /// the SRV records for `service` (like "_http._tcp") at the name, then
+    /// each target's addresses, with the ports from the records.  Names
+    /// without SRV records are looked up as the resolver usually would.
+    SrvFirst { service: String },
+    /// the name's HTTPS records, then each endpoint's addresses, as
+    /// `svcb::SvcbResolver` does (which is also how names without any are
+    /// looked up)
+    HttpsFirst,
+}
+
+/// Looks up `name` with `resolver` as `policy` says
+///
+/// As with `MyResolve::resolve_to_vec`, addresses from A and AAAA records
+/// have port 0, and the port from the URL (or wherever) goes in when
+/// connecting.  Addresses from SRV and HTTPS records have the ports those
+/// records gave them, where they gave any.
+///
+/// ```no_run
+/// # use reqwest_resolve::lookup_policy::RecordTypePolicy;
+/// # use reqwest_resolve::MyCustomDnsResolver;
+/// # async fn example() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let resolver = trust_dns_resolver::TokioAsyncResolver::tokio(
+///     Default::default(),
+///     Default::default(),
+/// )?;
+/// let my_resolver = MyCustomDnsResolver::new(resolver);
+/// let legacy = my_resolver.lookup("legacy.example.com", RecordTypePolicy::A);
+/// let _addrs = legacy.await?;
+/// let service = RecordTypePolicy::SrvFirst { service: "_imaps._tcp".into() };
+/// let _addrs = my_resolver.lookup("example.com", service).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn lookup(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+    policy: RecordTypePolicy,
+) -> LookupResult {
+    let Ok(parsed) = hyper::client::connect::dns::Name::from_str(name) else {
+        return Err(ResolveError::InvalidName { name: name.to_owned() }.into());
+    };
+    debug!("looking up name by policy", name = name, policy = policy);
+    match policy {
+        RecordTypePolicy::A => {
+            let lookup = resolver.ipv4_lookup(name).await?;
+            let ips = lookup.iter().map(|ip| IpAddr::V4(*ip)).collect();
+            Ok(addrs(ips, 0))
+        }
+        RecordTypePolicy::Aaaa => {
+            let lookup = resolver.ipv6_lookup(name).await?;
+            let ips = lookup.iter().map(|ip| IpAddr::V6(*ip)).collect();
+            Ok(addrs(ips, 0))
+        }
+        RecordTypePolicy::Both => {
+            let (v4, v6) = futures::join!(
+                resolver.ipv4_lookup(name),
+                resolver.ipv6_lookup(name)
+            );
+            let mut ips = IpList::new();
+            match (v4, v6) {
+                (Err(error), Err(_)) => return Err(error.into()),
+                (v4, v6) => {
+                    if let Ok(v4) = v4 {
+                        ips.extend(v4.iter().map(|ip| IpAddr::V4(*ip)));
+                    }
+                    if let Ok(v6) = v6 {
+                        ips.extend(v6.iter().map(|ip| IpAddr::V6(*ip)));
+                    }
+                }
+            }
+            Ok(addrs(ips, 0))
+        }
+        RecordTypePolicy::SrvFirst { service } => {
+            let srv_name = format!("{}.{}", service, name);
+            match lookup_srv(resolver, &srv_name).await? {
+                Some(addrs) => Ok(addrs),
+                None => Ok(do_resolve(resolver, parsed).await?.collect()),
+            }
+        }
+        RecordTypePolicy::HttpsFirst => {
+            Ok(do_resolve_svcb(resolver, None, parsed, 0).await?.collect())
+        }
+    }
+}
+
+/// Returns `ips`, in a stable order if that's been asked for, with `port`.
+fn addrs(mut ips: IpList, port: u16) -> Vec<SocketAddr> {
+    stable_order(&mut ips);
+    ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()
+}
+
+/// Looks up the SRV records at `srv_name` and then their targets' addresses,
+/// returning `None` if there aren't any records.
+async fn lookup_srv(
+    resolver: &TokioAsyncResolver,
+    srv_name: &str,
+) -> Result<Option<Vec<SocketAddr>>, Box<dyn StdError + Send + Sync>> {
+    let lookup = match resolver.srv_lookup(srv_name).await {
+        Ok(lookup) => lookup,
+        Err(error) => match error.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok(None),
+            _ => return Err(error.into()),
+        },
+    };
+    let mut records: Vec<_> = lookup.iter().collect();
+    if records.is_empty() {
+        return Ok(None);
+    }
+    // A single target of "." means the service is decidedly not available
+    // here (RFC 2782).
+    if records.len() == 1 && records[0].target().is_root() {
+        return Err(ResolveError::NotFound { name: srv_name.to_owned() }.into());
+    }
+    // Weights are meant for choosing among records at random; here, heavier
+    // ones just go first.
+    records.sort_by_key(|srv| (srv.priority(), Reverse(srv.weight())));
+
+    let lookups = join_all(
+        records.iter().map(|srv| resolver.lookup_ip(srv.target().clone())),
+    )
+    .await;
+    let mut found = Vec::new();
+    for (srv, lookup) in records.iter().zip(lookups) {
+        let Ok(lookup) = lookup else {
+            continue;
+        };
+        for addr in addrs(lookup.iter().collect(), srv.port()) {
+            if !found.contains(&addr) {
+                found.push(addr);
+            }
+        }
+    }
+    if found.is_empty() {
+        return Err(ResolveError::NotFound { name: srv_name.to_owned() }.into());
+    }
+    Ok(Some(found))
+}
+
+impl CustomDnsResolver {
+    /// Looks up `name` as `policy` says (see [`lookup`]).
+    pub async fn lookup(
+        &self,
+        name: &str,
+        policy: RecordTypePolicy,
+    ) -> LookupResult {
+        lookup(&self.resolver, name, policy).await
+    }
+}
+
+impl MyCustomDnsResolver {
+    /// Looks up `name` as `policy` says (see [`lookup`]).
+    pub async fn lookup(
+        &self,
+        name: &str,
+        policy: RecordTypePolicy,
+    ) -> LookupResult {
+        lookup(&self.resolver, name, policy).await
+    }
+}
@@ -0,0 +1,202 @@
+//! Catching lookups that come back around to where they started
+//!
+//! Layers are composed statically, so a stack can't contain itself, but a
+//! lookup can still find its way back in: through `global::global()` or a
+//! shared `handle::ResolverHandle` used as an inner resolver, or a layer of
+//! the application's own that resolves some other name.  When a rewrite
+//! along the way maps "a" to "b" and another maps "b" back to "a", the lookup
+//! goes around forever, building ever-deeper futures until it runs out of
+//! time or memory.
+//!
+//! Each lookup keeps track of the layers it has passed through, and the name
+//! it was resolving at each.  Coming back to a layer with a name it has
+//! already seen fails the lookup with [`ResolveError::LookupLoop`], and
+//! passing through more than a maximum number of layers (even without a
+//! cycle, say from a rewrite that keeps making names longer) fails it with
+//! [`ResolveError::TooManyRewrites`].  `normalize::NormalizingResolver` does
+//! this on its own whenever it rewrites a name, and [`LoopGuardResolver`]
+//! does it for anything else.
+//!
+//! Like the other task-local settings in this crate, this only follows
+//! lookups made on the task running the original one.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// How many layers a lookup can pass through when nothing says otherwise
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+
+tokio::task_local! {
+    static CURRENT_PATH: Arc<Path>;
+}
+
+/// Where the lookup in scope has been
+struct Path {
+    /// each layer passed through, and the name it was asked about
+    hops: Vec<(usize, String)>,
+    max_depth: usize,
+}
+
+/// Returns an identifier for a new layer, different from every other
+/// layer's.
+pub(crate) fn layer_id() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Runs the lookup made by `lookup` as having passed through `layer` while
+/// resolving `name`, unless that makes a loop or goes deeper than allowed
+///
+/// `max_depth` applies to this lookup and everything under it.  A smaller
+/// limit from an enclosing layer still applies, and so does
+/// [`DEFAULT_MAX_DEPTH`] if none says otherwise.  The lookup is only created
+/// once its path is in scope, so that the layers under this one see it even
+/// if they check it right away.
+pub(crate) async fn enter<T, F, Fut>(
+    layer: usize,
+    name: &str,
+    max_depth: Option<usize>,
+    lookup: F,
+) -> Result<T, Box<dyn StdError + Send + Sync>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>,
+{
+    let outer = CURRENT_PATH.try_with(Arc::clone).ok();
+    let (mut hops, outer_depth) = match &outer {
+        Some(path) => (path.hops.clone(), Some(path.max_depth)),
+        None => (Vec::new(), None),
+    };
+    let max_depth = match (outer_depth, max_depth) {
+        (Some(outer), Some(inner)) => outer.min(inner),
+        (Some(depth), None) | (None, Some(depth)) => depth,
+        (None, None) => DEFAULT_MAX_DEPTH,
+    };
+
+    if hops.iter().any(|(l, n)| *l == layer && n == name) {
+        let mut chain: Vec<String> = Vec::new();
+        for (_, hop) in &hops {
+            if chain.last() != Some(hop) {
+                chain.push(hop.clone());
+            }
+        }
+        chain.push(name.to_owned());
+        debug!("lookup looped", name = name, chain = chain);
+        return Err(
+            ResolveError::LookupLoop { name: name.to_owned(), chain }.into()
+        );
+    }
+    if hops.len() >= max_depth {
+        debug!("lookup went too deep", name = name, limit = max_depth);
+        let error =
+            ResolveError::TooManyRewrites { name: name.to_owned(), max_depth };
+        return Err(error.into());
+    }
+
+    hops.push((layer, name.to_owned()));
+    let path = Arc::new(Path { hops, max_depth });
+    CURRENT_PATH.scope(path, async move { lookup().await }).await
+}
+
+/// Fails lookups that come back through this layer with a name it's already
+/// resolving, or that have passed through too many layers
+///
+/// Put one around a layer that can send lookups back into the stack it's
+/// part of, or around a stack that's used, through a handle, by layers
+/// inside of it.
+///
+/// ```
+/// # use futures::future::FutureExt;
+/// # use reqwest_resolve::global::{global, install_global};
+/// # use reqwest_resolve::loops::LoopGuardResolver;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve, MyResolving};
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+///
+/// // Resolves the names it knows, and sends the rest to the global
+/// // resolver, which is the stack this is part of.
+/// struct Delegate;
+///
+/// impl MyResolve for Delegate {
+///     fn resolve(
+///         &self,
+///         name: hyper::client::connect::dns::Name,
+///     ) -> MyResolving<'_> {
+///         if name.as_str() == "api.example.com" {
+///             return HOSTS.resolve(name);
+///         }
+///         async move { global().unwrap().resolve(name).await }.boxed().into()
+///     }
+/// }
+///
+/// install_global(LoopGuardResolver::new(Delegate)).unwrap();
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let stack = global().unwrap();
+/// assert!(stack.resolve_to_vec("api.example.com").await.is_ok());
+/// // Without the guard, this would never finish.
+/// let error = stack.resolve_to_vec("www.example.com").await.unwrap_err();
+/// assert!(error.to_string().contains("loops"));
+/// # });
+/// ```
+pub struct LoopGuardResolver<R> {
+    inner: R,
+    id: usize,
+    max_depth: Option<usize>,
+}
+
+impl<R> LoopGuardResolver<R> {
+    /// Uses the maximum depth of any enclosing guard, or
+    /// [`DEFAULT_MAX_DEPTH`].
+    pub fn new(inner: R) -> LoopGuardResolver<R> {
+        LoopGuardResolver { inner, id: layer_id(), max_depth: None }
+    }
+
+    /// Fails lookups that pass through more than `max_depth` layers,
+    /// counting this one, or fewer if an enclosing guard says so.
+    pub fn with_max_depth(inner: R, max_depth: usize) -> LoopGuardResolver<R> {
+        LoopGuardResolver { inner, id: layer_id(), max_depth: Some(max_depth) }
+    }
+}
+
+impl<R: MyResolve> MyResolve for LoopGuardResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let host = name.as_str().to_owned();
+            enter(self.id, &host, self.max_depth, || self.inner.resolve(name))
+                .await
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let host = name.as_str().to_owned();
+            enter(self.id, &host, self.max_depth, || {
+                self.inner.resolve_detailed(name)
+            })
+            .await
+        }
+        .boxed()
+    }
+}
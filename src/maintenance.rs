@@ -0,0 +1,186 @@
+//! Switching lookups off while a service keeps running
+//!
+//! During an incident, an operator sometimes needs a service to stop
+//! making outbound requests right away: to contain a compromise, to stop
+//! hammering a partner that's down, or to keep data from leaving while
+//! something is looked into.  Restarting every instance with a different
+//! configuration takes too long, and requests that are already queued go
+//! out anyway.
+//!
+//! A [`MaintenanceResolver`] fails every lookup, as soon as its
+//! [`MaintenanceSwitch`] is engaged, with
+//! [`ResolveError::ResolutionDisabled`] (which says what happened, rather
+//! than looking like an outage), except for names in the switch's
+//! allowlist.  Connections that are already open aren't affected, so
+//! reqwest clients can keep using the ones they have until they're closed.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::logging::warning;
+use crate::special_use::in_domain;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// The state shared between a switch's clones
+#[derive(Default)]
+struct State {
+    engaged: AtomicBool,
+    /// domains, normalized with `special_use::normalize()`
+    allowlist: RwLock<Vec<String>>,
+    refused: AtomicU64,
+}
+
+/// Turns lookups off and on for every [`MaintenanceResolver`] it's been
+/// given to
+///
+/// Clones share the same state, so one can be kept wherever the operator's
+/// command will arrive (an admin endpoint, a signal handler, a watched
+/// configuration flag) while the resolvers carry the others.
+#[derive(Clone, Default)]
+pub struct MaintenanceSwitch {
+    state: Arc<State>,
+}
+
+impl MaintenanceSwitch {
+    /// Starts out released, with nothing in the allowlist.
+    pub fn new() -> MaintenanceSwitch {
+        MaintenanceSwitch::default()
+    }
+
+    /// Makes every lookup fail, other than the allowlist's.
+    pub fn engage(&self) {
+        if !self.state.engaged.swap(true, Ordering::SeqCst) {
+            warning!("resolution switched off for maintenance");
+        }
+    }
+
+    /// Lets lookups through again.
+    pub fn release(&self) {
+        if self.state.engaged.swap(false, Ordering::SeqCst) {
+            warning!("resolution switched back on");
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.state.engaged.load(Ordering::SeqCst)
+    }
+
+    /// Lets `domain` and the names under it through while the switch is
+    /// engaged, like the names an incident response needs (a status page,
+    /// the paging service).
+    pub fn allow(&self, domain: &str) {
+        let domain = normalize(domain).into_owned();
+        let mut allowlist = self.state.allowlist.write().unwrap();
+        if !allowlist.contains(&domain) {
+            allowlist.push(domain);
+        }
+    }
+
+    /// Takes `domain` back out of the allowlist, returning whether it was
+    /// there.
+    pub fn disallow(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+        let mut allowlist = self.state.allowlist.write().unwrap();
+        let before = allowlist.len();
+        allowlist.retain(|allowed| *allowed != domain);
+        allowlist.len() != before
+    }
+
+    pub fn allowlist(&self) -> Vec<String> {
+        self.state.allowlist.read().unwrap().clone()
+    }
+
+    /// Returns how many lookups have been refused since the switch was
+    /// created.
+    pub fn refused(&self) -> u64 {
+        self.state.refused.load(Ordering::Relaxed)
+    }
+
+    /// Returns the error to fail a lookup of `name` with, if the switch
+    /// says it should.
+    fn check(&self, name: &str) -> Result<(), ResolveError> {
+        if !self.is_engaged() {
+            return Ok(());
+        }
+        let normalized = normalize(name);
+        let allowlist = self.state.allowlist.read().unwrap();
+        if allowlist.iter().any(|domain| in_domain(&normalized, domain)) {
+            return Ok(());
+        }
+        self.state.refused.fetch_add(1, Ordering::Relaxed);
+        debug!("refusing lookup during maintenance", name = name);
+        Err(ResolveError::ResolutionDisabled { name: name.to_owned() })
+    }
+}
+
+/// Fails lookups while its [`MaintenanceSwitch`] is engaged, and passes
+/// them to the inner resolver otherwise
+///
+/// ```
+/// # use reqwest_resolve::maintenance::{MaintenanceResolver, MaintenanceSwitch};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.partner.example" => ["192.0.2.10"],
+///     "status.example.com" => ["192.0.2.20"],
+/// };
+/// let switch = MaintenanceSwitch::new();
+/// switch.allow("status.example.com");
+/// let my_resolver = MaintenanceResolver::new(HOSTS, switch.clone());
+///
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// switch.engage();
+/// assert!(my_resolver.resolve_to_vec("api.partner.example").await.is_err());
+/// assert!(my_resolver.resolve_to_vec("status.example.com").await.is_ok());
+///
+/// switch.release();
+/// assert!(my_resolver.resolve_to_vec("api.partner.example").await.is_ok());
+/// # });
+/// ```
+pub struct MaintenanceResolver<R> {
+    inner: R,
+    switch: MaintenanceSwitch,
+}
+
+impl<R> MaintenanceResolver<R> {
+    pub fn new(inner: R, switch: MaintenanceSwitch) -> MaintenanceResolver<R> {
+        MaintenanceResolver { inner, switch }
+    }
+
+    pub fn switch(&self) -> &MaintenanceSwitch {
+        &self.switch
+    }
+}
+
+impl<R: MyResolve> MyResolve for MaintenanceResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.switch.check(name.as_str()) {
+            Ok(()) => self.inner.resolve(name),
+            Err(error) => MyResolving::ready(Err(error.into())),
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.switch.check(name.as_str()) {
+            Ok(()) => self.inner.resolve_detailed(name),
+            Err(error) => futures::future::err(error.into()).boxed(),
+        }
+    }
+}
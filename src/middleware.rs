@@ -0,0 +1,288 @@
+//! Telling `reqwest-middleware` stacks what DNS did for a request
+//!
+//! When a request fails to connect, reqwest reports a connection error, and
+//! a middleware can't tell whether that's because the name didn't resolve
+//! (which retrying won't fix, or will only fix after a while) or because
+//! the server didn't answer.  [`DnsTimingMiddleware`] keeps track of the
+//! lookups made for each request it passes on, through a
+//! [`DnsTimingResolver`] in the client's resolver stack, and leaves a
+//! [`DnsTiming`] describing them in the request's extensions, where
+//! middleware further out (retries, telemetry) can find it after the
+//! request finishes.  It also leaves a [`ResolutionMetadata`] in the
+//! response's extensions, with the address the response came from, so that
+//! application code logging a response can say what DNS did for it.
+//!
+//! Like the other task-local settings in this crate, this only sees lookups
+//! made on the task running the request.  reqwest reuses connections, so
+//! most requests don't look anything up at all, and a connection that hyper
+//! finishes in the background resolves without being seen.
+
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use crate::ResolvedAddr;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use reqwest::Request;
+use reqwest::Response;
+use reqwest_middleware::Middleware;
+use reqwest_middleware::Next;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use task_local_extensions::Extensions;
+
+tokio::task_local! {
+    static CURRENT_REQUEST: Arc<Mutex<Vec<DnsLookup>>>;
+}
+
+/// One lookup made for a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsLookup {
+    /// the name looked up
+    pub name: String,
+    /// how long it took
+    pub latency: Duration,
+    /// the addresses found, or what went wrong
+    pub result: Result<Vec<SocketAddr>, String>,
+    /// whether the addresses all came out of this crate's cache (see
+    /// `ResolvedAddr::cached`)
+    pub cached: bool,
+}
+
+/// The lookups made for a request, left in its extensions by
+/// [`DnsTimingMiddleware`]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DnsTiming {
+    /// every lookup made, in the order they finished.  This is empty if the
+    /// request reused a connection.
+    pub lookups: Vec<DnsLookup>,
+}
+
+impl DnsTiming {
+    /// Returns whether any lookup failed, which (if the request failed too)
+    /// means it failed because of DNS.
+    pub fn failed(&self) -> bool {
+        self.lookups.iter().any(|lookup| lookup.result.is_err())
+    }
+
+    /// Returns how long the request spent on lookups.
+    pub fn latency(&self) -> Duration {
+        self.lookups.iter().map(|lookup| lookup.latency).sum()
+    }
+}
+
+/// What DNS did for a request, left in its response's extensions by
+/// [`DnsTimingMiddleware`]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResolutionMetadata {
+    /// the address the response came from, if reqwest knows it (see
+    /// `reqwest::Response::remote_addr`)
+    pub remote_addr: Option<SocketAddr>,
+    /// whether the lookup that found `remote_addr` came out of this crate's
+    /// cache, or nothing if the request didn't make that lookup (because it
+    /// reused a connection)
+    pub cached: Option<bool>,
+    /// how long the request spent on lookups
+    pub resolve_duration: Duration,
+}
+
+impl ResolutionMetadata {
+    /// Describes a response from `remote_addr` to a request that made
+    /// `timing`'s lookups.
+    pub fn new(
+        remote_addr: Option<SocketAddr>,
+        timing: &DnsTiming,
+    ) -> ResolutionMetadata {
+        // The lookup the connection's address came from, or (if reqwest
+        // doesn't say what that was) the last one that found anything
+        let lookup = timing.lookups.iter().rev().find(|lookup| {
+            let Ok(addrs) = &lookup.result else {
+                return false;
+            };
+            match remote_addr {
+                Some(remote) => {
+                    addrs.iter().any(|addr| addr.ip() == remote.ip())
+                }
+                None => !addrs.is_empty(),
+            }
+        });
+        ResolutionMetadata {
+            remote_addr,
+            cached: lookup.map(|lookup| lookup.cached),
+            resolve_duration: timing.latency(),
+        }
+    }
+}
+
+/// Records each request's lookups in its extensions, as a [`DnsTiming`],
+/// and what they came to in its response's, as a [`ResolutionMetadata`]
+///
+/// This only sees lookups made through a [`DnsTimingResolver`].  Middleware
+/// that wants to look at the [`DnsTiming`] has to be added before this, so
+/// that it's further out.
+///
+/// ```
+/// # use reqwest_resolve::middleware::{DnsTimingMiddleware, DnsTimingResolver};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// let my_resolver = DnsTimingResolver::new(HOSTS);
+/// let client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)))
+///     .build()
+///     .unwrap();
+/// let client = reqwest_middleware::ClientBuilder::new(client)
+///     // Retry middleware goes here, and can check
+///     // `extensions.get::<DnsTiming>()` after each attempt.
+///     .with(DnsTimingMiddleware::new())
+///     .build();
+/// # async fn example(
+/// #     client: reqwest_middleware::ClientWithMiddleware,
+/// # ) -> reqwest_middleware::Result<()> {
+/// # use reqwest_resolve::middleware::ResolutionMetadata;
+/// let response = client.get("http://api.example.com/").send().await?;
+/// if let Some(dns) = response.extensions().get::<ResolutionMetadata>() {
+///     println!(
+///         "{} from {:?} (cached: {:?}, DNS took {:?})",
+///         response.status(),
+///         dns.remote_addr,
+///         dns.cached,
+///         dns.resolve_duration,
+///     );
+/// }
+/// # Ok(())
+/// # }
+/// # let _ = client;
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DnsTimingMiddleware;
+
+impl DnsTimingMiddleware {
+    pub fn new() -> DnsTimingMiddleware {
+        DnsTimingMiddleware
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for DnsTimingMiddleware {
+    async fn handle(
+        &self,
+        request: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let lookups = Arc::new(Mutex::new(Vec::new()));
+        let result = CURRENT_REQUEST
+            .scope(Arc::clone(&lookups), next.run(request, extensions))
+            .await;
+        let lookups = std::mem::take(&mut *lookups.lock().unwrap());
+        let timing = DnsTiming { lookups };
+        let result = result.map(|mut response| {
+            let metadata =
+                ResolutionMetadata::new(response.remote_addr(), &timing);
+            response.extensions_mut().insert(metadata);
+            response
+        });
+        extensions.insert(timing);
+        result
+    }
+}
+
+/// Records the lookups made through the inner resolver for the request
+/// being handled by [`DnsTimingMiddleware`], if there is one
+///
+/// Lookups for a request go through the inner resolver's
+/// `resolve_detailed`, to find out whether they came out of the cache;
+/// outside of a request, they're passed straight through.
+pub struct DnsTimingResolver<R> {
+    inner: R,
+}
+
+impl<R> DnsTimingResolver<R> {
+    pub fn new(inner: R) -> DnsTimingResolver<R> {
+        DnsTimingResolver { inner }
+    }
+}
+
+/// Returns whether `addrs` all came out of the cache (and there are some).
+fn all_cached(addrs: &[ResolvedAddr]) -> bool {
+    !addrs.is_empty() && addrs.iter().all(|addr| addr.cached)
+}
+
+/// Adds `lookup` to the request being handled, if there is one.
+fn record(lookup: DnsLookup) {
+    let _ = CURRENT_REQUEST
+        .try_with(|lookups| lookups.lock().unwrap().push(lookup));
+}
+
+impl<R: MyResolve> MyResolve for DnsTimingResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            if CURRENT_REQUEST.try_with(|_| ()).is_err() {
+                return self.inner.resolve(name).await;
+            }
+            let start = Instant::now();
+            let result = self.inner.resolve_detailed(name.clone()).await;
+            let latency = start.elapsed();
+            let (result, recorded, cached) = match result {
+                Ok(resolved) => {
+                    let cached = all_cached(&resolved);
+                    let addrs: AddrList =
+                        resolved.iter().map(|addr| addr.addr).collect();
+                    let recorded = Ok(addrs.to_vec());
+                    let addrs = Box::new(addrs.into_iter()) as Addrs;
+                    (Ok(addrs), recorded, cached)
+                }
+                Err(error) => {
+                    let recorded = Err(error.to_string());
+                    (Err(error), recorded, false)
+                }
+            };
+            record(DnsLookup {
+                name: name.as_str().to_owned(),
+                latency,
+                result: recorded,
+                cached,
+            });
+            result
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            if CURRENT_REQUEST.try_with(|_| ()).is_err() {
+                return self.inner.resolve_detailed(name).await;
+            }
+            let start = Instant::now();
+            let result = self.inner.resolve_detailed(name.clone()).await;
+            record(DnsLookup {
+                name: name.as_str().to_owned(),
+                latency: start.elapsed(),
+                result: match &result {
+                    Ok(addrs) => {
+                        Ok(addrs.iter().map(|addr| addr.addr).collect())
+                    }
+                    Err(error) => Err(error.to_string()),
+                },
+                cached: result.as_ref().is_ok_and(|addrs| all_cached(addrs)),
+            });
+            result
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,204 @@
+//! Client certificates for encrypted upstreams
+//!
+//! Private resolvers (the kind a company runs for its own network) often
+//! only answer clients that present a certificate, using mutual TLS.  Those
+//! certificates tend to be short-lived and are replaced on disk by whatever
+//! issues them, so a long-running process can't just read them once.
+//! [`ClientCertificate`] reads a certificate and key from PEM files and reads
+//! them again whenever they change, and [`client_config`] builds the TLS
+//! configuration that presents it, for `ResolverConfig::set_tls_client_config`
+//! or [`StartupConfig::tls_client_config`](crate::startup::StartupConfig).
+//!
+//! This works for DNS-over-TLS as well as DNS-over-HTTPS.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::logging::warning;
+use crate::upstream_tls::invalid;
+use crate::upstream_tls::read_certs;
+use crate::upstream_tls::UpstreamTls;
+use rustls::client::ResolvesClientCert;
+use rustls::sign::CertifiedKey;
+use rustls::ClientConfig;
+use rustls::PrivateKey;
+use rustls::SignatureScheme;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A certificate and key as last read, and when their files were modified
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    cert_modified: Option<SystemTime>,
+    key_modified: Option<SystemTime>,
+}
+
+/// A client certificate and key read from PEM files, and read again when
+/// they change
+///
+/// Before each TLS handshake, the files' modification times are checked,
+/// and if either has changed, both are read again.  If that fails (say, the
+/// certificate has been replaced but the key hasn't yet), the failure is
+/// logged as a warning and the certificate read before is used until the
+/// files can be read again.
+pub struct ClientCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl ClientCertificate {
+    /// Reads the certificate chain in `cert_path` (the client's certificate
+    /// first) and the private key in `key_path` (PKCS #8, RSA, or SEC1),
+    /// failing with [`ResolveError::InvalidConfig`] if they can't be read.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Arc<ClientCertificate>, ResolveError> {
+        let cert_path = cert_path.as_ref().to_owned();
+        let key_path = key_path.as_ref().to_owned();
+        let loaded = load(&cert_path, &key_path)?;
+        Ok(Arc::new(ClientCertificate {
+            cert_path,
+            key_path,
+            loaded: Mutex::new(loaded),
+        }))
+    }
+
+    /// Reads the files again now, whether or not they've changed.  If they
+    /// can't be read, the certificate read before is kept.
+    pub fn reload(&self) -> Result<(), ResolveError> {
+        let loaded = load(&self.cert_path, &self.key_path)?;
+        debug!("read client certificate", path = self.cert_path);
+        *self.loaded.lock().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Returns the certificate to present, reading the files again first if
+    /// they've changed.
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if modified(&self.cert_path) != loaded.cert_modified
+            || modified(&self.key_path) != loaded.key_modified
+        {
+            match load(&self.cert_path, &self.key_path) {
+                Ok(new) => {
+                    debug!("read client certificate", path = self.cert_path);
+                    *loaded = new;
+                }
+                Err(error) => {
+                    warning!(
+                        "couldn't read changed client certificate; using \
+                         the one read before",
+                        path = self.cert_path,
+                        error = error,
+                    );
+                }
+            }
+        }
+        Arc::clone(&loaded.key)
+    }
+}
+
+impl ResolvesClientCert for ClientCertificate {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Returns a TLS configuration that trusts the same root certificates
+/// trust-dns does by default (Mozilla's) and presents `certificate`.
+///
+/// ```no_run
+/// # use reqwest_resolve::mtls::{client_config, ClientCertificate};
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfigGroup, ResolverConfig, ResolverOpts,
+/// # };
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let certificate = ClientCertificate::from_pem_files(
+///     "/etc/dns-client/tls.crt",
+///     "/etc/dns-client/tls.key",
+/// )
+/// .unwrap();
+/// let mut config = ResolverConfig::from_parts(
+///     None,
+///     Vec::new(),
+///     NameServerConfigGroup::from_ips_https(
+///         &["10.0.0.53".parse().unwrap()],
+///         443,
+///         String::from("dns.corp.example"),
+///         true,
+///     ),
+/// );
+/// config.set_tls_client_config(client_config(certificate));
+/// let _resolver =
+///     TokioAsyncResolver::tokio(config, ResolverOpts::default()).unwrap();
+/// ```
+pub fn client_config(certificate: Arc<ClientCertificate>) -> Arc<ClientConfig> {
+    UpstreamTls::new().with_client_certificate(certificate).build()
+}
+
+/// Like [`client_config`], but trusts only the root certificates in the PEM
+/// file at `ca_path`, as for a resolver whose certificate comes from a
+/// private certificate authority
+///
+/// For pinning, too, use [`UpstreamTls`].
+pub fn client_config_with_ca(
+    ca_path: impl AsRef<Path>,
+    certificate: Arc<ClientCertificate>,
+) -> Result<Arc<ClientConfig>, ResolveError> {
+    Ok(UpstreamTls::new()
+        .with_ca_file(ca_path)?
+        .with_client_certificate(certificate)
+        .build())
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<Loaded, ResolveError> {
+    // Look at the times before reading, so that a change made while reading
+    // is noticed next time.
+    let cert_modified = modified(cert_path);
+    let key_modified = modified(key_path);
+
+    let certs = read_certs(cert_path)?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "reading", "no certificates found"));
+    }
+    let pem = fs::read(key_path)
+        .map_err(|error| invalid(key_path, "reading key", error))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(&pem[..]))
+        .map_err(|error| invalid(key_path, "reading key", error))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            invalid(key_path, "reading key", "no private key found")
+        })?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|error| invalid(key_path, "unsupported key", error))?;
+
+    Ok(Loaded {
+        key: Arc::new(CertifiedKey::new(certs, key)),
+        cert_modified,
+        key_modified,
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -0,0 +1,36 @@
+//! Remembering parsed names
+//!
+//! Given a string, trust-dns parses it into a `Name` for every lookup, which
+//! runs it through IDNA processing and allocates along the way.  A client
+//! mostly looks up the same few hostnames over and over, so the resolvers in
+//! this crate hand trust-dns names parsed once and kept here instead.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use trust_dns_resolver::Name;
+
+/// How many parsed names are kept.  Names first looked up after this many
+/// others are parsed every time, as before.
+const MAX_PARSED_NAMES: usize = 1024;
+
+static PARSED: RwLock<BTreeMap<String, Name>> = RwLock::new(BTreeMap::new());
+
+/// Returns `name` parsed the way trust-dns would parse it, or `None` for IP
+/// literals (which trust-dns answers without a lookup only when given a
+/// string) and names that don't parse (so trust-dns reports the error).
+pub(crate) fn parsed(name: &str) -> Option<Name> {
+    if name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    if let Some(parsed) = PARSED.read().unwrap().get(name) {
+        return Some(parsed.clone());
+    }
+
+    let parsed = Name::from_utf8(name).ok()?;
+    let mut names = PARSED.write().unwrap();
+    if names.len() < MAX_PARSED_NAMES {
+        names.insert(name.to_owned(), parsed.clone());
+    }
+    Some(parsed)
+}
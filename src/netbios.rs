@@ -0,0 +1,480 @@
+//! NetBIOS name queries (RFC 1001/1002)
+//!
+//! In older Windows environments, flat names like "FILESRV01" are often only
+//! resolvable through the NetBIOS Name Service: either by broadcasting a
+//! query on the local subnet or by asking a WINS server.  Programs that
+//! resolve names with `getaddrinfo()` on Windows get this for free, but
+//! trust-dns-based clients don't.  [`NetbiosFallback`] fills that gap.
+
+use crate::deterministic::random;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::logging::trace;
+use crate::resolved::ResolvedAddr;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// The UDP port the NetBIOS Name Service uses
+pub const NETBIOS_NS_PORT: u16 = 137;
+/// How long to wait for an answer when no timeout is configured
+pub const DEFAULT_NETBIOS_TIMEOUT: Duration = Duration::from_millis(750);
+
+/// NetBIOS names are at most 15 characters, plus a one-byte suffix.
+const NETBIOS_NAME_MAX: usize = 15;
+/// The suffix for the "workstation" name, which every Windows host registers
+const NETBIOS_SUFFIX_WORKSTATION: u8 = 0x00;
+/// Resource record type "NB" (NetBIOS general name service)
+const NBNS_TYPE_NB: u16 = 0x0020;
+const NBNS_CLASS_IN: u16 = 0x0001;
+const NBNS_FLAG_RESPONSE: u16 = 0x8000;
+const NBNS_FLAG_RD: u16 = 0x0100;
+const NBNS_FLAG_BROADCAST: u16 = 0x0010;
+
+/// Resolves flat names with NetBIOS Name Service queries
+///
+/// By default, queries are broadcast on the local subnet.  Configuring WINS
+/// servers with [`NetbiosResolver::with_wins_servers`] sends them directly to
+/// those servers instead.  The first positive answer wins.
+pub struct NetbiosResolver {
+    wins_servers: Vec<Ipv4Addr>,
+    timeout: Duration,
+}
+
+impl NetbiosResolver {
+    /// Broadcasts queries on the local subnet.
+    pub fn new(timeout: Duration) -> NetbiosResolver {
+        NetbiosResolver { wins_servers: Vec::new(), timeout }
+    }
+
+    /// Sends queries to these WINS servers instead of broadcasting.
+    ///
+    /// NetBIOS only runs over IPv4, so that's all WINS servers can have.
+    pub fn with_wins_servers(
+        wins_servers: Vec<Ipv4Addr>,
+        timeout: Duration,
+    ) -> NetbiosResolver {
+        NetbiosResolver { wins_servers, timeout }
+    }
+}
+
+impl Default for NetbiosResolver {
+    fn default() -> NetbiosResolver {
+        NetbiosResolver::new(DEFAULT_NETBIOS_TIMEOUT)
+    }
+}
+
+impl MyResolve for NetbiosResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve_netbios(&self.wins_servers, self.timeout, name)
+            .boxed()
+            .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let addrs =
+                do_resolve_netbios(&self.wins_servers, self.timeout, name)
+                    .await?;
+            Ok(addrs
+                .map(|addr| ResolvedAddr {
+                    backend: Cow::Borrowed("netbios"),
+                    ..ResolvedAddr::unknown(addr)
+                })
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+async fn do_resolve_netbios(
+    wins_servers: &[Ipv4Addr],
+    timeout: Duration,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    let not_found =
+        || ResolveError::NotFound { name: name.as_str().to_owned() }.into();
+    let Some(encoded) = encode_name(name.as_str()) else {
+        return Err(not_found());
+    };
+
+    let broadcast = wins_servers.is_empty();
+    let targets: Vec<SocketAddr> = if broadcast {
+        vec![SocketAddr::from((Ipv4Addr::BROADCAST, NETBIOS_NS_PORT))]
+    } else {
+        wins_servers
+            .iter()
+            .map(|ip| SocketAddr::from((*ip, NETBIOS_NS_PORT)))
+            .collect()
+    };
+
+    match query_servers(&targets, broadcast, &encoded, timeout).await? {
+        Some(ips) => {
+            let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, 0));
+            Ok(Box::new(addrs))
+        }
+        None => Err(not_found()),
+    }
+}
+
+/// Sends a query to each of `targets` and returns the addresses in the first
+/// positive answer, or `None` if none arrives before `timeout`.
+///
+/// A server that can't be reached doesn't stop the others from answering:
+/// this only fails if the query couldn't be sent anywhere.
+async fn query_servers(
+    targets: &[SocketAddr],
+    broadcast: bool,
+    encoded: &[u8; 32],
+    timeout: Duration,
+) -> Result<Option<Vec<IpAddr>>, std::io::Error> {
+    let id = random::<u16>();
+    let query = query_packet(id, encoded, broadcast);
+    let socket =
+        UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.set_broadcast(broadcast)?;
+    let mut send_error = None;
+    let mut sent = 0;
+    for target in targets {
+        match socket.send_to(&query, target).await {
+            Ok(_) => sent += 1,
+            Err(error) => {
+                debug!(
+                    "NetBIOS query not sent",
+                    server = target,
+                    error = error
+                );
+                send_error = Some(error);
+            }
+        }
+    }
+    if sent == 0 {
+        if let Some(error) = send_error {
+            return Err(error);
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let received =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await;
+        let Ok(received) = received else {
+            return Ok(None);
+        };
+        // Some platforms report an ICMP error from one server (e.g., port
+        // unreachable) as a failed receive; other servers may still answer.
+        let len = match received {
+            Ok((len, _)) => len,
+            Err(error) => {
+                trace!("NetBIOS receive failed", error = error);
+                continue;
+            }
+        };
+        if let Some(ips) = parse_response(id, &buf[..len]) {
+            if !ips.is_empty() {
+                return Ok(Some(ips));
+            }
+        }
+    }
+}
+
+/// Encodes a flat name using NetBIOS "first-level encoding": the name is
+/// uppercased and padded to 15 bytes, given a suffix byte, and then each
+/// nibble becomes a letter from 'A' to 'P'.  Returns `None` for names that
+/// can't be NetBIOS names.
+fn encode_name(name: &str) -> Option<[u8; 32]> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty()
+        || name.len() > NETBIOS_NAME_MAX
+        || name.contains('.')
+        || !name.is_ascii()
+    {
+        return None;
+    }
+
+    let mut raw = [b' '; NETBIOS_NAME_MAX + 1];
+    raw[..name.len()].copy_from_slice(name.to_ascii_uppercase().as_bytes());
+    raw[NETBIOS_NAME_MAX] = NETBIOS_SUFFIX_WORKSTATION;
+
+    let mut encoded = [0u8; 32];
+    for (i, byte) in raw.iter().enumerate() {
+        encoded[2 * i] = b'A' + (byte >> 4);
+        encoded[2 * i + 1] = b'A' + (byte & 0x0f);
+    }
+    Some(encoded)
+}
+
+fn query_packet(id: u16, encoded: &[u8; 32], broadcast: bool) -> Vec<u8> {
+    let mut flags = NBNS_FLAG_RD;
+    if broadcast {
+        flags |= NBNS_FLAG_BROADCAST;
+    }
+
+    let mut packet = Vec::with_capacity(50);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    // one question, no answers, authority records, or additional records
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    packet.push(32);
+    packet.extend_from_slice(encoded);
+    // no scope ID
+    packet.push(0);
+    packet.extend_from_slice(&NBNS_TYPE_NB.to_be_bytes());
+    packet.extend_from_slice(&NBNS_CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// Returns the addresses in a positive name query response to query `id`,
+/// or `None` if this isn't one.
+fn parse_response(id: u16, packet: &[u8]) -> Option<Vec<IpAddr>> {
+    let u16_at = |offset: usize| -> Option<u16> {
+        let bytes = packet.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id
+        || flags & NBNS_FLAG_RESPONSE == 0
+        || flags & 0x000f != 0
+        || u16_at(6)? == 0
+    {
+        return None;
+    }
+
+    // Skip any question entries (positive responses normally have none) and
+    // then the answer's name.
+    let mut offset = 12;
+    for _ in 0..u16_at(4)? {
+        offset = skip_name(packet, offset)? + 4;
+    }
+    offset = skip_name(packet, offset)?;
+
+    let rr_type = u16_at(offset)?;
+    let rdlength = usize::from(u16_at(offset + 8)?);
+    if rr_type != NBNS_TYPE_NB {
+        return None;
+    }
+
+    // The RDATA is a list of (2-byte flags, 4-byte IPv4 address) entries.
+    let rdata = packet.get(offset + 10..offset + 10 + rdlength)?;
+    Some(
+        rdata
+            .chunks_exact(6)
+            .map(|entry| {
+                IpAddr::from(Ipv4Addr::new(
+                    entry[2], entry[3], entry[4], entry[5],
+                ))
+            })
+            .collect(),
+    )
+}
+
+/// Returns the offset just past the encoded name starting at `offset`.
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)?;
+        if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + usize::from(len);
+        if len == 0 {
+            return Some(offset);
+        }
+    }
+}
+
+/// Resolves flat names over NetBIOS when the inner resolver can't
+///
+/// This works like [`crate::llmnr::LlmnrFallback`] (when that's enabled):
+/// names containing a dot only ever go to the inner resolver, and the NetBIOS
+/// query only happens after the inner resolver fails.  Windows tries DNS,
+/// then LLMNR, then NetBIOS, and wrapping an `LlmnrFallback` in a
+/// `NetbiosFallback` gets the same order.
+pub struct NetbiosFallback<R> {
+    inner: R,
+    netbios: NetbiosResolver,
+}
+
+impl<R> NetbiosFallback<R> {
+    pub fn new(inner: R, netbios: NetbiosResolver) -> NetbiosFallback<R> {
+        NetbiosFallback { inner, netbios }
+    }
+}
+
+impl<R: MyResolve> MyResolve for NetbiosFallback<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        if name.as_str().trim_end_matches('.').contains('.') {
+            return self.inner.resolve(name);
+        }
+
+        async move {
+            match self.inner.resolve(name.clone()).await {
+                Ok(addrs) => Ok(addrs),
+                Err(error) => {
+                    self.netbios.resolve(name).await.map_err(|_| error)
+                }
+            }
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        if name.as_str().trim_end_matches('.').contains('.') {
+            return self.inner.resolve_detailed(name);
+        }
+
+        async move {
+            match self.inner.resolve_detailed(name.clone()).await {
+                Ok(addrs) => Ok(addrs),
+                Err(error) => {
+                    self.netbios.resolve_detailed(name).await.map_err(|_| error)
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_name;
+    use super::parse_response;
+    use super::query_packet;
+    use super::query_servers;
+    use super::NetbiosFallback;
+    use super::NetbiosResolver;
+    use super::NBNS_FLAG_BROADCAST;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use std::net::IpAddr;
+    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "filesrv01" => ["192.0.2.1"],
+    };
+
+    #[test]
+    fn encoding() {
+        // The example from RFC 1001, section 14.1, with the workstation
+        // suffix instead of a space.
+        let encoded = encode_name("fred").unwrap();
+        assert_eq!(&encoded, b"EGFCEFEECACACACACACACACACACACAAA");
+        assert_eq!(encode_name("FRED."), Some(encoded));
+
+        assert_eq!(encode_name(""), None);
+        assert_eq!(encode_name("filesrv.example"), None);
+        assert_eq!(encode_name("a-name-too-long-for-netbios"), None);
+    }
+
+    #[test]
+    fn packets() {
+        let encoded = encode_name("fred").unwrap();
+        let query = query_packet(0x1234, &encoded, false);
+        assert_eq!(query.len(), 50);
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        let broadcast = query_packet(0x1234, &encoded, true);
+        assert_ne!(broadcast[3] & NBNS_FLAG_BROADCAST as u8, 0);
+
+        // A positive response: the header, the name, and one NB record
+        // with two addresses.
+        let mut response = vec![0x12, 0x34, 0x85, 0x00, 0, 0, 0, 1, 0, 0, 0, 0];
+        response.push(32);
+        response.extend_from_slice(&encoded);
+        response.push(0);
+        response.extend_from_slice(&[0, 0x20, 0, 1, 0, 0, 0, 60, 0, 12]);
+        response.extend_from_slice(&[0, 0, 192, 0, 2, 1, 0, 0, 192, 0, 2, 2]);
+        let expected: Vec<IpAddr> = vec![
+            Ipv4Addr::new(192, 0, 2, 1).into(),
+            Ipv4Addr::new(192, 0, 2, 2).into(),
+        ];
+        assert_eq!(parse_response(0x1234, &response), Some(expected));
+
+        // Responses to other queries, and negative ones, are ignored.
+        assert_eq!(parse_response(0x4321, &response), None);
+        response[3] = 0x03;
+        assert_eq!(parse_response(0x1234, &response), None);
+        assert_eq!(parse_response(0x1234, &query), None);
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        // Nothing answers NetBIOS queries on the loopback address, so every
+        // query times out.
+        let netbios = NetbiosResolver::with_wins_servers(
+            vec![Ipv4Addr::LOCALHOST],
+            Duration::from_millis(10),
+        );
+        let resolver = NetbiosFallback::new(HOSTS, netbios);
+
+        let found = resolver.resolve_to_vec("filesrv01").await.unwrap();
+        assert_eq!(found, ["192.0.2.1:0".parse().unwrap()]);
+        let error =
+            resolver.resolve_to_vec("filesrv02").await.unwrap_err().to_string();
+        assert!(error.contains("filesrv02"), "{}", error);
+        assert!(resolver.resolve_to_vec("filesrv01.example").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dead_server() {
+        // A WINS server that answers every query with 192.0.2.1
+        let live = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((len, from)) = live.recv_from(&mut buf).await {
+                let mut response = buf[..2].to_vec();
+                response.extend_from_slice(&[0x85, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+                response.extend_from_slice(&buf[12..len - 4]);
+                response.extend_from_slice(&[0, 0x20, 0, 1, 0, 0, 0, 60, 0, 6]);
+                response.extend_from_slice(&[0, 0, 192, 0, 2, 1]);
+                let _ = live.send_to(&response, from).await;
+            }
+        });
+
+        // One server whose port is closed, and one the query can't even be
+        // sent to (a broadcast address, without permission to broadcast)
+        let dead_addr =
+            UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let unsendable = SocketAddr::from((Ipv4Addr::BROADCAST, 137));
+
+        let encoded = encode_name("filesrv01").unwrap();
+        let timeout = Duration::from_secs(5);
+        let targets = [unsendable, dead_addr, live_addr];
+        let found = query_servers(&targets, false, &encoded, timeout).await;
+        let expected: Vec<IpAddr> = vec![Ipv4Addr::new(192, 0, 2, 1).into()];
+        assert_eq!(found.unwrap(), Some(expected));
+
+        // It's only an error if the query couldn't be sent anywhere.
+        let error =
+            query_servers(&[unsendable], false, &encoded, timeout).await;
+        assert!(error.is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Sending queries from another network namespace
+//!
+//! Network appliances often keep each routing domain in its own Linux
+//! network namespace, with its own interfaces, routes, and DNS servers, and
+//! run one process that makes requests in all of them.  A socket belongs to
+//! the namespace of the thread that created it, so [`NetnsTransport`] keeps
+//! a thread in the target namespace that does nothing but create sockets,
+//! and uses them for queries from the process's usual tasks.  (Point
+//! reqwest's own connections into the namespace the same way, or the
+//! answers won't be much use.)
+//!
+//! A VRF, as opposed to a namespace, is a network device, so queries are
+//! sent in one with
+//! [`source::DeviceTransport`](crate::source::DeviceTransport) and the
+//! VRF's name.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::exchange::exchange_tcp;
+use crate::exchange::exchange_udp;
+use crate::exchange::local_addr;
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc;
+use tokio::net::TcpSocket;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::Message;
+
+/// Where `ip netns add` puts the namespaces it creates
+pub const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// What the thread in the namespace is asked to create
+enum SocketRequest {
+    Udp {
+        local: SocketAddr,
+        reply: oneshot::Sender<io::Result<std::net::UdpSocket>>,
+    },
+    Tcp {
+        local: SocketAddr,
+        reply: oneshot::Sender<io::Result<TcpSocket>>,
+    },
+}
+
+/// Sends queries over UDP and TCP from inside a network namespace
+///
+/// Creating the transport starts a thread (named "netns" followed by the
+/// namespace's path) and moves it into the namespace, which takes
+/// `CAP_SYS_ADMIN`.  The thread exits when the transport is dropped.  Queries
+/// to servers using other protocols fail.
+///
+/// ```no_run
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::netns::NetnsTransport;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+/// # };
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// // The namespace created with `ip netns add tenant-a`, where 10.1.0.53
+/// // is reachable.
+/// let transport = NetnsTransport::named("tenant-a")?;
+/// let mut config = ResolverConfig::new();
+/// config.add_name_server(NameServerConfig::new(
+///     "10.1.0.53:53".parse()?,
+///     Protocol::Udp,
+/// ));
+/// let resolver =
+///     transport_resolver(config, ResolverOpts::default(), transport)?;
+/// let _my_resolver = TransportDnsResolver::new(resolver);
+/// # Ok(())
+/// # }
+/// ```
+pub struct NetnsTransport {
+    requests: mpsc::Sender<SocketRequest>,
+}
+
+impl NetnsTransport {
+    /// Uses the namespace called `name` by `ip netns` (the one bound at
+    /// `/run/netns/<name>`).
+    pub fn named(name: &str) -> io::Result<NetnsTransport> {
+        NetnsTransport::from_path(Path::new(NETNS_RUN_DIR).join(name))
+    }
+
+    /// Uses the namespace bound at `path`, which can also be a process's
+    /// namespace (like `/proc/1234/ns/net`).
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<NetnsTransport> {
+        let path = path.as_ref();
+        let namespace = File::open(path)?;
+        let (requests, receiver) = mpsc::channel();
+        let (entered, entered_receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name(format!("netns {}", path.display()));
+        thread.spawn(move || {
+            // Safety: setns() only reads the descriptor, which `namespace`
+            // keeps open.
+            let result = unsafe {
+                libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET)
+            };
+            if result != 0 {
+                let _ = entered.send(Err(io::Error::last_os_error()));
+                return;
+            }
+            let _ = entered.send(Ok(()));
+            drop(namespace);
+            make_sockets(receiver);
+        })?;
+        entered_receiver.recv().map_err(|_| thread_gone())??;
+        Ok(NetnsTransport { requests })
+    }
+}
+
+/// Asks the thread in the namespace for a socket.
+async fn request<T>(
+    requests: &mpsc::Sender<SocketRequest>,
+    request: impl FnOnce(oneshot::Sender<io::Result<T>>) -> SocketRequest,
+) -> io::Result<T> {
+    let (reply, receiver) = oneshot::channel();
+    requests.send(request(reply)).map_err(|_| thread_gone())?;
+    receiver.await.map_err(|_| thread_gone())?
+}
+
+fn thread_gone() -> io::Error {
+    io::Error::other("netns thread exited")
+}
+
+/// Creates sockets for as long as anyone asks for them.
+fn make_sockets(requests: mpsc::Receiver<SocketRequest>) {
+    for request in requests {
+        match request {
+            SocketRequest::Udp { local, reply } => {
+                let socket = std::net::UdpSocket::bind(local).and_then(|s| {
+                    s.set_nonblocking(true)?;
+                    Ok(s)
+                });
+                let _ = reply.send(socket);
+            }
+            SocketRequest::Tcp { local, reply } => {
+                let socket = match local {
+                    SocketAddr::V4(_) => TcpSocket::new_v4(),
+                    SocketAddr::V6(_) => TcpSocket::new_v6(),
+                }
+                .and_then(|s| {
+                    s.bind(local)?;
+                    Ok(s)
+                });
+                let _ = reply.send(socket);
+            }
+        }
+    }
+}
+
+impl DnsTransport for NetnsTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let server = server.clone();
+        let requests = self.requests.clone();
+        async move {
+            let local = local_addr(&server);
+            let bytes = query.to_vec()?;
+            let response = match server.protocol {
+                Protocol::Udp => {
+                    let socket = request(&requests, |reply| {
+                        SocketRequest::Udp { local, reply }
+                    })
+                    .await?;
+                    let socket = UdpSocket::from_std(socket)?;
+                    exchange_udp(
+                        &socket,
+                        server.socket_addr,
+                        query.id(),
+                        &bytes,
+                    )
+                    .await?
+                }
+                Protocol::Tcp => {
+                    let socket = request(&requests, |reply| {
+                        SocketRequest::Tcp { local, reply }
+                    })
+                    .await?;
+                    exchange_tcp(socket, server.socket_addr, &bytes).await?
+                }
+                protocol => {
+                    return Err(ResolveError::from(ResolveErrorKind::Msg(
+                        format!(
+                            "can't send {:?} queries from another namespace",
+                            protocol
+                        ),
+                    )))
+                }
+            };
+            Ok(Message::from_vec(&response)?)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,223 @@
+//! Canonicalizing names before they're resolved
+//!
+//! "Example.COM", "example.com", and "example.com." are the same host as far
+//! as DNS is concerned, but not as far as a string comparison is.  Layers
+//! that key anything on the name (caches, static maps, routing rules) either
+//! have to normalize names themselves or see each spelling as a different
+//! name.  Putting a [`NormalizingResolver`] at the outside of the stack means
+//! every layer under it sees one canonical spelling.
+
+use crate::error::ResolveError;
+use crate::logging::trace;
+use crate::loops;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::str::FromStr;
+
+/// Rewrites a name into its canonical form before it's resolved
+///
+/// This is implemented for closures, for one-off rules:
+///
+/// ```
+/// # use reqwest_resolve::normalize::NameNormalizer;
+/// // Send bare service names to a fixed domain.
+/// let normalizer = |name: &str| {
+///     if name.contains('.') {
+///         name.to_owned()
+///     } else {
+///         format!("{}.svc.example.internal", name)
+///     }
+/// };
+/// assert_eq!(normalizer.normalize("api"), "api.svc.example.internal");
+/// ```
+pub trait NameNormalizer: Send + Sync {
+    fn normalize(&self, name: &str) -> String;
+}
+
+impl<F> NameNormalizer for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn normalize(&self, name: &str) -> String {
+        self(name)
+    }
+}
+
+/// Applies each normalizer in turn
+impl NameNormalizer for Vec<Box<dyn NameNormalizer>> {
+    fn normalize(&self, name: &str) -> String {
+        self.iter().fold(name.to_owned(), |name, normalizer| {
+            normalizer.normalize(&name)
+        })
+    }
+}
+
+/// What [`Canonicalize`] does with trailing dots
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TrailingDot {
+    /// Leave names as they are.
+    Keep,
+    /// Remove the trailing dot.  The resolver will treat every name as
+    /// relative, so names may be tried with the configured search domains.
+    Strip,
+    /// Add a trailing dot.  The resolver will treat every name as fully
+    /// qualified and never apply search domains.
+    Add,
+}
+
+/// The usual normalization: lowercasing, plus a choice of what to do with
+/// trailing dots
+///
+/// By default, names are lowercased and trailing dots are left alone, since
+/// removing or adding one can change which names the resolver tries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Canonicalize {
+    case_fold: bool,
+    trailing_dot: TrailingDot,
+}
+
+impl Canonicalize {
+    pub fn new() -> Canonicalize {
+        Canonicalize { case_fold: true, trailing_dot: TrailingDot::Keep }
+    }
+
+    pub fn with_trailing_dot(self, trailing_dot: TrailingDot) -> Canonicalize {
+        Canonicalize { trailing_dot, ..self }
+    }
+
+    /// Leaves the case of names alone.
+    pub fn without_case_folding(self) -> Canonicalize {
+        Canonicalize { case_fold: false, ..self }
+    }
+}
+
+impl Default for Canonicalize {
+    fn default() -> Canonicalize {
+        Canonicalize::new()
+    }
+}
+
+impl NameNormalizer for Canonicalize {
+    fn normalize(&self, name: &str) -> String {
+        let mut name =
+            if self.case_fold { name.to_lowercase() } else { name.to_owned() };
+        match self.trailing_dot {
+            TrailingDot::Keep => (),
+            TrailingDot::Strip => {
+                let len = name.trim_end_matches('.').len();
+                name.truncate(len);
+            }
+            TrailingDot::Add => {
+                if !name.ends_with('.') {
+                    name.push('.');
+                }
+            }
+        }
+        name
+    }
+}
+
+/// Normalizes each name before passing it to the inner resolver
+///
+/// If normalizing produces something that isn't a valid name, the lookup
+/// fails with [`ResolveError::InvalidName`].  A name that the normalizer
+/// changes counts as a pass through a layer for the purposes of
+/// [`loops`](crate::loops), so rewrites that lead a lookup back here with a
+/// name it has already rewritten fail with [`ResolveError::LookupLoop`].
+///
+/// ```
+/// # use reqwest_resolve::normalize::{
+/// #     Canonicalize, NormalizingResolver, TrailingDot,
+/// # };
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = NormalizingResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     Canonicalize::new().with_trailing_dot(TrailingDot::Strip),
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct NormalizingResolver<R, N> {
+    inner: R,
+    normalizer: N,
+    id: usize,
+}
+
+impl<R, N> NormalizingResolver<R, N> {
+    pub fn new(inner: R, normalizer: N) -> NormalizingResolver<R, N> {
+        NormalizingResolver { inner, normalizer, id: loops::layer_id() }
+    }
+}
+
+impl<R, N: NameNormalizer> NormalizingResolver<R, N> {
+    /// Returns `name` normalized, or `None` if normalizing doesn't change
+    /// it.
+    fn normalized(
+        &self,
+        name: &hyper::client::connect::dns::Name,
+    ) -> Option<Result<hyper::client::connect::dns::Name, ResolveError>> {
+        let normalized = self.normalizer.normalize(name.as_str());
+        if normalized == name.as_str() {
+            return None;
+        }
+        trace!("normalized name", name = name.as_str(), to = normalized);
+        Some(
+            hyper::client::connect::dns::Name::from_str(&normalized)
+                .map_err(|_| ResolveError::InvalidName { name: normalized }),
+        )
+    }
+}
+
+impl<R: MyResolve, N: NameNormalizer> MyResolve for NormalizingResolver<R, N> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.normalized(&name) {
+            None => self.inner.resolve(name),
+            Some(Ok(normalized)) => async move {
+                let host = name.as_str();
+                loops::enter(self.id, host, None, || {
+                    self.inner.resolve(normalized)
+                })
+                .await
+            }
+            .boxed()
+            .into(),
+            Some(Err(error)) => MyResolving::ready(Err(error.into())),
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.normalized(&name) {
+            None => self.inner.resolve_detailed(name),
+            Some(Ok(normalized)) => async move {
+                let host = name.as_str();
+                loops::enter(self.id, host, None, || {
+                    self.inner.resolve_detailed(normalized)
+                })
+                .await
+            }
+            .boxed(),
+            Some(Err(error)) => {
+                futures::future::ready(Err(error.into())).boxed()
+            }
+        }
+    }
+}
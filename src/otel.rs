@@ -0,0 +1,273 @@
+//! Reporting lookups to OpenTelemetry
+//!
+//! [`OtelResolver`] records a span and metrics for every lookup through the
+//! `opentelemetry` API, using whatever tracer and meter providers the
+//! application has installed globally.  Attribute and instrument names follow
+//! the OpenTelemetry semantic conventions for DNS where there are any:
+//!
+//! * "dns.lookup.duration": a histogram of lookup times, in seconds
+//! * "dns.lookups": a counter of lookups
+//! * "dns.question.name": the name looked up, on spans and metrics
+//! * "error.type": on failed lookups, what went wrong (like "not_found" or
+//!   "timeout")
+//!
+//! Spans are called "dns.lookup" and also carry "dns.answer.count", the
+//! number of addresses returned.  When the lookup's queries went through a
+//! [`CnameTraceFilter`](crate::cname_trace::CnameTraceFilter), spans also
+//! carry "dns.cname.chain", the names the lookup went through (if there was
+//! more than one), and a "dns.query" event for each query, with its
+//! "dns.question.name", "dns.question.type", "server.address" and
+//! "dns.query.duration" (in seconds).  Labels attached with
+//! [`labels::with_labels`](crate::labels::with_labels) are added to both spans
+//! and metrics, under their own keys.
+//!
+//! Including the name in metrics means one time series per name.  That's the
+//! convention, and it's fine for services that talk to a bounded set of
+//! hosts, but something to be aware of for ones that resolve arbitrary,
+//! user-supplied names.
+
+use crate::cname_trace::chain;
+use crate::cname_trace::traced;
+use crate::error::ResolveError;
+use crate::labels::current_labels;
+use crate::resolved::ResolvedAddr;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use opentelemetry::global;
+use opentelemetry::global::BoxedTracer;
+use opentelemetry::metrics::Counter;
+use opentelemetry::metrics::Histogram;
+use opentelemetry::metrics::Unit;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::trace::Status;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::trace::Tracer;
+use opentelemetry::Array;
+use opentelemetry::Context;
+use opentelemetry::KeyValue;
+use opentelemetry::StringValue;
+use opentelemetry::Value;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::time::Instant;
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// The instrumentation scope name used for the tracer and meter
+pub const INSTRUMENTATION_NAME: &str = "reqwest-resolve";
+
+/// Records an OpenTelemetry span and metrics for every lookup made through
+/// the inner resolver
+///
+/// Each span is a child of the OpenTelemetry context that's current when the
+/// lookup starts, and is the current context while the inner resolver runs.
+///
+/// ```
+/// # use reqwest_resolve::otel::OtelResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = OtelResolver::new(MyCustomDnsResolver::new(resolver));
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct OtelResolver<R> {
+    inner: R,
+    tracer: BoxedTracer,
+    duration: Histogram<f64>,
+    lookups: Counter<u64>,
+}
+
+impl<R> OtelResolver<R> {
+    /// Uses the global tracer and meter providers.  Install those before
+    /// calling this: instruments made before a meter provider is installed
+    /// don't record anything.
+    pub fn new(inner: R) -> OtelResolver<R> {
+        let meter = global::meter(INSTRUMENTATION_NAME);
+        OtelResolver {
+            inner,
+            tracer: global::tracer(INSTRUMENTATION_NAME),
+            duration: meter
+                .f64_histogram("dns.lookup.duration")
+                .with_unit(Unit::new("s"))
+                .with_description("How long DNS lookups took")
+                .init(),
+            lookups: meter
+                .u64_counter("dns.lookups")
+                .with_description("How many DNS lookups were made")
+                .init(),
+        }
+    }
+}
+
+impl<R: MyResolve> MyResolve for OtelResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let host = name.as_str().to_owned();
+        let lookup =
+            async move { Ok(self.inner.resolve(name).await?.collect()) };
+        self.observed(host, lookup, |addrs: &AddrList| addrs.len())
+            .map(|result| Ok(Box::new(result?.into_iter()) as Addrs))
+            .boxed()
+            .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let host = name.as_str().to_owned();
+        let lookup = self.inner.resolve_detailed(name);
+        self.observed(host, lookup, |addrs: &Vec<ResolvedAddr>| addrs.len())
+    }
+}
+
+impl<R: Sync> OtelResolver<R> {
+    /// Runs `lookup` (of `name`) in a "dns.lookup" span, and records how
+    /// long it took and how it went, with `count` saying how many addresses
+    /// it found.
+    fn observed<'a, T, F>(
+        &'a self,
+        name: String,
+        lookup: F,
+        count: fn(&T) -> usize,
+    ) -> BoxFuture<'a, Result<T, Box<dyn StdError + Send + Sync>>>
+    where
+        T: Send + 'a,
+        F: Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>
+            + Send
+            + 'a,
+    {
+        let mut lookup_attributes =
+            vec![KeyValue::new("dns.question.name", name)];
+        lookup_attributes.extend(
+            current_labels()
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value)),
+        );
+        let span = self
+            .tracer
+            .span_builder("dns.lookup")
+            .with_kind(SpanKind::Client)
+            .with_attributes(lookup_attributes.clone())
+            .start(&self.tracer);
+        let cx = Context::current_with_span(span);
+
+        async move {
+            let start = Instant::now();
+            let (result, hops) =
+                traced(opentelemetry::trace::FutureExt::with_context(
+                    lookup,
+                    cx.clone(),
+                ))
+                .await;
+            let elapsed = start.elapsed().as_secs_f64();
+
+            let span = cx.span();
+            let names = chain(&hops);
+            if names.len() > 1 {
+                let names = names.into_iter().map(StringValue::from).collect();
+                span.set_attribute(KeyValue::new(
+                    "dns.cname.chain",
+                    Value::Array(Array::String(names)),
+                ));
+            }
+            for hop in hops {
+                span.add_event(
+                    "dns.query",
+                    vec![
+                        KeyValue::new("dns.question.name", hop.name),
+                        KeyValue::new("dns.question.type", hop.record_type),
+                        KeyValue::new(
+                            "server.address",
+                            hop.upstream.to_string(),
+                        ),
+                        KeyValue::new(
+                            "dns.query.duration",
+                            hop.latency.as_secs_f64(),
+                        ),
+                    ],
+                );
+            }
+            let mut attributes = lookup_attributes;
+            match &result {
+                Ok(found) => {
+                    span.set_attribute(KeyValue::new(
+                        "dns.answer.count",
+                        count(found) as i64,
+                    ));
+                }
+                Err(error) => {
+                    let error_type =
+                        KeyValue::new("error.type", error_type(&**error));
+                    span.set_attribute(error_type.clone());
+                    span.set_status(Status::error(error.to_string()));
+                    attributes.push(error_type);
+                }
+            }
+            span.end();
+
+            self.duration.record(elapsed, &attributes);
+            self.lookups.add(1, &attributes);
+            result
+        }
+        .boxed()
+    }
+}
+
+/// Classifies a lookup error for the "error.type" attribute
+fn error_type(error: &(dyn StdError + 'static)) -> &'static str {
+    if let Some(error) = error.downcast_ref::<ResolveError>() {
+        return match error {
+            ResolveError::AddressesFiltered { .. } => "addresses_filtered",
+            ResolveError::BlockedForTenant { .. } => "blocked_for_tenant",
+            ResolveError::BootstrapFailed(_) => "bootstrap_failed",
+            ResolveError::CanaryFailed { .. } => "canary_failed",
+            ResolveError::DeadlineExceeded { .. } => "timeout",
+            ResolveError::DeferredToProxy { .. } => "deferred_to_proxy",
+            ResolveError::GlobalAlreadyInstalled => "already_installed",
+            ResolveError::Internal { .. } => "internal",
+            ResolveError::InvalidConfig(_) => "invalid_config",
+            ResolveError::InvalidFixture { .. } => "invalid_fixture",
+            ResolveError::InvalidName { .. } => "invalid_name",
+            ResolveError::InvalidSignature(_) => "invalid_signature",
+            ResolveError::LookupLoop { .. } => "lookup_loop",
+            ResolveError::NoConsensus { .. } => "no_consensus",
+            ResolveError::NotFound { .. } => "not_found",
+            ResolveError::QueryBudgetExceeded { .. } => "query_budget_exceeded",
+            ResolveError::ResolutionDisabled { .. } => "resolution_disabled",
+            ResolveError::SingleLabelName { .. } => "single_label_name",
+            ResolveError::SpecialUse { .. } => "special_use",
+            ResolveError::TooManyRewrites { .. } => "too_many_rewrites",
+            ResolveError::UnknownTenant { .. } => "unknown_tenant",
+            ResolveError::ZoneTransferFailed { .. } => "zone_transfer_failed",
+        };
+    }
+
+    if let Some(error) =
+        error.downcast_ref::<trust_dns_resolver::error::ResolveError>()
+    {
+        return match error.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => "not_found",
+            ResolveErrorKind::Timeout => "timeout",
+            ResolveErrorKind::NoConnections => "no_connections",
+            ResolveErrorKind::Io(_) => "io",
+            ResolveErrorKind::Proto(_) => "protocol",
+            _ => "_OTHER",
+        };
+    }
+
+    "_OTHER"
+}
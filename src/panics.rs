@@ -0,0 +1,47 @@
+//! Keeping a panic in a resolver from unwinding through reqwest
+//!
+//! A panic that escapes a `Resolve` future unwinds through hyper's
+//! connector and takes the whole request (and, on a runtime that doesn't
+//! catch panics in tasks, possibly more) with it.  The adapters at the
+//! boundary with reqwest run lookups through [`isolate`], which turns a
+//! panic into a [`ResolveError::Internal`] for that one lookup.
+
+use crate::error::ResolveError;
+use crate::logging::warning;
+use futures::future::FutureExt;
+use std::any::Any;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+/// Runs `lookup` (which may panic while being created or polled), turning
+/// any panic into a [`ResolveError::Internal`] for `name`.
+pub(crate) async fn isolate<T, F, Fut>(
+    name: &str,
+    lookup: F,
+) -> Result<T, Box<dyn StdError + Send + Sync>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>,
+{
+    match AssertUnwindSafe(async move { lookup().await }).catch_unwind().await {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = panic_message(&*panic).to_owned();
+            warning!("resolver panicked", name = name, panic = message);
+            Err(ResolveError::Internal { name: name.to_owned(), message }
+                .into())
+        }
+    }
+}
+
+/// Returns what a panic said, if it said it with a string.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "(not a string)"
+    }
+}
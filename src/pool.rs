@@ -0,0 +1,192 @@
+//! Spreading queries across several underlying resolvers
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::global::overridable;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Spreads lookups round-robin across a fixed set of `TokioAsyncResolver`s.
+///
+/// A single `TokioAsyncResolver` pushes all of its queries through one
+/// connection per name server.  At very high lookup rates, that one UDP socket
+/// (and the task that drives it) becomes the bottleneck.  Running several
+/// independent resolvers and rotating between them spreads that load out.
+///
+/// Each resolver in the pool keeps its own cache, so a name that's looked up
+/// repeatedly may be fetched from upstream once per resolver rather than once
+/// overall.  If that matters, keep the pool small.
+///
+/// ```
+/// # use reqwest_resolve::pool::ResolverPool;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let pool = ResolverPool::with_size(
+///     4,
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// assert_eq!(pool.len(), 4);
+/// let _client =
+///     reqwest::ClientBuilder::new().dns_resolver(Arc::new(pool)).build();
+/// ```
+pub struct ResolverPool {
+    // As with `CustomDnsResolver`, these are `Arc`s so that the futures we
+    // hand to reqwest can hold onto the resolver they were assigned.
+    resolvers: Vec<Arc<TokioAsyncResolver>>,
+    next: AtomicUsize,
+}
+
+impl ResolverPool {
+    /// Builds a pool from resolvers that the caller has already constructed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `resolvers` is empty.
+    pub fn new(resolvers: Vec<TokioAsyncResolver>) -> ResolverPool {
+        assert!(!resolvers.is_empty(), "resolver pool must not be empty");
+        ResolverPool {
+            resolvers: resolvers.into_iter().map(Arc::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Builds a pool of `size` resolvers that all share the same
+    /// configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn with_size(
+        size: usize,
+        config: ResolverConfig,
+        options: ResolverOpts,
+    ) -> Result<ResolverPool, ResolveError> {
+        let resolvers = (0..size)
+            .map(|_| TokioAsyncResolver::tokio(config.clone(), options))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ResolverPool::new(resolvers))
+    }
+
+    /// Returns the number of resolvers in the pool.
+    pub fn len(&self) -> usize {
+        self.resolvers.len()
+    }
+
+    /// Returns whether the pool is empty.  This is always false, since an
+    /// empty pool can't be constructed.
+    pub fn is_empty(&self) -> bool {
+        self.resolvers.is_empty()
+    }
+
+    /// Picks the resolver that should handle the next lookup.
+    fn next_resolver(&self) -> &Arc<TokioAsyncResolver> {
+        // Relaxed is fine: we only need each caller to get some index, not to
+        // synchronize anything else with other callers.
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.resolvers[i % self.resolvers.len()]
+    }
+}
+
+impl reqwest::dns::Resolve for ResolverPool {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.next_resolver().clone();
+        async move {
+            overridable(name, |name| do_resolve(&resolver, name)).await
+        }
+        .boxed()
+    }
+}
+
+impl MyResolve for ResolverPool {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve(self.next_resolver(), name).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        do_resolve_detailed(self.next_resolver(), name).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResolverPool;
+    #[cfg(feature = "testserver")]
+    use crate::testserver::TestServer;
+    #[cfg(feature = "testserver")]
+    use crate::testserver::TestZone;
+    #[cfg(feature = "testserver")]
+    use crate::MyResolve;
+    #[cfg(feature = "testserver")]
+    use trust_dns_resolver::config::ResolverOpts;
+    #[cfg(feature = "testserver")]
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    #[test]
+    #[should_panic(expected = "resolver pool must not be empty")]
+    fn empty_pool_panics() {
+        ResolverPool::new(vec![]);
+    }
+
+    /// Each resolver in the pool talks to its own server, which gives its
+    /// own answer, so the answers show which resolver handled each lookup.
+    #[cfg(feature = "testserver")]
+    #[tokio::test]
+    async fn lookups_rotate() {
+        let mut servers = Vec::new();
+        for addr in ["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            let mut zone = TestZone::new();
+            zone.add_addr("api.test", addr.parse().unwrap());
+            servers.push(TestServer::start(zone).await.unwrap());
+        }
+        let mut options = ResolverOpts::default();
+        options.cache_size = 0;
+        let resolvers = servers
+            .iter()
+            .map(|server| {
+                TokioAsyncResolver::tokio(server.resolver_config(), options)
+                    .unwrap()
+            })
+            .collect();
+        let pool = ResolverPool::new(resolvers);
+
+        let mut answers = Vec::new();
+        for _ in 0..6 {
+            let addrs = pool.resolve_to_vec("api.test").await.unwrap();
+            answers.push(addrs[0].ip().to_string());
+        }
+        assert_eq!(
+            answers,
+            [
+                "192.0.2.1",
+                "192.0.2.2",
+                "192.0.2.3",
+                "192.0.2.1",
+                "192.0.2.2",
+                "192.0.2.3"
+            ]
+        );
+        for server in &servers {
+            assert_eq!(server.queries().len(), 2);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Choosing the source ports of queries over UDP
+//!
+//! A resolver that sends its UDP queries from a predictable port is much
+//! easier to feed forged answers: an off-path attacker only has to guess the
+//! 16-bit query ID.  That's why trust-dns sends each query from a new socket,
+//! on a port chosen at random from the 16,384 ports in the IANA dynamic
+//! range, which makes the attacker guess both.  Some firewalls only allow
+//! DNS from a small, fixed set of source ports, though, and some hosts can't
+//! spare a socket per query.
+//!
+//! [`UdpPortTransport`] sends UDP queries with a [`PortStrategy`] chosen for
+//! the network: a fresh random port per query from any range, or a small
+//! pool of long-lived sockets.  Narrowing the range, or pooling, gives up
+//! some of that protection (see [`PortStrategy`] for how much), so only do
+//! it when the network requires it, and consider DNS-over-TLS or
+//! `anti_spoofing` to make up for it.
+
+use crate::deterministic::random;
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::StandardTransport;
+use crate::dns_transport::TransportFuture;
+use crate::exchange::exchange_udp;
+use crate::exchange::id_of;
+use crate::exchange::local_addr;
+use crate::exchange::MAX_UDP_RESPONSE;
+use crate::tasks::TaskSet;
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::proto::op::Message;
+
+/// The ports trust-dns picks from: the IANA dynamic range
+pub const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Where a [`UdpPortTransport`] sends UDP queries from
+///
+/// An attacker trying to forge a response has to guess the query ID (16
+/// bits) and whichever of the ports the query might have come from, so
+/// every halving of the number of ports makes a forgery twice as likely to
+/// succeed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PortStrategy {
+    /// A new socket for every query, on a port chosen at random from
+    /// `ports`.  With [`DYNAMIC_PORTS`], this is what trust-dns does on its
+    /// own.  Each query in flight holds its port, so with a narrow range,
+    /// queries beyond one per port fail until others finish.
+    RandomPerQuery { ports: RangeInclusive<u16> },
+    /// `sockets` long-lived sockets (or as many as `ports` has room for), on
+    /// ports chosen at random from `ports` when the first query is sent and
+    /// kept from then on, with each query sent from one of them at random.
+    /// This uses far fewer sockets and suits firewalls that need to know the
+    /// ports in advance (a range with a single port pins every query to
+    /// it), but an attacker who sees one query can learn the ports, leaving
+    /// only the query ID to guess.
+    Pooled { ports: RangeInclusive<u16>, sockets: usize },
+}
+
+impl Default for PortStrategy {
+    fn default() -> PortStrategy {
+        PortStrategy::RandomPerQuery { ports: DYNAMIC_PORTS }
+    }
+}
+
+/// Sends queries over UDP from source ports chosen by a [`PortStrategy`]
+///
+/// Queries over other protocols are sent as usual.  Any source address set
+/// in a server's `bind_addr` (see
+/// [`source::with_source_addrs`](crate::source::with_source_addrs)) is used,
+/// but not its port.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::ports::{PortStrategy, UdpPortTransport};
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// // The firewall only lets DNS out from ports 40000 to 40099.
+/// let strategy = PortStrategy::RandomPerQuery { ports: 40000..=40099 };
+/// let options = ResolverOpts::default();
+/// let resolver = transport_resolver(
+///     ResolverConfig::default(),
+///     options.clone(),
+///     UdpPortTransport::new(strategy, options),
+/// )
+/// .unwrap();
+/// let _my_resolver = TransportDnsResolver::new(resolver);
+/// ```
+pub struct UdpPortTransport {
+    strategy: PortStrategy,
+    standard: StandardTransport,
+    /// with `PortStrategy::Pooled`, the pool for each local address
+    pools: Arc<Pools>,
+}
+
+impl UdpPortTransport {
+    /// Sends queries over other protocols like
+    /// [`StandardTransport`] with `options`, which should be the same
+    /// options the resolver was built with.
+    pub fn new(
+        strategy: PortStrategy,
+        options: ResolverOpts,
+    ) -> UdpPortTransport {
+        UdpPortTransport {
+            strategy,
+            standard: StandardTransport::new(options),
+            pools: Arc::new(Pools::default()),
+        }
+    }
+}
+
+impl DnsTransport for UdpPortTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        if server.protocol != Protocol::Udp {
+            return self.standard.send(server, query);
+        }
+
+        let local = local_addr(server).ip();
+        let remote = server.socket_addr;
+        let strategy = self.strategy.clone();
+        let pools = Arc::clone(&self.pools);
+        async move {
+            match strategy {
+                PortStrategy::RandomPerQuery { ports } => {
+                    Ok(exchange_once(local, &ports, remote, query).await?)
+                }
+                PortStrategy::Pooled { ports, sockets } => {
+                    let pool = pool_for(&pools, local, &ports, sockets)?;
+                    Ok(pool.exchange(remote, query).await?)
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Binds a socket to `ip` and a random port from `ports`.
+fn bind_random(
+    ip: IpAddr,
+    ports: &RangeInclusive<u16>,
+) -> io::Result<UdpSocket> {
+    if ports.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty source port range",
+        ));
+    }
+
+    // Start at a random port and take the first one that's free.
+    let span = u32::from(*ports.end()) - u32::from(*ports.start()) + 1;
+    let start = random::<u32>() % span;
+    let mut last_error = None;
+    for offset in 0..span {
+        // This is less than `span`, so the port can't overflow.
+        let offset = (start + offset) % span;
+        let port = *ports.start() + offset as u16;
+        match std::net::UdpSocket::bind((ip, port)) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                return UdpSocket::from_std(socket);
+            }
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse => {
+                last_error = Some(error);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+/// Sends `query` from a new socket and waits for the response.
+async fn exchange_once(
+    local: IpAddr,
+    ports: &RangeInclusive<u16>,
+    remote: SocketAddr,
+    query: Message,
+) -> io::Result<Message> {
+    let socket = bind_random(local, ports)?;
+    let response =
+        exchange_udp(&socket, remote, query.id(), &query.to_vec()?).await?;
+    Ok(Message::from_vec(&response)?)
+}
+
+/// Returns the pool for `local`, creating it if this is its first query.
+fn pool_for(
+    pools: &Pools,
+    local: IpAddr,
+    ports: &RangeInclusive<u16>,
+    sockets: usize,
+) -> io::Result<Arc<Pool>> {
+    let mut pools = pools.lock().unwrap();
+    if let Some((_, pool)) = pools.iter().find(|(ip, _)| *ip == local) {
+        return Ok(Arc::clone(pool));
+    }
+    let pool = Arc::new(Pool::bind(local, ports, sockets)?);
+    pools.push((local, Arc::clone(&pool)));
+    Ok(pool)
+}
+
+/// The socket pool for each local address
+type Pools = Mutex<Vec<(IpAddr, Arc<Pool>)>>;
+
+/// Responses being waited for on a pooled socket, by server and query ID
+type Pending = Mutex<BTreeMap<(SocketAddr, u16), oneshot::Sender<Vec<u8>>>>;
+
+/// Long-lived sockets shared by every query from one local address
+struct Pool {
+    sockets: Vec<PooledSocket>,
+    /// each socket's reader, which stops when the pool is dropped
+    readers: TaskSet,
+}
+
+struct PooledSocket {
+    socket: Arc<UdpSocket>,
+    pending: Arc<Pending>,
+}
+
+impl Pool {
+    fn bind(
+        local: IpAddr,
+        ports: &RangeInclusive<u16>,
+        sockets: usize,
+    ) -> io::Result<Pool> {
+        let room = usize::from(*ports.end())
+            .saturating_sub(usize::from(*ports.start()))
+            + 1;
+        let mut pool =
+            Pool { sockets: Vec::new(), readers: TaskSet::default() };
+        for _ in 0..sockets.clamp(1, room) {
+            let socket = Arc::new(bind_random(local, ports)?);
+            let pending = Arc::new(Pending::default());
+            pool.readers.spawn(
+                "udp port pool",
+                read_responses(Arc::clone(&socket), Arc::clone(&pending)),
+            );
+            pool.sockets.push(PooledSocket { socket, pending });
+        }
+        Ok(pool)
+    }
+
+    /// Sends `query` from one of the sockets and waits for the response.
+    async fn exchange(
+        &self,
+        remote: SocketAddr,
+        mut query: Message,
+    ) -> io::Result<Message> {
+        let pooled = &self.sockets[random::<usize>() % self.sockets.len()];
+
+        // Other queries share the socket, so the ID has to be unique on it.
+        // A fresh random one also keeps it as hard to guess as it would be
+        // on a socket of its own.
+        let original_id = query.id();
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = pooled.pending.lock().unwrap();
+            let id = loop {
+                let id = random::<u16>();
+                if !pending.contains_key(&(remote, id)) {
+                    break id;
+                }
+            };
+            pending.insert((remote, id), sender);
+            id
+        };
+        let _waiting = Waiting { pending: &pooled.pending, key: (remote, id) };
+
+        query.set_id(id);
+        pooled.socket.send_to(&query.to_vec()?, remote).await?;
+        let bytes = receiver.await.map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "pooled socket failed")
+        })?;
+        let mut response = Message::from_vec(&bytes)?;
+        response.set_id(original_id);
+        Ok(response)
+    }
+}
+
+/// Forgets a query that's no longer being waited for, whether it got its
+/// response or not.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    key: (SocketAddr, u16),
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Hands each response that arrives on `socket` to the query waiting for it.
+///
+/// Responses nobody's waiting for (late, duplicated, or forged) are
+/// dropped.
+async fn read_responses(socket: Arc<UdpSocket>, pending: Arc<Pending>) {
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        // Errors here are left over from earlier sends (like an ICMP port
+        // unreachable), not a problem with the socket.
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(id) = id_of(&buf[..len]) else {
+            continue;
+        };
+        let waiting = pending.lock().unwrap().remove(&(from, id));
+        if let Some(sender) = waiting {
+            let _ = sender.send(buf[..len].to_vec());
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Choosing which source of addresses wins
+//!
+//! A stack usually answers from several places: fixed overrides (a
+//! `StaticResolver` or `env_hosts::HostOverrides`), the hosts file, a
+//! cache, and DNS itself.  Which of them wins when they disagree depends
+//! on how the layers happen to be nested (and, for the hosts file, on
+//! `ResolverOpts::use_hosts_file`, which makes trust-dns check it before
+//! every lookup), and that isn't visible anywhere but in the code that
+//! builds the stack.  Some deployments want "the hosts file wins", so that
+//! an administrator can pin a name; others want "live DNS wins", with the
+//! hosts file only for names DNS doesn't know.
+//!
+//! A [`PrecedenceResolver`] makes the order explicit: it's given a resolver
+//! for each [`Source`] and an order to ask them in, which can come from
+//! configuration (see [`parse_order`]) and be read back to see what's in
+//! effect.
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::special_use::normalize;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::Query;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::Hosts;
+use trust_dns_resolver::Name;
+
+/// Where a [`PrecedenceResolver`]'s addresses can come from
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Source {
+    /// fixed overrides, from the program or its environment
+    Static,
+    /// the hosts file (see [`HostsFileResolver`])
+    HostsFile,
+    /// a cache of earlier answers (see `cache::CachingResolver::cache_only`)
+    Cache,
+    /// live DNS
+    Dns,
+}
+
+impl Source {
+    /// Returns the name the source is written as in an order, like
+    /// "hosts_file".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Static => "static",
+            Source::HostsFile => "hosts_file",
+            Source::Cache => "cache",
+            Source::Dns => "dns",
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Source {
+    type Err = ResolveError;
+
+    fn from_str(name: &str) -> Result<Source, ResolveError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "static" => Ok(Source::Static),
+            "hosts_file" | "hosts" => Ok(Source::HostsFile),
+            "cache" => Ok(Source::Cache),
+            "dns" => Ok(Source::Dns),
+            _ => Err(ResolveError::InvalidConfig(format!(
+                "unknown address source {:?}",
+                name
+            ))),
+        }
+    }
+}
+
+/// The order a [`PrecedenceResolver`] asks its sources in unless it's told
+/// otherwise: overrides first, then the hosts file (as glibc does with
+/// "files dns"), then the cache, and DNS last
+pub const DEFAULT_ORDER: [Source; 4] =
+    [Source::Static, Source::HostsFile, Source::Cache, Source::Dns];
+
+/// Parses a comma-separated order of sources, like "dns,hosts_file" for
+/// "live DNS wins"
+///
+/// Sources may be left out, in which case they're never asked, but not
+/// listed twice.
+pub fn parse_order(order: &str) -> Result<Vec<Source>, ResolveError> {
+    let order = order
+        .split(',')
+        .filter(|source| !source.trim().is_empty())
+        .map(Source::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    check_order(&order)?;
+    Ok(order)
+}
+
+fn check_order(order: &[Source]) -> Result<(), ResolveError> {
+    for (i, source) in order.iter().enumerate() {
+        if order[..i].contains(source) {
+            return Err(ResolveError::InvalidConfig(format!(
+                "address source {} is listed more than once",
+                source
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `error` only says that a source has no addresses for the
+/// name, so the next source should be asked.
+fn is_miss(error: &(dyn StdError + 'static)) -> bool {
+    if let Some(ResolveError::NotFound { .. }) = error.downcast_ref() {
+        return true;
+    }
+    match error.downcast_ref::<trust_dns_resolver::error::ResolveError>() {
+        Some(error) => {
+            matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+        }
+        None => false,
+    }
+}
+
+/// Asks each source in a configured order, and returns the first one's
+/// answer that has any addresses
+///
+/// A source that has nothing for a name (it returns no addresses, or says
+/// the name isn't there) passes the lookup on to the next one.  So does a
+/// source that fails some other way (a DNS server that's down, say), but
+/// if none of the sources after it has addresses either, the lookup fails
+/// with that error rather than with "not found".  Sources in the order that
+/// haven't been given a resolver are skipped.
+///
+/// Each source is asked with its resolver's `resolve` (or
+/// `resolve_detailed`), so the whole stack for DNS, including any layers,
+/// goes in as one source.
+///
+/// ```
+/// # use reqwest_resolve::precedence::{parse_order, PrecedenceResolver, Source};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// static PINNED: StaticResolver = static_resolver! {
+///     "api.example.com" => ["10.0.0.5"],
+/// };
+/// static DNS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+///     "www.example.com" => ["192.0.2.80"],
+/// };
+/// // "Live DNS wins": the pinned address is only for when DNS has none.
+/// let my_resolver = PrecedenceResolver::new()
+///     .with_source(Source::Static, PINNED)
+///     .with_source(Source::Dns, DNS)
+///     .with_order(&parse_order("dns,static").unwrap())
+///     .unwrap();
+/// assert_eq!(my_resolver.effective_order(), [Source::Dns, Source::Static]);
+///
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let addrs = my_resolver.resolve_to_vec("api.example.com").await.unwrap();
+/// assert_eq!(addrs, ["192.0.2.10:0".parse().unwrap()]);
+/// # });
+/// ```
+pub struct PrecedenceResolver {
+    order: Vec<Source>,
+    sources: BTreeMap<Source, Box<dyn MyResolve>>,
+}
+
+impl PrecedenceResolver {
+    /// Has no sources yet, and asks them in [`DEFAULT_ORDER`].
+    pub fn new() -> PrecedenceResolver {
+        PrecedenceResolver {
+            order: DEFAULT_ORDER.to_vec(),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Answers from `resolver` for `source`, replacing any resolver given
+    /// for it before.
+    pub fn with_source<R: MyResolve + 'static>(
+        mut self,
+        source: Source,
+        resolver: R,
+    ) -> PrecedenceResolver {
+        self.sources.insert(source, Box::new(resolver));
+        self
+    }
+
+    /// Asks the sources in `order`, failing with
+    /// [`ResolveError::InvalidConfig`] if a source is listed twice.  Sources
+    /// left out are never asked.
+    pub fn with_order(
+        mut self,
+        order: &[Source],
+    ) -> Result<PrecedenceResolver, ResolveError> {
+        check_order(order)?;
+        self.order = order.to_vec();
+        Ok(self)
+    }
+
+    /// Returns the order sources are asked in, including ones without a
+    /// resolver.
+    pub fn order(&self) -> &[Source] {
+        &self.order
+    }
+
+    /// Returns the sources that are actually asked, in order: the ones in
+    /// the order that have a resolver.
+    pub fn effective_order(&self) -> Vec<Source> {
+        self.order
+            .iter()
+            .copied()
+            .filter(|source| self.sources.contains_key(source))
+            .collect()
+    }
+
+    /// Asks each source in turn with `lookup`, returning the first
+    /// non-empty answer.
+    async fn first_answer<'a, T, F>(
+        &'a self,
+        name: hyper::client::connect::dns::Name,
+        lookup: F,
+    ) -> Result<Vec<T>, Box<dyn StdError + Send + Sync>>
+    where
+        F: Fn(
+            &'a dyn MyResolve,
+            hyper::client::connect::dns::Name,
+        )
+            -> BoxFuture<'a, Result<Vec<T>, Box<dyn StdError + Send + Sync>>>,
+    {
+        let mut failure = None;
+        for source in &self.order {
+            let Some(resolver) = self.sources.get(source) else {
+                continue;
+            };
+            match lookup(&**resolver, name.clone()).await {
+                Ok(found) if !found.is_empty() => {
+                    debug!(
+                        "answered by source",
+                        name = name.as_str(),
+                        source = source,
+                    );
+                    return Ok(found);
+                }
+                Ok(_) => {}
+                Err(error) if is_miss(&*error) => {}
+                Err(error) => {
+                    debug!(
+                        "source failed",
+                        name = name.as_str(),
+                        source = source,
+                        error = error,
+                    );
+                    failure.get_or_insert(error);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| {
+            let name = name.as_str().to_owned();
+            ResolveError::NotFound { name }.into()
+        }))
+    }
+}
+
+impl Default for PrecedenceResolver {
+    fn default() -> PrecedenceResolver {
+        PrecedenceResolver::new()
+    }
+}
+
+impl MyResolve for PrecedenceResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs: Vec<SocketAddr> = self
+                .first_answer(name, |resolver, name| {
+                    let lookup = resolver.resolve(name);
+                    async move { Ok(lookup.await?.collect()) }.boxed()
+                })
+                .await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            self.first_answer(name, |resolver, name| {
+                resolver.resolve_detailed(name)
+            })
+            .await
+        }
+        .boxed()
+    }
+}
+
+/// Answers from a hosts file, in the format of `/etc/hosts`
+///
+/// Names with no entry fail with [`ResolveError::NotFound`].  For this to
+/// be the only place the hosts file is read, so that its place in a
+/// [`PrecedenceResolver`]'s order is what decides, turn off
+/// `ResolverOpts::use_hosts_file` for the resolver used for DNS.
+pub struct HostsFileResolver {
+    hosts: Hosts,
+}
+
+impl HostsFileResolver {
+    /// Reads the system's hosts file (`/etc/hosts`, on Unix), which is
+    /// treated as empty if it can't be read.
+    pub fn system() -> HostsFileResolver {
+        HostsFileResolver { hosts: Hosts::new() }
+    }
+
+    /// Parses hosts file entries from `contents`.
+    pub fn parse(contents: &str) -> io::Result<HostsFileResolver> {
+        let hosts = Hosts::default().read_hosts_conf(contents.as_bytes())?;
+        Ok(HostsFileResolver { hosts })
+    }
+
+    /// Returns `name`'s addresses, which are empty if it has no entry.
+    pub fn lookup(&self, name: &str) -> AddrList {
+        let Ok(name) = Name::from_str(&normalize(name)) else {
+            return AddrList::new();
+        };
+        [RecordType::A, RecordType::AAAA]
+            .into_iter()
+            .filter_map(|record_type| {
+                let query = Query::query(name.clone(), record_type);
+                self.hosts.lookup_static_host(&query)
+            })
+            .flat_map(|lookup| {
+                lookup
+                    .iter()
+                    .filter_map(|rdata| match rdata {
+                        RData::A(ip) => Some(SocketAddr::from((*ip, 0))),
+                        RData::AAAA(ip) => Some(SocketAddr::from((*ip, 0))),
+                        _ => None,
+                    })
+                    .collect::<AddrList>()
+            })
+            .collect()
+    }
+}
+
+impl MyResolve for HostsFileResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        };
+        MyResolving::ready(result)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            Ok(addrs
+                .into_iter()
+                .map(|addr| ResolvedAddr::from_table(addr, "hosts"))
+                .collect())
+        };
+        futures::future::ready(result).boxed()
+    }
+}
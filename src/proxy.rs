@@ -0,0 +1,359 @@
+//! Leaving name resolution to a proxy
+
+use crate::context::current_context;
+use crate::context::ResolveContext;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::routing::CompiledRules;
+use crate::routing::RoutingRules;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::Url;
+
+/// Refuses to resolve names that a proxy is supposed to resolve
+///
+/// When reqwest sends traffic through an HTTP proxy or a `socks5h://` proxy,
+/// the proxy resolves the destination's name, and the only name reqwest
+/// resolves itself is the proxy's.  If some code path ends up resolving a
+/// destination locally anyway (say, a `NO_PROXY` entry nobody expected to
+/// match, or a client built without the proxy), that lookup leaks the name
+/// to the local DNS servers even though the traffic was supposed to be
+/// tunneled.  This layer turns such lookups into
+/// [`ResolveError::DeferredToProxy`] errors instead.  The proxies' own
+/// hostnames are still passed to the inner resolver, and so are the names
+/// reqwest connects to directly, bypassing the proxy: those matching a
+/// `NO_PROXY` list (see [`ProxyDeferredResolver::with_no_proxy`]).
+///
+/// `socks5://` proxies (without the "h") are rejected, since with those,
+/// reqwest resolves destination names locally by design.
+///
+/// A proxy may only be used for some schemes, as with
+/// [`ProxyDeferredResolver::from_env`] when only `HTTPS_PROXY` is set.
+/// reqwest connects to the other schemes' destinations directly, so a name
+/// is only left to the proxy when the lookup's context (see
+/// `context::ResolveContext`) names a scheme that has one.  Lookups without
+/// a scheme are resolved normally in that case.
+///
+/// ```
+/// # use reqwest_resolve::proxy::ProxyDeferredResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let proxy = "http://proxy.example.com:3128";
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = ProxyDeferredResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     &[proxy],
+/// )
+/// .unwrap();
+/// let _client = reqwest::ClientBuilder::new()
+///     .proxy(reqwest::Proxy::all(proxy).unwrap())
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)))
+///     .build();
+/// ```
+pub struct ProxyDeferredResolver<R> {
+    inner: R,
+    /// hostnames of the configured proxies, or `None` if there aren't any (in
+    /// which case every name is resolved normally)
+    proxy_hosts: Option<Vec<String>>,
+    /// URL schemes whose traffic goes through a proxy, or `None` if all of
+    /// them do
+    proxied_schemes: Option<Vec<&'static str>>,
+    /// names reqwest connects to without the proxy, which still have to be
+    /// resolved here
+    direct: Option<CompiledRules<bool>>,
+}
+
+impl<R> ProxyDeferredResolver<R> {
+    /// Defers resolution to the given proxies, identified by URL as they'd be
+    /// passed to `reqwest::Proxy`.
+    pub fn new(
+        inner: R,
+        proxies: &[&str],
+    ) -> Result<ProxyDeferredResolver<R>, ResolveError> {
+        let proxy_hosts = proxies
+            .iter()
+            .map(|proxy| proxy_host(proxy))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ProxyDeferredResolver {
+            inner,
+            proxy_hosts: Some(proxy_hosts),
+            proxied_schemes: None,
+            direct: None,
+        })
+    }
+
+    /// Resolves the names matching any entry of `list` normally, since
+    /// reqwest connects to them directly
+    ///
+    /// `list` is in the syntax of the `NO_PROXY` environment variable (see
+    /// `RoutingRules::no_proxy`), and should be the same list the client's
+    /// proxies were given with `reqwest::Proxy::no_proxy`.
+    ///
+    /// ```
+    /// # use reqwest_resolve::proxy::ProxyDeferredResolver;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "wiki.corp.example.com" => ["10.0.0.7"],
+    /// };
+    /// let proxy = "http://proxy.example.com:3128";
+    /// let my_resolver = ProxyDeferredResolver::new(HOSTS, &[proxy])
+    ///     .unwrap()
+    ///     .with_no_proxy(".corp.example.com, 10.0.0.0/8")
+    ///     .unwrap();
+    /// let no_proxy = reqwest::NoProxy::from_string(".corp.example.com");
+    /// let _client = reqwest::ClientBuilder::new()
+    ///     .proxy(reqwest::Proxy::all(proxy).unwrap().no_proxy(no_proxy));
+    /// # let _ = my_resolver;
+    /// ```
+    pub fn with_no_proxy(
+        self,
+        list: &str,
+    ) -> Result<ProxyDeferredResolver<R>, ResolveError> {
+        let direct = RoutingRules::new().no_proxy(list, true).compile()?;
+        Ok(ProxyDeferredResolver { direct: Some(direct), ..self })
+    }
+
+    /// Defers resolution to whatever proxies reqwest would pick up from the
+    /// environment (`HTTP_PROXY` for "http" URLs and `HTTPS_PROXY` for
+    /// "https" ones, or their lowercase forms), except for the names reqwest
+    /// connects to directly because they match `NO_PROXY` (or `no_proxy`).
+    /// If there aren't any proxies, names are resolved normally.
+    ///
+    /// Like reqwest, this ignores `HTTP_PROXY` in a CGI program (when
+    /// `REQUEST_METHOD` is set), where a request's "Proxy" header would
+    /// arrive as that variable ("httpoxy").
+    pub fn from_env(
+        inner: R,
+    ) -> Result<ProxyDeferredResolver<R>, ResolveError> {
+        let proxies: Vec<(&'static str, String)> = [
+            ("http", ["HTTP_PROXY", "http_proxy"]),
+            ("https", ["HTTPS_PROXY", "https_proxy"]),
+        ]
+        .iter()
+        .filter(|(scheme, _)| *scheme != "http" || !is_cgi())
+        .filter_map(|(scheme, vars)| Some((*scheme, env_var(vars)?)))
+        .collect();
+
+        if proxies.is_empty() {
+            return Ok(ProxyDeferredResolver {
+                inner,
+                proxy_hosts: None,
+                proxied_schemes: None,
+                direct: None,
+            });
+        }
+
+        let no_proxy = env_var(&["NO_PROXY", "no_proxy"]);
+        ProxyDeferredResolver::for_schemes(inner, &proxies, no_proxy.as_deref())
+    }
+
+    /// Defers resolution for the schemes in `proxies`, each paired with the
+    /// URL of the proxy used for it, except for the names matching
+    /// `no_proxy`
+    fn for_schemes(
+        inner: R,
+        proxies: &[(&'static str, String)],
+        no_proxy: Option<&str>,
+    ) -> Result<ProxyDeferredResolver<R>, ResolveError> {
+        let urls: Vec<&str> =
+            proxies.iter().map(|(_, proxy)| proxy.as_str()).collect();
+        let resolver = ProxyDeferredResolver {
+            proxied_schemes: Some(
+                proxies.iter().map(|(scheme, _)| *scheme).collect(),
+            ),
+            ..ProxyDeferredResolver::new(inner, &urls)?
+        };
+        match no_proxy {
+            Some(list) => resolver.with_no_proxy(list),
+            None => Ok(resolver),
+        }
+    }
+}
+
+/// Returns the value of the first of `vars` that's set and not blank.
+fn env_var(vars: &[&str; 2]) -> Option<String> {
+    vars.iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+/// Returns whether this is running as a CGI program, as reqwest checks.
+fn is_cgi() -> bool {
+    std::env::var_os("REQUEST_METHOD").is_some()
+}
+
+/// Returns the host part of a proxy URL, which is the one name we still need
+/// to resolve ourselves.
+fn proxy_host(proxy: &str) -> Result<String, ResolveError> {
+    // Like reqwest, treat a proxy without a scheme as an HTTP proxy.
+    let url = if proxy.contains("://") {
+        Url::parse(proxy)
+    } else {
+        Url::parse(&format!("http://{}", proxy))
+    }
+    .map_err(|error| {
+        ResolveError::InvalidConfig(format!("proxy {:?}: {}", proxy, error))
+    })?;
+
+    match url.scheme() {
+        "http" | "https" | "socks5h" => (),
+        "socks5" => {
+            return Err(ResolveError::InvalidConfig(format!(
+                "proxy {:?}: names are resolved locally with socks5:// \
+                 proxies (use socks5h:// to have the proxy resolve them)",
+                proxy
+            )))
+        }
+        scheme => {
+            return Err(ResolveError::InvalidConfig(format!(
+                "proxy {:?}: unsupported scheme {:?}",
+                proxy, scheme
+            )))
+        }
+    }
+
+    url.host_str().map(|host| host.to_ascii_lowercase()).ok_or_else(|| {
+        ResolveError::InvalidConfig(format!("proxy {:?}: no host", proxy))
+    })
+}
+
+impl<R: MyResolve> MyResolve for ProxyDeferredResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.deferred(name.as_str(), current_context().as_ref()) {
+            Some(error) => MyResolving::ready(Err(error.into())),
+            None => self.inner.resolve(name),
+        }
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &ResolveContext,
+    ) -> MyResolving<'_> {
+        match self.deferred(name.as_str(), Some(context)) {
+            Some(error) => MyResolving::ready(Err(error.into())),
+            None => self.inner.resolve_with_context(name, context),
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.deferred(name.as_str(), current_context().as_ref()) {
+            Some(error) => futures::future::ready(Err(error.into())).boxed(),
+            None => self.inner.resolve_detailed(name),
+        }
+    }
+}
+
+impl<R> ProxyDeferredResolver<R> {
+    /// Returns the error for `name` if it's left to the proxy, given the
+    /// lookup's `context` (if there is one).
+    fn deferred(
+        &self,
+        name: &str,
+        context: Option<&ResolveContext>,
+    ) -> Option<ResolveError> {
+        let proxy_hosts = self.proxy_hosts.as_ref()?;
+        if let Some(schemes) = &self.proxied_schemes {
+            // Without a proxy for the scheme (or without knowing the
+            // scheme), reqwest may well connect directly.
+            let scheme = context.and_then(ResolveContext::scheme)?;
+            if !schemes.contains(&scheme) {
+                return None;
+            }
+        }
+        let host = name.trim_end_matches('.').to_ascii_lowercase();
+        if proxy_hosts.contains(&host) {
+            return None;
+        }
+        let direct = self.direct.as_ref();
+        if direct.and_then(|rules| rules.lookup(&host)).copied() == Some(true) {
+            return None;
+        }
+        debug!("leaving name to the proxy", name = host);
+        Some(ResolveError::DeferredToProxy { name: name.to_owned() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProxyDeferredResolver;
+    use crate::context::ResolveContext;
+    use crate::error::ResolveError;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "proxy.example.com" => ["192.0.2.1"],
+        "api.example.com" => ["192.0.2.2"],
+        "wiki.corp.example.com" => ["10.0.0.7"],
+    };
+
+    #[tokio::test]
+    async fn no_proxy() {
+        let resolver =
+            ProxyDeferredResolver::new(HOSTS, &["proxy.example.com:3128"])
+                .unwrap()
+                .with_no_proxy(".corp.example.com")
+                .unwrap();
+        for name in ["proxy.example.com", "wiki.corp.example.com."] {
+            assert!(resolver.resolve_to_vec(name).await.is_ok(), "{}", name);
+        }
+        let error = resolver.resolve_to_vec("api.example.com").await;
+        assert_eq!(
+            error.unwrap_err().downcast_ref::<ResolveError>(),
+            Some(&ResolveError::DeferredToProxy {
+                name: "api.example.com".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn https_proxy_only() {
+        let proxies = [("https", "http://proxy.example.com:3128".to_owned())];
+        let resolver =
+            ProxyDeferredResolver::for_schemes(HOSTS, &proxies, None).unwrap();
+        let resolver = &resolver;
+        let lookup = |url: &str| {
+            let url = reqwest::Url::parse(url).unwrap();
+            let name = url.host_str().unwrap().parse().unwrap();
+            let context = ResolveContext::for_url(&url);
+            async move {
+                resolver
+                    .resolve_with_context(name, &context)
+                    .await
+                    .map(|addrs| addrs.collect::<Vec<_>>())
+            }
+        };
+
+        // reqwest connects to http:// URLs directly.
+        let addrs = lookup("http://api.example.com/").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.2:80".parse().unwrap()]);
+
+        let error = lookup("https://api.example.com/").await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ResolveError>(),
+            Some(&ResolveError::DeferredToProxy {
+                name: "api.example.com".into()
+            })
+        );
+
+        // Without a scheme, the name might be connected to directly.
+        assert!(resolver.resolve_to_vec("api.example.com").await.is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+//! Bounding how many queries one lookup can send
+//!
+//! A single call to `resolve` can turn into a lot of queries: one for each
+//! search domain and address family, again for each CNAME in the chain, and
+//! all of that again for each retry and each name server tried.  Usually
+//! that's a handful, but a long search list combined with a misbehaving
+//! upstream can make one lookup cost dozens of round trips.
+//! [`QueryBudgetResolver`] caps the number of upstream queries each lookup
+//! may send, and fails the lookup with [`ResolveError::QueryBudgetExceeded`]
+//! once it has used them up.  The queries themselves are counted (and
+//! refused) by a [`QueryBudgetTransport`], so this works with resolvers
+//! built by `dns_transport::transport_resolver`.
+//!
+//! Like the other task-local settings in this crate, the budget only applies
+//! to queries sent from the task running the lookup.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::Message;
+
+tokio::task_local! {
+    static CURRENT_BUDGET: Arc<Budget>;
+}
+
+/// The queries left to one lookup
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+impl Budget {
+    /// Uses up one query, returning whether there was one left.
+    fn take(&self) -> bool {
+        if self.used.fetch_add(1, Ordering::Relaxed) < self.limit {
+            return true;
+        }
+        self.exceeded.store(true, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Sends queries through the inner transport as long as the lookup they're
+/// for has any left in its budget (see [`QueryBudgetResolver`])
+///
+/// Queries sent outside of a `QueryBudgetResolver` lookup aren't limited.
+/// Since each query this transport passes on counts once, it should wrap
+/// the transport that's doing the sending: wrapped around a
+/// `hedge::HedgedTransport`, for example, a query and its hedge count as one.
+pub struct QueryBudgetTransport<T> {
+    inner: T,
+}
+
+impl<T: DnsTransport> QueryBudgetTransport<T> {
+    pub fn new(inner: T) -> QueryBudgetTransport<T> {
+        QueryBudgetTransport { inner }
+    }
+}
+
+impl<T: DnsTransport> DnsTransport for QueryBudgetTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let allowed = CURRENT_BUDGET.try_with(|budget| budget.take());
+        if allowed == Ok(false) {
+            debug!(
+                "query budget exhausted",
+                upstream = server.socket_addr,
+                name = query.queries().first().map(|query| query.name()),
+            );
+            return futures::future::err(
+                ResolveErrorKind::Message("query budget exhausted").into(),
+            )
+            .boxed();
+        }
+        self.inner.send(server, query)
+    }
+}
+
+/// Fails lookups that need more than `max_queries` upstream queries
+///
+/// Once a lookup has used up its budget, [`QueryBudgetTransport`] refuses
+/// any more of its queries, and if the lookup then fails (as it will, unless
+/// some earlier query already found what it needed), it fails with
+/// [`ResolveError::QueryBudgetExceeded`].  Lookups answered from trust-dns's
+/// cache don't send any queries at all.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::dns_transport::StandardTransport;
+/// # use reqwest_resolve::query_budget::{QueryBudgetResolver, QueryBudgetTransport};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let options = ResolverOpts::default();
+/// let transport = QueryBudgetTransport::new(StandardTransport::new(options));
+/// let resolver =
+///     transport_resolver(ResolverConfig::default(), options, transport)
+///         .unwrap();
+/// let my_resolver =
+///     QueryBudgetResolver::new(TransportDnsResolver::new(resolver), 8);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct QueryBudgetResolver<R> {
+    inner: R,
+    max_queries: usize,
+}
+
+impl<R> QueryBudgetResolver<R> {
+    pub fn new(inner: R, max_queries: usize) -> QueryBudgetResolver<R> {
+        QueryBudgetResolver { inner, max_queries }
+    }
+}
+
+impl<R: MyResolve> MyResolve for QueryBudgetResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let host = name.as_str().to_owned();
+        within_budget(self.max_queries, host, self.inner.resolve(name))
+            .boxed()
+            .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let host = name.as_str().to_owned();
+        let lookup = self.inner.resolve_detailed(name);
+        within_budget(self.max_queries, host, lookup).boxed()
+    }
+}
+
+/// Runs `lookup` with a budget of `limit` queries.
+async fn within_budget<T>(
+    limit: usize,
+    name: String,
+    lookup: impl Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>,
+) -> Result<T, Box<dyn StdError + Send + Sync>> {
+    let budget = Arc::new(Budget {
+        limit,
+        used: AtomicUsize::new(0),
+        exceeded: AtomicBool::new(false),
+    });
+    let result = CURRENT_BUDGET.scope(Arc::clone(&budget), lookup).await;
+    match result {
+        Err(_) if budget.exceeded.load(Ordering::Relaxed) => {
+            debug!("lookup ran out of queries", name = name, limit = limit);
+            Err(ResolveError::QueryBudgetExceeded { name, limit }.into())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,261 @@
+//! Preferring whichever upstreams are working best
+//!
+//! trust-dns either keeps name servers in the configured order, or reorders
+//! them by its own smoothed round-trip times, which it doesn't expose and
+//! which don't account for servers that answer quickly with errors.
+//! [`RankingTransport`] keeps rolling statistics for each upstream (success
+//! rate and latency over its most recent queries), makes them available
+//! through [`UpstreamRanking`], and moves an upstream down the preference
+//! list when it's doing clearly worse than the one behind it.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::latency::Latencies;
+use crate::logging::debug;
+use futures::future::FutureExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::proto::op::Message;
+
+/// When a [`RankingTransport`] swaps two upstreams
+///
+/// The one behind moves ahead if its success rate is more than
+/// `success_margin` higher, or if the two are within `success_margin` of
+/// each other and its median latency is less than the other's divided by
+/// `latency_factor`.  Moving back takes the same margins the other way, so
+/// two upstreams that are about as good as each other don't keep trading
+/// places.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RankingPolicy {
+    pub success_margin: f64,
+    pub latency_factor: f64,
+}
+
+impl Default for RankingPolicy {
+    /// 5 percentage points of success rate, or twice as fast
+    fn default() -> RankingPolicy {
+        RankingPolicy { success_margin: 0.05, latency_factor: 2.0 }
+    }
+}
+
+/// What a [`RankingTransport`] knows about one upstream
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct UpstreamStats {
+    pub addr: SocketAddr,
+    /// how many recent queries the rest is based on
+    pub samples: usize,
+    /// the fraction of those that were answered, once there are enough
+    pub success_rate: Option<f64>,
+    /// the median latency of the answered ones, once there are enough
+    pub median: Option<Duration>,
+    /// the 99th percentile latency of the answered ones, once there are
+    /// enough
+    pub p99: Option<Duration>,
+}
+
+struct Shared {
+    latencies: Latencies,
+    /// upstream addresses, most preferred first
+    order: Mutex<Vec<SocketAddr>>,
+}
+
+/// Reads the statistics of a [`RankingTransport`]
+#[derive(Clone)]
+pub struct UpstreamRanking {
+    shared: Arc<Shared>,
+}
+
+impl UpstreamRanking {
+    /// Returns each upstream's statistics, most preferred first.
+    pub fn snapshot(&self) -> Vec<UpstreamStats> {
+        let order = self.shared.order.lock().unwrap().clone();
+        let latencies = &self.shared.latencies;
+        order
+            .into_iter()
+            .map(|addr| UpstreamStats {
+                addr,
+                samples: latencies.samples(addr),
+                success_rate: latencies.success_rate(addr),
+                median: latencies.percentile(addr, 0.5),
+                p99: latencies.percentile(addr, 0.99),
+            })
+            .collect()
+    }
+}
+
+/// Sends each query to the upstream currently ranked where the one it was
+/// meant for is configured, and reranks them as queries finish
+///
+/// To trust-dns, nothing changes: it still asks its first name server first.
+/// This transport just sends that query to whichever upstream is ranked
+/// first at the time (using its configuration for the query's protocol),
+/// and so on down the list.  Set `ResolverOpts::server_ordering_strategy`
+/// to `UserProvidedOrder`, so that trust-dns isn't reordering them too.
+///
+/// An upstream only moves once both it and its neighbour have enough
+/// statistics to go by (see [`RankingPolicy`]).  One that has been moved
+/// down only gets queries when the ones ahead of it fail, so it stays down
+/// until they do worse than it did.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::dns_transport::StandardTransport;
+/// # use reqwest_resolve::ranking::{RankingPolicy, RankingTransport};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::config::ServerOrderingStrategy::UserProvidedOrder;
+/// let config = ResolverConfig::quad9();
+/// let mut options = ResolverOpts::default();
+/// options.server_ordering_strategy = UserProvidedOrder;
+/// let transport = RankingTransport::new(
+///     StandardTransport::new(options),
+///     &config,
+///     RankingPolicy::default(),
+/// );
+/// let ranking = transport.ranking();
+/// let resolver = transport_resolver(config, options, transport).unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// for upstream in ranking.snapshot() {
+///     println!("{}: {:?}", upstream.addr, upstream.success_rate);
+/// }
+/// ```
+pub struct RankingTransport<T> {
+    inner: T,
+    servers: Vec<NameServerConfig>,
+    /// upstream addresses, in the configured order
+    configured: Vec<SocketAddr>,
+    policy: RankingPolicy,
+    shared: Arc<Shared>,
+}
+
+impl<T: DnsTransport> RankingTransport<T> {
+    /// Ranks the name servers in `config`, which should be the
+    /// configuration the resolver was built with.
+    pub fn new(
+        inner: T,
+        config: &ResolverConfig,
+        policy: RankingPolicy,
+    ) -> RankingTransport<T> {
+        let servers = config.name_servers().to_vec();
+        let mut configured = Vec::new();
+        for server in &servers {
+            if !configured.contains(&server.socket_addr) {
+                configured.push(server.socket_addr);
+            }
+        }
+        RankingTransport {
+            inner,
+            servers,
+            policy,
+            shared: Arc::new(Shared {
+                latencies: Latencies::default(),
+                order: Mutex::new(configured.clone()),
+            }),
+            configured,
+        }
+    }
+
+    pub fn ranking(&self) -> UpstreamRanking {
+        UpstreamRanking { shared: Arc::clone(&self.shared) }
+    }
+
+    /// Returns the server a query meant for `server` should go to.
+    fn target(&self, server: &NameServerConfig) -> NameServerConfig {
+        let addr = server.socket_addr;
+        let Some(position) = self.configured.iter().position(|a| *a == addr)
+        else {
+            return server.clone();
+        };
+        let ranked = self.shared.order.lock().unwrap()[position];
+        self.servers
+            .iter()
+            .find(|s| s.socket_addr == ranked && s.protocol == server.protocol)
+            .cloned()
+            .unwrap_or_else(|| server.clone())
+    }
+}
+
+/// Returns whether `behind` is doing clearly better than `ahead`.
+fn clearly_better(
+    latencies: &Latencies,
+    policy: &RankingPolicy,
+    ahead: SocketAddr,
+    behind: SocketAddr,
+) -> bool {
+    let (Some(ahead_rate), Some(behind_rate)) =
+        (latencies.success_rate(ahead), latencies.success_rate(behind))
+    else {
+        return false;
+    };
+    if (behind_rate - ahead_rate).abs() > policy.success_margin {
+        return behind_rate > ahead_rate;
+    }
+    match (latencies.percentile(ahead, 0.5), latencies.percentile(behind, 0.5))
+    {
+        (Some(ahead), Some(behind)) => {
+            behind.mul_f64(policy.latency_factor) < ahead
+        }
+        _ => false,
+    }
+}
+
+/// Moves upstreams down the preference list past any that are doing
+/// clearly better.
+fn rerank(shared: &Shared, policy: &RankingPolicy) {
+    let mut order = shared.order.lock().unwrap();
+    let mut changed = false;
+    for _ in 0..order.len() {
+        let mut swapped = false;
+        for i in 1..order.len() {
+            let (ahead, behind) = (order[i - 1], order[i]);
+            if clearly_better(&shared.latencies, policy, ahead, behind) {
+                order.swap(i - 1, i);
+                swapped = true;
+            }
+        }
+        if !swapped {
+            break;
+        }
+        changed = true;
+    }
+    if changed {
+        debug!("reordered upstreams", order = *order);
+    }
+}
+
+impl<T: DnsTransport> DnsTransport for RankingTransport<T> {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let target = self.target(server);
+        let started = Instant::now();
+        let response = self.inner.send(&target, query);
+        let shared = Arc::clone(&self.shared);
+        let policy = self.policy;
+
+        async move {
+            let result = response.await;
+            match &result {
+                Ok(_) => shared
+                    .latencies
+                    .record(target.socket_addr, started.elapsed()),
+                Err(_) => shared.latencies.record_failure(target.socket_addr),
+            }
+            rerank(&shared, &policy);
+            result
+        }
+        .boxed()
+    }
+}
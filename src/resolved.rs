@@ -0,0 +1,107 @@
+//! Addresses with where they came from
+//!
+//! reqwest only wants socket addresses back from a resolver, so that's all
+//! `MyResolve::resolve` returns.  Callers building their own connectors can
+//! make better decisions with more: how long an address is good for, whether
+//! it came from a cache (and so might be stale), and which backend produced
+//! it.  [`MyResolve::resolve_detailed`](crate::MyResolve::resolve_detailed)
+//! returns that as a list of [`ResolvedAddr`]s.
+
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::future::Future;
+#[cfg(feature = "trust-dns")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+#[cfg(feature = "trust-dns")]
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::proto::rr::RecordType;
+
+/// What `MyResolve::resolve_detailed` returns
+pub type DetailedResolving<'a> = Pin<
+    Box<
+        dyn Future<
+                Output = Result<
+                    Vec<ResolvedAddr>,
+                    Box<dyn StdError + Send + Sync>,
+                >,
+            > + Send
+            + 'a,
+    >,
+>;
+
+/// The backend name used for addresses from resolvers that don't say where
+/// they came from
+pub const UNKNOWN_BACKEND: &str = "unknown";
+
+/// One address from a lookup, along with how it was found
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ResolvedAddr {
+    /// the address itself, with the port 0 unless the backend supplied one
+    pub addr: SocketAddr,
+    /// the type of DNS record the address came from, if it came from DNS
+    #[cfg(feature = "trust-dns")]
+    pub record_type: Option<RecordType>,
+    /// how much longer the address may be used, if the backend says
+    pub ttl: Option<Duration>,
+    /// what produced the address, like "dns", "static", or "fixture"
+    pub backend: Cow<'static, str>,
+    /// whether it came out of this crate's cache rather than from the
+    /// backend just now (trust-dns's own cache doesn't count: it doesn't say
+    /// when it's been used)
+    pub cached: bool,
+}
+
+impl ResolvedAddr {
+    /// Describes an address that nothing more is known about.
+    pub fn unknown(addr: SocketAddr) -> ResolvedAddr {
+        ResolvedAddr {
+            addr,
+            #[cfg(feature = "trust-dns")]
+            record_type: None,
+            ttl: None,
+            backend: Cow::Borrowed(UNKNOWN_BACKEND),
+            cached: false,
+        }
+    }
+
+    /// Describes an address from an A or AAAA record (depending on its
+    /// family) that's good until `valid_until`.
+    #[cfg(feature = "trust-dns")]
+    pub(crate) fn from_dns(
+        ip: IpAddr,
+        valid_until: Instant,
+        cached: bool,
+    ) -> ResolvedAddr {
+        let record_type = match ip {
+            IpAddr::V4(_) => RecordType::A,
+            IpAddr::V6(_) => RecordType::AAAA,
+        };
+        ResolvedAddr {
+            addr: SocketAddr::new(ip, 0),
+            record_type: Some(record_type),
+            ttl: Some(valid_until.saturating_duration_since(Instant::now())),
+            backend: Cow::Borrowed("dns"),
+            cached,
+        }
+    }
+
+    /// Describes an address from a fixed table, like a `StaticResolver`.
+    pub(crate) fn from_table(
+        addr: SocketAddr,
+        backend: &'static str,
+    ) -> ResolvedAddr {
+        ResolvedAddr {
+            addr,
+            #[cfg(feature = "trust-dns")]
+            record_type: None,
+            ttl: None,
+            backend: Cow::Borrowed(backend),
+            cached: false,
+        }
+    }
+}
@@ -0,0 +1,727 @@
+//! Sending names to different upstreams according to glob patterns
+//!
+//! Rules are written as patterns over DNS names:
+//!
+//! * "api.example.com" matches only that name.
+//! * "*.example.com" matches every name under "example.com" (at any depth),
+//!   but not "example.com" itself.
+//! * "api-*.example.com" matches names like "api-eu.example.com": a `*`
+//!   inside the first label matches any run of characters within that one
+//!   label.
+//! * "*" matches everything.
+//!
+//! Matching is case-insensitive and ignores any trailing dot.  `*` may only
+//! appear in the first label.
+//!
+//! Rules can also be added from a list in the syntax of the `NO_PROXY`
+//! environment variable (see [`RoutingRules::no_proxy`]), so that the lists
+//! already maintained for proxy bypass can be reused as they are.
+//!
+//! Rules are collected in a [`RoutingRules`] and then compiled into a
+//! [`CompiledRules`], which indexes them by the literal part of each pattern.
+//! Matching a name then costs one binary search per label of the name, no
+//! matter how many rules there are.
+
+#[cfg(feature = "trust-dns")]
+use crate::do_resolve;
+#[cfg(feature = "trust-dns")]
+use crate::do_resolve_detailed;
+use crate::error::ResolveError;
+#[cfg(feature = "trust-dns")]
+use crate::logging::debug;
+use crate::special_use::normalize;
+#[cfg(feature = "trust-dns")]
+use crate::DetailedResolving;
+#[cfg(feature = "trust-dns")]
+use crate::MyResolve;
+#[cfg(feature = "trust-dns")]
+use crate::MyResolving;
+#[cfg(feature = "trust-dns")]
+use futures::future::FutureExt;
+use std::net::IpAddr;
+#[cfg(feature = "trust-dns")]
+use std::sync::Arc;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Routing rules as they're being collected, before compilation
+///
+/// When several rules match a name, the one with the highest priority wins.
+/// Among rules with the same priority, the most specific wins (the one with
+/// the longest literal suffix, so "*.eu.example.com" beats "*.example.com";
+/// if that's a tie, "legacy-*.example.com" beats "*.example.com"), and after
+/// that, whichever was added first.
+///
+/// ```
+/// # use reqwest_resolve::routing::RoutingRules;
+/// let rules = RoutingRules::new()
+///     .rule("*.example.com", "global")
+///     .rule("*.eu.example.com", "eu")
+///     .rule_with_priority("legacy-*.eu.example.com", 10, "legacy")
+///     .compile()
+///     .unwrap();
+/// assert_eq!(rules.lookup("www.example.com"), Some(&"global"));
+/// assert_eq!(rules.lookup("www.eu.example.com"), Some(&"eu"));
+/// assert_eq!(rules.lookup("legacy-1.eu.example.com"), Some(&"legacy"));
+/// assert_eq!(rules.lookup("example.com"), None);
+/// ```
+pub struct RoutingRules<T> {
+    rules: Vec<(Pattern, i32, T)>,
+}
+
+enum Pattern {
+    /// a glob pattern over names, as described in the module docs
+    Glob(String),
+    /// an IP address, optionally with a prefix length ("10.0.0.0/8")
+    Network(String),
+}
+
+impl<T> RoutingRules<T> {
+    pub fn new() -> RoutingRules<T> {
+        RoutingRules { rules: Vec::new() }
+    }
+
+    /// Adds a rule sending names matching `pattern` to `target`, with
+    /// priority 0.
+    pub fn rule(self, pattern: &str, target: T) -> RoutingRules<T> {
+        self.rule_with_priority(pattern, 0, target)
+    }
+
+    /// Adds a rule sending names matching `pattern` to `target`, with the
+    /// given priority.  Higher priorities win.
+    pub fn rule_with_priority(
+        mut self,
+        pattern: &str,
+        priority: i32,
+        target: T,
+    ) -> RoutingRules<T> {
+        self.rules.push((Pattern::Glob(pattern.to_owned()), priority, target));
+        self
+    }
+
+    /// Indexes the rules for matching, checking that every pattern is valid.
+    pub fn compile(self) -> Result<CompiledRules<T>, ResolveError> {
+        let mut entries = Vec::with_capacity(self.rules.len());
+        let mut networks = Vec::new();
+        for (order, (pattern, priority, target)) in
+            self.rules.into_iter().enumerate()
+        {
+            let pattern = match pattern {
+                Pattern::Glob(pattern) => pattern,
+                Pattern::Network(network) => {
+                    let (addr, prefix) = parse_network(&network)?;
+                    let kind = PatternKind::Network(addr, prefix);
+                    let specificity = usize::from(prefix) + 1;
+                    networks.push(CompiledRule {
+                        kind,
+                        priority,
+                        specificity,
+                        order,
+                        target,
+                    });
+                    continue;
+                }
+            };
+            let (suffix, kind) = parse_pattern(&pattern)?;
+            let specificity = match kind {
+                PatternKind::Subdomains => 2 * suffix.len(),
+                _ => 2 * suffix.len() + 1,
+            };
+            entries.push((
+                suffix,
+                CompiledRule { kind, priority, specificity, order, target },
+            ));
+        }
+
+        // Group the rules into buckets by literal suffix, sorted so that
+        // lookups can binary search for each of a name's suffixes.
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut buckets: Vec<(Vec<String>, Vec<CompiledRule<T>>)> = Vec::new();
+        for (suffix, rule) in entries {
+            match buckets.last_mut() {
+                Some((last, rules)) if *last == suffix => rules.push(rule),
+                _ => buckets.push((suffix, vec![rule])),
+            }
+        }
+
+        Ok(CompiledRules { buckets, networks })
+    }
+}
+
+impl<T: Clone> RoutingRules<T> {
+    /// Adds rules sending names matching any entry of `list` to `target`,
+    /// with priority 0
+    ///
+    /// `list` is in the syntax of the `NO_PROXY` environment variable, as
+    /// reqwest interprets it: entries are separated by commas, and
+    ///
+    /// * "example.com" and ".example.com" both match "example.com" and every
+    ///   name under it (and so does "*.example.com", which reqwest ignores
+    ///   but other tools accept),
+    /// * "*" matches everything, and
+    /// * an IP address, or a network like "10.0.0.0/8" or "fd00::/8",
+    ///   matches names that are IP addresses in it.
+    ///
+    /// Entries with a port, like "example.com:8080", are skipped: reqwest
+    /// compares them with bare hostnames, which they never match.
+    ///
+    /// A `CompiledRules<bool>` built this way works as a filter, too:
+    ///
+    /// ```
+    /// # use reqwest_resolve::routing::RoutingRules;
+    /// let bypass = RoutingRules::new()
+    ///     .no_proxy("internal.example.com, .corp, 10.0.0.0/8", true)
+    ///     .compile()
+    ///     .unwrap();
+    /// assert_eq!(bypass.lookup("internal.example.com"), Some(&true));
+    /// assert_eq!(bypass.lookup("wiki.corp"), Some(&true));
+    /// assert_eq!(bypass.lookup("10.1.2.3"), Some(&true));
+    /// assert_eq!(bypass.lookup("example.com"), None);
+    /// ```
+    pub fn no_proxy(self, list: &str, target: T) -> RoutingRules<T> {
+        self.no_proxy_with_priority(list, 0, target)
+    }
+
+    /// Adds rules sending names matching any entry of `list` to `target`,
+    /// with the given priority (see [`RoutingRules::no_proxy`]).
+    ///
+    /// Among the rules this adds, the same rules as for glob patterns decide
+    /// which is most specific: "api.example.com" beats ".example.com", and a
+    /// network with a longer prefix beats one with a shorter prefix.
+    pub fn no_proxy_with_priority(
+        mut self,
+        list: &str,
+        priority: i32,
+        target: T,
+    ) -> RoutingRules<T> {
+        for entry in list.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            if entry == "*" {
+                self = self.rule_with_priority("*", priority, target.clone());
+                continue;
+            }
+            let host = entry.strip_prefix('[').unwrap_or(entry);
+            let host = host.strip_suffix(']').unwrap_or(host);
+            let address = host.split('/').next().unwrap_or(host);
+            if address.parse::<IpAddr>().is_ok() {
+                let network = Pattern::Network(host.to_owned());
+                self.rules.push((network, priority, target.clone()));
+                continue;
+            }
+            if entry.contains(':') {
+                continue;
+            }
+            let domain = entry.strip_prefix('.').unwrap_or(entry);
+            let domain = domain.strip_prefix("*.").unwrap_or(domain);
+            let subdomains = format!("*.{}", domain);
+            self = self
+                .rule_with_priority(domain, priority, target.clone())
+                .rule_with_priority(&subdomains, priority, target.clone());
+        }
+        self
+    }
+}
+
+impl<T> Default for RoutingRules<T> {
+    fn default() -> RoutingRules<T> {
+        RoutingRules::new()
+    }
+}
+
+enum PatternKind {
+    /// the name must be exactly the suffix
+    Exact,
+    /// the name must be strictly under the suffix
+    Subdomains,
+    /// the name must be exactly one label under the suffix, and that label
+    /// must match this glob
+    LabelGlob(String),
+    /// the name must be an IP address in this network
+    Network(IpAddr, u8),
+}
+
+struct CompiledRule<T> {
+    kind: PatternKind,
+    priority: i32,
+    /// twice the number of literal labels in the pattern, plus one if the
+    /// first label isn't a bare "*" (or for networks, the prefix length plus
+    /// one)
+    specificity: usize,
+    /// the order in which the rule was added
+    order: usize,
+    target: T,
+}
+
+/// Returns the pattern's literal labels (in reverse order, so that they sort
+/// by suffix) and what the name has to look like in front of them.
+fn parse_pattern(
+    pattern: &str,
+) -> Result<(Vec<String>, PatternKind), ResolveError> {
+    let invalid = |message: &str| {
+        ResolveError::InvalidConfig(format!(
+            "routing pattern {:?}: {}",
+            pattern, message
+        ))
+    };
+
+    let normalized = normalize(pattern);
+    if normalized.is_empty() {
+        return Err(invalid("pattern is empty"));
+    }
+
+    let mut labels: Vec<&str> = normalized.split('.').collect();
+    if labels.iter().any(|label| label.is_empty()) {
+        return Err(invalid("pattern contains an empty label"));
+    }
+    if labels[1..].iter().any(|label| label.contains('*')) {
+        return Err(invalid("'*' may only appear in the first label"));
+    }
+
+    let first = labels[0];
+    let kind = if first == "*" {
+        labels.remove(0);
+        PatternKind::Subdomains
+    } else if first.contains('*') {
+        labels.remove(0);
+        PatternKind::LabelGlob(first.to_owned())
+    } else {
+        PatternKind::Exact
+    };
+
+    let suffix = labels.iter().rev().map(|label| (*label).to_owned()).collect();
+    Ok((suffix, kind))
+}
+
+/// Parses an IP address with an optional prefix length, which defaults to
+/// the whole address.
+fn parse_network(network: &str) -> Result<(IpAddr, u8), ResolveError> {
+    let invalid = || {
+        ResolveError::InvalidConfig(format!(
+            "routing network {:?}: expected an IP address with an optional \
+             prefix length",
+            network
+        ))
+    };
+
+    let (addr, prefix) = match network.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (network, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        None => bits,
+        Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+    };
+    if prefix > bits {
+        return Err(invalid());
+    }
+    Ok((addr, prefix))
+}
+
+/// Returns whether `addr` is in the network of `prefix` bits starting at
+/// `network`.
+fn network_contains(network: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    let (network, addr, bits) = match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            (u128::from(u32::from(network)), u128::from(u32::from(addr)), 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            (u128::from(network), u128::from(addr), 128)
+        }
+        _ => return false,
+    };
+    let shift = bits - u32::from(prefix);
+    network.checked_shr(shift).unwrap_or(0)
+        == addr.checked_shr(shift).unwrap_or(0)
+}
+
+/// Returns whether `label` matches `glob`, in which `*` matches any run of
+/// characters (including none).
+fn glob_matches(glob: &str, label: &str) -> bool {
+    let mut parts = glob.split('*');
+    // `split()` always yields at least one part.
+    let first = parts.next().unwrap();
+    let Some(mut rest) = label.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // There was no '*' at all.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Routing rules indexed for matching, built by [`RoutingRules::compile`]
+pub struct CompiledRules<T> {
+    /// literal suffix (labels reversed), sorted, with the rules for each
+    buckets: Vec<(Vec<String>, Vec<CompiledRule<T>>)>,
+    /// rules for IP addresses, from [`RoutingRules::no_proxy`]
+    networks: Vec<CompiledRule<T>>,
+}
+
+impl<T> CompiledRules<T> {
+    /// Returns the target of the winning rule for `name`, if any rule
+    /// matches.
+    pub fn lookup(&self, name: &str) -> Option<&T> {
+        let name = normalize(name);
+        let labels: Vec<String> =
+            name.split('.').rev().map(str::to_owned).collect();
+
+        let mut best: Option<&CompiledRule<T>> = None;
+        for depth in 0..=labels.len() {
+            let suffix = &labels[..depth];
+            let Ok(index) =
+                self.buckets.binary_search_by(|(key, _)| key[..].cmp(suffix))
+            else {
+                continue;
+            };
+
+            for rule in &self.buckets[index].1 {
+                let matches = match &rule.kind {
+                    PatternKind::Exact => depth == labels.len(),
+                    PatternKind::Subdomains => depth < labels.len(),
+                    PatternKind::LabelGlob(glob) => {
+                        depth + 1 == labels.len()
+                            && glob_matches(glob, &labels[depth])
+                    }
+                    PatternKind::Network(..) => false,
+                };
+                if matches && beats(rule, best) {
+                    best = Some(rule);
+                }
+            }
+        }
+
+        let host = name.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+        if let Ok(addr) = host.unwrap_or(&name).parse::<IpAddr>() {
+            for rule in &self.networks {
+                let PatternKind::Network(network, prefix) = rule.kind else {
+                    continue;
+                };
+                if network_contains(network, prefix, addr) && beats(rule, best)
+                {
+                    best = Some(rule);
+                }
+            }
+        }
+
+        best.map(|rule| &rule.target)
+    }
+
+    /// Returns the number of rules.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|(_, rules)| rules.len()).sum::<usize>()
+            + self.networks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty() && self.networks.is_empty()
+    }
+}
+
+/// Returns whether `rule` wins over `best`, the best matching rule so far.
+fn beats<T>(rule: &CompiledRule<T>, best: Option<&CompiledRule<T>>) -> bool {
+    match best {
+        None => true,
+        Some(best) => {
+            (rule.priority, rule.specificity, best.order)
+                > (best.priority, best.specificity, rule.order)
+        }
+    }
+}
+
+impl<T> Default for CompiledRules<T> {
+    fn default() -> CompiledRules<T> {
+        CompiledRules { buckets: Vec::new(), networks: Vec::new() }
+    }
+}
+
+/// Sends each name to the upstream resolver chosen by the routing rules, and
+/// names that no rule matches to the inner resolver
+///
+/// ```
+/// # use reqwest_resolve::routing::{RoutingResolver, RoutingRules};
+/// # use reqwest_resolve::MyCustomDnsResolver;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = |config| {
+///     Arc::new(
+///         TokioAsyncResolver::tokio(config, ResolverOpts::default()).unwrap(),
+///     )
+/// };
+/// let rules = RoutingRules::new()
+///     .rule("*.eu.example.com", resolver(ResolverConfig::quad9()))
+///     .rule("*.example.com", resolver(ResolverConfig::cloudflare()))
+///     .compile()
+///     .unwrap();
+/// let default = TokioAsyncResolver::tokio(
+///     ResolverConfig::google(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver =
+///     RoutingResolver::new(MyCustomDnsResolver::new(default), rules);
+/// ```
+#[cfg(feature = "trust-dns")]
+pub struct RoutingResolver<R> {
+    inner: R,
+    rules: CompiledRules<Arc<TokioAsyncResolver>>,
+}
+
+#[cfg(feature = "trust-dns")]
+impl<R> RoutingResolver<R> {
+    pub fn new(
+        inner: R,
+        rules: CompiledRules<Arc<TokioAsyncResolver>>,
+    ) -> RoutingResolver<R> {
+        RoutingResolver { inner, rules }
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+impl<R: MyResolve> MyResolve for RoutingResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.rules.lookup(name.as_str()) {
+            None => self.inner.resolve(name),
+            Some(resolver) => {
+                debug!("routing name by rule", name = name.as_str());
+                let resolver = Arc::clone(resolver);
+                async move { do_resolve(&resolver, name).await }.boxed().into()
+            }
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.rules.lookup(name.as_str()) {
+            None => self.inner.resolve_detailed(name),
+            Some(resolver) => {
+                debug!("routing name by rule", name = name.as_str());
+                let resolver = Arc::clone(resolver);
+                async move { do_resolve_detailed(&resolver, name).await }
+                    .boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+    use super::RoutingRules;
+
+    #[test]
+    fn precedence() {
+        let rules = RoutingRules::new()
+            .rule("*", "any")
+            .rule("*.example.com", "sub")
+            .rule("*.eu.example.com", "eu")
+            .rule("api-*.eu.example.com", "api-glob")
+            .rule("api-1.eu.example.com", "api-1")
+            .rule("legacy-*.example.com", "legacy")
+            .rule("*.example.com", "sub again")
+            .rule_with_priority("*.pinned.example.com", -1, "low")
+            .rule_with_priority("www.example.org", 5, "high")
+            .compile()
+            .unwrap();
+        let cases = [
+            // The longest literal suffix wins...
+            ("www.eu.example.com", Some("eu")),
+            ("a.b.eu.example.com", Some("eu")),
+            ("eu.example.com", Some("sub")),
+            ("www.example.com", Some("sub")),
+            // ...then a glob in the first label over a bare "*"...
+            ("api-2.eu.example.com", Some("api-glob")),
+            ("legacy-1.example.com", Some("legacy")),
+            // ...which only covers one label...
+            ("legacy-1.x.example.com", Some("sub")),
+            // An exact name's first label is part of its literal suffix.
+            ("api-1.eu.example.com", Some("api-1")),
+            // A lower priority loses to less specific rules.
+            ("www.pinned.example.com", Some("sub")),
+            ("www.example.org", Some("high")),
+            ("example.com", Some("any")),
+            ("org", Some("any")),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(rules.lookup(name).copied(), expected, "{}", name);
+        }
+        assert_eq!(rules.len(), 9);
+    }
+
+    /// Among equally specific rules with the same priority, the one added
+    /// first wins.
+    #[test]
+    fn earliest_wins_ties() {
+        let rules = RoutingRules::new()
+            .rule("api-*.example.com", "api")
+            .rule("*-eu.example.com", "eu")
+            .rule("*.example.com", "first")
+            .rule("*.example.com", "second")
+            .compile()
+            .unwrap();
+        assert_eq!(rules.lookup("api-eu.example.com"), Some(&"api"));
+        assert_eq!(rules.lookup("web-eu.example.com"), Some(&"eu"));
+        assert_eq!(rules.lookup("www.example.com"), Some(&"first"));
+
+        let rules = RoutingRules::new()
+            .rule("*-eu.example.com", "eu")
+            .rule("api-*.example.com", "api")
+            .compile()
+            .unwrap();
+        assert_eq!(rules.lookup("api-eu.example.com"), Some(&"eu"));
+    }
+
+    #[test]
+    fn case_and_trailing_dot() {
+        let rules = RoutingRules::new()
+            .rule("API-*.Example.COM.", "api")
+            .rule("www.example.com", "www")
+            .compile()
+            .unwrap();
+        for name in
+            ["api-eu.example.com", "API-EU.EXAMPLE.COM.", "Api-Eu.Example.Com"]
+        {
+            assert_eq!(rules.lookup(name), Some(&"api"), "{}", name);
+        }
+        for name in ["www.example.com.", "WWW.Example.com"] {
+            assert_eq!(rules.lookup(name), Some(&"www"), "{}", name);
+        }
+        assert_eq!(rules.lookup("example.com."), None);
+    }
+
+    #[test]
+    fn networks() {
+        let rules = RoutingRules::new()
+            .no_proxy("10.0.0.0/8, 10.1.0.0/16", "narrow-or-wide")
+            .no_proxy("10.1.2.3", "host")
+            .no_proxy("10.1.0.0/16, fd00::/8", "later")
+            .compile()
+            .unwrap();
+        let cases = [
+            // The longest prefix wins...
+            ("10.1.2.3", Some("host")),
+            ("10.9.9.9", Some("narrow-or-wide")),
+            // ...then whichever came first.
+            ("10.1.9.9", Some("narrow-or-wide")),
+            ("fd00::1", Some("later")),
+            ("[fd00::1]", Some("later")),
+            ("11.0.0.1", None),
+            ("fe80::1", None),
+            ("10.1.2.3.example.com", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(rules.lookup(name).copied(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn no_proxy() {
+        let rules = RoutingRules::new()
+            .no_proxy(
+                " internal.example.com ,.corp,, *.lab.example.net,\t\
+                 10.0.0.0/8 , 192.0.2.7, [2001:db8::1], fd00::/8, \
+                 app.example.org:8080, 198.51.100.1:443, [2001:db8::2]:443",
+                true,
+            )
+            .compile()
+            .unwrap();
+        let cases = [
+            // Entries without a leading dot cover the name itself, and the
+            // names under it.
+            ("internal.example.com", true),
+            ("api.internal.example.com", true),
+            ("example.com", false),
+            ("notinternal.example.com", false),
+            // So do entries with one, or with "*.".
+            ("corp", true),
+            ("wiki.corp", true),
+            ("Wiki.Corp.", true),
+            ("lab.example.net", true),
+            ("a.b.lab.example.net", true),
+            ("xcorp", false),
+            // Addresses and networks cover addresses.
+            ("10.1.2.3", true),
+            ("192.0.2.7", true),
+            ("192.0.2.8", false),
+            ("2001:db8::1", true),
+            ("[2001:db8::1]", true),
+            ("fd12::1", true),
+            ("2001:db8::3", false),
+            // Entries with a port never match.
+            ("app.example.org", false),
+            ("198.51.100.1", false),
+            ("2001:db8::2", false),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(rules.lookup(name).is_some(), expected, "{}", name);
+        }
+
+        let everything = RoutingRules::new()
+            .no_proxy("example.com, *", true)
+            .compile()
+            .unwrap();
+        for name in ["example.com", "other.org", "10.0.0.1", "localhost"] {
+            assert_eq!(everything.lookup(name), Some(&true), "{}", name);
+        }
+
+        let nothing =
+            RoutingRules::new().no_proxy(" , ,", true).compile().unwrap();
+        assert!(nothing.is_empty());
+    }
+
+    #[test]
+    fn glob() {
+        let cases = [
+            ("api-*", "api-eu", true),
+            ("api-*", "api-", true),
+            ("api-*", "api", false),
+            ("*-eu", "api-eu", true),
+            ("*-eu", "api-us", false),
+            ("a*b", "ab", true),
+            ("a*b", "axxb", true),
+            ("a*b", "ba", false),
+            ("a*a", "a", false),
+            ("a*b*c", "abc", true),
+            ("a*b*c", "acb", false),
+            ("*x*", "x", true),
+            ("exact", "exact", true),
+            ("exact", "exactly", false),
+        ];
+        for (glob, label, expected) in cases {
+            assert_eq!(
+                glob_matches(glob, label),
+                expected,
+                "{} {}",
+                glob,
+                label
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in ["", ".", "a..example.com", "api.*.example.com", "a.b*"]
+        {
+            let compiled = RoutingRules::new().rule(pattern, ()).compile();
+            assert!(compiled.is_err(), "{:?}", pattern);
+        }
+    }
+}
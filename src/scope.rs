@@ -0,0 +1,180 @@
+//! Attaching scope IDs to link-local IPv6 answers
+//!
+//! An IPv6 link-local address (in fe80::/10) only means something on one
+//! link, so connecting to one takes the scope ID of the interface it's on
+//! too (the "%eth0" in "fe80::1%eth0").  DNS answers don't carry scope IDs:
+//! whoever asked has to know which link the answer is about.  Without one,
+//! the connection attempt fails (or, on some systems, goes out of whichever
+//! interface the routing table picks).
+//!
+//! [`ScopedResolver`] attaches a scope ID to every link-local address its
+//! inner resolver returns without one.  The resolvers in this crate that
+//! know which link an answer came from do the same on their own:
+//! `links::LinkResolver` uses each link's interface, and `llmnr` uses the
+//! interface each response arrived on.
+
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+/// Returns whether `ip` is an IPv6 link-local address (in fe80::/10).
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Returns `addr` with the scope ID `scope_id` if it's a link-local IPv6
+/// address without one, and unchanged otherwise
+///
+/// ```
+/// # use reqwest_resolve::scope::with_scope;
+/// # use std::net::SocketAddr;
+/// let addr: SocketAddr = "[fe80::1]:443".parse().unwrap();
+/// assert_eq!(with_scope(addr, 3), "[fe80::1%3]:443".parse().unwrap());
+///
+/// let global: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+/// assert_eq!(with_scope(global, 3), global);
+/// ```
+pub fn with_scope(addr: SocketAddr, scope_id: u32) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(mut addr)
+            if addr.scope_id() == 0 && is_link_local(addr.ip()) =>
+        {
+            addr.set_scope_id(scope_id);
+            SocketAddr::V6(addr)
+        }
+        addr => addr,
+    }
+}
+
+/// Returns the scope ID of the link-local servers among `servers`, if they
+/// have one and all agree on it
+///
+/// A server reached at a link-local address is on that link, and so are
+/// the link-local addresses in its answers.
+#[cfg(feature = "trust-dns")]
+pub(crate) fn scope_of_servers<'a>(
+    servers: impl IntoIterator<Item = &'a SocketAddr>,
+) -> Option<u32> {
+    let mut scope = None;
+    for server in servers {
+        let SocketAddr::V6(server) = server else {
+            continue;
+        };
+        if server.scope_id() == 0 || !is_link_local(server.ip()) {
+            continue;
+        }
+        match scope {
+            None => scope = Some(server.scope_id()),
+            Some(scope) if scope == server.scope_id() => (),
+            Some(_) => return None,
+        }
+    }
+    scope
+}
+
+/// Returns the index of the network interface called `name` (like "eth0"),
+/// for use as a scope ID.
+#[cfg(target_os = "linux")]
+pub fn interface_index(name: &str) -> std::io::Result<u32> {
+    use std::io;
+
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name {:?}", name),
+        ));
+    }
+    let index =
+        std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", name))?;
+    index.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("interface {:?} has index {:?}", name, index.trim()),
+        )
+    })
+}
+
+/// Attaches a scope ID to the link-local addresses from its inner resolver
+///
+/// Addresses that already have a scope ID, and all other addresses, are
+/// left alone.  This suits a resolver whose servers are all on the same
+/// link, like the ones learned from a router advertisement on one
+/// interface.
+///
+/// ```
+/// # use reqwest_resolve::scope::ScopedResolver;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// # use std::net::SocketAddr;
+/// # use std::str::FromStr;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "printer.local" => ["fe80::2", "2001:db8::2"],
+/// };
+///
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// // The printer is on the interface with index 2.
+/// let my_resolver = ScopedResolver::new(&HOSTS, 2);
+/// let name =
+///     hyper::client::connect::dns::Name::from_str("printer.local").unwrap();
+/// let addrs: Vec<SocketAddr> =
+///     my_resolver.resolve(name).await.unwrap().collect();
+/// assert_eq!(
+///     addrs,
+///     [
+///         "[fe80::2%2]:0".parse::<SocketAddr>().unwrap(),
+///         "[2001:db8::2]:0".parse().unwrap(),
+///     ]
+/// );
+/// # });
+/// ```
+pub struct ScopedResolver<R> {
+    inner: R,
+    scope_id: u32,
+}
+
+impl<R> ScopedResolver<R> {
+    /// Attaches `scope_id` (an interface index; see `interface_index`).
+    pub fn new(inner: R, scope_id: u32) -> ScopedResolver<R> {
+        ScopedResolver { inner, scope_id }
+    }
+}
+
+impl<R: MyResolve> MyResolve for ScopedResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let scope_id = self.scope_id;
+        let lookup = self.inner.resolve(name);
+        async move {
+            let addrs = lookup.await?;
+            Ok(Box::new(addrs.map(move |addr| with_scope(addr, scope_id)))
+                as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let scope_id = self.scope_id;
+        let lookup = self.inner.resolve_detailed(name);
+        async move {
+            let mut addrs = lookup.await?;
+            for addr in &mut addrs {
+                addr.addr = with_scope(addr.addr, scope_id);
+            }
+            Ok(addrs)
+        }
+        .boxed()
+    }
+}
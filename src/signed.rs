@@ -0,0 +1,460 @@
+//! Checking signatures on addresses from discovery stores
+//!
+//! Internal service discovery usually means addresses from a store like
+//! etcd or Consul, pushed to a `watch::WatchingResolver`, or a hosts-style
+//! document that's fetched and parsed into a [`Fixture`].  Anyone who can
+//! write to the store can then send outbound requests wherever they like.
+//! With the addresses signed by whatever publishes them, and the signatures
+//! checked here, they can't: writing to the store isn't enough without the
+//! publisher's key.
+//!
+//! Signatures are Ed25519, detached, and checked against a set of
+//! [`TrustedKeys`] (more than one, so that keys can be rotated).  There are
+//! two things that can be signed:
+//!
+//! * a whole document, like a hosts file or zone snippet, whose exact
+//!   bytes are signed (see [`TrustedKeys::verify_fixture`]).  Nothing stops
+//!   an old document being put back in place of a new one, so documents
+//!   that need that should have something in them that says how old they
+//!   are.
+//! * one name's addresses, as a [`SignedAddresses`], which also says when
+//!   the signature stops being good so that an old set can't be replayed
+//!   forever.  `WatchingResolver::publish_signed` (with the "watch"
+//!   feature) checks them before publishing them.
+
+use crate::error::ResolveError;
+use crate::fixtures::Fixture;
+use crate::logging::debug;
+use crate::special_use::normalize;
+use base64::Engine;
+use ring::signature::Ed25519KeyPair;
+use ring::signature::UnparsedPublicKey;
+use ring::signature::ED25519;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// What [`SignedAddresses::message`] starts with, so that its signatures
+/// can't be passed off as signatures on anything else
+const ADDRESS_SET_CONTEXT: &str = "reqwest-resolve signed addresses v1";
+
+/// The Ed25519 public keys whose signatures are trusted
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TrustedKeys {
+    keys: Vec<[u8; 32]>,
+}
+
+impl TrustedKeys {
+    /// Trusts no keys, so nothing verifies until some are added.
+    pub fn new() -> TrustedKeys {
+        TrustedKeys::default()
+    }
+
+    /// Trusts signatures made with the key whose public half is
+    /// `public_key`.
+    pub fn with_key(mut self, public_key: [u8; 32]) -> TrustedKeys {
+        self.keys.push(public_key);
+        self
+    }
+
+    /// Like [`TrustedKeys::with_key`], with the public key base64-encoded.
+    pub fn with_base64_key(
+        self,
+        public_key: &str,
+    ) -> Result<TrustedKeys, ResolveError> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(public_key)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| {
+                ResolveError::InvalidConfig(format!(
+                    "{:?} is not a base64-encoded Ed25519 public key",
+                    public_key
+                ))
+            })?;
+        Ok(self.with_key(key))
+    }
+
+    /// Checks that `signature` is a signature on `message` by one of the
+    /// trusted keys, failing with [`ResolveError::InvalidSignature`] (which
+    /// says what `what` is) if it isn't.
+    pub fn verify(
+        &self,
+        what: &str,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), ResolveError> {
+        let verified = self.keys.iter().any(|key| {
+            UnparsedPublicKey::new(&ED25519, key)
+                .verify(message, signature)
+                .is_ok()
+        });
+        if !verified {
+            debug!("signature doesn't verify", what = what);
+            return Err(ResolveError::InvalidSignature(format!(
+                "{} isn't signed by any trusted key",
+                what
+            )));
+        }
+        Ok(())
+    }
+
+    /// Parses `document` as a [`Fixture`], once `signature` checks out as a
+    /// signature on its bytes.
+    ///
+    /// ```
+    /// # use reqwest_resolve::signed::TrustedKeys;
+    /// # use ring::signature::KeyPair;
+    /// # let random = ring::rand::SystemRandom::new();
+    /// # let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&random).unwrap();
+    /// # let key_pair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    /// # let public_key = key_pair.public_key().as_ref().try_into().unwrap();
+    /// let document = "backend.internal.  60  IN  A  192.0.2.10\n";
+    /// let signature = key_pair.sign(document.as_bytes());
+    ///
+    /// let keys = TrustedKeys::new().with_key(public_key);
+    /// let fixture =
+    ///     keys.verify_fixture(document, signature.as_ref()).unwrap();
+    /// assert_eq!(fixture.lookup("backend.internal").len(), 1);
+    ///
+    /// let tampered = "backend.internal.  60  IN  A  198.51.100.66\n";
+    /// assert!(keys.verify_fixture(tampered, signature.as_ref()).is_err());
+    /// ```
+    pub fn verify_fixture(
+        &self,
+        document: &str,
+        signature: &[u8],
+    ) -> Result<Fixture, ResolveError> {
+        self.verify("discovery document", document.as_bytes(), signature)?;
+        Fixture::parse(document)
+    }
+}
+
+/// One name's addresses, with a signature over them
+///
+/// These are meant to be stored as they are (with the "serde" feature, as
+/// JSON), by a publisher that signs them with [`SignedAddresses::sign`] or
+/// by signing [`SignedAddresses::message`] some other way.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SignedAddresses {
+    pub name: String,
+    pub addrs: Vec<SocketAddr>,
+    /// when the signature stops being good, in seconds since the Unix epoch
+    pub expires: u64,
+    /// the signature, base64-encoded
+    pub signature: String,
+}
+
+impl SignedAddresses {
+    /// Returns the bytes that are signed for `name`'s `addrs`
+    ///
+    /// That's a line saying what this is, then the name (lowercase, without
+    /// a trailing dot), the expiry time in seconds since the Unix epoch, and
+    /// the addresses, sorted and without duplicates, each on a line of its
+    /// own that ends with a newline.
+    pub fn message(name: &str, addrs: &[SocketAddr], expires: u64) -> Vec<u8> {
+        let mut addrs = addrs.to_vec();
+        addrs.sort();
+        addrs.dedup();
+        let mut message = format!(
+            "{}\n{}\n{}\n",
+            ADDRESS_SET_CONTEXT,
+            normalize(name),
+            expires
+        );
+        for addr in addrs {
+            message.push_str(&addr.to_string());
+            message.push('\n');
+        }
+        message.into_bytes()
+    }
+
+    /// Signs `name`'s `addrs` with the Ed25519 key in `pkcs8` (a PKCS#8
+    /// document, as `openssl genpkey -algorithm ed25519 -outform der` writes)
+    ///
+    /// ```
+    /// # use reqwest_resolve::signed::{SignedAddresses, TrustedKeys};
+    /// # use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    /// # fn publisher_key() -> (Vec<u8>, [u8; 32]) {
+    /// #     use ring::signature::KeyPair;
+    /// #     let random = ring::rand::SystemRandom::new();
+    /// #     let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&random).unwrap();
+    /// #     let pair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    /// #     (pkcs8.as_ref().to_vec(), pair.public_key().as_ref().try_into().unwrap())
+    /// # }
+    /// let (pkcs8, public_key) = publisher_key();
+    /// let expires = SystemTime::now() + Duration::from_secs(300);
+    /// let expires = expires.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    /// let signed = SignedAddresses::sign(
+    ///     "backend.internal",
+    ///     &["192.0.2.10:0".parse().unwrap()],
+    ///     expires,
+    ///     &pkcs8,
+    /// )
+    /// .unwrap();
+    ///
+    /// let keys = TrustedKeys::new().with_key(public_key);
+    /// assert_eq!(signed.verify(&keys).unwrap(), signed.addrs);
+    ///
+    /// let mut tampered = signed.clone();
+    /// tampered.addrs = vec!["198.51.100.66:0".parse().unwrap()];
+    /// assert!(tampered.verify(&keys).is_err());
+    /// ```
+    pub fn sign(
+        name: &str,
+        addrs: &[SocketAddr],
+        expires: u64,
+        pkcs8: &[u8],
+    ) -> Result<SignedAddresses, ResolveError> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|error| {
+            ResolveError::InvalidConfig(format!(
+                "not an Ed25519 private key: {}",
+                error
+            ))
+        })?;
+        let signature =
+            key_pair.sign(&SignedAddresses::message(name, addrs, expires));
+        Ok(SignedAddresses {
+            name: name.to_owned(),
+            addrs: addrs.to_vec(),
+            expires,
+            signature: base64::engine::general_purpose::STANDARD
+                .encode(signature.as_ref()),
+        })
+    }
+
+    /// Returns the addresses, once the signature checks out and hasn't
+    /// expired, failing with [`ResolveError::InvalidSignature`] otherwise.
+    pub fn verify(
+        &self,
+        keys: &TrustedKeys,
+    ) -> Result<Vec<SocketAddr>, ResolveError> {
+        let what = format!("the addresses for {:?}", self.name);
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(&self.signature)
+            .map_err(|_| {
+                ResolveError::InvalidSignature(format!(
+                    "the signature on {} isn't base64",
+                    what
+                ))
+            })?;
+        let message =
+            SignedAddresses::message(&self.name, &self.addrs, self.expires);
+        keys.verify(&what, &message, &signature)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        if now >= self.expires {
+            debug!(
+                "signature expired",
+                name = self.name,
+                expires = self.expires
+            );
+            return Err(ResolveError::InvalidSignature(format!(
+                "the signature on {} has expired",
+                what
+            )));
+        }
+        Ok(self.addrs.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignedAddresses;
+    use super::TrustedKeys;
+    use super::ADDRESS_SET_CONTEXT;
+    use crate::error::ResolveError;
+    use base64::Engine;
+    use ring::signature::Ed25519KeyPair;
+    use ring::signature::KeyPair;
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+
+    /// Returns a new key's PKCS#8 document and public key.
+    fn key() -> (Vec<u8>, [u8; 32]) {
+        let random = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&random).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = pair.public_key().as_ref().try_into().unwrap();
+        (pkcs8.as_ref().to_vec(), public_key)
+    }
+
+    /// Returns the time `from_now` seconds from now (or ago), in seconds
+    /// since the Unix epoch.
+    fn expiry(from_now: i64) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        now.as_secs().checked_add_signed(from_now).unwrap()
+    }
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            "192.0.2.10:0".parse().unwrap(),
+            "[2001:db8::10]:0".parse().unwrap(),
+        ]
+    }
+
+    fn assert_invalid(result: Result<Vec<SocketAddr>, ResolveError>) {
+        match result {
+            Err(ResolveError::InvalidSignature(_)) => (),
+            other => panic!("expected InvalidSignature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies() {
+        let (pkcs8, public_key) = key();
+        let keys = TrustedKeys::new().with_key(public_key);
+        let signed = SignedAddresses::sign(
+            "Backend.Internal.",
+            &addrs(),
+            expiry(300),
+            &pkcs8,
+        )
+        .unwrap();
+        assert_eq!(signed.verify(&keys).unwrap(), addrs());
+
+        // The order of the addresses isn't part of what's signed.
+        let mut reordered = signed.clone();
+        reordered.addrs.reverse();
+        assert!(reordered.verify(&keys).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_addresses() {
+        let (pkcs8, public_key) = key();
+        let keys = TrustedKeys::new().with_key(public_key);
+        let signed = SignedAddresses::sign(
+            "backend.internal",
+            &addrs(),
+            expiry(300),
+            &pkcs8,
+        )
+        .unwrap();
+
+        let mut replaced = signed.clone();
+        replaced.addrs[0] = "198.51.100.66:0".parse().unwrap();
+        assert_invalid(replaced.verify(&keys));
+        let mut added = signed.clone();
+        added.addrs.push("198.51.100.66:0".parse().unwrap());
+        assert_invalid(added.verify(&keys));
+        let mut removed = signed.clone();
+        removed.addrs.pop();
+        assert_invalid(removed.verify(&keys));
+        let mut renamed = signed.clone();
+        renamed.name = "other.internal".to_owned();
+        assert_invalid(renamed.verify(&keys));
+        let mut extended = signed;
+        extended.expires += 3600;
+        assert_invalid(extended.verify(&keys));
+    }
+
+    /// A signature on the same lines under another context line (say, from
+    /// a different protocol, or a later version of this one) doesn't count.
+    #[test]
+    fn rejects_wrong_context() {
+        let (pkcs8, public_key) = key();
+        let keys = TrustedKeys::new().with_key(public_key);
+        let expires = expiry(300);
+        let message =
+            SignedAddresses::message("backend.internal", &addrs(), expires);
+        let message = String::from_utf8(message).unwrap();
+        let wrong = message.replacen(
+            ADDRESS_SET_CONTEXT,
+            "reqwest-resolve signed addresses v2",
+            1,
+        );
+        assert_ne!(message, wrong);
+        let pair = Ed25519KeyPair::from_pkcs8(&pkcs8).unwrap();
+        let signature = pair.sign(wrong.as_bytes());
+
+        let signed = SignedAddresses {
+            name: "backend.internal".to_owned(),
+            addrs: addrs(),
+            expires,
+            signature: base64::engine::general_purpose::STANDARD
+                .encode(signature.as_ref()),
+        };
+        assert_invalid(signed.verify(&keys));
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let (pkcs8, public_key) = key();
+        let keys = TrustedKeys::new().with_key(public_key);
+        for from_now in [-3600, -1, 0] {
+            let signed = SignedAddresses::sign(
+                "backend.internal",
+                &addrs(),
+                expiry(from_now),
+                &pkcs8,
+            )
+            .unwrap();
+            assert_invalid(signed.verify(&keys));
+        }
+        let signed =
+            SignedAddresses::sign("backend.internal", &addrs(), 0, &pkcs8)
+                .unwrap();
+        assert_invalid(signed.verify(&keys));
+
+        // Expiring in the future but close by is still good.
+        let signed = SignedAddresses::sign(
+            "backend.internal",
+            &addrs(),
+            expiry(5),
+            &pkcs8,
+        )
+        .unwrap();
+        assert!(signed.verify(&keys).is_ok());
+    }
+
+    #[test]
+    fn rejects_untrusted_key() {
+        let (trusted_pkcs8, trusted_key) = key();
+        let (other_pkcs8, other_key) = key();
+        let signed = SignedAddresses::sign(
+            "backend.internal",
+            &addrs(),
+            expiry(300),
+            &other_pkcs8,
+        )
+        .unwrap();
+
+        assert_invalid(signed.verify(&TrustedKeys::new()));
+        assert_invalid(
+            signed.verify(&TrustedKeys::new().with_key(trusted_key)),
+        );
+
+        // Either of several trusted keys will do, as when rotating keys.
+        let both = TrustedKeys::new().with_key(trusted_key).with_key(other_key);
+        assert!(signed.verify(&both).is_ok());
+        let signed = SignedAddresses::sign(
+            "backend.internal",
+            &addrs(),
+            expiry(300),
+            &trusted_pkcs8,
+        )
+        .unwrap();
+        assert!(signed.verify(&both).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        let (pkcs8, public_key) = key();
+        let keys = TrustedKeys::new().with_key(public_key);
+        let mut signed = SignedAddresses::sign(
+            "backend.internal",
+            &addrs(),
+            expiry(300),
+            &pkcs8,
+        )
+        .unwrap();
+        signed.signature = "not base64!".to_owned();
+        assert_invalid(signed.verify(&keys));
+        signed.signature = String::new();
+        assert_invalid(signed.verify(&keys));
+    }
+}
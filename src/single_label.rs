@@ -0,0 +1,310 @@
+//! Deciding what happens to single-label names
+//!
+//! A name like "api" or "printer" means different things depending on where
+//! a program runs.  glibc tries it under each search domain in resolv.conf
+//! and then as it is; musl does the same, but gives up at the first lookup
+//! that fails with anything other than "no such name"; and in Kubernetes,
+//! where the search list leads with the pod's namespace, it's almost always
+//! a service name that should never reach the public DNS at all.  Which of
+//! those a client gets otherwise depends on the resolver underneath and how
+//! it's configured.  A [`SingleLabelResolver`] makes it an explicit
+//! [`SingleLabelPolicy`], with a default for each [`Environment`].
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// The environment variable Kubernetes sets in every container
+const KUBERNETES_ENV_VAR: &str = "KUBERNETES_SERVICE_HOST";
+
+/// What a [`SingleLabelResolver`] does with names that have only one label
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SingleLabelPolicy {
+    /// Fail the lookup with [`ResolveError::SingleLabelName`].
+    Reject,
+    /// Try the name under each search domain, in order, until one has
+    /// addresses.
+    Search {
+        /// whether to try the name as it is after the search domains
+        then_as_is: bool,
+        /// whether to give up at the first lookup that fails with anything
+        /// other than "no such name", as musl does, rather than moving on
+        /// to the next domain
+        stop_on_error: bool,
+    },
+    /// Look the name up as it is, as a top-level name, without any search
+    /// domains.
+    AsIs,
+}
+
+/// A kind of system with its own habits for single-label names
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Environment {
+    Glibc,
+    Musl,
+    Kubernetes,
+}
+
+impl Environment {
+    /// Returns `Kubernetes` inside a Kubernetes container, and otherwise the
+    /// C library this was built for.
+    pub fn detect() -> Environment {
+        if std::env::var_os(KUBERNETES_ENV_VAR).is_some() {
+            Environment::Kubernetes
+        } else if cfg!(target_env = "musl") {
+            Environment::Musl
+        } else {
+            Environment::Glibc
+        }
+    }
+
+    /// Returns the policy that matches what the environment's own resolver
+    /// does
+    ///
+    /// In Kubernetes, a single-label name that isn't found under the
+    /// cluster's search domains isn't tried as it is.
+    pub fn default_policy(self) -> SingleLabelPolicy {
+        match self {
+            Environment::Glibc => SingleLabelPolicy::Search {
+                then_as_is: true,
+                stop_on_error: false,
+            },
+            Environment::Musl => SingleLabelPolicy::Search {
+                then_as_is: true,
+                stop_on_error: true,
+            },
+            Environment::Kubernetes => SingleLabelPolicy::Search {
+                then_as_is: false,
+                stop_on_error: false,
+            },
+        }
+    }
+}
+
+/// Returns the search domains in the contents of a resolv.conf file
+///
+/// As with glibc, the last "search" or "domain" line is the one that counts.
+pub fn search_domains(resolv_conf: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    for line in resolv_conf.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("search") | Some("domain") => {
+                domains = words
+                    .map(|domain| domain.trim_end_matches('.').to_owned())
+                    .filter(|domain| !domain.is_empty())
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    domains
+}
+
+/// Returns whether `name` has a single label.
+fn is_single_label(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    !name.is_empty() && !name.contains('.') && name.parse::<IpAddr>().is_err()
+}
+
+/// Returns whether `error` only says that the name has no addresses.
+fn is_miss(error: &(dyn StdError + 'static)) -> bool {
+    if let Some(ResolveError::NotFound { .. }) = error.downcast_ref() {
+        return true;
+    }
+    #[cfg(feature = "trust-dns")]
+    if let Some(error) =
+        error.downcast_ref::<trust_dns_resolver::error::ResolveError>()
+    {
+        return matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. });
+    }
+    false
+}
+
+/// Applies a [`SingleLabelPolicy`] to single-label names, and passes other
+/// names through unchanged
+///
+/// The names it tries are fully qualified (they end with a dot), so that
+/// the inner resolver doesn't apply search domains of its own on top.  When
+/// a search turns up nothing, the lookup fails with the error from the last
+/// name tried.
+///
+/// ```
+/// # use reqwest_resolve::single_label::{SingleLabelPolicy, SingleLabelResolver};
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "wiki.corp.example.com" => ["192.0.2.10"],
+/// };
+/// let search = vec![String::from("corp.example.com")];
+/// let policy = SingleLabelPolicy::Search {
+///     then_as_is: false,
+///     stop_on_error: false,
+/// };
+/// let my_resolver = SingleLabelResolver::new(HOSTS, policy, search);
+/// # futures::executor::block_on(async {
+/// let addrs = my_resolver.resolve_to_vec("wiki").await.unwrap();
+/// assert_eq!(addrs, ["192.0.2.10:0".parse().unwrap()]);
+///
+/// let rejecting =
+///     SingleLabelResolver::new(HOSTS, SingleLabelPolicy::Reject, Vec::new());
+/// assert!(rejecting.resolve_to_vec("wiki").await.is_err());
+/// # });
+/// ```
+pub struct SingleLabelResolver<R> {
+    inner: R,
+    policy: SingleLabelPolicy,
+    search: Vec<String>,
+}
+
+impl<R> SingleLabelResolver<R> {
+    /// Applies `policy`, with `search` as the search domains.
+    pub fn new(
+        inner: R,
+        policy: SingleLabelPolicy,
+        search: Vec<String>,
+    ) -> SingleLabelResolver<R> {
+        SingleLabelResolver { inner, policy, search }
+    }
+
+    /// Applies `environment`'s default policy, with the search domains from
+    /// /etc/resolv.conf (or none, if there isn't one).
+    pub fn for_environment(
+        inner: R,
+        environment: Environment,
+    ) -> io::Result<SingleLabelResolver<R>> {
+        let path = Path::new("/etc/resolv.conf");
+        let search = match std::fs::read_to_string(path) {
+            Ok(contents) => search_domains(&contents),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        Ok(SingleLabelResolver::new(
+            inner,
+            environment.default_policy(),
+            search,
+        ))
+    }
+
+    pub fn policy(&self) -> SingleLabelPolicy {
+        self.policy
+    }
+
+    /// Returns the names to try for `name` (a single label), in order.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        let name = name.trim_end_matches('.');
+        let mut candidates = Vec::new();
+        if let SingleLabelPolicy::Search { then_as_is, .. } = self.policy {
+            candidates.extend(
+                self.search
+                    .iter()
+                    .map(|domain| format!("{}.{}.", name, domain)),
+            );
+            if then_as_is {
+                candidates.push(format!("{}.", name));
+            }
+        } else if self.policy == SingleLabelPolicy::AsIs {
+            candidates.push(format!("{}.", name));
+        }
+        candidates
+    }
+}
+
+impl<R> SingleLabelResolver<R> {
+    /// Resolves the single-label `name` with `lookup` according to the
+    /// policy, returning the first answer that `found` accepts.
+    async fn search<T, F, Fut>(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        lookup: F,
+        found: fn(T) -> Option<T>,
+    ) -> Result<T, Box<dyn StdError + Send + Sync>>
+    where
+        F: Fn(hyper::client::connect::dns::Name) -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn StdError + Send + Sync>>>,
+    {
+        if self.policy == SingleLabelPolicy::Reject {
+            let name = name.as_str().to_owned();
+            return Err(ResolveError::SingleLabelName { name }.into());
+        }
+
+        let stop_on_error = matches!(
+            self.policy,
+            SingleLabelPolicy::Search { stop_on_error: true, .. }
+        );
+        let mut last_error = None;
+        for candidate in self.candidates(name.as_str()) {
+            let Ok(parsed) = candidate.parse() else {
+                continue;
+            };
+            debug!(
+                "trying single-label name",
+                name = name.as_str(),
+                candidate = candidate,
+            );
+            match lookup(parsed).await {
+                Ok(answer) => {
+                    if let Some(answer) = found(answer) {
+                        return Ok(answer);
+                    }
+                }
+                Err(error) => {
+                    let hard = !is_miss(&*error);
+                    last_error = Some(error);
+                    if hard && stop_on_error {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            let name = name.as_str().to_owned();
+            ResolveError::NotFound { name }.into()
+        }))
+    }
+}
+
+impl<R: MyResolve> MyResolve for SingleLabelResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        if !is_single_label(name.as_str()) {
+            return self.inner.resolve(name);
+        }
+        let found = |addrs: Addrs| {
+            let mut addrs = addrs.peekable();
+            addrs.peek().is_some().then(|| Box::new(addrs) as Addrs)
+        };
+        self.search(name, |name| self.inner.resolve(name), found).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        if !is_single_label(name.as_str()) {
+            return self.inner.resolve_detailed(name);
+        }
+        let found =
+            |addrs: Vec<ResolvedAddr>| (!addrs.is_empty()).then_some(addrs);
+        self.search(name, |name| self.inner.resolve_detailed(name), found)
+            .boxed()
+    }
+}
@@ -0,0 +1,124 @@
+//! Simulating slow DNS
+//!
+//! Request timeouts, hedged requests, and retry budgets are hard to test
+//! against a resolver that always answers in a millisecond.  [`SlowResolver`]
+//! wraps any resolver and holds each lookup back for a delay drawn from a
+//! [`DelayDistribution`], so an application's tests can see how it copes
+//! when DNS is slow all the time, slow at the tail, or slow in bursts.
+//!
+//! The delays are random, so use `deterministic::with_seed` for tests that
+//! need the same delays on every run.
+
+use crate::deterministic::random;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::time::Duration;
+
+/// How long a [`SlowResolver`] holds each lookup back
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DelayDistribution {
+    /// The same delay every time.
+    Fixed(Duration),
+    /// A Pareto distribution: never less than `scale`, usually close to it,
+    /// and occasionally much longer.  Smaller values of `shape` make the tail
+    /// heavier; 1.16 gives the classic "80/20" distribution.  This is a good
+    /// model of real DNS latency, where most answers come from a cache and a
+    /// few need a slow upstream.
+    Pareto { scale: Duration, shape: f64 },
+    /// `base` most of the time, but `spike` with the given probability (from
+    /// 0 to 1), like a resolver that stalls now and then.
+    Spikes { base: Duration, spike: Duration, probability: f64 },
+}
+
+impl DelayDistribution {
+    /// Draws one delay.
+    pub fn sample(&self) -> Duration {
+        match *self {
+            DelayDistribution::Fixed(delay) => delay,
+            DelayDistribution::Pareto { scale, shape } => {
+                // Inverse transform sampling.  `random()` is in [0, 1), so
+                // `1 - random()` is in (0, 1] and never divides by zero.
+                let uniform = 1.0 - random::<f64>();
+                let factor = uniform.powf(-1.0 / shape);
+                Duration::try_from_secs_f64(scale.as_secs_f64() * factor)
+                    .unwrap_or(Duration::MAX)
+            }
+            DelayDistribution::Spikes { base, spike, probability } => {
+                if random::<f64>() < probability {
+                    spike
+                } else {
+                    base
+                }
+            }
+        }
+    }
+}
+
+/// Delays every lookup by a random amount before passing it to the inner
+/// resolver
+///
+/// ```
+/// # use reqwest_resolve::slow::{DelayDistribution, SlowResolver};
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = SlowResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     DelayDistribution::Spikes {
+///         base: Duration::from_millis(5),
+///         spike: Duration::from_secs(3),
+///         probability: 0.05,
+///     },
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)))
+///     .timeout(Duration::from_secs(2));
+/// ```
+pub struct SlowResolver<R> {
+    inner: R,
+    delay: DelayDistribution,
+}
+
+impl<R> SlowResolver<R> {
+    pub fn new(inner: R, delay: DelayDistribution) -> SlowResolver<R> {
+        SlowResolver { inner, delay }
+    }
+}
+
+impl<R: MyResolve> MyResolve for SlowResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let delay = self.delay.sample();
+        async move {
+            tokio::time::sleep(delay).await;
+            self.inner.resolve(name).await
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let delay = self.delay.sample();
+        async move {
+            tokio::time::sleep(delay).await;
+            self.inner.resolve_detailed(name).await
+        }
+        .boxed()
+    }
+}
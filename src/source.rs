@@ -0,0 +1,230 @@
+//! Choosing where queries to upstream servers leave from
+//!
+//! On a host with more than one network interface, the OS picks the one DNS
+//! queries go out of from its routing table, which isn't necessarily the one
+//! the application's HTTP traffic is going to use.  When the interfaces lead
+//! to different networks (a VPN and the local LAN, say), the answers can be
+//! for the wrong network, or the queries might not get anywhere at all.
+//!
+//! [`with_source_addr`] and [`with_source_addrs`] set the source address of
+//! the queries to each name server, which trust-dns supports for every
+//! protocol.  Some networks need the interface itself to be chosen, not just
+//! the address (on Linux, with `SO_BINDTODEVICE`): with the `bind-device`
+//! feature, that's what [`DeviceTransport`] does.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::ResolverConfig;
+
+#[cfg(all(
+    feature = "bind-device",
+    any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+))]
+pub use device::DeviceTransport;
+
+/// Returns a copy of `config` with queries to every name server sent from
+/// `source`
+///
+/// Name servers in the other address family (IPv6 servers, when `source` is
+/// an IPv4 address) are left alone.  The source port is still chosen for
+/// each query.
+///
+/// ```
+/// # use reqwest_resolve::source::with_source_addr;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig,
+/// # };
+/// let mut config = ResolverConfig::new();
+/// config.add_name_server(NameServerConfig::new(
+///     "192.0.2.53:53".parse().unwrap(),
+///     Protocol::Udp,
+/// ));
+///
+/// let bound = with_source_addr(&config, "198.51.100.7".parse().unwrap());
+/// assert_eq!(
+///     bound.name_servers()[0].bind_addr,
+///     Some("198.51.100.7:0".parse().unwrap())
+/// );
+/// ```
+pub fn with_source_addr(
+    config: &ResolverConfig,
+    source: IpAddr,
+) -> ResolverConfig {
+    with_source_addrs(config, |_| Some(source))
+}
+
+/// Returns a copy of `config` with queries to each name server sent from
+/// whatever address `source_for` returns for it
+///
+/// Name servers that `source_for` returns `None` for, or an address in the
+/// other address family, are left alone.
+pub fn with_source_addrs<F>(
+    config: &ResolverConfig,
+    source_for: F,
+) -> ResolverConfig
+where
+    F: Fn(&NameServerConfig) -> Option<IpAddr>,
+{
+    let mut adjusted = ResolverConfig::from_parts(
+        config.domain().cloned(),
+        config.search().to_vec(),
+        Vec::new(),
+    );
+    for name_server in config.name_servers() {
+        let mut name_server = name_server.clone();
+        match source_for(&name_server) {
+            Some(source)
+                if source.is_ipv4() == name_server.socket_addr.is_ipv4() =>
+            {
+                name_server.bind_addr = Some(SocketAddr::new(source, 0));
+            }
+            _ => (),
+        }
+        adjusted.add_name_server(name_server);
+    }
+    adjusted
+}
+
+#[cfg(all(
+    feature = "bind-device",
+    any(target_os = "android", target_os = "fuchsia", target_os = "linux")
+))]
+mod device {
+    use crate::dns_transport::DnsTransport;
+    use crate::dns_transport::StandardTransport;
+    use crate::dns_transport::TransportFuture;
+    use crate::exchange;
+    use crate::exchange::local_addr;
+    use futures::future::FutureExt;
+    use std::io;
+    use std::net::SocketAddr;
+    use tokio::net::TcpSocket;
+    use tokio::net::UdpSocket;
+    use trust_dns_resolver::config::NameServerConfig;
+    use trust_dns_resolver::config::Protocol;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::error::ResolveErrorKind;
+    use trust_dns_resolver::proto::op::Message;
+
+    type DeviceFor =
+        Box<dyn Fn(&NameServerConfig) -> Option<String> + Send + Sync>;
+
+    /// Sends queries out of a particular network interface
+    ///
+    /// The interface can also be a VRF, to send queries in that routing
+    /// domain.
+    ///
+    /// This sets `SO_BINDTODEVICE` on the socket for each query, which needs
+    /// `CAP_NET_RAW` on older kernels.  Queries over UDP each get their own
+    /// socket, and queries over TCP each get their own connection.  This
+    /// transport can't bind TLS or HTTPS connections to an interface, and
+    /// queries to those servers fail (unless no interface is chosen for
+    /// them, in which case they're sent as usual).
+    ///
+    /// Any source address set in a server's `bind_addr` (see
+    /// [`with_source_addrs`](super::with_source_addrs)) is used too.
+    ///
+    /// ```no_run
+    /// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+    /// # use reqwest_resolve::source::DeviceTransport;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// let options = ResolverOpts::default();
+    /// let resolver = transport_resolver(
+    ///     ResolverConfig::default(),
+    ///     options.clone(),
+    ///     DeviceTransport::new("wg0", options),
+    /// )
+    /// .unwrap();
+    /// let _my_resolver = TransportDnsResolver::new(resolver);
+    /// ```
+    pub struct DeviceTransport {
+        device_for: DeviceFor,
+        standard: StandardTransport,
+    }
+
+    impl DeviceTransport {
+        /// Sends queries to every server out of `device` (like "eth1").
+        pub fn new(device: &str, options: ResolverOpts) -> DeviceTransport {
+            let device = device.to_owned();
+            DeviceTransport::per_server(options, move |_| Some(device.clone()))
+        }
+
+        /// Sends queries to each server out of whatever interface
+        /// `device_for` returns for it, or as usual for servers it returns
+        /// `None` for.
+        pub fn per_server<F>(
+            options: ResolverOpts,
+            device_for: F,
+        ) -> DeviceTransport
+        where
+            F: Fn(&NameServerConfig) -> Option<String> + Send + Sync + 'static,
+        {
+            DeviceTransport {
+                device_for: Box::new(device_for),
+                standard: StandardTransport::new(options),
+            }
+        }
+    }
+
+    impl DnsTransport for DeviceTransport {
+        fn send(
+            &self,
+            server: &NameServerConfig,
+            query: Message,
+        ) -> TransportFuture {
+            let Some(device) = (self.device_for)(server) else {
+                return self.standard.send(server, query);
+            };
+            let server = server.clone();
+            async move {
+                let bytes = query.to_vec()?;
+                let response = match server.protocol {
+                    Protocol::Udp => {
+                        exchange_udp(&server, &device, query.id(), &bytes)
+                            .await?
+                    }
+                    Protocol::Tcp => {
+                        exchange_tcp(&server, &device, &bytes).await?
+                    }
+                    protocol => {
+                        return Err(ResolveError::from(ResolveErrorKind::Msg(
+                            format!(
+                                "can't bind {:?} queries to interface {:?}",
+                                protocol, device
+                            ),
+                        )))
+                    }
+                };
+                Ok(Message::from_vec(&response)?)
+            }
+            .boxed()
+        }
+    }
+
+    async fn exchange_udp(
+        server: &NameServerConfig,
+        device: &str,
+        id: u16,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let socket = UdpSocket::bind(local_addr(server)).await?;
+        socket.bind_device(Some(device.as_bytes()))?;
+        exchange::exchange_udp(&socket, server.socket_addr, id, query).await
+    }
+
+    async fn exchange_tcp(
+        server: &NameServerConfig,
+        device: &str,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let socket = match server.socket_addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind_device(Some(device.as_bytes()))?;
+        socket.bind(local_addr(server))?;
+        exchange::exchange_tcp(socket, server.socket_addr, query).await
+    }
+}
@@ -0,0 +1,389 @@
+//! Keeping special-use domain names away from upstream DNS
+
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::AddrList;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+/// What to do with names under a special-use domain
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SpecialUseAction {
+    /// Fail the lookup with [`ResolveError::SpecialUse`]
+    Refuse,
+    /// Answer locally with these addresses
+    Answer(Vec<IpAddr>),
+    /// Answer locally with the loopback addresses 127.0.0.1 and ::1, which is
+    /// what RFC 6761 requires for names under "localhost"
+    Loopback(LoopbackPreference),
+    /// Resolve the name normally, as though it weren't special
+    Forward,
+}
+
+/// Which loopback addresses [`SpecialUseAction::Loopback`] answers with, and
+/// in what order
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LoopbackPreference {
+    /// 127.0.0.1, then ::1
+    #[default]
+    Ipv4First,
+    /// ::1, then 127.0.0.1
+    Ipv6First,
+    /// only 127.0.0.1
+    Ipv4Only,
+    /// only ::1
+    Ipv6Only,
+}
+
+impl LoopbackPreference {
+    /// Returns the loopback addresses to answer with, in order.
+    fn addrs(self) -> &'static [IpAddr] {
+        const V4: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        const V6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        match self {
+            LoopbackPreference::Ipv4First => &[V4, V6],
+            LoopbackPreference::Ipv6First => &[V6, V4],
+            LoopbackPreference::Ipv4Only => &[V4],
+            LoopbackPreference::Ipv6Only => &[V6],
+        }
+    }
+}
+
+/// Domains that are never supposed to be looked up in the global DNS and
+/// that [`SpecialUseResolver::new`] refuses by default
+///
+/// * "onion": Tor hidden services (RFC 7686).  Leaking these to a DNS server
+///   reveals which hidden services a client is trying to reach.
+/// * "invalid": guaranteed not to exist (RFC 6761)
+/// * "localhost": always the local host (RFC 6761), which upstream servers
+///   can't know anything about.  See [`SpecialUseResolver::with_localhost`]
+///   for answering these with loopback addresses instead.
+/// * "alt": names for non-DNS resolution systems (RFC 9476)
+pub const DEFAULT_SPECIAL_USE_DOMAINS: &[&str] =
+    &["onion", "invalid", "localhost", "alt"];
+
+/// Domains reserved for testing (RFC 2606 and RFC 6761) that
+/// [`SpecialUseResolver::with_test_mode`] answers with sandbox addresses
+pub const TEST_DOMAINS: &[&str] = &["test", "example"];
+
+/// Handles names under special-use domains locally instead of passing them
+/// to the inner resolver
+///
+/// Each configured domain covers itself and every name under it.  When
+/// domains overlap, the longest match wins, so you can (for example) refuse
+/// everything under "localhost" except for an answer configured for
+/// "api.localhost".
+///
+/// ```
+/// # use reqwest_resolve::special_use::{
+/// #     LoopbackPreference, SpecialUseAction, SpecialUseResolver,
+/// # };
+/// # use reqwest_resolve::MyCustomDnsResolver;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver =
+///     SpecialUseResolver::new(MyCustomDnsResolver::new(resolver))
+///         .with_localhost(LoopbackPreference::Ipv6First)
+///         .with_action(
+///             "api.localhost",
+///             SpecialUseAction::Answer(vec!["127.0.0.2".parse().unwrap()]),
+///         )
+///         .with_action("local", SpecialUseAction::Refuse);
+/// ```
+pub struct SpecialUseResolver<R> {
+    inner: R,
+    /// (domain, action) pairs, with domains lowercased and without a trailing
+    /// dot
+    rules: Vec<(String, SpecialUseAction)>,
+}
+
+impl<R> SpecialUseResolver<R> {
+    /// Wraps `inner`, refusing the [`DEFAULT_SPECIAL_USE_DOMAINS`].
+    pub fn new(inner: R) -> SpecialUseResolver<R> {
+        let resolver = SpecialUseResolver { inner, rules: Vec::new() };
+        DEFAULT_SPECIAL_USE_DOMAINS.iter().fold(resolver, |resolver, domain| {
+            resolver.with_action(domain, SpecialUseAction::Refuse)
+        })
+    }
+
+    /// Sets the action for names under `domain`, replacing any action
+    /// configured for exactly that domain before.
+    pub fn with_action(
+        mut self,
+        domain: &str,
+        action: SpecialUseAction,
+    ) -> SpecialUseResolver<R> {
+        let domain = normalize(domain).into_owned();
+        self.rules.retain(|(d, _)| *d != domain);
+        self.rules.push((domain, action));
+        self
+    }
+
+    /// Answers "localhost" and every name under it with loopback addresses,
+    /// without asking the inner resolver.
+    ///
+    /// RFC 6761 requires this, but forwarding setups often send these names
+    /// upstream, where they may fail or (worse) resolve to something else.
+    /// More specific rules, like an answer for "api.localhost", still take
+    /// precedence.
+    pub fn with_localhost(
+        self,
+        preference: LoopbackPreference,
+    ) -> SpecialUseResolver<R> {
+        self.with_action("localhost", SpecialUseAction::Loopback(preference))
+    }
+
+    /// Answers every name under the [`TEST_DOMAINS`] with `addrs`, without
+    /// asking the inner resolver.
+    ///
+    /// This is for integration environments, where tests can then use names
+    /// like "api.test" for a sandbox service without any DNS infrastructure
+    /// for them.  Rules for more specific domains, like "db.test", still take
+    /// precedence.  Names under "invalid" are left to whatever rule covers
+    /// them, which unless it's been changed is the refusal
+    /// [`SpecialUseResolver::new`] sets up.
+    pub fn with_test_mode(self, addrs: Vec<IpAddr>) -> SpecialUseResolver<R> {
+        TEST_DOMAINS.iter().fold(self, |resolver, domain| {
+            resolver
+                .with_action(domain, SpecialUseAction::Answer(addrs.clone()))
+        })
+    }
+
+    /// Returns the most specific rule covering `name`, if any.
+    fn rule_for(&self, name: &str) -> Option<&(String, SpecialUseAction)> {
+        let name = normalize(name);
+        self.rules
+            .iter()
+            .filter(|(domain, _)| in_domain(&name, domain))
+            .max_by_key(|(domain, _)| domain.len())
+    }
+}
+
+/// Lowercases `name` and removes any trailing dot, which most names already
+/// have done, so those are borrowed rather than copied.
+pub(crate) fn normalize(name: &str) -> Cow<'_, str> {
+    let name = name.trim_end_matches('.');
+    if name.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Returns whether `name` is `domain` or a name under it.  Both must already
+/// be normalized.
+pub(crate) fn in_domain(name: &str, domain: &str) -> bool {
+    name == domain
+        || (name.ends_with(domain)
+            && name[..name.len() - domain.len()].ends_with('.'))
+}
+
+impl<R> SpecialUseResolver<R> {
+    /// Returns the local answer for `name`, or `None` if it's to be resolved
+    /// by the inner resolver.
+    fn local_answer(
+        &self,
+        name: &str,
+    ) -> Option<Result<AddrList, ResolveError>> {
+        let ips = match self.rule_for(name) {
+            None | Some((_, SpecialUseAction::Forward)) => return None,
+            Some((domain, SpecialUseAction::Refuse)) => {
+                debug!(
+                    "refusing special-use name",
+                    name = name,
+                    domain = domain
+                );
+                return Some(Err(ResolveError::SpecialUse {
+                    name: name.to_owned(),
+                    domain: domain.clone(),
+                }));
+            }
+            Some((domain, SpecialUseAction::Answer(ips))) => {
+                debug!(
+                    "answering special-use name locally",
+                    name = name,
+                    domain = domain,
+                );
+                ips.as_slice()
+            }
+            Some((domain, SpecialUseAction::Loopback(preference))) => {
+                debug!(
+                    "answering special-use name with loopback addresses",
+                    name = name,
+                    domain = domain,
+                );
+                preference.addrs()
+            }
+        };
+        Some(Ok(ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect()))
+    }
+}
+
+impl<R: MyResolve> MyResolve for SpecialUseResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.local_answer(name.as_str()) {
+            None => self.inner.resolve(name),
+            Some(result) => MyResolving::ready(
+                result
+                    .map(|addrs| Box::new(addrs.into_iter()) as Addrs)
+                    .map_err(Into::into),
+            ),
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.local_answer(name.as_str()) {
+            None => self.inner.resolve_detailed(name),
+            Some(result) => futures::future::ready(
+                result
+                    .map(|addrs| {
+                        addrs
+                            .into_iter()
+                            .map(|addr| {
+                                ResolvedAddr::from_table(addr, "special_use")
+                            })
+                            .collect()
+                    })
+                    .map_err(Into::into),
+            )
+            .boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopbackPreference;
+    use super::SpecialUseAction;
+    use super::SpecialUseResolver;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "example.com" => ["192.0.2.1"],
+    };
+
+    #[tokio::test]
+    async fn resolve_detailed() {
+        let resolver = SpecialUseResolver::new(HOSTS)
+            .with_localhost(LoopbackPreference::Ipv4First);
+
+        let forwarded = resolver
+            .resolve_detailed("example.com".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].backend, "static");
+
+        let local = resolver
+            .resolve_detailed("svc.localhost".parse().unwrap())
+            .await
+            .unwrap();
+        let addrs: Vec<_> = local.iter().map(|addr| addr.addr).collect();
+        assert_eq!(
+            addrs,
+            ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()]
+        );
+        assert!(local.iter().all(|addr| addr.backend == "special_use"));
+
+        let refused =
+            resolver.resolve_detailed("router.invalid".parse().unwrap()).await;
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn localhost_preferences() {
+        let v4 = "127.0.0.1:0".parse().unwrap();
+        let v6 = "[::1]:0".parse().unwrap();
+        let cases = [
+            (LoopbackPreference::Ipv4First, vec![v4, v6]),
+            (LoopbackPreference::Ipv6First, vec![v6, v4]),
+            (LoopbackPreference::Ipv4Only, vec![v4]),
+            (LoopbackPreference::Ipv6Only, vec![v6]),
+        ];
+        for (preference, expected) in cases {
+            let resolver =
+                SpecialUseResolver::new(HOSTS).with_localhost(preference);
+            for name in ["localhost", "svc.localhost", "LOCALHOST."] {
+                let addrs = resolver.resolve_to_vec(name).await.unwrap();
+                assert_eq!(addrs, expected, "{:?} {}", preference, name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn localhost_more_specific_rule() {
+        let resolver = SpecialUseResolver::new(HOSTS)
+            .with_action(
+                "api.localhost",
+                SpecialUseAction::Answer(vec!["127.0.0.2".parse().unwrap()]),
+            )
+            .with_localhost(LoopbackPreference::Ipv6Only);
+
+        let addrs = resolver.resolve_to_vec("api.localhost").await.unwrap();
+        assert_eq!(addrs, ["127.0.0.2:0".parse().unwrap()]);
+        let addrs = resolver.resolve_to_vec("v1.api.localhost").await.unwrap();
+        assert_eq!(addrs, ["127.0.0.2:0".parse().unwrap()]);
+        let addrs = resolver.resolve_to_vec("web.localhost").await.unwrap();
+        assert_eq!(addrs, ["[::1]:0".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_mode() {
+        let sandbox = vec!["192.0.2.100".parse().unwrap()];
+        let resolver = SpecialUseResolver::new(HOSTS)
+            .with_action(
+                "db.test",
+                SpecialUseAction::Answer(vec!["192.0.2.200".parse().unwrap()]),
+            )
+            .with_test_mode(sandbox);
+
+        for name in ["api.test", "x.example", "test"] {
+            let addrs = resolver.resolve_to_vec(name).await.unwrap();
+            assert_eq!(addrs, ["192.0.2.100:0".parse().unwrap()], "{}", name);
+        }
+        let addrs = resolver.resolve_to_vec("db.test").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.200:0".parse().unwrap()]);
+        let addrs = resolver.resolve_to_vec("example.com").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.1:0".parse().unwrap()]);
+        assert!(resolver.resolve_to_vec("router.invalid").await.is_err());
+    }
+
+    /// Test mode leaves an action set for "invalid" alone.
+    #[tokio::test]
+    async fn test_mode_keeps_invalid_rule() {
+        let resolver = SpecialUseResolver::new(HOSTS)
+            .with_action(
+                "invalid",
+                SpecialUseAction::Answer(vec!["192.0.2.50".parse().unwrap()]),
+            )
+            .with_test_mode(vec!["192.0.2.100".parse().unwrap()]);
+        let addrs = resolver.resolve_to_vec("router.invalid").await.unwrap();
+        assert_eq!(addrs, ["192.0.2.50:0".parse().unwrap()]);
+    }
+}
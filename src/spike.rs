@@ -0,0 +1,334 @@
+//! Noticing when lookups under a domain start failing far more than usual
+//!
+//! Some NXDOMAINs and timeouts are normal: names that are probed for and
+//! don't exist, a search domain tried before the right one, an upstream
+//! that's slow now and then.  An upstream outage or a search domain that's
+//! been misconfigured looks different: the rate of one or the other jumps
+//! for the names under a domain, usually well before the connection errors
+//! it causes get noticed.  [`SpikeResolver`] keeps those rates for each
+//! domain, compares them with what's been usual for it, and calls back into
+//! the application when one jumps.
+
+use crate::anomaly::is_nxdomain;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// Above this many domains, lookups under domains not seen before aren't
+/// tracked
+const MAX_TRACKED_SUFFIXES: usize = 1_000;
+
+/// How much each interval's rates move a domain's baseline toward them
+const BASELINE_WEIGHT: f64 = 0.2;
+
+/// Which kind of failure a [`FailureSpike`] is for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpikeKind {
+    Nxdomain,
+    Timeout,
+}
+
+/// What a [`SpikeResolver`] passes to its callback
+#[derive(Clone, Debug, PartialEq)]
+pub struct FailureSpike {
+    /// the domain the names were under, lowercased and without a trailing
+    /// dot
+    pub suffix: String,
+    pub kind: SpikeKind,
+    /// how many lookups under the domain the interval had
+    pub lookups: u64,
+    /// the fraction of them that failed this way
+    pub rate: f64,
+    /// the fraction that usually does
+    pub baseline: f64,
+}
+
+/// When a [`SpikeResolver`] reports a spike
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SpikeThresholds {
+    /// how long each interval that rates are counted over lasts
+    pub interval: Duration,
+    /// the fewest lookups under a domain an interval needs for its rates to
+    /// count at all
+    pub min_lookups: u64,
+    /// how many times the baseline a rate has to be to be a spike
+    pub ratio: f64,
+    /// how much higher than the baseline (as a fraction of lookups) a rate
+    /// also has to be, so that going from 0.1% to 0.5% isn't one
+    pub min_increase: f64,
+}
+
+impl Default for SpikeThresholds {
+    /// Thirty-second intervals of at least 20 lookups, and spikes of at
+    /// least three times the baseline and ten more failures in every 100
+    /// lookups
+    fn default() -> SpikeThresholds {
+        SpikeThresholds {
+            interval: Duration::from_secs(30),
+            min_lookups: 20,
+            ratio: 3.0,
+            min_increase: 0.1,
+        }
+    }
+}
+
+/// The counts for one domain
+struct SuffixRates {
+    /// when the current interval started
+    started: Instant,
+    lookups: u64,
+    nxdomain: u64,
+    timeouts: u64,
+    /// the usual NXDOMAIN and timeout rates, once there's been an interval
+    /// to learn them from
+    baseline: Option<[f64; 2]>,
+}
+
+impl SuffixRates {
+    /// Ends the current interval, returning the spikes it had.
+    fn close(
+        &mut self,
+        suffix: &str,
+        thresholds: &SpikeThresholds,
+    ) -> Vec<FailureSpike> {
+        let lookups = self.lookups;
+        let counts = [self.nxdomain, self.timeouts];
+        self.lookups = 0;
+        self.nxdomain = 0;
+        self.timeouts = 0;
+        if lookups == 0 || lookups < thresholds.min_lookups {
+            return Vec::new();
+        }
+
+        let rates = counts.map(|count| count as f64 / lookups as f64);
+        let Some(baseline) = &mut self.baseline else {
+            self.baseline = Some(rates);
+            return Vec::new();
+        };
+        let mut spikes = Vec::new();
+        for ((kind, rate), usual) in [SpikeKind::Nxdomain, SpikeKind::Timeout]
+            .into_iter()
+            .zip(rates)
+            .zip(baseline.iter_mut())
+        {
+            if rate >= *usual * thresholds.ratio
+                && rate - *usual >= thresholds.min_increase
+            {
+                spikes.push(FailureSpike {
+                    suffix: suffix.to_owned(),
+                    kind,
+                    lookups,
+                    rate,
+                    baseline: *usual,
+                });
+            }
+            *usual += BASELINE_WEIGHT * (rate - *usual);
+        }
+        spikes
+    }
+}
+
+type Callback = Box<dyn Fn(&FailureSpike) + Send + Sync>;
+
+/// Returns which kind of failure `error` is, if it's one that's tracked.
+fn failure_kind(error: &(dyn StdError + 'static)) -> Option<SpikeKind> {
+    if is_nxdomain(error) {
+        return Some(SpikeKind::Nxdomain);
+    }
+    if let Some(ResolveError::DeadlineExceeded { .. }) = error.downcast_ref() {
+        return Some(SpikeKind::Timeout);
+    }
+    #[cfg(feature = "trust-dns")]
+    if let Some(error) =
+        error.downcast_ref::<trust_dns_resolver::error::ResolveError>()
+    {
+        if matches!(error.kind(), ResolveErrorKind::Timeout) {
+            return Some(SpikeKind::Timeout);
+        }
+    }
+    None
+}
+
+/// Returns the domain made of the last `labels` labels of `name` (or all
+/// of them, if it has fewer).
+fn suffix_of(name: &str, labels: usize) -> &str {
+    match name.rmatch_indices('.').nth(labels - 1) {
+        Some((dot, _)) => &name[dot + 1..],
+        None => name,
+    }
+}
+
+/// Calls a callback when the NXDOMAIN or timeout rate for the names under a
+/// domain jumps well above its baseline
+///
+/// Lookups are grouped by their last two labels (see
+/// [`SpikeResolver::with_suffix_labels`]), and counted over intervals of
+/// `thresholds.interval`.  The first interval with enough lookups sets a
+/// domain's baseline, and each one after that is compared with it and then
+/// moves it a fifth of the way to its own rates.  So a domain that's failed
+/// from the start isn't reported, and one that keeps failing after a spike
+/// stops being reported after a few intervals, as the failures become what's
+/// usual for it.
+///
+/// Intervals end with the first lookup under the domain after they've run
+/// out, so the callback is called on the task doing that lookup, and should
+/// be quick; hand anything slow off to another task.  Only the first 1000
+/// domains seen are tracked.
+///
+/// ```
+/// # use reqwest_resolve::spike::{SpikeResolver, SpikeThresholds};
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = SpikeResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     SpikeThresholds::default(),
+///     |spike| {
+///         eprintln!(
+///             "{:?} rate under {} is {:.0}% (usually {:.0}%)",
+///             spike.kind,
+///             spike.suffix,
+///             spike.rate * 100.0,
+///             spike.baseline * 100.0,
+///         );
+///     },
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct SpikeResolver<R> {
+    inner: R,
+    thresholds: SpikeThresholds,
+    suffix_labels: usize,
+    callback: Callback,
+    suffixes: Mutex<BTreeMap<String, SuffixRates>>,
+}
+
+impl<R> SpikeResolver<R> {
+    pub fn new<F>(
+        inner: R,
+        thresholds: SpikeThresholds,
+        callback: F,
+    ) -> SpikeResolver<R>
+    where
+        F: Fn(&FailureSpike) + Send + Sync + 'static,
+    {
+        SpikeResolver {
+            inner,
+            thresholds,
+            suffix_labels: 2,
+            callback: Box::new(callback),
+            suffixes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Groups lookups by their last `labels` labels (at least 1), as for
+    /// domains under a public suffix like "co.uk", which need three.
+    pub fn with_suffix_labels(mut self, labels: usize) -> SpikeResolver<R> {
+        self.suffix_labels = labels.max(1);
+        self
+    }
+
+    /// Counts a lookup of `name`, returning the spikes to report if it ends
+    /// an interval.
+    fn record(&self, name: &str, kind: Option<SpikeKind>) -> Vec<FailureSpike> {
+        let now = Instant::now();
+        let name = normalize(name);
+        let suffix = suffix_of(&name, self.suffix_labels);
+        let mut suffixes = self.suffixes.lock().unwrap();
+        if suffixes.len() >= MAX_TRACKED_SUFFIXES
+            && !suffixes.contains_key(suffix)
+        {
+            return Vec::new();
+        }
+
+        let rates =
+            suffixes.entry(suffix.to_owned()).or_insert_with(|| SuffixRates {
+                started: now,
+                lookups: 0,
+                nxdomain: 0,
+                timeouts: 0,
+                baseline: None,
+            });
+        let mut spikes = Vec::new();
+        if now - rates.started >= self.thresholds.interval {
+            spikes = rates.close(suffix, &self.thresholds);
+            rates.started = now;
+        }
+        rates.lookups += 1;
+        match kind {
+            Some(SpikeKind::Nxdomain) => rates.nxdomain += 1,
+            Some(SpikeKind::Timeout) => rates.timeouts += 1,
+            None => {}
+        }
+        spikes
+    }
+
+    /// Counts a lookup of `name` that ended with `result`, reporting any
+    /// spikes.
+    fn observe<T>(
+        &self,
+        name: &str,
+        result: &Result<T, Box<dyn StdError + Send + Sync>>,
+    ) {
+        let kind = match result {
+            Ok(_) => None,
+            Err(error) => failure_kind(&**error),
+        };
+        for spike in self.record(name, kind) {
+            debug!(
+                "lookup failure rate spiked",
+                suffix = spike.suffix,
+                kind = spike.kind,
+                rate = spike.rate,
+                baseline = spike.baseline,
+            );
+            (self.callback)(&spike);
+        }
+    }
+}
+
+impl<R: MyResolve> MyResolve for SpikeResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let result = self.inner.resolve(name.clone()).await;
+            self.observe(name.as_str(), &result);
+            result
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let result = self.inner.resolve_detailed(name.clone()).await;
+            self.observe(name.as_str(), &result);
+            result
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,308 @@
+//! Split DNS for VPNs
+//!
+//! A VPN client typically comes with its own DNS server that's only reachable
+//! (and only knows about internal names) while the tunnel is up.  Names under
+//! the VPN's domains should go to that server, and everything else should go
+//! wherever it normally would, so that ordinary lookups neither depend on the
+//! tunnel nor leak into it.
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::logging::debug;
+use crate::routing::CompiledRules;
+use crate::routing::RoutingRules;
+use crate::tasks::TaskSet;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Describes one tunnel's DNS: the domains it's responsible for and the
+/// servers that answer for them
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SplitDnsConfig {
+    /// identifies the tunnel, so that it can be taken down later (e.g.,
+    /// "corp-vpn" or the name of the tunnel interface)
+    pub name: String,
+    /// names under any of these domains are sent to `servers`.  These can
+    /// also be patterns like "*.corp.example.com" (see [`crate::routing`]),
+    /// which match only what they say.
+    pub domains: Vec<String>,
+    /// the tunnel's DNS servers, tried over UDP and then TCP
+    pub servers: Vec<SocketAddr>,
+}
+
+impl SplitDnsConfig {
+    pub fn new(
+        name: &str,
+        domains: &[&str],
+        servers: Vec<SocketAddr>,
+    ) -> SplitDnsConfig {
+        SplitDnsConfig {
+            name: name.to_owned(),
+            domains: domains.iter().map(|d| (*d).to_owned()).collect(),
+            servers,
+        }
+    }
+
+    /// Builds a resolver that sends queries only to this tunnel's servers.
+    pub fn resolver(
+        &self,
+        options: ResolverOpts,
+    ) -> Result<TokioAsyncResolver, ResolveError> {
+        let mut config = ResolverConfig::new();
+        for server in &self.servers {
+            config
+                .add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+            config
+                .add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+        }
+        TokioAsyncResolver::tokio(config, options)
+    }
+}
+
+/// Tells a [`SplitDnsResolver`] that a tunnel came up or went down
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TunnelEvent {
+    Up(SplitDnsConfig),
+    /// the tunnel with this name went down
+    Down(String),
+}
+
+#[derive(Clone)]
+struct Tunnel {
+    name: String,
+    domains: Vec<String>,
+    resolver: Arc<TokioAsyncResolver>,
+}
+
+#[derive(Default)]
+struct Tunnels {
+    tunnels: Vec<Tunnel>,
+    /// compiled from all of the tunnels' domains
+    rules: CompiledRules<Arc<TokioAsyncResolver>>,
+}
+
+impl Tunnels {
+    /// Recompiles `rules` from the tunnels that are up.
+    fn compile(
+        tunnels: Vec<Tunnel>,
+    ) -> Result<Tunnels, crate::error::ResolveError> {
+        let mut rules = RoutingRules::new();
+        for tunnel in &tunnels {
+            for domain in &tunnel.domains {
+                if domain.contains('*') {
+                    rules = rules.rule(domain, Arc::clone(&tunnel.resolver));
+                } else {
+                    rules =
+                        rules.rule(domain, Arc::clone(&tunnel.resolver)).rule(
+                            &format!("*.{}", domain),
+                            Arc::clone(&tunnel.resolver),
+                        );
+                }
+            }
+        }
+        Ok(Tunnels { rules: rules.compile()?, tunnels })
+    }
+}
+
+/// Sends names under the domains of any tunnel that's up to that tunnel's
+/// servers, and everything else to the inner resolver
+///
+/// When tunnels' domains overlap, the most specific one wins.  Tunnels
+/// are brought up and down through a [`SplitDnsHandle`], either directly or
+/// by feeding it a stream of [`TunnelEvent`]s from whatever is managing the
+/// VPN.  Lookups already in progress when a tunnel goes down are allowed to
+/// finish.
+///
+/// ```
+/// # use reqwest_resolve::split_dns::{SplitDnsConfig, SplitDnsResolver};
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = SplitDnsResolver::new(MyCustomDnsResolver::new(resolver));
+/// let handle = my_resolver.handle();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+///
+/// // later, once the tunnel is up:
+/// let vpn = SplitDnsConfig::new(
+///     "corp-vpn",
+///     &["corp.example.com", "10.in-addr.arpa"],
+///     vec!["10.8.0.1:53".parse().unwrap()],
+/// );
+/// handle.tunnel_up(&vpn, ResolverOpts::default()).unwrap();
+/// assert_eq!(handle.active(), vec!["corp-vpn".to_owned()]);
+///
+/// // and when it goes down again:
+/// assert!(handle.tunnel_down("corp-vpn"));
+/// ```
+pub struct SplitDnsResolver<R> {
+    inner: R,
+    handle: SplitDnsHandle,
+    /// watchers started with `spawn_watch`
+    tasks: TaskSet,
+}
+
+impl<R> SplitDnsResolver<R> {
+    /// Wraps `inner` with no tunnels up.
+    pub fn new(inner: R) -> SplitDnsResolver<R> {
+        SplitDnsResolver {
+            inner,
+            handle: SplitDnsHandle::default(),
+            tasks: TaskSet::default(),
+        }
+    }
+
+    /// Returns a handle for bringing tunnels up and down.
+    pub fn handle(&self) -> SplitDnsHandle {
+        self.handle.clone()
+    }
+
+    /// Spawns a task that runs [`SplitDnsHandle::watch`]
+    ///
+    /// The task is called "split-dns watcher" (see [`crate::tasks`]), and
+    /// ends when `events` does or when this resolver is dropped, whichever
+    /// comes first.  To run the watcher some other way, await
+    /// [`SplitDnsHandle::watch`] instead.
+    pub fn spawn_watch<S>(&self, events: S, options: ResolverOpts)
+    where
+        S: Stream<Item = TunnelEvent> + Send + 'static,
+    {
+        self.tasks
+            .spawn("split-dns watcher", self.handle().watch(events, options));
+    }
+}
+
+impl<R: MyResolve> MyResolve for SplitDnsResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        match self.handle.resolver_for(name.as_str()) {
+            None => self.inner.resolve(name),
+            Some(resolver) => {
+                debug!("sending name to tunnel", name = name.as_str());
+                async move { do_resolve(&resolver, name).await }.boxed().into()
+            }
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        match self.handle.resolver_for(name.as_str()) {
+            None => self.inner.resolve_detailed(name),
+            Some(resolver) => {
+                debug!("sending name to tunnel", name = name.as_str());
+                async move { do_resolve_detailed(&resolver, name).await }
+                    .boxed()
+            }
+        }
+    }
+}
+
+/// Brings tunnels up and down for a [`SplitDnsResolver`]
+#[derive(Clone, Default)]
+pub struct SplitDnsHandle {
+    tunnels: Arc<RwLock<Tunnels>>,
+}
+
+impl SplitDnsHandle {
+    /// Starts sending names under `config`'s domains to its servers,
+    /// replacing any tunnel that was already up with the same name.
+    pub fn tunnel_up(
+        &self,
+        config: &SplitDnsConfig,
+        options: ResolverOpts,
+    ) -> Result<(), ResolveError> {
+        let tunnel = Tunnel {
+            name: config.name.clone(),
+            domains: config.domains.clone(),
+            resolver: Arc::new(config.resolver(options)?),
+        };
+        let mut tunnels = self.tunnels.write().unwrap();
+        let mut list = tunnels.tunnels.clone();
+        list.retain(|t| t.name != tunnel.name);
+        list.push(tunnel);
+        *tunnels = Tunnels::compile(list)
+            .map_err(|error| ResolveError::from(error.to_string()))?;
+        debug!("split-DNS tunnel up", tunnel = config.name);
+        Ok(())
+    }
+
+    /// Stops using the tunnel called `name`.  Returns whether it was up.
+    pub fn tunnel_down(&self, name: &str) -> bool {
+        let mut tunnels = self.tunnels.write().unwrap();
+        let mut list = tunnels.tunnels.clone();
+        list.retain(|t| t.name != name);
+        if list.len() == tunnels.tunnels.len() {
+            return false;
+        }
+        // These tunnels' domains all compiled before, so they still will.
+        *tunnels = Tunnels::compile(list).unwrap();
+        debug!("split-DNS tunnel down", tunnel = name);
+        true
+    }
+
+    /// Returns the names of the tunnels that are up.
+    pub fn active(&self) -> Vec<String> {
+        let tunnels = self.tunnels.read().unwrap();
+        tunnels.tunnels.iter().map(|t| t.name.clone()).collect()
+    }
+
+    /// Applies `events` as they arrive, until the stream ends
+    ///
+    /// This is the hook for reconfiguring automatically: have whatever
+    /// watches the VPN (its client's status API, network interface
+    /// notifications, etc.) send [`TunnelEvent`]s into a channel, and run
+    /// this on the receiving end (or have the resolver do it with
+    /// [`SplitDnsResolver::spawn_watch`]).  A tunnel whose resolver can't be
+    /// built is left down.
+    pub async fn watch<S>(self, events: S, options: ResolverOpts)
+    where
+        S: Stream<Item = TunnelEvent>,
+    {
+        futures::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                TunnelEvent::Up(config) => {
+                    if self.tunnel_up(&config, options).is_err() {
+                        self.tunnel_down(&config.name);
+                    }
+                }
+                TunnelEvent::Down(name) => {
+                    self.tunnel_down(&name);
+                }
+            }
+        }
+    }
+
+    /// Returns the resolver for the tunnel with the most specific domain
+    /// covering `name`, if any.
+    fn resolver_for(&self, name: &str) -> Option<Arc<TokioAsyncResolver>> {
+        let tunnels = self.tunnels.read().unwrap();
+        tunnels.rules.lookup(name).map(Arc::clone)
+    }
+}
@@ -0,0 +1,240 @@
+//! Assembling a resolver stack one layer at a time
+//!
+//! Every layer in this crate wraps the resolver inside it, so a stack is
+//! written inside out, as nested constructor calls:
+//! `AuditResolver::new(BogonFilter::new(SpecialUseResolver::new(base)))`.
+//! The more layers there are, the harder that is to read, and to change: the
+//! backend comes last, and each layer's configuration ends up far from its
+//! name.  A [`ResolverStackBuilder`] lists the layers from the backend
+//! outward instead, and ends in whatever the stack is being built for: a
+//! reqwest `Resolve`, a [`ResolverHandle`], or the stack itself.
+//!
+//! The builder wraps one concrete type around another, just as the nested
+//! calls would, so the stack it builds is the same type and costs nothing
+//! extra.
+
+use crate::audit::AuditResolver;
+use crate::bogons::BogonFilter;
+use crate::cache::CacheBackend;
+use crate::cache::CacheConfig;
+use crate::cache::CachingResolver;
+use crate::dedup::DedupResolver;
+use crate::error::ResolveError;
+use crate::handle::ResolverHandle;
+use crate::loops::LoopGuardResolver;
+use crate::normalize::Canonicalize;
+use crate::normalize::NormalizingResolver;
+use crate::single_label::SingleLabelPolicy;
+use crate::single_label::SingleLabelResolver;
+use crate::special_use::SpecialUseResolver;
+use crate::system::SystemBypass;
+use crate::MyResolve;
+use crate::ResolveAdapter;
+use std::sync::Arc;
+
+/// Builds a resolver stack from the backend outward
+///
+/// Each method wraps the stack built so far in one more layer, so the last
+/// layer added is the outermost one, which sees each lookup first.
+/// [`ResolverStackBuilder::layer`] adds any layer, including ones defined
+/// outside this crate (and [`ResolverStackBuilder::try_layer`] any whose
+/// constructor can fail); the other methods are shorthand for the common
+/// ones.
+///
+/// ```
+/// # use reqwest_resolve::stack::ResolverStackBuilder;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// # use reqwest_resolve::scope::ScopedResolver;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// let stack = ResolverStackBuilder::new(HOSTS)
+///     .layer(|inner| ScopedResolver::new(inner, 2))
+///     .bogon_filter()
+///     .special_use()
+///     .normalized()
+///     .system_bypass(".corp.example.com")
+///     .unwrap()
+///     .audit();
+/// let log = stack.get_ref().log();
+/// let _client =
+///     reqwest::ClientBuilder::new().dns_resolver(stack.into_resolve());
+/// assert!(log.entries().is_empty());
+/// ```
+pub struct ResolverStackBuilder<R> {
+    resolver: R,
+}
+
+impl<R> ResolverStackBuilder<R> {
+    /// Starts a stack with `base` as its backend.
+    pub fn new(base: R) -> ResolverStackBuilder<R> {
+        ResolverStackBuilder { resolver: base }
+    }
+
+    /// Wraps the stack in the layer `wrap` returns.
+    pub fn layer<L, F>(self, wrap: F) -> ResolverStackBuilder<L>
+    where
+        F: FnOnce(R) -> L,
+    {
+        ResolverStackBuilder::new(wrap(self.resolver))
+    }
+
+    /// Wraps the stack in the layer `wrap` returns, for layers whose
+    /// constructors check their configuration and can fail.
+    pub fn try_layer<L, E, F>(
+        self,
+        wrap: F,
+    ) -> Result<ResolverStackBuilder<L>, E>
+    where
+        F: FnOnce(R) -> Result<L, E>,
+    {
+        wrap(self.resolver).map(ResolverStackBuilder::new)
+    }
+
+    /// Returns the stack.
+    pub fn build(self) -> R {
+        self.resolver
+    }
+
+    /// Returns a reference to the stack built so far.
+    pub fn get_ref(&self) -> &R {
+        &self.resolver
+    }
+
+    /// Adds a [`LoopGuardResolver`].
+    pub fn loop_guard(self) -> ResolverStackBuilder<LoopGuardResolver<R>> {
+        self.layer(LoopGuardResolver::new)
+    }
+
+    /// Adds a [`NormalizingResolver`] with the usual [`Canonicalize`]
+    /// normalization.
+    pub fn normalized(
+        self,
+    ) -> ResolverStackBuilder<NormalizingResolver<R, Canonicalize>> {
+        self.layer(|inner| NormalizingResolver::new(inner, Canonicalize::new()))
+    }
+
+    /// Adds a [`SpecialUseResolver`] with the default special-use domains.
+    pub fn special_use(self) -> ResolverStackBuilder<SpecialUseResolver<R>> {
+        self.layer(SpecialUseResolver::new)
+    }
+
+    /// Adds a [`SingleLabelResolver`] applying `policy`, with `search` as
+    /// the search domains.
+    pub fn single_label(
+        self,
+        policy: SingleLabelPolicy,
+        search: Vec<String>,
+    ) -> ResolverStackBuilder<SingleLabelResolver<R>> {
+        self.layer(|inner| SingleLabelResolver::new(inner, policy, search))
+    }
+
+    /// Adds a [`SystemBypass`] for the names matching `list` (in the syntax
+    /// of `NO_PROXY`).
+    pub fn system_bypass(
+        self,
+        list: &str,
+    ) -> Result<ResolverStackBuilder<SystemBypass<R>>, ResolveError> {
+        self.try_layer(|inner| SystemBypass::no_proxy(inner, list))
+    }
+
+    /// Adds a [`BogonFilter`].
+    pub fn bogon_filter(self) -> ResolverStackBuilder<BogonFilter<R>> {
+        self.layer(BogonFilter::new)
+    }
+
+    /// Adds an `answer_limit::AnswerLimit`.
+    #[cfg(feature = "answer-limit")]
+    pub fn answer_limit(
+        self,
+        max: usize,
+        selection: crate::answer_limit::Selection,
+    ) -> ResolverStackBuilder<crate::answer_limit::AnswerLimit<R>> {
+        self.layer(|inner| {
+            crate::answer_limit::AnswerLimit::new(inner, max, selection)
+        })
+    }
+
+    /// Adds a `deadline::DeadlineResolver`.
+    #[cfg(feature = "deadline")]
+    pub fn deadline(
+        self,
+    ) -> ResolverStackBuilder<crate::deadline::DeadlineResolver<R>> {
+        self.layer(crate::deadline::DeadlineResolver::new)
+    }
+
+    /// Adds an [`AuditResolver`].
+    pub fn audit(self) -> ResolverStackBuilder<AuditResolver<R>> {
+        self.layer(AuditResolver::new)
+    }
+
+    /// Adds an `otel::OtelResolver`.
+    #[cfg(feature = "opentelemetry")]
+    pub fn otel(self) -> ResolverStackBuilder<crate::otel::OtelResolver<R>> {
+        self.layer(crate::otel::OtelResolver::new)
+    }
+}
+
+impl ResolverStackBuilder<CachingResolver> {
+    /// Starts a stack with a [`CachingResolver`] around `resolver` as its
+    /// backend.
+    pub fn caching(
+        resolver: impl CacheBackend,
+    ) -> ResolverStackBuilder<CachingResolver> {
+        ResolverStackBuilder::new(CachingResolver::new(resolver))
+    }
+
+    /// Starts a stack with a [`CachingResolver`] around `resolver`,
+    /// configured by `config`, as its backend
+    ///
+    /// ```
+    /// # use reqwest_resolve::cache::CacheConfig;
+    /// # use reqwest_resolve::stack::ResolverStackBuilder;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "api.example.com" => ["192.0.2.10"],
+    /// };
+    /// let config = CacheConfig { max_entries: 100, ..CacheConfig::default() };
+    /// // Lookups that miss the cache at the same time share one lookup.
+    /// let stack = ResolverStackBuilder::new(HOSTS)
+    ///     .dedup()
+    ///     .layer(|inner| {
+    ///         ResolverStackBuilder::caching_with_config(inner, &config).build()
+    ///     })
+    ///     .special_use();
+    /// let _client =
+    ///     reqwest::ClientBuilder::new().dns_resolver(stack.into_resolve());
+    /// ```
+    pub fn caching_with_config(
+        resolver: impl CacheBackend,
+        config: &CacheConfig,
+    ) -> ResolverStackBuilder<CachingResolver> {
+        ResolverStackBuilder::new(CachingResolver::from_config(
+            resolver, config,
+        ))
+    }
+}
+
+impl<R: MyResolve + 'static> ResolverStackBuilder<R> {
+    /// Adds a [`DedupResolver`].
+    pub fn dedup(self) -> ResolverStackBuilder<DedupResolver<R>> {
+        self.layer(DedupResolver::new)
+    }
+
+    /// Returns the stack as a reqwest resolver, for
+    /// `ClientBuilder::dns_resolver`
+    ///
+    /// This is a concrete type rather than an `Arc<dyn Resolve>`, because
+    /// that's what `dns_resolver` accepts.
+    pub fn into_resolve(self) -> Arc<ResolveAdapter<R>> {
+        Arc::new(ResolveAdapter::new(self.resolver))
+    }
+
+    /// Returns a [`ResolverHandle`] to the stack, for sharing it between
+    /// clients.
+    pub fn into_handle(self) -> ResolverHandle<R> {
+        ResolverHandle::new(self.resolver)
+    }
+}
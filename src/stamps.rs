@@ -0,0 +1,563 @@
+//! Configuring upstreams with DNS Stamps
+//!
+//! dnscrypt-proxy, and the public lists of resolvers it reads, describe
+//! each server with a DNS Stamp: one "sdns://" string with everything
+//! needed to reach it (its protocol and address, the name its certificate
+//! is for, and which certificates to expect).  [`DnsStamp::parse`] reads
+//! them, so the same stamps can be pasted here instead of writing out
+//! [`EncryptedUpstream`]s and pins by hand, and [`build_resolver`] builds a
+//! resolver that uses them.
+//!
+//! Stamps for plain DNS, DNS-over-TLS, and DNS-over-HTTPS are supported
+//! here, and with the "dnscrypt" feature, `dnscrypt::DnscryptServer` reads
+//! stamps for DNSCrypt servers and `dnscrypt::relay_from_stamp` reads them
+//! for relays.  Stamps for protocols this crate doesn't speak (like
+//! DNS-over-QUIC) are rejected when parsed.
+
+use crate::bootstrap::Bootstrap;
+use crate::bootstrap::EncryptedProtocol;
+use crate::bootstrap::EncryptedUpstream;
+use crate::error::ResolveError;
+use crate::upstream_tls::UpstreamTls;
+use base64::Engine;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::NameServerConfigGroup;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// What every DNS Stamp starts with
+pub const STAMP_PREFIX: &str = "sdns://";
+
+/// The port plain DNS uses
+const DNS_PORT: u16 = 53;
+
+/// The path trust-dns sends DNS-over-HTTPS queries to
+const DOH_PATH: &str = "/dns-query";
+
+/// What a server's DNS Stamp says about how it's run
+///
+/// These are the operator's claims, and nothing here checks them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StampProps {
+    /// the server validates answers with DNSSEC
+    pub dnssec: bool,
+    /// the server doesn't keep logs of queries
+    pub no_logs: bool,
+    /// the server doesn't block or change answers
+    pub no_filter: bool,
+}
+
+/// A server described by a DNS Stamp
+///
+/// ```
+/// # use reqwest_resolve::bootstrap::EncryptedProtocol;
+/// # use reqwest_resolve::stamps::DnsStamp;
+/// let stamp = DnsStamp::parse(concat!(
+///     "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQov",
+///     "ZG5zLXF1ZXJ5",
+/// ))
+/// .unwrap();
+/// assert_eq!(stamp.protocol, Some(EncryptedProtocol::Https));
+/// assert_eq!(stamp.hostname, "dns.cloudflare.com");
+/// assert_eq!(stamp.addr, Some("1.0.0.1".parse().unwrap()));
+/// assert!(stamp.props.no_logs);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsStamp {
+    /// the encrypted protocol, or `None` for plain DNS
+    pub protocol: Option<EncryptedProtocol>,
+    pub props: StampProps,
+    /// the server's address, if the stamp gives one.  If it doesn't,
+    /// `hostname` has to be looked up.
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+    /// the name the server's certificate is checked against (empty for
+    /// plain DNS)
+    pub hostname: String,
+    /// for DNS-over-HTTPS, the path queries are sent to
+    pub path: Option<String>,
+    /// SHA-256 digests of certificates (see
+    /// [`UpstreamTls::with_certificate_hash`]), one of which the server has
+    /// to send, if there are any
+    pub hashes: Vec<[u8; 32]>,
+    /// plain resolvers to look `hostname` up with, if the stamp suggests
+    /// any
+    pub bootstrap_ips: Vec<IpAddr>,
+}
+
+impl DnsStamp {
+    /// Parses a stamp ("sdns://" and then the encoded server), failing with
+    /// [`ResolveError::InvalidConfig`] if it's malformed or for a protocol
+    /// that isn't supported.
+    pub fn parse(stamp: &str) -> Result<DnsStamp, ResolveError> {
+        let invalid = |message: &str| stamp_error(stamp, message);
+        let (kind, bytes) = decode(stamp)?;
+        match kind {
+            0x00 | 0x02 | 0x03 => (),
+            #[cfg(feature = "dnscrypt")]
+            0x01 => {
+                return Err(invalid(
+                    "DNSCrypt stamps are read by \
+                     dnscrypt::DnscryptServer::from_stamp",
+                ))
+            }
+            #[cfg(not(feature = "dnscrypt"))]
+            0x01 => return Err(invalid("DNSCrypt isn't supported")),
+            0x04 => return Err(invalid("DNS-over-QUIC isn't supported")),
+            0x05 | 0x85 => {
+                return Err(invalid("Oblivious DoH isn't supported"))
+            }
+            #[cfg(feature = "dnscrypt")]
+            0x81 => {
+                return Err(invalid(
+                    "DNSCrypt relay stamps are read by \
+                     dnscrypt::relay_from_stamp",
+                ))
+            }
+            #[cfg(not(feature = "dnscrypt"))]
+            0x81 => return Err(invalid("relays aren't supported")),
+            kind => {
+                return Err(invalid(&format!("unknown protocol {:#04x}", kind)))
+            }
+        }
+        let mut reader = Reader(&bytes);
+        let props = reader.props().ok_or_else(|| invalid("truncated"))?;
+        let addr = reader.string().ok_or_else(|| invalid("truncated"))?;
+
+        let protocol = match kind {
+            0x00 => None,
+            0x03 => Some(EncryptedProtocol::Tls),
+            #[cfg(feature = "dns-over-https-rustls")]
+            _ => Some(EncryptedProtocol::Https),
+            #[cfg(not(feature = "dns-over-https-rustls"))]
+            _ => {
+                return Err(invalid(
+                    "DNS-over-HTTPS needs the \"dns-over-https-rustls\" \
+                     feature",
+                ))
+            }
+        };
+        let default_port =
+            protocol.map_or(DNS_PORT, EncryptedProtocol::default_port);
+        let (addr, addr_port) = match parse_addr(addr) {
+            Some(parsed) => parsed,
+            None if addr.is_empty() && protocol.is_some() => (None, None),
+            None => return Err(invalid(&format!("bad address {:?}", addr))),
+        };
+        let mut stamp = DnsStamp {
+            protocol,
+            props,
+            addr,
+            port: addr_port.unwrap_or(default_port),
+            hostname: String::new(),
+            path: None,
+            hashes: Vec::new(),
+            bootstrap_ips: Vec::new(),
+        };
+        if protocol.is_none() {
+            if !reader.0.is_empty() {
+                return Err(invalid("trailing bytes"));
+            }
+            return Ok(stamp);
+        }
+
+        for hash in reader.set().ok_or_else(|| invalid("truncated"))? {
+            match <[u8; 32]>::try_from(hash) {
+                Ok(hash) => stamp.hashes.push(hash),
+                Err(_) if hash.is_empty() => (),
+                Err(_) => return Err(invalid("bad certificate hash")),
+            }
+        }
+        let hostname = reader.string().ok_or_else(|| invalid("truncated"))?;
+        let (hostname, hostname_port) = match hostname.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| invalid(&format!("bad port {:?}", port)))?;
+                (host, Some(port))
+            }
+            _ => (hostname, None),
+        };
+        if hostname.is_empty() {
+            return Err(invalid("no hostname"));
+        }
+        stamp.hostname = hostname.to_owned();
+        if addr_port.is_none() {
+            stamp.port = hostname_port.unwrap_or(default_port);
+        }
+        if kind == 0x02 {
+            let path = reader.string().ok_or_else(|| invalid("truncated"))?;
+            stamp.path = Some(path.to_owned());
+        }
+        // The bootstrap resolvers were added later and may be left out.
+        if !reader.0.is_empty() {
+            for ip in reader.set().ok_or_else(|| invalid("truncated"))? {
+                if ip.is_empty() {
+                    continue;
+                }
+                let ip = std::str::from_utf8(ip)
+                    .ok()
+                    .and_then(parse_addr)
+                    .and_then(|(ip, _)| ip)
+                    .ok_or_else(|| invalid("bad bootstrap address"))?;
+                stamp.bootstrap_ips.push(ip);
+            }
+        }
+        if !reader.0.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(stamp)
+    }
+
+    /// Returns the upstream the stamp describes, or `None` for plain DNS.
+    /// If the stamp gives an address, that's the upstream's hostname (so
+    /// it's used without a lookup) and the stamp's hostname is its TLS name.
+    pub fn upstream(&self) -> Option<EncryptedUpstream> {
+        let protocol = self.protocol?;
+        let hostname = match self.addr {
+            Some(addr) => addr.to_string(),
+            None => self.hostname.clone(),
+        };
+        Some(EncryptedUpstream {
+            hostname,
+            port: self.port,
+            protocol,
+            tls_name: self.addr.map(|_| self.hostname.clone()),
+        })
+    }
+
+    /// Returns `tls` with this stamp's certificate hashes required too.
+    pub fn with_hashes(&self, tls: UpstreamTls) -> UpstreamTls {
+        self.hashes
+            .iter()
+            .fold(tls, |tls, hash| tls.with_certificate_hash(*hash))
+    }
+}
+
+/// Returns a resolver that sends queries to the servers `stamps` describe,
+/// using `bootstrap` to find the addresses of those that don't give one
+///
+/// trust-dns uses one TLS configuration for every server, so the
+/// certificate hashes from all of the stamps go into it together: each
+/// encrypted server has to send a certificate matching a hash from any of
+/// the stamps (or, if none of them have hashes, just a valid certificate).
+/// trust-dns only sends DNS-over-HTTPS queries to "/dns-query", so stamps
+/// with any other path fail here; `doh::DohTransport::with_path` can send
+/// queries to those.
+pub async fn build_resolver(
+    stamps: &[DnsStamp],
+    bootstrap: &Bootstrap,
+    tls: UpstreamTls,
+    options: ResolverOpts,
+) -> Result<TokioAsyncResolver, trust_dns_resolver::error::ResolveError> {
+    let mut name_servers = Vec::new();
+    let mut tls = tls;
+    for stamp in stamps {
+        let upstream = match stamp.upstream() {
+            Some(upstream) => upstream,
+            None => {
+                let ip = stamp.addr.ok_or_else(|| {
+                    trust_dns_resolver::error::ResolveError::from(
+                        "DNS stamp for plain DNS has no address",
+                    )
+                })?;
+                let addr = SocketAddr::new(ip, stamp.port);
+                name_servers.push(NameServerConfig::new(addr, Protocol::Udp));
+                name_servers.push(NameServerConfig::new(addr, Protocol::Tcp));
+                continue;
+            }
+        };
+        if let Some(path) = &stamp.path {
+            if path != DOH_PATH {
+                return Err(trust_dns_resolver::error::ResolveError::from(
+                    format!(
+                        "DNS stamp for {}: DNS-over-HTTPS path {:?} isn't \
+                         supported",
+                        stamp.hostname, path
+                    ),
+                ));
+            }
+        }
+        name_servers.extend(
+            bootstrap.resolve_upstreams(&[upstream]).await?.into_inner(),
+        );
+        tls = stamp.with_hashes(tls);
+    }
+
+    let mut config = ResolverConfig::from_parts(
+        None,
+        Vec::new(),
+        NameServerConfigGroup::from(name_servers),
+    );
+    config.set_tls_client_config(tls.build());
+    TokioAsyncResolver::tokio(config, options)
+}
+
+/// Decodes a stamp, returning its protocol (the first byte) and the rest.
+pub(crate) fn decode(stamp: &str) -> Result<(u8, Vec<u8>), ResolveError> {
+    let encoded = stamp
+        .trim()
+        .strip_prefix(STAMP_PREFIX)
+        .ok_or_else(|| stamp_error(stamp, "doesn't start with \"sdns://\""))?;
+    let mut bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .map_err(|_| stamp_error(stamp, "not base64"))?;
+    if bytes.is_empty() {
+        return Err(stamp_error(stamp, "empty"));
+    }
+    let kind = bytes.remove(0);
+    Ok((kind, bytes))
+}
+
+pub(crate) fn stamp_error(stamp: &str, message: &str) -> ResolveError {
+    ResolveError::InvalidConfig(format!("DNS stamp {:?}: {}", stamp, message))
+}
+
+/// Parses a stamp's address: an IP address with or without a port, with
+/// IPv6 addresses in brackets.  An empty address is `None`.
+pub(crate) fn parse_addr(addr: &str) -> Option<(Option<IpAddr>, Option<u16>)> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Some((Some(addr.ip()), Some(addr.port())));
+    }
+    let ip = addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+    ip.unwrap_or(addr).parse().ok().map(|ip: IpAddr| (Some(ip), None))
+}
+
+/// Reads the fields of a decoded stamp
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    /// Reads the properties every stamp starts with.
+    pub(crate) fn props(&mut self) -> Option<StampProps> {
+        let props = u64::from_le_bytes(self.bytes(8)?.try_into().ok()?);
+        Some(StampProps {
+            dnssec: props & 1 != 0,
+            no_logs: props & 2 != 0,
+            no_filter: props & 4 != 0,
+        })
+    }
+
+    /// Reads bytes preceded by their length.
+    pub(crate) fn value(&mut self) -> Option<&'a [u8]> {
+        let len = self.byte()?;
+        self.bytes(usize::from(len))
+    }
+
+    /// Reads a string preceded by its length.
+    pub(crate) fn string(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.value()?).ok()
+    }
+
+    /// Reads a set of values, each preceded by its length, with the high bit
+    /// of the length set on all but the last.
+    fn set(&mut self) -> Option<Vec<&'a [u8]>> {
+        let mut values = Vec::new();
+        loop {
+            let len = self.byte()?;
+            values.push(self.bytes(usize::from(len & 0x7f))?);
+            if len & 0x80 == 0 {
+                return Some(values);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DnsStamp;
+    use super::StampProps;
+    use super::STAMP_PREFIX;
+    use crate::bootstrap::EncryptedProtocol;
+    use crate::error::ResolveError;
+    use base64::Engine;
+
+    /// 9.9.9.9, plain DNS, with DNSSEC
+    const PLAIN: &str = "sdns://AAEAAAAAAAAABzkuOS45Ljk";
+    /// [2001:db8::53]:5353, plain DNS
+    const PLAIN_V6: &str = "sdns://AAAAAAAAAAAAE1syMDAxOmRiODo6NTNdOjUzNTM";
+    /// 1.1.1.1 over TLS as "one.one.one.one", with a certificate hash
+    const TLS: &str = concat!(
+        "sdns://AwcAAAAAAAAABzEuMS4xLjEgAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBka",
+        "GxwdHh8Pb25lLm9uZS5vbmUub25l",
+    );
+    /// "dot.example" on port 8853 over TLS, with no address but two
+    /// bootstrap resolvers
+    const TLS_BOOTSTRAP: &str =
+        "sdns://AwAAAAAAAAAAAAAQZG90LmV4YW1wbGU6ODg1M4c5LjkuOS45BzguOC44Ljg";
+    /// Cloudflare's DNS-over-HTTPS server, as published
+    const HTTPS: &str = concat!(
+        "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQov",
+        "ZG5zLXF1ZXJ5",
+    );
+
+    fn decode(stamp: &str) -> Vec<u8> {
+        let encoded = stamp.strip_prefix(STAMP_PREFIX).unwrap();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded)
+            .unwrap()
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        let encoded =
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        format!("{}{}", STAMP_PREFIX, encoded)
+    }
+
+    fn assert_invalid(stamp: &str) {
+        match DnsStamp::parse(stamp) {
+            Err(ResolveError::InvalidConfig(_)) => (),
+            other => panic!("{:?}: expected an error, got {:?}", stamp, other),
+        }
+    }
+
+    #[test]
+    fn plain() {
+        let stamp = DnsStamp::parse(PLAIN).unwrap();
+        assert_eq!(
+            stamp,
+            DnsStamp {
+                protocol: None,
+                props: StampProps { dnssec: true, ..StampProps::default() },
+                addr: Some("9.9.9.9".parse().unwrap()),
+                port: 53,
+                hostname: String::new(),
+                path: None,
+                hashes: Vec::new(),
+                bootstrap_ips: Vec::new(),
+            }
+        );
+        assert_eq!(stamp.upstream(), None);
+
+        let stamp = DnsStamp::parse(PLAIN_V6).unwrap();
+        assert_eq!(stamp.addr, Some("2001:db8::53".parse().unwrap()));
+        assert_eq!(stamp.port, 5353);
+    }
+
+    #[test]
+    fn tls() {
+        let stamp = DnsStamp::parse(TLS).unwrap();
+        assert_eq!(stamp.protocol, Some(EncryptedProtocol::Tls));
+        assert_eq!(
+            stamp.props,
+            StampProps { dnssec: true, no_logs: true, no_filter: true }
+        );
+        assert_eq!(stamp.addr, Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(stamp.port, 853);
+        assert_eq!(stamp.hostname, "one.one.one.one");
+        let hash: Vec<u8> = (0..32).collect();
+        assert_eq!(stamp.hashes, [<[u8; 32]>::try_from(hash).unwrap()]);
+        let upstream = stamp.upstream().unwrap();
+        assert_eq!(upstream.hostname, "1.1.1.1");
+        assert_eq!(upstream.tls_name.as_deref(), Some("one.one.one.one"));
+
+        let stamp = DnsStamp::parse(TLS_BOOTSTRAP).unwrap();
+        assert_eq!(stamp.addr, None);
+        assert_eq!(stamp.port, 8853);
+        assert_eq!(stamp.hostname, "dot.example");
+        assert!(stamp.hashes.is_empty());
+        assert_eq!(
+            stamp.bootstrap_ips,
+            [
+                "9.9.9.9".parse::<std::net::IpAddr>().unwrap(),
+                "8.8.8.8".parse().unwrap()
+            ]
+        );
+        let upstream = stamp.upstream().unwrap();
+        assert_eq!(upstream.hostname, "dot.example");
+        assert_eq!(upstream.tls_name, None);
+    }
+
+    #[cfg(feature = "dns-over-https-rustls")]
+    #[test]
+    fn https() {
+        let stamp = DnsStamp::parse(HTTPS).unwrap();
+        assert_eq!(stamp.protocol, Some(EncryptedProtocol::Https));
+        assert_eq!(stamp.addr, Some("1.0.0.1".parse().unwrap()));
+        assert_eq!(stamp.port, 443);
+        assert_eq!(stamp.hostname, "dns.cloudflare.com");
+        assert_eq!(stamp.path.as_deref(), Some("/dns-query"));
+    }
+
+    #[cfg(not(feature = "dns-over-https-rustls"))]
+    #[test]
+    fn https_unsupported() {
+        assert_invalid(HTTPS);
+    }
+
+    /// Every stamp cut short anywhere fails, rather than reading past the
+    /// end or filling in what's missing.
+    #[test]
+    fn truncated() {
+        for stamp in [PLAIN, PLAIN_V6, TLS, HTTPS] {
+            let bytes = decode(stamp);
+            for len in 0..bytes.len() {
+                assert_invalid(&encode(&bytes[..len]));
+            }
+        }
+    }
+
+    #[test]
+    fn overlong() {
+        for stamp in [PLAIN, TLS, TLS_BOOTSTRAP, HTTPS] {
+            let mut bytes = decode(stamp);
+            bytes.extend_from_slice(b"\0extra");
+            assert_invalid(&encode(&bytes));
+        }
+        let mut bytes = decode(PLAIN);
+        bytes.push(0);
+        assert_invalid(&encode(&bytes));
+
+        // A length that runs past the end
+        let mut bytes = decode(PLAIN);
+        bytes[9] = 200;
+        assert_invalid(&encode(&bytes));
+
+        // A certificate hash that isn't 32 bytes
+        let mut bytes = decode(TLS);
+        bytes[17] = 33;
+        bytes.insert(18, 0);
+        assert_invalid(&encode(&bytes));
+    }
+
+    #[test]
+    fn bad_encoding() {
+        for stamp in [
+            "",
+            "sdns://",
+            "AAEAAAAAAAAABzkuOS45Ljk",
+            "https://AAEAAAAAAAAABzkuOS45Ljk",
+            "sdns://AAEAAAAAAAAA*zkuOS45Ljk",
+            "sdns://AAEAAAAAAAAABzkuOS45Ljk+/",
+            "sdns://A",
+            "sdns://!!!!",
+        ] {
+            assert_invalid(stamp);
+        }
+    }
+
+    #[test]
+    fn unsupported_protocols() {
+        for kind in [0x04, 0x05, 0x06, 0x85, 0xff] {
+            let mut bytes = decode(PLAIN);
+            bytes[0] = kind;
+            assert_invalid(&encode(&bytes));
+        }
+    }
+}
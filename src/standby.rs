@@ -0,0 +1,161 @@
+//! Switching every client over to a new resolver stack at once
+//!
+//! Moving a service to new DNS infrastructure (new upstreams, a new
+//! encrypted endpoint, a resolver stack configured differently) usually
+//! means restarting it, and finding out afterward whether the new
+//! configuration works.  A [`SwitchableResolver`] behind a shared
+//! `handle::ResolverHandle` lets the new stack be built while the old one
+//! keeps serving, checked with canary lookups, and then swapped in for
+//! every client holding the handle, without any of them being rebuilt.
+
+use crate::context::ResolveContext;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::logging::warning;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// Resolves names with whichever stack it was last switched to
+///
+/// Each lookup uses the stack that was current when it started, and keeps
+/// it alive until it finishes, so switching never interrupts a lookup
+/// that's in flight.  The previous stack is dropped (stopping its
+/// background tasks) once the last of those is done.
+///
+/// ```
+/// # use reqwest_resolve::handle::ResolverHandle;
+/// # use reqwest_resolve::standby::SwitchableResolver;
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, MyResolve};
+/// static OLD: StaticResolver = static_resolver! {
+///     "api.example.com" => ["192.0.2.10"],
+/// };
+/// static NEW: StaticResolver = static_resolver! {
+///     "api.example.com" => ["198.51.100.10"],
+///     "canary.example.com" => ["198.51.100.1"],
+/// };
+/// let handle = ResolverHandle::new(SwitchableResolver::new(OLD));
+/// let _client = handle.client_builder().build().unwrap();
+///
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// // Usually in a task of its own, once the new stack has been built:
+/// let canaries = ["canary.example.com"];
+/// handle.core().switch_to_verified(NEW, &canaries).await.unwrap();
+///
+/// let addrs = handle.resolve_to_vec("api.example.com").await.unwrap();
+/// assert_eq!(addrs, ["198.51.100.10:0".parse().unwrap()]);
+/// assert_eq!(handle.core().generation(), 1);
+/// # });
+/// ```
+pub struct SwitchableResolver {
+    current: RwLock<Arc<dyn MyResolve>>,
+    generation: AtomicU64,
+}
+
+impl SwitchableResolver {
+    pub fn new<R: MyResolve + 'static>(initial: R) -> SwitchableResolver {
+        SwitchableResolver {
+            current: RwLock::new(Arc::new(initial)),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stack lookups are using now.
+    pub fn current(&self) -> Arc<dyn MyResolve> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Returns how many times the resolver has been switched.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Makes `resolver` the stack for every lookup from now on, returning
+    /// the one it replaces.
+    pub fn switch_to<R: MyResolve + 'static>(
+        &self,
+        resolver: R,
+    ) -> Arc<dyn MyResolve> {
+        let mut current = self.current.write().unwrap();
+        let previous = std::mem::replace(&mut *current, Arc::new(resolver));
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        warning!("switched to a new resolver", generation = generation);
+        previous
+    }
+
+    /// Looks up each of `canaries` with `standby`, and switches to it if
+    /// they all resolve to at least one address
+    ///
+    /// Otherwise, this fails with [`ResolveError::CanaryFailed`] for the
+    /// first canary that didn't, and the current stack is kept.
+    pub async fn switch_to_verified<R: MyResolve + 'static>(
+        &self,
+        standby: R,
+        canaries: &[&str],
+    ) -> Result<(), ResolveError> {
+        for name in canaries {
+            let message = match standby.resolve_to_vec(name).await {
+                Ok(addrs) if !addrs.is_empty() => {
+                    debug!("standby canary lookup succeeded", name = name);
+                    continue;
+                }
+                Ok(_) => String::from("no addresses"),
+                Err(error) => error.to_string(),
+            };
+            let name = String::from(*name);
+            return Err(ResolveError::CanaryFailed { name, message });
+        }
+        self.switch_to(standby);
+        Ok(())
+    }
+}
+
+impl MyResolve for SwitchableResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let current = self.current();
+        async move { current.resolve(name).await }.boxed().into()
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        let current = self.current();
+        async move { current.resolve_with_port(name, port).await }
+            .boxed()
+            .into()
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &ResolveContext,
+    ) -> MyResolving<'_> {
+        let current = self.current();
+        let context = context.clone();
+        async move { current.resolve_with_context(name, &context).await }
+            .boxed()
+            .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let current = self.current();
+        async move { current.resolve_detailed(name).await }.boxed()
+    }
+}
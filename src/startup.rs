@@ -0,0 +1,372 @@
+//! Building a resolver that's known to work before it's used
+//!
+//! `CustomDnsResolver::new` takes a resolver that's already been built, and
+//! nothing about the configuration is checked until the first request tries
+//! to resolve a name: a server that isn't reachable, or an encrypted
+//! upstream whose name can't be found, shows up as every request failing.
+//! A service would usually rather fail at startup.
+//! [`CustomDnsResolver::try_new_async`] (and the same on
+//! `MyCustomDnsResolver`) checks the configuration, finds any encrypted
+//! upstreams, and optionally looks up a canary name first, returning a
+//! [`ResolveError`] that says which of those went wrong.
+//! [`StartupConfig::verify`] goes further, looking up canary names with
+//! each upstream on its own, and reports how each of them did.
+
+#[cfg(feature = "dns-over-rustls")]
+use crate::bootstrap::Bootstrap;
+#[cfg(feature = "dns-over-rustls")]
+use crate::bootstrap::EncryptedUpstream;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::CustomDnsResolver;
+use crate::MyCustomDnsResolver;
+use futures::future::join_all;
+use std::net::SocketAddr;
+#[cfg(feature = "dns-over-rustls")]
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Everything [`CustomDnsResolver::try_new_async`] needs to build and check
+/// a resolver
+pub struct StartupConfig {
+    pub config: ResolverConfig,
+    pub options: ResolverOpts,
+    /// encrypted upstreams to add to `config`'s name servers, found with
+    /// `bootstrap`
+    #[cfg(feature = "dns-over-rustls")]
+    pub encrypted: Vec<EncryptedUpstream>,
+    /// how to find `encrypted`'s addresses (required if there are any)
+    #[cfg(feature = "dns-over-rustls")]
+    pub bootstrap: Option<Bootstrap>,
+    /// the TLS configuration for `encrypted`, as from `mtls::client_config`
+    /// for servers that want a client certificate (trust-dns's default if
+    /// `None`)
+    #[cfg(feature = "dns-over-rustls")]
+    pub tls_client_config: Option<Arc<rustls::ClientConfig>>,
+    /// a name to look up before returning the resolver.  Any answer from the
+    /// servers will do, including that the name doesn't exist: this checks
+    /// that they can be reached, not what they say.
+    pub canary: Option<String>,
+}
+
+impl StartupConfig {
+    /// Uses `config` and `options` as they are, with no canary.
+    pub fn new(config: ResolverConfig, options: ResolverOpts) -> StartupConfig {
+        StartupConfig {
+            config,
+            options,
+            #[cfg(feature = "dns-over-rustls")]
+            encrypted: Vec::new(),
+            #[cfg(feature = "dns-over-rustls")]
+            bootstrap: None,
+            #[cfg(feature = "dns-over-rustls")]
+            tls_client_config: None,
+            canary: None,
+        }
+    }
+
+    /// Uses the system's configuration (on Unix, from /etc/resolv.conf),
+    /// failing with [`ResolveError::InvalidConfig`] if it can't be read.
+    pub fn from_system() -> Result<StartupConfig, ResolveError> {
+        let (config, options) =
+            trust_dns_resolver::system_conf::read_system_conf().map_err(
+                |error| {
+                    ResolveError::InvalidConfig(format!(
+                        "system configuration: {}",
+                        error
+                    ))
+                },
+            )?;
+        Ok(StartupConfig::new(config, options))
+    }
+
+    /// Sets the name to look up before returning the resolver.
+    pub fn with_canary(mut self, name: &str) -> StartupConfig {
+        self.canary = Some(name.to_owned());
+        self
+    }
+
+    /// Looks up each of `names` with each upstream on its own, and reports
+    /// how each upstream did
+    ///
+    /// As with the canary, any answer will do, including that a name doesn't
+    /// exist.  The lookups go to every upstream at once, with no cache and
+    /// without consulting the hosts file.  The configuration is checked and
+    /// any encrypted upstreams are found first, just as for
+    /// [`CustomDnsResolver::try_new_async`], and if that fails there's an
+    /// error instead of a summary.
+    ///
+    /// ```no_run
+    /// # use reqwest_resolve::startup::StartupConfig;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # async fn f() -> Result<(), reqwest_resolve::error::ResolveError> {
+    /// let startup = StartupConfig::new(
+    ///     ResolverConfig::cloudflare(),
+    ///     ResolverOpts::default(),
+    /// );
+    /// let summary = startup.verify(&["example.com", "example.net"]).await?;
+    /// if !summary.is_healthy() {
+    ///     for upstream in summary.unhealthy() {
+    ///         eprintln!("upstream {} is failing", upstream.addr);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn verify(
+        &self,
+        names: &[&str],
+    ) -> Result<HealthSummary, ResolveError> {
+        let config = full_config(self).await?;
+        validate(&config, &self.options)?;
+        let mut options = self.options;
+        options.cache_size = 0;
+        options.use_hosts_file = false;
+
+        let upstreams =
+            join_all(config.name_servers().iter().map(|name_server| {
+                verify_upstream(name_server, options, names)
+            }))
+            .await;
+        Ok(HealthSummary { upstreams })
+    }
+}
+
+/// What [`StartupConfig::verify`] found about each upstream
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct HealthSummary {
+    /// in the order the upstreams are configured
+    pub upstreams: Vec<UpstreamHealth>,
+}
+
+impl HealthSummary {
+    /// Returns whether every upstream answered every canary.
+    pub fn is_healthy(&self) -> bool {
+        self.upstreams.iter().all(UpstreamHealth::is_healthy)
+    }
+
+    /// Returns the upstreams that failed at least one canary.
+    pub fn unhealthy(&self) -> impl Iterator<Item = &UpstreamHealth> {
+        self.upstreams.iter().filter(|upstream| !upstream.is_healthy())
+    }
+}
+
+/// How one upstream did in [`StartupConfig::verify`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct UpstreamHealth {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    /// one for each canary name, in order
+    pub canaries: Vec<CanaryResult>,
+}
+
+impl UpstreamHealth {
+    /// Returns whether the upstream answered every canary.
+    pub fn is_healthy(&self) -> bool {
+        self.canaries.iter().all(|canary| canary.error.is_none())
+    }
+}
+
+/// How one canary lookup went in [`StartupConfig::verify`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct CanaryResult {
+    pub name: String,
+    /// how long the lookup took, whether or not it worked
+    pub elapsed: Duration,
+    /// why the lookup failed, if it did
+    pub error: Option<String>,
+}
+
+/// Looks up each of `names` with only `name_server`.
+async fn verify_upstream(
+    name_server: &NameServerConfig,
+    options: ResolverOpts,
+    names: &[&str],
+) -> UpstreamHealth {
+    let config =
+        ResolverConfig::from_parts(None, Vec::new(), vec![name_server.clone()]);
+    let resolver = TokioAsyncResolver::tokio(config, options);
+    let mut canaries = Vec::with_capacity(names.len());
+    for name in names {
+        let start = Instant::now();
+        let error = match &resolver {
+            Ok(resolver) => canary_lookup(resolver, name).await.err(),
+            Err(error) => Some(error.to_string()),
+        };
+        let elapsed = start.elapsed();
+        if let Some(error) = &error {
+            debug!(
+                "canary lookup failed",
+                upstream = name_server.socket_addr,
+                name = name,
+                error = error,
+            );
+        }
+        canaries.push(CanaryResult {
+            name: String::from(*name),
+            elapsed,
+            error,
+        });
+    }
+    UpstreamHealth {
+        addr: name_server.socket_addr,
+        protocol: name_server.protocol,
+        canaries,
+    }
+}
+
+/// Looks up `name`, treating an answer that it doesn't exist as success.
+async fn canary_lookup(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<(), String> {
+    match resolver.lookup_ip(name).await {
+        Ok(_) => Ok(()),
+        Err(error)
+            if matches!(
+                error.kind(),
+                ResolveErrorKind::NoRecordsFound { .. }
+            ) =>
+        {
+            Ok(())
+        }
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Returns `startup`'s configuration with any encrypted upstreams added.
+async fn full_config(
+    startup: &StartupConfig,
+) -> Result<ResolverConfig, ResolveError> {
+    #[cfg_attr(not(feature = "dns-over-rustls"), allow(unused_mut))]
+    let mut config = startup.config.clone();
+
+    #[cfg(feature = "dns-over-rustls")]
+    if !startup.encrypted.is_empty() {
+        let Some(bootstrap) = &startup.bootstrap else {
+            return Err(ResolveError::InvalidConfig(String::from(
+                "encrypted upstreams need a bootstrap",
+            )));
+        };
+        let mut group =
+            bootstrap.resolve_upstreams(&startup.encrypted).await.map_err(
+                |error| ResolveError::BootstrapFailed(error.to_string()),
+            )?;
+        if let Some(tls_client_config) = &startup.tls_client_config {
+            group = group.with_client_config(Arc::clone(tls_client_config));
+        }
+        for name_server in group.iter() {
+            config.add_name_server(name_server.clone());
+        }
+    }
+
+    Ok(config)
+}
+
+/// Checks `startup`, then builds the resolver and looks up the canary.
+async fn build_checked(
+    startup: StartupConfig,
+) -> Result<TokioAsyncResolver, ResolveError> {
+    let config = full_config(&startup).await?;
+    let options = startup.options;
+
+    validate(&config, &options)?;
+    let resolver = TokioAsyncResolver::tokio(config, options)
+        .map_err(|error| ResolveError::InvalidConfig(error.to_string()))?;
+
+    if let Some(name) = startup.canary {
+        if let Err(message) = canary_lookup(&resolver, &name).await {
+            return Err(ResolveError::CanaryFailed { name, message });
+        }
+        debug!("canary lookup succeeded", name = name);
+    }
+
+    Ok(resolver)
+}
+
+/// Rejects configurations that trust-dns accepts but can never work.
+fn validate(
+    config: &ResolverConfig,
+    options: &ResolverOpts,
+) -> Result<(), ResolveError> {
+    if config.name_servers().is_empty() {
+        return Err(ResolveError::InvalidConfig(String::from(
+            "no name servers",
+        )));
+    }
+    for name_server in config.name_servers() {
+        let encrypted =
+            !matches!(name_server.protocol, Protocol::Udp | Protocol::Tcp);
+        if encrypted && name_server.tls_dns_name.is_none() {
+            return Err(ResolveError::InvalidConfig(format!(
+                "name server {} ({:?}) has no name to check its certificate \
+                 against",
+                name_server.socket_addr, name_server.protocol
+            )));
+        }
+    }
+    if options.timeout.is_zero() {
+        return Err(ResolveError::InvalidConfig(String::from(
+            "the timeout is zero",
+        )));
+    }
+    if options.attempts == 0 {
+        return Err(ResolveError::InvalidConfig(String::from(
+            "the number of attempts is zero",
+        )));
+    }
+    Ok(())
+}
+
+impl CustomDnsResolver {
+    /// Builds a resolver from `startup`, or says why it wouldn't work
+    ///
+    /// The configuration is checked first (it has to have name servers,
+    /// encrypted ones need names for their certificates, and the timeout and
+    /// attempts can't be zero), then any encrypted upstreams are found, and
+    /// then the canary, if there is one, is looked up.
+    ///
+    /// ```
+    /// # use reqwest_resolve::error::ResolveError;
+    /// # use reqwest_resolve::startup::StartupConfig;
+    /// # use reqwest_resolve::CustomDnsResolver;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .enable_all()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let startup =
+    ///     StartupConfig::new(ResolverConfig::new(), ResolverOpts::default());
+    /// let error = CustomDnsResolver::try_new_async(startup).await.err();
+    /// assert_eq!(
+    ///     error,
+    ///     Some(ResolveError::InvalidConfig(String::from("no name servers")))
+    /// );
+    /// # });
+    /// ```
+    pub async fn try_new_async(
+        startup: StartupConfig,
+    ) -> Result<CustomDnsResolver, ResolveError> {
+        Ok(CustomDnsResolver::new(build_checked(startup).await?))
+    }
+}
+
+impl MyCustomDnsResolver {
+    /// Like [`CustomDnsResolver::try_new_async`]
+    pub async fn try_new_async(
+        startup: StartupConfig,
+    ) -> Result<MyCustomDnsResolver, ResolveError> {
+        Ok(MyCustomDnsResolver::new(build_checked(startup).await?))
+    }
+}
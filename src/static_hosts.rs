@@ -0,0 +1,305 @@
+//! Fixed host maps built at compile time
+//!
+//! Test binaries and embedded deployments often talk to a fixed set of peers
+//! whose addresses are known when the program is built.  The
+//! [`static_resolver!`](crate::static_resolver) macro turns a list of names
+//! and addresses into a [`StaticResolver`], parsing the addresses at compile
+//! time (so a typo is a build error) and putting the whole table in static
+//! memory.
+
+use crate::error::ResolveError;
+use crate::resolved::ResolvedAddr;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+/// Builds a [`StaticResolver`] from a fixed list of names and addresses
+///
+/// Addresses may be written with or without a port ("10.0.0.1:443",
+/// "10.0.0.1", "[fd00::1]:443", or "fd00::1").  Note that reqwest connects
+/// to the port in the URL, whatever port the resolver returns.  An invalid
+/// address fails the build.
+///
+/// The result can be used in a `static` or `const` item:
+///
+/// ```
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static PEERS: StaticResolver = static_resolver! {
+///     "api.test" => ["10.0.0.1:443", "10.0.0.2:443"],
+///     "db.test" => ["[fd00::5]:5432"],
+/// };
+///
+/// assert_eq!(PEERS.lookup("API.test.").len(), 2);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(&PEERS)));
+/// ```
+#[macro_export]
+macro_rules! static_resolver {
+    ($($name:literal => [$($addr:literal),* $(,)?]),* $(,)?) => {
+        $crate::static_hosts::StaticResolver::new(&[
+            $((
+                $name,
+                &[$($crate::static_hosts::parse_socket_addr($addr)),*],
+            )),*
+        ])
+    };
+}
+
+/// Resolves names from a fixed table in static memory
+///
+/// Names are matched case-insensitively, ignoring any trailing dot.  Names
+/// that aren't in the table fail with [`ResolveError::NotFound`].
+#[derive(Clone, Copy, Debug)]
+pub struct StaticResolver {
+    entries: &'static [(&'static str, &'static [SocketAddr])],
+}
+
+impl StaticResolver {
+    /// Usually called by [`static_resolver!`](crate::static_resolver).
+    pub const fn new(
+        entries: &'static [(&'static str, &'static [SocketAddr])],
+    ) -> StaticResolver {
+        StaticResolver { entries }
+    }
+
+    /// Returns the addresses for `name`, which are empty if it isn't in the
+    /// table.
+    pub fn lookup(&self, name: &str) -> &'static [SocketAddr] {
+        let name = name.trim_end_matches('.');
+        self.entries
+            .iter()
+            .find(|(entry, _)| {
+                entry.trim_end_matches('.').eq_ignore_ascii_case(name)
+            })
+            .map_or(&[], |(_, addrs)| *addrs)
+    }
+}
+
+impl MyResolve for StaticResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            Ok(Box::new(addrs.iter().copied()) as Addrs)
+        };
+        MyResolving::ready(result)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        let result = if addrs.is_empty() {
+            Err(ResolveError::NotFound { name: name.as_str().to_owned() }
+                .into())
+        } else {
+            Ok(addrs
+                .iter()
+                .map(|addr| ResolvedAddr::from_table(*addr, "static"))
+                .collect())
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+/// Parses an IP address with an optional port, at compile time if called in
+/// a const context
+///
+/// This is what [`static_resolver!`](crate::static_resolver) uses.  It
+/// panics on anything it can't parse, which in a const context is a build
+/// error.  IPv6 addresses may not use the embedded-IPv4 form
+/// ("::ffff:10.0.0.1").
+pub const fn parse_socket_addr(addr: &str) -> SocketAddr {
+    let bytes = addr.as_bytes();
+    if bytes.is_empty() {
+        panic!("empty address");
+    }
+
+    // "[v6]" or "[v6]:port"
+    if bytes[0] == b'[' {
+        let Some(close) = find(bytes, b']', 0) else {
+            panic!("IPv6 address is missing ']'");
+        };
+        let ip = parse_ipv6(bytes, 1, close);
+        let port = if close + 1 == bytes.len() {
+            0
+        } else if bytes[close + 1] == b':' {
+            parse_decimal(bytes, close + 2, bytes.len(), u16::MAX as u32) as u16
+        } else {
+            panic!("unexpected characters after ']'")
+        };
+        return SocketAddr::new(IpAddr::V6(ip), port);
+    }
+
+    // A bare IPv6 address has more than one colon, and no port.
+    let Some(colon) = find(bytes, b':', 0) else {
+        let ip = parse_ipv4(bytes, 0, bytes.len());
+        return SocketAddr::new(IpAddr::V4(ip), 0);
+    };
+    if find(bytes, b':', colon + 1).is_some() {
+        let ip = parse_ipv6(bytes, 0, bytes.len());
+        return SocketAddr::new(IpAddr::V6(ip), 0);
+    }
+
+    let ip = parse_ipv4(bytes, 0, colon);
+    let port =
+        parse_decimal(bytes, colon + 1, bytes.len(), u16::MAX as u32) as u16;
+    SocketAddr::new(IpAddr::V4(ip), port)
+}
+
+/// Returns the index of the first `byte` at or after `start`.
+const fn find(bytes: &[u8], byte: u8, start: usize) -> Option<usize> {
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i] == byte {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Parses `bytes[start..end]` as a decimal number no greater than `max`.
+const fn parse_decimal(
+    bytes: &[u8],
+    start: usize,
+    end: usize,
+    max: u32,
+) -> u32 {
+    if start >= end {
+        panic!("expected a number");
+    }
+    let mut value: u32 = 0;
+    let mut i = start;
+    while i < end {
+        let digit = bytes[i];
+        if !digit.is_ascii_digit() {
+            panic!("expected a decimal digit");
+        }
+        value = value * 10 + (digit - b'0') as u32;
+        if value > max {
+            panic!("number out of range");
+        }
+        i += 1;
+    }
+    value
+}
+
+const fn parse_ipv4(bytes: &[u8], start: usize, end: usize) -> Ipv4Addr {
+    let mut octets = [0u8; 4];
+    let mut octet = 0;
+    let mut field_start = start;
+    let mut i = start;
+    while i <= end {
+        if i == end || bytes[i] == b'.' {
+            if octet == 4 {
+                panic!("IPv4 address has too many octets");
+            }
+            octets[octet] = parse_decimal(bytes, field_start, i, 255) as u8;
+            octet += 1;
+            field_start = i + 1;
+        }
+        i += 1;
+    }
+    if octet != 4 {
+        panic!("IPv4 address has too few octets");
+    }
+    Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])
+}
+
+const fn parse_ipv6(bytes: &[u8], start: usize, end: usize) -> Ipv6Addr {
+    // Groups before and after the "::", if there is one.
+    let mut head = [0u16; 8];
+    let mut head_len = 0;
+    let mut tail = [0u16; 8];
+    let mut tail_len = 0;
+    let mut seen_gap = false;
+
+    let mut i = start;
+    if end - start >= 2 && bytes[i] == b':' && bytes[i + 1] == b':' {
+        seen_gap = true;
+        i += 2;
+    }
+    while i < end {
+        let mut value: u32 = 0;
+        let mut digits = 0;
+        while i < end && bytes[i] != b':' {
+            let digit = match bytes[i] {
+                b'0'..=b'9' => bytes[i] - b'0',
+                b'a'..=b'f' => bytes[i] - b'a' + 10,
+                b'A'..=b'F' => bytes[i] - b'A' + 10,
+                _ => panic!("expected a hex digit in IPv6 address"),
+            };
+            value = value * 16 + digit as u32;
+            digits += 1;
+            if digits > 4 {
+                panic!("IPv6 group has more than 4 digits");
+            }
+            i += 1;
+        }
+        if digits == 0 {
+            panic!("empty group in IPv6 address");
+        }
+        if head_len + tail_len == 8 {
+            panic!("IPv6 address has too many groups");
+        }
+        if seen_gap {
+            tail[tail_len] = value as u16;
+            tail_len += 1;
+        } else {
+            head[head_len] = value as u16;
+            head_len += 1;
+        }
+
+        if i < end {
+            // We're at a ':'.  Another right after it is the "::".
+            i += 1;
+            if i < end && bytes[i] == b':' {
+                if seen_gap {
+                    panic!("IPv6 address has more than one \"::\"");
+                }
+                seen_gap = true;
+                i += 1;
+            } else if i == end {
+                panic!("IPv6 address ends with ':'");
+            }
+        }
+    }
+
+    if !seen_gap && head_len != 8 {
+        panic!("IPv6 address has too few groups");
+    }
+    if seen_gap && head_len + tail_len == 8 {
+        panic!("\"::\" in IPv6 address stands for no groups");
+    }
+
+    let mut groups = [0u16; 8];
+    let mut g = 0;
+    while g < head_len {
+        groups[g] = head[g];
+        g += 1;
+    }
+    let mut t = 0;
+    while t < tail_len {
+        groups[8 - tail_len + t] = tail[t];
+        t += 1;
+    }
+    Ipv6Addr::new(
+        groups[0], groups[1], groups[2], groups[3], groups[4], groups[5],
+        groups[6], groups[7],
+    )
+}
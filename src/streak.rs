@@ -0,0 +1,185 @@
+//! Noticing when a name keeps failing to resolve
+//!
+//! One failed lookup is usually noise.  The same name failing over and over
+//! usually isn't: its DNS is broken, or the name has been removed, and the
+//! application may want to alert someone or switch to another endpoint
+//! before its users notice.  [`FailureStreakResolver`] calls back into the
+//! application when that happens.
+
+use crate::logging::debug;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Above this many names with failures, names whose streak has aged out of
+/// the window are forgotten
+const MAX_TRACKED_NAMES: usize = 10_000;
+
+/// What a [`FailureStreakResolver`] passes to its callback
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FailureStreak {
+    /// the name, lowercased and without a trailing dot
+    pub name: String,
+    /// how many lookups in a row have failed
+    pub failures: u32,
+    /// when the first of those failed
+    pub since: Instant,
+    /// the error from the most recent one
+    pub last_error: String,
+}
+
+struct Streak {
+    failures: u32,
+    since: Instant,
+}
+
+type Callback = Box<dyn Fn(&FailureStreak) + Send + Sync>;
+
+/// Calls a callback when lookups for a name fail `threshold` times in a row
+/// within `window`
+///
+/// The callback runs once per streak, on the failure that reaches the
+/// threshold.  A successful lookup ends the streak, and so does the window
+/// running out: a failure more than `window` after the streak's first one
+/// starts a new streak.  The callback is called on the task doing the
+/// lookup, so it should be quick; hand anything slow off to another task.
+///
+/// ```
+/// # use reqwest_resolve::streak::FailureStreakResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = FailureStreakResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     5,
+///     Duration::from_secs(60),
+///     |streak| {
+///         eprintln!(
+///             "{} has failed to resolve {} times in a row: {}",
+///             streak.name, streak.failures, streak.last_error
+///         );
+///     },
+/// );
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct FailureStreakResolver<R> {
+    inner: R,
+    threshold: u32,
+    window: Duration,
+    callback: Callback,
+    streaks: Mutex<BTreeMap<String, Streak>>,
+}
+
+impl<R> FailureStreakResolver<R> {
+    /// A `threshold` of 0 is treated as 1.
+    pub fn new<F>(
+        inner: R,
+        threshold: u32,
+        window: Duration,
+        callback: F,
+    ) -> FailureStreakResolver<R>
+    where
+        F: Fn(&FailureStreak) + Send + Sync + 'static,
+    {
+        FailureStreakResolver {
+            inner,
+            threshold: threshold.max(1),
+            window,
+            callback: Box::new(callback),
+            streaks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a failure, returning the streak to report if this failure
+    /// reaches the threshold.
+    fn record_failure(
+        &self,
+        name: &str,
+        error: &dyn std::error::Error,
+    ) -> Option<FailureStreak> {
+        let now = Instant::now();
+        let name = normalize(name).into_owned();
+        let mut streaks = self.streaks.lock().unwrap();
+        if streaks.len() >= MAX_TRACKED_NAMES && !streaks.contains_key(&name) {
+            streaks.retain(|_, streak| now - streak.since <= self.window);
+        }
+
+        let streak = streaks
+            .entry(name.clone())
+            .or_insert(Streak { failures: 0, since: now });
+        if now - streak.since > self.window {
+            *streak = Streak { failures: 0, since: now };
+        }
+        streak.failures = streak.failures.saturating_add(1);
+
+        (streak.failures == self.threshold).then(|| FailureStreak {
+            name,
+            failures: streak.failures,
+            since: streak.since,
+            last_error: error.to_string(),
+        })
+    }
+
+    /// Ends the streak for `name` if its lookup succeeded, or extends it
+    /// (reporting it if it reaches the threshold) if it failed.
+    fn observe<T>(
+        &self,
+        name: &str,
+        result: &Result<T, Box<dyn std::error::Error + Send + Sync>>,
+    ) {
+        let Err(error) = result else {
+            let mut streaks = self.streaks.lock().unwrap();
+            streaks.remove(&*normalize(name));
+            return;
+        };
+        if let Some(streak) = self.record_failure(name, &**error) {
+            debug!(
+                "name keeps failing to resolve",
+                name = streak.name,
+                failures = streak.failures,
+            );
+            (self.callback)(&streak);
+        }
+    }
+}
+
+impl<R: MyResolve> MyResolve for FailureStreakResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let result = self.inner.resolve(name.clone()).await;
+            self.observe(name.as_str(), &result);
+            result
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let result = self.inner.resolve_detailed(name.clone()).await;
+            self.observe(name.as_str(), &result);
+            result
+        }
+        .boxed()
+    }
+}
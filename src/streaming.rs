@@ -0,0 +1,114 @@
+//! Getting addresses as soon as each kind is known
+//!
+//! `lookup_ip` waits for both the A and the AAAA lookup before returning
+//! anything, so a connection can't start until the slower of the two is
+//! done, even if the other came straight from the cache.
+//! [`CustomDnsResolver::resolve_stream`] (and the same on
+//! `MyCustomDnsResolver`) looks the two up side by side, and yields each
+//! one's addresses as soon as it has them.  Happy Eyeballs connectors can
+//! start on the first address while the rest are still on their way.
+
+use crate::logging::debug;
+use crate::names;
+use crate::CustomDnsResolver;
+use crate::MyCustomDnsResolver;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use trust_dns_resolver::TokioAsyncResolver;
+
+impl CustomDnsResolver {
+    /// Returns a stream of `name`'s addresses that yields each family's as
+    /// soon as its lookup finishes
+    ///
+    /// Unlike `resolve`, this looks `name` up exactly as given: there's no
+    /// search domain and no hosts file, and `ResolverOpts::ip_strategy` is
+    /// ignored, since both families are always looked up.  A family whose
+    /// lookup fails is left out, so if both fail (or the name doesn't
+    /// exist), the stream ends without yielding anything.  The ports are all
+    /// 0.
+    ///
+    /// ```no_run
+    /// # use futures::stream::StreamExt;
+    /// # use reqwest_resolve::CustomDnsResolver;
+    /// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+    /// # use trust_dns_resolver::TokioAsyncResolver;
+    /// # async fn f() {
+    /// let resolver = CustomDnsResolver::new(
+    ///     TokioAsyncResolver::tokio(
+    ///         ResolverConfig::default(),
+    ///         ResolverOpts::default(),
+    ///     )
+    ///     .unwrap(),
+    /// );
+    /// let name = "example.com".parse().unwrap();
+    /// let mut addrs = Box::pin(resolver.resolve_stream(name));
+    /// while let Some(addr) = addrs.next().await {
+    ///     // start connecting to `addr`
+    /// #   drop(addr);
+    /// }
+    /// # }
+    /// ```
+    pub fn resolve_stream(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> impl Stream<Item = SocketAddr> + Send + 'static {
+        lookup_stream(self.resolver.clone(), name)
+    }
+}
+
+impl MyCustomDnsResolver {
+    /// The same as [`CustomDnsResolver::resolve_stream`]
+    pub fn resolve_stream(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> impl Stream<Item = SocketAddr> + Send + '_ {
+        lookup_stream(&self.resolver, name)
+    }
+}
+
+/// Looks up `name`'s A and AAAA records at the same time, yielding each
+/// one's addresses as they come.
+fn lookup_stream<R>(
+    resolver: R,
+    name: hyper::client::connect::dns::Name,
+) -> impl Stream<Item = SocketAddr> + Send
+where
+    R: Deref<Target = TokioAsyncResolver> + Clone + Send + Sync,
+{
+    // An IP literal has nothing to look up.
+    let literal = name.as_str().parse::<IpAddr>().ok();
+    let parsed = literal.is_none().then(|| names::parsed(name.as_str()));
+    let v4_name = parsed.clone().flatten();
+    let v6_name = parsed.flatten();
+    let host = name.as_str().to_owned();
+
+    let v4_resolver = resolver.clone();
+    let v4_host = host.clone();
+    let v4 = futures::stream::once(async move {
+        let Some(name) = v4_name else { return Vec::new() };
+        match v4_resolver.ipv4_lookup(name).await {
+            Ok(lookup) => lookup.iter().map(|ip| IpAddr::V4(*ip)).collect(),
+            Err(error) => {
+                debug!("A lookup failed", name = v4_host, error = error);
+                Vec::new()
+            }
+        }
+    });
+    let v6 = futures::stream::once(async move {
+        let Some(name) = v6_name else { return Vec::new() };
+        match resolver.ipv6_lookup(name).await {
+            Ok(lookup) => lookup.iter().map(|ip| IpAddr::V6(*ip)).collect(),
+            Err(error) => {
+                debug!("AAAA lookup failed", name = host, error = error);
+                Vec::new()
+            }
+        }
+    });
+
+    futures::stream::iter(literal)
+        .chain(futures::stream::select(v4, v6).flat_map(futures::stream::iter))
+        .map(|ip| SocketAddr::new(ip, 0))
+}
@@ -0,0 +1,792 @@
+//! Using HTTPS records (the SVCB-compatible record type for HTTP origins)
+
+use crate::context::current_context;
+use crate::deterministic::is_deterministic;
+use crate::deterministic::stable_order;
+use crate::do_resolve;
+use crate::global::overridable;
+use crate::logging::debug;
+use crate::with_port;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::join_all;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::error::Error as StdError;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamKey;
+use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamValue;
+use trust_dns_resolver::proto::rr::rdata::svcb::SVCB;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Looks up the HTTPS records for `name`, most preferred first
+///
+/// A name with no HTTPS records (which is most of them, today) produces an
+/// empty list rather than an error.
+pub async fn lookup_https(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<SVCB>, ResolveError> {
+    let lookup = match resolver.lookup(name, RecordType::HTTPS).await {
+        Ok(lookup) => lookup,
+        Err(error) => match error.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok(Vec::new()),
+            _ => return Err(error),
+        },
+    };
+
+    let mut records: Vec<SVCB> = lookup
+        .iter()
+        .filter_map(|rdata| match rdata {
+            RData::HTTPS(svcb) => Some(svcb.clone()),
+            _ => None,
+        })
+        .collect();
+    // Lower priorities are preferred, except that 0 means "AliasMode", and
+    // those records aren't alternatives to the ServiceMode ones.  Sorting on
+    // the raw u16 puts any AliasMode records first, which is where callers
+    // will want them anyway.
+    // Records with the same priority stay in the server's order, unless that
+    // has to be reproducible.
+    if is_deterministic() {
+        records.sort_by(|a, b| {
+            (a.svc_priority(), a.target_name())
+                .cmp(&(b.svc_priority(), b.target_name()))
+        });
+    } else {
+        records.sort_by_key(|svcb| svcb.svc_priority());
+    }
+    Ok(records)
+}
+
+/// An ECHConfigList published in an HTTPS record
+///
+/// This is left in its wire encoding, which is what TLS implementations that
+/// support Encrypted Client Hello expect to be handed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct EchConfigList(Vec<u8>);
+
+impl EchConfigList {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Returns the ECHConfigList to use, given HTTPS records sorted by
+/// [`lookup_https`]
+///
+/// The ECH configuration is specific to the endpoint that published it, so
+/// this only looks at the most preferred ServiceMode record.  If that one
+/// doesn't advertise ECH, we don't go looking for another that does.
+pub fn ech_config(records: &[SVCB]) -> Option<EchConfigList> {
+    records.iter().find(|svcb| svcb.svc_priority() != 0).and_then(|svcb| {
+        svcb.svc_params().iter().find_map(|(_, value)| match value {
+            SvcParamValue::EchConfig(ech) => Some(EchConfigList(ech.0.clone())),
+            _ => None,
+        })
+    })
+}
+
+/// Receives the ECH configurations discovered by [`EchResolver`]
+///
+/// reqwest's TLS backends can't do Encrypted Client Hello themselves, but an
+/// application with a TLS stack that can may use this to learn the
+/// configuration for each host as it's resolved.  Any
+/// `Fn(&str, EchConfigList)` works here.
+pub trait EchConfigSink: Send + Sync {
+    fn ech_config_found(&self, name: &str, config: EchConfigList);
+}
+
+impl<F> EchConfigSink for F
+where
+    F: Fn(&str, EchConfigList) + Send + Sync,
+{
+    fn ech_config_found(&self, name: &str, config: EchConfigList) {
+        self(name, config)
+    }
+}
+
+/// Resolves addresses like [`crate::CustomDnsResolver`], but also looks up
+/// each name's HTTPS records and reports any ECH configuration to an
+/// [`EchConfigSink`]
+///
+/// The HTTPS query runs concurrently with the address queries, so it doesn't
+/// add latency unless it's slower than they are.  Failures looking up HTTPS
+/// records are ignored: they only mean we can't use ECH for that host.
+///
+/// ```
+/// # use reqwest_resolve::svcb::{EchConfigList, EchResolver};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let on_ech = |name: &str, ech: EchConfigList| {
+///     println!("{}: {} bytes of ECH config", name, ech.as_bytes().len());
+/// };
+/// let my_resolver = EchResolver::new(resolver, on_ech);
+/// let _client =
+///     reqwest::ClientBuilder::new().dns_resolver(Arc::new(my_resolver));
+/// ```
+pub struct EchResolver {
+    resolver: Arc<TokioAsyncResolver>,
+    sink: Arc<dyn EchConfigSink>,
+}
+
+impl EchResolver {
+    pub fn new<S: EchConfigSink + 'static>(
+        resolver: TokioAsyncResolver,
+        sink: S,
+    ) -> EchResolver {
+        EchResolver { resolver: Arc::new(resolver), sink: Arc::new(sink) }
+    }
+}
+
+impl reqwest::dns::Resolve for EchResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        let sink = self.sink.clone();
+        async move {
+            overridable(name, |name| do_resolve_ech(&resolver, &*sink, name))
+                .await
+        }
+        .boxed()
+    }
+}
+
+impl MyResolve for EchResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve_ech(&self.resolver, &*self.sink, name).boxed().into()
+    }
+}
+
+async fn do_resolve_ech(
+    resolver: &TokioAsyncResolver,
+    sink: &dyn EchConfigSink,
+    name: hyper::client::connect::dns::Name,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    let (addrs, https) = futures::join!(
+        do_resolve(resolver, name.clone()),
+        lookup_https(resolver, name.as_str())
+    );
+    if let Some(ech) = https.ok().as_deref().and_then(ech_config) {
+        sink.ech_config_found(name.as_str(), ech);
+    }
+    addrs
+}
+
+/// The most AliasMode records we'll follow for one name before giving up
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// One of the alternative endpoints published for a name
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SvcbEndpoint {
+    /// SvcPriority of the record this came from (lower is preferred)
+    pub priority: u16,
+    /// the owner of the record this came from, without a trailing dot:
+    /// after an alias, that's the alias's target, not the name looked up
+    pub owner: String,
+    /// the host to connect to
+    pub target: String,
+    /// the port to connect to, if the record says to use a different one
+    pub port: Option<u16>,
+    /// addresses from the record's "ipv4hint" and "ipv6hint" parameters
+    pub hints: Vec<IpAddr>,
+}
+
+/// Works out which endpoints to use for `name` from its HTTPS records, most
+/// preferred first
+///
+/// AliasMode records are followed (up to a fixed depth).  ServiceMode records
+/// that this crate can't use are skipped: that means ones that require
+/// parameters we don't understand, and ones that don't offer HTTP/1.1 or
+/// HTTP/2.  Records with the same priority are left in the order the server
+/// returned them.
+///
+/// An empty list means the name doesn't publish any usable endpoints this way
+/// and should be resolved normally.
+pub async fn select_endpoints(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<SvcbEndpoint>, ResolveError> {
+    select_endpoints_for_port(resolver, name, 443).await
+}
+
+/// Like [`select_endpoints`], for connecting to `name` on `port`
+///
+/// For ports other than 443 and 80 (the defaults for "https" and "http"),
+/// the records are looked up at a name with the port prefixed, like
+/// "_8443._https.example.com", as RFC 9460 describes.
+pub async fn select_endpoints_for_port(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+    port: u16,
+) -> Result<Vec<SvcbEndpoint>, ResolveError> {
+    let mut owner = https_query_name(name, port);
+    // the owner of the last AliasMode record followed, if any
+    let mut alias_owner: Option<String> = None;
+    for _ in 0..MAX_ALIAS_DEPTH {
+        let records = lookup_https(resolver, &owner).await?;
+        match (records.first(), alias_owner) {
+            // An AliasMode target of "." means there's no service here.
+            (Some(svcb), _) if svcb.svc_priority() == 0 => {
+                if svcb.target_name().is_root() {
+                    return Ok(Vec::new());
+                }
+                let target = svcb.target_name().to_ascii();
+                alias_owner = Some(std::mem::replace(&mut owner, target));
+                continue;
+            }
+            // If we followed an alias to a name with no HTTPS records, that
+            // name's addresses are what we want.
+            (None, Some(alias_owner)) => {
+                return Ok(vec![SvcbEndpoint {
+                    priority: 1,
+                    owner: alias_owner.trim_end_matches('.').to_owned(),
+                    target: owner,
+                    port: None,
+                    hints: Vec::new(),
+                }]);
+            }
+            (_, aliased) => {
+                // A ServiceMode target of "." means the owner name, which
+                // for a port-prefixed name means the host itself.
+                let host = match aliased {
+                    Some(_) => owner.as_str(),
+                    None => name,
+                };
+                return Ok(records
+                    .iter()
+                    .filter(|svcb| usable(svcb))
+                    .map(|svcb| endpoint(&owner, host, svcb))
+                    .collect());
+            }
+        }
+    }
+
+    Err(ResolveError::from(format!(
+        "more than {} chained AliasMode HTTPS records for {:?}",
+        MAX_ALIAS_DEPTH, name
+    )))
+}
+
+/// Returns the name to look up HTTPS records at for connecting to `name` on
+/// `port`.
+pub fn https_query_name(name: &str, port: u16) -> String {
+    match port {
+        // 0 is what reqwest's lookups carry, which means "whatever the URL
+        // says", and for most URLs that's the default.
+        0 | 80 | 443 => name.to_owned(),
+        port => format!("_{}._https.{}", port, name),
+    }
+}
+
+fn usable(svcb: &SVCB) -> bool {
+    let mut no_default_alpn = false;
+    let mut alpn_ok = false;
+    for (_, value) in svcb.svc_params() {
+        match value {
+            SvcParamValue::Mandatory(mandatory)
+                if mandatory.0.iter().any(|key| {
+                    matches!(key, SvcParamKey::Key(_) | SvcParamKey::Unknown(_))
+                }) =>
+            {
+                return false;
+            }
+            SvcParamValue::NoDefaultAlpn => no_default_alpn = true,
+            SvcParamValue::Alpn(alpn) => {
+                alpn_ok = alpn.0.iter().any(|p| p == "h2" || p == "http/1.1")
+            }
+            _ => (),
+        }
+    }
+
+    // Without "no-default-alpn", HTTP/1.1 is always implicitly supported.
+    !no_default_alpn || alpn_ok
+}
+
+/// Returns the endpoint `svcb` describes, from a record at `owner`, with a
+/// target of "." meaning `host`.
+fn endpoint(owner: &str, host: &str, svcb: &SVCB) -> SvcbEndpoint {
+    let target = if svcb.target_name().is_root() {
+        host.to_owned()
+    } else {
+        svcb.target_name().to_ascii()
+    };
+    let mut port = None;
+    let mut hints = Vec::new();
+    for (_, value) in svcb.svc_params() {
+        match value {
+            SvcParamValue::Port(p) => port = Some(*p),
+            SvcParamValue::Ipv4Hint(ips) => {
+                hints.extend(ips.0.iter().map(|ip| IpAddr::from(*ip)))
+            }
+            SvcParamValue::Ipv6Hint(ips) => {
+                hints.extend(ips.0.iter().map(|ip| IpAddr::from(*ip)))
+            }
+            _ => (),
+        }
+    }
+
+    stable_order(&mut hints);
+    SvcbEndpoint {
+        priority: svcb.svc_priority(),
+        owner: owner.trim_end_matches('.').to_owned(),
+        target,
+        port,
+        hints,
+    }
+}
+
+/// Maps the target names in SVCB and SRV records to the names that are
+/// looked up instead
+///
+/// Service registries often publish logical targets (like "api.service")
+/// that only resolve once they've been mapped to a concrete hostname (like
+/// "api.service.dc1.example.net").  The target is given without a trailing
+/// dot, along with the owner of the record it came from, and the name
+/// returned is looked up as it is, subject to the resolver's search domains
+/// unless it ends with a dot.  Any `Fn(&str, &str) -> Option<String>`
+/// taking the owner and the target works here.
+///
+/// ```
+/// # use reqwest_resolve::svcb::TargetRewrite;
+/// let in_dc1 = |_owner: &str, target: &str| {
+///     target
+///         .ends_with(".service")
+///         .then(|| format!("{}.dc1.example.net.", target))
+/// };
+/// assert_eq!(
+///     in_dc1.rewrite_target("_api._tcp.example.net", "api.service"),
+///     Some(String::from("api.service.dc1.example.net.")),
+/// );
+/// ```
+pub trait TargetRewrite: Send + Sync {
+    /// Returns the name to look up instead of `target`, or `None` to look
+    /// `target` itself up.
+    fn rewrite_target(&self, owner: &str, target: &str) -> Option<String>;
+}
+
+impl<F> TargetRewrite for F
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync,
+{
+    fn rewrite_target(&self, owner: &str, target: &str) -> Option<String> {
+        self(owner, target)
+    }
+}
+
+/// Returns the name to look up for `target`, from a record at `owner`.
+pub(crate) fn rewritten_target(
+    rewrite: Option<&dyn TargetRewrite>,
+    owner: &str,
+    target: &str,
+) -> String {
+    let Some(rewrite) = rewrite else {
+        return target.to_owned();
+    };
+    let owner = owner.trim_end_matches('.');
+    match rewrite.rewrite_target(owner, target.trim_end_matches('.')) {
+        Some(rewritten) => {
+            debug!(
+                "rewrote target",
+                owner = owner,
+                target = target,
+                rewritten = rewritten,
+            );
+            rewritten
+        }
+        None => target.to_owned(),
+    }
+}
+
+/// Resolves names by way of the endpoints published in their HTTPS records
+///
+/// The addresses returned are ordered by endpoint preference, so when the
+/// most preferred endpoint is unreachable, hyper's connector falls back to
+/// the next one on its own.  This is what lets a service move between
+/// providers by publishing both for a while.  If a target's address lookup
+/// fails, its "ipv4hint"/"ipv6hint" addresses are used instead.  Names
+/// without usable HTTPS records are resolved exactly as
+/// [`crate::CustomDnsResolver`] would.
+///
+/// Ports published in the records are included in the returned addresses,
+/// but note that reqwest always connects to the port from the URL, and
+/// always asks for records at the name itself.  Callers that know the port
+/// should use [`MyResolve::resolve_with_port`], which looks records up at
+/// the port-prefixed name (see [`select_endpoints_for_port`]) and fills the
+/// port in for endpoints that don't publish their own.
+///
+/// Targets can be mapped to other names before their addresses are looked
+/// up, with [`SvcbResolver::with_target_rewrite`].
+pub struct SvcbResolver {
+    resolver: Arc<TokioAsyncResolver>,
+    rewrite: Option<Arc<dyn TargetRewrite>>,
+}
+
+impl SvcbResolver {
+    pub fn new(resolver: TokioAsyncResolver) -> SvcbResolver {
+        SvcbResolver { resolver: Arc::new(resolver), rewrite: None }
+    }
+
+    /// Looks up the name `rewrite` maps each endpoint's target to, instead of
+    /// the target itself.
+    pub fn with_target_rewrite<T: TargetRewrite + 'static>(
+        mut self,
+        rewrite: T,
+    ) -> SvcbResolver {
+        self.rewrite = Some(Arc::new(rewrite));
+        self
+    }
+}
+
+impl reqwest::dns::Resolve for SvcbResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        let rewrite = self.rewrite.clone();
+        async move {
+            overridable(name, |name| {
+                do_resolve_svcb(&resolver, rewrite.as_deref(), name, 0)
+            })
+            .await
+        }
+        .boxed()
+    }
+}
+
+impl SvcbResolver {
+    /// Looks up `name` for `port` (0 if it isn't known)
+    ///
+    /// HTTPS records describe endpoints for "https" URLs, so lookups whose
+    /// context (see `context::current_context`) is for any other scheme
+    /// skip them and only look up the name's addresses.
+    fn lookup(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        let context = current_context().unwrap_or_default();
+        let port = match port {
+            0 => context.port().unwrap_or(0),
+            port => port,
+        };
+        match context.scheme() {
+            Some(scheme) if scheme != "https" => async move {
+                Ok(with_port(do_resolve(&self.resolver, name).await?, port))
+            }
+            .boxed()
+            .into(),
+            _ => do_resolve_svcb(
+                &self.resolver,
+                self.rewrite.as_deref(),
+                name,
+                port,
+            )
+            .boxed()
+            .into(),
+        }
+    }
+}
+
+impl MyResolve for SvcbResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        self.lookup(name, 0)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        self.lookup(name, port)
+    }
+}
+
+/// `port` is used for endpoints that don't publish their own, and 0 means
+/// the port isn't known.
+pub(crate) async fn do_resolve_svcb(
+    resolver: &TokioAsyncResolver,
+    rewrite: Option<&dyn TargetRewrite>,
+    name: hyper::client::connect::dns::Name,
+    port: u16,
+) -> Result<Addrs, Box<dyn StdError + Send + Sync>> {
+    // Problems with HTTPS records shouldn't make a host unreachable when its
+    // A/AAAA records are fine, so any error here means "resolve normally".
+    let endpoints = select_endpoints_for_port(resolver, name.as_str(), port)
+        .await
+        .unwrap_or_default();
+    let lookups = join_all(endpoints.iter().map(|endpoint| {
+        let target =
+            rewritten_target(rewrite, &endpoint.owner, &endpoint.target);
+        async move { resolver.lookup_ip(target).await }
+    }))
+    .await;
+
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for (endpoint, lookup) in endpoints.iter().zip(lookups) {
+        let port = endpoint.port.unwrap_or(port);
+        let mut ips: Vec<IpAddr> = match lookup {
+            Ok(lookup) => lookup.iter().collect(),
+            Err(_) => endpoint.hints.clone(),
+        };
+        stable_order(&mut ips);
+        for ip in ips {
+            let addr = SocketAddr::new(ip, port);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        return Ok(with_port(do_resolve(resolver, name).await?, port));
+    }
+
+    Ok(Box::new(addrs.into_iter()))
+}
+
+#[cfg(all(test, feature = "testserver"))]
+mod tests {
+    use super::select_endpoints;
+    use super::select_endpoints_for_port;
+    use super::SvcbEndpoint;
+    use super::SvcbResolver;
+    use crate::testserver::TestServer;
+    use crate::testserver::TestZone;
+    use crate::testserver::DEFAULT_TEST_TTL;
+    use crate::MyResolve;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::proto::rr::rdata::svcb::Alpn;
+    use trust_dns_resolver::proto::rr::rdata::svcb::IpHint;
+    use trust_dns_resolver::proto::rr::rdata::svcb::Mandatory;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamKey;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SvcParamValue;
+    use trust_dns_resolver::proto::rr::rdata::svcb::SVCB;
+    use trust_dns_resolver::proto::rr::RData;
+    use trust_dns_resolver::proto::rr::Record;
+    use trust_dns_resolver::Name;
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    fn add_https(
+        zone: &mut TestZone,
+        owner: &str,
+        priority: u16,
+        target: &str,
+        params: Vec<(SvcParamKey, SvcParamValue)>,
+    ) {
+        zone.add_record(Record::from_rdata(
+            Name::from_ascii(owner).unwrap(),
+            DEFAULT_TEST_TTL,
+            RData::HTTPS(SVCB::new(
+                priority,
+                Name::from_ascii(target).unwrap(),
+                params,
+            )),
+        ));
+    }
+
+    fn endpoint(owner: &str, priority: u16, target: &str) -> SvcbEndpoint {
+        SvcbEndpoint {
+            priority,
+            owner: owner.to_owned(),
+            target: target.to_owned(),
+            port: None,
+            hints: Vec::new(),
+        }
+    }
+
+    fn zone() -> TestZone {
+        let mut zone = TestZone::new();
+        // Out of order, to check they're sorted by priority.
+        add_https(
+            &mut zone,
+            "svc.test.",
+            2,
+            "b.test.",
+            vec![(SvcParamKey::Port, SvcParamValue::Port(8443))],
+        );
+        add_https(
+            &mut zone,
+            "svc.test.",
+            1,
+            "a.test.",
+            vec![(
+                SvcParamKey::Ipv4Hint,
+                SvcParamValue::Ipv4Hint(IpHint(vec![Ipv4Addr::new(
+                    192, 0, 2, 1,
+                )])),
+            )],
+        );
+        // Only HTTP/3, which this crate can't use
+        add_https(
+            &mut zone,
+            "svc.test.",
+            3,
+            "h3.test.",
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h3".to_owned()])),
+                ),
+                (SvcParamKey::NoDefaultAlpn, SvcParamValue::NoDefaultAlpn),
+            ],
+        );
+        // Explicitly offering HTTP/2 as well
+        add_https(
+            &mut zone,
+            "svc.test.",
+            4,
+            "h2.test.",
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec![
+                        "h3".to_owned(),
+                        "h2".to_owned(),
+                    ])),
+                ),
+                (SvcParamKey::NoDefaultAlpn, SvcParamValue::NoDefaultAlpn),
+            ],
+        );
+        // Requiring a parameter this crate doesn't know
+        add_https(
+            &mut zone,
+            "svc.test.",
+            5,
+            "unknown.test.",
+            vec![(
+                SvcParamKey::Mandatory,
+                SvcParamValue::Mandatory(Mandatory(vec![SvcParamKey::Key(
+                    65000,
+                )])),
+            )],
+        );
+        add_https(&mut zone, "alias.test.", 0, "svc.test.", Vec::new());
+        add_https(&mut zone, "plain-alias.test.", 0, "plain.test.", Vec::new());
+        add_https(&mut zone, "none.test.", 0, ".", Vec::new());
+        add_https(&mut zone, "loop.test.", 0, "loop.test.", Vec::new());
+        add_https(&mut zone, "_8080._https.svc.test.", 1, ".", Vec::new());
+        zone.add_addr("plain.test", "192.0.2.9".parse().unwrap());
+        zone
+    }
+
+    #[tokio::test]
+    async fn selects_endpoints() {
+        let server = TestServer::start(zone()).await.unwrap();
+        let resolver = TokioAsyncResolver::tokio(
+            server.resolver_config(),
+            ResolverOpts::default(),
+        )
+        .unwrap();
+
+        let expected = vec![
+            SvcbEndpoint {
+                hints: vec!["192.0.2.1".parse().unwrap()],
+                ..endpoint("svc.test", 1, "a.test.")
+            },
+            SvcbEndpoint {
+                port: Some(8443),
+                ..endpoint("svc.test", 2, "b.test.")
+            },
+            endpoint("svc.test", 4, "h2.test."),
+        ];
+        assert_eq!(
+            select_endpoints(&resolver, "svc.test").await.unwrap(),
+            expected
+        );
+        // Following an alias gets the target's endpoints, which came from
+        // records at the target.
+        assert_eq!(
+            select_endpoints(&resolver, "alias.test").await.unwrap(),
+            expected
+        );
+        // An alias to a name without HTTPS records means that name.
+        assert_eq!(
+            select_endpoints(&resolver, "plain-alias.test").await.unwrap(),
+            [endpoint("plain-alias.test", 1, "plain.test.")]
+        );
+        // An alias to "." means there's no service, and a name with no
+        // records at all should be resolved normally.
+        assert!(select_endpoints(&resolver, "none.test")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(select_endpoints(&resolver, "plain.test")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(select_endpoints(&resolver, "loop.test").await.is_err());
+
+        // Other ports have records of their own, and a target of "." there
+        // means the host.
+        assert_eq!(
+            select_endpoints_for_port(&resolver, "svc.test", 8080)
+                .await
+                .unwrap(),
+            [endpoint("_8080._https.svc.test", 1, "svc.test")]
+        );
+    }
+
+    /// A rewrite of a target reached through an alias is told the owner of
+    /// the record the target came from, not the name looked up.
+    #[tokio::test]
+    async fn rewrites_through_alias() {
+        let server = TestServer::start(zone()).await.unwrap();
+        let resolver = TokioAsyncResolver::tokio(
+            server.resolver_config(),
+            ResolverOpts::default(),
+        )
+        .unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let rewrite = {
+            let seen = Arc::clone(&seen);
+            move |owner: &str, target: &str| {
+                seen.lock()
+                    .unwrap()
+                    .push((owner.to_owned(), target.to_owned()));
+                (target == "a.test").then(|| "plain.test.".to_owned())
+            }
+        };
+        let resolver = SvcbResolver::new(resolver).with_target_rewrite(rewrite);
+
+        let addrs = resolver.resolve_to_vec("alias.test").await.unwrap();
+        assert_eq!(addrs[0], "192.0.2.9:0".parse().unwrap());
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        let expected: Vec<(String, String)> = ["a.test", "b.test", "h2.test"]
+            .iter()
+            .map(|target| ("svc.test".to_owned(), (*target).to_owned()))
+            .collect();
+        assert_eq!(seen, expected);
+    }
+}
@@ -0,0 +1,207 @@
+//! Resolving names with the operating system's resolver
+//!
+//! [`SystemResolver`] looks names up the way reqwest does when it isn't given
+//! a resolver (`getaddrinfo`, on Unix, on tokio's blocking pool), but as a
+//! `MyResolve`, so that the layers in this crate can be put in front of it.
+//! Built without the "trust-dns" feature, this and the fixed tables in
+//! [`static_hosts`](crate::static_hosts) are the only resolvers there are.
+//!
+//! Some corporate names only resolve correctly through the operating
+//! system: Windows sends them to particular servers with NRPT rules, and
+//! glibc may hand them to an NSS plugin.  Nothing a custom stack does will
+//! find them, however it's configured.  [`SystemBypass`] sends the names
+//! matching a list, written the way `NO_PROXY` is, straight to the system
+//! resolver, and everything else through the stack.
+
+use crate::context::ResolveContext;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::resolved::ResolvedAddr;
+use crate::routing::CompiledRules;
+use crate::routing::RoutingRules;
+use crate::with_port;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+
+/// Resolves names with the operating system's resolver
+///
+/// Each lookup ties up a thread from tokio's blocking pool until it's done.
+///
+/// ```
+/// # use reqwest_resolve::system::SystemResolver;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(SystemResolver)));
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl MyResolve for SystemResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let addrs = lookup_system(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let addrs = lookup_system(name.as_str()).await?;
+            Ok(addrs
+                .into_iter()
+                .map(|addr| ResolvedAddr {
+                    backend: Cow::Borrowed("system"),
+                    ..ResolvedAddr::unknown(addr)
+                })
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+/// Looks up `name` with the operating system's resolver, on tokio's blocking
+/// pool.
+pub(crate) async fn lookup_system(
+    name: &str,
+) -> Result<Vec<SocketAddr>, Box<dyn StdError + Send + Sync>> {
+    let host = name.to_owned();
+    let addrs = tokio::task::spawn_blocking(move || {
+        (host.as_str(), 0)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+    })
+    .await??;
+    debug!("resolved with the system resolver", name = name, addrs = addrs);
+    Ok(addrs)
+}
+
+/// Sends names matching its rules to the [`SystemResolver`], and the rest
+/// to the inner resolver
+///
+/// The rules are [`CompiledRules<bool>`](CompiledRules), where a name is
+/// sent to the system resolver if the rule it matches says `true`, so that
+/// a more specific rule saying `false` can make an exception.  To bypass the
+/// whole stack, this has to be its outermost layer.  A name that's sent to
+/// the system resolver is looked up there and nowhere else: if the system
+/// can't find it, the lookup fails, rather than trying the stack too.
+///
+/// ```
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::system::SystemBypass;
+/// # use reqwest_resolve::{static_resolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// static STACK: StaticResolver = static_resolver! {
+///     "www.example.com" => ["192.0.2.80"],
+/// };
+/// let my_resolver =
+///     SystemBypass::no_proxy(STACK, ".corp.example.com, .ad.example.net")
+///         .unwrap();
+/// assert!(my_resolver.bypasses("wiki.corp.example.com"));
+/// assert!(!my_resolver.bypasses("www.example.com"));
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct SystemBypass<R> {
+    inner: R,
+    rules: CompiledRules<bool>,
+}
+
+impl<R> SystemBypass<R> {
+    pub fn new(inner: R, rules: CompiledRules<bool>) -> SystemBypass<R> {
+        SystemBypass { inner, rules }
+    }
+
+    /// Sends the names matching any entry of `list`, in the syntax of the
+    /// `NO_PROXY` environment variable (see `RoutingRules::no_proxy`), to
+    /// the system resolver.
+    pub fn no_proxy(
+        inner: R,
+        list: &str,
+    ) -> Result<SystemBypass<R>, ResolveError> {
+        let rules = RoutingRules::new().no_proxy(list, true).compile()?;
+        Ok(SystemBypass::new(inner, rules))
+    }
+
+    /// Returns whether `name` is sent to the system resolver.
+    pub fn bypasses(&self, name: &str) -> bool {
+        self.rules.lookup(name).copied().unwrap_or(false)
+    }
+
+    /// Returns whether `name` is sent to the system resolver, logging it if
+    /// so.
+    fn check(&self, name: &str) -> bool {
+        let bypass = self.bypasses(name);
+        if bypass {
+            debug!("bypassing the resolver stack", name = name);
+        }
+        bypass
+    }
+}
+
+impl<R: MyResolve> MyResolve for SystemBypass<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        if self.check(name.as_str()) {
+            return SystemResolver.resolve(name);
+        }
+        self.inner.resolve(name)
+    }
+
+    fn resolve_with_port(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        port: u16,
+    ) -> MyResolving<'_> {
+        if !self.check(name.as_str()) {
+            return self.inner.resolve_with_port(name, port);
+        }
+        async move {
+            let addrs = lookup_system(name.as_str()).await?;
+            Ok(with_port(Box::new(addrs.into_iter()), port))
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_with_context(
+        &self,
+        name: hyper::client::connect::dns::Name,
+        context: &ResolveContext,
+    ) -> MyResolving<'_> {
+        if !self.bypasses(name.as_str()) {
+            return self.inner.resolve_with_context(name, context);
+        }
+        match context.port() {
+            Some(port) => self.resolve_with_port(name, port),
+            None => self.resolve(name),
+        }
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        if self.check(name.as_str()) {
+            return SystemResolver.resolve_detailed(name);
+        }
+        self.inner.resolve_detailed(name)
+    }
+}
@@ -0,0 +1,176 @@
+//! Keeping track of the background tasks this crate spawns
+//!
+//! Some of this crate's resolvers do work in background tasks: watching for
+//! configuration changes, serving test queries, and driving upstream
+//! connections.  Every one of those tasks is spawned with a name saying what
+//! it's for, and [`running_tasks`] lists the ones still running, which is the
+//! first thing to check when a process seems to be doing more resolver work
+//! than it should.
+//!
+//! The names also show up in tokio-console when this crate's "tokio-console"
+//! feature is on and the program is built with `RUSTFLAGS="--cfg
+//! tokio_unstable"`, which is what Tokio requires for named tasks.
+//! Otherwise, the tasks are spawned normally and only the inventory knows
+//! their names.
+//!
+//! Tasks that trust-dns spawns for a plain `TokioAsyncResolver` don't go
+//! through here, since trust-dns spawns them itself.  The connection tasks
+//! for a `transport::FilteredAsyncResolver` do.
+//!
+//! Every other task belongs to the value that started it (a resolver, a
+//! socket pool, a test server), which keeps it in a [`TaskSet`] and aborts
+//! it when dropped, so dropping a resolver stops all of its background
+//! work.  The connection tasks are the exception: they belong to their
+//! connection, and end when it's closed, which happens once the resolver
+//! using it is dropped.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::task::JoinHandle;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::name_server::RuntimeProvider;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::name_server::Spawn;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::name_server::TokioRuntime;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::proto::error::ProtoError;
+
+/// A background task spawned by this crate that hasn't finished yet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TaskInfo {
+    /// identifies the task for the life of the process (this isn't Tokio's
+    /// task ID, which is unstable)
+    pub id: u64,
+    /// what the task is for, like "split-dns watcher"
+    pub name: String,
+    pub started: Instant,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static RUNNING: Mutex<BTreeMap<u64, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// Returns the background tasks this crate has spawned that are still
+/// running, oldest first.
+pub fn running_tasks() -> Vec<TaskInfo> {
+    RUNNING.lock().unwrap().values().cloned().collect()
+}
+
+/// Removes a task from the inventory when its future is dropped, which
+/// happens when it finishes, is aborted, or its runtime shuts down
+struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Spawns `future` as a task called `name`, and lists it in the inventory
+/// until it's done.
+pub(crate) fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let info = TaskInfo { id, name: name.to_owned(), started: Instant::now() };
+    RUNNING.lock().unwrap().insert(id, info);
+
+    let registration = Registration(id);
+    let future = async move {
+        let _registration = registration;
+        future.await
+    };
+    spawn(name, future)
+}
+
+#[cfg(all(tokio_unstable, feature = "tokio-console"))]
+fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("failed to spawn task")
+}
+
+#[cfg(not(all(tokio_unstable, feature = "tokio-console")))]
+fn spawn<F>(_name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future)
+}
+
+/// Background tasks owned by whatever holds the set, which are aborted when
+/// it's dropped
+#[derive(Default)]
+pub(crate) struct TaskSet {
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl TaskSet {
+    /// Spawns `future` with [`spawn_named`] as one of this set's tasks.
+    pub(crate) fn spawn<F>(&self, name: &str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut handles = self.handles.lock().unwrap();
+        // Forget tasks that are done, so a set that keeps spawning short
+        // tasks doesn't keep growing.
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(spawn_named(name, future));
+    }
+}
+
+impl Drop for TaskSet {
+    fn drop(&mut self) {
+        let handles = self
+            .handles
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for handle in handles.iter() {
+            handle.abort();
+        }
+    }
+}
+
+/// trust-dns's Tokio runtime, except that background tasks are spawned with
+/// [`spawn_named`]
+#[cfg(feature = "trust-dns")]
+#[derive(Clone, Copy)]
+pub(crate) struct NamedTokioRuntime;
+
+#[cfg(feature = "trust-dns")]
+impl RuntimeProvider for NamedTokioRuntime {
+    type Handle = NamedTokioHandle;
+    type Tcp = <TokioRuntime as RuntimeProvider>::Tcp;
+    type Timer = <TokioRuntime as RuntimeProvider>::Timer;
+    type Udp = <TokioRuntime as RuntimeProvider>::Udp;
+}
+
+#[cfg(feature = "trust-dns")]
+#[derive(Clone, Copy)]
+pub(crate) struct NamedTokioHandle;
+
+#[cfg(feature = "trust-dns")]
+impl Spawn for NamedTokioHandle {
+    fn spawn_bg<F>(&mut self, future: F)
+    where
+        F: Future<Output = Result<(), ProtoError>> + Send + 'static,
+    {
+        // trust-dns reports connection errors to the queries waiting on the
+        // connection, so there's nothing more to do with them here.
+        spawn_named("trust-dns connection", async move {
+            let _ = future.await;
+        });
+    }
+}
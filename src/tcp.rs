@@ -0,0 +1,100 @@
+//! Making sure large answers can come back over TCP
+//!
+//! When an answer doesn't fit in a UDP response, the server sets the
+//! truncation bit and the client is supposed to ask again over TCP.
+//! trust-dns does that, but only to name servers that appear in its
+//! configuration with `Protocol::Tcp` as well as `Protocol::Udp`.  A config
+//! that lists only UDP entries (like one built by hand with
+//! `NameServerConfig::new(addr, Protocol::Udp)`) quietly gets the truncated
+//! answer instead: typically a partial or empty set of SVCB or TXT records,
+//! with no error.
+//!
+//! [`with_tcp_mode`] fixes up a config so that can't happen, or so that every
+//! query goes over TCP, for networks where UDP DNS is unreliable.
+
+use std::net::SocketAddr;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+
+/// How queries to plain-DNS servers use TCP
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TcpMode {
+    /// Query over UDP, and retry over TCP when the response is truncated.
+    /// (Set `ResolverOpts::try_tcp_on_error` to also retry over TCP when
+    /// UDP fails outright.)
+    #[default]
+    Fallback,
+    /// Query only over TCP.
+    Always,
+}
+
+/// Returns a copy of `config` with its name servers adjusted for `mode`
+///
+/// With [`TcpMode::Fallback`], any server listed for UDP but not TCP gets a
+/// TCP entry too.  With [`TcpMode::Always`], each UDP entry is replaced
+/// with a TCP one.  Either way, servers using other protocols (TLS, HTTPS)
+/// are left alone.
+///
+/// ```
+/// # use reqwest_resolve::tcp::{with_tcp_mode, TcpMode};
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig,
+/// # };
+/// let mut config = ResolverConfig::new();
+/// config.add_name_server(NameServerConfig::new(
+///     "192.0.2.53:53".parse().unwrap(),
+///     Protocol::Udp,
+/// ));
+///
+/// let fallback = with_tcp_mode(&config, TcpMode::Fallback);
+/// let protocols: Vec<_> =
+///     fallback.name_servers().iter().map(|ns| ns.protocol).collect();
+/// assert_eq!(protocols, vec![Protocol::Udp, Protocol::Tcp]);
+///
+/// let tcp_only = with_tcp_mode(&config, TcpMode::Always);
+/// let protocols: Vec<_> =
+///     tcp_only.name_servers().iter().map(|ns| ns.protocol).collect();
+/// assert_eq!(protocols, vec![Protocol::Tcp]);
+/// ```
+pub fn with_tcp_mode(config: &ResolverConfig, mode: TcpMode) -> ResolverConfig {
+    let servers = config.name_servers();
+    let mut adjusted = ResolverConfig::from_parts(
+        config.domain().cloned(),
+        config.search().to_vec(),
+        Vec::new(),
+    );
+    for name_server in servers {
+        let addr = name_server.socket_addr;
+        let needs_tcp =
+            !has_tcp(servers, addr) && !has_tcp(adjusted.name_servers(), addr);
+        match (name_server.protocol, mode) {
+            (Protocol::Udp, TcpMode::Fallback) => {
+                adjusted.add_name_server(name_server.clone());
+                if needs_tcp {
+                    adjusted.add_name_server(as_tcp(name_server));
+                }
+            }
+            (Protocol::Udp, TcpMode::Always) => {
+                if needs_tcp {
+                    adjusted.add_name_server(as_tcp(name_server));
+                }
+            }
+            _ => adjusted.add_name_server(name_server.clone()),
+        }
+    }
+
+    adjusted
+}
+
+fn has_tcp(servers: &[NameServerConfig], addr: SocketAddr) -> bool {
+    servers
+        .iter()
+        .any(|ns| ns.socket_addr == addr && ns.protocol == Protocol::Tcp)
+}
+
+fn as_tcp(name_server: &NameServerConfig) -> NameServerConfig {
+    NameServerConfig { protocol: Protocol::Tcp, ..name_server.clone() }
+}
@@ -0,0 +1,225 @@
+//! Resolving on behalf of several tenants
+//!
+//! Services that make outbound requests for their customers (webhooks,
+//! integrations, and the like) usually need each customer's lookups kept
+//! apart: one tenant mustn't be able to see what another has looked up
+//! through a shared cache, and each may have its own restrictions on what it
+//! can reach and which servers it uses.
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::special_use::in_domain;
+use crate::special_use::normalize;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::RwLock;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::TokioAsyncResolver;
+
+tokio::task_local! {
+    static CURRENT_TENANT: String;
+}
+
+/// Runs `future` with `tenant` as the current tenant
+///
+/// Any lookups a [`TenantResolver`] does while `future` is being polled use
+/// that tenant's policy.
+pub async fn with_tenant<F: Future>(tenant: &str, future: F) -> F::Output {
+    CURRENT_TENANT.scope(tenant.to_owned(), future).await
+}
+
+/// Returns the tenant in scope (see [`with_tenant`]), if any.
+pub fn current_tenant() -> Option<String> {
+    CURRENT_TENANT.try_with(|tenant| tenant.clone()).ok()
+}
+
+/// What one tenant is allowed to resolve, and how
+///
+/// Every policy has its own `TokioAsyncResolver`, and so its own cache and
+/// its own upstream servers.
+pub struct TenantPolicy {
+    resolver: Arc<TokioAsyncResolver>,
+    /// (domain, allowed) pairs, with domains normalized with
+    /// `special_use::normalize()`
+    rules: Vec<(String, bool)>,
+}
+
+impl TenantPolicy {
+    /// Builds a policy that sends this tenant's lookups to `config`'s
+    /// servers and allows every name.
+    pub fn new(
+        config: ResolverConfig,
+        options: ResolverOpts,
+    ) -> Result<TenantPolicy, trust_dns_resolver::error::ResolveError> {
+        let resolver = TokioAsyncResolver::tokio(config, options)?;
+        Ok(TenantPolicy { resolver: Arc::new(resolver), rules: Vec::new() })
+    }
+
+    /// Allows names under `domain`.  Once any domain is allowed, names that
+    /// aren't under an allowed domain are blocked.
+    pub fn allow(self, domain: &str) -> TenantPolicy {
+        self.with_rule(domain, true)
+    }
+
+    /// Blocks names under `domain`.
+    pub fn block(self, domain: &str) -> TenantPolicy {
+        self.with_rule(domain, false)
+    }
+
+    fn with_rule(mut self, domain: &str, allowed: bool) -> TenantPolicy {
+        let domain = normalize(domain).into_owned();
+        self.rules.retain(|(d, _)| *d != domain);
+        self.rules.push((domain, allowed));
+        self
+    }
+
+    /// Returns whether this policy allows looking up `name`
+    ///
+    /// As with `SpecialUseResolver`, the rule for the longest matching
+    /// domain wins.  That makes it possible to block "example.com" while
+    /// allowing "api.example.com", or the other way around.
+    pub fn allows(&self, name: &str) -> bool {
+        let name = normalize(name);
+        match self
+            .rules
+            .iter()
+            .filter(|(domain, _)| in_domain(&name, domain))
+            .max_by_key(|(domain, _)| domain.len())
+        {
+            Some((_, allowed)) => *allowed,
+            None => !self.rules.iter().any(|(_, allowed)| *allowed),
+        }
+    }
+}
+
+/// Resolves names according to the policy of whichever tenant is in scope
+///
+/// Lookups with no tenant in scope, or with a tenant that hasn't been
+/// configured, fail with [`ResolveError::UnknownTenant`] rather than falling
+/// back to some shared configuration.  Names a tenant's policy doesn't allow
+/// fail with [`ResolveError::BlockedForTenant`].
+///
+/// reqwest keeps idle connections in a pool keyed by host, and a connection
+/// that one tenant made can be reused for another.  Give each tenant its own
+/// `Client` (they can all share one `TenantResolver`) so that the policy is
+/// applied to every connection.  The tenant has to be in scope around the
+/// whole request, since that's where the lookup happens:
+///
+/// ```no_run
+/// # async fn example() {
+/// # use reqwest_resolve::tenant::{with_tenant, TenantPolicy, TenantResolver};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let tenants = TenantResolver::new();
+/// tenants.insert_tenant(
+///     "acme",
+///     TenantPolicy::new(ResolverConfig::cloudflare(), ResolverOpts::default())
+///         .unwrap()
+///         .allow("hooks.acme.example"),
+/// );
+/// let client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(tenants)))
+///     .build()
+///     .unwrap();
+/// let _response = with_tenant(
+///     "acme",
+///     client.post("https://hooks.acme.example/deliver").send(),
+/// )
+/// .await;
+/// # }
+/// ```
+#[derive(Default)]
+pub struct TenantResolver {
+    tenants: RwLock<BTreeMap<String, Arc<TenantPolicy>>>,
+}
+
+impl TenantResolver {
+    /// Returns a resolver with no tenants configured.
+    pub fn new() -> TenantResolver {
+        TenantResolver::default()
+    }
+
+    /// Sets the policy for `tenant`, replacing any it had before.
+    pub fn insert_tenant(&self, tenant: &str, policy: TenantPolicy) {
+        let mut tenants = self.tenants.write().unwrap();
+        tenants.insert(tenant.to_owned(), Arc::new(policy));
+    }
+
+    /// Removes `tenant`'s policy, so that its lookups fail from now on.
+    /// Returns whether it was configured.
+    pub fn remove_tenant(&self, tenant: &str) -> bool {
+        self.tenants.write().unwrap().remove(tenant).is_some()
+    }
+
+    /// Returns the configured tenants.
+    pub fn tenants(&self) -> Vec<String> {
+        self.tenants.read().unwrap().keys().cloned().collect()
+    }
+}
+
+impl TenantResolver {
+    /// Returns the current tenant's policy, if it allows resolving `name`.
+    fn policy_for(
+        &self,
+        name: &str,
+    ) -> Result<Arc<TenantPolicy>, ResolveError> {
+        let tenant = current_tenant();
+        let policy = tenant.as_ref().and_then(|tenant| {
+            self.tenants.read().unwrap().get(tenant).map(Arc::clone)
+        });
+
+        match (tenant, policy) {
+            (tenant, None) => {
+                debug!("unknown tenant", name = name, tenant = tenant);
+                Err(ResolveError::UnknownTenant {
+                    name: name.to_owned(),
+                    tenant,
+                })
+            }
+            (Some(tenant), Some(policy)) if !policy.allows(name) => {
+                debug!("name blocked for tenant", name = name, tenant = tenant);
+                Err(ResolveError::BlockedForTenant {
+                    name: name.to_owned(),
+                    tenant,
+                })
+            }
+            (_, Some(policy)) => Ok(policy),
+        }
+    }
+}
+
+impl MyResolve for TenantResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let result = self.policy_for(name.as_str());
+        async move {
+            let policy = result?;
+            do_resolve(&policy.resolver, name).await
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let result = self.policy_for(name.as_str());
+        async move {
+            let policy = result?;
+            do_resolve_detailed(&policy.resolver, name).await
+        }
+        .boxed()
+    }
+}
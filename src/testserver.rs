@@ -0,0 +1,695 @@
+//! An in-process DNS server for tests
+//!
+//! Checking how a resolver handles truncation, SERVFAIL, timeouts, or CNAME
+//! chains means going all the way through trust-dns to a real server.
+//! [`TestServer`] is that server: it answers UDP and TCP queries on an
+//! ephemeral port on the loopback address, from a [`TestZone`] that the test
+//! can change while it runs.  Point a resolver at it with
+//! [`TestServer::resolver_config`].
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::TransportFuture;
+use crate::fixtures::Fixture;
+use crate::logging::debug;
+use crate::special_use::normalize;
+use crate::tasks::TaskSet;
+use futures::future::FutureExt;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::MessageType;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::Record;
+use trust_dns_resolver::proto::rr::RecordType;
+use trust_dns_resolver::Name;
+
+/// The TTL of records added with [`TestZone::add_addr`] and
+/// [`TestZone::add_cname`]
+pub const DEFAULT_TEST_TTL: u32 = 60;
+
+/// How many CNAMEs in a row the server follows when answering
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The largest UDP response allowed without EDNS
+const CLASSIC_UDP_PAYLOAD: usize = 512;
+
+/// How long the server waits after a socket error before trying again, so
+/// that an error that persists (like running out of file descriptors)
+/// doesn't keep it spinning
+const SOCKET_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Something other than a normal answer for queries about one name
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Answer with SERVFAIL.
+    ServFail,
+    /// Answer with REFUSED.
+    Refused,
+    /// Don't answer at all.
+    Drop,
+    /// Over UDP, answer with the truncation bit set and no records, so the
+    /// client has to retry over TCP.  Answer normally over TCP.
+    Truncate,
+    /// Answer normally, after this long.
+    Delay(Duration),
+}
+
+/// The records and faults a [`TestServer`] answers from
+///
+/// Names are matched case-insensitively, ignoring any trailing dot.  A query
+/// for a name with no records at all gets NXDOMAIN; a query for a name that
+/// has records, but not of the requested type, gets an empty NOERROR answer.
+/// CNAMEs are followed within the zone, the way an authoritative server
+/// would.
+#[derive(Clone, Debug, Default)]
+pub struct TestZone {
+    records: BTreeMap<String, Vec<Record>>,
+    faults: BTreeMap<String, Fault>,
+}
+
+impl TestZone {
+    pub fn new() -> TestZone {
+        TestZone::default()
+    }
+
+    /// Returns a zone with an A or AAAA record for every address in
+    /// `fixture`.
+    pub fn from_fixture(fixture: &Fixture, ttl: u32) -> TestZone {
+        let mut zone = TestZone::new();
+        for (name, addrs) in fixture.iter() {
+            for addr in addrs {
+                zone.add_record(address_record(name, *addr, ttl));
+            }
+        }
+        zone
+    }
+
+    /// Adds an A or AAAA record, depending on the kind of address.
+    ///
+    /// Panics if `name` isn't a valid DNS name.
+    pub fn add_addr(&mut self, name: &str, addr: IpAddr) {
+        self.add_record(address_record(name, addr, DEFAULT_TEST_TTL));
+    }
+
+    /// Adds a CNAME record.
+    ///
+    /// Panics if `name` or `target` isn't a valid DNS name.
+    pub fn add_cname(&mut self, name: &str, target: &str) {
+        let rdata = RData::CNAME(fqdn(target));
+        self.add_record(Record::from_rdata(
+            fqdn(name),
+            DEFAULT_TEST_TTL,
+            rdata,
+        ));
+    }
+
+    /// Adds any kind of record.
+    pub fn add_record(&mut self, record: Record) {
+        let name = normalize(&record.name().to_ascii()).into_owned();
+        self.records.entry(name).or_default().push(record);
+    }
+
+    /// Removes every record for `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.records.remove(&*normalize(name));
+    }
+
+    /// Makes queries for `name` fail (or be slow) in the way `fault` says,
+    /// until [`TestZone::clear_fault`] is called for it.
+    pub fn set_fault(&mut self, name: &str, fault: Fault) {
+        self.faults.insert(normalize(name).into_owned(), fault);
+    }
+
+    pub fn clear_fault(&mut self, name: &str) {
+        self.faults.remove(&*normalize(name));
+    }
+
+    /// Builds the response to `request`, or returns `None` if there shouldn't
+    /// be one, along with how long to wait before sending it.
+    fn respond(
+        &self,
+        request: &Message,
+        protocol: Protocol,
+    ) -> Option<(Message, Option<Duration>)> {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_authoritative(true)
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true);
+        response.add_queries(request.queries().iter().cloned());
+
+        let [query] = request.queries() else {
+            response.set_response_code(ResponseCode::FormErr);
+            return Some((response, None));
+        };
+        let qname = normalize(&query.name().to_ascii()).into_owned();
+
+        let mut delay = None;
+        match self.faults.get(&qname) {
+            Some(Fault::ServFail) => {
+                response.set_response_code(ResponseCode::ServFail);
+                return Some((response, None));
+            }
+            Some(Fault::Refused) => {
+                response.set_response_code(ResponseCode::Refused);
+                return Some((response, None));
+            }
+            Some(Fault::Drop) => return None,
+            Some(Fault::Truncate) if protocol == Protocol::Udp => {
+                response.set_truncated(true);
+                return Some((response, None));
+            }
+            Some(Fault::Truncate) => (),
+            Some(Fault::Delay(duration)) => delay = Some(*duration),
+            None => (),
+        }
+
+        if !self.records.contains_key(&qname) {
+            response.set_response_code(ResponseCode::NXDomain);
+            return Some((response, delay));
+        }
+
+        let mut name = qname;
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = self.records.get(&name) else {
+                break;
+            };
+            let matching: Vec<_> = records
+                .iter()
+                .filter(|record| record.record_type() == query.query_type())
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                response.add_answers(matching);
+                break;
+            }
+            let cname = records.iter().find_map(|record| match record.data() {
+                Some(RData::CNAME(target)) => Some((record, target)),
+                _ => None,
+            });
+            let Some((record, target)) = cname else {
+                break;
+            };
+            response.add_answer(record.clone());
+            name = normalize(&target.to_ascii()).into_owned();
+        }
+
+        Some((response, delay))
+    }
+}
+
+fn fqdn(name: &str) -> Name {
+    let mut name = Name::from_ascii(name)
+        .unwrap_or_else(|_| panic!("invalid DNS name: {:?}", name));
+    name.set_fqdn(true);
+    name
+}
+
+fn address_record(name: &str, addr: IpAddr, ttl: u32) -> Record {
+    let rdata = match addr {
+        IpAddr::V4(addr) => RData::A(addr),
+        IpAddr::V6(addr) => RData::AAAA(addr),
+    };
+    Record::from_rdata(fqdn(name), ttl, rdata)
+}
+
+/// A query the server received
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoggedQuery {
+    /// the name, lowercased and without a trailing dot
+    pub name: String,
+    pub record_type: RecordType,
+    pub protocol: Protocol,
+}
+
+/// State shared by the server's tasks
+struct Shared {
+    zone: Mutex<TestZone>,
+    queries: Mutex<Vec<LoggedQuery>>,
+}
+
+impl Shared {
+    fn new(zone: TestZone) -> Shared {
+        Shared { zone: Mutex::new(zone), queries: Mutex::new(Vec::new()) }
+    }
+
+    /// Logs a query and builds the response, if any.
+    async fn answer(
+        &self,
+        request: &Message,
+        protocol: Protocol,
+    ) -> Option<Message> {
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+
+        {
+            let mut queries = self.queries.lock().unwrap();
+            queries.extend(request.queries().iter().map(|query| LoggedQuery {
+                name: normalize(&query.name().to_ascii()).into_owned(),
+                record_type: query.query_type(),
+                protocol,
+            }));
+        }
+
+        let (response, delay) =
+            self.zone.lock().unwrap().respond(request, protocol)?;
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        Some(response)
+    }
+
+    /// Parses a query and builds the bytes of the response, if any.
+    async fn handle(
+        &self,
+        bytes: &[u8],
+        protocol: Protocol,
+    ) -> Option<Vec<u8>> {
+        let request = Message::from_vec(bytes).ok()?;
+        let mut response = self.answer(&request, protocol).await?;
+
+        let mut bytes = response.to_vec().ok()?;
+        if protocol == Protocol::Udp {
+            let limit = request
+                .extensions()
+                .as_ref()
+                .map_or(CLASSIC_UDP_PAYLOAD, |edns| {
+                    usize::from(edns.max_payload()).max(CLASSIC_UDP_PAYLOAD)
+                });
+            if bytes.len() > limit {
+                response.take_answers();
+                response.take_name_servers();
+                response.take_additionals();
+                response.set_truncated(true);
+                bytes = response.to_vec().ok()?;
+            }
+        }
+        Some(bytes)
+    }
+}
+
+/// A DNS server on the loopback address, answering from a [`TestZone`]
+///
+/// The server runs on the Tokio runtime that started it, and stops when
+/// it's dropped.
+///
+/// Remember that trust-dns caches answers (including failures) for the
+/// lifetime of the resolver, so a test that changes the zone partway through
+/// usually wants `ResolverOpts::cache_size` set to 0.
+///
+/// ```no_run
+/// # use reqwest_resolve::testserver::{Fault, TestServer, TestZone};
+/// # use reqwest_resolve::CustomDnsResolver;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::ResolverOpts;
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut zone = TestZone::new();
+/// zone.add_addr("api.test", "127.0.0.1".parse()?);
+/// let server = TestServer::start(zone).await?;
+///
+/// let resolver = TokioAsyncResolver::tokio(
+///     server.resolver_config(),
+///     ResolverOpts::default(),
+/// )?;
+/// let client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(CustomDnsResolver::new(resolver)))
+///     .build()?;
+/// # let _ = client;
+///
+/// // Later, make the name fail.
+/// server.update(|zone| zone.set_fault("api.test", Fault::ServFail));
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    /// the tasks serving UDP and TCP, which stop when the server is dropped
+    _tasks: TaskSet,
+}
+
+impl TestServer {
+    /// Starts answering queries on an ephemeral port on 127.0.0.1, over both
+    /// UDP and TCP.
+    pub async fn start(zone: TestZone) -> io::Result<TestServer> {
+        let (udp, tcp) = bind_pair().await?;
+        let addr = udp.local_addr()?;
+        let shared = Arc::new(Shared::new(zone));
+
+        let tasks = TaskSet::default();
+        tasks.spawn("testserver udp", serve_udp(Arc::new(udp), shared.clone()));
+        tasks.spawn("testserver tcp", serve_tcp(tcp, shared.clone()));
+        Ok(TestServer { addr, shared, _tasks: tasks })
+    }
+
+    /// Returns the address the server is listening on (for both UDP and
+    /// TCP).
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a config with just this server, over UDP and TCP.
+    pub fn resolver_config(&self) -> ResolverConfig {
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(self.addr, Protocol::Udp));
+        config.add_name_server(NameServerConfig::new(self.addr, Protocol::Tcp));
+        config
+    }
+
+    /// Changes the zone.  Queries that arrive afterwards see the change.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut TestZone),
+    {
+        f(&mut self.shared.zone.lock().unwrap());
+    }
+
+    /// Returns every query received so far, oldest first.
+    pub fn queries(&self) -> Vec<LoggedQuery> {
+        self.shared.queries.lock().unwrap().clone()
+    }
+}
+
+/// Answers queries from a [`TestZone`] without going through a socket
+///
+/// This is the same as a [`TestServer`], but as a [`DnsTransport`], so
+/// tests don't need to open any sockets.  Faults work the same way,
+/// including [`Fault::Truncate`] for servers whose protocol is UDP.  The
+/// servers in the resolver's configuration are only used for their
+/// protocol, so any address will do.
+///
+/// ```
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::testserver::{InMemoryTransport, TestZone};
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut zone = TestZone::new();
+/// zone.add_addr("api.test", "127.0.0.1".parse()?);
+/// let transport = InMemoryTransport::new(zone);
+///
+/// let resolver = transport_resolver(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+///     transport.clone(),
+/// )?;
+/// let _client = reqwest::ClientBuilder::new().dns_resolver(Arc::new(
+///     ResolveAdapter::new(TransportDnsResolver::new(resolver)),
+/// ));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct InMemoryTransport {
+    shared: Arc<Shared>,
+}
+
+impl InMemoryTransport {
+    pub fn new(zone: TestZone) -> InMemoryTransport {
+        InMemoryTransport { shared: Arc::new(Shared::new(zone)) }
+    }
+
+    /// Changes the zone.  Queries sent afterwards see the change.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut TestZone),
+    {
+        f(&mut self.shared.zone.lock().unwrap());
+    }
+
+    /// Returns every query sent so far, oldest first.
+    pub fn queries(&self) -> Vec<LoggedQuery> {
+        self.shared.queries.lock().unwrap().clone()
+    }
+}
+
+impl DnsTransport for InMemoryTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let shared = Arc::clone(&self.shared);
+        let protocol = server.protocol;
+        async move {
+            match shared.answer(&query, protocol).await {
+                Some(response) => Ok(response),
+                // Like a dropped UDP packet: the resolver times out.
+                None => futures::future::pending().await,
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Binds a UDP socket and a TCP listener to the same ephemeral port.
+async fn bind_pair() -> io::Result<(UdpSocket, TcpListener)> {
+    // The port the OS picks for TCP may already be taken for UDP, so try a
+    // few times.
+    let mut last_error = None;
+    for _ in 0..10 {
+        let tcp = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = tcp.local_addr()?;
+        match UdpSocket::bind(addr).await {
+            Ok(udp) => return Ok((udp, tcp)),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, shared: Arc<Shared>) {
+    // The queries' tasks end with this one, when the server is dropped.
+    let queries = TaskSet::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                debug!("testserver udp receive failed", error = error);
+                tokio::time::sleep(SOCKET_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let request = buf[..len].to_vec();
+        let socket = socket.clone();
+        let shared = shared.clone();
+        // Each query gets its own task so that a delayed answer doesn't hold
+        // up the others.
+        queries.spawn("testserver udp query", async move {
+            if let Some(response) = shared.handle(&request, Protocol::Udp).await
+            {
+                let _ = socket.send_to(&response, peer).await;
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, shared: Arc<Shared>) {
+    // Likewise for the connections' tasks, which would otherwise keep
+    // answering on connections left open after the server was dropped.
+    let connections = TaskSet::default();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                debug!("testserver tcp accept failed", error = error);
+                tokio::time::sleep(SOCKET_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        connections.spawn(
+            "testserver tcp connection",
+            serve_tcp_connection(stream, shared.clone()),
+        );
+    }
+}
+
+/// Answers length-prefixed queries on one connection until the client closes
+/// it.
+async fn serve_tcp_connection(mut stream: TcpStream, shared: Arc<Shared>) {
+    loop {
+        let Ok(len) = stream.read_u16().await else {
+            return;
+        };
+        let mut request = vec![0u8; usize::from(len)];
+        if stream.read_exact(&mut request).await.is_err() {
+            return;
+        }
+        let Some(response) = shared.handle(&request, Protocol::Tcp).await
+        else {
+            continue;
+        };
+        let Ok(len) = u16::try_from(response.len()) else {
+            continue;
+        };
+        if stream.write_u16(len).await.is_err()
+            || stream.write_all(&response).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fault;
+    use super::InMemoryTransport;
+    use super::LoggedQuery;
+    use super::TestServer;
+    use super::TestZone;
+    use crate::dns_transport::transport_resolver;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use trust_dns_resolver::config::LookupIpStrategy;
+    use trust_dns_resolver::config::NameServerConfig;
+    use trust_dns_resolver::config::Protocol;
+    use trust_dns_resolver::config::ResolverConfig;
+    use trust_dns_resolver::config::ResolverOpts;
+    use trust_dns_resolver::proto::rr::RecordType;
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    fn zone() -> TestZone {
+        let mut zone = TestZone::new();
+        zone.add_addr("api.test", "192.0.2.1".parse().unwrap());
+        zone.add_cname("www.test", "web.test");
+        zone.add_cname("web.test", "api.test");
+        zone
+    }
+
+    /// Options for checking every query reaches the server: no cache, and
+    /// only A records, so each lookup is one query
+    fn options() -> ResolverOpts {
+        let mut options = ResolverOpts::default();
+        options.cache_size = 0;
+        options.ip_strategy = LookupIpStrategy::Ipv4Only;
+        options.timeout = Duration::from_millis(500);
+        options.attempts = 1;
+        options
+    }
+
+    fn protocols(queries: &[LoggedQuery], name: &str) -> Vec<Protocol> {
+        queries
+            .iter()
+            .filter(|query| query.name == name)
+            .map(|query| query.protocol)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn serves_zone() {
+        let server = TestServer::start(zone()).await.unwrap();
+        let resolver =
+            TokioAsyncResolver::tokio(server.resolver_config(), options())
+                .unwrap();
+        let expected: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let addrs: Vec<IpAddr> =
+            resolver.lookup_ip("api.test.").await.unwrap().iter().collect();
+        assert_eq!(addrs, [expected]);
+        // The server follows the chain itself, so this is one query.
+        let addrs: Vec<IpAddr> =
+            resolver.lookup_ip("WWW.test.").await.unwrap().iter().collect();
+        assert_eq!(addrs, [expected]);
+        assert!(resolver.lookup_ip("missing.test.").await.is_err());
+
+        server.update(|zone| zone.set_fault("api.test", Fault::ServFail));
+        assert!(resolver.lookup_ip("api.test.").await.is_err());
+        server.update(|zone| {
+            zone.clear_fault("api.test");
+            zone.add_addr("api.test", "192.0.2.2".parse().unwrap());
+        });
+        let addrs: Vec<IpAddr> =
+            resolver.lookup_ip("api.test.").await.unwrap().iter().collect();
+        assert_eq!(addrs.len(), 2);
+
+        let queries = server.queries();
+        assert_eq!(protocols(&queries, "www.test"), [Protocol::Udp]);
+        assert!(queries.iter().all(|query| query.record_type == RecordType::A));
+    }
+
+    #[tokio::test]
+    async fn truncation_retries_over_tcp() {
+        let mut zone = zone();
+        zone.set_fault("api.test", Fault::Truncate);
+        let server = TestServer::start(zone).await.unwrap();
+        let resolver =
+            TokioAsyncResolver::tokio(server.resolver_config(), options())
+                .unwrap();
+
+        let addrs: Vec<IpAddr> =
+            resolver.lookup_ip("api.test.").await.unwrap().iter().collect();
+        assert_eq!(addrs, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            protocols(&server.queries(), "api.test"),
+            [Protocol::Udp, Protocol::Tcp]
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_queries_time_out() {
+        let mut zone = zone();
+        zone.set_fault("api.test", Fault::Drop);
+        let server = TestServer::start(zone).await.unwrap();
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(
+            server.addr(),
+            Protocol::Udp,
+        ));
+        let mut options = options();
+        options.timeout = Duration::from_millis(50);
+        let resolver = TokioAsyncResolver::tokio(config, options).unwrap();
+
+        assert!(resolver.lookup_ip("api.test.").await.is_err());
+        // trust-dns may retry, but every query went unanswered.
+        let protocols = protocols(&server.queries(), "api.test");
+        assert!(!protocols.is_empty());
+        assert!(protocols.iter().all(|protocol| *protocol == Protocol::Udp));
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        let mut zone = zone();
+        zone.set_fault("www.test", Fault::Truncate);
+        let transport = InMemoryTransport::new(zone);
+        // The addresses are never used, only the protocols.
+        let mut config = ResolverConfig::new();
+        let addr = "192.0.2.53:53".parse().unwrap();
+        config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+        config.add_name_server(NameServerConfig::new(addr, Protocol::Tcp));
+        let resolver =
+            transport_resolver(config, options(), transport.clone()).unwrap();
+
+        let addrs: Vec<IpAddr> =
+            resolver.lookup_ip("www.test.").await.unwrap().iter().collect();
+        assert_eq!(addrs, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            protocols(&transport.queries(), "www.test"),
+            [Protocol::Udp, Protocol::Tcp]
+        );
+
+        transport.update(|zone| zone.remove("api.test"));
+        assert!(resolver.lookup_ip("www.test.").await.is_err());
+    }
+}
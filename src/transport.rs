@@ -0,0 +1,233 @@
+//! Hooking into the queries trust-dns sends upstream
+//!
+//! `TokioAsyncResolver` builds and sends its queries internally, which leaves
+//! no way to change what goes out on the wire or to check what comes back.
+//! trust-dns does let callers supply their own `ConnectionProvider`, though,
+//! and [`FilteredConnectionProvider`] uses that to run every query and
+//! response to every upstream server through a list of [`QueryFilter`]s.
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::global::overridable;
+use crate::logging::debug;
+use crate::tasks::NamedTokioHandle;
+use crate::tasks::NamedTokioRuntime;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::Protocol;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::name_server::ConnectionProvider;
+use trust_dns_resolver::name_server::GenericConnectionProvider;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::xfer::DnsRequest;
+use trust_dns_resolver::proto::xfer::DnsResponse;
+use trust_dns_resolver::proto::DnsHandle;
+use trust_dns_resolver::AsyncResolver;
+use trust_dns_resolver::TokioConnection;
+use trust_dns_resolver::TokioConnectionProvider;
+
+/// The upstream server a query is being sent to
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+}
+
+/// Inspects and modifies queries on their way to an upstream server, and
+/// responses on their way back
+///
+/// Both methods do nothing by default.  With several filters installed,
+/// queries pass through them in order and responses in reverse order.
+pub trait QueryFilter: Send + Sync + 'static {
+    /// Called with each query just before it's sent
+    fn on_request(&self, _upstream: &Upstream, _request: &mut Message) {}
+
+    /// Called with each response, along with the query it answers (as it was
+    /// sent, after every filter's `on_request`)
+    ///
+    /// Returning an error discards the response, and trust-dns treats it
+    /// like any other failure to get an answer from this server.
+    fn on_response(
+        &self,
+        _upstream: &Upstream,
+        _request: &Message,
+        _response: &mut Message,
+    ) -> Result<(), ResolveError> {
+        Ok(())
+    }
+}
+
+type Filters = Arc<Vec<Arc<dyn QueryFilter>>>;
+
+/// An `AsyncResolver` whose queries go through [`QueryFilter`]s
+pub type FilteredAsyncResolver =
+    AsyncResolver<FilteredConnection, FilteredConnectionProvider>;
+
+/// Builds a resolver like `TokioAsyncResolver::tokio()` does, but with every
+/// query and response passing through `filters`.
+pub fn filtered_resolver(
+    config: ResolverConfig,
+    options: ResolverOpts,
+    filters: Vec<Arc<dyn QueryFilter>>,
+) -> Result<FilteredAsyncResolver, ResolveError> {
+    AsyncResolver::new_with_conn(
+        config,
+        options,
+        FilteredConnectionProvider::new(filters),
+    )
+}
+
+/// Makes trust-dns's usual Tokio connections, wrapped so that their queries
+/// go through [`QueryFilter`]s
+///
+/// The tasks that drive the connections are listed in
+/// [`tasks::running_tasks`](crate::tasks::running_tasks).
+#[derive(Clone)]
+pub struct FilteredConnectionProvider {
+    inner: GenericConnectionProvider<NamedTokioRuntime>,
+    filters: Filters,
+}
+
+impl FilteredConnectionProvider {
+    pub fn new(
+        filters: Vec<Arc<dyn QueryFilter>>,
+    ) -> FilteredConnectionProvider {
+        FilteredConnectionProvider {
+            inner: GenericConnectionProvider::new(NamedTokioHandle),
+            filters: Arc::new(filters),
+        }
+    }
+}
+
+impl ConnectionProvider for FilteredConnectionProvider {
+    type Conn = FilteredConnection;
+    type FutureConn = Pin<
+        Box<
+            dyn Future<Output = Result<FilteredConnection, ResolveError>>
+                + Send,
+        >,
+    >;
+    type Time = <TokioConnectionProvider as ConnectionProvider>::Time;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Self::FutureConn {
+        let upstream = Arc::new(Upstream {
+            addr: config.socket_addr,
+            protocol: config.protocol,
+        });
+        let filters = Arc::clone(&self.filters);
+        self.inner
+            .new_connection(config, options)
+            .map(move |inner| {
+                Ok(FilteredConnection { inner: inner?, upstream, filters })
+            })
+            .boxed()
+    }
+}
+
+/// A connection to one upstream server, made by
+/// [`FilteredConnectionProvider`]
+#[derive(Clone)]
+pub struct FilteredConnection {
+    inner: TokioConnection,
+    upstream: Arc<Upstream>,
+    filters: Filters,
+}
+
+impl DnsHandle for FilteredConnection {
+    type Response =
+        Pin<Box<dyn Stream<Item = Result<DnsResponse, ResolveError>> + Send>>;
+    type Error = ResolveError;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(
+        &mut self,
+        request: R,
+    ) -> Self::Response {
+        let (mut message, options) = request.into().into_parts();
+        for filter in self.filters.iter() {
+            filter.on_request(&self.upstream, &mut message);
+        }
+
+        let sent = message.clone();
+        let upstream = Arc::clone(&self.upstream);
+        let filters = Arc::clone(&self.filters);
+        self.inner
+            .send(DnsRequest::new(message, options))
+            .map(move |response| {
+                let mut response = response?;
+                for filter in filters.iter().rev() {
+                    let result =
+                        filter.on_response(&upstream, &sent, &mut response);
+                    if let Err(error) = result {
+                        debug!(
+                            "filter rejected response",
+                            upstream = upstream.addr,
+                            protocol = upstream.protocol,
+                            error = error,
+                        );
+                        return Err(error);
+                    }
+                }
+                Ok(response)
+            })
+            .boxed()
+    }
+}
+
+/// Resolves names with a [`FilteredAsyncResolver`]
+///
+/// This is the same as `CustomDnsResolver` and `MyCustomDnsResolver`, for
+/// resolvers built with [`filtered_resolver`].
+pub struct FilteredDnsResolver {
+    resolver: Arc<FilteredAsyncResolver>,
+}
+
+impl FilteredDnsResolver {
+    pub fn new(resolver: FilteredAsyncResolver) -> FilteredDnsResolver {
+        FilteredDnsResolver { resolver: Arc::new(resolver) }
+    }
+}
+
+impl reqwest::dns::Resolve for FilteredDnsResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> reqwest::dns::Resolving {
+        let resolver = self.resolver.clone();
+        async move {
+            overridable(name, |name| do_resolve(&resolver, name)).await
+        }
+        .boxed()
+    }
+}
+
+impl MyResolve for FilteredDnsResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        do_resolve(&self.resolver, name).boxed().into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        do_resolve_detailed(&self.resolver, name).boxed()
+    }
+}
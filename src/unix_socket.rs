@@ -0,0 +1,129 @@
+//! Sending queries to a local daemon over a Unix domain socket
+//!
+//! Some hosts run their caching resolver (dnsmasq, unbound, or a custom
+//! stub) on a Unix domain socket rather than on a loopback address: in
+//! containers that share a socket with the host but not its network, or
+//! where nothing should be listening on a port at all.  trust-dns only
+//! knows how to reach servers at IP addresses, so [`UnixSocketTransport`]
+//! sends the queries for them over the socket instead.
+//!
+//! The socket has to be a stream socket, and queries and responses are
+//! framed the way they are over TCP (RFC 1035, section 4.2.2), each with a
+//! two-byte length in front of it.
+
+use crate::dns_transport::DnsTransport;
+use crate::dns_transport::StandardTransport;
+use crate::dns_transport::TransportFuture;
+use crate::logging::debug;
+use futures::future::FutureExt;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use trust_dns_resolver::config::NameServerConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::proto::op::Message;
+
+type PathFor = Box<dyn Fn(&NameServerConfig) -> Option<PathBuf> + Send + Sync>;
+
+/// Sends queries over a Unix domain socket
+///
+/// Each query gets a connection of its own, which is closed once the
+/// response has been read.  The servers in the resolver's configuration
+/// only say which socket their queries go to (see
+/// [`UnixSocketTransport::per_server`]), so their addresses and protocols
+/// don't matter, but there has to be at least one.
+///
+/// ```no_run
+/// # use reqwest_resolve::dns_transport::{transport_resolver, TransportDnsResolver};
+/// # use reqwest_resolve::unix_socket::UnixSocketTransport;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{
+/// #     NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+/// # };
+/// // The address is never used: every query goes to the socket.
+/// let mut config = ResolverConfig::new();
+/// config.add_name_server(NameServerConfig::new(
+///     "127.0.0.1:53".parse().unwrap(),
+///     Protocol::Tcp,
+/// ));
+///
+/// let transport = UnixSocketTransport::new("/run/dns/stub.sock");
+/// let resolver =
+///     transport_resolver(config, ResolverOpts::default(), transport).unwrap();
+/// let my_resolver = TransportDnsResolver::new(resolver);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct UnixSocketTransport {
+    path_for: PathFor,
+    standard: StandardTransport,
+}
+
+impl UnixSocketTransport {
+    /// Sends the queries for every server to the socket at `path`.
+    pub fn new(path: impl AsRef<Path>) -> UnixSocketTransport {
+        let path = path.as_ref().to_owned();
+        UnixSocketTransport {
+            path_for: Box::new(move |_| Some(path.clone())),
+            // This is never used, since every server has a socket.
+            standard: StandardTransport::new(ResolverOpts::default()),
+        }
+    }
+
+    /// Sends the queries for each server to whatever socket `path_for`
+    /// returns for it, or as usual (with `options`, which should be the
+    /// resolver's) for servers it returns `None` for.
+    pub fn per_server<F>(
+        options: ResolverOpts,
+        path_for: F,
+    ) -> UnixSocketTransport
+    where
+        F: Fn(&NameServerConfig) -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        UnixSocketTransport {
+            path_for: Box::new(path_for),
+            standard: StandardTransport::new(options),
+        }
+    }
+}
+
+impl DnsTransport for UnixSocketTransport {
+    fn send(
+        &self,
+        server: &NameServerConfig,
+        query: Message,
+    ) -> TransportFuture {
+        let Some(path) = (self.path_for)(server) else {
+            return self.standard.send(server, query);
+        };
+        async move {
+            let response =
+                exchange(&path, &query.to_vec()?).await.map_err(|error| {
+                    debug!("socket query failed", path = path, error = error);
+                    error
+                })?;
+            Ok(Message::from_vec(&response)?)
+        }
+        .boxed()
+    }
+}
+
+/// Connects to the socket at `path`, sends `query`, and returns the
+/// response.
+async fn exchange(path: &Path, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(path).await?;
+    let len = u16::try_from(query.len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "query too large")
+    })?;
+    stream.write_u16(len).await?;
+    stream.write_all(query).await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
@@ -0,0 +1,677 @@
+//! Which certificates encrypted upstreams are trusted with
+//!
+//! By default, trust-dns checks DNS-over-TLS and DNS-over-HTTPS servers'
+//! certificates against Mozilla's root certificates, whatever roots reqwest
+//! uses for the requests themselves.  A private resolver's certificate may
+//! come from a company's own certificate authority instead, and a careful
+//! deployment may want to accept only certificates with one particular key,
+//! so that no other certificate authority can stand in for the resolver.
+//! [`UpstreamTls`] builds the TLS configuration for either, for
+//! `ResolverConfig::set_tls_client_config`,
+//! [`StartupConfig::tls_client_config`](crate::startup::StartupConfig), or
+//! `doh::DohTransport::with_tls_config`.
+
+use crate::error::ResolveError;
+use crate::mtls::ClientCertificate;
+use base64::Engine;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
+use rustls::Certificate;
+use rustls::ClientConfig;
+use rustls::OwnedTrustAnchor;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use std::fmt;
+use std::fs;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// The SHA-256 digest of a certificate's public key (its
+/// SubjectPublicKeyInfo), as used for pinning
+///
+/// This is the same digest HTTP Public Key Pinning used, which is what
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl
+/// dgst -sha256 -binary | base64` prints for a certificate.  Pinning the key
+/// rather than the certificate means the pin still matches after the
+/// certificate is renewed with the same key.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SpkiPin([u8; 32]);
+
+impl SpkiPin {
+    pub fn sha256(digest: [u8; 32]) -> SpkiPin {
+        SpkiPin(digest)
+    }
+
+    /// Parses a base64-encoded digest, with or without a "sha256/" prefix.
+    pub fn from_base64(pin: &str) -> Result<SpkiPin, ResolveError> {
+        let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+        let digest = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .ok()
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .ok_or_else(|| {
+                ResolveError::InvalidConfig(format!(
+                    "{:?} is not a base64-encoded SHA-256 digest",
+                    pin
+                ))
+            })?;
+        Ok(SpkiPin(digest))
+    }
+
+    /// Returns the pin for the key in `certificate` (DER-encoded), or `None`
+    /// if it can't be parsed.
+    pub fn of_certificate(certificate: &[u8]) -> Option<SpkiPin> {
+        Some(SpkiPin(sha256(parse(certificate)?.public_key().raw)))
+    }
+}
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(self.0);
+        write!(f, "sha256/{}", encoded)
+    }
+}
+
+/// The TLS settings for talking to encrypted upstreams
+///
+/// Server certificates are always checked against the root certificates
+/// (Mozilla's, unless others are given) and the server's name.  With pins,
+/// one of the certificates in the server's chain (its own, or an
+/// intermediate that issued it or one of the certificates above it) also
+/// has to have a pinned key.  Certificates the server sends that aren't part
+/// of that chain don't count.
+///
+/// ```no_run
+/// # use reqwest_resolve::upstream_tls::{SpkiPin, UpstreamTls};
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// let tls = UpstreamTls::new()
+///     .with_ca_file("/etc/pki/corp-dns-ca.pem")
+///     .unwrap()
+///     .with_pin(
+///         SpkiPin::from_base64("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=")
+///             .unwrap(),
+///     )
+///     .build();
+/// let mut config = ResolverConfig::cloudflare_tls();
+/// config.set_tls_client_config(tls);
+/// let _resolver =
+///     TokioAsyncResolver::tokio(config, ResolverOpts::default()).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct UpstreamTls {
+    roots: RootCertStore,
+    pins: Vec<SpkiPin>,
+    certificate_hashes: Vec<[u8; 32]>,
+    required_ips: Vec<IpAddr>,
+    client_certificate: Option<Arc<ClientCertificate>>,
+}
+
+impl Default for UpstreamTls {
+    fn default() -> UpstreamTls {
+        UpstreamTls::new()
+    }
+}
+
+impl UpstreamTls {
+    /// Trusts Mozilla's root certificates (the same ones trust-dns trusts by
+    /// default), with no pins and no client certificate.
+    pub fn new() -> UpstreamTls {
+        UpstreamTls::with_roots(mozilla_roots())
+    }
+
+    /// Trusts only the root certificates in `roots`.
+    pub fn with_roots(roots: RootCertStore) -> UpstreamTls {
+        UpstreamTls {
+            roots,
+            pins: Vec::new(),
+            certificate_hashes: Vec::new(),
+            required_ips: Vec::new(),
+            client_certificate: None,
+        }
+    }
+
+    /// Trusts only the root certificates in the PEM file at `path` (a CA
+    /// bundle), instead of any given before.
+    pub fn with_ca_file(
+        mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<UpstreamTls, ResolveError> {
+        let path = path.as_ref();
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(path)? {
+            roots.add(&cert).map_err(|error| {
+                invalid(path, "bad certificate authority", error)
+            })?;
+        }
+        if roots.is_empty() {
+            return Err(invalid(path, "reading", "no certificates found"));
+        }
+        self.roots = roots;
+        Ok(self)
+    }
+
+    /// Requires servers to present a certificate chain including `pin`'s
+    /// key, or any other pinned key.
+    pub fn with_pin(mut self, pin: SpkiPin) -> UpstreamTls {
+        self.pins.push(pin);
+        self
+    }
+
+    /// Also requires one of the certificates in a server's chain (its own, or
+    /// an intermediate that issued it, as with pins) to be one whose
+    /// to-be-signed part (its TBSCertificate) has the SHA-256 digest
+    /// `digest`, as DNS Stamps specify (see [`crate::stamps`]).  Called more
+    /// than once, this requires any one of the digests.
+    pub fn with_certificate_hash(mut self, digest: [u8; 32]) -> UpstreamTls {
+        self.certificate_hashes.push(digest);
+        self
+    }
+
+    /// Also requires servers' certificates to list `ip` among their
+    /// addresses (as [`crate::ddr`] does for designated resolvers).  Called
+    /// more than once, this requires any one of the addresses.
+    pub fn with_required_ip(mut self, ip: IpAddr) -> UpstreamTls {
+        self.required_ips.push(ip);
+        self
+    }
+
+    /// Presents `certificate` to servers that ask for one (see
+    /// [`crate::mtls`]).
+    pub fn with_client_certificate(
+        mut self,
+        certificate: Arc<ClientCertificate>,
+    ) -> UpstreamTls {
+        self.client_certificate = Some(certificate);
+        self
+    }
+
+    pub fn build(self) -> Arc<ClientConfig> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(Verifier {
+                inner: WebPkiVerifier::new(self.roots, None),
+                pins: self.pins,
+                certificate_hashes: self.certificate_hashes,
+                required_ips: self.required_ips,
+            }));
+        Arc::new(match self.client_certificate {
+            Some(certificate) => builder.with_client_cert_resolver(certificate),
+            None => builder.with_no_client_auth(),
+        })
+    }
+}
+
+/// Checks certificates the usual way, then checks for a pinned key, a
+/// certificate with a given hash, and the required addresses (if there are
+/// any)
+struct Verifier {
+    inner: WebPkiVerifier,
+    pins: Vec<SpkiPin>,
+    certificate_hashes: Vec<[u8; 32]>,
+    required_ips: Vec<IpAddr>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let chain = issuing_chain(end_entity, intermediates);
+        if !self.pins.is_empty()
+            && !chain
+                .iter()
+                .filter_map(|cert| SpkiPin::of_certificate(&cert.0))
+                .any(|pin| self.pins.contains(&pin))
+        {
+            return Err(rustls::Error::General(String::from(
+                "no certificate in the server's chain has a pinned key",
+            )));
+        }
+        if !self.certificate_hashes.is_empty()
+            && !chain
+                .iter()
+                .filter_map(|cert| tbs_digest(&cert.0))
+                .any(|digest| self.certificate_hashes.contains(&digest))
+        {
+            return Err(rustls::Error::General(String::from(
+                "no certificate in the server's chain has a given hash",
+            )));
+        }
+        if !self.required_ips.is_empty()
+            && !self
+                .required_ips
+                .iter()
+                .any(|ip| certificate_has_ip(&end_entity.0, *ip))
+        {
+            return Err(rustls::Error::General(String::from(
+                "the server's certificate doesn't list a required address",
+            )));
+        }
+        Ok(verified)
+    }
+}
+
+/// Returns the end entity certificate followed by each certificate among
+/// `intermediates` that issued the one before it
+///
+/// A certificate counts as the issuer of another if its subject is the
+/// other's issuer and its key verifies the other's signature, so a
+/// certificate that was sent along without being part of the chain (like
+/// some other server's real certificate, appended to make a pin match)
+/// isn't included.  The chain ends at the first certificate none of the
+/// rest issued, which is normally the one a root certificate issued.
+fn issuing_chain<'a>(
+    end_entity: &'a Certificate,
+    intermediates: &'a [Certificate],
+) -> Vec<&'a Certificate> {
+    let mut chain = vec![end_entity];
+    let Some(mut current) = parse(&end_entity.0) else {
+        return chain;
+    };
+    let mut candidates: Vec<(&Certificate, X509Certificate<'_>)> =
+        intermediates
+            .iter()
+            .filter_map(|cert| Some((cert, parse(&cert.0)?)))
+            .collect();
+    loop {
+        let issuer = candidates.iter().position(|(_, candidate)| {
+            candidate.subject().as_raw() == current.issuer().as_raw()
+                && current
+                    .verify_signature(Some(candidate.public_key()))
+                    .is_ok()
+        });
+        let Some(issuer) = issuer else {
+            return chain;
+        };
+        let (cert, parsed) = candidates.swap_remove(issuer);
+        chain.push(cert);
+        current = parsed;
+    }
+}
+
+/// Returns Mozilla's root certificates.
+pub(crate) fn mozilla_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(
+        webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }),
+    );
+    roots
+}
+
+/// Reads the certificates in the PEM file at `path`.
+pub(crate) fn read_certs(
+    path: &Path,
+) -> Result<Vec<Certificate>, ResolveError> {
+    let pem =
+        fs::read(path).map_err(|error| invalid(path, "reading", error))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&pem[..]))
+        .map_err(|error| invalid(path, "reading", error))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn invalid(
+    path: &Path,
+    what: &str,
+    error: impl fmt::Display,
+) -> ResolveError {
+    ResolveError::InvalidConfig(format!(
+        "{}: {}: {}",
+        path.display(),
+        what,
+        error
+    ))
+}
+
+/// Returns the SHA-256 digest of `bytes`.
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, bytes);
+    digest.as_ref().try_into().expect("SHA-256 digest is 32 bytes")
+}
+
+/// Parses a DER-encoded X.509 certificate.
+fn parse(certificate: &[u8]) -> Option<X509Certificate<'_>> {
+    X509Certificate::from_der(certificate).ok().map(|(_, parsed)| parsed)
+}
+
+/// Returns the SHA-256 digest of the TBSCertificate in a DER-encoded X.509
+/// certificate (tag and length included).
+fn tbs_digest(certificate: &[u8]) -> Option<[u8; 32]> {
+    Some(sha256(parse(certificate)?.tbs_certificate.as_ref()))
+}
+
+/// Returns whether a DER-encoded X.509 certificate lists `ip` in its
+/// subjectAltName extension.
+fn certificate_has_ip(certificate: &[u8], ip: IpAddr) -> bool {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let Some(certificate) = parse(certificate) else {
+        return false;
+    };
+    let Ok(Some(names)) = certificate.subject_alternative_name() else {
+        return false;
+    };
+    names.value.general_names.iter().any(|name| {
+        matches!(name, GeneralName::IPAddress(listed) if *listed == octets)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::certificate_has_ip;
+    use super::tbs_digest;
+    use super::SpkiPin;
+    use super::Verifier;
+    use rcgen::BasicConstraints;
+    use rcgen::CertificateParams;
+    use rcgen::DistinguishedName;
+    use rcgen::DnType;
+    use rcgen::IsCa;
+    use rcgen::SanType;
+    use rustls::client::ServerCertVerifier;
+    use rustls::client::WebPkiVerifier;
+    use rustls::Certificate;
+    use rustls::RootCertStore;
+    use rustls::ServerName;
+    use std::time::SystemTime;
+
+    const SERVER_NAME: &str = "dns.example.com";
+
+    fn params(common_name: &str, is_ca: bool) -> CertificateParams {
+        let mut params = CertificateParams::new(if is_ca {
+            Vec::new()
+        } else {
+            vec![String::from(SERVER_NAME)]
+        });
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, common_name);
+        params.distinguished_name = name;
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        params
+    }
+
+    /// A certificate authority, and the DER encoding of its certificate
+    struct Issuer {
+        cert: rcgen::Certificate,
+        der: Certificate,
+    }
+
+    impl Issuer {
+        fn root(common_name: &str) -> Issuer {
+            let cert =
+                rcgen::Certificate::from_params(params(common_name, true))
+                    .unwrap();
+            let der = Certificate(cert.serialize_der().unwrap());
+            Issuer { cert, der }
+        }
+
+        fn intermediate(&self, common_name: &str) -> Issuer {
+            let cert =
+                rcgen::Certificate::from_params(params(common_name, true))
+                    .unwrap();
+            let der = Certificate(
+                cert.serialize_der_with_signer(&self.cert).unwrap(),
+            );
+            Issuer { cert, der }
+        }
+
+        fn leaf(&self, params: CertificateParams) -> Certificate {
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            Certificate(cert.serialize_der_with_signer(&self.cert).unwrap())
+        }
+
+        fn server(&self, common_name: &str) -> Certificate {
+            self.leaf(params(common_name, false))
+        }
+    }
+
+    fn roots(issuers: &[&Issuer]) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        for issuer in issuers {
+            roots.add(&issuer.der).unwrap();
+        }
+        roots
+    }
+
+    fn verifier(roots: RootCertStore) -> Verifier {
+        Verifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins: Vec::new(),
+            certificate_hashes: Vec::new(),
+            required_ips: Vec::new(),
+        }
+    }
+
+    fn verify(
+        verifier: &Verifier,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+    ) -> Result<(), rustls::Error> {
+        verifier
+            .verify_server_cert(
+                end_entity,
+                intermediates,
+                &ServerName::try_from(SERVER_NAME).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    fn pin(cert: &Certificate) -> SpkiPin {
+        SpkiPin::of_certificate(&cert.0).unwrap()
+    }
+
+    fn hash(cert: &Certificate) -> [u8; 32] {
+        tbs_digest(&cert.0).unwrap()
+    }
+
+    #[test]
+    fn pinned_end_entity_key() {
+        let root = Issuer::root("Resolver Root");
+        let server = root.server("Resolver");
+        let mut verifier = verifier(roots(&[&root]));
+        verifier.pins.push(pin(&server));
+        verify(&verifier, &server, &[]).unwrap();
+    }
+
+    #[test]
+    fn pinned_intermediate_key() {
+        let root = Issuer::root("Resolver Root");
+        let intermediate = root.intermediate("Resolver Intermediate");
+        let server = intermediate.server("Resolver");
+        let mut verifier = verifier(roots(&[&root]));
+        verifier.pins.push(pin(&intermediate.der));
+        verify(&verifier, &server, std::slice::from_ref(&intermediate.der))
+            .unwrap();
+    }
+
+    #[test]
+    fn unpinned_chain_is_rejected() {
+        let root = Issuer::root("Resolver Root");
+        let server = root.server("Resolver");
+        let other = root.server("Other");
+        let mut verifier = verifier(roots(&[&root]));
+        verifier.pins.push(pin(&other));
+        verify(&verifier, &server, &[]).unwrap_err();
+    }
+
+    /// A server with a certificate from some other trusted authority can't
+    /// pass the pin check by sending the real server's (public) certificate
+    /// along as if it were an intermediate.
+    #[test]
+    fn appended_pinned_certificate_is_rejected() {
+        let real_root = Issuer::root("Resolver Root");
+        let real_server = real_root.server("Resolver");
+        let other_root = Issuer::root("Other Root");
+        let impostor = other_root.server("Impostor");
+
+        let mut verifier = verifier(roots(&[&real_root, &other_root]));
+        verifier.pins.push(pin(&real_server));
+        verify(&verifier, &real_server, &[]).unwrap();
+        verify(&verifier, &impostor, &[]).unwrap_err();
+        verify(&verifier, &impostor, std::slice::from_ref(&real_server))
+            .unwrap_err();
+    }
+
+    /// The same goes for the real server's intermediate.
+    #[test]
+    fn appended_pinned_intermediate_is_rejected() {
+        let real_root = Issuer::root("Resolver Root");
+        let real_intermediate = real_root.intermediate("Resolver Intermediate");
+        let other_root = Issuer::root("Other Root");
+        let impostor = other_root.server("Impostor");
+
+        let mut verifier = verifier(roots(&[&real_root, &other_root]));
+        verifier.pins.push(pin(&real_intermediate.der));
+        let appended = [real_intermediate.der.clone()];
+        verify(&verifier, &impostor, &appended).unwrap_err();
+    }
+
+    #[test]
+    fn hashed_chain() {
+        let root = Issuer::root("Resolver Root");
+        let intermediate = root.intermediate("Resolver Intermediate");
+        let server = intermediate.server("Resolver");
+        let intermediates = std::slice::from_ref(&intermediate.der);
+
+        let mut verifier = verifier(roots(&[&root]));
+        verifier.certificate_hashes.push(hash(&server));
+        verify(&verifier, &server, intermediates).unwrap();
+
+        verifier.certificate_hashes = vec![hash(&intermediate.der)];
+        verify(&verifier, &server, intermediates).unwrap();
+
+        verifier.certificate_hashes = vec![hash(&root.server("Other"))];
+        verify(&verifier, &server, intermediates).unwrap_err();
+    }
+
+    /// As with pins, sending the real server's certificate along doesn't
+    /// make a server with some other trusted certificate match its hash.
+    #[test]
+    fn appended_hashed_certificate_is_rejected() {
+        let real_root = Issuer::root("Resolver Root");
+        let real_server = real_root.server("Resolver");
+        let other_root = Issuer::root("Other Root");
+        let impostor = other_root.server("Impostor");
+
+        let mut verifier = verifier(roots(&[&real_root, &other_root]));
+        verifier.certificate_hashes.push(hash(&real_server));
+        let appended = std::slice::from_ref(&real_server);
+        verify(&verifier, &real_server, &[]).unwrap();
+        verify(&verifier, &impostor, appended).unwrap_err();
+    }
+
+    fn with_ips(ips: &[&str]) -> CertificateParams {
+        let mut params = params("Resolver", false);
+        params.subject_alt_names.extend(
+            ips.iter().map(|ip| SanType::IpAddress(ip.parse().unwrap())),
+        );
+        params
+    }
+
+    #[test]
+    fn ip_addresses_in_subject_alt_name() {
+        let root = Issuer::root("Resolver Root");
+        let server = root.leaf(with_ips(&["192.0.2.53", "2001:db8::53"]));
+        assert!(certificate_has_ip(&server.0, "192.0.2.53".parse().unwrap()));
+        assert!(certificate_has_ip(&server.0, "2001:db8::53".parse().unwrap()));
+        assert!(!certificate_has_ip(&server.0, "192.0.2.54".parse().unwrap()));
+        // The same address, as an IPv4-mapped IPv6 address, isn't listed.
+        let mapped = "::ffff:192.0.2.53".parse().unwrap();
+        assert!(!certificate_has_ip(&server.0, mapped));
+    }
+
+    #[test]
+    fn no_ip_addresses_in_subject_alt_name() {
+        let root = Issuer::root("Resolver Root");
+        let server = root.server("Resolver");
+        assert!(!certificate_has_ip(&server.0, "192.0.2.53".parse().unwrap()));
+
+        let mut params = with_ips(&[]);
+        params.subject_alt_names.clear();
+        let server = root.leaf(params);
+        assert!(!certificate_has_ip(&server.0, "192.0.2.53".parse().unwrap()));
+    }
+
+    #[test]
+    fn truncated_certificate() {
+        let root = Issuer::root("Resolver Root");
+        let server = root.leaf(with_ips(&["192.0.2.53"]));
+        let ip = "192.0.2.53".parse().unwrap();
+        for len in [0, 1, 2, 10, server.0.len() / 2, server.0.len() - 1] {
+            let truncated = &server.0[..len];
+            assert!(!certificate_has_ip(truncated, ip));
+            assert_eq!(SpkiPin::of_certificate(truncated), None);
+            assert_eq!(tbs_digest(truncated), None);
+        }
+    }
+
+    #[test]
+    fn required_ip() {
+        let root = Issuer::root("Resolver Root");
+        let listed = root.leaf(with_ips(&["192.0.2.53"]));
+        let unlisted = root.leaf(with_ips(&["192.0.2.54"]));
+
+        let mut verifier = verifier(roots(&[&root]));
+        verifier.required_ips.push("192.0.2.53".parse().unwrap());
+        verify(&verifier, &listed, &[]).unwrap();
+        verify(&verifier, &unlisted, &[]).unwrap_err();
+
+        // An intermediate listing the address doesn't count.
+        let intermediate = Certificate(
+            rcgen::Certificate::from_params({
+                let mut params = with_ips(&["192.0.2.53"]);
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params
+            })
+            .unwrap()
+            .serialize_der_with_signer(&root.cert)
+            .unwrap(),
+        );
+        let appended = std::slice::from_ref(&intermediate);
+        verify(&verifier, &unlisted, appended).unwrap_err();
+    }
+
+    /// The pin for a certificate is the digest of its whole
+    /// SubjectPublicKeyInfo, as `openssl` computes it.
+    #[test]
+    fn pin_is_spki_digest() {
+        let root = Issuer::root("Resolver Root");
+        let spki = root.cert.get_key_pair().public_key_der();
+        let digest = ring::digest::digest(&ring::digest::SHA256, &spki);
+        let expected = SpkiPin::sha256(digest.as_ref().try_into().unwrap());
+        assert_eq!(pin(&root.der), expected);
+    }
+}
@@ -0,0 +1,423 @@
+//! Following a name's addresses as they change
+//!
+//! Most of this crate answers one lookup at a time, on behalf of one
+//! request.  Load balancers and connection managers built on top of it want
+//! something else: to be told whenever a host's addresses change, so that
+//! they can keep connections open to the current ones.
+//! [`WatchingResolver::subscribe`] looks a name up periodically (or when its
+//! answer expires) and publishes each new set of addresses on a Tokio
+//! `watch` channel, and [`WatchingResolver::watch_resolve`] offers the same
+//! as a `Stream`.  Backends that are told about changes, rather than having
+//! to ask, can push them with [`WatchingResolver::publish`].
+
+#[cfg(feature = "signed-answers")]
+use crate::error::ResolveError;
+use crate::logging::debug;
+#[cfg(feature = "signed-answers")]
+use crate::signed::SignedAddresses;
+#[cfg(feature = "signed-answers")]
+use crate::signed::TrustedKeys;
+use crate::special_use::normalize;
+use crate::tasks::TaskSet;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::stream::Stream;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "signed-answers")]
+use std::time::SystemTime;
+#[cfg(feature = "signed-answers")]
+use std::time::UNIX_EPOCH;
+use tokio::sync::watch;
+
+type Watches =
+    Arc<Mutex<BTreeMap<String, Arc<watch::Sender<Vec<SocketAddr>>>>>>;
+
+/// Keeps subscribers up to date with the addresses of the names they're
+/// interested in
+///
+/// Each subscribed name is looked up with the inner resolver every
+/// `interval` (see also [`WatchingResolver::following_ttl`]), in a
+/// background task called "address watcher" (see
+/// [`crate::tasks`]), for as long as anybody holds a receiver for it.
+/// Subscribers are only woken up when the set of addresses changes: the
+/// same addresses in a different order aren't a change, and a failed lookup
+/// leaves the last set in place.  The set is sorted, and is empty until the
+/// first lookup succeeds.
+///
+/// Lookups pass straight through to the inner resolver, so this can also
+/// sit in the stack a client uses.
+///
+/// ```
+/// # use reqwest_resolve::static_hosts::StaticResolver;
+/// # use reqwest_resolve::static_resolver;
+/// # use reqwest_resolve::watch::WatchingResolver;
+/// # use std::net::SocketAddr;
+/// # use std::time::Duration;
+/// static HOSTS: StaticResolver = static_resolver! {
+///     "backend.example.com" => ["192.0.2.2", "192.0.2.1"],
+/// };
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .enable_all()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let watcher = WatchingResolver::new(HOSTS, Duration::from_secs(30));
+/// let mut addrs = watcher.subscribe("backend.example.com".parse().unwrap());
+/// addrs.changed().await.unwrap();
+/// let expected: Vec<SocketAddr> =
+///     vec!["192.0.2.1:0".parse().unwrap(), "192.0.2.2:0".parse().unwrap()];
+/// assert_eq!(*addrs.borrow(), expected);
+/// # });
+/// ```
+pub struct WatchingResolver<R> {
+    inner: Arc<R>,
+    interval: Duration,
+    follow_ttl: bool,
+    /// the channel for each subscribed name (normalized), which its
+    /// watcher removes once nobody is subscribed any more
+    watches: Watches,
+    tasks: TaskSet,
+}
+
+impl<R> WatchingResolver<R> {
+    /// Looks subscribed names up every `interval`.
+    pub fn new(inner: R, interval: Duration) -> WatchingResolver<R> {
+        WatchingResolver {
+            inner: Arc::new(inner),
+            interval,
+            follow_ttl: false,
+            watches: Arc::default(),
+            tasks: TaskSet::default(),
+        }
+    }
+
+    /// Looks subscribed names up again when their answers expire, but no
+    /// more often than every `interval`, and every `interval` for answers
+    /// that don't say when they expire.
+    pub fn following_ttl(mut self) -> WatchingResolver<R> {
+        self.follow_ttl = true;
+        self
+    }
+
+    /// Publishes `addrs` as `name`'s addresses right away, returning whether
+    /// anybody is watching it
+    ///
+    /// This is for backends that are told about changes, like a Kubernetes
+    /// watch on a service's endpoints.  It doesn't stop the periodic lookups,
+    /// so the inner resolver should answer with what was last published (or
+    /// the interval should be long), or the next lookup will replace it.
+    pub fn publish(&self, name: &str, mut addrs: Vec<SocketAddr>) -> bool {
+        addrs.sort();
+        addrs.dedup();
+        let watches = self.watches.lock().unwrap();
+        let Some(sender) = watches.get(&*normalize(name)) else {
+            return false;
+        };
+        publish(sender, name, addrs);
+        true
+    }
+
+    /// Publishes the addresses in `signed` (see [`WatchingResolver::publish`])
+    /// once its signature checks out against `keys`, returning whether
+    /// anybody is watching the name
+    ///
+    /// Addresses that aren't signed by a trusted key, or whose signature has
+    /// expired, aren't published, and the error says why.  Addresses that
+    /// are published are taken back when the signature expires, if they
+    /// haven't been replaced by then: subscribers see an empty set until the
+    /// next lookup or publication.  That's done in a task called "signed
+    /// addresses expiry", so this must be called from within a Tokio
+    /// runtime.
+    #[cfg(feature = "signed-answers")]
+    pub fn publish_signed(
+        &self,
+        signed: &SignedAddresses,
+        keys: &TrustedKeys,
+    ) -> Result<bool, ResolveError> {
+        let mut addrs = signed.verify(keys)?;
+        addrs.sort();
+        addrs.dedup();
+        if !self.publish(&signed.name, addrs.clone()) {
+            return Ok(false);
+        }
+
+        // An expiry too far off for the system clock never comes.
+        let expiry =
+            UNIX_EPOCH.checked_add(Duration::from_secs(signed.expires));
+        let Some(expires) = expiry else {
+            return Ok(true);
+        };
+        let remaining =
+            expires.duration_since(SystemTime::now()).unwrap_or_default();
+        let name = signed.name.clone();
+        let watches = Arc::clone(&self.watches);
+        self.tasks.spawn("signed addresses expiry", async move {
+            tokio::time::sleep(remaining).await;
+            let watches = watches.lock().unwrap();
+            let Some(sender) = watches.get(&*normalize(&name)) else {
+                return;
+            };
+            sender.send_if_modified(|current| {
+                if *current != addrs {
+                    return false;
+                }
+                debug!("signed addresses expired", name = name);
+                current.clear();
+                true
+            });
+        });
+        Ok(true)
+    }
+}
+
+impl<R: MyResolve + 'static> WatchingResolver<R> {
+    /// Returns a receiver that sees each new set of addresses for `name`
+    ///
+    /// Subscribers to the same name share one watcher.  When the last
+    /// receiver is dropped, the watcher stops after its next lookup.  This
+    /// must be called from within a Tokio runtime, which the watcher runs
+    /// on.
+    pub fn subscribe(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> watch::Receiver<Vec<SocketAddr>> {
+        let key = normalize(name.as_str()).into_owned();
+        let mut watches = self.watches.lock().unwrap();
+        if let Some(sender) = watches.get(&key) {
+            return sender.subscribe();
+        }
+
+        let (sender, receiver) = watch::channel(Vec::new());
+        let sender = Arc::new(sender);
+        watches.insert(key.clone(), Arc::clone(&sender));
+        self.tasks.spawn(
+            "address watcher",
+            watch_name(
+                Arc::clone(&self.inner),
+                name,
+                key,
+                sender,
+                (self.interval, self.follow_ttl),
+                Arc::clone(&self.watches),
+            ),
+        );
+        receiver
+    }
+
+    /// Returns a stream of `name`'s addresses, with a new item every time
+    /// they change
+    ///
+    /// This is [`WatchingResolver::subscribe`] for callers that would rather
+    /// have a `Stream`.  The first item is the current set, or the result of
+    /// the first lookup if there hasn't been one yet.  The stream ends when
+    /// this resolver is dropped.
+    ///
+    /// ```
+    /// # use futures::stream::StreamExt;
+    /// # use reqwest_resolve::static_hosts::StaticResolver;
+    /// # use reqwest_resolve::static_resolver;
+    /// # use reqwest_resolve::watch::WatchingResolver;
+    /// # use std::time::Duration;
+    /// static HOSTS: StaticResolver = static_resolver! {
+    ///     "backend.example.com" => ["192.0.2.1"],
+    /// };
+    /// # tokio::runtime::Builder::new_current_thread()
+    /// #     .enable_all()
+    /// #     .build()
+    /// #     .unwrap()
+    /// #     .block_on(async {
+    /// let watcher = WatchingResolver::new(HOSTS, Duration::from_secs(30));
+    /// let name = "backend.example.com".parse().unwrap();
+    /// let mut updates = Box::pin(watcher.watch_resolve(name));
+    /// let first = updates.next().await.unwrap();
+    /// assert_eq!(first.count(), 1);
+    ///
+    /// // A backend that's told about changes pushes them:
+    /// let addrs = ["192.0.2.1:0", "192.0.2.2:0"].map(|a| a.parse().unwrap());
+    /// watcher.publish("backend.example.com", addrs.to_vec());
+    /// let second = updates.next().await.unwrap();
+    /// assert_eq!(second.count(), 2);
+    /// # });
+    /// ```
+    pub fn watch_resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> impl Stream<Item = Addrs> + Send + 'static {
+        let receiver = self.subscribe(name);
+        futures::stream::unfold((receiver, true), |(mut receiver, first)| {
+            async move {
+                // The channel starts out empty, which means there hasn't been
+                // a lookup yet, not that there are no addresses.
+                let ready = first && !receiver.borrow().is_empty();
+                if !ready {
+                    receiver.changed().await.ok()?;
+                }
+                let addrs = receiver.borrow_and_update().clone();
+                Some((Box::new(addrs.into_iter()) as Addrs, (receiver, false)))
+            }
+        })
+    }
+}
+
+/// Sends `addrs` (sorted, without duplicates) on `sender` if they're
+/// different from the last set.
+fn publish(
+    sender: &watch::Sender<Vec<SocketAddr>>,
+    name: &str,
+    addrs: Vec<SocketAddr>,
+) {
+    sender.send_if_modified(|current| {
+        if *current == addrs {
+            return false;
+        }
+        debug!("watched name's addresses changed", name = name, addrs = addrs);
+        *current = addrs;
+        true
+    });
+}
+
+/// Looks up `name` every `interval` (or when its answer expires, if
+/// `follow_ttl`), publishing changes on `sender`, until nobody is subscribed
+async fn watch_name<R: MyResolve>(
+    inner: Arc<R>,
+    name: hyper::client::connect::dns::Name,
+    key: String,
+    sender: Arc<watch::Sender<Vec<SocketAddr>>>,
+    (interval, follow_ttl): (Duration, bool),
+    watches: Watches,
+) {
+    loop {
+        let mut delay = interval;
+        match inner.resolve_detailed(name.clone()).await {
+            Ok(resolved) => {
+                let ttl = resolved.iter().filter_map(|r| r.ttl).min();
+                if let Some(ttl) = ttl.filter(|_| follow_ttl) {
+                    delay = ttl.max(interval);
+                }
+                let mut addrs: Vec<SocketAddr> =
+                    resolved.into_iter().map(|r| r.addr).collect();
+                addrs.sort();
+                addrs.dedup();
+                publish(&sender, &key, addrs);
+            }
+            Err(error) => {
+                debug!(
+                    "looking up watched name failed",
+                    name = key,
+                    error = error
+                );
+            }
+        }
+
+        tokio::time::sleep(delay).await;
+
+        // Checking for subscribers with the lock held means nobody can
+        // subscribe between the check and the removal.
+        let mut watches = watches.lock().unwrap();
+        if sender.is_closed() {
+            if watches.get(&key).is_some_and(|s| Arc::ptr_eq(s, &sender)) {
+                watches.remove(&key);
+            }
+            debug!("no more subscribers for watched name", name = key);
+            return;
+        }
+    }
+}
+
+impl<R: MyResolve> MyResolve for WatchingResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        self.inner.resolve(name)
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        self.inner.resolve_detailed(name)
+    }
+}
+
+#[cfg(all(test, feature = "signed-answers"))]
+mod tests {
+    use super::WatchingResolver;
+    use crate::signed::SignedAddresses;
+    use crate::signed::TrustedKeys;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use ring::signature::KeyPair;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "backend.test" => ["192.0.2.1"],
+    };
+
+    /// Signed addresses are taken back when their signature expires.
+    #[tokio::test]
+    async fn signed_addresses_expire() {
+        let random = ring::rand::SystemRandom::new();
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&random).unwrap();
+        let pair = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .unwrap();
+        let keys = TrustedKeys::new()
+            .with_key(pair.public_key().as_ref().try_into().unwrap());
+
+        let watcher = WatchingResolver::new(HOSTS, Duration::from_secs(3600));
+        let mut addrs = watcher.subscribe("backend.test".parse().unwrap());
+        addrs.changed().await.unwrap();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let signed_addr: SocketAddr = "192.0.2.9:0".parse().unwrap();
+        let signed = SignedAddresses::sign(
+            "backend.test",
+            &[signed_addr],
+            now.as_secs() + 2,
+            pkcs8.as_ref(),
+        )
+        .unwrap();
+        assert!(watcher.publish_signed(&signed, &keys).unwrap());
+        addrs.changed().await.unwrap();
+        assert_eq!(*addrs.borrow_and_update(), [signed_addr]);
+
+        tokio::time::timeout(Duration::from_secs(5), addrs.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(addrs.borrow().is_empty());
+
+        // Nobody watches this name, so nothing is published (or scheduled).
+        let other = SignedAddresses::sign(
+            "other.test",
+            &[signed_addr],
+            now.as_secs() + 300,
+            pkcs8.as_ref(),
+        )
+        .unwrap();
+        assert!(!watcher.publish_signed(&other, &keys).unwrap());
+
+        // Addresses whose signature expires too far in the future to
+        // represent are published, and never taken back.
+        let forever = SignedAddresses::sign(
+            "backend.test",
+            &[signed_addr],
+            u64::MAX,
+            pkcs8.as_ref(),
+        )
+        .unwrap();
+        assert!(watcher.publish_signed(&forever, &keys).unwrap());
+        addrs.changed().await.unwrap();
+        assert_eq!(*addrs.borrow_and_update(), [signed_addr]);
+    }
+}
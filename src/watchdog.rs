@@ -0,0 +1,173 @@
+//! Replacing a resolver that has stopped answering
+//!
+//! Long-lived processes occasionally find their `TokioAsyncResolver` wedged
+//! after the network flaps: every query times out, even once the network is
+//! back, until the process is restarted.  [`WatchdogResolver`] notices when
+//! that has been going on for a while and builds a new resolver in place of
+//! the old one, without anything using it having to know.
+
+use crate::do_resolve;
+use crate::do_resolve_detailed;
+use crate::logging::debug;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use std::error::Error as StdError;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_resolver::config::ResolverConfig;
+use trust_dns_resolver::config::ResolverOpts;
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Rebuilds its `TokioAsyncResolver` once every lookup has timed out for
+/// `wedged_after`
+///
+/// Any other outcome, including that a name doesn't exist, shows the
+/// resolver is still getting answers and starts the clock over.  The new
+/// resolver is built from the same configuration, and starts with an empty
+/// cache.  Lookups already in progress finish on the old one.
+///
+/// ```
+/// # use reqwest_resolve::watchdog::WatchdogResolver;
+/// # use reqwest_resolve::ResolveAdapter;
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// let my_resolver = WatchdogResolver::new(
+///     ResolverConfig::cloudflare(),
+///     ResolverOpts::default(),
+///     Duration::from_secs(30),
+/// )
+/// .unwrap();
+/// assert_eq!(my_resolver.restarts(), 0);
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// ```
+pub struct WatchdogResolver {
+    config: ResolverConfig,
+    options: ResolverOpts,
+    wedged_after: Duration,
+    state: Mutex<State>,
+    restarts: AtomicU64,
+}
+
+struct State {
+    // As with `CustomDnsResolver`, this is an `Arc` so that lookups can
+    // hold onto the resolver they started with.
+    resolver: Arc<TokioAsyncResolver>,
+    /// when the current run of timeouts started, if the last lookup to
+    /// finish timed out
+    failing_since: Option<Instant>,
+}
+
+impl WatchdogResolver {
+    pub fn new(
+        config: ResolverConfig,
+        options: ResolverOpts,
+        wedged_after: Duration,
+    ) -> Result<WatchdogResolver, ResolveError> {
+        let resolver = TokioAsyncResolver::tokio(config.clone(), options)?;
+        Ok(WatchdogResolver {
+            config,
+            options,
+            wedged_after,
+            state: Mutex::new(State {
+                resolver: Arc::new(resolver),
+                failing_since: None,
+            }),
+            restarts: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns how many times the resolver has been rebuilt.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    fn current(&self) -> Arc<TokioAsyncResolver> {
+        Arc::clone(&self.state.lock().unwrap().resolver)
+    }
+
+    /// Records how a lookup with `resolver` went, rebuilding the resolver
+    /// if it has been timing out for too long.
+    fn observe(
+        &self,
+        resolver: &Arc<TokioAsyncResolver>,
+        error: Option<&(dyn StdError + 'static)>,
+    ) {
+        let timed_out = error
+            .and_then(|error| error.downcast_ref::<ResolveError>())
+            .is_some_and(|error| {
+                matches!(error.kind(), ResolveErrorKind::Timeout)
+            });
+
+        let mut state = self.state.lock().unwrap();
+        // Lookups that finish on a resolver that's already been replaced
+        // say nothing about the new one.
+        if !Arc::ptr_eq(&state.resolver, resolver) {
+            return;
+        }
+        if !timed_out {
+            state.failing_since = None;
+            return;
+        }
+
+        let now = Instant::now();
+        let since = *state.failing_since.get_or_insert(now);
+        if now.duration_since(since) < self.wedged_after {
+            return;
+        }
+        match TokioAsyncResolver::tokio(self.config.clone(), self.options) {
+            Ok(resolver) => {
+                debug!(
+                    "rebuilding resolver that stopped answering",
+                    timing_out_for = now.duration_since(since),
+                );
+                state.resolver = Arc::new(resolver);
+                state.failing_since = None;
+                self.restarts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(error) => {
+                // This worked once, so it's unlikely to fail now, but if
+                // it does, keep the old one and try again next time.
+                debug!("rebuilding wedged resolver failed", error = error);
+            }
+        }
+    }
+}
+
+impl MyResolve for WatchdogResolver {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        async move {
+            let resolver = self.current();
+            let result = do_resolve(&resolver, name).await;
+            self.observe(&resolver, result.as_ref().err().map(|e| &**e as _));
+            result
+        }
+        .boxed()
+        .into()
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        async move {
+            let resolver = self.current();
+            let result = do_resolve_detailed(&resolver, name).await;
+            self.observe(&resolver, result.as_ref().err().map(|e| &**e as _));
+            result
+        }
+        .boxed()
+    }
+}
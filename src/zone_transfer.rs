@@ -0,0 +1,765 @@
+//! Keeping a local copy of an internal zone with zone transfers
+//!
+//! Clients inside an enterprise network often depend on a handful of
+//! internal zones, served by resolvers that are one more thing to go wrong.
+//! A [`ZoneTransferResolver`] pulls a copy of such a zone from a server
+//! that allows it (with AXFR, and then IXFR to pick up changes, as in
+//! RFC 1995), keeps it up to date in the background, and answers for the
+//! names in it locally, so that those still resolve while the resolver tier
+//! is degraded.  Only records for names in the zone are kept, and a CNAME
+//! that leads out of the zone is left to the inner resolver to follow.
+//!
+//! Transfers aren't signed (there's no TSIG support), so the server has to
+//! allow them by the client's address, and the network path to it has to
+//! be trusted.
+
+use crate::deterministic::random;
+use crate::error::ResolveError;
+use crate::logging::debug;
+use crate::logging::warning;
+use crate::resolved::ResolvedAddr;
+use crate::special_use::in_domain;
+use crate::special_use::normalize;
+use crate::tasks::TaskSet;
+use crate::DetailedResolving;
+use crate::MyResolve;
+use crate::MyResolving;
+use futures::future::FutureExt;
+use reqwest::dns::Addrs;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::op::MessageType;
+use trust_dns_resolver::proto::op::OpCode;
+use trust_dns_resolver::proto::op::Query;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::rdata::SOA;
+use trust_dns_resolver::proto::rr::Name;
+use trust_dns_resolver::proto::rr::RData;
+use trust_dns_resolver::proto::rr::Record;
+use trust_dns_resolver::proto::rr::RecordType;
+
+/// How many CNAMEs in a row are followed within the copy
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How long to wait before trying again when the first transfer fails
+const DEFAULT_RETRY: Duration = Duration::from_secs(60);
+
+/// The A, AAAA, and CNAME records of a zone, as of one serial number
+#[derive(Clone)]
+struct ZoneCopy {
+    /// the zone's name, normalized
+    zone: String,
+    soa: SOA,
+    /// when the copy was last transferred or found to be up to date
+    checked: Instant,
+    /// the records for each name, lowercased and without a trailing dot
+    records: BTreeMap<String, Vec<RData>>,
+}
+
+impl ZoneCopy {
+    /// Returns whether the copy has gone longer without being checked than
+    /// the zone's SOA says a secondary may keep answering from it.
+    fn expired(&self) -> bool {
+        let expire = u64::try_from(self.soa.expire()).unwrap_or(0);
+        self.checked.elapsed() > Duration::from_secs(expire)
+    }
+
+    /// Adds (or with `add` false, removes) `record`, if it's one that's
+    /// kept
+    ///
+    /// Records for names outside the zone aren't kept: a server has no
+    /// business sending them, and they mustn't be used to answer for names
+    /// that belong to some other zone.
+    fn apply(&mut self, record: &Record, add: bool) {
+        let Some(data) = record.data() else {
+            return;
+        };
+        if !matches!(data, RData::A(_) | RData::AAAA(_) | RData::CNAME(_)) {
+            return;
+        }
+        let name = normalize(&record.name().to_ascii()).into_owned();
+        if !in_domain(&name, &self.zone) {
+            debug!(
+                "ignoring transferred record outside the zone",
+                zone = self.zone,
+                name = name,
+            );
+            return;
+        }
+        if add {
+            let data_for_name = self.records.entry(name).or_default();
+            if !data_for_name.contains(data) {
+                data_for_name.push(data.clone());
+            }
+        } else if let Some(data_for_name) = self.records.get_mut(&name) {
+            data_for_name.retain(|existing| existing != data);
+            if data_for_name.is_empty() {
+                self.records.remove(&name);
+            }
+        }
+    }
+
+    /// Returns the addresses of `name` (normalized), following CNAMEs that
+    /// stay within the zone.  Nothing is returned for a CNAME that leads out
+    /// of the zone, so that the name is resolved normally.
+    fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        let mut name = name.to_owned();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let Some(data) = self.records.get(&name) else {
+                break;
+            };
+            let ips: Vec<IpAddr> = data
+                .iter()
+                .filter_map(|data| match data {
+                    RData::A(ip) => Some(IpAddr::from(*ip)),
+                    RData::AAAA(ip) => Some(IpAddr::from(*ip)),
+                    _ => None,
+                })
+                .collect();
+            if !ips.is_empty() {
+                return ips;
+            }
+            match data.iter().find_map(RData::as_cname) {
+                Some(target) => {
+                    name = normalize(&target.to_ascii()).into_owned();
+                    if !in_domain(&name, &self.zone) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// The zone, where to transfer it from, and the copy
+struct Zone {
+    /// the zone's name, normalized
+    name: String,
+    origin: Name,
+    server: SocketAddr,
+    timeout: Duration,
+    refresh: Option<Duration>,
+    incremental: bool,
+    copy: RwLock<Option<ZoneCopy>>,
+}
+
+impl Zone {
+    fn error(&self, message: impl ToString) -> ResolveError {
+        ResolveError::ZoneTransferFailed {
+            zone: self.name.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Transfers the zone (or the changes since the copy's serial), and
+    /// returns the serial the copy is now at.
+    async fn refresh(&self) -> Result<u32, ResolveError> {
+        let current = self.copy.read().unwrap().clone();
+        let since =
+            current.as_ref().filter(|_| self.incremental).map(|c| &c.soa);
+        let records = tokio::time::timeout(self.timeout, self.transfer(since))
+            .await
+            .map_err(|_| self.error("timed out"))?
+            .map_err(|error| self.error(error))?;
+
+        let updated = match since {
+            // The copy is already up to date.
+            Some(_) if records.len() == 1 => current.unwrap(),
+            Some(_) if is_incremental(&records) => {
+                let mut copy = current.unwrap();
+                apply_changes(&mut copy, &records);
+                copy
+            }
+            _ => {
+                let mut copy = ZoneCopy {
+                    zone: self.name.clone(),
+                    soa: soa_of(&records[0]).unwrap().clone(),
+                    checked: Instant::now(),
+                    records: BTreeMap::new(),
+                };
+                for record in &records[1..records.len() - 1] {
+                    copy.apply(record, true);
+                }
+                copy
+            }
+        };
+
+        let serial = updated.soa.serial();
+        let mut copy = self.copy.write().unwrap();
+        if copy.as_ref().map(|c| c.soa.serial()) != Some(serial) {
+            debug!(
+                "transferred zone",
+                zone = self.name,
+                serial = serial,
+                names = updated.records.len(),
+            );
+        }
+        *copy = Some(ZoneCopy { checked: Instant::now(), ..updated });
+        Ok(serial)
+    }
+
+    /// Sends an AXFR query for the zone (or an IXFR query, if there's an SOA
+    /// to transfer changes since), and returns the records in the response,
+    /// from its first SOA to its last.
+    async fn transfer(&self, since: Option<&SOA>) -> io::Result<Vec<Record>> {
+        let id = random::<u16>();
+        let mut query = Message::new();
+        query
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query);
+        match since {
+            Some(soa) => {
+                query.add_query(Query::query(
+                    self.origin.clone(),
+                    RecordType::IXFR,
+                ));
+                query.add_name_server(Record::from_rdata(
+                    self.origin.clone(),
+                    0,
+                    RData::SOA(soa.clone()),
+                ));
+            }
+            None => {
+                query.add_query(Query::query(
+                    self.origin.clone(),
+                    RecordType::AXFR,
+                ));
+            }
+        }
+        let query = query.to_vec().map_err(invalid_data)?;
+        let len = u16::try_from(query.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "query too large")
+        })?;
+
+        let mut stream = TcpStream::connect(self.server).await?;
+        stream.write_u16(len).await?;
+        stream.write_all(&query).await?;
+
+        let mut records = Vec::new();
+        loop {
+            let len = stream.read_u16().await?;
+            let mut buf = vec![0u8; usize::from(len)];
+            stream.read_exact(&mut buf).await?;
+            let response = Message::from_vec(&buf).map_err(invalid_data)?;
+            if response.id() != id {
+                continue;
+            }
+            if response.response_code() != ResponseCode::NoError {
+                return Err(invalid_data(format!(
+                    "server responded {}",
+                    response.response_code()
+                )));
+            }
+            records.extend(response.answers().iter().cloned());
+
+            let Some(first) = records.first() else {
+                return Err(invalid_data("empty response"));
+            };
+            let Some(serial) = soa_of(first).map(SOA::serial) else {
+                return Err(invalid_data("response doesn't start with an SOA"));
+            };
+            if records.len() == 1 {
+                if since.map(SOA::serial) == Some(serial) {
+                    return Ok(records);
+                }
+                continue;
+            }
+            let last = records.last().and_then(soa_of).map(SOA::serial);
+            if last != Some(serial) {
+                continue;
+            }
+            // A full transfer ends with the SOA it started with.  An
+            // incremental one has that SOA once more, at the start of the
+            // last change.
+            let times = records
+                .iter()
+                .filter(|r| soa_of(r).map(SOA::serial) == Some(serial))
+                .count();
+            if !is_incremental(&records) || times >= 3 {
+                return Ok(records);
+            }
+        }
+    }
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn soa_of(record: &Record) -> Option<&SOA> {
+    match record.data() {
+        Some(RData::SOA(soa)) => Some(soa),
+        _ => None,
+    }
+}
+
+/// Returns whether `records` (starting with an SOA) are the changes of an
+/// incremental transfer rather than a whole zone: their second record is an
+/// SOA for an older serial.
+fn is_incremental(records: &[Record]) -> bool {
+    let serial = |i: usize| records.get(i).and_then(soa_of).map(SOA::serial);
+    matches!((serial(0), serial(1)), (Some(new), Some(old)) if new != old)
+}
+
+/// Applies the changes in an incremental transfer to `copy`
+///
+/// Between the first SOA and the last, each change is an SOA for the old
+/// serial, the records removed, an SOA for the new serial, and the records
+/// added.
+fn apply_changes(copy: &mut ZoneCopy, records: &[Record]) {
+    let mut adding = true;
+    for record in &records[1..records.len() - 1] {
+        match soa_of(record) {
+            Some(soa) => {
+                adding = !adding;
+                if adding {
+                    copy.soa = soa.clone();
+                }
+            }
+            None => copy.apply(record, adding),
+        }
+    }
+}
+
+/// Answers for the names in a zone from a copy kept with zone transfers,
+/// and sends everything else to the inner resolver
+///
+/// [`ZoneTransferResolver::start`] transfers the zone and then keeps the
+/// copy up to date in a background task called "zone transfer" (see
+/// [`crate::tasks`]), checking for changes as often as the zone's SOA
+/// record says to, or as often as [`ZoneTransferResolver::with_refresh`]
+/// says.  Until the first transfer finishes, and whenever the copy has gone
+/// unchecked for longer than the SOA's expire time, names in the zone go to
+/// the inner resolver like any others.  So do names the copy doesn't have
+/// addresses for, since it only keeps A, AAAA, and CNAME records (without
+/// wildcards), and may be out of date.
+///
+/// ```no_run
+/// # use reqwest_resolve::zone_transfer::ZoneTransferResolver;
+/// # use reqwest_resolve::{MyCustomDnsResolver, ResolveAdapter};
+/// # use std::sync::Arc;
+/// # use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+/// # use trust_dns_resolver::TokioAsyncResolver;
+/// # tokio::runtime::Builder::new_current_thread()
+/// #     .enable_all()
+/// #     .build()
+/// #     .unwrap()
+/// #     .block_on(async {
+/// let resolver = TokioAsyncResolver::tokio(
+///     ResolverConfig::default(),
+///     ResolverOpts::default(),
+/// )
+/// .unwrap();
+/// let my_resolver = ZoneTransferResolver::new(
+///     MyCustomDnsResolver::new(resolver),
+///     "corp.example.com",
+///     "10.0.0.53:53".parse().unwrap(),
+/// )
+/// .unwrap();
+/// my_resolver.refresh().await.unwrap();
+/// my_resolver.start();
+/// let _client = reqwest::ClientBuilder::new()
+///     .dns_resolver(Arc::new(ResolveAdapter::new(my_resolver)));
+/// # });
+/// ```
+pub struct ZoneTransferResolver<R> {
+    inner: R,
+    zone: Arc<Zone>,
+    tasks: TaskSet,
+}
+
+impl<R> ZoneTransferResolver<R> {
+    /// Keeps a copy of `zone`, transferred from `server`, once started.
+    pub fn new(
+        inner: R,
+        zone: &str,
+        server: SocketAddr,
+    ) -> Result<ZoneTransferResolver<R>, ResolveError> {
+        let name = normalize(zone).into_owned();
+        let origin = Name::from_ascii(format!("{}.", name)).map_err(|_| {
+            ResolveError::InvalidConfig(format!("invalid zone: {:?}", zone))
+        })?;
+        Ok(ZoneTransferResolver {
+            inner,
+            zone: Arc::new(Zone {
+                name,
+                origin,
+                server,
+                timeout: Duration::from_secs(30),
+                refresh: None,
+                incremental: true,
+                copy: RwLock::new(None),
+            }),
+            tasks: TaskSet::default(),
+        })
+    }
+
+    fn zone_mut(&mut self) -> &mut Zone {
+        Arc::get_mut(&mut self.zone).expect("configured after starting")
+    }
+
+    /// Checks for changes every `interval`, rather than as often as the
+    /// zone's SOA record says.
+    pub fn with_refresh(mut self, interval: Duration) -> Self {
+        self.zone_mut().refresh = Some(interval);
+        self
+    }
+
+    /// Gives up on a transfer that hasn't finished after `timeout` (by
+    /// default, 30 seconds).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.zone_mut().timeout = timeout;
+        self
+    }
+
+    /// Transfers the whole zone every time, for servers that don't support
+    /// IXFR properly.
+    pub fn full_transfers_only(mut self) -> Self {
+        self.zone_mut().incremental = false;
+        self
+    }
+
+    /// Returns the serial number of the copy, if there is one.
+    pub fn serial(&self) -> Option<u32> {
+        self.zone.copy.read().unwrap().as_ref().map(|c| c.soa.serial())
+    }
+
+    /// Brings the copy up to date now, returning its serial number
+    ///
+    /// This is what the background task does on its schedule.  Awaiting it
+    /// before starting the task means the first lookups are answered from
+    /// the copy.
+    pub async fn refresh(&self) -> Result<u32, ResolveError> {
+        self.zone.refresh().await
+    }
+
+    /// Starts keeping the copy up to date in the background, until this is
+    /// dropped
+    ///
+    /// This must be called from within a Tokio runtime, which the task runs
+    /// on.
+    pub fn start(&self) {
+        let zone = Arc::clone(&self.zone);
+        self.tasks.spawn("zone transfer", async move {
+            loop {
+                let result = zone.refresh().await;
+                let soa = zone
+                    .copy
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|c| (c.soa.refresh(), c.soa.retry()));
+                let secs = |value: i32| {
+                    Duration::from_secs(u64::try_from(value).unwrap_or(0))
+                };
+                let next = match (result, soa) {
+                    (Ok(_), Some((refresh, _))) => {
+                        zone.refresh.unwrap_or_else(|| secs(refresh))
+                    }
+                    (Err(error), soa) => {
+                        warning!(
+                            "zone transfer failed",
+                            zone = zone.name,
+                            error = error.to_string(),
+                        );
+                        soa.map(|(_, retry)| secs(retry))
+                            .unwrap_or(DEFAULT_RETRY)
+                    }
+                    (Ok(_), None) => DEFAULT_RETRY,
+                };
+                tokio::time::sleep(next.max(Duration::from_secs(1))).await;
+            }
+        });
+    }
+
+    /// Returns the addresses the copy has for `name`, if it's in the zone.
+    fn lookup(&self, name: &str) -> Vec<SocketAddr> {
+        let name = normalize(name);
+        if !in_domain(&name, &self.zone.name) {
+            return Vec::new();
+        }
+        let copy = self.zone.copy.read().unwrap();
+        let Some(copy) = copy.as_ref().filter(|c| !c.expired()) else {
+            return Vec::new();
+        };
+        let addrs: Vec<SocketAddr> = copy
+            .lookup(&name)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, 0))
+            .collect();
+        if !addrs.is_empty() {
+            debug!("answered from zone copy", name = name, addrs = addrs);
+        }
+        addrs
+    }
+}
+
+impl<R: MyResolve> MyResolve for ZoneTransferResolver<R> {
+    fn resolve(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> MyResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        if addrs.is_empty() {
+            return self.inner.resolve(name);
+        }
+        MyResolving::ready(Ok(Box::new(addrs.into_iter()) as Addrs))
+    }
+
+    fn resolve_detailed(
+        &self,
+        name: hyper::client::connect::dns::Name,
+    ) -> DetailedResolving<'_> {
+        let addrs = self.lookup(name.as_str());
+        if addrs.is_empty() {
+            return self.inner.resolve_detailed(name);
+        }
+        let addrs = addrs
+            .into_iter()
+            .map(|addr| ResolvedAddr::from_table(addr, "zone_transfer"))
+            .collect();
+        futures::future::ready(Ok(addrs)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_changes;
+    use super::is_incremental;
+    use super::ZoneCopy;
+    use super::ZoneTransferResolver;
+    use crate::static_hosts::StaticResolver;
+    use crate::static_resolver;
+    use crate::MyResolve;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use trust_dns_resolver::proto::op::Message;
+    use trust_dns_resolver::proto::op::MessageType;
+    use trust_dns_resolver::proto::rr::rdata::SOA;
+    use trust_dns_resolver::proto::rr::Name;
+    use trust_dns_resolver::proto::rr::RData;
+    use trust_dns_resolver::proto::rr::Record;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    const ZONE: &str = "corp.example.com.";
+
+    static HOSTS: StaticResolver = static_resolver! {
+        "alias.corp.example.com" => ["198.51.100.7"],
+        "other.corp.example.com" => ["198.51.100.8"],
+    };
+
+    fn name(owner: &str) -> Name {
+        match owner.strip_suffix('.') {
+            Some(_) => owner.parse().unwrap(),
+            None => format!("{}.{}", owner, ZONE).parse().unwrap(),
+        }
+    }
+
+    fn soa(serial: u32) -> Record {
+        let soa =
+            SOA::new(name(ZONE), name(ZONE), serial, 3600, 600, 86400, 300);
+        Record::from_rdata(name(ZONE), 300, RData::SOA(soa))
+    }
+
+    fn a(owner: &str, ip: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::A(ip.parse().unwrap()))
+    }
+
+    fn cname(owner: &str, target: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::CNAME(name(target)))
+    }
+
+    fn copy_of(records: &[Record]) -> ZoneCopy {
+        let mut copy = ZoneCopy {
+            zone: "corp.example.com".to_owned(),
+            soa: super::soa_of(&records[0]).unwrap().clone(),
+            checked: Instant::now(),
+            records: BTreeMap::new(),
+        };
+        for record in &records[1..records.len() - 1] {
+            copy.apply(record, true);
+        }
+        copy
+    }
+
+    fn ips(copy: &ZoneCopy, owner: &str) -> Vec<String> {
+        let name = format!("{}.corp.example.com", owner);
+        copy.lookup(&name).iter().map(ToString::to_string).collect()
+    }
+
+    /// Serves one transfer for each item of `transfers`, each a series of
+    /// messages holding the answer records given, and returns the address
+    /// to transfer from and the types of the queries received.
+    async fn serve(
+        transfers: Vec<Vec<Vec<Record>>>,
+    ) -> (SocketAddr, Arc<Mutex<Vec<RecordType>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&queries);
+        tokio::spawn(async move {
+            for messages in transfers {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = vec![0u8; usize::from(len)];
+                stream.read_exact(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf).unwrap();
+                received.lock().unwrap().push(query.queries()[0].query_type());
+
+                // A stray message with some other ID is ignored.
+                let mut all = vec![(query.id().wrapping_add(1), vec![soa(99)])];
+                all.extend(messages.into_iter().map(|m| (query.id(), m)));
+                for (id, answers) in all {
+                    let mut response = Message::new();
+                    response.set_id(id).set_message_type(MessageType::Response);
+                    response.add_answers(answers);
+                    let response = response.to_vec().unwrap();
+                    let len = u16::try_from(response.len()).unwrap();
+                    stream.write_u16(len).await.unwrap();
+                    stream.write_all(&response).await.unwrap();
+                }
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn incremental() {
+        assert!(is_incremental(&[soa(3), soa(1), a("www", "192.0.2.1")]));
+        assert!(!is_incremental(&[soa(3), a("www", "192.0.2.1"), soa(3)]));
+        assert!(!is_incremental(&[soa(3), soa(3)]));
+        assert!(!is_incremental(&[soa(3)]));
+    }
+
+    #[test]
+    fn out_of_zone_records() {
+        let copy = copy_of(&[
+            soa(1),
+            a("www", "192.0.2.1"),
+            a("www.example.net.", "203.0.113.1"),
+            a("evilcorp.example.com.", "203.0.113.2"),
+            cname("alias", "www.example.net."),
+            cname("web", "app"),
+            a("app", "192.0.2.2"),
+            soa(1),
+        ]);
+        let names: Vec<&str> =
+            copy.records.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            [
+                "alias.corp.example.com",
+                "app.corp.example.com",
+                "web.corp.example.com",
+                "www.corp.example.com",
+            ]
+        );
+        assert_eq!(ips(&copy, "web"), ["192.0.2.2"]);
+        assert!(ips(&copy, "alias").is_empty());
+        assert!(copy.lookup("www.example.net").is_empty());
+    }
+
+    #[test]
+    fn changes() {
+        let mut copy = copy_of(&[
+            soa(1),
+            a("www", "192.0.2.1"),
+            a("old", "192.0.2.9"),
+            soa(1),
+        ]);
+        apply_changes(
+            &mut copy,
+            &[
+                soa(3),
+                soa(1),
+                a("www", "192.0.2.1"),
+                soa(2),
+                a("www", "192.0.2.3"),
+                soa(2),
+                a("old", "192.0.2.9"),
+                soa(3),
+                a("new", "192.0.2.4"),
+                a("new.example.net.", "203.0.113.1"),
+                soa(3),
+            ],
+        );
+        assert_eq!(copy.soa.serial(), 3);
+        assert_eq!(ips(&copy, "www"), ["192.0.2.3"]);
+        assert!(ips(&copy, "old").is_empty());
+        assert_eq!(ips(&copy, "new"), ["192.0.2.4"]);
+        assert_eq!(copy.records.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn transfers() {
+        let (server, queries) = serve(vec![
+            // The whole zone, in two messages
+            vec![
+                vec![soa(1), a("www", "192.0.2.1")],
+                vec![
+                    cname("alias", "www.example.net."),
+                    a("www.example.net.", "203.0.113.1"),
+                    soa(1),
+                ],
+            ],
+            // The changes since serial 1, in three
+            vec![
+                vec![soa(3), soa(1), a("www", "192.0.2.1")],
+                vec![soa(2), a("www", "192.0.2.3"), soa(2), soa(3)],
+                vec![a("new", "192.0.2.4"), soa(3)],
+            ],
+            // No changes
+            vec![vec![soa(3)]],
+        ])
+        .await;
+        let resolver =
+            ZoneTransferResolver::new(HOSTS, "corp.example.com", server)
+                .unwrap();
+        let lookup = |name: &'static str| {
+            let resolver = &resolver;
+            async move {
+                let addrs = resolver.resolve_to_vec(name).await.unwrap();
+                addrs
+                    .iter()
+                    .map(|addr| addr.ip().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(resolver.refresh().await.unwrap(), 1);
+        assert_eq!(lookup("www.corp.example.com").await, ["192.0.2.1"]);
+        // The CNAME leads out of the zone, so the inner resolver answers.
+        assert_eq!(lookup("alias.corp.example.com").await, ["198.51.100.7"]);
+        assert_eq!(lookup("other.corp.example.com").await, ["198.51.100.8"]);
+
+        assert_eq!(resolver.refresh().await.unwrap(), 3);
+        assert_eq!(lookup("www.corp.example.com").await, ["192.0.2.3"]);
+        assert_eq!(lookup("new.corp.example.com").await, ["192.0.2.4"]);
+
+        assert_eq!(resolver.refresh().await.unwrap(), 3);
+        assert_eq!(resolver.serial(), Some(3));
+        assert_eq!(
+            *queries.lock().unwrap(),
+            [RecordType::AXFR, RecordType::IXFR, RecordType::IXFR]
+        );
+    }
+}